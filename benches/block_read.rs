@@ -0,0 +1,82 @@
+// Peak heap usage of reading a ~1MB block back from sled, with and without copying the
+// IVec first. No benchmark framework is needed: `cargo bench --bench block_read`
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use blockchain::block::Block;
+use blockchain::transaction::Transaction;
+use blockchain::tx::{TXInput, TXOutput};
+
+struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(current, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAlloc = PeakAlloc;
+
+// Extra heap used at the peak of `f`, above what was allocated before it ran
+fn peak_during<T>(f: impl FnOnce() -> T) -> (usize, T) {
+    let before = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let result = f();
+    (PEAK.load(Ordering::SeqCst) - before, result)
+}
+
+// Serialized like a Block (same field order), without mining 1MB worth of transactions
+fn encoded_block(size: usize) -> Vec<u8> {
+    let tx = Transaction {
+        id: "0".repeat(64),
+        vin: vec![TXInput { txid: "0".repeat(64), vout: 0, signature: vec![0; 64], pub_key: vec![0; 32] }],
+        vout: vec![TXOutput { value: 1, pub_key_hash: vec![0; 20] }],
+        data: Vec::new(),
+    };
+    let tx_size = bincode::serialized_size(&tx).unwrap() as usize;
+    let transactions = vec![tx; size / tx_size];
+    bincode::serialize(&(0u128, transactions, "0".repeat(64), "0".repeat(64), 1i32, 0i32)).unwrap()
+}
+
+fn main() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let data = encoded_block(1024 * 1024);
+    db.insert("block", data.as_slice()).unwrap();
+    drop(data);
+
+    let rounds = 20;
+    let ivec = db.get("block").unwrap().unwrap();
+    println!("block size: {} bytes", ivec.len());
+
+    let start = Instant::now();
+    let (copied, _) = peak_during(|| {
+        for _ in 0..rounds {
+            let copy: Vec<u8> = ivec.to_vec();
+            let block: Block = bincode::deserialize(&copy).unwrap();
+            std::hint::black_box(block);
+        }
+    });
+    println!("deserialize(&ivec.to_vec()): peak {:>9} bytes, {:?} per read", copied, start.elapsed() / rounds);
+
+    let start = Instant::now();
+    let (direct, _) = peak_during(|| {
+        for _ in 0..rounds {
+            let block: Block = bincode::deserialize(&ivec).unwrap();
+            std::hint::black_box(block);
+        }
+    });
+    println!("deserialize(&ivec):          peak {:>9} bytes, {:?} per read", direct, start.elapsed() / rounds);
+    println!("saved {} bytes per read", copied.saturating_sub(direct));
+}
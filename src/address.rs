@@ -0,0 +1,94 @@
+use bitcoincash_addr::{Address, HashType, Network, Scheme};
+use crypto::{digest::Digest, ripemd160::Ripemd160, sha2::Sha256};
+use failure::format_err;
+
+use crate::errors::Result;
+
+/*
+    Addresses
+
+    An address is RIPEMD160(SHA256(public key)), the pub key hash, encoded with a network
+    byte and a checksum. Outputs are locked to the pub key hash, so converting between the
+    two has to agree everywhere: wallets, inputs and the UI all go through this module.
+    Wallets use Base58 on the main network.
+*/
+
+/// RIPEMD160 of the SHA256 of `pub_key`
+pub fn pub_key_to_hash(pub_key: &[u8]) -> Vec<u8> {
+    let mut sha256 = Sha256::new();
+    sha256.input(pub_key);
+    let mut sha256_bytes = [0u8; 32];
+    sha256.result(&mut sha256_bytes);
+
+    let mut ripemd160 = Ripemd160::new();
+    ripemd160.input(&sha256_bytes);
+    let mut hash = vec![0u8; 20];
+    ripemd160.result(&mut hash);
+    hash
+}
+
+/// Fails for hashes CashAddr can't encode, Base58 takes any
+pub fn hash_to_address(hash: &[u8], scheme: Scheme, network: Network) -> Result<String> {
+    Address::new(hash.to_vec(), scheme, HashType::Key, network)
+        .encode()
+        .map_err(|e| format_err!("Can't encode pub key hash {}: {:?}", hex::encode(hash), e))
+}
+
+/// Either scheme, any network
+pub fn address_to_hash(address: &str) -> Result<Vec<u8>> {
+    Address::decode(address)
+        .map(|a| a.body)
+        .map_err(|_| format_err!("Invalid address: {}", address))
+}
+
+/// The Base58 main network address of `hash`, the kind wallets use
+pub fn wallet_address(hash: &[u8]) -> String {
+    // Base58 encoding can't fail
+    hash_to_address(hash, Scheme::Base58, Network::Main).unwrap_or_default()
+}
+
+/// The address a wallet with `pub_key` receives at
+pub fn pub_key_to_address(pub_key: &[u8]) -> String {
+    wallet_address(&pub_key_to_hash(pub_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    // The SHA256 -> hex -> RIPEMD160 -> hex round trips Wallet and TXInput used to do
+    fn hex_round_trip_address(pub_key: &[u8]) -> String {
+        let mut sha256 = Sha256::new();
+        sha256.input(pub_key);
+        let sha256_result = sha256.result_str();
+
+        let mut ripemd160 = Ripemd160::new();
+        ripemd160.input(&hex::decode(sha256_result).unwrap());
+        let ripemd160_vec = hex::decode(ripemd160.result_str()).unwrap();
+
+        Address::new(ripemd160_vec, Scheme::Base58, HashType::Key, Network::Main).encode().unwrap()
+    }
+
+    #[test]
+    fn test_address_round_trips() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..500 {
+            let mut pub_key = [0u8; 32];
+            rng.fill_bytes(&mut pub_key);
+
+            let address = pub_key_to_address(&pub_key);
+            assert_eq!(address, hex_round_trip_address(&pub_key));
+            assert_eq!(address_to_hash(&address).unwrap(), pub_key_to_hash(&pub_key));
+
+            let hash = pub_key_to_hash(&pub_key);
+            for (scheme, network) in [(Scheme::Base58, Network::Main), (Scheme::CashAddr, Network::Test)] {
+                let encoded = hash_to_address(&hash, scheme, network).unwrap();
+                assert_eq!(address_to_hash(&encoded).unwrap(), hash);
+            }
+        }
+
+        assert!(address_to_hash("not an address").is_err());
+        assert!(hash_to_address(&[1, 2, 3], Scheme::CashAddr, Network::Main).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use crate::errors::Result;
+
+/*
+    Address book
+
+    Labels the user gave addresses, usually other people's, saved to ADDRESS_BOOK_PATH after
+    every change. Views show a counterparty by the name `resolve` finds for it and keep the
+    raw address at hand (on hover, click to copy).
+
+    Our own wallets are named by their wallet labels first, so a self-transfer reads as the
+    wallet it went to. An unlabeled wallet falls back to the address book like any address.
+*/
+
+pub const ADDRESS_BOOK_PATH: &str = "address_book.json";
+
+/// Who an address belongs to, as far as the labels tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Counterparty<'a> {
+    Own(&'a str),     // one of our wallets, by its label
+    Contact(&'a str), // labeled in the address book
+    Unknown,
+}
+
+impl<'a> Counterparty<'a> {
+    pub fn label(&self) -> Option<&'a str> {
+        match self {
+            Counterparty::Own(label) | Counterparty::Contact(label) => Some(label),
+            Counterparty::Unknown => None,
+        }
+    }
+}
+
+/// Address labels by address, saved to `path` after every change. Without a path they're
+/// only kept in memory
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    path: Option<String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl AddressBook {
+    /// Reads the book at `path`, empty when there's no file yet
+    pub fn load(path: &str) -> Result<AddressBook> {
+        let labels = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(AddressBook { path: Some(path.to_string()), labels })
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            fs::write(path, serde_json::to_string_pretty(&self.labels)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, address: &str) -> Option<&str> {
+        self.labels.get(address).map(String::as_str)
+    }
+
+    /// Labels `address`, a blank label removes it from the book
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        let label = label.trim();
+        if label.is_empty() {
+            self.labels.remove(address);
+        } else {
+            self.labels.insert(address.to_string(), label.to_string());
+        }
+        self.save()
+    }
+}
+
+/// Names `address` from the labels of our wallets (`own`, by address, empty for none) and
+/// then the address book
+pub fn resolve<'a>(address: &str, book: &'a AddressBook, own: &'a HashMap<String, String>) -> Counterparty<'a> {
+    if let Some(label) = own.get(address).filter(|label| !label.is_empty()) {
+        return Counterparty::Own(label);
+    }
+    match book.get(address) {
+        Some(label) => Counterparty::Contact(label),
+        None => Counterparty::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(entries: &[(&str, &str)]) -> AddressBook {
+        let mut book = AddressBook::default();
+        for (address, label) in entries {
+            book.set_label(address, label).unwrap();
+        }
+        book
+    }
+
+    #[test]
+    fn test_resolves_book_entries_and_own_wallets() {
+        let book = book(&[("1Alice", "Alice"), ("1Mine", "Old name")]);
+        let own = HashMap::from([
+            (String::from("1Mine"), String::from("Savings")),
+            (String::from("1Unlabeled"), String::new()),
+        ]);
+
+        assert_eq!(resolve("1Alice", &book, &own), Counterparty::Contact("Alice"));
+        // a wallet's own label wins over the book
+        assert_eq!(resolve("1Mine", &book, &own), Counterparty::Own("Savings"));
+        assert_eq!(resolve("1Unlabeled", &book, &own), Counterparty::Unknown);
+        assert_eq!(resolve("1Stranger", &book, &own), Counterparty::Unknown);
+        assert_eq!(resolve("1Stranger", &book, &own).label(), None);
+    }
+
+    #[test]
+    fn test_labels_are_saved_and_blank_ones_removed() {
+        let path = std::env::temp_dir().join(format!("blockjain-address-book-{}.json", rand::random::<u64>()));
+        let path = path.to_string_lossy().to_string();
+
+        let mut book = AddressBook::load(&path).unwrap();
+        book.set_label("1Alice", "  Alice ").unwrap();
+        book.set_label("1Bob", "Bob").unwrap();
+        book.set_label("1Bob", " ").unwrap();
+
+        let reloaded = AddressBook::load(&path).unwrap();
+        assert_eq!(reloaded.get("1Alice"), Some("Alice"));
+        assert_eq!(reloaded.get("1Bob"), None);
+        let _ = fs::remove_file(&path);
+    }
+}
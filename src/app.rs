@@ -1,36 +1,136 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use eframe::egui;
 use egui::{Grid, Ui};
-use failure::Fail;
-use reqwest;
-use bitcoincash_addr::Address;
-use crypto::ed25519;
-use hex;
-use log::error;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::{ RwLock, mpsc };
-use std::collections::HashMap;
-use tokio::time::Duration;
 
 // My Crates
-use crate::blockchain::Blockchain;
-use crate::block::Block;
-use crate::errors::Result;
-use crate::server::{ Server, KnownNode };
-use crate::transaction::Transaction;
-use crate::tx::TXOutputs;
-use crate::utxoset::UTXOSet;
-use crate::wallet::*;
-use crate::runtime::RUNTIME;    // Import the global runtime (tokio)
-use crate::settings::SETTINGS;  // Application Settings
-
-#[derive(Debug, Fail)]
-pub enum WalletImportError {
-    #[fail(display = "Invalid secret key format")]
-    InvalidSecretKeyFormat,
-    // Add other error types here as needed
+use blockchain::address;
+use blockchain::address_book::{self, AddressBook, Counterparty, ADDRESS_BOOK_PATH};
+use blockchain::audit::{self, AuditAction, AuditEntry, AuditLog};
+use blockchain::blockchain::{Blockchain, ChainAuditReport, ChainHealth, ChainRecovery, ChainStats, BLOCKS_PATH};
+use blockchain::bandwidth::Throughput;
+use blockchain::backend::{ChainView, MockBackend, NetworkControl, WalletStore, MOCK_BLOCKS};
+use blockchain::block::Block;
+use blockchain::chain_file::{self, ImportSummary};
+use blockchain::clock;
+use blockchain::coin_selection::CoinSelection;
+use blockchain::confirmations::WatchList;
+use blockchain::console;
+use blockchain::disk::{DiskLevel, StoreUsage};
+use blockchain::events::{BlocksConnected, NodeEvent};
+use blockchain::health::{HealthReport, HealthStatus};
+use blockchain::idle::{DeferredWork, IdleState, Visibility};
+use blockchain::errors::{ChainOpenError, Result, SchemaError};
+use blockchain::maintenance::{self, StoreReport};
+use blockchain::mempool::{EvictionReason, PackageStats};
+use blockchain::node;
+use blockchain::outbox::{Outbox, PaymentStatus};
+use blockchain::peer_history::PeerHistoryEntry;
+use blockchain::proof_of_funds::{self, ProofOfFunds, VerifiedReport};
+use blockchain::raw;
+use blockchain::pending::{self, PendingSend, PendingSends, SendState, Settled, PENDING_SENDS_PATH};
+use blockchain::receipt::{BroadcastReceipt, TxAck};
+use blockchain::payment_request::{PaymentRequest, MAX_MEMO_LEN};
+use blockchain::network_map::{self, MapRole};
+use blockchain::protocol::Capabilities;
+use blockchain::sent_txs::{SentStatus, SentTx};
+use blockchain::server::{BroadcastReport, KnownNode, Server};
+use blockchain::transaction::{circulating_supply, estimate_size, fee_for_size, SendMode, SendPreview, Transaction, DEFAULT_FEE_RATE, MAX_TX_DATA};
+use blockchain::tx::is_unspendable_address;
+use blockchain::utxo_file::{self, SnapshotSummary};
+use blockchain::utxoset::{UtxoAuditReport, UTXOSet};
+use blockchain::wallet::*;
+use blockchain::wallet_format::{self, ExportFormat};
+use blockchain::wallet_history::{self, Direction, HistoryEntry, MiningIncome};
+use blockchain::runtime::{BackgroundTasks, RUNTIME};    // Import the global runtime (tokio)
+use blockchain::settings::{SETTINGS, SETTINGS_PATH};  // Application Settings
+use blockchain::config::CONFIG;
+
+// Shown in the Peers tab for every capability a peer advertised
+const CAPABILITY_ICONS: [(Capabilities, &str, &str); 5] = [
+    (Capabilities::COMPACT_BLOCKS, "📦", "Compact blocks"),
+    (Capabilities::BLOCK_FILTERS, "🔍", "Block filters"),
+    (Capabilities::ENCRYPTION, "🔒", "Encrypted transport"),
+    (Capabilities::HEADERS_FIRST, "⏩", "Headers-first sync"),
+    (Capabilities::TX_RECEIPTS, "🧾", "Signs transaction receipts"),
+];
+
+// How long on_exit waits for background tasks before saving anyway
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+// Peer throughput is reloaded this often while the Peers tab is open
+const PEERS_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+// Peers taking longer to accept a connection are drawn yellow on the network map
+const SLOW_PEER_LATENCY: std::time::Duration = std::time::Duration::from_millis(500);
+// Startup errors and database repairs are appended here
+const STARTUP_LOG: &str = "startup.log";
+// The health report in the status bar is reloaded this often
+const HEALTH_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+// Pending sends are checked for confirmation and expiry this often
+const PENDING_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+// Newest transactions listed per wallet
+const WALLET_HISTORY_ROWS: usize = 20;
+// Transactions listed in an expanded block until "show all" is clicked, and the height of
+// the list, which only lays out the rows scrolled into view
+const BLOCK_TX_ROWS: usize = 50;
+const BLOCK_TX_LIST_HEIGHT: f32 = 300.0;
+// Blocks "Load More Blocks" adds to the list
+const LOAD_MORE_BLOCKS: usize = 20;
+// How long "Copied!" stays next to what was copied
+const COPIED_TOOLTIP_SECS: f64 = 1.5;
+// Fee presets of the send form and the blocks each aims to be mined within
+const FEE_PRESETS: [(&str, u32); 3] = [("Slow", 6), ("Normal", 3), ("Fast", 1)];
+
+// Below this width (in points) the sections switch to their compact layout
+const COMPACT_WIDTH: f32 = 960.0;
+
+// How the sections arrange themselves, from the width they're given
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LayoutMode {
+    Compact, // icon nav bar, single column forms, wallet actions in a menu
+    Regular,
 }
 
+impl LayoutMode {
+    fn from_width(width: f32) -> LayoutMode {
+        if width < COMPACT_WIDTH {
+            LayoutMode::Compact
+        } else {
+            LayoutMode::Regular
+        }
+    }
+
+    fn notification_size(self) -> egui::Vec2 {
+        match self {
+            LayoutMode::Compact => egui::vec2(240.0, 50.0),
+            LayoutMode::Regular => egui::vec2(350.0, 75.0),
+        }
+    }
+
+    // Notifications stacked at once, the newest ones
+    fn max_notifications(self) -> usize {
+        match self {
+            LayoutMode::Compact => 3,
+            LayoutMode::Regular => 6,
+        }
+    }
+}
+
+// Buttons of a wallet card, in reading order
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WalletAction {
+    Receive,
+    Send,
+    Export,
+    Delete,
+}
+
+const WALLET_ACTIONS: [WalletAction; 4] = [WalletAction::Receive, WalletAction::Send, WalletAction::Export, WalletAction::Delete];
+
+#[derive(PartialEq)]
 enum Tab {
     Blockchain,
     Transactions,
@@ -44,25 +144,168 @@ struct Notification {
     pub message: String,
     pub start_time: std::time::Instant,  // When the notification was created
     pub duration: u64,        // Duration in seconds before auto-dismissal
+    pub warning: bool,        // yellow border, stays longer
+    pub action: Option<NotificationAction>,
+}
+
+// A button on a notification
+#[derive(Debug, Clone, PartialEq)]
+enum NotificationAction {
+    RetryWithHigherFee(String), // txid of the abandoned send
+    ReindexUtxos,
+}
+
+// Buttons whose work runs on the runtime. One action of each kind can be in flight at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActionKind {
+    SendTx,
+    BurnCoins,
+    AddPeer,
+    CreateWallet,
+    CompactDatabases,
+    VerifyChain,
+    RepairUtxos, // the check against the chain and the reindex it may lead to
+    SendBatch,
+    ExportChain,
+    ImportChain,
+    UtxoSnapshot, // export or import
+    ProofOfFunds,
+    BroadcastRawTx,
 }
 
 #[derive(Debug)]
 pub enum TaskMessage {
     BalancesUpdated(Vec<i32>),
     Error(String),
-    TransactionSent(bool),
+    TransactionSent(std::result::Result<String, String>), // txid or error
+    FeeBumped(String, std::result::Result<String, String>), // replaced txid, replacement txid or error
+    MaxAmountLoaded(std::result::Result<(i32, i32), String>), // amount and fee of a send max
+    FeeEstimated(&'static str, std::result::Result<i64, String>), // preset, fee rate or error
+    SendPreviewed(i32, std::result::Result<SendPreview, String>), // for this amount
+    BatchSent(Vec<u64>, std::result::Result<String, String>), // outbox payment ids, txid or error
     PeerAdded(String),
+    PeersLoaded(Vec<(String, Capabilities)>),
+    PeerHistoryLoaded(Vec<PeerHistoryEntry>), // newest first
+    ThroughputLoaded(HashMap<String, Throughput>),
+    SweepFinished(String, std::result::Result<String, String>), // swept address, txid or error
+    BlocksLoaded(Vec<Block>), // blocks above the current tip, newest first
+    ConsoleOutput(String),
+    ActionFinished(ActionKind), // sent by spawn_action after the action's own message
+    MempoolLoaded(Vec<Transaction>),
+    TxDetailLoaded(std::result::Result<TxDetail, String>),
+    CompactionFinished(std::result::Result<Vec<StoreReport>, String>),
+    AuditLogLoaded(std::result::Result<Vec<AuditEntry>, String>), // oldest first
+    ChainVerified(std::result::Result<ChainAuditReport, String>),
+    UtxosAudited(std::result::Result<UtxoAuditReport, String>),
+    UtxosReindexed(std::result::Result<(), String>),
+    ChainFileProgress(usize, usize), // blocks done, total
+    ChainExported(std::result::Result<(usize, PathBuf), String>), // blocks written
+    ChainImported(std::result::Result<ImportSummary, String>),
+    UtxoSnapshotExported(std::result::Result<(SnapshotSummary, PathBuf), String>),
+    UtxoSnapshotImported(std::result::Result<SnapshotSummary, String>),
+    ProofOfFundsSaved(std::result::Result<PathBuf, String>),
+    ProofOfFundsVerified(std::result::Result<VerifiedReport, String>),
+    HealthLoaded(HealthReport),
+    KnownNodesLoaded(HashMap<String, KnownNode>),
+    PeerContacted(String),
+    PendingSendsChecked {
+        abandoned: Vec<PendingSend>,
+        settled: Vec<Settled>,
+        pending_amounts: HashMap<String, i64>, // by wallet address, see UTXOSet::locked_value
+    },
+    WalletHistoryLoaded(HashMap<String, Vec<HistoryEntry>>), // per address, newest first
+    SentTxsLoaded(Vec<SentTx>, i32), // newest first, and the tip height
+    ChainStatsLoaded(ChainStats),
+    ReceiptUpdated(BroadcastReceipt), // a peer acknowledged one of our pending sends
+    BlockTransactionsLoaded(String, std::result::Result<Vec<BlockTx>, String>), // block hash, txids and memos
+    PublicIpLoaded(std::result::Result<String, String>),
+    OlderBlocksLoaded(Vec<Block>), // below the oldest loaded block, newest first
+    PrunedHeightLoaded(Option<i32>),
+    RawHexLoaded(std::result::Result<String, String>), // to be copied to the clipboard
+    RawTxBroadcast(std::result::Result<String, String>), // txid or why the mempool rules refused it
+    BlockSearchFinished(String, Option<BlockSummary>), // query, block found
+}
+
+// What the Blockchain tab keeps of a block. Transaction ids are read from the database when
+// the block's list is expanded
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSummary {
+    hash: String,
+    prev_hash: String,
+    merkle_root: String,
+    height: i32,
+    timestamp: u128,
+    nonce: i32,
+    tx_count: usize,
+}
+
+impl BlockSummary {
+    fn of(block: &Block) -> BlockSummary {
+        BlockSummary {
+            hash: block.get_hash(),
+            prev_hash: block.get_prev_hash(),
+            merkle_root: block.get_merkle_root(),
+            height: block.get_height(),
+            timestamp: block.get_timestamp(),
+            nonce: block.get_nonce(),
+            tx_count: block.get_transactions().len(),
+        }
+    }
+}
+
+// Asked for by a block or its transaction list while rendering
+#[derive(Debug, PartialEq)]
+enum BlockAction {
+    Load,       // expanded, ids not read yet
+    ShowAll,    // lift the BLOCK_TX_ROWS cap
+    Collapsed,  // drop the ids read
+    CopyRawHex, // the whole block, read for it
+    CopyRawTx(String), // one of its transactions, by txid
+}
+
+// What was clicked in a wallet's history
+enum HistoryAction {
+    OpenTx(String),
+    Label(String), // counterparty address, opens the label editor
+}
+
+// What the transaction detail popup shows. Package stats only exist for mempool transactions
+#[derive(Debug)]
+pub struct TxDetail {
+    tx: Transaction,
+    package: Option<PackageStats>,
+}
+
+// What a send spends from: the wallets, in order, and where change goes for the first
+pub struct Funding {
+    wallets: Vec<Wallet>,
+    change: Box<dyn ChangeAddressProvider>,
+}
+
+// A transaction id in block detail and the memo of the transaction's data, if it has any
+type BlockTx = (String, Option<String>);
+
+// Who a send pays, how much, and the data attached to it
+pub struct Payment {
+    to: String,
+    mode: SendMode,
+    data: Vec<u8>, // the memo, empty for none
 }
 
 pub struct BlockchainModule {
-    wallets: Wallets,
+    wallets: Box<dyn WalletStore>,
     balances: Vec<i32>,
-    utxo_set: Arc<RwLock<UTXOSet>>,
+    chain: Arc<dyn ChainView>, // the blocks and balances shown
+    utxo_set: Arc<RwLock<UTXOSet>>, // for building transactions
+    pending_sends: Arc<RwLock<PendingSends>>, // our sends without a block yet
+    address_book: AddressBook, // labels of counterparties
 }
 
 pub struct NetworkModule {
     public_ip: Option<Result<String>>, // Use the custom Result type here
+    network: Arc<dyn NetworkControl>, // peers, mempool and broadcasting
     server: Arc<RwLock<Server>>,
+    visibility: Visibility, // tells the node when the window is minimized
 }
 
 pub struct NotificationModule {
@@ -74,27 +317,84 @@ pub struct UIState {
     active_tab: Tab, // -
 
     // Blockchain Tab
-    blocks: Vec<Block>,
+    blocks: Vec<BlockSummary>,
     show_transactions: bool,
+    block_txids: HashMap<String, Option<Vec<BlockTx>>>, // txids and memos of expanded blocks by hash, None while loading
+    show_all_txs: HashSet<String>, // expanded blocks listing past BLOCK_TX_ROWS
     blocks_to_display: usize,
+    oldest_block_loaded: bool, // nothing below `blocks` to load
+    pruned_height: Option<i32>, // lowest stored block of a pruned chain
+    chain_stats: Option<ChainStats>, // the strip at the top, None until read
+    new_blocks_since_view: usize, // badge, reset when the tab is opened
     block_search_query: String,
-    block_search_result: Option<Block>,
+    block_search_result: Option<BlockSummary>,
 
     // Transaction Tab
     selected_wallet: Option<String>,
+    combine_wallets: bool, // fund the transaction from several wallets
+    combined_wallets: Vec<String>, // spent from after selected_wallet, which gets the change
+    batch_sends: bool, // Send queues in the outbox
+    outbox: Outbox,
     receiver_address: String,
     tx_amount: i32,
-    tx_gas_price: i32,
-    tx_gas_limit: i32,
+    send_max_fee: Option<i32>, // set while tx_amount is a sweep of the From wallet, see SendMode::Sweep
+    send_preview: Option<(i32, SendPreview)>, // of the amount given, shown while tx_amount and tx_fee are still the same
+    tx_fee: i32, // for the miner, on top of the amount
+    fee_estimate: Option<(&'static str, i64, i32)>, // preset, fee rate and the fee it filled in, shown while tx_fee is still that
+    tx_memo: String, // attached to the transaction as its data
+    coin_selection: CoinSelection, // how the inputs of a send are picked
+    confirm_burn: bool, // user acknowledged that the coins will be lost
+    burn_amount: i32,
+    burn_confirmed: bool, // checkbox of the Burn Coins action
+    raw_tx_input: String, // hex pasted in the Broadcast raw transaction box
+    pending_txids: Vec<String>, // sent from this app, not in a block yet
+    receipts: HashMap<String, BroadcastReceipt>, // of pending sends peers acknowledged, by txid
+    pending_amounts: HashMap<String, i64>, // coins of each wallet our unconfirmed sends spend
+    sent_txs: Vec<SentTx>, // newest first
+    sent_tip_height: i32, // the confirmations of sent_txs are counted from
+    mempool_txs: Vec<Transaction>,
+    tx_detail: Option<TxDetail>,
+    label_editor: Option<(String, String)>, // address being labeled and the label typed so far
+    payment_request_banner: Option<String>, // the form was filled in from a payment request
 
     // Wallet Tab
+    incoming: WatchList, // payments to our wallets below the confirmation target
+    wallet_history: HashMap<String, Vec<HistoryEntry>>, // from the owned transactions index
     show_delete_popup: Option<String>,
     show_add_existing_wallet_popup: bool,
+    receive_request: Option<PaymentRequest>, // Receive popup, being filled in
+    proof_of_funds: Option<ProofOfFundsForm>, // Proof of funds window, open
+    import_password: String, // for encrypted wallet files
+    sweep_destination: String,
+    sweep_in_progress: Option<String>,
 
     // Peers Tab
     peer_ip_address_input: String,
     peer_port_input: String,
-    connected_peers_displayed: Vec<String>,
+    connected_peers_displayed: Vec<(String, Capabilities)>,
+    peer_history: Vec<PeerHistoryEntry>,
+    peer_throughput: HashMap<String, Throughput>,
+    peers_refreshed: Option<std::time::Instant>, // throughput is refreshed while the tab is open
+    known_nodes: HashMap<String, KnownNode>, // for the network map
+    map_selected: Option<String>, // gossiped node clicked on the map
+
+    // Settings Tab
+    console_input: String,
+    console_output: String,
+    compaction_report: Vec<StoreReport>, // last compaction, sizes per store
+    audit_entries: Vec<AuditEntry>, // oldest first
+    audit_filter: Option<AuditAction>, // None shows every action
+    chain_file_progress: Option<(usize, usize)>, // of the running export or import
+
+    // Status bar, stays until the condition clears
+    status_warning: Option<String>,
+    disk_warning: Option<u32>, // id of the sticky low disk space notification
+    health: Option<HealthReport>,
+    health_refreshed: Option<std::time::Instant>,
+    pending_checked: Option<std::time::Instant>,
+    idle: IdleState, // whether the window is minimized, and the refreshes put off meanwhile
+    startup_problem: Option<String>, // shown in a dialog until dismissed
+    damaged_chain: Option<ChainHealth>, // the node refused to start on it, the dialog offers recovery
 }
 
 pub struct MyApp {
@@ -104,6 +404,10 @@ pub struct MyApp {
 
     sender: mpsc::Sender<TaskMessage>,
     receiver: mpsc::Receiver<TaskMessage>,
+    node_events: mpsc::Receiver<NodeEvent>,
+    actions_in_flight: HashSet<ActionKind>,
+    tasks: BackgroundTasks, // everything spawned on RUNTIME, stopped in on_exit
+    mock_ui: bool, // --mock-ui, nothing is saved or compacted on exit
     
     // the popups basically
     notif_module: NotificationModule,
@@ -117,7 +421,7 @@ impl MyApp {
         let (sender, receiver) = mpsc::channel(100);
 
         // Retrieve first wallet and its address. 
-        let mining_address =  wallets.get_all_address().get(0).cloned().unwrap_or_default();
+        let mining_address =  wallets.get_all_address().first().cloned().unwrap_or_default();
         
         // Uncomment to create a new blockchain with a new genesis block and genesis address (Use for Custom)        
         /*
@@ -125,35 +429,54 @@ impl MyApp {
             let blockchain = Blockchain::create_blockchain(address.clone())?;
         */        
 
-        // This can either load the existing blockchain or create a new genesis block. (Standard way)
-        let blockchain = Arc::new(RwLock::new(Blockchain::new()?));
-        let utxo_set = Arc::new(RwLock::new(UTXOSet::new(Arc::clone(&blockchain))));
-        utxo_set.write().await.reindex().await?;
-
-        let mut current_blocks:Vec<Block> = Vec::new();
-
-        // Load node's blockchain blocks
-        for block_hash in &blockchain.read().await.get_block_hashes() {
-            current_blocks.push( blockchain.read().await.get_block(block_hash)?.clone() );
-        }
-        
-        // Create a Server and loop it
-        let server = Arc::new(RwLock::new(Server::new("8334", &mining_address, Arc::clone(&utxo_set))?));
-
-        tokio::spawn({
-            let server_clone = Arc::clone(&server);
-            async move {
-                //println!("Starting server with instance: {:?}", Arc::as_ptr(&server_clone));
-
-                if let Err(e) = Server::start_server(server_clone).await {
-                    error!("Server error: {}", e);
-                }
-            }
+        // Loads the existing blockchain (or creates the genesis block) and starts the server
+        let (event_sender, node_events) = mpsc::channel(100);
+        let node = node::start(&CONFIG.port.value, &mining_address, Some(event_sender)).await?;
+        let utxo_set = node.utxo_set;
+        let server = node.server;
+        let startup_problem = node.blockchain.read().await.take_repair()?.map(|repair| {
+            let message = format!("The block database was inconsistent and has been repaired.\n{}", repair);
+            record_startup_problem(&message);
+            message
         });
 
-        let mut connected_peer_ips: Vec<String> = Vec::new();
-        for address_string in &server.read().await.get_known_nodes().await {
-            connected_peer_ips.push(address_string.0.to_string());
+        let pending_sends = PendingSends::load(PENDING_SENDS_PATH)?;
+        pending::apply_locks(&pending_sends, &utxo_set).await;
+        let pending_txids: Vec<String> = pending_sends.txids().into_iter()
+            .filter(|txid| pending_sends.get(txid).is_some_and(|send| send.state == SendState::Pending))
+            .collect();
+        let receipts = pending_sends.receipts().into_iter().map(|receipt| (receipt.txid.clone(), receipt)).collect();
+        let address_book = AddressBook::load(ADDRESS_BOOK_PATH)?;
+
+        // The newest blocks, older ones are read when "Load More Blocks" gets to them. They
+        // cover the payments that were still confirming when the app closed
+        let newest_blocks = {
+            let blockchain = node.blockchain.read().await;
+            let best_height = blockchain.get_best_height()?;
+            let count = SETTINGS.max_blocks_loaded.max(SETTINGS.confirmation_target as usize) as i32;
+            blockchain.get_blocks_range(best_height - count + 1, best_height)?
+        };
+        let current_blocks: Vec<BlockSummary> = newest_blocks.iter().map(BlockSummary::of).collect();
+        let oldest_block_loaded = current_blocks.last().is_none_or(|b| b.height == 0);
+        let pruned_height = node.blockchain.read().await.pruned_height()?;
+
+        // Payments that were still confirming when the app closed, without notifications
+        let mut incoming = WatchList::new(SETTINGS.confirmation_target);
+        for (txid, address, amount, height, _) in incoming_payments(newest_blocks.iter().rev(), &wallets.get_all_address()) {
+            incoming.watch(&txid, &address, amount, height);
+        }
+        if let Some(tip) = current_blocks.first() {
+            incoming.tip_changed(tip.height);
+        }
+        let wallet_history = load_wallet_history(&*node.blockchain.read().await, &wallets.get_all_address())?;
+        // Seeds the counters on the first run, they follow the chain from then on
+        let chain_stats = node.blockchain.read().await.stats()
+            .map_err(|e| println!("Failed to read the chain statistics: {}", e))
+            .ok();
+
+        let mut connected_peer_ips: Vec<(String, Capabilities)> = Vec::new();
+        for (address, known_node) in &server.read().await.get_known_nodes().await {
+            connected_peer_ips.push((address.to_string(), known_node.capabilities()));
         }
        
         // Fetch Public IP
@@ -168,7 +491,7 @@ impl MyApp {
         
         // Update Balances
         let balances: Vec<i32> = Vec::new();
-        let new_balances = MyApp::calculate_new_balances(&wallets, Arc::clone(&utxo_set)).await?;
+        let new_balances = MyApp::calculate_new_balances(wallets.address_groups(), utxo_set.clone()).await?;
         let _ = sender.send(TaskMessage::BalancesUpdated(new_balances)).await;
 
 
@@ -176,13 +499,18 @@ impl MyApp {
 
         let app = MyApp {
             bc_module: BlockchainModule{
-                wallets: wallets,
-                balances: balances,
+                wallets: Box::new(wallets),
+                balances,
+                chain: utxo_set.clone(),
                 utxo_set: Arc::clone(&utxo_set),
+                pending_sends: Arc::new(RwLock::new(pending_sends)),
+                address_book,
             },
             net_module: NetworkModule {
-                public_ip: public_ip, // Use the custom Result type here
+                public_ip, // Use the custom Result type here
+                network: server.clone(),
                 server: Arc::clone(&server),
+                visibility: server.read().await.visibility(),
             },
 
             ui_state: UIState {
@@ -191,25 +519,81 @@ impl MyApp {
                 // Blockchain Tab
                 blocks: current_blocks,
                 show_transactions: false,
+                block_txids: HashMap::new(),
+                show_all_txs: HashSet::new(),
                 blocks_to_display: 5,
+                oldest_block_loaded,
+                pruned_height,
+                chain_stats,
+                new_blocks_since_view: 0,
                 block_search_query: String::new(),
                 block_search_result: None,
 
                 // Transaction Tab
                 selected_wallet: None,
+                combine_wallets: false,
+                combined_wallets: Vec::new(),
+                batch_sends: SETTINGS.batch_sends,
+                outbox: Outbox::default(),
                 receiver_address: String::from(""),
                 tx_amount: 0,
-                tx_gas_price: 0,
-                tx_gas_limit: 0,
+                send_max_fee: None,
+                send_preview: None,
+                tx_fee: 0,
+                fee_estimate: None,
+                tx_memo: String::new(),
+                coin_selection: CoinSelection::default(),
+                confirm_burn: false,
+                burn_amount: 0,
+                burn_confirmed: false,
+                raw_tx_input: String::new(),
+                pending_txids,
+                receipts,
+                pending_amounts: HashMap::new(),
+                sent_txs: Vec::new(),
+                sent_tip_height: -1,
+                mempool_txs: Vec::new(),
+                tx_detail: None,
+                label_editor: None,
+                payment_request_banner: None,
 
                 // Wallets Tab
+                incoming,
+                wallet_history,
                 show_delete_popup: None,
                 show_add_existing_wallet_popup: false, 
+                receive_request: None,
+                proof_of_funds: None,
+                import_password: String::new(),
+                sweep_destination: String::new(),
+                sweep_in_progress: None,
 
                 // Peers Tab
                 peer_ip_address_input: String::new(),
                 peer_port_input: String::from("8334"),
                 connected_peers_displayed: connected_peer_ips,
+                peer_history: Vec::new(),
+                peer_throughput: HashMap::new(),
+                peers_refreshed: None,
+                known_nodes: HashMap::new(),
+                map_selected: None,
+
+                // Settings Tab
+                console_input: String::new(),
+                console_output: String::new(),
+                compaction_report: Vec::new(),
+                audit_entries: Vec::new(),
+                audit_filter: None,
+                chain_file_progress: None,
+
+                status_warning: None,
+                disk_warning: None,
+                health: None,
+                health_refreshed: None,
+                pending_checked: None,
+                idle: IdleState::default(),
+                startup_problem,
+                damaged_chain: None,
             },
 
             notif_module: NotificationModule {
@@ -217,31 +601,31 @@ impl MyApp {
                 notification_counter: 0,
             },
 
-            sender: sender,
-            receiver: receiver,
+            sender,
+            receiver,
+            node_events,
+            actions_in_flight: HashSet::new(),
+            tasks: BackgroundTasks::new(),
+            mock_ui: false,
         };
 
         Ok(app)
     }
 
-    // calculates and returns new balances (vector of i32)
-    pub async fn calculate_new_balances(wallets: &Wallets, utxo_set: Arc<RwLock<UTXOSet>>) -> Result<Vec<i32>> {
+    // calculates and returns new balances (vector of i32), one per group of addresses: a wallet
+    // and its change wallets, see WalletStore::address_groups. The UTXO set caches them, only
+    // addresses a block touched since the last call are summed again
+    pub async fn calculate_new_balances(groups: Vec<Vec<String>>, chain: Arc<dyn ChainView>) -> Result<Vec<i32>> {
         let mut new_balances = Vec::new();
         
-        for address in wallets.get_all_address() {            
-            let pub_key_hash = Address::decode(&address).unwrap().body;
-
-            // Find all UTXOs for this address
-            let utxos: TXOutputs = utxo_set.read().await.find_utxo(&pub_key_hash).unwrap_or_else(|_| {
-                TXOutputs {
-                    outputs: vec![],
-                }
-            });
+        for addresses in groups {
+            let mut balance: i32 = 0;
+            for address in addresses {
+                address::address_to_hash(&address)?;
 
-            // Calculate the total balance for this address
-            let balance: i32 = utxos.outputs.iter().map(|out| out.value).sum();
-            
-            //println!("address: {}, balance: {}", &address, &balance);
+                // Sum of the UTXOs for this address, see UTXOSet::get_balance
+                balance = balance.saturating_add(chain.balance(address).await.unwrap_or(0));
+            }
 
             // Add the balance to the vector
             new_balances.push(balance);
@@ -267,22 +651,30 @@ impl MyApp {
     }
 
     pub fn delete_wallet(&mut self, address: &str) -> Result<()> {
+        let index = self.bc_module.wallets.get_all_address().iter().position(|a| a == address);
         self.bc_module.wallets.delete_wallet(address)?;
 
-        let message = format!("Wallet Deleted (Address): {}", &address);
+        let message = format!("Wallet Deleted (Address): {}", wallet_label(address));
         self.add_notification(message);
 
         // Update balances: Assuming balances align with wallet order
-        if let Some(index) = self.bc_module.wallets.get_all_address().iter().position(|a| a == address) {
+        if let Some(index) = index.filter(|&index| index < self.bc_module.balances.len()) {
             self.bc_module.balances.remove(index);
         }
 
-        let wallets = self.bc_module.wallets.clone(); // contains the new wallet
+        self.ui_state.wallet_history.remove(address);
+
+        let addresses = self.bc_module.wallets.address_groups();
+        let chain = Arc::clone(&self.bc_module.chain);
         let utxo_set = Arc::clone(&self.bc_module.utxo_set);
         let sender = self.sender.clone();
+        let deleted = address.to_string();
 
-        RUNTIME.spawn(async move {
-            match MyApp::calculate_new_balances(&wallets, utxo_set).await {
+        self.tasks.spawn(async move {
+            if let Err(err) = utxo_set.read().await.blockchain.read().await.untrack_wallet(&deleted) {
+                println!("Failed to remove the wallet history of {}: {}", deleted, err);
+            }
+            match MyApp::calculate_new_balances(addresses, chain).await {
                 Ok(new_balances) => {
                     sender.send(TaskMessage::BalancesUpdated(new_balances))
                         .await
@@ -299,45 +691,59 @@ impl MyApp {
         Ok(())
     }
 
-    pub fn export_wallet_to_file(&self, address: &str, wallet: &Wallet) -> Result<()> {
-        use std::fs::File;
-        use std::io::Write;
-
-        let file_name = format!("data/wallets/export/{}_wallet.dat", address);
-        let mut file = File::create(&file_name)?;
-
-        let serialized_wallet = bincode::serialize(wallet)?;
-        file.write_all(&serialized_wallet)?;
-        
-        let msg = String::from(format!("Wallet exported to file: {}", file_name));
-        println!("{}", msg);
+    /// Exports the wallet at `address` and its change wallets to wallets/export, see `export_wallet_to`
+    pub fn export_wallet_to_file(&self, address: &str) -> Result<Vec<PathBuf>> {
+        self.export_wallet_to(std::path::Path::new("wallets/export"), address)
+    }
 
-        Ok(())
+    // One file per key: the wallet's own, then its change wallets'. Its balance counts the
+    // change, so a backup of the wallet's key alone would lose it. Returns the files written
+    fn export_wallet_to(&self, dir: &std::path::Path, address: &str) -> Result<Vec<PathBuf>> {
+        let wallets = self.bc_module.wallets.spending_wallets(address);
+        if wallets.is_empty() {
+            return Err(failure::format_err!("No wallet {}", address));
+        }
+        let mut files = Vec::new();
+        for wallet in &wallets {
+            let file_name = dir.join(format!("{}_wallet.dat", wallet.get_address()));
+            std::fs::write(&file_name, wallet_format::export(wallet, ExportFormat::Binary)?)?;
+            println!("Wallet exported to file: {}", file_name.display());
+            files.push(file_name);
+        }
+        Ok(files)
     }
 
-     // Method for importing wallet from .dat file
+     // Method for importing wallet from .dat file, in any format older builds exported
     fn import_wallet_from_file(&self, path: std::path::PathBuf) -> Result<Wallet> {
-        // Read the file content and deserialize it
-        let file_content = std::fs::read(path).map_err(|_| WalletImportError::InvalidSecretKeyFormat)?;
-        let wallet: Wallet = bincode::deserialize(&file_content).map_err(|_| WalletImportError::InvalidSecretKeyFormat)?;
-        Ok(wallet)
+        let file_content = std::fs::read(path)?;
+        let password = Some(self.ui_state.import_password.as_str()).filter(|p| !p.is_empty());
+        wallet_format::import(&file_content, password)
     }
 
-    // Method for importing wallet from secret key
+    // Method for importing wallet from a secret key, hex or WIF
     fn import_wallet_from_key(&self, secret_key: &str) -> Result<Wallet> {
-        // parbaudit vai ir pareizs length
-        // Convert the secret key into bytes and generate the corresponding public key
-        let secret_key_bytes = hex::decode(secret_key).map_err(|_| WalletImportError::InvalidSecretKeyFormat)?;
-       
-        let (public_key, _) = ed25519::keypair(&secret_key_bytes);
-        let wallet = Wallet {
-            secret_key: secret_key_bytes,
-            public_key: public_key.to_vec(),
+        wallet_format::import(secret_key.as_bytes(), None)
+    }
+
+    // The selected wallet, then the ones combined with it when that's enabled
+    fn funding_addresses(&self) -> Vec<String> {
+        let Some(selected) = self.ui_state.selected_wallet.clone() else {
+            return Vec::new();
         };
-        Ok(wallet)
+        let mut addresses = vec![selected];
+        if self.ui_state.combine_wallets {
+            for address in &self.ui_state.combined_wallets {
+                if !addresses.contains(address) && self.bc_module.wallets.get_wallet(address).is_some() {
+                    addresses.push(address.clone());
+                }
+            }
+        }
+        addresses
     }
 
-    fn valid_tx_fields(&self) -> Result<(String, Wallet, String, i32)> {
+    // Sender name, the wallets funding the transaction with their change wallets (change goes to the
+    // first), receiver and amount
+    fn valid_tx_fields(&self) -> Result<(String, Vec<Wallet>, String, i32)> {
         let selected_wallet_name = self
             .ui_state
             .selected_wallet
@@ -347,255 +753,1670 @@ impl MyApp {
     
         println!("From: {}", selected_wallet_name);
     
-        let wallet = self
-            .bc_module
-            .wallets
-            .get_wallet(&selected_wallet_name)
-            .ok_or_else(|| failure::err_msg("Wallet not found for the selected address"))?;
+        if self.bc_module.wallets.get_wallet(&selected_wallet_name).is_none() {
+            return Err(failure::err_msg("Wallet not found for the selected address"));
+        }
     
         if self.ui_state.receiver_address.is_empty() {
             return Err(failure::err_msg("Receiver address cannot be empty"));
         }
     
         println!("To: {}", self.ui_state.receiver_address);
+
+        if is_unspendable_address(&self.ui_state.receiver_address) && !self.ui_state.confirm_burn {
+            return Err(failure::err_msg("Nobody can spend coins sent to this address, confirm that you want to burn them"));
+        }
     
         if self.ui_state.tx_amount <= 0 {
             return Err(failure::err_msg("Transaction amount must be greater than zero"));
         }
     
         println!("Amount: {}", self.ui_state.tx_amount);
+
+        if self.ui_state.tx_memo.len() > MAX_TX_DATA {
+            return Err(failure::format_err!("The memo is {} bytes, at most {} can be attached", self.ui_state.tx_memo.len(), MAX_TX_DATA));
+        }
     
+        let mut wallets = Vec::new();
+        for address in self.funding_addresses() {
+            wallets.extend(self.bc_module.wallets.spending_wallets(&address));
+        }
+
         Ok((
             selected_wallet_name,
-            wallet.clone(),
+            wallets,
             self.ui_state.receiver_address.clone(),
             self.ui_state.tx_amount,
         ))
     }
 
+    // Sends from the first of `funding.wallets`, the From wallet, and the others when they're combined
     pub async fn send_transaction(
-        selected_wallet_name: String,
-        wallet: Wallet,
-        receiver_address: String,
-        tx_amount: i32,
+        funding: Funding,
+        payment: Payment,
+        selection: CoinSelection,
         utxo_set: Arc<RwLock<UTXOSet>>,
-        server: Arc<RwLock<Server>>,
-    ) -> Result<bool> {
-        let tx = Transaction::new_utxo(&wallet, &receiver_address, tx_amount, &utxo_set)
+        network: Arc<dyn NetworkControl>,
+        pending_sends: Arc<RwLock<PendingSends>>,
+    ) -> Result<String> {
+        let Funding { wallets, change } = funding;
+        let Payment { to, mode, data } = payment;
+        let tx = Transaction::new_utxo(&wallets, &to, mode, selection, data, change.as_ref(), &utxo_set)
             .await
-            .map_err(|e| failure::err_msg(e))?;
+            .map_err(failure::err_msg)?;
+        let txid = tx.id.clone();
     
         let mine_now = false;
 
         if mine_now {
-            let cbtx = Transaction::new_coinbase(selected_wallet_name, String::from("reward!"))
-                .map_err(|e| failure::err_msg(e))?;
+            let height = utxo_set.read().await
+                .blockchain.read().await
+                .get_best_height()
+                .map_err(failure::err_msg)? + 1;
+            let cbtx = Transaction::new_coinbase(wallets[0].get_address(), String::from("reward!"), height)
+                .map_err(failure::err_msg)?;
     
             let new_block = utxo_set.write().await
                 .blockchain.write().await
                 .mine_block(vec![cbtx, tx])
-                .map_err(|e| failure::err_msg(e))?;
+                .map_err(failure::err_msg)?;
     
             utxo_set.write().await
                 .update(&new_block)
-                .map_err(|e| failure::err_msg(e))?;
+                .map_err(failure::err_msg)?;
 
         } else {
-            server.write().await.send_transaction(&tx).await?;
+            MyApp::broadcast(network.as_ref(), &utxo_set, &tx).await?;
+            MyApp::track_send(&pending_sends, &utxo_set, tx, None, &[to]).await?;
         }
     
-        Ok(true)
-    }
-    
-    
-    fn preview_transaction(&self) {
-
-        // display popup
-
+        Ok(txid)
     }
 
-    fn clear_transaction_form(&mut self){
-        // Transaction Tab
-        self.ui_state.selected_wallet = None;
-        self.ui_state.receiver_address = String::from("");
-        self.ui_state.tx_amount = 0;
-        self.ui_state.tx_gas_price = 0;
-        self.ui_state.tx_gas_limit = 0;
-    }
-
-    pub fn add_notification(&mut self, message: String) {
-        let notification = Notification {
-            id: self.generate_notification_id(),
-            message,
-            start_time: std::time::Instant::now(),
-            duration: 10, // 10 seconds
-        };
-
-        self.notif_module.notifications.push(notification);
+    // Broadcasts a send built from our wallets. Its inputs are released for other sends
+    // when it doesn't go out
+    async fn broadcast(network: &dyn NetworkControl, utxo_set: &RwLock<UTXOSet>, tx: &Transaction) -> Result<BroadcastReport> {
+        let report = network.send_transaction(tx.clone()).await;
+        if report.is_err() {
+            utxo_set.read().await.release(tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)));
+        }
+        report
     }
 
-    fn generate_notification_id(&mut self) -> u32 {
-        self.notif_module.notification_counter += 1;
-        self.notif_module.notification_counter
+    // Records a broadcast send so its inputs stay locked until a block holds it or it expires,
+    // and in the sent transactions with what it pays `destinations`
+    async fn track_send(
+        pending_sends: &RwLock<PendingSends>,
+        utxo_set: &RwLock<UTXOSet>,
+        tx: Transaction,
+        replaces: Option<String>,
+        destinations: &[String],
+    ) -> Result<()> {
+        // the send went out already, without a record it's only missing from the list
+        let recorded = utxo_set.read().await.blockchain.read().await.record_sent(&tx, destinations, clock::now_millis());
+        if let Err(e) = recorded {
+            println!("Couldn't record sent transaction {}: {}", tx.id, e);
+        }
+        let mut pending_sends = pending_sends.write().await;
+        pending_sends.record(tx, clock::now_millis(), replaces)?;
+        pending::apply_locks(&pending_sends, utxo_set).await;
+        Ok(())
     }
 
-
-    fn add_peer(&mut self, new_peer_ip: String, new_peer_port: String) -> Result<()> {        
+    // Confirmed sends leave the ledger, old ones are abandoned. Sends are looked up in the
+    // chain as well, blocks may have come in while the app was closed
+    fn check_pending_sends(&mut self) {
+        self.ui_state.pending_checked = Some(std::time::Instant::now());
+        let pending_sends = Arc::clone(&self.bc_module.pending_sends);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
         let sender = self.sender.clone();
-        let server_clone = Arc::clone(&self.net_module.server);
+        let max_age = std::time::Duration::from_secs(SETTINGS.pending_expiry_hours * 60 * 60);
+        let addresses = self.bc_module.wallets.get_all_address();
+
+        self.tasks.spawn(async move {
+            let result = async {
+                let mut pending_sends = pending_sends.write().await;
+                let mined: Vec<String> = {
+                    let utxo = utxo_set.read().await;
+                    let blockchain = utxo.blockchain.read().await;
+                    pending_sends.txids().into_iter().filter(|txid| blockchain.find_transaction(txid).is_ok()).collect()
+                };
+                let settled = pending_sends.reconcile(&mined)?;
+                let abandoned = pending_sends.expire(clock::now_millis(), max_age)?;
+                pending::apply_locks(&pending_sends, &utxo_set).await;
+
+                let utxo = utxo_set.read().await;
+                let mut pending_amounts = HashMap::new();
+                for address in addresses {
+                    let locked = utxo.locked_value(&address::address_to_hash(&address)?)?;
+                    if locked > 0 {
+                        pending_amounts.insert(address, locked);
+                    }
+                }
+                Ok::<_, failure::Error>(TaskMessage::PendingSendsChecked { abandoned, settled, pending_amounts })
+            }
+            .await;
 
-        //println!("Server instance: {:?} add_peer", Arc::as_ptr(&server_clone));
+            let message = result.unwrap_or_else(|e| TaskMessage::Error(format!("Couldn't check pending sends: {}", e)));
+            let _ = sender.send(message).await;
+        });
+    }
 
-        let new_peer_ip_port = new_peer_ip + ":" + &new_peer_port;
-        //println!("New_peer_ip: {}", new_peer_ip.clone());
-        
-        RUNTIME.spawn( async move {
-            match server_clone.write().await.add_peer(new_peer_ip_port.clone()).await {
-                Ok(_result) => {
-                    let _ = sender.send(TaskMessage::PeerAdded(new_peer_ip_port)).await;
+    fn handle_pending_sends_checked(&mut self, abandoned: Vec<PendingSend>, settled: Vec<Settled>, pending_amounts: HashMap<String, i64>) {
+        self.ui_state.pending_amounts = pending_amounts;
+        self.refresh_sent_txs();
+        for send in abandoned {
+            self.ui_state.pending_txids.retain(|txid| *txid != send.tx.id);
+            self.add_warning(
+                format!(
+                    "Transaction {} wasn't mined within {} hours and was abandoned. Its coins can be spent again.",
+                    &send.tx.id,
+                    SETTINGS.pending_expiry_hours,
+                ),
+                Some(NotificationAction::RetryWithHigherFee(send.tx.id)),
+            );
+        }
+        // The originals of confirmed retries are settled as conflicted, that's expected
+        let retried: Vec<String> = settled.iter()
+            .filter_map(|outcome| match outcome {
+                Settled::Confirmed(send) => send.replaces.clone(),
+                Settled::Conflicted(_) => None,
+            })
+            .collect();
+        for outcome in settled {
+            match outcome {
+                Settled::Confirmed(send) if send.state == SendState::Abandoned => {
+                    self.add_notification(format!("Abandoned transaction {} was mined after all", &send.tx.id));
                 }
-                Err(err) => {
-                    println!("Error while adding peer: {}", err);
+                Settled::Confirmed(_) => {}
+                Settled::Conflicted(send) if retried.contains(&send.tx.id) => {
+                    self.ui_state.pending_txids.retain(|txid| *txid != send.tx.id);
+                }
+                Settled::Conflicted(send) => {
+                    self.ui_state.pending_txids.retain(|txid| *txid != send.tx.id);
+                    self.add_notification(format!(
+                        "Transaction {} was dropped, another one spending the same coins was mined",
+                        &send.tx.id,
+                    ));
                 }
             }
-        });
-
-        Ok(())
+        }
+        let pending_txids = &self.ui_state.pending_txids;
+        self.ui_state.receipts.retain(|txid, _| pending_txids.contains(txid));
     }
-}
-
-impl Default for MyApp {
-    fn default() -> Self {
-        let (sender, receiver) = mpsc::channel(100);        
-        
-        // Create the `utxo_set` first, since it is needed by `server`
-        let utxo_set = Arc::new(RwLock::new(UTXOSet {
-            blockchain: Arc::new(RwLock::new(Blockchain::default_empty())),
-        }));
 
-        // Use `utxo_set` to create the `server`
-        let server = Arc::new(RwLock::new(Server::new("8334", "", Arc::clone(&utxo_set)).unwrap()));
+    // Sends the pending or abandoned `txid` again with a higher fee, paid out of its change.
+    // Peers replace the original with it, see mempool
+    fn retry_with_higher_fee(&mut self, txid: String) {
+        let wallets: Vec<Wallet> = self.bc_module.wallets.get_all_address().iter()
+            .flat_map(|address| self.bc_module.wallets.spending_wallets(address))
+            .collect();
+        let pending_sends = Arc::clone(&self.bc_module.pending_sends);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let network = Arc::clone(&self.net_module.network);
+
+        self.spawn_action(ActionKind::SendTx, async move {
+            let result = async {
+                let original = pending_sends.read().await.get(&txid).cloned()
+                    .ok_or_else(|| failure::format_err!("Transaction {} is no longer pending", txid))?;
+                let tx = Transaction::bump_fee(&original.tx, &wallets, DEFAULT_FEE_RATE, &utxo_set).await?;
+                network.send_transaction(tx.clone()).await?;
+                let retry_id = tx.id.clone();
+                let destinations: Vec<String> = utxo_set.read().await.blockchain.read().await
+                    .sent_transaction(&txid)?
+                    .map(|sent| sent.recipients.into_iter().map(|(address, _)| address).collect())
+                    .unwrap_or_default();
+                MyApp::track_send(&pending_sends, &utxo_set, tx, Some(txid.clone()), &destinations).await?;
+                Ok::<String, failure::Error>(retry_id)
+            }
+            .await
+            .map_err(|e| e.to_string());
 
-        
-        Self {
-            bc_module: BlockchainModule {
-                wallets: Wallets::default(),
-                balances: Vec::new(),
-                utxo_set: utxo_set,
-            },
-    
-            net_module: NetworkModule {
-                public_ip: None,
-                server: server,
-            },
-    
-            ui_state: UIState {
-                active_tab: Tab::Blockchain,
-    
-                // Blockchain Tab
-                blocks: Vec::new(),
-                show_transactions: false,
-                blocks_to_display: 5,
-                block_search_query: String::new(),
-                block_search_result: None,
+            TaskMessage::FeeBumped(txid, result)
+        });
+    }
     
-                // Transaction Tab
-                selected_wallet: None,
-                receiver_address: String::from(""),
-                tx_amount: 0,
-                tx_gas_price: 0,
-                tx_gas_limit: 0,
     
-                // Wallets Tab
-                show_delete_popup: None,
-                show_add_existing_wallet_popup: false, 
+    // Queues the payment in the form instead of sending it, see outbox
+    fn queue_payment(&mut self) {
+        match self.valid_tx_fields() {
+            Ok((from, _, to, amount)) => {
+                self.ui_state.outbox.add(&from, &to, amount, clock::now_millis());
+                self.add_notification(format!("{} coins to {} added to the outbox", amount, to));
+                self.ui_state.receiver_address.clear();
+                self.ui_state.tx_amount = 0;
+                self.ui_state.send_max_fee = None;
+                self.ui_state.confirm_burn = false;
+                self.ui_state.payment_request_banner = None;
+            }
+            Err(err) => self.add_notification(err.to_string()),
+        }
+    }
 
-                // Peers Tab
-                peer_ip_address_input: String::new(),
-                peer_port_input: String::from("8334"),
-                connected_peers_displayed: Vec::new(),
-            },
-            
-            notif_module: NotificationModule {
-                // Notification Tab
-                notifications: Vec::new(),
-                notification_counter: 0,
-            },
+    // Sends the queued payments of wallet `from` as one transaction, the result comes back as a BatchSent
+    fn flush_outbox(&mut self, from: String) {
+        let wallets = self.bc_module.wallets.spending_wallets(&from);
+        if wallets.is_empty() {
+            self.add_notification(format!("Wallet {} is gone, its queued payments can't be sent", from));
+            return;
+        }
+        let invalid_before = self.invalid_payments(&from);
+        let batch = self.ui_state.outbox.take_batch(&from);
+        let invalid = self.invalid_payments(&from) - invalid_before;
+        if invalid > 0 {
+            self.add_warning(format!("{} queued payments can't be sent, fix or remove them in the outbox", invalid), None);
+        }
+        if batch.is_empty() {
+            return;
+        }
+
+        let ids: Vec<u64> = batch.iter().map(|payment| payment.id).collect();
+        let payments: Vec<(String, i32)> = batch.into_iter().map(|payment| (payment.to, payment.amount)).collect();
+        let network = Arc::clone(&self.net_module.network);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let pending_sends = Arc::clone(&self.bc_module.pending_sends);
+        let batch_ids = ids.clone();
+        let change = self.bc_module.wallets.change_provider();
+
+        let spawned = self.spawn_action(ActionKind::SendBatch, async move {
+            let result = async {
+                let tx = Transaction::new_batch(&wallets, &payments, change.as_ref(), &utxo_set).await?;
+                MyApp::broadcast(network.as_ref(), &utxo_set, &tx).await?;
+                let txid = tx.id.clone();
+                let destinations: Vec<String> = payments.into_iter().map(|(to, _)| to).collect();
+                MyApp::track_send(&pending_sends, &utxo_set, tx, None, &destinations).await?;
+                Ok::<String, failure::Error>(txid)
+            }
+            .await
+            .map_err(|e| e.to_string());
 
-            sender: sender,
-            receiver: receiver,
+            TaskMessage::BatchSent(batch_ids, result)
+        });
+        if !spawned {
+            self.ui_state.outbox.failed(&ids);
         }
     }
-}
-
-impl eframe::App for MyApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {       
-        let bg_color = egui::Color32::from_rgb(30, 30, 30); // Dark gray
-        let visuals = egui::Visuals {
-            override_text_color: Some(egui::Color32::WHITE), // Optional: Ensure white text
-            ..egui::Visuals::dark() // Base dark theme
-        };
-        ctx.set_visuals(visuals);
 
-        ctx.set_style({
-            let mut style = (*ctx.style()).clone();
+    fn invalid_payments(&self, from: &str) -> usize {
+        self.ui_state.outbox.payments().iter()
+            .filter(|payment| payment.from == from && matches!(payment.status, PaymentStatus::Invalid(_)))
+            .count()
+    }
 
-            style.visuals.window_fill = bg_color;
-            style.spacing.button_padding = egui::vec2(15.0, 10.0); // Custom padding
+    fn handle_batch_sent(&mut self, ids: Vec<u64>, result: std::result::Result<String, String>) {
+        match result {
+            Ok(txid) => {
+                self.ui_state.outbox.sent(&ids, &txid);
+                self.add_notification(format!("Sent {} queued payments in transaction {}", ids.len(), txid));
+                self.ui_state.pending_txids.push(txid);
+                self.check_pending_sends();
+            }
+            Err(err) => {
+                self.ui_state.outbox.failed(&ids);
+                self.add_notification(format!("Couldn't send the batch, the payments stay queued: {}", err));
+            }
+        }
+    }
 
-            style
-        });
+    fn render_outbox(&mut self, ui: &mut egui::Ui) {
+        let queued = self.ui_state.outbox.payments().iter().filter(|p| p.status == PaymentStatus::Queued).count();
+        ui.collapsing(format!("Outbox ({} queued)", queued), |ui| {
+            let mut remove = None;
+            Grid::new("outbox").striped(true).show(ui, |ui| {
+                ui.strong("From");
+                ui.strong("To");
+                ui.strong("Amount");
+                ui.strong("Status");
+                ui.end_row();
+                for payment in self.ui_state.outbox.payments() {
+                    ui.label(tagged_text(&payment.from, &payment.from));
+                    ui.label(&payment.to);
+                    ui.label(payment.amount.to_string());
+                    match &payment.status {
+                        PaymentStatus::Queued => ui.label("Queued"),
+                        PaymentStatus::Invalid(reason) => ui.label(egui::RichText::new(format!("Invalid: {}", reason)).color(egui::Color32::RED)),
+                        PaymentStatus::Sending => ui.label("Sending…"),
+                        PaymentStatus::Sent(txid) => ui.label(format!("Sent in {}", txid)),
+                    };
+                    if matches!(payment.status, PaymentStatus::Queued | PaymentStatus::Invalid(_)) && ui.small_button("Remove").clicked() {
+                        remove = Some(payment.id);
+                    }
+                    ui.end_row();
+                }
+            });
+            if let Some(id) = remove {
+                self.ui_state.outbox.remove(id);
+            }
 
-        // Render the UI
-        egui::CentralPanel::default().show(ctx, |ui| {
-            
-            // Navigation bar at the top
             ui.horizontal(|ui| {
-                if ui.button(egui::RichText::new("Blockchain").size(16.0)).clicked() {
-                    self.ui_state.active_tab = Tab::Blockchain;
-                }
-                if ui.button(egui::RichText::new("Transactions").size(16.0)).clicked() {
-                    self.ui_state.active_tab = Tab::Transactions;
+                if self.action_button(ui, ActionKind::SendBatch, "Send batch now") {
+                    match self.ui_state.selected_wallet.clone() {
+                        Some(from) => self.flush_outbox(from),
+                        None => self.add_notification(String::from("Select the From wallet whose payments to send")),
+                    }
                 }
-                if ui.button(egui::RichText::new("Wallets").size(16.0)).clicked() {
-                    self.ui_state.active_tab = Tab::Wallets;
+                if ui.button("Clear sent").clicked() {
+                    self.ui_state.outbox.clear_sent();
                 }
-                if ui.button(egui::RichText::new("Peers").size(16.0)).clicked() {
-                    self.ui_state.active_tab = Tab::Peers;
+            });
+        });
+    }
+
+    // Our sends without a block yet, with what peers acknowledged of them
+    fn render_pending_sends(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing(format!("Pending sends ({})", self.ui_state.pending_txids.len()), |ui| {
+            let mut export = None;
+            let mut bump = None;
+            let sending = self.actions_in_flight.contains(&ActionKind::SendTx);
+            Grid::new("pending_sends").striped(true).show(ui, |ui| {
+                for txid in &self.ui_state.pending_txids {
+                    ui.label(txid);
+                    if ui.add_enabled(!sending, egui::Button::new("Bump fee"))
+                        .on_hover_text("Send it again with a higher fee, paid out of its change")
+                        .clicked()
+                    {
+                        bump = Some(txid.clone());
+                    }
+                    match self.ui_state.receipts.get(txid) {
+                        Some(receipt) => {
+                            let at = receipt.acknowledged_at().map(format_time_of_day).unwrap_or_default();
+                            ui.label(format!("acknowledged by {} peers at {}", receipt.acks.len(), at))
+                                .on_hover_text("Peers that signed for having the transaction in their mempool");
+                            if ui.small_button("Export receipt…").clicked() {
+                                export = Some(receipt.clone());
+                            }
+                        }
+                        None => {
+                            ui.label("not acknowledged");
+                        }
+                    }
+                    ui.end_row();
                 }
-                if ui.button(egui::RichText::new("Settings").size(16.0)).clicked() {
-                    self.ui_state.active_tab = Tab::Settings;
+            });
+
+            if let Some(txid) = bump {
+                self.retry_with_higher_fee(txid);
+            }
+            if let Some(receipt) = export {
+                let dialog = rfd::FileDialog::new().add_filter("JSON", &["json"]).set_file_name(format!("{}_receipt.json", receipt.txid));
+                if let Some(path) = dialog.save_file() {
+                    match receipt.to_json().map(|json| std::fs::write(&path, json)) {
+                        Ok(Ok(())) => self.add_notification(format!("Receipt exported to {}", path.display())),
+                        Ok(Err(e)) => self.add_notification(format!("Couldn't export the receipt: {}", e)),
+                        Err(e) => self.add_notification(format!("Couldn't export the receipt: {}", e)),
+                    }
                 }
+            }
+        });
+    }
 
-                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                    let wallet_count = self.bc_module.wallets.get_all_address().len();
-                    
-                    let text = if wallet_count > 0 {
-                        format!("Connected Wallets: {}", wallet_count)
-                    } else {
-                        "No Wallets Connected".to_owned()
+    // Everything we sent, from pending to however deep it's buried
+    fn render_sent_txs(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing(format!("Sent transactions ({})", self.ui_state.sent_txs.len()), |ui| {
+            Grid::new("sent_txs").striped(true).show(ui, |ui| {
+                ui.strong("Transaction");
+                ui.strong("To");
+                ui.strong("Amount");
+                ui.strong("Fee");
+                ui.strong("Sent");
+                ui.strong("Status");
+                ui.end_row();
+                for sent in &self.ui_state.sent_txs {
+                    ui.label(&sent.txid[..sent.txid.len().min(16)]).on_hover_text(&sent.txid);
+                    let recipients: Vec<&str> = sent.recipients.iter().map(|(to, _)| to.as_str()).collect();
+                    ui.label(recipients.join(", "));
+                    ui.label(sent.amount.to_string());
+                    ui.label(sent.fee.to_string());
+                    ui.label(format_time_of_day(sent.created_at));
+                    match &sent.status {
+                        SentStatus::Pending => ui.label("Pending"),
+                        SentStatus::Mined(_) => match sent.confirmations(self.ui_state.sent_tip_height) {
+                            1 => ui.label("1 confirmation"),
+                            n => ui.label(format!("{} confirmations", n)),
+                        },
+                        SentStatus::Conflicted(txid, _) => ui.label(format!("Replaced by {}", &txid[..txid.len().min(16)]))
+                            .on_hover_text(txid),
                     };
-
-                    ui.add_space(10.0);
-                    if ui.add(
-                            egui::Label::new(egui::RichText::new(text))
-                                .sense(egui::Sense::click()), // Make it interactive          
-                        )
-                        .on_hover_text("Go to Wallets tab") // Optional tooltip
-                        .on_hover_cursor(egui::CursorIcon::PointingHand) // Change cursor to pointer
-                        .clicked(){
-                            self.ui_state.active_tab = Tab::Wallets;
-                        };
-                });
+                    ui.end_row();
+                }
             });
+        });
+    }
 
-            // Section rendering based on the active tab
-            ui.separator(); // Add a visual separator
+    // Burns burn_amount from the selected wallet, the result comes back as a TransactionSent
+    fn burn_coins(&mut self) -> Result<()> {
+        let wallets = self.ui_state.selected_wallet.as_ref()
+            .map(|address| self.bc_module.wallets.spending_wallets(address))
+            .filter(|wallets| !wallets.is_empty())
+            .ok_or_else(|| failure::err_msg("No wallet selected"))?;
+        if self.ui_state.burn_amount <= 0 {
+            return Err(failure::err_msg("Burn amount must be greater than zero"));
+        }
 
-            match self.ui_state.active_tab {
+        let amount = self.ui_state.burn_amount;
+        let network = Arc::clone(&self.net_module.network);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let pending_sends = Arc::clone(&self.bc_module.pending_sends);
+        let change = self.bc_module.wallets.change_provider();
+        self.ui_state.burn_confirmed = false;
+
+        self.spawn_action(ActionKind::BurnCoins, async move {
+            let result = async {
+                let tx = Transaction::new_burn(&wallets, amount, change.as_ref(), &utxo_set).await?;
+                MyApp::broadcast(network.as_ref(), &utxo_set, &tx).await?;
+                let txid = tx.id.clone();
+                MyApp::track_send(&pending_sends, &utxo_set, tx, None, &[String::from(wallet_history::BURN_COUNTERPARTY)]).await?;
+                Ok::<String, failure::Error>(txid)
+            }
+            .await
+            .map_err(|e| e.to_string());
+
+            TaskMessage::TransactionSent(result)
+        });
+
+        Ok(())
+    }
+
+    // Decodes the pasted hex and sends the transaction once the mempool rules take it, the
+    // result comes back as a RawTxBroadcast
+    fn broadcast_raw_tx(&mut self) -> Result<()> {
+        let tx = Transaction::from_hex(&self.ui_state.raw_tx_input)?;
+        let network = Arc::clone(&self.net_module.network);
+
+        self.spawn_action(ActionKind::BroadcastRawTx, async move {
+            let result = network.broadcast_raw_transaction(tx.clone()).await
+                .map(|_| tx.id)
+                .map_err(|e| e.to_string());
+            TaskMessage::RawTxBroadcast(result)
+        });
+        Ok(())
+    }
+
+    // Asks what a sweep of the From wallet sends after the fee, it fills in the amount
+    fn load_max_amount(&mut self) {
+        if self.mock_ui {
+            self.add_notification(String::from("Send max isn't available with --mock-ui"));
+            return;
+        }
+        let Some(wallets) = self.ui_state.selected_wallet.as_ref().map(|address| self.bc_module.wallets.spending_wallets(address)) else {
+            return;
+        };
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+        self.tasks.spawn(async move {
+            let result = Transaction::sweep_amount(&wallets, DEFAULT_FEE_RATE, &utxo_set).await;
+            let _ = sender.send(TaskMessage::MaxAmountLoaded(result.map_err(|e| e.to_string()))).await;
+        });
+    }
+
+    // Asks for the fee rate of a preset, the fee field is filled in from it
+    fn estimate_fee(&mut self, preset: &'static str, target_blocks: u32) {
+        let network = Arc::clone(&self.net_module.network);
+        let sender = self.sender.clone();
+        self.tasks.spawn(async move {
+            let result = network.estimate_fee(target_blocks).await.map_err(|e| e.to_string());
+            let _ = sender.send(TaskMessage::FeeEstimated(preset, result)).await;
+        });
+    }
+
+    // The fee of the send in the form at `fee_rate`: the inputs of its preview when there's
+    // one for this amount, one otherwise, with change and the memo
+    fn fee_at_rate(&self, fee_rate: i64) -> i32 {
+        let inputs = self.ui_state.send_preview.as_ref()
+            .filter(|(amount, _)| *amount == self.ui_state.tx_amount)
+            .map_or(1, |(_, preview)| preview.inputs);
+        let size = estimate_size(inputs, 2) + self.ui_state.tx_memo.len();
+        fee_for_size(size, fee_rate.clamp(0, i32::MAX as i64) as i32)
+    }
+
+    // Sends the whole balance of the wallet and its change wallets minus the fee to
+    // `destination`, returns the txid
+    pub async fn sweep_wallet(
+        wallets: Vec<Wallet>,
+        destination: String,
+        utxo_set: Arc<RwLock<UTXOSet>>,
+        network: Arc<dyn NetworkControl>,
+    ) -> Result<String> {
+        if address::address_to_hash(&destination).is_err() {
+            return Err(failure::format_err!("Invalid destination address: {}", destination));
+        }
+        if wallets.iter().any(|wallet| destination == wallet.get_address()) {
+            return Err(failure::err_msg("Destination must be a different wallet"));
+        }
+
+        let (_, fee) = Transaction::sweep_amount(&wallets, DEFAULT_FEE_RATE, &utxo_set).await?;
+        let tx = Transaction::new_sweep(&wallets, &destination, fee, &utxo_set).await?;
+        let report = MyApp::broadcast(network.as_ref(), &utxo_set, &tx).await?;
+
+        // Deleting the wallet is only safe once some peer has the transaction
+        if report.delivered == 0 {
+            return Err(failure::err_msg("No peer acknowledged the sweep transaction"));
+        }
+
+        Ok(tx.id)
+    }
+
+    fn start_sweep_then_delete(&mut self, address: &str) {
+        if self.mock_ui {
+            self.add_notification(String::from("Sweeping isn't available with --mock-ui"));
+            return;
+        }
+        let wallets = self.bc_module.wallets.spending_wallets(address);
+        if wallets.is_empty() {
+            return;
+        }
+
+        let destination = self.ui_state.sweep_destination.trim().to_string();
+        let from = address.to_string();
+        let sender = self.sender.clone();
+        let network = Arc::clone(&self.net_module.network);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+
+        self.ui_state.sweep_in_progress = Some(from.clone());
+        self.ui_state.show_delete_popup = None;
+        self.add_notification(format!("Sweeping funds from {} before deleting it...", wallet_label(&from)));
+
+        self.tasks.spawn(async move {
+            let result = MyApp::sweep_wallet(wallets, destination, utxo_set, network)
+                .await
+                .map_err(|e| e.to_string());
+            let _ = sender.send(TaskMessage::SweepFinished(from, result)).await;
+        });
+    }
+
+    // The wallet is only deleted once the sweep was broadcast
+    fn handle_sweep_finished(&mut self, address: String, result: std::result::Result<String, String>) {
+        self.ui_state.sweep_in_progress = None;
+        self.ui_state.sweep_destination.clear();
+
+        match result {
+            Ok(txid) => {
+                self.add_notification(format!("Funds swept in transaction {}", txid));
+                // swept along with it
+                for change in self.bc_module.wallets.change_addresses(&address) {
+                    if let Err(e) = self.bc_module.wallets.delete_wallet(&change) {
+                        self.add_notification(format!("Failed to delete change wallet {}: {}", wallet_label(&change), e));
+                    }
+                }
+                if let Err(e) = self.delete_wallet(&address) {
+                    self.add_notification(format!("Failed to delete wallet: {}", e));
+                }
+            }
+            Err(err) => {
+                self.add_notification(format!("Sweep failed, wallet {} was not deleted: {}", wallet_label(&address), err));
+            }
+        }
+    }
+
+    fn open_tab(&mut self, tab: Tab) {
+        if tab == Tab::Blockchain {
+            self.ui_state.new_blocks_since_view = 0;
+        }
+        if tab == Tab::Peers {
+            self.refresh_peers();
+        }
+        if tab == Tab::Transactions {
+            self.refresh_mempool();
+        }
+        if tab == Tab::Settings {
+            self.refresh_audit_log();
+        }
+        self.ui_state.active_tab = tab;
+    }
+
+    // Number shown next to the tab's label, None hides the badge
+    fn tab_badge(&self, tab: &Tab) -> Option<String> {
+        let count = match tab {
+            Tab::Blockchain => return match self.ui_state.new_blocks_since_view {
+                0 => None,
+                n => Some(format!("+{}", n)),
+            },
+            Tab::Transactions => self.ui_state.pending_txids.len(),
+            Tab::Peers => self.ui_state.connected_peers_displayed.len(),
+            Tab::Wallets | Tab::Settings => 0,
+        };
+        (count > 0).then(|| count.to_string())
+    }
+
+    // One batch per BLOCK_EVENT_INTERVAL at most, however fast blocks come in
+    fn handle_blocks_connected(&mut self, batch: BlocksConnected) {
+        self.ui_state.status_warning = None; // the chain is moving again
+        self.ui_state.pending_txids.retain(|txid| !batch.txids.contains(txid));
+        self.ui_state.receipts.retain(|txid, _| !batch.txids.contains(txid));
+        self.count_new_blocks(batch.count);
+        self.check_pending_sends();
+        if batch.count == 1 {
+            self.add_notification(format!("New block at height {}", batch.height));
+        } else {
+            self.add_notification(format!("Synced {} blocks, new height {}", batch.count, batch.height));
+        }
+        self.refresh_blocks();
+        self.refresh_wallet_history();
+        self.refresh_chain_stats();
+        if self.ui_state.active_tab == Tab::Transactions {
+            self.refresh_mempool();
+        }
+    }
+
+    fn handle_blocks_loaded(&mut self, new_blocks: Vec<Block>) {
+        // A refresh that raced an earlier one may return blocks we already have
+        let top = self.ui_state.blocks.first().map_or(-1, |b| b.height);
+        let new_blocks: Vec<Block> = new_blocks.into_iter().filter(|b| b.get_height() > top).collect();
+
+        let addresses = self.bc_module.wallets.get_all_address();
+        let mut reached = Vec::new();
+        let mut received = Vec::new();
+        for (txid, address, amount, height, sender) in incoming_payments(new_blocks.iter().rev(), &addresses) {
+            if let Some(sender) = sender {
+                received.push(format!(
+                    "Received {} coins from {} to {}",
+                    amount, self.counterparty_name(&sender), wallet_label(&address)
+                ));
+            }
+            reached.extend(self.ui_state.incoming.watch(&txid, &address, amount, height));
+        }
+        for message in received {
+            self.add_notification(message);
+        }
+        if let Some(tip) = new_blocks.first() {
+            reached.extend(self.ui_state.incoming.tip_changed(tip.get_height()));
+        }
+        for payment in reached {
+            self.add_notification(format!(
+                "✅ Payment of {} coins to {} is confirmed ({} confirmations)",
+                payment.amount, wallet_label(&payment.address), payment.confirmations
+            ));
+        }
+
+        self.ui_state.blocks.splice(0..0, new_blocks.iter().map(BlockSummary::of));
+    }
+
+    fn handle_block_transactions_loaded(&mut self, hash: String, txids: std::result::Result<Vec<BlockTx>, String>) {
+        // Collapsed again while loading
+        if !self.ui_state.block_txids.contains_key(&hash) {
+            return;
+        }
+        match txids {
+            Ok(txids) => { self.ui_state.block_txids.insert(hash, Some(txids)); }
+            Err(e) => {
+                self.ui_state.block_txids.remove(&hash);
+                self.add_notification(format!("Couldn't read the transactions of block {}: {}", hash, e));
+            }
+        }
+    }
+
+    // Only blocks that arrive while the Blockchain tab isn't open count towards its badge
+    fn count_new_blocks(&mut self, count: usize) {
+        if self.ui_state.active_tab != Tab::Blockchain {
+            self.ui_state.new_blocks_since_view += count;
+        }
+    }
+
+    // Runs `action` on the runtime and forwards the message it returns. While an action of the
+    // same kind is still pending nothing is spawned and false is returned
+    fn spawn_action<F>(&mut self, kind: ActionKind, action: F) -> bool
+    where
+        F: Future<Output = TaskMessage> + Send + 'static,
+    {
+        // Everything else needs the UTXO set or the databases of a real node
+        if self.mock_ui && !matches!(kind, ActionKind::AddPeer | ActionKind::CreateWallet) {
+            self.add_notification(format!("{:?} isn't available with --mock-ui", kind));
+            return false;
+        }
+        if !self.actions_in_flight.insert(kind) {
+            println!("{:?} is already in progress", kind);
+            return false;
+        }
+
+        let sender = self.sender.clone();
+        let spawned = self.tasks.spawn(async move {
+            let message = action.await;
+            let _ = sender.send(message).await;
+            let _ = sender.send(TaskMessage::ActionFinished(kind)).await;
+        });
+        if !spawned {
+            self.actions_in_flight.remove(&kind);
+        }
+        spawned
+    }
+
+    // A button that's disabled, with a spinner next to it, while its action is pending
+    fn action_button(&self, ui: &mut egui::Ui, kind: ActionKind, label: &str) -> bool {
+        if self.actions_in_flight.contains(&kind) {
+            ui.add_enabled(false, egui::Button::new(label));
+            ui.spinner();
+            false
+        } else {
+            ui.button(label).clicked()
+        }
+    }
+
+    // Reads the blocks above the displayed tip from the database
+    fn refresh_blocks(&self) {
+        let top = self.ui_state.blocks.first().map_or(-1, |b| b.height);
+        let chain = Arc::clone(&self.bc_module.chain);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let new_blocks = match chain.best_height().await {
+                Ok(best_height) => chain.blocks_range(top + 1, best_height).await,
+                Err(e) => Err(e),
+            };
+            match new_blocks {
+                Ok(new_blocks) => { let _ = sender.send(TaskMessage::BlocksLoaded(new_blocks)).await; }
+                Err(e) => println!("Failed to read the new blocks: {}", e),
+            }
+            let pruned_height = chain.pruned_height().await;
+            // The node prunes as blocks come in
+            if let Ok(pruned_height) = pruned_height {
+                let _ = sender.send(TaskMessage::PrunedHeightLoaded(pruned_height)).await;
+            }
+        });
+    }
+
+    // Reads the blocks below the oldest loaded one that "Load More Blocks" is about to show
+    fn load_older_blocks(&self) {
+        let missing = self.ui_state.blocks_to_display.saturating_sub(self.ui_state.blocks.len()) as i32;
+        let Some(oldest) = self.ui_state.blocks.last().map(|b| b.height) else {
+            return;
+        };
+        if missing == 0 || self.ui_state.oldest_block_loaded {
+            return;
+        }
+        let chain = Arc::clone(&self.bc_module.chain);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let result = chain.blocks_range(oldest - missing, oldest - 1).await;
+            let message = match result {
+                Ok(blocks) => TaskMessage::OlderBlocksLoaded(blocks),
+                Err(e) => TaskMessage::Error(format!("Couldn't read older blocks: {}", e)),
+            };
+            let _ = sender.send(message).await;
+        });
+    }
+
+    // The stored bytes of a block as hex, for the clipboard
+    fn copy_block_hex(&self, hash: String) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let hex = utxo_set.read().await
+                .blockchain.read().await
+                .get_block(&hash)
+                .and_then(|block| raw::block_hex(&block))
+                .map_err(|e| e.to_string());
+            let _ = sender.send(TaskMessage::RawHexLoaded(hex)).await;
+        });
+    }
+
+    // A transaction of a block as raw hex (Transaction::to_hex), for the clipboard
+    fn copy_tx_hex(&self, block_hash: String, txid: String) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let hex = utxo_set.read().await
+                .blockchain.read().await
+                .get_block(&block_hash)
+                .and_then(|block| {
+                    let tx = block.get_transactions().iter().find(|tx| tx.id == txid)
+                        .ok_or_else(|| failure::format_err!("transaction {} isn't in block {}", txid, block_hash))?;
+                    tx.to_hex()
+                })
+                .map_err(|e| e.to_string());
+            let _ = sender.send(TaskMessage::RawHexLoaded(hex)).await;
+        });
+    }
+
+    fn handle_older_blocks_loaded(&mut self, blocks: Vec<Block>) {
+        // A refresh that raced this one may have loaded some of them
+        let oldest = self.ui_state.blocks.last().map_or(i32::MAX, |b| b.height);
+        let older: Vec<BlockSummary> = blocks.iter().filter(|b| b.get_height() < oldest).map(BlockSummary::of).collect();
+        // Nothing came back below a snapshot base
+        self.ui_state.oldest_block_loaded = older.last().is_none_or(|b| b.height == 0);
+        self.ui_state.blocks.extend(older);
+    }
+
+    fn handle_block_search_finished(&mut self, query: String, found: Option<BlockSummary>) {
+        // Answers to queries typed over since are dropped
+        if query == self.ui_state.block_search_query {
+            self.ui_state.block_search_result = found;
+        }
+    }
+
+    // Looks the query up as a height or a hash of the active chain
+    fn search_block(&self, query: String) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let found = {
+                let utxo_set = utxo_set.read().await;
+                let blockchain = utxo_set.blockchain.read().await;
+                let trimmed = query.trim();
+                match trimmed.parse::<i32>() {
+                    Ok(height) => blockchain.get_block_by_height(height),
+                    Err(_) => blockchain.get_block(trimmed),
+                }
+            };
+            let found = found.ok().map(|block| BlockSummary::of(&block));
+            let _ = sender.send(TaskMessage::BlockSearchFinished(query, found)).await;
+        });
+    }
+
+    // Reads the transaction ids and memos of an expanded block
+    fn load_block_transactions(&mut self, hash: String) {
+        self.ui_state.block_txids.insert(hash.clone(), None);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let txids = utxo_set.read().await
+                .blockchain.read().await
+                .get_block(&hash)
+                .map(|block| block.get_transactions().iter().map(|tx| (tx.id.clone(), tx.memo())).collect())
+                .map_err(|e| e.to_string());
+            let _ = sender.send(TaskMessage::BlockTransactionsLoaded(hash, txids)).await;
+        });
+    }
+
+    // Tracks wallets the index doesn't know yet (new or imported ones are backfilled) and
+    // reads the history of every wallet
+    fn refresh_wallet_history(&mut self) {
+        if self.ui_state.idle.defer(DeferredWork::WalletHistory) {
+            return;
+        }
+        let addresses = self.bc_module.wallets.get_all_address();
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let history = load_wallet_history(&*utxo_set.read().await.blockchain.read().await, &addresses);
+            match history {
+                Ok(history) => { let _ = sender.send(TaskMessage::WalletHistoryLoaded(history)).await; }
+                Err(err) => println!("Failed to load the wallet history: {}", err),
+            }
+        });
+    }
+
+    // Reads our sends with their statuses, rescanning the chain if the index fell behind
+    fn refresh_sent_txs(&mut self) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let loaded = {
+                let utxo = utxo_set.read().await;
+                let blockchain = utxo.blockchain.read().await;
+                blockchain.sent_transactions().map(|sent| (sent, blockchain.get_best_height().unwrap_or(-1)))
+            };
+            match loaded {
+                Ok((sent, height)) => { let _ = sender.send(TaskMessage::SentTxsLoaded(sent, height)).await; }
+                Err(err) => println!("Failed to load the sent transactions: {}", err),
+            }
+        });
+    }
+
+    // Tells the node whether the window is minimized, and catches up on what was put off
+    // while it was when it comes back
+    fn follow_visibility(&mut self, hidden: bool) {
+        self.net_module.visibility.set_hidden(hidden);
+        for work in self.ui_state.idle.set_hidden(hidden) {
+            match work {
+                DeferredWork::ChainStats => self.refresh_chain_stats(),
+                DeferredWork::WalletHistory => self.refresh_wallet_history(),
+                DeferredWork::Health => self.refresh_health(),
+            }
+        }
+    }
+
+    // Keeps a peer's ack with the pending send it's for
+    fn record_ack(&self, ack: TxAck) {
+        let pending_sends = Arc::clone(&self.bc_module.pending_sends);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let txid = ack.txid.clone();
+            match pending_sends.write().await.add_ack(ack) {
+                Ok(Some(receipt)) => { let _ = sender.send(TaskMessage::ReceiptUpdated(receipt)).await; }
+                Ok(None) => {}
+                Err(err) => println!("Failed to record the ack for {}: {}", txid, err),
+            }
+        });
+    }
+
+    fn refresh_chain_stats(&mut self) {
+        if self.ui_state.idle.defer(DeferredWork::ChainStats) {
+            return;
+        }
+        let chain = Arc::clone(&self.bc_module.chain);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let stats = chain.stats().await;
+            match stats {
+                Ok(stats) => { let _ = sender.send(TaskMessage::ChainStatsLoaded(stats)).await; }
+                Err(err) => println!("Failed to read the chain statistics: {}", err),
+            }
+        });
+    }
+
+    // Same report as `--healthcheck`, shown in the status bar
+    fn refresh_health(&mut self) {
+        if self.ui_state.idle.defer(DeferredWork::Health) {
+            return;
+        }
+        self.ui_state.health_refreshed = Some(std::time::Instant::now());
+        let network = Arc::clone(&self.net_module.network);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let report = network.healthcheck().await;
+            let _ = sender.send(TaskMessage::HealthLoaded(report)).await;
+        });
+    }
+
+    // Capabilities are learned in the handshake, after the peer was listed
+    // The address may have changed while the machine was away
+    fn refresh_public_ip(&self) {
+        if self.mock_ui {
+            return;
+        }
+        let sender = self.sender.clone();
+        self.tasks.spawn(async move {
+            let result = get_public_ip().await.map_err(|e| e.to_string());
+            let _ = sender.send(TaskMessage::PublicIpLoaded(result)).await;
+        });
+    }
+
+    fn refresh_peers(&mut self) {
+        self.ui_state.peers_refreshed = Some(std::time::Instant::now());
+        let network = Arc::clone(&self.net_module.network);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let known_nodes = network.known_nodes().await;
+            let mut peers: Vec<(String, Capabilities)> = known_nodes.iter()
+                .map(|(address, known_node)| (address.clone(), known_node.capabilities()))
+                .collect();
+            peers.sort_by(|a, b| a.0.cmp(&b.0));
+            let _ = sender.send(TaskMessage::KnownNodesLoaded(known_nodes)).await;
+            let _ = sender.send(TaskMessage::PeersLoaded(peers)).await;
+
+            let throughput = network.peer_throughput().await;
+            let _ = sender.send(TaskMessage::ThroughputLoaded(throughput)).await;
+
+            match network.peer_history().await {
+                Ok(history) => { let _ = sender.send(TaskMessage::PeerHistoryLoaded(history)).await; }
+                Err(err) => println!("Failed to load the peer history: {}", err),
+            }
+        });
+    }
+
+    // The mock backend has no audit log, the tab stays empty
+    fn refresh_audit_log(&self) {
+        if self.mock_ui {
+            return;
+        }
+        let sender = self.sender.clone();
+        self.tasks.spawn(async move {
+            let entries = tokio::task::spawn_blocking(|| AuditLog::default().entries().map_err(|e| e.to_string())).await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            let _ = sender.send(TaskMessage::AuditLogLoaded(entries)).await;
+        });
+    }
+
+    fn refresh_mempool(&self) {
+        let network = Arc::clone(&self.net_module.network);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let mut txs = network.mempool_transactions().await;
+            txs.sort_by(|a, b| a.id.cmp(&b.id));
+            let _ = sender.send(TaskMessage::MempoolLoaded(txs)).await;
+        });
+    }
+
+    // Loads a transaction from the mempool, or the chain, into the detail popup
+    fn open_tx_detail(&self, txid: String) {
+        let server = Arc::clone(&self.net_module.server);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+
+        self.tasks.spawn(async move {
+            let detail = async {
+                let server = server.read().await;
+                let in_mempool = server.mempool_transactions().await.into_iter().find(|tx| tx.id == txid);
+                match in_mempool {
+                    Some(tx) => Ok(TxDetail { package: Some(server.package_stats(&txid).await?), tx }),
+                    None => {
+                        let tx = utxo_set.read().await.blockchain.read().await.find_transaction(&txid)?;
+                        Ok::<TxDetail, failure::Error>(TxDetail { tx, package: None })
+                    }
+                }
+            }
+            .await
+            .map_err(|e| e.to_string());
+            let _ = sender.send(TaskMessage::TxDetailLoaded(detail)).await;
+        });
+    }
+
+    /// The app without a node, showing why starting it failed
+    pub fn with_startup_error(error: &failure::Error) -> Self {
+        let message = match (error.downcast_ref::<ChainOpenError>(), error.downcast_ref::<SchemaError>()) {
+            (Some(ChainOpenError::Damaged(health)), _) => format!("The block database has blocks that can't be read.\n{}", health),
+            (Some(problem), _) => format!("The block database is inconsistent and couldn't be repaired.\n{}", problem),
+            (_, Some(problem)) => format!("This build can't open the block database.\n{}", problem),
+            _ => format!("Starting the node failed.\n{}", error),
+        };
+        record_startup_problem(&message);
+
+        let mut app = MyApp::default();
+        app.ui_state.startup_problem = Some(message);
+        if let Some(ChainOpenError::Damaged(health)) = error.downcast_ref::<ChainOpenError>() {
+            app.ui_state.damaged_chain = Some(health.clone());
+        }
+        app
+    }
+
+    /// The app over MockBackend's made up chain, wallets and peers, for `--mock-ui`. Nothing
+    /// is read from or written to the data directory and no peer is contacted
+    pub async fn with_mock_backend() -> Result<Self> {
+        let mock = Arc::new(MockBackend::new(MOCK_BLOCKS)?);
+        let mut app = MyApp { mock_ui: true, ..MyApp::default() };
+        app.bc_module.wallets = Box::new(mock.wallets());
+        app.bc_module.chain = mock.clone();
+        app.net_module.network = mock.clone();
+        app.net_module.public_ip = Some(Ok(String::from("203.0.113.7")));
+
+        let best_height = mock.best_height().await?;
+        let blocks = mock.blocks_range(best_height - SETTINGS.max_blocks_loaded as i32 + 1, best_height).await?;
+        app.ui_state.blocks = blocks.iter().map(BlockSummary::of).collect();
+        app.ui_state.oldest_block_loaded = app.ui_state.blocks.last().is_none_or(|b| b.height == 0);
+        app.ui_state.chain_stats = Some(mock.stats().await?);
+        app.ui_state.connected_peers_displayed = mock.known_nodes().await.iter()
+            .map(|(address, known_node)| (address.clone(), known_node.capabilities()))
+            .collect();
+        app.bc_module.balances = MyApp::calculate_new_balances(app.bc_module.wallets.address_groups(), mock).await?;
+        Ok(app)
+    }
+
+    fn render_startup_problem(&mut self, ctx: &egui::Context) {
+        let Some(message) = &self.ui_state.startup_problem else {
+            return;
+        };
+        let mut dismissed = false;
+        let mut recovery = None;
+        egui::Window::new("Startup problem")
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label(message);
+                ui.label(format!("This message was also written to {}.", STARTUP_LOG));
+                let Some(health) = &self.ui_state.damaged_chain else {
+                    dismissed = ui.button("OK").clicked();
+                    return;
+                };
+                // No way around it, the node doesn't run on a damaged chain
+                ui.label("Choose how to recover, the missing blocks are downloaded from peers afterwards:");
+                ui.horizontal(|ui| {
+                    if ui.button("Re-sync from peers").on_hover_text("Deletes the local chain and downloads all of it again").clicked() {
+                        recovery = Some(ChainRecovery::Resync);
+                    }
+                    let truncate = ui.add_enabled(health.last_good.is_some(), egui::Button::new("Truncate to last good block"));
+                    let truncate = match &health.last_good {
+                        Some((height, _)) => truncate.on_hover_text(format!("Keeps the blocks up to height {}", height)),
+                        None => truncate.on_disabled_hover_text("No intact part of the chain was found"),
+                    };
+                    if truncate.clicked() {
+                        recovery = Some(ChainRecovery::TruncateToLastGood);
+                    }
+                });
+            });
+        if dismissed {
+            self.ui_state.startup_problem = None;
+        }
+        if let Some(recovery) = recovery {
+            self.recover_chain(recovery);
+        }
+    }
+
+    // Recovers the chain the node refused to start on, then starts again the way the app
+    // does when it's launched
+    fn recover_chain(&mut self, recovery: ChainRecovery) {
+        if let Err(e) = Blockchain::recover_damaged(BLOCKS_PATH, recovery, &AuditLog::default()) {
+            self.add_notification(format!("Recovering the chain failed: {}", e));
+            return;
+        }
+        *self = match RUNTIME.block_on(MyApp::initialize_async()) {
+            Ok(app) => app,
+            Err(e) => MyApp::with_startup_error(&e),
+        };
+        self.add_notification(String::from("Chain recovered, downloading the missing blocks from peers"));
+    }
+
+    // Labels of our wallets by address, empty for unlabeled ones
+    fn own_labels(&self) -> HashMap<String, String> {
+        self.bc_module.wallets.get_all_address().into_iter()
+            .map(|address| {
+                let label = self.bc_module.wallets.get_wallet(&address).map(|wallet| wallet.metadata.label.clone());
+                (address, label.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    // What `address` is called in notifications, the address itself when nothing labels it
+    fn counterparty_name(&self, address: &str) -> String {
+        let own = self.own_labels();
+        address_book::resolve(address, &self.bc_module.address_book, &own).label().unwrap_or(address).to_string()
+    }
+
+    fn open_label_editor(&mut self, address: String) {
+        let label = self.bc_module.address_book.get(&address).unwrap_or_default().to_string();
+        self.ui_state.label_editor = Some((address, label));
+    }
+
+    // Writes the label typed for an address to the address book, a blank one removes it
+    fn render_label_editor(&mut self, ctx: &egui::Context) {
+        let Some((address, label)) = &mut self.ui_state.label_editor else {
+            return;
+        };
+
+        let mut open = true;
+        let mut save = false;
+        egui::Window::new("Label address")
+            .collapsible(false)
+            .resizable(false)
+            .open(&mut open)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(egui::RichText::new(address.as_str()).monospace());
+                let edit = ui.add(egui::TextEdit::singleline(label).hint_text("e.g. Alice"));
+                let entered = edit.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                save = ui.button("Save").clicked() || entered;
+            });
+
+        if save {
+            if let Some((address, label)) = self.ui_state.label_editor.take() {
+                if let Err(e) = self.bc_module.address_book.set_label(&address, &label) {
+                    self.add_notification(format!("Couldn't save the label: {}", e));
+                }
+            }
+        } else if !open {
+            self.ui_state.label_editor = None;
+        }
+    }
+
+    fn render_tx_detail(&mut self, ctx: &egui::Context) {
+        let Some(detail) = &self.ui_state.tx_detail else {
+            return;
+        };
+
+        let own_labels = self.own_labels();
+        let book = &self.bc_module.address_book;
+
+        let mut open = true;
+        let mut follow: Option<String> = None;
+        let mut label_address: Option<String> = None;
+        egui::Window::new("Transaction")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                let tx = &detail.tx;
+                let id = ui.add(egui::Label::new(egui::RichText::new(&tx.id).monospace()).sense(egui::Sense::click()))
+                    .on_hover_text("Click to copy");
+                if id.clicked() {
+                    copy_to_clipboard(ctx, &tx.id, Some(&id));
+                }
+                ui.label(if detail.package.is_some() { "Unconfirmed" } else { "Confirmed" });
+                ui.label(format!("{} inputs, {} outputs", tx.vin.len(), tx.vout.len()));
+                if !tx.is_coinbase() {
+                    let mut senders: Vec<String> = tx.vin.iter().map(|vin| vin.get_address()).collect();
+                    senders.dedup();
+                    ui.horizontal_wrapped(|ui| {
+                        ui.label("From");
+                        for sender in senders {
+                            if counterparty_link(ui, &sender, address_book::resolve(&sender, book, &own_labels)) {
+                                label_address = Some(sender);
+                            }
+                        }
+                    });
+                }
+                for out in &tx.vout {
+                    ui.horizontal(|ui| {
+                        if out.is_burn() {
+                            ui.label(format!("  {} coins burned", out.value));
+                            return;
+                        }
+                        ui.label(format!("  {} coins to", out.value));
+                        let to = address::wallet_address(&out.pub_key_hash);
+                        if counterparty_link(ui, &to, address_book::resolve(&to, book, &own_labels)) {
+                            label_address = Some(to);
+                        }
+                    });
+                }
+                let copy_hex = ui.small_button("Copy raw hex");
+                if copy_hex.clicked() {
+                    match tx.to_hex() {
+                        Ok(hex) => { copy_to_clipboard(ctx, &hex, Some(&copy_hex)); }
+                        Err(e) => println!("Couldn't encode transaction {}: {}", tx.id, e),
+                    }
+                }
+
+                let Some(package) = &detail.package else {
+                    return;
+                };
+                ui.separator();
+                ui.heading("Unconfirmed ancestry");
+                for (title, txids) in [("Parents", &package.ancestors), ("Children", &package.descendants)] {
+                    ui.label(format!("{}: {}", title, txids.len()));
+                    for txid in txids {
+                        if ui.link(egui::RichText::new(txid).monospace()).clicked() {
+                            follow = Some(txid.clone());
+                        }
+                    }
+                }
+                ui.label(format!(
+                    "Package with parents: {} bytes, {} coins in fees",
+                    package.size, package.fee
+                ));
+                ui.label(format!("Effective fee rate: {} coins per 1000 bytes", package.fee_rate()));
+            });
+
+        if !open {
+            self.ui_state.tx_detail = None;
+        }
+        if let Some(txid) = follow {
+            self.open_tx_detail(txid);
+        }
+        if let Some(address) = label_address {
+            self.open_label_editor(address);
+        }
+    }
+
+    // Shows under the form what the send would spend. Nothing is reserved, another send
+    // may still take the same outputs
+    fn preview_transaction(&mut self) {
+        if self.mock_ui {
+            self.add_notification(String::from("Previews aren't available with --mock-ui"));
+            return;
+        }
+        if self.ui_state.send_max_fee.is_some() {
+            return; // the send max line already tells
+        }
+        let (_, wallets, _, amount) = match self.valid_tx_fields() {
+            Ok(fields) => fields,
+            Err(err) => {
+                self.add_notification(err.to_string());
+                return;
+            }
+        };
+        let (fee, selection) = (self.ui_state.tx_fee, self.ui_state.coin_selection);
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let sender = self.sender.clone();
+        self.tasks.spawn(async move {
+            let result = Transaction::preview_send(&wallets, amount, fee, selection, &utxo_set).await;
+            let _ = sender.send(TaskMessage::SendPreviewed(amount, result.map_err(|e| e.to_string()))).await;
+        });
+    }
+
+    fn clear_transaction_form(&mut self){
+        // Transaction Tab
+        self.ui_state.selected_wallet = None;
+        self.ui_state.combine_wallets = false;
+        self.ui_state.combined_wallets.clear();
+        self.ui_state.receiver_address = String::from("");
+        self.ui_state.tx_amount = 0;
+        self.ui_state.send_max_fee = None;
+        self.ui_state.send_preview = None;
+        self.ui_state.tx_fee = 0;
+        self.ui_state.fee_estimate = None;
+        self.ui_state.tx_memo.clear();
+        self.ui_state.coin_selection = CoinSelection::default();
+        self.ui_state.confirm_burn = false;
+        self.ui_state.burn_amount = 0;
+        self.ui_state.burn_confirmed = false;
+        self.ui_state.payment_request_banner = None;
+    }
+
+    pub fn add_notification(&mut self, message: String) {
+        let notification = Notification {
+            id: self.generate_notification_id(),
+            message,
+            start_time: std::time::Instant::now(),
+            duration: 10, // 10 seconds
+            warning: false,
+            action: None,
+        };
+
+        self.notif_module.notifications.push(notification);
+    }
+
+    // Something the user should act on, kept up long enough to click its action
+    fn add_warning(&mut self, message: String, action: Option<NotificationAction>) {
+        let notification = Notification {
+            id: self.generate_notification_id(),
+            message,
+            start_time: std::time::Instant::now(),
+            duration: 60,
+            warning: true,
+            action,
+        };
+
+        self.notif_module.notifications.push(notification);
+    }
+
+    // Stays up until dismissed or removed by its id
+    fn add_sticky_warning(&mut self, message: String) -> u32 {
+        let id = self.generate_notification_id();
+        self.notif_module.notifications.push(Notification {
+            id,
+            message,
+            start_time: std::time::Instant::now(),
+            duration: u64::MAX,
+            warning: true,
+            action: None,
+        });
+        id
+    }
+
+    fn generate_notification_id(&mut self) -> u32 {
+        self.notif_module.notification_counter += 1;
+        self.notif_module.notification_counter
+    }
+
+
+    // One sticky warning while space is short, replaced as the level changes
+    fn handle_disk_space(&mut self, level: DiskLevel, free: u64, stores: &[StoreUsage]) {
+        if let Some(id) = self.ui_state.disk_warning.take() {
+            self.notif_module.notifications.retain(|n| n.id != id);
+        }
+        let usage: Vec<String> = stores.iter()
+            .map(|store| format!("{} {}", store.name, maintenance::format_size(store.bytes)))
+            .collect();
+        let message = match level {
+            DiskLevel::Ok => {
+                self.add_notification(String::from("Disk space recovered"));
+                return;
+            }
+            DiskLevel::Low => format!("Low disk space: {} free. Using {}", maintenance::format_size(free), usage.join(", ")),
+            DiskLevel::Full => format!(
+                "Disk almost full ({} free): block downloads and mining are paused until space is freed. Using {}",
+                maintenance::format_size(free),
+                usage.join(", ")
+            ),
+        };
+        self.ui_state.disk_warning = Some(self.add_sticky_warning(message));
+    }
+
+    // The server reports the removal with a PeerRemoved event, which refreshes the list
+    fn disconnect_peer(&self, address: String) {
+        let network = Arc::clone(&self.net_module.network);
+        self.tasks.spawn(async move {
+            network.disconnect_peer(address).await;
+        });
+    }
+
+    fn add_peer(&mut self, new_peer_ip: String, new_peer_port: String) -> Result<()> {        
+        let network = Arc::clone(&self.net_module.network);
+
+        //println!("Server instance: {:?} add_peer", Arc::as_ptr(&server_clone));
+
+        let new_peer_ip_port = new_peer_ip + ":" + &new_peer_port;
+        //println!("New_peer_ip: {}", new_peer_ip.clone());
+        
+        self.spawn_action(ActionKind::AddPeer, async move {
+            match network.add_peer(new_peer_ip_port.clone()).await {
+                Ok(_result) => TaskMessage::PeerAdded(new_peer_ip_port),
+                Err(err) => {
+                    println!("Error while adding peer: {}", err);
+                    TaskMessage::Error(format!("Error while adding peer: {}", err))
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl Default for MyApp {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel(100);        
+        let (_, node_events) = mpsc::channel(1);
+        
+        // Create the `utxo_set` first, since it is needed by `server`
+        let utxo_set = Arc::new(RwLock::new(UTXOSet::in_memory(
+            Arc::new(RwLock::new(Blockchain::default_empty())),
+        )));
+
+        // Use `utxo_set` to create the `server`
+        let server = Server::new("8334", "", Arc::clone(&utxo_set)).unwrap();
+        let visibility = server.visibility();
+        let server = Arc::new(RwLock::new(server));
+
+        
+        Self {
+            bc_module: BlockchainModule {
+                wallets: Box::new(Wallets::default()),
+                balances: Vec::new(),
+                chain: utxo_set.clone(),
+                utxo_set,
+                pending_sends: Arc::new(RwLock::new(PendingSends::default())),
+                address_book: AddressBook::default(),
+            },
+    
+            net_module: NetworkModule {
+                public_ip: None,
+                network: server.clone(),
+                server,
+                visibility,
+            },
+    
+            ui_state: UIState {
+                active_tab: Tab::Blockchain,
+    
+                // Blockchain Tab
+                blocks: Vec::new(),
+                show_transactions: false,
+                block_txids: HashMap::new(),
+                show_all_txs: HashSet::new(),
+                blocks_to_display: 5,
+                oldest_block_loaded: false,
+                pruned_height: None,
+                chain_stats: None,
+                new_blocks_since_view: 0,
+                block_search_query: String::new(),
+                block_search_result: None,
+    
+                // Transaction Tab
+                selected_wallet: None,
+                combine_wallets: false,
+                combined_wallets: Vec::new(),
+                batch_sends: SETTINGS.batch_sends,
+                outbox: Outbox::default(),
+                receiver_address: String::from(""),
+                tx_amount: 0,
+                send_max_fee: None,
+                send_preview: None,
+                tx_fee: 0,
+                fee_estimate: None,
+                tx_memo: String::new(),
+                coin_selection: CoinSelection::default(),
+                confirm_burn: false,
+                burn_amount: 0,
+                burn_confirmed: false,
+                raw_tx_input: String::new(),
+                pending_txids: Vec::new(),
+                receipts: HashMap::new(),
+                pending_amounts: HashMap::new(),
+                sent_txs: Vec::new(),
+                sent_tip_height: -1,
+                mempool_txs: Vec::new(),
+                tx_detail: None,
+                label_editor: None,
+                payment_request_banner: None,
+    
+                // Wallets Tab
+                incoming: WatchList::new(SETTINGS.confirmation_target),
+                wallet_history: HashMap::new(),
+                show_delete_popup: None,
+                show_add_existing_wallet_popup: false, 
+                receive_request: None,
+                proof_of_funds: None,
+                import_password: String::new(),
+                sweep_destination: String::new(),
+                sweep_in_progress: None,
+
+                // Peers Tab
+                peer_ip_address_input: String::new(),
+                peer_port_input: String::from("8334"),
+                connected_peers_displayed: Vec::new(),
+                peer_history: Vec::new(),
+                peer_throughput: HashMap::new(),
+                peers_refreshed: None,
+                known_nodes: HashMap::new(),
+                map_selected: None,
+
+                // Settings Tab
+                console_input: String::new(),
+                console_output: String::new(),
+                compaction_report: Vec::new(),
+                audit_entries: Vec::new(),
+                audit_filter: None,
+                chain_file_progress: None,
+
+                status_warning: None,
+                disk_warning: None,
+                health: None,
+                health_refreshed: None,
+                pending_checked: None,
+                idle: IdleState::default(),
+                startup_problem: None,
+                damaged_chain: None,
+            },
+            
+            notif_module: NotificationModule {
+                // Notification Tab
+                notifications: Vec::new(),
+                notification_counter: 0,
+            },
+
+            sender,
+            receiver,
+            node_events,
+            actions_in_flight: HashSet::new(),
+            tasks: BackgroundTasks::new(),
+            mock_ui: false,
+        }
+    }
+}
+
+impl eframe::App for MyApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {       
+        let bg_color = egui::Color32::from_rgb(30, 30, 30); // Dark gray
+        let visuals = egui::Visuals {
+            override_text_color: Some(egui::Color32::WHITE), // Optional: Ensure white text
+            ..egui::Visuals::dark() // Base dark theme
+        };
+        ctx.set_visuals(visuals);
+
+        ctx.set_style({
+            let mut style = (*ctx.style()).clone();
+
+            style.visuals.window_fill = bg_color;
+            style.spacing.button_padding = egui::vec2(15.0, 10.0); // Custom padding
+
+            style
+        });
+
+        self.follow_visibility(ctx.input(|i| i.viewport().minimized == Some(true)));
+        if self.ui_state.health_refreshed.is_none_or(|at| at.elapsed() >= HEALTH_REFRESH_INTERVAL) {
+            self.refresh_health();
+        }
+        let pending_interval = self.ui_state.idle.interval(PENDING_CHECK_INTERVAL, SETTINGS.hidden_interval_multiplier);
+        if self.ui_state.pending_checked.is_none_or(|at| at.elapsed() >= pending_interval) {
+            self.check_pending_sends();
+        }
+        if !self.actions_in_flight.contains(&ActionKind::SendBatch) {
+            let max_wait = std::time::Duration::from_secs(SETTINGS.batch_flush_secs);
+            if let Some(from) = self.ui_state.outbox.due(clock::now_millis(), max_wait).into_iter().next() {
+                self.flush_outbox(from);
+            }
+        }
+        // Also what takes in node events, the channel would fill up if this waited longer
+        ctx.request_repaint_after(HEALTH_REFRESH_INTERVAL);
+        if self.ui_state.idle.is_hidden() {
+            // Nothing to lay out, the timers above and the messages are all a hidden window needs
+            self.render_channel_messages(ctx);
+            return;
+        }
+        self.render_copy_feedback(ctx);
+
+        egui::TopBottomPanel::bottom("status_bar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if let Some(report) = &self.ui_state.health {
+                    health_indicator(ui, report);
+                }
+                if let Some(warning) = &self.ui_state.status_warning {
+                    ui.label(egui::RichText::new(format!("⚠ {}", warning)).color(egui::Color32::YELLOW));
+                }
+            });
+        });
+
+        // Render the UI
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let layout = LayoutMode::from_width(ui.available_width());
+            
+            // Navigation bar at the top, icons only in compact mode
+            ui.horizontal(|ui| {
+                for (tab, icon, label) in [
+                    (Tab::Blockchain, "🔗", "Blockchain"),
+                    (Tab::Transactions, "💸", "Transactions"),
+                    (Tab::Wallets, "💰", "Wallets"),
+                    (Tab::Peers, "🌐", "Peers"),
+                    (Tab::Settings, "⚙", "Settings"),
+                ] {
+                    let badge = self.tab_badge(&tab);
+                    let clicked = match layout {
+                        LayoutMode::Compact => ui.button(tab_label(ui.style(), icon, badge)).on_hover_text(label).clicked(),
+                        LayoutMode::Regular => ui.button(tab_label(ui.style(), label, badge)).clicked(),
+                    };
+                    if clicked {
+                        self.open_tab(tab);
+                    }
+                }
+
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    let wallet_count = self.bc_module.wallets.get_all_address().len();
+                    
+                    let text = match (layout, wallet_count) {
+                        (LayoutMode::Compact, _) => format!("💰 {}", wallet_count),
+                        (LayoutMode::Regular, 0) => "No Wallets Connected".to_owned(),
+                        (LayoutMode::Regular, _) => format!("Connected Wallets: {}", wallet_count),
+                    };
+
+                    ui.add_space(10.0);
+                    if ui.add(
+                            egui::Label::new(egui::RichText::new(text))
+                                .sense(egui::Sense::click()), // Make it interactive          
+                        )
+                        .on_hover_text("Go to Wallets tab") // Optional tooltip
+                        .on_hover_cursor(egui::CursorIcon::PointingHand) // Change cursor to pointer
+                        .clicked(){
+                            self.open_tab(Tab::Wallets);
+                        };
+                });
+            });
+
+            // Section rendering based on the active tab
+            ui.separator(); // Add a visual separator
+
+            match self.ui_state.active_tab {
                 Tab::Blockchain => self.render_blockchain_section(ui),
-                Tab::Transactions => self.render_transactions_section(ui),
-                Tab::Wallets => self.render_wallets_section(ui),
+                Tab::Transactions => self.render_transactions_section(ui, layout),
+                Tab::Wallets => self.render_wallets_section(ui, layout),
                 Tab::Peers => self.render_peers_section(ui),
                 Tab::Settings => self.render_settings_section(ui),
             }
@@ -604,7 +2425,9 @@ impl eframe::App for MyApp {
             self.render_channel_messages(ctx);
 
             // Notification rendering
-            self.render_notifications(ctx);
+            self.render_notifications(ctx, layout);
+            self.render_startup_problem(ctx);
+            self.render_label_editor(ctx);
 
         }); 
     }
@@ -616,15 +2439,37 @@ impl eframe::App for MyApp {
     }
 
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        // Background tasks may still be writing to the databases
+        if !RUNTIME.block_on(self.tasks.shutdown(SHUTDOWN_TIMEOUT)) {
+            eprintln!("{} background tasks didn't stop in time", self.tasks.len());
+        }
+
+        if self.mock_ui {
+            return;
+        }
+
         // Saves Wallets on disk
         if let Err(e) = self.bc_module.wallets.save_all() {
             eprintln!("Failed to save wallets on exit: {}", e);
         } else {
             println!("Wallets successfully saved on exit.");
         }
-        
+
+        // Weekly (by default) compaction, nothing else is using the databases anymore
+        if maintenance::compaction_due(std::time::Duration::from_secs(SETTINGS.compact_interval_days as u64 * 24 * 60 * 60)) {
+            let (utxo_set, server) = (&self.bc_module.utxo_set, &self.net_module.server);
+            match RUNTIME.block_on(async { maintenance::compact_node(utxo_set, &*server.read().await).await }) {
+                Ok(report) => println!("Databases compacted on exit: {:?}", report),
+                Err(e) => eprintln!("Skipped compacting the databases: {}", e),
+            }
+            match self.bc_module.wallets.compact() {
+                Ok(report) => println!("Wallets compacted on exit: {:?}", report),
+                Err(e) => eprintln!("Skipped compacting the wallets: {}", e),
+            }
+        }
+
         // Settings
-        SETTINGS.save("settings.json");
+        SETTINGS.save(&SETTINGS_PATH.to_string_lossy());
         
         println!("Application exiting. Cleaning up resources...");
     }
@@ -635,6 +2480,10 @@ impl eframe::App for MyApp {
 // Methods for rendering each section
 impl MyApp {
     fn render_blockchain_section(&mut self, ui: &mut egui::Ui) {
+        if let Some(stats) = &self.ui_state.chain_stats {
+            render_chain_stats(ui, stats);
+            ui.separator();
+        }
 
         ui.horizontal(|ui|{
             ui.vertical(|ui|{
@@ -644,9 +2493,15 @@ impl MyApp {
     
             if ui.button("Toggle Transactions").clicked() {
                 self.ui_state.show_transactions = !self.ui_state.show_transactions;
+                self.ui_state.block_txids.clear();
+                self.ui_state.show_all_txs.clear();
             }
     
-            ui.label(format!(" Current Height: {}", &self.ui_state.blocks.first().unwrap().get_height() ));
+            let height = self.ui_state.blocks.first().unwrap().height;
+            ui.label(format!(" Current Height: {}", height));
+            if let Some(lowest) = self.ui_state.pruned_height {
+                ui.label(format!("Pruned node, height range {}–{}", lowest, height));
+            }
 
             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                 // Search input
@@ -659,17 +2514,9 @@ impl MyApp {
 
                     // Update search result dynamically
                     if response.changed() {
-                        if self.ui_state.block_search_query.trim().is_empty() {
-                            self.ui_state.block_search_result = None;
-                        } else {
-                            self.ui_state.block_search_result = self
-                                .ui_state.blocks
-                                .iter()
-                                .find(|block| {
-                                    block.get_height().to_string() == self.ui_state.block_search_query
-                                        || block.get_hash() == self.ui_state.block_search_query
-                                })
-                                .cloned();
+                        self.ui_state.block_search_result = None;
+                        if !self.ui_state.block_search_query.trim().is_empty() {
+                            self.search_block(self.ui_state.block_search_query.clone());
                         }
                     }
                 });
@@ -678,24 +2525,36 @@ impl MyApp {
         ui.add_space(5.0);
 
         // Scrollable display section
+        let mut block_actions = Vec::new();
+        let mut load_more = false;
         egui::ScrollArea::vertical().show(ui, |ui| {
             ui.vertical(|ui| {
-                match &self.ui_state.block_search_result {
+                let state = &self.ui_state;
+                let mut render = |ui: &mut egui::Ui, block: &BlockSummary| {
+                    let transactions = state.show_transactions.then(|| BlockTxList {
+                        txids: state.block_txids.get(&block.hash),
+                        show_all: state.show_all_txs.contains(&block.hash),
+                    });
+                    if let Some(action) = MyApp::render_block(ui, block, transactions) {
+                        block_actions.push((block.hash.clone(), action));
+                    }
+                };
+                match &state.block_search_result {
                     Some(block) => {
                         // Render only the searched block
-                        MyApp::render_block(ui, block, self.ui_state.show_transactions);
+                        render(ui, block);
                     }
                     None => {
-                        for block in self.ui_state.blocks.iter().take(self.ui_state.blocks_to_display) {
-                            MyApp::render_block(ui, block, self.ui_state.show_transactions);
+                        for block in state.blocks.iter().take(state.blocks_to_display) {
+                            render(ui, block);
                             ui.add_space(15.0);
                         }
                     
                         // Load More button
-                        if self.ui_state.blocks_to_display < self.ui_state.blocks.len() {
+                        if state.blocks_to_display < state.blocks.len() || !state.oldest_block_loaded {
                             ui.vertical_centered(|ui| {
                                 if ui.button("Load More Blocks").clicked() {
-                                    self.ui_state.blocks_to_display += 20; // Increment by 20 blocks
+                                    load_more = true;
                                 }
                             });
                         }
@@ -703,10 +2562,29 @@ impl MyApp {
                 }
             });
         });
+
+        if load_more {
+            self.ui_state.blocks_to_display += LOAD_MORE_BLOCKS;
+            self.load_older_blocks();
+        }
+        for (hash, action) in block_actions {
+            match action {
+                BlockAction::Load => self.load_block_transactions(hash),
+                BlockAction::ShowAll => { self.ui_state.show_all_txs.insert(hash); }
+                BlockAction::Collapsed => {
+                    self.ui_state.block_txids.remove(&hash);
+                    self.ui_state.show_all_txs.remove(&hash);
+                }
+                BlockAction::CopyRawHex => self.copy_block_hex(hash),
+                BlockAction::CopyRawTx(txid) => self.copy_tx_hex(hash, txid),
+            }
+        }
     }
 
-    // Function to render a single block
-    fn render_block(ui: &mut egui::Ui, block: &Block, show_transactions: bool) {
+    // Function to render a single block. With `transactions` it has a collapsible list of
+    // its transaction ids, which are only read and laid out while expanded
+    fn render_block(ui: &mut egui::Ui, block: &BlockSummary, transactions: Option<BlockTxList>) -> Option<BlockAction> {
+        let mut action = None;
         egui::Frame::none()
             .rounding(egui::Rounding::same(5.0))
             .fill(egui::Color32::from_rgb(20, 20, 20))
@@ -714,15 +2592,21 @@ impl MyApp {
             .stroke(egui::Stroke::new(2.0, egui::Color32::DARK_GRAY))
             .show(ui, |ui| {
                 ui.vertical_centered(|ui| {
-                    ui.label(format!("{}", block.get_height()));
-                    ui.label(format!("Block Hash: {}", block.get_hash()));
-                    ui.label(format!("Previous Hash: {}", block.get_prev_hash()));
-                    ui.label(format!("Timestamp: {}", convert_timestamp(block.get_timestamp())));
-                    ui.label(format!("Nonce: {}", block.get_nonce()));
-
-                    
+                    ui.label(format!("{}", block.height));
+                    let hash = ui.add(egui::Label::new(format!("Block Hash: {}", block.hash)).sense(egui::Sense::click()))
+                        .on_hover_text("Click to copy");
+                    if hash.clicked() {
+                        copy_to_clipboard(ui.ctx(), &block.hash, Some(&hash));
+                    }
+                    ui.label(format!("Previous Hash: {}", block.prev_hash));
+                    ui.label(format!("Merkle Root: {}", block.merkle_root));
+                    ui.label(format!("Timestamp: {}", convert_timestamp(block.timestamp)));
+                    ui.label(format!("Nonce: {}", block.nonce));
+                    if ui.small_button("Copy raw hex").clicked() {
+                        action = Some(BlockAction::CopyRawHex);
+                    }
 
-                    if show_transactions {
+                    if let Some(list) = transactions {
                         ui.add_space(10.0);
                         egui::Frame::none()
                             .rounding(egui::Rounding::same(5.0))
@@ -730,17 +2614,15 @@ impl MyApp {
                             .inner_margin(egui::Margin::same(10.0))
                             .stroke(egui::Stroke::new(1.0, egui::Color32::WHITE))
                             .show(ui, |ui| {
-                                ui.label("Transactions:");
-                                for tx in block.get_transactions() {
-                                    ui.label(format!("Tx ID: {}", tx.id));
-                                }
+                                action = render_block_transactions(ui, block, list);
                             });
                     }
                 });
             });
+        action
     }
     
-    fn render_transactions_section(&mut self, ui: &mut egui::Ui) {
+    fn render_transactions_section(&mut self, ui: &mut egui::Ui, layout: LayoutMode) {
         ui.heading("Transactions");
         ui.label("View and create transactions.");
 
@@ -753,95 +2635,205 @@ impl MyApp {
             ui.heading("Create New Transaction");
 
             // Wallet Selection
-            ui.horizontal(|ui| {
-                ui.label(egui::RichText::new("From Wallet:"));
+            form_row(ui, layout, "From Wallet:", |ui| {
             
                 // Borrow the wallets before the closure to avoid borrowing `self` inside
-                let wallet_entries: Vec<(String, String)> = self
+                let wallet_entries: Vec<(String, egui::text::LayoutJob)> = self
                     .bc_module
                     .wallets
-                    .iter()
-                    .map(|(address, _wallet)| {                        
+                    .get_all_address()
+                    .into_iter()
+                    .map(|address| {                        
                         let balance = self.get_balance(&address).unwrap_or(0);
-                        let display_text = format!("{} - {} coins", address, balance);
-                        (address.clone(), display_text)
+                        let display_text = tagged_text(&address, &format!("{} - {} coins", address, balance));
+                        (address, display_text)
                     })
                     .collect();
             
                 // Use the collected data in the dropdown
-                egui::ComboBox::from_label("")
-                    .selected_text(self.ui_state.selected_wallet.clone().unwrap_or("Select Wallet".into()))
+                let mut combo = egui::ComboBox::from_label("");
+                if layout == LayoutMode::Compact {
+                    combo = combo.width(ui.available_width() - ui.spacing().item_spacing.x);
+                }
+                combo
+                    .selected_text(match &self.ui_state.selected_wallet {
+                        Some(address) => tagged_text(address, address).into(),
+                        None => egui::WidgetText::from("Select Wallet"),
+                    })
                     .show_ui(ui, |ui| {
                         for (address, display_text) in wallet_entries {
                             if ui.selectable_value(&mut self.ui_state.selected_wallet, Some(address.clone()), display_text).clicked() {
                                 self.ui_state.selected_wallet = Some(address);
+                                self.ui_state.send_max_fee = None;
                             }
                         }
                     });
             });
             
-            if let Some(wlt_address) = &self.ui_state.selected_wallet {
-                let available_funds = self.get_balance(&wlt_address).unwrap_or(0);
+            ui.checkbox(&mut self.ui_state.combine_wallets, "Combine wallets")
+                .on_hover_text("Spend from several wallets when one doesn't hold enough. Change goes to the From wallet");
+            if self.ui_state.combine_wallets {
+                let others: Vec<String> = self.bc_module.wallets.get_all_address().into_iter()
+                    .filter(|address| Some(address) != self.ui_state.selected_wallet.as_ref())
+                    .collect();
+                ui.label("Also spend from:");
+                for address in others {
+                    let mut included = self.ui_state.combined_wallets.contains(&address);
+                    let balance = self.get_balance(&address).unwrap_or(0);
+                    if ui.checkbox(&mut included, tagged_text(&address, &format!("{} - {} coins", address, balance))).changed() {
+                        if included {
+                            self.ui_state.combined_wallets.push(address);
+                        } else {
+                            self.ui_state.combined_wallets.retain(|a| *a != address);
+                        }
+                    }
+                }
+            }
+
+            if self.ui_state.selected_wallet.is_some() {
+                let available_funds: i32 = self.funding_addresses().iter()
+                    .map(|address| self.get_balance(address).unwrap_or(0))
+                    .sum();
                 ui.label(egui::RichText::new(format!("Available Funds: {}", available_funds)));
             }
 
             ui.separator();
 
             // Receiver Address
-            ui.horizontal(|ui| {
-                ui.label("To Address:");
-                ui.text_edit_singleline(&mut self.ui_state.receiver_address);
+            form_row(ui, layout, "To Address:", |ui| {
+                if ui.text_edit_singleline(&mut self.ui_state.receiver_address).changed() {
+                    self.ui_state.confirm_burn = false;
+                    self.ui_state.payment_request_banner = None;
+                    if PaymentRequest::is_uri(&self.ui_state.receiver_address) {
+                        self.apply_payment_request();
+                    }
+                }
             });
 
+            if let Some(banner) = &self.ui_state.payment_request_banner {
+                ui.label(egui::RichText::new(banner).color(egui::Color32::from_rgb(50, 150, 70)));
+            }
+
+            if is_unspendable_address(&self.ui_state.receiver_address) {
+                ui.label(egui::RichText::new("⚠ Nobody has the keys to this address. Coins sent to it are lost forever.")
+                    .color(egui::Color32::RED)
+                    .strong());
+                ui.checkbox(&mut self.ui_state.confirm_burn, "I want to burn these coins");
+            }
+
             // Amount
-            ui.horizontal(|ui| {
-                ui.label("Amount:");
-                ui.add(egui::DragValue::new(&mut self.ui_state.tx_amount).speed(0.1));
+            form_row(ui, layout, "Amount:", |ui| {
+                if ui.add(egui::DragValue::new(&mut self.ui_state.tx_amount).speed(0.1)).changed() {
+                    self.ui_state.send_max_fee = None;
+                }
                 ui.label("coins");
+                if ui.add_enabled(self.ui_state.selected_wallet.is_some(), egui::Button::new("Max"))
+                    .on_hover_text("Sweep the From wallet: its whole balance minus the fee, sent without change")
+                    .clicked()
+                {
+                    self.load_max_amount();
+                }
             });
+            if let Some(fee) = self.ui_state.send_max_fee {
+                ui.label(format!("Sweeps the From wallet: {} coins after a {} coin fee, no change", self.ui_state.tx_amount, fee));
+            } else if let Some((_, preview)) = self.ui_state.send_preview.as_ref()
+                .filter(|(amount, preview)| *amount == self.ui_state.tx_amount && preview.fee == self.ui_state.tx_fee)
+            {
+                let mut line = format!("Spends {} outputs worth {} coins: a {} coin fee, {} coins of change", preview.inputs, preview.total, preview.fee, preview.change);
+                if preview.dust_folded > 0 {
+                    line.push_str(&format!(", includes {} dust folded into fee", preview.dust_folded));
+                }
+                ui.label(line);
+            }
 
             ui.separator();
 
-            // Gas and Gas Limit (Optional)
             ui.collapsing("Advanced Options", |ui| {
-                ui.horizontal(|ui| {
-                    ui.label("Gas Price:");
-                    ui.add(egui::DragValue::new(&mut self.ui_state.tx_gas_price).speed(0.1));
+                form_row(ui, layout, "Fee:", |ui| {
+                    ui.add_enabled(self.ui_state.send_max_fee.is_none(), egui::DragValue::new(&mut self.ui_state.tx_fee).speed(0.1).range(0..=i32::MAX))
+                        .on_hover_text("Paid to the miner of the block that includes the transaction, on top of the amount. Send max works out its own");
+                    ui.label("coins");
                 });
-                ui.horizontal(|ui| {
-                    ui.label("Gas Limit:");
-                    ui.add(egui::DragValue::new(&mut self.ui_state.tx_gas_limit).speed(0.1));
+                form_row(ui, layout, "Fee Presets:", |ui| {
+                    for (preset, target_blocks) in FEE_PRESETS {
+                        let button = ui.add_enabled(self.ui_state.send_max_fee.is_none(), egui::Button::new(preset))
+                            .on_hover_text(format!("Fills in a fee to be mined within {} blocks, from recent blocks and the mempool", target_blocks));
+                        if button.clicked() {
+                            self.estimate_fee(preset, target_blocks);
+                        }
+                    }
+                });
+                if let Some((preset, fee_rate, _)) = self.ui_state.fee_estimate.filter(|(_, _, fee)| *fee == self.ui_state.tx_fee) {
+                    ui.label(format!("{}: {} coins per 1000 bytes", preset, fee_rate));
+                }
+                form_row(ui, layout, "Coin Selection:", |ui| {
+                    egui::ComboBox::from_id_salt("coin_selection")
+                        .selected_text(self.ui_state.coin_selection.to_string())
+                        .show_ui(ui, |ui| {
+                            for strategy in CoinSelection::ALL {
+                                ui.selectable_value(&mut self.ui_state.coin_selection, strategy, strategy.to_string());
+                            }
+                        })
+                        .response
+                        .on_hover_text("Which of the wallet's outputs pay for the transaction. Send max spends them all");
+                });
+                form_row(ui, layout, "Memo:", |ui| {
+                    ui.add_enabled(self.ui_state.send_max_fee.is_none(), egui::TextEdit::singleline(&mut self.ui_state.tx_memo).hint_text("Optional, e.g. an invoice number"))
+                        .on_hover_text(format!("Attached to the transaction, anyone can read it. At most {} bytes, send max can't carry one", MAX_TX_DATA));
+                    if self.ui_state.tx_memo.len() > MAX_TX_DATA {
+                        ui.colored_label(egui::Color32::RED, format!("{} of {} bytes", self.ui_state.tx_memo.len(), MAX_TX_DATA));
+                    }
                 });
             });
 
             ui.separator();
 
+            ui.checkbox(&mut self.ui_state.batch_sends, "Batch sends")
+                .on_hover_text("Queue payments in the outbox and send them together in one transaction, from the From wallet only");
+
             // Buttons
             ui.horizontal(|ui| {
-                if ui.button("Send Transaction").clicked() {
-
-                    let sender = self.sender.clone();
+                if self.ui_state.batch_sends {
+                    if ui.button("Add to Outbox").clicked() {
+                        self.queue_payment();
+                    }
+                } else if self.action_button(ui, ActionKind::SendTx, "Send Transaction") {
 
                     // Extract only the necessary references from `MyApp`
-                    let server = Arc::clone(&self.net_module.server);
+                    let network = Arc::clone(&self.net_module.network);
                     let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+                    let pending_sends = Arc::clone(&self.bc_module.pending_sends);
 
-                    if let Ok((selected_wallet_name, wallet, receiver_address, tx_amount)) = self.valid_tx_fields() {
-                        
-                        RUNTIME.spawn(async move {
+                    if let Ok((from, mut wallets, receiver_address, tx_amount)) = self.valid_tx_fields() {
+                        let mode = match self.ui_state.send_max_fee {
+                            Some(fee) => {
+                                wallets = self.bc_module.wallets.spending_wallets(&from);
+                                SendMode::Sweep { fee }
+                            }
+                            None => SendMode::Amount { amount: tx_amount, fee: self.ui_state.tx_fee },
+                        };
+                        let selection = self.ui_state.coin_selection;
+                        let funding = Funding { wallets, change: self.bc_module.wallets.change_provider() };
+                        let data = match mode {
+                            SendMode::Sweep { .. } => Vec::new(),
+                            SendMode::Amount { .. } => self.ui_state.tx_memo.clone().into_bytes(),
+                        };
+                        let payment = Payment { to: receiver_address, mode, data };
+
+                        self.spawn_action(ActionKind::SendTx, async move {
                             let result = MyApp::send_transaction(
-                                selected_wallet_name,
-                                wallet,
-                                receiver_address,
-                                tx_amount,
+                                funding,
+                                payment,
+                                selection,
                                 utxo_set,
-                                server,
+                                network,
+                                pending_sends,
                             )
                             .await
-                            .unwrap_or(false);
+                            .map_err(|e| e.to_string());
                 
-                            // Send the result back to the main thread
-                            let _ = sender.send(TaskMessage::TransactionSent(result)).await;
+                            // Sent back to the main thread
+                            TaskMessage::TransactionSent(result)
                         });
                         
                     } else {
@@ -860,12 +2852,89 @@ impl MyApp {
             });
         });
 
-        /* Search transactions by id  */
+        if !self.ui_state.outbox.payments().is_empty() {
+            ui.add_space(10.0);
+            self.render_outbox(ui);
+        }
+
+        if !self.ui_state.pending_txids.is_empty() {
+            ui.add_space(10.0);
+            self.render_pending_sends(ui);
+        }
+
+        if !self.ui_state.sent_txs.is_empty() {
+            ui.add_space(10.0);
+            self.render_sent_txs(ui);
+        }
+
+        ui.add_space(10.0);
+        ui.collapsing("Broadcast raw transaction", |ui| {
+            ui.label("Paste the raw hex of a signed transaction. It's checked like any relayed transaction before it's sent to peers.");
+            ui.add(egui::TextEdit::multiline(&mut self.ui_state.raw_tx_input)
+                .font(egui::TextStyle::Monospace)
+                .desired_rows(3)
+                .desired_width(f32::INFINITY));
+            let broadcast_clicked = ui.add_enabled_ui(!self.ui_state.raw_tx_input.trim().is_empty(), |ui| {
+                self.action_button(ui, ActionKind::BroadcastRawTx, "Broadcast")
+            }).inner;
+            if broadcast_clicked {
+                if let Err(err) = self.broadcast_raw_tx() {
+                    self.add_notification(format!("Couldn't read the raw transaction: {}", err));
+                }
+            }
+        });
+
+        ui.add_space(10.0);
+        ui.collapsing("Burn Coins", |ui| {
+            ui.label("Destroys coins from the selected wallet. Burned coins are taken out of the supply for good.");
+            ui.horizontal(|ui| {
+                ui.label("Amount:");
+                ui.add(egui::DragValue::new(&mut self.ui_state.burn_amount).speed(0.1));
+                ui.label("coins");
+            });
+            ui.checkbox(&mut self.ui_state.burn_confirmed, "I want to burn these coins");
+
+            let burn_clicked = ui.add_enabled_ui(self.ui_state.burn_confirmed, |ui| {
+                self.action_button(ui, ActionKind::BurnCoins, "🔥 Burn")
+            }).inner;
+            if burn_clicked {
+                if let Err(err) = self.burn_coins() {
+                    self.add_notification(err.to_string());
+                }
+            }
+        });
+
+                ui.add_space(10.0);
+        ui.horizontal(|ui| {
+            ui.heading("Mempool");
+            if ui.button("Refresh").clicked() {
+                self.refresh_mempool();
+            }
+        });
+        if self.ui_state.mempool_txs.is_empty() {
+            ui.label("No transactions waiting to be mined.");
+        }
+        let mut open_txid = None;
+        for tx in &self.ui_state.mempool_txs {
+            let total: i32 = tx.vout.iter().map(|out| out.value).sum();
+            ui.horizontal(|ui| {
+                if ui.link(egui::RichText::new(&tx.id).monospace()).clicked() {
+                    open_txid = Some(tx.id.clone());
+                }
+                ui.label(format!("{} coins", total));
+            });
+        }
+        if let Some(txid) = open_txid {
+            self.open_tx_detail(txid);
+        }
+        self.render_tx_detail(ui.ctx());
+
+                /* Search transactions by id  */
         /* Search your transactions? */
     }
 
 
-    fn render_wallets_section(&mut self, ui: &mut egui::Ui) {
+    fn render_wallets_section(&mut self, ui: &mut egui::Ui, layout: LayoutMode) {
         ui.horizontal(|ui| {
             // Left section: "Total Balance"
             ui.heading("Wallets");
@@ -880,9 +2949,7 @@ impl MyApp {
                 
             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
 
-                if ui.button("Create New Wallet").clicked() {
-                    let sender = self.sender.clone(); // Clone the sender for the async task
-
+                if self.action_button(ui, ActionKind::CreateWallet, "Create New Wallet") {
                     let new_address = self.bc_module.wallets.create_wallet();
                     println!("New wallet address: {}", new_address);
 
@@ -890,26 +2957,18 @@ impl MyApp {
                         println!("Error saving wallet: {}", err);
                     }
 
-                    let wallets = self.bc_module.wallets.clone(); // contains the new wallet
-                    let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+                    let addresses = self.bc_module.wallets.address_groups(); // contains the new wallet
+                    let chain = Arc::clone(&self.bc_module.chain);
 
-                    RUNTIME.spawn(async move {
-                        match MyApp::calculate_new_balances(&wallets, utxo_set).await {
-                            Ok(new_balances) => {
-                                sender.send(TaskMessage::BalancesUpdated(new_balances))
-                                    .await
-                                    .unwrap_or_else(|e| println!("Failed to send balances: {}", e));
-                            }
-                            Err(err) => {
-                                sender.send(TaskMessage::Error(err.to_string()))
-                                    .await
-                                    .unwrap_or_else(|e| println!("Failed to send error: {}", e));
-                            }
+                    self.spawn_action(ActionKind::CreateWallet, async move {
+                        match MyApp::calculate_new_balances(addresses, chain).await {
+                            Ok(new_balances) => TaskMessage::BalancesUpdated(new_balances),
+                            Err(err) => TaskMessage::Error(err.to_string()),
                         }
                     });
 
-
                     self.add_notification("New wallet created successfully.".to_string());
+                    self.refresh_wallet_history();
 
                 }
         
@@ -919,18 +2978,40 @@ impl MyApp {
                     self.ui_state.show_add_existing_wallet_popup = true;                    
                 }
 
+                if ui.button("Proof of Funds").clicked() {
+                    self.ui_state.proof_of_funds.get_or_insert_with(ProofOfFundsForm::default);
+                }
+
             });
         });
 
         ui.label("Manage wallets and their transactions.");
 
+        if !self.ui_state.incoming.payments().is_empty() {
+            let target = self.ui_state.incoming.target();
+            ui.group(|ui| {
+                ui.label(egui::RichText::new("Incoming").strong());
+                for payment in self.ui_state.incoming.payments() {
+                    ui.horizontal(|ui| {
+                        confirmation_bar(ui, payment.confirmations, target);
+                        ui.label(tagged_text(&payment.address, &format!("{} coins to {}", payment.amount, payment.address)));
+                    });
+                }
+            });
+            ui.add_space(10.0);
+        }
+
         // Get immutable data for the loop
         let all_addresses = self.bc_module.wallets.get_all_address();
+        let own_labels = self.own_labels();
+
+        let mut open_txid = None;
+        let mut label_address = None;
 
         // displays each wallet saved on the device
         egui::ScrollArea::vertical().show(ui, |ui: &mut Ui| {
             for address in &all_addresses {
-                let balance = self.get_balance(&address).unwrap_or(0);
+                let balance = self.get_balance(address).unwrap_or(0);
                 
                 egui::Frame::none()
                     .rounding(egui::Rounding::same(5.0))
@@ -946,6 +3027,8 @@ impl MyApp {
 
                                 
                                 ui.horizontal(|ui| {
+                                    ui.label(tagged_text(address, ""));
+
                                     // Add the label
                                     let label_response = ui.add(
                                         egui::Label::new(egui::RichText::new(format!("Address: {}", address)))
@@ -959,8 +3042,10 @@ impl MyApp {
                                     );                                      
 
                                     // Handle click behavior
-                                    if icon_response.clicked() || label_response.clicked() {
-                                        ui.output_mut(|o| o.copied_text = address.clone());
+                                    if icon_response.clicked() {
+                                        copy_to_clipboard(ui.ctx(), address, Some(&icon_response));
+                                    } else if label_response.clicked() {
+                                        copy_to_clipboard(ui.ctx(), address, Some(&label_response));
                                     }
 
                                     // Handle hover behavior
@@ -976,46 +3061,73 @@ impl MyApp {
                                 });
 
                                 ui.label(format!("Balance: {:?} coins", balance));
+                                if let Some(pending) = self.ui_state.pending_amounts.get(address) {
+                                    ui.label(format!("Spendable: {} coins", balance as i64 - pending))
+                                        .on_hover_text("The balance less what our pending sends spend");
+                                }
+                                // counted in the balance above
+                                let change_addresses = self.bc_module.wallets.change_addresses(address);
+                                if !change_addresses.is_empty() {
+                                    egui::CollapsingHeader::new(format!("{} change addresses", change_addresses.len()))
+                                        .id_salt(("change addresses", address))
+                                        .show(ui, |ui| {
+                                            for change in &change_addresses {
+                                                ui.label(tagged_text(change, change));
+                                            }
+                                        });
+                                }
+                                if let Some(pending) = self.ui_state.pending_amounts.get(address) {
+                                    ui.label(format!("{} coins in pending sends", pending));
+                                }
+                                for payment in self.ui_state.incoming.payments_to(address) {
+                                    ui.horizontal(|ui| {
+                                        ui.label(format!("+{} incoming", payment.amount));
+                                        confirmation_bar(ui, payment.confirmations, self.ui_state.incoming.target());
+                                    });
+                                }
+                                if let Some(history) = self.ui_state.wallet_history.get(address) {
+                                    render_mining_income(ui, address, history);
+                                }
+                                let history = self.ui_state.wallet_history.get(address);
+                                match render_wallet_history(ui, address, history, &self.bc_module.address_book, &own_labels) {
+                                    Some(HistoryAction::OpenTx(txid)) => open_txid = Some(txid),
+                                    Some(HistoryAction::Label(counterparty)) => label_address = Some(counterparty),
+                                    None => {}
+                                }
+                                if let Some(history) = self.ui_state.wallet_history.get(address).filter(|history| !history.is_empty()) {
+                                    if ui.small_button("Export history CSV…").clicked() {
+                                        let dialog = rfd::FileDialog::new().add_filter("CSV", &["csv"]).set_file_name(format!("{}_history.csv", address));
+                                        if let Some(path) = dialog.save_file() {
+                                            match std::fs::write(&path, wallet_history::history_csv(history)) {
+                                                Ok(()) => self.add_notification(format!("History exported to {}", path.display())),
+                                                Err(e) => self.add_notification(format!("Couldn't export the history: {}", e)),
+                                            }
+                                        }
+                                    }
+                                }
                             });
 
-                            // Right side buttons
+                            // Right side buttons, behind a menu when they don't fit
                             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                                
-                                // Delete Wallet
-                                ui.scope(|ui|{
-                                    ui.style_mut().visuals.widgets.inactive.weak_bg_fill = egui::Color32::from_rgb(194, 42, 25);
-                                    ui.style_mut().visuals.widgets.active.weak_bg_fill = egui::Color32::from_rgb(194, 42, 25);
-                                    ui.style_mut().visuals.widgets.hovered.weak_bg_fill = egui::Color32::from_rgb(217, 47, 28);
-
-                                    if ui.button(egui::RichText::new("Delete Wallet")).clicked() {
-                                        // Set a flag or show a popup
-                                        self.ui_state.show_delete_popup = Some(address.clone());
+                                match layout {
+                                    LayoutMode::Compact => {
+                                        ui.menu_button("⋯", |ui| {
+                                            for action in WALLET_ACTIONS {
+                                                if wallet_action_button(ui, action) {
+                                                    self.handle_wallet_action(action, address);
+                                                    ui.close_menu();
+                                                }
+                                            }
+                                        });
                                     }
-                                });
-                                    
-                                // Export Wallet
-                                if ui.button("Export Wallet").clicked() {
-                                    if let Some(wallet) = self.bc_module.wallets.get_wallet(address) {
-                                        if let Err(err) = self.export_wallet_to_file(address, wallet) {
-                                            println!("Error exporting wallet: {}", err);
+                                    LayoutMode::Regular => {
+                                        for action in WALLET_ACTIONS.into_iter().rev() {
+                                            if wallet_action_button(ui, action) {
+                                                self.handle_wallet_action(action, address);
+                                            }
                                         }
                                     }
                                 }
-
-                                // Send Wallet
-                                if ui.button("Send").clicked() {
-                                    println!("Send button clicked for wallet: {}", address);
-                                    
-                                    self.ui_state.active_tab = Tab::Transactions;
-
-                                    self.ui_state.selected_wallet = Some(address.clone());
-                                }
-
-                                // Receive - doesn't do anything
-                                if ui.button("Receive").clicked() {
-                                    println!("Receive button clicked for wallet: {}", address);
-                                }
-                                
                             });
                         });
                     });
@@ -1023,12 +3135,29 @@ impl MyApp {
             }
         });
 
+        // the detail popup lives on the Transactions tab
+        if let Some(txid) = open_txid {
+            self.ui_state.active_tab = Tab::Transactions;
+            self.open_tx_detail(txid);
+        }
+        if let Some(address) = label_address {
+            self.open_label_editor(address);
+        }
+
         // ----------- For Popups -----------
 
         let mut delete_wallet_address: Option<String> = None;
+        let mut sweep_wallet_address: Option<String> = None;
 
         // Handle Delete Wallet Popup
         if let Some(wallet_to_delete) = &self.ui_state.show_delete_popup.clone() {
+            let balance = self.get_balance(wallet_to_delete).unwrap_or(0);
+            let other_wallets: Vec<String> = all_addresses
+                .iter()
+                .filter(|a| *a != wallet_to_delete)
+                .cloned()
+                .collect();
+
             egui::Window::new("Confirm Wallet Deletion")
                 .collapsible(false)
                 .resizable(false)
@@ -1038,10 +3167,40 @@ impl MyApp {
                     ui.label(format!("Address: {}", wallet_to_delete.clone()));
                     ui.label("All funds will be lost if the wallet is not retrievable.");
 
+                    // Offer moving the funds somewhere safe first
+                    if balance > 0 {
+                        ui.separator();
+                        ui.label(format!("This wallet holds {} coins. Sweep them to:", balance));
+
+                        egui::ComboBox::from_id_salt("sweep_destination")
+                            .selected_text(if self.ui_state.sweep_destination.is_empty() {
+                                "Select Wallet".to_string()
+                            } else {
+                                self.ui_state.sweep_destination.clone()
+                            })
+                            .show_ui(ui, |ui| {
+                                for address in &other_wallets {
+                                    ui.selectable_value(&mut self.ui_state.sweep_destination, address.clone(), tagged_text(address, address));
+                                }
+                            });
+
+                        ui.add(egui::TextEdit::singleline(&mut self.ui_state.sweep_destination)
+                            .hint_text("Or type an address"));
+
+                        let can_sweep = self.ui_state.sweep_in_progress.is_none()
+                            && !self.ui_state.sweep_destination.trim().is_empty();
+
+                        if ui.add_enabled(can_sweep, egui::Button::new("Sweep funds then delete")).clicked() {
+                            sweep_wallet_address = Some(wallet_to_delete.clone());
+                        }
+                        ui.separator();
+                    }
+
                     ui.horizontal(|ui| {
                         if ui.button("Cancel").clicked() {
                             // Close the popup without deleting
                             self.ui_state.show_delete_popup = None;
+                            self.ui_state.sweep_destination.clear();
                         }
                         ui.scope(|ui|{
                             ui.style_mut().visuals.widgets.inactive.weak_bg_fill = egui::Color32::from_rgb(194, 42, 25);
@@ -1065,6 +3224,13 @@ impl MyApp {
             let _ = self.delete_wallet(&wallet_to_delete);
         }
 
+        if let Some(wallet_to_sweep) = sweep_wallet_address {
+            self.start_sweep_then_delete(&wallet_to_sweep);
+        }
+
+        self.render_receive_popup(ui.ctx());
+        self.render_proof_of_funds_window(ui.ctx());
+
         if self.ui_state.show_add_existing_wallet_popup {
             // Start the window for adding an existing wallet
             egui::Window::new("Add Existing Wallet")
@@ -1075,16 +3241,25 @@ impl MyApp {
                 ui.label("Select Wallet Method:");
 
                 // Option 1: "Select Wallet (.dat file)"
+                ui.horizontal(|ui| {
+                    ui.label("Password (encrypted files):");
+                    ui.add(egui::TextEdit::singleline(&mut self.ui_state.import_password).password(true));
+                });
                 if ui.button("Select Wallet (.dat file)").clicked() {
-                    // Open file explorer to select .dat file
-                    if let Some(path) = rfd::FileDialog::new().add_filter("Wallet File", &["dat"]).pick_file() {
-                        // Deserialize the .dat file to retrieve the wallet
-                        if let Ok(wallet) = self.import_wallet_from_file(path) {
-                            self.bc_module.wallets.insert(&wallet.get_address(), wallet);
-                            println!("Wallet added from .dat file");
-                            self.ui_state.show_add_existing_wallet_popup = false;
-                        } else {
-                            println!("Failed to import wallet from .dat file");
+                    // Open file explorer to select a wallet file
+                    if let Some(path) = rfd::FileDialog::new().add_filter("Wallet File", &["dat", "json", "wif"]).pick_file() {
+                        match self.import_wallet_from_file(path) {
+                            Ok(wallet) => {
+                                self.bc_module.wallets.insert(&wallet.get_address(), wallet);
+                                println!("Wallet added from file");
+                                self.refresh_wallet_history();
+                                self.ui_state.import_password.clear();
+                                self.ui_state.show_add_existing_wallet_popup = false;
+                            }
+                            Err(err) => {
+                                println!("Failed to import wallet from file: {}", err);
+                                self.add_notification(format!("Failed to import wallet: {}", err));
+                            }
                         }
                     }
                 }
@@ -1101,13 +3276,18 @@ impl MyApp {
                 // Provide a button to submit the secret key
                 ui.horizontal(|ui|{
                     if ui.button("Retrieve Wallet").clicked() {
-                        if let Ok(wallet) = self.import_wallet_from_key(&secret_key_input) {
-                            self.bc_module.wallets.insert(&wallet.get_address(), wallet);
-                            println!("Wallet retrieved from private key");
-    
-                            self.ui_state.show_add_existing_wallet_popup = false;
-                        } else {
-                            println!("Failed to retrieve wallet from the provided key");
+                        match self.import_wallet_from_key(&secret_key_input) {
+                            Ok(wallet) => {
+                                self.bc_module.wallets.insert(&wallet.get_address(), wallet);
+                                println!("Wallet retrieved from private key");
+                                self.refresh_wallet_history();
+
+                                self.ui_state.show_add_existing_wallet_popup = false;
+                            }
+                            Err(err) => {
+                                println!("Failed to retrieve wallet from the provided key: {}", err);
+                                self.add_notification(format!("Failed to retrieve wallet: {}", err));
+                            }
                         }
                     }
                     if ui.button("Cancel").clicked(){
@@ -1120,7 +3300,204 @@ impl MyApp {
         
     }
 
+    // Signs the balances of the selected wallets with the verifier's challenge, or checks a
+    // proof someone else made
+    fn render_proof_of_funds_window(&mut self, ctx: &egui::Context) {
+        let Some(form) = &mut self.ui_state.proof_of_funds else {
+            return;
+        };
+        let mut open = true;
+        let mut generate = false;
+        let mut verify = false;
+
+        egui::Window::new("Proof of Funds")
+            .collapsible(false)
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.label("Proves to a verifier which coins your addresses hold at the current tip, without revealing any key.");
+                for address in self.bc_module.wallets.get_all_address() {
+                    let mut selected = form.selected.contains(&address);
+                    if ui.checkbox(&mut selected, wallet_label(&address)).changed() {
+                        if selected {
+                            form.selected.insert(address);
+                        } else {
+                            form.selected.remove(&address);
+                        }
+                    }
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Challenge:");
+                    ui.add(egui::TextEdit::singleline(&mut form.challenge).hint_text("Text the verifier gave you"));
+                });
+                ui.horizontal(|ui| {
+                    let ready = !form.selected.is_empty() && !form.challenge.trim().is_empty();
+                    generate = ui.add_enabled(ready, egui::Button::new("Generate proof of funds…")).clicked();
+                    verify = ui.button("Verify a proof…").clicked();
+                });
+
+                if let Some(report) = &form.report {
+                    ui.separator();
+                    match report {
+                        Ok(report) => {
+                            let (color, verdict) = if report.is_valid() {
+                                (egui::Color32::GREEN, format!("Valid: {} coins proven", report.proven_total()))
+                            } else {
+                                (egui::Color32::RED, String::from("Not valid"))
+                            };
+                            ui.colored_label(color, verdict);
+                            ui.label(format!("At block {} (height {}), challenge \"{}\"", report.block_hash, report.height, report.challenge));
+                            Grid::new("proof_of_funds_report").striped(true).show(ui, |ui| {
+                                ui.strong("Address");
+                                ui.strong("Claimed");
+                                ui.strong("In our chain");
+                                ui.strong("Signature");
+                                ui.end_row();
+                                for check in &report.addresses {
+                                    ui.label(&check.address);
+                                    ui.label(check.claimed.to_string());
+                                    ui.label(check.actual.to_string());
+                                    ui.label(if check.signed { "valid" } else { "invalid" });
+                                    ui.end_row();
+                                }
+                            });
+                        }
+                        Err(err) => { ui.colored_label(egui::Color32::RED, err); }
+                    }
+                }
+            });
+
+        let challenge = form.challenge.trim().to_string();
+        let selected: Vec<String> = form.selected.iter().cloned().collect();
+        if !open {
+            self.ui_state.proof_of_funds = None;
+        }
+        if generate {
+            let dialog = rfd::FileDialog::new().add_filter("Proof of funds", &[proof_of_funds::FILE_EXTENSION]).set_file_name("proof-of-funds.json");
+            if let Some(path) = dialog.save_file() {
+                self.generate_proof_of_funds(selected, challenge, path);
+            }
+        }
+        if verify {
+            if let Some(path) = rfd::FileDialog::new().add_filter("Proof of funds", &[proof_of_funds::FILE_EXTENSION]).pick_file() {
+                self.verify_proof_of_funds(path);
+            }
+        }
+    }
+
+    fn generate_proof_of_funds(&mut self, addresses: Vec<String>, challenge: String, path: PathBuf) {
+        let wallets: Vec<Wallet> = addresses.iter().filter_map(|address| self.bc_module.wallets.get_wallet(address).cloned()).collect();
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        self.spawn_action(ActionKind::ProofOfFunds, async move {
+            let utxo_set = utxo_set.read().await;
+            let blockchain = utxo_set.blockchain.read().await;
+            let result = ProofOfFunds::create(&blockchain, &wallets.iter().collect::<Vec<_>>(), &challenge)
+                .and_then(|proof| proof.to_json())
+                .and_then(|json| Ok(std::fs::write(&path, json)?));
+            TaskMessage::ProofOfFundsSaved(result.map(|()| path).map_err(|e| e.to_string()))
+        });
+    }
+
+    fn verify_proof_of_funds(&mut self, path: PathBuf) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        self.spawn_action(ActionKind::ProofOfFunds, async move {
+            let utxo_set = utxo_set.read().await;
+            let blockchain = utxo_set.blockchain.read().await;
+            let result = std::fs::read_to_string(&path).map_err(failure::Error::from)
+                .and_then(|document| proof_of_funds::verify_proof_of_funds(&document, &blockchain));
+            TaskMessage::ProofOfFundsVerified(result.map_err(|e| e.to_string()))
+        });
+    }
+
+    fn render_receive_popup(&mut self, ctx: &egui::Context) {
+        let Some(request) = &mut self.ui_state.receive_request else {
+            return;
+        };
+        let mut open = true;
+
+        egui::Window::new("Receive")
+            .collapsible(false)
+            .resizable(false)
+            .open(&mut open)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!("Address: {}", request.address));
+                ui.horizontal(|ui| {
+                    ui.label("Amount:");
+                    ui.add(egui::DragValue::new(&mut request.amount).range(1..=i32::MAX));
+                    ui.label("coins");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Memo:");
+                    ui.add(egui::TextEdit::singleline(&mut request.memo).char_limit(MAX_MEMO_LEN).hint_text("Optional, e.g. an invoice number"));
+                });
+
+                ui.separator();
+                let uri = request.to_uri();
+                ui.add(egui::Label::new(egui::RichText::new(&uri).monospace()).wrap());
+                let copy = ui.button("Copy payment request");
+                if copy.clicked() {
+                    copy_to_clipboard(ui.ctx(), &uri, Some(&copy));
+                }
+            });
+
+        if !open {
+            self.ui_state.receive_request = None;
+        }
+    }
+
+    // Replaces the payment request pasted as the receiver with the address, amount and memo in it
+    fn apply_payment_request(&mut self) {
+        match PaymentRequest::from_uri(&self.ui_state.receiver_address) {
+            Ok(request) => {
+                self.ui_state.receiver_address = request.address;
+                self.ui_state.tx_amount = request.amount;
+                self.ui_state.tx_memo = request.memo.clone();
+                self.ui_state.payment_request_banner = Some(if request.memo.is_empty() {
+                    format!("✔ Filled in from a payment request for {} coins", request.amount)
+                } else {
+                    format!("✔ Filled in from a payment request for {} coins: {}", request.amount, request.memo)
+                });
+            }
+            Err(err) => self.add_notification(format!("Couldn't read the payment request: {}", err)),
+        }
+    }
+
+    fn handle_wallet_action(&mut self, action: WalletAction, address: &str) {
+        match action {
+            WalletAction::Receive => {
+                self.ui_state.receive_request = Some(PaymentRequest { address: address.to_string(), amount: 1, memo: String::new() });
+            }
+            WalletAction::Send => {
+                println!("Send button clicked for wallet: {}", address);
+                self.ui_state.active_tab = Tab::Transactions;
+                self.ui_state.selected_wallet = Some(address.to_string());
+            }
+            WalletAction::Export => match self.export_wallet_to_file(address) {
+                Ok(files) if files.len() > 1 => self.add_warning(
+                    format!(
+                        "Exported {} files to wallets/export: the wallet and its {} change wallets. Keep and import all of them, the change wallets hold part of its balance",
+                        files.len(),
+                        files.len() - 1,
+                    ),
+                    None,
+                ),
+                Ok(_) => self.add_notification(String::from("Wallet exported to wallets/export")),
+                Err(err) => {
+                    println!("Error exporting wallet: {}", err);
+                    self.add_notification(format!("Failed to export wallet: {}", err));
+                }
+            },
+            // Set a flag or show a popup
+            WalletAction::Delete => self.ui_state.show_delete_popup = Some(address.to_string()),
+        }
+    }
+
     fn render_peers_section(&mut self, ui: &mut egui::Ui) {
+        if self.ui_state.peers_refreshed.is_none_or(|at| at.elapsed() >= PEERS_REFRESH_INTERVAL) {
+            self.refresh_peers();
+        }
+        ui.ctx().request_repaint_after(PEERS_REFRESH_INTERVAL);
+
         ui.heading("Peers");
         ui.horizontal(|ui| {
             ui.label("View And Manage Your Peers");
@@ -1136,7 +3513,7 @@ impl MyApp {
                 ui.label(format!("Your Public IP: {}", ip));
             },
             Some(Err(_)) => {
-                ui.label(format!("Couldn't retrieve your Public IP"));
+                ui.label("Couldn't retrieve your Public IP");
             },
             _none => {
                 ui.label("Wait...");
@@ -1154,71 +3531,567 @@ impl MyApp {
             });
         });
 
-        if ui.button("Add Peer").clicked() {
-            if !self.ui_state.peer_ip_address_input.is_empty() {
-                
-                let _ = self.add_peer(self.ui_state.peer_ip_address_input.clone(), self.ui_state.peer_port_input.clone());
-                self.ui_state.peer_ip_address_input.clear();
-                self.ui_state.peer_port_input = String::from("8334");
-            
-            }
+        if self.action_button(ui, ActionKind::AddPeer, "Add Peer") && !self.ui_state.peer_ip_address_input.is_empty() {
+            let _ = self.add_peer(self.ui_state.peer_ip_address_input.clone(), self.ui_state.peer_port_input.clone());
+            self.ui_state.peer_ip_address_input.clear();
+            self.ui_state.peer_port_input = String::from("8334");
         }
 
         ui.separator();
 
         // Display the list of connected peers
         ui.label("Connected Peers:");
+        let mut disconnect = None;
         Grid::new("connected_peers_table")
         .striped(true) // Alternating row colors
         .show(ui, |ui| {
             ui.heading("IP Address");
             ui.heading("Node Type");
+            ui.heading("Capabilities");
+            ui.heading("Throughput");
             ui.heading("Actions");
             ui.end_row();
 
-            for peer in &self.ui_state.connected_peers_displayed {
+            for (peer, capabilities) in &self.ui_state.connected_peers_displayed {
                 ui.label(peer);  // IP Address
                 ui.label("Full Node"); // Placeholder for Node Type
 
+                ui.horizontal(|ui| {
+                    for (capability, icon, name) in CAPABILITY_ICONS {
+                        if capabilities.contains(capability) {
+                            ui.label(icon).on_hover_text(name);
+                        }
+                    }
+                });
+
+                let throughput = self.ui_state.peer_throughput.get(peer).copied().unwrap_or_default();
+                ui.label(format!("↑ {:.1} KB/s  ↓ {:.1} KB/s", throughput.upload / 1024.0, throughput.download / 1024.0));
+
                 // Disconnect Button
                 if ui.button("❌ Disconnect").clicked() {
-                    //self.remove_peer(peer.clone());
-                    println!("Disconnecting: {}", peer);
+                    disconnect = Some(peer.clone());
+                }
+
+                ui.end_row();
+            }
+        });
+        if let Some(peer) = disconnect {
+            self.disconnect_peer(peer);
+        }
+
+        ui.add_space(10.0);
+        let mut re_add = None;
+        ui.collapsing(format!("Recently removed peers ({})", self.ui_state.peer_history.len()), |ui| {
+            if self.ui_state.peer_history.is_empty() {
+                ui.label("No peers were removed yet.");
+                return;
+            }
+            Grid::new("peer_history_table")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.heading("Removed");
+                ui.heading("IP Address");
+                ui.heading("Reason");
+                ui.heading("Actions");
+                ui.end_row();
+
+                let now = clock::now_millis();
+                for entry in &self.ui_state.peer_history {
+                    ui.label(format!("{} ago", clock::format_age(clock::tip_age(entry.timestamp, now))));
+                    ui.label(&entry.address);
+                    ui.label(entry.reason.to_string());
+                    if ui.button("➕ Re-add").clicked() {
+                        re_add = Some(entry.address.clone());
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+        if let Some((ip, port)) = re_add.as_deref().and_then(|address| address.rsplit_once(':')) {
+            let _ = self.add_peer(ip.to_string(), port.to_string());
+        }
+
+        ui.add_space(10.0);
+        ui.collapsing("Network", |ui| self.render_network_map(ui));
+    }
+
+    // Our node, the peers we heard from and the nodes they told us about
+    fn render_network_map(&mut self, ui: &mut egui::Ui) {
+        let known_nodes = &self.ui_state.known_nodes;
+        let mut direct: Vec<String> = known_nodes.iter().filter(|(_, n)| n.last_seen().is_some()).map(|(a, _)| a.clone()).collect();
+        let mut gossiped: Vec<String> = known_nodes.iter().filter(|(_, n)| n.last_seen().is_none()).map(|(a, _)| a.clone()).collect();
+        direct.sort();
+        gossiped.sort();
+        let reported: HashMap<String, Vec<String>> = known_nodes.iter()
+            .map(|(address, node)| (address.clone(), node.reported_peers().to_vec()))
+            .collect();
+        let nodes = network_map::layout(&direct, &gossiped, &reported);
+
+        let (response, painter) = ui.allocate_painter(egui::vec2(ui.available_width(), 300.0), egui::Sense::click());
+        let center = response.rect.center();
+        let scale = response.rect.width().min(response.rect.height()) / 2.0 / (network_map::SECOND_RING + 0.2);
+        let to_screen = |pos: (f32, f32)| center + egui::vec2(pos.0, pos.1) * scale;
+
+        for node in &nodes {
+            if let Some(parent) = node.parent {
+                painter.line_segment([to_screen(nodes[parent].pos), to_screen(node.pos)], egui::Stroke::new(1.0, egui::Color32::DARK_GRAY));
+            }
+        }
+        for node in &nodes {
+            let (radius, color) = match node.role {
+                MapRole::Local => (8.0, egui::Color32::LIGHT_BLUE),
+                MapRole::Direct => (6.0, peer_status_color(&known_nodes[&node.address])),
+                MapRole::Gossiped => (3.5, egui::Color32::GRAY),
+            };
+            painter.circle_filled(to_screen(node.pos), radius, color);
+        }
+
+        let hit = |screen: egui::Pos2| {
+            let pos = ((screen.x - center.x) / scale, (screen.y - center.y) / scale);
+            network_map::hit_test(&nodes, pos, 8.0 / scale)
+        };
+        let mut clicked = None;
+        if response.clicked() {
+            clicked = response.interact_pointer_pos().and_then(hit).map(|i| &nodes[i]);
+        }
+        if let Some(node) = response.hover_pos().and_then(hit).map(|i| &nodes[i]) {
+            let text = match known_nodes.get(&node.address) {
+                None => String::from("This node"),
+                Some(peer) => format!(
+                    "{}\nVersion: {}\nLast seen: {}\nLatency: {}",
+                    node.address,
+                    peer.version().map_or(String::from("unknown"), |v| v.to_string()),
+                    peer.last_seen().map_or(String::from("never"), |at| {
+                        format!("{} ago", clock::format_age(clock::tip_age(at, clock::now_millis())))
+                    }),
+                    peer.latency().map_or(String::from("unknown"), |l| format!("{} ms", l.as_millis())),
+                ),
+            };
+            response.on_hover_text_at_pointer(text);
+        }
+        if let Some(node) = clicked {
+            self.ui_state.map_selected = (node.role == MapRole::Gossiped).then(|| node.address.clone());
+        }
+
+        ui.label("Rings: peers we heard from, then the nodes they reported. Click a small dot to contact it.");
+        if let Some(address) = self.ui_state.map_selected.clone() {
+            ui.horizontal(|ui| {
+                ui.label(&address);
+                if self.action_button(ui, ActionKind::AddPeer, "Connect") {
+                    let network = Arc::clone(&self.net_module.network);
+                    self.ui_state.map_selected = None;
+                    self.spawn_action(ActionKind::AddPeer, async move {
+                        match network.connect_peer(address.clone()).await {
+                            Ok(()) => TaskMessage::PeerContacted(address),
+                            Err(err) => TaskMessage::Error(format!("Couldn't reach {}: {}", address, err)),
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    fn render_settings_section(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Settings");
+        ui.label("Change Your Preferred Settings");
+
+        ui.add_space(10.0);
+        ui.collapsing("Node configuration", |ui| {
+            ui.label("In effect since startup. Command-line flags win over the environment, which wins over settings.json.");
+            Grid::new("node_configuration").striped(true).show(ui, |ui| {
+                ui.strong("Setting");
+                ui.strong("Value");
+                ui.strong("Source");
+                ui.end_row();
+                for (name, value, source) in CONFIG.rows() {
+                    ui.label(name);
+                    ui.monospace(value);
+                    ui.label(source.to_string());
+                    ui.end_row();
+                }
+            });
+            for warning in CONFIG.warnings() {
+                ui.colored_label(egui::Color32::YELLOW, format!("⚠ {}", warning));
+            }
+        });
+
+        ui.collapsing("Dust", |ui| {
+            if SETTINGS.dust_threshold > 0 {
+                ui.label(format!("Outputs worth less than {} coins are dust. Change that small is paid as fee instead, and transactions from peers paying dust aren't relayed.", SETTINGS.dust_threshold));
+                ui.label(if SETTINGS.sweep_dust {
+                    "Sends spend the wallet's dust along with the outputs they pick."
+                } else {
+                    "Sends leave the wallet's dust alone."
+                });
+            } else {
+                ui.label("Dust checks are off.");
+            }
+            ui.label("Set dust_threshold and sweep_dust in settings.json.");
+        });
+
+        ui.collapsing("Change addresses", |ui| {
+            ui.label(if SETTINGS.change_addresses {
+                "Change of each send goes to a new address of the sending wallet, shown and counted under it."
+            } else {
+                "Change goes back to the sending address."
+            });
+            ui.label("Set change_addresses in settings.json.");
+        });
+
+        ui.collapsing("Debug Console", |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    ui.add(
+                        egui::TextEdit::multiline(&mut self.ui_state.console_output.as_str())
+                            .font(egui::TextStyle::Monospace)
+                            .desired_width(f32::INFINITY),
+                    );
+                });
+
+            ui.horizontal(|ui| {
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.ui_state.console_input)
+                        .font(egui::TextStyle::Monospace)
+                        .hint_text("help"),
+                );
+                let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if entered || ui.button("Run").clicked() {
+                    self.run_console_command();
+                    response.request_focus();
+                }
+                let copy = ui.button("Copy");
+                if copy.clicked() {
+                    copy_to_clipboard(ui.ctx(), &self.ui_state.console_output, Some(&copy));
+                }
+                if ui.button("Clear").clicked() {
+                    self.ui_state.console_output.clear();
+                }
+            });
+        });
+
+        ui.collapsing("Maintenance", |ui| {
+            ui.label("Rewrites the databases to give back the space of deleted data. Not available while syncing.");
+            if self.action_button(ui, ActionKind::CompactDatabases, "Compact databases") {
+                self.compact_databases();
+            }
+
+            ui.label("Checks every stored block and transaction, for after a crash.");
+            if self.action_button(ui, ActionKind::VerifyChain, "Verify Blockchain") {
+                self.verify_chain();
+            }
+
+            ui.label("Compares the UTXO set with the chain and offers to rebuild it when they differ.");
+            if self.action_button(ui, ActionKind::RepairUtxos, "Repair UTXO index") {
+                self.audit_utxos();
+            }
+
+            if !self.ui_state.compaction_report.is_empty() {
+                Grid::new("compaction_report").striped(true).show(ui, |ui| {
+                    ui.strong("Store");
+                    ui.strong("Before");
+                    ui.strong("After");
+                    ui.end_row();
+                    for report in &self.ui_state.compaction_report {
+                        ui.label(&report.name);
+                        ui.label(maintenance::format_size(report.before));
+                        ui.label(maintenance::format_size(report.after));
+                        ui.end_row();
+                    }
+                });
+            }
+        });
+
+        ui.collapsing("Chain file", |ui| {
+            ui.label("Copies the chain to a file that any machine can import. Importing checks every block; a node can only take a file of its own chain unless it has no blocks yet.");
+            ui.horizontal(|ui| {
+                if self.action_button(ui, ActionKind::ExportChain, "Export chain…") {
+                    let file_name = format!("chain.{}", chain_file::FILE_EXTENSION);
+                    if let Some(path) = rfd::FileDialog::new().add_filter("Chain file", &[chain_file::FILE_EXTENSION]).set_file_name(file_name).save_file() {
+                        self.export_chain(path);
+                    }
+                }
+                if self.action_button(ui, ActionKind::ImportChain, "Import chain…") {
+                    if let Some(path) = rfd::FileDialog::new().add_filter("Chain file", &[chain_file::FILE_EXTENSION]).pick_file() {
+                        self.import_chain(path);
+                    }
+                }
+            });
+            if let Some((done, total)) = self.ui_state.chain_file_progress {
+                ui.add(egui::ProgressBar::new(done as f32 / total.max(1) as f32).text(format!("{} of {} blocks", done, total)));
+            }
+        });
+
+        ui.collapsing("UTXO snapshot", |ui| {
+            ui.label("Saves the unspent outputs at the current height, so a node with the same chain can load them instead of rebuilding them from every block. Importing checks the file's hash and that its block is on our chain.");
+            ui.horizontal(|ui| {
+                if self.action_button(ui, ActionKind::UtxoSnapshot, "Export UTXO snapshot…") {
+                    let file_name = format!("utxos.{}", utxo_file::FILE_EXTENSION);
+                    if let Some(path) = rfd::FileDialog::new().add_filter("UTXO snapshot", &[utxo_file::FILE_EXTENSION]).set_file_name(file_name).save_file() {
+                        self.export_utxo_snapshot(path);
+                    }
+                }
+                if self.action_button(ui, ActionKind::UtxoSnapshot, "Import UTXO snapshot…") {
+                    if let Some(path) = rfd::FileDialog::new().add_filter("UTXO snapshot", &[utxo_file::FILE_EXTENSION]).pick_file() {
+                        self.import_utxo_snapshot(path);
+                    }
+                }
+            });
+        });
+
+        ui.collapsing("Audit log", |ui| {
+            self.render_audit_log(ui);
+        });
+    }
+
+    // Read-only, newest first. Each entry carries the hash of the one before, so an edited
+    // or removed entry shows up as a broken chain
+    fn render_audit_log(&mut self, ui: &mut egui::Ui) {
+        ui.label("Wallet deletions, chain recreations, rollbacks and chain imports, whether they went through or not.");
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("audit_filter")
+                .selected_text(self.ui_state.audit_filter.map_or(String::from("All actions"), |action| action.to_string()))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.ui_state.audit_filter, None, "All actions");
+                    for action in AuditAction::ALL {
+                        ui.selectable_value(&mut self.ui_state.audit_filter, Some(action), action.to_string());
+                    }
+                });
+            if ui.button("Refresh").clicked() {
+                self.refresh_audit_log();
+            }
+            if ui.add_enabled(!self.ui_state.audit_entries.is_empty(), egui::Button::new("Export audit log…")).clicked() {
+                self.export_audit_log();
+            }
+        });
+
+        match audit::verify(&self.ui_state.audit_entries) {
+            None => { ui.label(format!("{} entries, hash chain intact", self.ui_state.audit_entries.len())); }
+            Some(i) => { ui.colored_label(egui::Color32::RED, format!("⚠ Hash chain broken at entry {} of {}, the log was altered", i + 1, self.ui_state.audit_entries.len())); }
+        }
+
+        let filter = self.ui_state.audit_filter;
+        egui::ScrollArea::vertical().id_salt("audit_log").max_height(300.0).show(ui, |ui| {
+            Grid::new("audit_log_grid").striped(true).show(ui, |ui| {
+                ui.strong("Time");
+                ui.strong("Action");
+                ui.strong("Parameters");
+                ui.strong("Outcome");
+                ui.end_row();
+                for entry in self.ui_state.audit_entries.iter().rev().filter(|e| filter.is_none_or(|action| e.action == action)) {
+                    ui.label(convert_timestamp(entry.timestamp));
+                    ui.label(entry.action.to_string());
+                    ui.monospace(entry.params.iter().map(|(name, value)| format!("{}: {}", name, value)).collect::<Vec<_>>().join(", "));
+                    ui.label(entry.outcome.to_string());
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    // There is no node profile to put it in, the log goes to its own JSON file
+    fn export_audit_log(&mut self) {
+        let dialog = rfd::FileDialog::new().add_filter("JSON", &["json"]).set_file_name("audit-log.json");
+        if let Some(path) = dialog.save_file() {
+            match serde_json::to_string_pretty(&self.ui_state.audit_entries).map(|json| std::fs::write(&path, json)) {
+                Ok(Ok(())) => self.add_notification(format!("Audit log exported to {}", path.display())),
+                Ok(Err(e)) => self.add_notification(format!("Couldn't export the audit log: {}", e)),
+                Err(e) => self.add_notification(format!("Couldn't export the audit log: {}", e)),
+            }
+        }
+    }
+
+    fn export_chain(&mut self, path: PathBuf) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let mut progress = chain_file_progress(self.sender.clone());
+        if self.spawn_action(ActionKind::ExportChain, async move {
+            let utxo_set = utxo_set.read().await;
+            let result = utxo_set.blockchain.read().await.export_to_file(&path, &mut progress);
+            TaskMessage::ChainExported(result.map(|blocks| (blocks, path)).map_err(|e| e.to_string()))
+        }) {
+            self.add_notification("Exporting the chain…".to_string());
+        }
+    }
+
+    fn import_chain(&mut self, path: PathBuf) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let server = Arc::clone(&self.net_module.server);
+        let addresses = self.bc_module.wallets.address_groups();
+        let chain = Arc::clone(&self.bc_module.chain);
+        let sender = self.sender.clone();
+        let progress = chain_file_progress(self.sender.clone());
+        if self.spawn_action(ActionKind::ImportChain, async move {
+            let result = chain_file::import_chain(&utxo_set, &*server.read().await, &path, progress).await;
+            if result.is_ok() {
+                if let Ok(new_balances) = MyApp::calculate_new_balances(addresses, chain).await {
+                    let _ = sender.send(TaskMessage::BalancesUpdated(new_balances)).await;
+                }
+            }
+            TaskMessage::ChainImported(result.map_err(|e| e.to_string()))
+        }) {
+            self.add_notification("Importing the chain…".to_string());
+        }
+    }
+
+    // At the best height when the export starts
+    fn export_utxo_snapshot(&mut self, path: PathBuf) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        if self.spawn_action(ActionKind::UtxoSnapshot, async move {
+            let utxo_set = utxo_set.read().await;
+            let result = match utxo_set.blockchain.read().await.get_best_height() {
+                Ok(height) => utxo_set.export_snapshot(&path, height).await,
+                Err(e) => Err(e),
+            };
+            TaskMessage::UtxoSnapshotExported(result.map(|summary| (summary, path)).map_err(|e| e.to_string()))
+        }) {
+            self.add_notification("Exporting the UTXO set…".to_string());
+        }
+    }
+
+    fn import_utxo_snapshot(&mut self, path: PathBuf) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let addresses = self.bc_module.wallets.address_groups();
+        let chain = Arc::clone(&self.bc_module.chain);
+        let sender = self.sender.clone();
+        if self.spawn_action(ActionKind::UtxoSnapshot, async move {
+            let result = utxo_set.write().await.import_snapshot(&path).await;
+            if result.is_ok() {
+                if let Ok(new_balances) = MyApp::calculate_new_balances(addresses, chain).await {
+                    let _ = sender.send(TaskMessage::BalancesUpdated(new_balances)).await;
+                }
+            }
+            TaskMessage::UtxoSnapshotImported(result.map_err(|e| e.to_string()))
+        }) {
+            self.add_notification("Importing the UTXO snapshot…".to_string());
+        }
+    }
+
+    fn compact_databases(&mut self) {
+        // The UI holds the wallets, they're small enough to do right here
+        let wallets = self.bc_module.wallets.compact().unwrap_or_else(|e| {
+            self.add_notification(format!("Couldn't compact the wallets: {}", e));
+            None
+        });
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let server = Arc::clone(&self.net_module.server);
+        self.spawn_action(ActionKind::CompactDatabases, async move {
+            let result = maintenance::compact_node(&utxo_set, &*server.read().await).await
+                .map(|mut report| { report.extend(wallets); report });
+            TaskMessage::CompactionFinished(result.map_err(|e| e.to_string()))
+        });
+    }
+
+    // "Copied!" next to the widget of the last copy, or a notification when it failed
+    fn render_copy_feedback(&mut self, ctx: &egui::Context) {
+        let id = egui::Id::new(COPY_FEEDBACK_ID);
+        match ctx.data(|d| d.get_temp::<CopyFeedback>(id)) {
+            Some(CopyFeedback::Failed(err)) => {
+                ctx.data_mut(|d| d.remove::<CopyFeedback>(id));
+                self.add_notification(format!("Couldn't copy to the clipboard: {}. Select the text and copy it by hand.", err));
+            }
+            Some(CopyFeedback::Copied { layer, widget, rect, until }) => {
+                let now = ctx.input(|i| i.time);
+                if now < until {
+                    egui::show_tooltip_for(ctx, layer, widget.with("copied"), &rect, |ui| ui.label("Copied!"));
+                    ctx.request_repaint_after(std::time::Duration::from_secs_f64(until - now));
+                } else {
+                    ctx.data_mut(|d| d.remove::<CopyFeedback>(id));
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn verify_chain(&mut self) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        if self.spawn_action(ActionKind::VerifyChain, async move {
+            let utxo_set = utxo_set.read().await;
+            let result = utxo_set.blockchain.read().await.verify_chain();
+            TaskMessage::ChainVerified(result.map_err(|e| e.to_string()))
+        }) {
+            self.add_notification("Verifying the blockchain…".to_string());
+        }
+    }
+
+    // A UTXO set that differs from the chain comes back as a warning offering to reindex
+    fn audit_utxos(&mut self) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        if self.spawn_action(ActionKind::RepairUtxos, async move {
+            let result = utxo_set.read().await.verify_against_chain().await;
+            TaskMessage::UtxosAudited(result.map_err(|e| e.to_string()))
+        }) {
+            self.add_notification("Checking the UTXO set against the chain…".to_string());
+        }
+    }
+
+    fn reindex_utxos(&mut self) {
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let addresses = self.bc_module.wallets.address_groups();
+        let chain = Arc::clone(&self.bc_module.chain);
+        let sender = self.sender.clone();
+        if self.spawn_action(ActionKind::RepairUtxos, async move {
+            let result = utxo_set.write().await.reindex().await;
+            if result.is_ok() {
+                if let Ok(new_balances) = MyApp::calculate_new_balances(addresses, chain).await {
+                    let _ = sender.send(TaskMessage::BalancesUpdated(new_balances)).await;
                 }
-
-                ui.end_row();
             }
-        });
-        // display connected peers - ip address, node type, Functionality (disconnect from peering, )
-
-        
-
+            TaskMessage::UtxosReindexed(result.map_err(|e| e.to_string()))
+        }) {
+            self.add_notification("Rebuilding the UTXO set from the chain…".to_string());
+        }
     }
 
-    fn render_settings_section(&mut self, ui: &mut egui::Ui) {
-        ui.heading("Settings");
-        ui.label("Change Your Preferred Settings");
+    // Parses the console line here, runs it on the runtime and prints the result via ConsoleOutput
+    fn run_console_command(&mut self) {
+        let line = std::mem::take(&mut self.ui_state.console_input);
+        if line.trim().is_empty() {
+            return;
+        }
+        self.ui_state.console_output.push_str(&format!("> {}\n", line.trim()));
 
+        let command = match console::parse(&line) {
+            Ok(command) => command,
+            Err(message) => {
+                self.ui_state.console_output.push_str(&format!("{}\n", message));
+                return;
+            }
+        };
 
+        let utxo_set = Arc::clone(&self.bc_module.utxo_set);
+        let server = Arc::clone(&self.net_module.server);
+        let sender = self.sender.clone();
+        self.tasks.spawn(async move {
+            let output = match console::execute(&command, &utxo_set, &server).await {
+                Ok(output) => output,
+                Err(e) => format!("Error: {}\n", e),
+            };
+            let _ = sender.send(TaskMessage::ConsoleOutput(output)).await;
+        });
     }
 
-    fn render_notifications(&mut self, ctx: &egui::Context) {
+    fn render_notifications(&mut self, ctx: &egui::Context, layout: LayoutMode) {
         // Calculate notification timeout and filter out expired notifications
         let now = std::time::Instant::now();
         self.notif_module.notifications.retain(|n| now.duration_since(n.start_time).as_secs() < n.duration);
     
-        // Bottom-right corner positioning
+        // Bottom-right corner positioning, smaller and fewer in compact mode so the
+        // stack stays clear of the forms
         let screen_rect = ctx.screen_rect();
+        let size = layout.notification_size();
         let mut y_offset = screen_rect.max.y - 15.0; // Start 15 px from the bottom
-        let x_offset = screen_rect.max.x - 350.0 - 15.0;    // Notifications are 300 px wide + 15px margin
+        let x_offset = screen_rect.max.x - size.x - 15.0; // 15px margin
     
         let mut to_remove = Vec::new(); // Collect IDs of notifications to remove
+        let mut clicked_actions = Vec::new();
 
-        for notification in &self.notif_module.notifications {
+        let hidden = self.notif_module.notifications.len().saturating_sub(layout.max_notifications());
+        for notification in self.notif_module.notifications.iter().skip(hidden) {
             // Calculate the position for this notification
             let notification_rect = egui::Rect::from_min_size(
-                egui::pos2(x_offset, y_offset - 75.0), // Notification height = 50 px
-                egui::vec2(350.0, 75.0),
+                egui::pos2(x_offset, y_offset - size.y),
+                size,
             );
             
             egui::Area::new(egui::Id::new(notification.id))
@@ -1231,10 +4104,11 @@ impl MyApp {
                         5.0, // Corner radius
                         egui::Color32::from_rgb(25, 25, 25), // Background color
                     );
+                    let border = if notification.warning { egui::Color32::YELLOW } else { egui::Color32::WHITE };
                     painter.rect_stroke(
                         notification_rect,
                         5.0, // Corner radius
-                        egui::Stroke::new(2.0, egui::Color32::WHITE), // Border width and color
+                        egui::Stroke::new(2.0, border), // Border width and color
                     );
 
                     // Constrain the UI to the rectangle width for wrapping
@@ -1256,6 +4130,17 @@ impl MyApp {
                                 to_remove.push(notification.id); // Schedule for removal
                             }
 
+                            if let Some(action) = &notification.action {
+                                let label = match action {
+                                    NotificationAction::RetryWithHigherFee(_) => "Retry with higher fee",
+                                    NotificationAction::ReindexUtxos => "Reindex",
+                                };
+                                if ui.button(label).clicked() {
+                                    clicked_actions.push(action.clone());
+                                    to_remove.push(notification.id);
+                                }
+                            }
+
                             // Centered, wrapped label
                             ui.add(egui::Label::new(egui::RichText::new(&notification.message)
                                 .color(egui::Color32::WHITE)
@@ -1267,10 +4152,16 @@ impl MyApp {
                 });
 
     
-            y_offset -= 90.0; // Stack next notification 10 px above the current one
+            y_offset -= size.y + 10.0; // Stack next notification 10 px above the current one
         }
 
         self.notif_module.notifications.retain(|n| !to_remove.contains(&n.id));
+        for action in clicked_actions {
+            match action {
+                NotificationAction::RetryWithHigherFee(txid) => self.retry_with_higher_fee(txid),
+                NotificationAction::ReindexUtxos => self.reindex_utxos(),
+            }
+        }
 
     }
 
@@ -1285,37 +4176,1058 @@ impl MyApp {
                     println!("Error occurred: {}", err);
                     self.add_notification(err); // Display error to the user
                 }
-                TaskMessage::TransactionSent(successful) => {
-                    if successful {
-                        self.add_notification(String::from("Successful Transaction!"));
-                    } else {
-                        self.add_notification(String::from("UNSUCCESSFUL Transaction."));
+                TaskMessage::TransactionSent(result) => {
+                    match result {
+                        Ok(txid) => {
+                            self.add_notification(String::from("Successful Transaction!"));
+                            if self.ui_state.send_max_fee.take().is_some() {
+                                self.ui_state.tx_amount = 0; // swept, nothing is left to send again
+                            }
+                            self.ui_state.pending_txids.push(txid);
+                            self.check_pending_sends();
+                        }
+                        Err(err) => {
+                            println!("Transaction failed: {}", err);
+                            self.add_notification(String::from("UNSUCCESSFUL Transaction."));
+                        }
                     }
                 }
+                TaskMessage::FeeBumped(original, Ok(txid)) => {
+                    self.add_notification(format!("Transaction {} sent again with a higher fee as {}", original, txid));
+                    self.ui_state.pending_txids.retain(|pending| *pending != original);
+                    self.ui_state.receipts.remove(&original);
+                    self.ui_state.pending_txids.push(txid);
+                    self.check_pending_sends();
+                }
+                TaskMessage::FeeBumped(original, Err(err)) => {
+                    self.add_notification(format!("Couldn't bump the fee of {}: {}", original, err));
+                }
+                TaskMessage::MaxAmountLoaded(Ok((amount, fee))) => {
+                    self.ui_state.tx_amount = amount;
+                    self.ui_state.send_max_fee = Some(fee);
+                }
+                TaskMessage::MaxAmountLoaded(Err(err)) => {
+                    self.add_notification(format!("Nothing to send: {}", err));
+                }
+                TaskMessage::FeeEstimated(preset, Ok(fee_rate)) => {
+                    let fee = self.fee_at_rate(fee_rate);
+                    self.ui_state.tx_fee = fee;
+                    self.ui_state.fee_estimate = Some((preset, fee_rate, fee));
+                }
+                TaskMessage::FeeEstimated(preset, Err(err)) => {
+                    self.add_notification(format!("Couldn't estimate a {} fee: {}", preset.to_lowercase(), err));
+                }
+                TaskMessage::SendPreviewed(amount, Ok(preview)) => {
+                    self.ui_state.send_preview = Some((amount, preview));
+                }
+                TaskMessage::SendPreviewed(_, Err(err)) => {
+                    self.add_notification(format!("Can't send this: {}", err));
+                }
+                TaskMessage::BatchSent(ids, result) => {
+                    self.handle_batch_sent(ids, result);
+                }
                 TaskMessage::PeerAdded(address) => {
                     println!("Successfully added: {}", address);
 
-                    self.ui_state.connected_peers_displayed.push(address);
-                    
+                    self.ui_state.connected_peers_displayed.push((address, Capabilities::NONE));
+                }
+                TaskMessage::PeersLoaded(peers) => {
+                    self.ui_state.connected_peers_displayed = peers;
+                }
+                TaskMessage::PeerHistoryLoaded(history) => {
+                    self.ui_state.peer_history = history;
+                }
+                TaskMessage::AuditLogLoaded(Ok(entries)) => {
+                    self.ui_state.audit_entries = entries;
+                }
+                TaskMessage::AuditLogLoaded(Err(err)) => {
+                    println!("Failed to load the audit log: {}", err);
+                }
+                TaskMessage::SentTxsLoaded(sent, height) => {
+                    self.ui_state.sent_txs = sent;
+                    self.ui_state.sent_tip_height = height;
+                }
+                TaskMessage::WalletHistoryLoaded(history) => {
+                    self.ui_state.wallet_history = history;
+                }
+                TaskMessage::ReceiptUpdated(receipt) => {
+                    if self.ui_state.pending_txids.contains(&receipt.txid) {
+                        self.ui_state.receipts.insert(receipt.txid.clone(), receipt);
+                    }
+                }
+                TaskMessage::ChainStatsLoaded(stats) => {
+                    self.ui_state.chain_stats = Some(stats);
+                }
+                TaskMessage::ThroughputLoaded(throughput) => {
+                    self.ui_state.peer_throughput = throughput;
+                }
+                TaskMessage::SweepFinished(address, result) => {
+                    self.handle_sweep_finished(address, result);
+                }
+                TaskMessage::ConsoleOutput(output) => {
+                    self.ui_state.console_output.push_str(&output);
+                    if !output.ends_with('\n') {
+                        self.ui_state.console_output.push('\n');
+                    }
+                }
+                TaskMessage::ActionFinished(kind) => {
+                    self.actions_in_flight.remove(&kind);
+                    // the send may have created a change wallet
+                    if matches!(kind, ActionKind::SendTx | ActionKind::SendBatch | ActionKind::BurnCoins) {
+                        if let Err(e) = self.bc_module.wallets.load_new() {
+                            self.add_notification(format!("Couldn't load the new change wallets: {}", e));
+                        }
+                    }
+                }
+                TaskMessage::MempoolLoaded(txs) => {
+                    self.ui_state.mempool_txs = txs;
+                }
+                TaskMessage::TxDetailLoaded(Ok(detail)) => {
+                    self.ui_state.tx_detail = Some(detail);
+                }
+                TaskMessage::TxDetailLoaded(Err(err)) => {
+                    self.add_notification(format!("Couldn't load transaction: {}", err));
+                }
+                TaskMessage::OlderBlocksLoaded(blocks) => {
+                    self.handle_older_blocks_loaded(blocks);
+                }
+                TaskMessage::PrunedHeightLoaded(pruned_height) => {
+                    self.ui_state.pruned_height = pruned_height;
+                }
+                TaskMessage::RawHexLoaded(Ok(hex)) => {
+                    if copy_to_clipboard(ctx, &hex, None) {
+                        self.add_notification(String::from("Raw hex copied"));
+                    }
+                }
+                TaskMessage::RawHexLoaded(Err(err)) => {
+                    self.add_notification(format!("Couldn't copy the raw hex: {}", err));
+                }
+                TaskMessage::RawTxBroadcast(Ok(txid)) => {
+                    self.add_notification(format!("Raw transaction {} broadcast", txid));
+                    self.ui_state.raw_tx_input.clear();
+                    self.refresh_mempool();
+                }
+                TaskMessage::RawTxBroadcast(Err(err)) => {
+                    self.add_notification(format!("Raw transaction refused: {}", err));
+                }
+                TaskMessage::BlockSearchFinished(query, found) => {
+                    self.handle_block_search_finished(query, found);
+                }
+                TaskMessage::BlockTransactionsLoaded(hash, txids) => {
+                    self.handle_block_transactions_loaded(hash, txids);
+                }
+                TaskMessage::BlocksLoaded(new_blocks) => {
+                    self.handle_blocks_loaded(new_blocks);
+                }
+                TaskMessage::CompactionFinished(Ok(report)) => {
+                    let saved: u64 = report.iter().map(|r| r.before.saturating_sub(r.after)).sum();
+                    self.add_notification(format!("Databases compacted, {} freed", maintenance::format_size(saved)));
+                    self.ui_state.compaction_report = report;
+                }
+                TaskMessage::CompactionFinished(Err(err)) => {
+                    self.add_notification(format!("Couldn't compact the databases: {}", err));
+                }
+                TaskMessage::ChainFileProgress(done, total) => {
+                    self.ui_state.chain_file_progress = Some((done, total));
+                }
+                TaskMessage::ChainExported(result) => {
+                    self.ui_state.chain_file_progress = None;
+                    match result {
+                        Ok((blocks, path)) => self.add_notification(format!("Exported {} blocks to {}", blocks, path.display())),
+                        Err(err) => self.add_notification(format!("Couldn't export the chain: {}", err)),
+                    }
+                }
+                TaskMessage::ProofOfFundsSaved(result) => match result {
+                    Ok(path) => self.add_notification(format!("Proof of funds saved to {}", path.display())),
+                    Err(err) => self.add_notification(format!("Couldn't create the proof of funds: {}", err)),
+                },
+                TaskMessage::ProofOfFundsVerified(report) => {
+                    if let Some(form) = &mut self.ui_state.proof_of_funds {
+                        form.report = Some(report);
+                    }
+                }
+                TaskMessage::ChainVerified(result) => match result {
+                    Ok(report) if report.problem.is_none() => self.add_notification(report.to_string()),
+                    Ok(report) => self.add_warning(format!("The blockchain is inconsistent. {}", report), None),
+                    Err(err) => self.add_notification(format!("Couldn't verify the blockchain: {}", err)),
+                },
+                TaskMessage::UtxosAudited(result) => match result {
+                    Ok(report) if report.is_consistent() => self.add_notification(report.to_string()),
+                    Ok(report) => self.add_warning(format!("The UTXO set doesn't match the chain. {}", report), Some(NotificationAction::ReindexUtxos)),
+                    Err(err) => self.add_notification(format!("Couldn't check the UTXO set: {}", err)),
+                },
+                TaskMessage::UtxosReindexed(result) => match result {
+                    Ok(()) => self.add_notification("Rebuilt the UTXO set from the chain".to_string()),
+                    Err(err) => self.add_notification(format!("Couldn't rebuild the UTXO set: {}", err)),
+                },
+                TaskMessage::UtxoSnapshotExported(result) => match result {
+                    Ok((summary, path)) => self.add_notification(format!("Exported {} to {}", summary, path.display())),
+                    Err(err) => self.add_notification(format!("Couldn't export the UTXO set: {}", err)),
+                },
+                TaskMessage::UtxoSnapshotImported(result) => match result {
+                    Ok(summary) => self.add_notification(format!("Imported {}", summary)),
+                    Err(err) => self.add_notification(format!("Couldn't import the UTXO snapshot: {}", err)),
+                },
+                TaskMessage::ChainImported(result) => {
+                    self.ui_state.chain_file_progress = None;
+                    match result {
+                        Ok(summary) => {
+                            self.add_notification(format!("Imported {} blocks, {} were already stored", summary.added, summary.known));
+                            // The genesis block may have been replaced, the list is read again
+                            self.ui_state.blocks.clear();
+                            self.refresh_blocks();
+                            self.refresh_wallet_history();
+                        }
+                        Err(err) => self.add_notification(format!("Couldn't import the chain: {}", err)),
+                    }
+                    self.refresh_audit_log();
+                }
+                TaskMessage::HealthLoaded(report) => {
+                    self.ui_state.health = Some(report);
+                }
+                TaskMessage::KnownNodesLoaded(nodes) => {
+                    self.ui_state.known_nodes = nodes;
+                }
+                TaskMessage::PublicIpLoaded(result) => {
+                    self.net_module.public_ip = Some(result.map_err(|e| failure::format_err!("Failed to retrieve public IP: {}", e)));
+                }
+                TaskMessage::PeerContacted(address) => {
+                    self.add_notification(format!("Sent our version to {}", address));
+                }
+                TaskMessage::PendingSendsChecked { abandoned, settled, pending_amounts } => {
+                    self.handle_pending_sends_checked(abandoned, settled, pending_amounts);
+                }
+            }
+        }
 
-                    
+        while let Ok(event) = self.node_events.try_recv() {
+            match event {
+                NodeEvent::BlocksConnected(batch) => {
+                    self.handle_blocks_connected(batch);
+                }
+                NodeEvent::TipStalled { tip_age } => {
+                    self.ui_state.status_warning = Some(format!(
+                        "Chain tip is {} old — node may be out of sync",
+                        clock::format_age(tip_age)
+                    ));
+                }
+                NodeEvent::ChainReorganized { fork_height, disconnected, connected } => {
+                    self.add_notification(if connected == 0 {
+                        format!("Rolled back {} blocks to height {}", disconnected, fork_height)
+                    } else {
+                        format!("Chain reorganized at height {}: {} blocks replaced by {}", fork_height, disconnected, connected)
+                    });
+                    // The replaced blocks are read again from the new branch
+                    self.ui_state.blocks.retain(|b| b.height <= fork_height);
+                    self.refresh_blocks();
+                    self.refresh_mempool();
+                    self.refresh_wallet_history();
+                    self.refresh_sent_txs();
+                    self.refresh_chain_stats();
+                }
+                NodeEvent::DiskSpace { level, free, stores } => {
+                    self.handle_disk_space(level, free, &stores);
+                    self.refresh_health();
+                }
+                NodeEvent::ResumedFromSleep { slept } => {
+                    self.add_notification(format!("Resumed after {} of sleep — reconnecting", clock::format_age(slept)));
+                    self.refresh_public_ip();
+                    self.refresh_peers();
+                }
+                NodeEvent::PeerRemoved { address, reason } => {
+                    println!("Peer {} removed: {}", address, reason);
+                    self.refresh_peers();
+                }
+                NodeEvent::CheckpointReceived { height, hash } => {
+                    self.add_notification(format!("Operator checkpoint: block {} at height {}", hash, height));
+                }
+                NodeEvent::TxAcknowledged { ack } => {
+                    self.record_ack(ack);
+                }
+                // Only our own sends are worth a notification, they stay pending until abandoned.
+                // Replaced ones were bumped
+                NodeEvent::MempoolEvicted { txid, reason } if reason != EvictionReason::Replaced && self.ui_state.pending_txids.contains(&txid) => {
+                    self.add_notification(format!(
+                        "Transaction {} was dropped from the mempool ({}). Its coins are released when it's abandoned after {} hours.",
+                        txid,
+                        reason.to_string().to_lowercase(),
+                        SETTINGS.pending_expiry_hours,
+                    ));
+                }
+                NodeEvent::MempoolEvicted { .. } => {}
+                NodeEvent::BalanceMismatch { block_hash, mismatches } => {
+                    for m in mismatches {
+                        self.add_notification(format!(
+                            "Balance mismatch after block {}: {} has {} on chain but {} in the UTXO set",
+                            block_hash, m.address, m.chain_balance, m.utxo_balance
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Payments to `addresses` in `blocks` (oldest first) as (txid, address, amount, height, sender),
+// summed per transaction and address. The sender is the first input's address, None for block
+// rewards. Transactions spending from our own wallets are change, not payments
+fn incoming_payments<'a>(blocks: impl Iterator<Item = &'a Block>, addresses: &[String]) -> Vec<(String, String, i32, i32, Option<String>)> {
+    let ours: Vec<(Vec<u8>, &String)> = addresses.iter()
+        .filter_map(|address| address::address_to_hash(address).ok().map(|hash| (hash, address)))
+        .collect();
+
+    let mut payments = Vec::new();
+    for block in blocks {
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() && tx.vin.iter().any(|vin| addresses.contains(&vin.get_address())) {
+                continue;
+            }
+            for (pub_key_hash, address) in &ours {
+                let amount: i32 = tx.vout.iter()
+                    .filter(|out| &out.pub_key_hash == pub_key_hash)
+                    .map(|out| out.value)
+                    .sum();
+                if amount > 0 {
+                    let sender = tx.vin.first().filter(|_| !tx.is_coinbase()).map(|vin| vin.get_address());
+                    payments.push((tx.id.clone(), address.to_string(), amount, block.get_height(), sender));
+                }
+            }
+        }
+    }
+    payments
+}
+
+// Appends a startup problem to STARTUP_LOG, so it's still there after the dialog is closed
+fn record_startup_problem(message: &str) {
+    use std::io::Write;
+    let line = format!("{} {}\n", Utc::now().to_rfc3339(), message.replace('\n', " "));
+    let written = std::fs::OpenOptions::new().create(true).append(true).open(STARTUP_LOG)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        eprintln!("Couldn't write {}: {}", STARTUP_LOG, e);
+    }
+}
+
+// Tracks `addresses` in the owned transactions index and reads their history
+fn load_wallet_history(blockchain: &Blockchain, addresses: &[String]) -> Result<HashMap<String, Vec<HistoryEntry>>> {
+    blockchain.track_wallets(addresses)?;
+    addresses.iter()
+        .map(|address| Ok((address.clone(), blockchain.wallet_history(address)?)))
+        .collect()
+}
+
+// The transaction list of a block, as far as it has been read
+// The Proof of Funds window
+#[derive(Default)]
+struct ProofOfFundsForm {
+    selected: HashSet<String>, // addresses to include
+    challenge: String,
+    report: Option<std::result::Result<VerifiedReport, String>>, // of the last proof verified
+}
+
+struct BlockTxList<'a> {
+    txids: Option<&'a Option<Vec<BlockTx>>>, // None when collapsed, Some(None) while loading
+    show_all: bool,
+}
+
+// Rows of an expanded block list, capped at BLOCK_TX_ROWS unless everything was asked for
+fn block_tx_rows(tx_count: usize, show_all: bool) -> usize {
+    if show_all { tx_count } else { tx_count.min(BLOCK_TX_ROWS) }
+}
+
+fn render_block_transactions(ui: &mut egui::Ui, block: &BlockSummary, list: BlockTxList) -> Option<BlockAction> {
+    let mut action = None;
+    let response = egui::CollapsingHeader::new(format!("Transactions ({})", block.tx_count))
+        .id_salt(("block_transactions", &block.hash))
+        .show(ui, |ui| {
+            let txids = match list.txids {
+                Some(Some(txids)) => txids,
+                Some(None) => {
+                    ui.spinner();
+                    return;
+                }
+                None => {
+                    ui.spinner();
+                    action = Some(BlockAction::Load);
+                    return;
+                }
+            };
+            let rows = block_tx_rows(txids.len(), list.show_all);
+            let row_height = ui.text_style_height(&egui::TextStyle::Body);
+            egui::ScrollArea::vertical()
+                .id_salt(("block_transactions_rows", &block.hash))
+                .max_height(BLOCK_TX_LIST_HEIGHT)
+                .show_rows(ui, row_height, rows, |ui, visible| {
+                    for (txid, memo) in &txids[visible] {
+                        let text = match memo {
+                            Some(memo) => format!("Tx ID: {}  Memo: {}", txid, memo),
+                            None => format!("Tx ID: {}", txid),
+                        };
+                        ui.add(egui::Label::new(text).sense(egui::Sense::click()))
+                            .on_hover_text("Right-click to copy the raw transaction")
+                            .context_menu(|ui| {
+                                if ui.button("Copy raw tx").clicked() {
+                                    action = Some(BlockAction::CopyRawTx(txid.clone()));
+                                    ui.close_menu();
+                                }
+                            });
+                    }
+                });
+            if rows < txids.len() && ui.button(format!("Show all {} transactions", txids.len())).clicked() {
+                action = Some(BlockAction::ShowAll);
+            }
+        });
+    if response.body_returned.is_none() && list.txids.is_some() {
+        action = Some(BlockAction::Collapsed);
+    }
+    action
+}
+
+// Collapsible list of a wallet's transactions, counterparties named from `book` and our
+// wallets' labels (`own`)
+fn render_wallet_history(
+    ui: &mut egui::Ui,
+    address: &str,
+    history: Option<&Vec<HistoryEntry>>,
+    book: &AddressBook,
+    own: &HashMap<String, String>,
+) -> Option<HistoryAction> {
+    let history = history.map_or(&[][..], |h| &h[..]);
+    let mut clicked = None;
+    egui::CollapsingHeader::new(format!("History ({})", history.len()))
+        .id_salt(("wallet_history", address))
+        .show(ui, |ui| {
+            if history.is_empty() {
+                ui.label("No transactions yet.");
+            }
+            for entry in history.iter().take(WALLET_HISTORY_ROWS) {
+                ui.horizontal(|ui| {
+                    ui.label(format!("#{}", entry.height));
+                    let (label, color) = match entry.direction {
+                        Direction::Mined => ("Mined", egui::Color32::from_rgb(50, 150, 70)),
+                        Direction::Received => ("Received", egui::Color32::from_rgb(50, 150, 70)),
+                        Direction::Sent => ("Sent", egui::Color32::from_rgb(194, 42, 25)),
+                        Direction::SelfTransfer => ("To self", egui::Color32::GRAY),
+                    };
+                    ui.label(egui::RichText::new(format!("{} {:+}", label, entry.net_amount)).color(color));
+                    for counterparty in &entry.counterparties {
+                        if counterparty == wallet_history::BURN_COUNTERPARTY {
+                            ui.label(counterparty);
+                        } else if counterparty_link(ui, counterparty, address_book::resolve(counterparty, book, own)) {
+                            clicked = Some(HistoryAction::Label(counterparty.clone()));
+                        }
+                    }
+                    if ui.link(egui::RichText::new(&entry.txid).monospace()).clicked() {
+                        clicked = Some(HistoryAction::OpenTx(entry.txid.clone()));
+                    }
+                });
+            }
+            if history.len() > WALLET_HISTORY_ROWS {
+                ui.label(format!("… and {} older transactions", history.len() - WALLET_HISTORY_ROWS));
+            }
+        });
+    clicked
+}
+
+// One line of totals above the block list
+fn render_chain_stats(ui: &mut egui::Ui, stats: &ChainStats) {
+    ui.horizontal_wrapped(|ui| {
+        let interval = stats.avg_block_interval.map_or(String::from("–"), clock::format_age);
+        for (label, value) in [
+            ("Blocks", stats.blocks.to_string()),
+            ("Transactions", stats.transactions.to_string()),
+            ("Coins issued", stats.coins_issued.to_string()),
+            ("Supply", format!("{} of {}", stats.circulating_supply, circulating_supply(i32::MAX))),
+            ("UTXOs", stats.utxos.to_string()),
+            ("Avg. block interval", interval), // over the last STATS_INTERVAL_BLOCKS
+            ("Difficulty", format!("{:.2}", stats.difficulty)),
+        ] {
+            ui.label(egui::RichText::new(label).weak());
+            ui.strong(value);
+            ui.add_space(12.0);
+        }
+    });
+}
+
+// Blocks mined to the address and what they paid, for wallets that received coinbases
+fn render_mining_income(ui: &mut egui::Ui, address: &str, history: &[HistoryEntry]) {
+    let Some(income) = MiningIncome::from_history(history, 0) else {
+        return;
+    };
+    let now = clock::now_millis();
+    let week = MiningIncome::from_history(history, now.saturating_sub(7 * 24 * 3600 * 1000)).unwrap_or_default();
+    egui::CollapsingHeader::new(format!("Mining income ({})", income.total()))
+        .id_salt(("mining_income", address))
+        .show(ui, |ui| {
+            egui::Grid::new(("mining_income_grid", address)).num_columns(3).show(ui, |ui| {
+                ui.label("");
+                ui.strong("All time");
+                ui.strong("Last 7 days");
+                ui.end_row();
+                for (label, all, recent) in [
+                    ("Blocks mined", income.blocks as i64, week.blocks as i64),
+                    ("Subsidy", income.subsidy, week.subsidy),
+                    ("Fees", income.fees, week.fees),
+                ] {
+                    ui.label(label);
+                    ui.label(all.to_string());
+                    ui.label(recent.to_string());
+                    ui.end_row();
                 }
+            });
+            if let Some(at) = income.last_reward {
+                ui.label(format!("Last reward {} ago", clock::format_age(clock::tip_age(at, now))));
             }
+        });
+}
+
+// A counterparty by its label, or its address when nothing labels it. The address is shown
+// on hover and copied on click. Returns true when "Label this address…" was picked from the
+// context menu
+fn counterparty_link(ui: &mut egui::Ui, address: &str, counterparty: Counterparty) -> bool {
+    let text = match counterparty {
+        Counterparty::Own(label) => egui::RichText::new(label).italics(),
+        Counterparty::Contact(label) => egui::RichText::new(label).strong(),
+        Counterparty::Unknown => egui::RichText::new(address),
+    };
+    let response = ui.add(egui::Label::new(text).sense(egui::Sense::click()))
+        .on_hover_text(format!("{}\nClick to copy, right-click to label", address));
+    if response.clicked() {
+        copy_to_clipboard(ui.ctx(), address, Some(&response));
+    }
+    let mut label = false;
+    response.context_menu(|ui| {
+        if ui.button("Label this address…").clicked() {
+            label = true;
+            ui.close_menu();
+        }
+    });
+    label
+}
+
+// "[🦊 brave-otter] 1Abc..." for notifications, which can't show the chip
+fn wallet_label(address: &str) -> String {
+    match WalletTag::from_address(address) {
+        Some(tag) => format!("[{}] {}", tag, address),
+        None => address.to_string(),
+    }
+}
+
+// The address's tag on its colored chip followed by `text`, for dropdowns and lists
+fn tagged_text(address: &str, text: &str) -> egui::text::LayoutJob {
+    let mut job = egui::text::LayoutJob::default();
+    if let Some(tag) = WalletTag::from_address(address) {
+        let (r, g, b) = tag.color;
+        job.append(&format!(" {} ", tag), 0.0, egui::TextFormat {
+            color: egui::Color32::WHITE,
+            background: egui::Color32::from_rgb(r, g, b),
+            ..Default::default()
+        });
+    }
+    job.append(text, 6.0, egui::TextFormat { color: egui::Color32::GRAY, ..Default::default() });
+    job
+}
+
+// "● ok" colored by status, the problems next to it and every check on hover
+fn health_indicator(ui: &mut egui::Ui, report: &HealthReport) {
+    let color = match report.status() {
+        HealthStatus::Ok => egui::Color32::GREEN,
+        HealthStatus::Degraded => egui::Color32::YELLOW,
+        HealthStatus::Failing => egui::Color32::RED,
+    };
+    let problems: Vec<String> = report.problems().iter().map(|c| format!("{}: {}", c.name, c.detail)).collect();
+    let text = if problems.is_empty() {
+        format!("● {}", report.status())
+    } else {
+        format!("● {} ({})", report.status(), problems.join(", "))
+    };
+    ui.label(egui::RichText::new(text).color(color))
+        .on_hover_text(report.to_string());
+}
+
+// Red for misbehaving peers, orange for unanswered connections, yellow when slow to connect
+fn peer_status_color(peer: &KnownNode) -> egui::Color32 {
+    if peer.misbehavior_score() > 0 {
+        egui::Color32::RED
+    } else if peer.no_response_counter() > 0 {
+        egui::Color32::from_rgb(255, 140, 0)
+    } else if peer.latency().is_some_and(|latency| latency > SLOW_PEER_LATENCY) {
+        egui::Color32::YELLOW
+    } else {
+        egui::Color32::GREEN
+    }
+}
+
+// Progress callback of chain file exports and imports. Messages only go out when the
+// percentage changes, and are dropped rather than waited for when the channel is full
+/// The clipboard of the operating system
+trait SystemClipboard {
+    fn set_text(&mut self, text: &str) -> std::result::Result<(), String>;
+}
+
+struct OsClipboard;
+
+impl SystemClipboard for OsClipboard {
+    fn set_text(&mut self, text: &str) -> std::result::Result<(), String> {
+        arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.set_text(text))
+            .map_err(|e| e.to_string())
+    }
+}
+
+const COPY_FEEDBACK_ID: &str = "copy_feedback";
+
+// What the last copy showed, kept in the context's temporary data until rendered
+#[derive(Clone)]
+enum CopyFeedback {
+    Copied { layer: egui::LayerId, widget: egui::Id, rect: egui::Rect, until: f64 },
+    Failed(String),
+}
+
+/// Copies `text` and shows "Copied!" at `anchor`, the widget clicked. Without an anchor the
+/// caller says it was copied. Returns false when it couldn't be, a notification explains why
+fn copy_to_clipboard(ctx: &egui::Context, text: &str, anchor: Option<&egui::Response>) -> bool {
+    let result = copy_text(ctx, &mut OsClipboard, text);
+    let feedback = match (&result, anchor) {
+        (Err(err), _) => CopyFeedback::Failed(err.clone()),
+        (Ok(()), Some(anchor)) => CopyFeedback::Copied {
+            layer: anchor.layer_id,
+            widget: anchor.id,
+            rect: anchor.rect,
+            until: ctx.input(|i| i.time) + COPIED_TOOLTIP_SECS,
+        },
+        (Ok(()), None) => return true,
+    };
+    ctx.data_mut(|d| d.insert_temp(egui::Id::new(COPY_FEEDBACK_ID), feedback));
+    result.is_ok()
+}
+
+// egui hands copied text to the window system, which drops it on some Linux (Wayland)
+// setups without a word, so it's set on `clipboard` too. That one reports failures
+fn copy_text(ctx: &egui::Context, clipboard: &mut dyn SystemClipboard, text: &str) -> std::result::Result<(), String> {
+    ctx.output_mut(|o| o.copied_text = text.to_string());
+    clipboard.set_text(text)
+}
+
+fn chain_file_progress(sender: mpsc::Sender<TaskMessage>) -> impl FnMut(usize, usize) + Send {
+    let mut last_percent = None;
+    move |done, total| {
+        let percent = done * 100 / total.max(1);
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            let _ = sender.try_send(TaskMessage::ChainFileProgress(done, total));
+        }
+    }
+}
+
+// "2 of 6 confirmations" as a small progress bar
+fn confirmation_bar(ui: &mut egui::Ui, confirmations: u32, target: u32) {
+    ui.add(
+        egui::ProgressBar::new(confirmations as f32 / target as f32)
+            .desired_width(140.0)
+            .text(format!("{} of {} confirmations", confirmations, target)),
+    );
+}
+
+fn wallet_action_button(ui: &mut egui::Ui, action: WalletAction) -> bool {
+    match action {
+        WalletAction::Receive => ui.button("Receive").clicked(),
+        WalletAction::Send => ui.button("Send").clicked(),
+        WalletAction::Export => ui.button("Export Wallet").clicked(),
+        WalletAction::Delete => ui.scope(|ui| {
+            ui.style_mut().visuals.widgets.inactive.weak_bg_fill = egui::Color32::from_rgb(194, 42, 25);
+            ui.style_mut().visuals.widgets.active.weak_bg_fill = egui::Color32::from_rgb(194, 42, 25);
+            ui.style_mut().visuals.widgets.hovered.weak_bg_fill = egui::Color32::from_rgb(217, 47, 28);
+            ui.button(egui::RichText::new("Delete Wallet")).clicked()
+        }).inner,
+    }
+}
+
+// A labelled form field: side by side, or the label above the field in compact mode
+fn form_row(ui: &mut egui::Ui, layout: LayoutMode, label: &str, add_field: impl FnOnce(&mut egui::Ui)) {
+    match layout {
+        LayoutMode::Compact => {
+            ui.label(label);
+            ui.horizontal(add_field);
+        }
+        LayoutMode::Regular => {
+            ui.horizontal(|ui| {
+                ui.label(label);
+                add_field(ui);
+            });
         }
     }
 }
 
+// Tab label with an optional badge drawn as a small superscript
+fn tab_label(style: &egui::Style, label: &str, badge: Option<String>) -> egui::text::LayoutJob {
+    let mut job = egui::text::LayoutJob::default();
+    egui::RichText::new(label).size(16.0)
+        .append_to(&mut job, style, egui::FontSelection::Default, egui::Align::Center);
+    if let Some(badge) = badge {
+        egui::RichText::new(format!(" {}", badge)).size(10.0).strong().color(egui::Color32::LIGHT_GREEN)
+            .append_to(&mut job, style, egui::FontSelection::Default, egui::Align::TOP);
+    }
+    job
+}
+
 fn convert_timestamp(timestamp: u128) -> String {
     let secs = (timestamp / 1000) as i64; // Convert milliseconds to seconds
-    let naive_datetime = NaiveDateTime::from_timestamp_opt(secs, 0)
-        .unwrap_or_else(|| Utc::now().naive_utc());
-    
-    // Convert NaiveDateTime to DateTime<Utc>
-    let datetime: DateTime<Utc> = DateTime::from_utc(naive_datetime, Utc);
+    let datetime: DateTime<Utc> = DateTime::from_timestamp(secs, 0)
+        .unwrap_or_else(Utc::now);
     datetime.format("%d-%m-%Y %H:%M:%S").to_string()
 }
 
+// HH:MM of a timestamp in ms since the epoch
+fn format_time_of_day(timestamp: u128) -> String {
+    let datetime: DateTime<Utc> = DateTime::from_timestamp((timestamp / 1000) as i64, 0)
+        .unwrap_or_else(Utc::now);
+    datetime.format("%H:%M").to_string()
+}
+
 async fn get_public_ip() -> Result<String> {
     let response = reqwest::get("https://ipinfo.io/ip").await?.text().await?;
     Ok(response)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::blockchain::GENESIS_ADDRESS;
+    use blockchain::block::INITIAL_TARGET;
+
+    #[test]
+    fn test_failed_sweep_keeps_wallet() {
+        let mut app = MyApp::default();
+        let address = app.bc_module.wallets.create_wallet();
+        app.ui_state.sweep_in_progress = Some(address.clone());
+
+        app.handle_sweep_finished(address.clone(), Err("Not Enough balance".to_string()));
+
+        assert!(app.bc_module.wallets.get_wallet(&address).is_some());
+        assert!(app.ui_state.sweep_in_progress.is_none());
+        assert!(app.notif_module.notifications.iter().any(|n| n.message.contains("was not deleted")));
+    }
+
+    #[test]
+    fn test_export_includes_change_wallets() {
+        let mut app = MyApp::default();
+        let owner = app.bc_module.wallets.create_wallet();
+        let change = Wallet::new_change(&owner);
+        app.bc_module.wallets.insert(&change.get_address(), change.clone());
+        let dir = std::env::temp_dir().join(format!("blockjain-export-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let files = app.export_wallet_to(&dir, &owner).unwrap();
+        let imported: Vec<String> = files.iter()
+            .map(|file| wallet_format::import(&std::fs::read(file).unwrap(), None).unwrap().get_address())
+            .collect();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(imported, vec![owner, change.get_address()]);
+    }
+
+    #[test]
+    fn test_sent_batch_maps_its_payments_to_the_txid() {
+        let mut app = MyApp::default();
+        let from = app.bc_module.wallets.create_wallet();
+        let to = app.bc_module.wallets.create_wallet();
+        app.ui_state.outbox.add(&from, &to, 3, 0);
+        app.ui_state.outbox.add(&from, &to, 4, 0);
+        let statuses = |app: &MyApp| app.ui_state.outbox.payments().iter().map(|p| p.status.clone()).collect::<Vec<_>>();
+
+        // A failed send leaves the payments queued for the next batch
+        let ids: Vec<u64> = app.ui_state.outbox.take_batch(&from).iter().map(|p| p.id).collect();
+        app.handle_batch_sent(ids, Err(String::from("Not Enough balance")));
+        assert_eq!(statuses(&app), vec![PaymentStatus::Queued, PaymentStatus::Queued]);
+
+        let ids: Vec<u64> = app.ui_state.outbox.take_batch(&from).iter().map(|p| p.id).collect();
+        app.handle_batch_sent(ids, Ok(String::from("batchtxid")));
+        assert_eq!(statuses(&app), vec![PaymentStatus::Sent(String::from("batchtxid")); 2]);
+        assert!(app.ui_state.pending_txids.contains(&String::from("batchtxid")));
+    }
+
+    // Remembers what it was given, or fails like a session without a clipboard
+    struct MockClipboard {
+        available: bool,
+        text: Option<String>,
+    }
+
+    impl SystemClipboard for MockClipboard {
+        fn set_text(&mut self, text: &str) -> std::result::Result<(), String> {
+            if !self.available {
+                return Err(String::from("no clipboard in this session"));
+            }
+            self.text = Some(text.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_falls_back_to_the_system_clipboard() {
+        let ctx = egui::Context::default();
+        let mut clipboard = MockClipboard { available: true, text: None };
+        assert_eq!(copy_text(&ctx, &mut clipboard, "abc"), Ok(()));
+        assert_eq!(clipboard.text.as_deref(), Some("abc"));
+        assert_eq!(ctx.output(|o| o.copied_text.clone()), "abc");
+
+        // Without one the failure reaches the user as a notification
+        let mut missing = MockClipboard { available: false, text: None };
+        let err = copy_text(&ctx, &mut missing, "def").unwrap_err();
+        assert_eq!(ctx.output(|o| o.copied_text.clone()), "def");
+        let mut app = MyApp::default();
+        ctx.data_mut(|d| d.insert_temp(egui::Id::new(COPY_FEEDBACK_ID), CopyFeedback::Failed(err)));
+        app.render_copy_feedback(&ctx);
+        assert!(app.notif_module.notifications.iter().any(|n| n.message.contains("no clipboard in this session")));
+        assert!(ctx.data(|d| d.get_temp::<CopyFeedback>(egui::Id::new(COPY_FEEDBACK_ID))).is_none());
+    }
+
+    #[test]
+    fn test_layout_mode_threshold() {
+        // The 800x400 minimum window is compact
+        assert_eq!(LayoutMode::from_width(800.0), LayoutMode::Compact);
+        assert_eq!(LayoutMode::from_width(COMPACT_WIDTH - 0.5), LayoutMode::Compact);
+        assert_eq!(LayoutMode::from_width(COMPACT_WIDTH), LayoutMode::Regular);
+        assert_eq!(LayoutMode::from_width(1920.0), LayoutMode::Regular);
+
+        let compact = LayoutMode::Compact;
+        assert!(compact.notification_size().x < LayoutMode::Regular.notification_size().x);
+        assert!(compact.max_notifications() < LayoutMode::Regular.max_notifications());
+    }
+
+    #[test]
+    fn test_disk_warning_is_replaced_as_space_changes() {
+        let mut app = MyApp::default();
+        let stores = vec![StoreUsage { name: String::from("Blocks"), bytes: 3 * 1024 * 1024 }];
+        let warnings = |app: &MyApp| app.notif_module.notifications.iter().filter(|n| n.warning).map(|n| n.message.clone()).collect::<Vec<_>>();
+
+        app.handle_disk_space(DiskLevel::Low, 500 * 1024 * 1024, &stores);
+        let low = warnings(&app);
+        assert_eq!(low.len(), 1);
+        assert!(low[0].contains("Blocks"), "{}", low[0]);
+
+        app.handle_disk_space(DiskLevel::Full, 10 * 1024 * 1024, &stores);
+        let full = warnings(&app);
+        assert_eq!(full.len(), 1);
+        assert!(full[0].contains("paused"), "{}", full[0]);
+
+        app.handle_disk_space(DiskLevel::Ok, 5000 * 1024 * 1024, &stores);
+        assert!(warnings(&app).is_empty());
+        assert_eq!(app.ui_state.disk_warning, None);
+    }
+
+    #[test]
+    fn test_new_blocks_badge() {
+        let mut app = MyApp::default();
+
+        // Blocks arriving while the Blockchain tab is open aren't counted
+        app.count_new_blocks(1);
+        assert_eq!(app.tab_badge(&Tab::Blockchain), None);
+
+        app.open_tab(Tab::Transactions);
+        app.count_new_blocks(1);
+        app.count_new_blocks(1);
+        assert_eq!(app.tab_badge(&Tab::Blockchain), Some("+2".to_string()));
+
+        // Switching to another tab keeps the count, visiting the Blockchain tab clears it
+        app.open_tab(Tab::Peers);
+        assert_eq!(app.ui_state.new_blocks_since_view, 2);
+        app.open_tab(Tab::Blockchain);
+        assert_eq!(app.tab_badge(&Tab::Blockchain), None);
+        app.count_new_blocks(3);
+        assert_eq!(app.ui_state.new_blocks_since_view, 0);
+    }
+
+    #[test]
+    fn test_unspendable_receiver_needs_confirmation() {
+        let mut app = MyApp::default();
+        let address = app.bc_module.wallets.create_wallet();
+        app.ui_state.selected_wallet = Some(address.clone());
+        app.ui_state.tx_amount = 5;
+
+        app.ui_state.receiver_address = address;
+        assert!(app.valid_tx_fields().is_ok());
+
+        let all_zero = address::wallet_address(&[0; 20]);
+        for unspendable in [String::from(GENESIS_ADDRESS), all_zero] {
+            app.ui_state.receiver_address = unspendable;
+            app.ui_state.confirm_burn = false;
+            assert!(app.valid_tx_fields().unwrap_err().to_string().contains("burn"));
+
+            app.ui_state.confirm_burn = true;
+            assert!(app.valid_tx_fields().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_block_list_keeps_summaries_and_reads_expanded_blocks() {
+        let mut app = MyApp::default();
+        let txs: Vec<Transaction> = (0..3)
+            .map(|i| Transaction::new_coinbase(GENESIS_ADDRESS.to_string(), format!("fixture {}", i), 1).unwrap())
+            .collect();
+        let block = Block::new_block(txs, String::new(), 1, INITIAL_TARGET).unwrap();
+        app.handle_blocks_loaded(vec![block.clone()]);
+        assert_eq!(app.ui_state.blocks, vec![BlockSummary::of(&block)]);
+        assert_eq!(app.ui_state.blocks[0].tx_count, 3);
+
+        // A block with 5,000 transactions lists BLOCK_TX_ROWS of them until "show all"
+        let hash = block.get_hash();
+        let txids: Vec<BlockTx> = (0..5000).map(|i| (format!("{:064x}", i), (i % 2 == 0).then(|| format!("invoice {}", i)))).collect();
+        assert_eq!(block_tx_rows(txids.len(), false), BLOCK_TX_ROWS);
+        assert_eq!(block_tx_rows(txids.len(), true), 5000);
+        assert_eq!(block_tx_rows(3, false), 3);
+
+        // Ids arriving for an expanded block are kept, for a block collapsed meanwhile dropped
+        app.ui_state.block_txids.insert(hash.clone(), None);
+        app.handle_block_transactions_loaded(hash.clone(), Ok(txids.clone()));
+        assert_eq!(app.ui_state.block_txids.get(&hash), Some(&Some(txids.clone())));
+        app.ui_state.block_txids.clear();
+        app.handle_block_transactions_loaded(hash.clone(), Ok(txids));
+        assert!(app.ui_state.block_txids.is_empty());
+    }
+
+    #[test]
+    fn test_load_more_and_search_results() {
+        let mut app = MyApp::default();
+        let block = |height: i32| {
+            let coinbase = Transaction::new_coinbase(GENESIS_ADDRESS.to_string(), format!("block {}", height), height).unwrap();
+            Block::new_block(vec![coinbase], String::new(), height, INITIAL_TARGET).unwrap()
+        };
+        let heights = |app: &MyApp| app.ui_state.blocks.iter().map(|b| b.height).collect::<Vec<_>>();
+
+        app.handle_blocks_loaded(vec![block(3), block(2)]);
+        app.handle_older_blocks_loaded(vec![block(2), block(1)]);
+        assert_eq!(heights(&app), vec![3, 2, 1]);
+        assert!(!app.ui_state.oldest_block_loaded);
+        app.handle_older_blocks_loaded(vec![block(0)]);
+        assert_eq!(heights(&app), vec![3, 2, 1, 0]);
+        assert!(app.ui_state.oldest_block_loaded);
+
+        // Only the answer to what's in the search box is shown
+        app.ui_state.block_search_query = String::from("12");
+        app.handle_block_search_finished(String::from("1"), Some(BlockSummary::of(&block(1))));
+        assert!(app.ui_state.block_search_result.is_none());
+        app.handle_block_search_finished(String::from("12"), Some(BlockSummary::of(&block(12))));
+        assert_eq!(app.ui_state.block_search_result.as_ref().map(|b| b.height), Some(12));
+    }
+
+    #[test]
+    fn test_pasted_payment_request_fills_the_form() {
+        let mut app = MyApp::default();
+        let address = app.bc_module.wallets.create_wallet();
+        let request = PaymentRequest { address: address.clone(), amount: 7, memo: String::from("Invoice42") };
+
+        app.ui_state.receiver_address = request.to_uri();
+        app.apply_payment_request();
+        assert_eq!(app.ui_state.receiver_address, address);
+        assert_eq!(app.ui_state.tx_amount, 7);
+        assert!(app.ui_state.payment_request_banner.as_ref().unwrap().contains("Invoice42"));
+
+        // A broken one stays in the field to be fixed
+        app.clear_transaction_form();
+        app.ui_state.receiver_address = format!("blockjain:{}?amount=1.5", address);
+        app.apply_payment_request();
+        assert_eq!(app.ui_state.tx_amount, 0);
+        assert!(app.ui_state.payment_request_banner.is_none());
+        assert!(app.notif_module.notifications.iter().any(|n| n.message.contains("payment request")));
+    }
+
+    #[test]
+    fn test_duplicate_actions_are_ignored() {
+        let mut app = MyApp::default();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+
+        // Double click: the second trigger is dropped while the first is pending
+        assert!(app.spawn_action(ActionKind::SendTx, async move {
+            let _ = released.await;
+            TaskMessage::TransactionSent(Ok(String::from("txid")))
+        }));
+        assert!(!app.spawn_action(ActionKind::SendTx, async { panic!("duplicate action was spawned") }));
+        assert!(app.actions_in_flight.contains(&ActionKind::SendTx));
+
+        // Other kinds aren't blocked
+        assert!(app.spawn_action(ActionKind::AddPeer, async { TaskMessage::Error(String::from("unreachable")) }));
+
+        release.send(()).unwrap();
+        let ctx = egui::Context::default();
+        let started = std::time::Instant::now();
+        while !app.actions_in_flight.is_empty() {
+            assert!(started.elapsed() < std::time::Duration::from_secs(2), "actions never finished");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            app.render_channel_messages(&ctx);
+        }
+
+        // Completion handled the result and allows the action again
+        assert_eq!(app.ui_state.pending_txids, vec![String::from("txid")]);
+        assert!(app.spawn_action(ActionKind::SendTx, async { TaskMessage::ConsoleOutput(String::new()) }));
+    }
+
+    #[test]
+    fn test_combined_wallets_fund_the_transaction() {
+        let mut app = MyApp::default();
+        let first = app.bc_module.wallets.create_wallet();
+        let second = app.bc_module.wallets.create_wallet();
+        app.ui_state.selected_wallet = Some(first.clone());
+        app.ui_state.combined_wallets = vec![second.clone(), first.clone(), String::from("not a wallet")];
+        app.ui_state.receiver_address = second.clone();
+        app.ui_state.tx_amount = 5;
+
+        // Only the selected wallet until combining is switched on
+        assert_eq!(app.funding_addresses(), vec![first.clone()]);
+        app.ui_state.combine_wallets = true;
+        assert_eq!(app.funding_addresses(), vec![first.clone(), second.clone()]);
+
+        let (_, wallets, _, _) = app.valid_tx_fields().unwrap();
+        let addresses: Vec<String> = wallets.iter().map(|w| w.get_address()).collect();
+        assert_eq!(addresses, vec![first, second]);
+    }
+
+    #[test]
+    fn test_incoming_payment_confirms() {
+        let mut app = MyApp::default();
+        app.ui_state.incoming = WatchList::new(2);
+        let address = app.bc_module.wallets.create_wallet();
+        let reward = |to: &str, height: i32| {
+            let coinbase = Transaction::new_coinbase(to.to_string(), format!("reward {}", height), height).unwrap();
+            Block::new_block(vec![coinbase], String::new(), height, INITIAL_TARGET).unwrap()
+        };
+
+        app.handle_blocks_loaded(vec![reward(&address, 1)]);
+        let payments = app.ui_state.incoming.payments();
+        assert_eq!(payments.len(), 1);
+        assert_eq!((payments[0].amount, payments[0].confirmations), (10, 1));
+
+        // The next block reaches the target
+        app.handle_blocks_loaded(vec![reward(GENESIS_ADDRESS, 2)]);
+        assert!(app.ui_state.incoming.payments().is_empty());
+        assert!(app.notif_module.notifications.iter().any(|n| n.message.contains("is confirmed (2 confirmations)")));
+    }
+
+    #[test]
+    fn test_no_actions_after_shutdown() {
+        let mut app = MyApp::default();
+        assert!(app.spawn_action(ActionKind::AddPeer, async {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            TaskMessage::PeerAdded(String::from("late"))
+        }));
+
+        assert!(RUNTIME.block_on(app.tasks.shutdown(SHUTDOWN_TIMEOUT)));
+
+        // A click during shutdown doesn't leave its button stuck in the pending state
+        assert!(!app.spawn_action(ActionKind::SendTx, async { panic!("spawned after shutdown") }));
+        assert!(!app.actions_in_flight.contains(&ActionKind::SendTx));
+    }
+
+    #[test]
+    fn test_mock_ui_starts_populated() {
+        let mut app = RUNTIME.block_on(MyApp::with_mock_backend()).unwrap();
+        assert_eq!(app.ui_state.blocks.first().map(|b| b.height), Some(MOCK_BLOCKS as i32 - 1));
+        assert!(app.ui_state.chain_stats.is_some());
+        assert_eq!(app.ui_state.connected_peers_displayed.len(), 4);
+        assert_eq!(app.bc_module.balances.len(), app.bc_module.wallets.get_all_address().len());
+        assert!(app.total_balance() > 0);
+
+        // Actions needing a real node are refused instead of touching the disk
+        assert!(!app.spawn_action(ActionKind::SendTx, async { panic!("sent with the mock backend") }));
+        assert!(app.spawn_action(ActionKind::AddPeer, async { TaskMessage::PeerAdded(String::from("10.0.0.9:8335")) }));
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::errors::Result;
+
+/*
+    Audit log
+
+    Destructive actions (deleting a wallet, recreating or rolling back the chain, importing a
+    chain file over it) leave an entry in their own sled database at AUDIT_LOG_PATH, apart
+    from the block and wallet databases they may wipe. Entries are appended under big-endian
+    ids from `Db::generate_id`, like the peer history, and never removed.
+
+    Each entry holds the hash of the one before it and a hash of itself over both, so
+    editing, removing or reordering entries breaks the chain from there on, see `verify`.
+    Writing the log never holds up the action it records: a failure is only printed.
+*/
+
+pub const AUDIT_LOG_PATH: &str = "audit";
+
+// The hash before the first entry
+const FIRST_PREV_HASH: &str = "";
+
+// Logs by path, opened on first use and kept open: sled holds on to its lock for a moment
+// after a database is dropped, reopening right away can fail. Appends read the last hash
+// and write after it under this lock, one at a time
+static OPEN_LOGS: Lazy<Mutex<HashMap<String, sled::Db>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AuditAction {
+    DeleteWallet,
+    RecreateChain,
+    Rollback,
+    ImportChain,
+}
+
+impl AuditAction {
+    pub const ALL: [AuditAction; 4] = [AuditAction::DeleteWallet, AuditAction::RecreateChain, AuditAction::Rollback, AuditAction::ImportChain];
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditAction::DeleteWallet => write!(f, "Delete wallet"),
+            AuditAction::RecreateChain => write!(f, "Recreate chain"),
+            AuditAction::Rollback => write!(f, "Rollback"),
+            AuditAction::ImportChain => write!(f, "Import chain"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOutcome {
+    Done,
+    Failed(String),
+}
+
+impl<T> From<&Result<T>> for AuditOutcome {
+    fn from(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => AuditOutcome::Done,
+            Err(e) => AuditOutcome::Failed(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for AuditOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditOutcome::Done => write!(f, "Done"),
+            AuditOutcome::Failed(reason) => write!(f, "Failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: u128, // ms since the epoch
+    pub action: AuditAction,
+    pub params: Vec<(String, String)>, // name, value, addresses shortened
+    pub outcome: AuditOutcome,
+    pub prev_hash: String, // hash of the entry before, empty for the first
+    pub hash: String,      // hex sha256 of everything above
+}
+
+impl AuditEntry {
+    fn new(prev_hash: String, timestamp: u128, action: AuditAction, params: Vec<(String, String)>, outcome: AuditOutcome) -> Result<AuditEntry> {
+        let mut entry = AuditEntry { timestamp, action, params, outcome, prev_hash, hash: String::new() };
+        entry.hash = entry.compute_hash()?;
+        Ok(entry)
+    }
+
+    fn compute_hash(&self) -> Result<String> {
+        let data = bincode::serialize(&(&self.prev_hash, self.timestamp, self.action, &self.params, &self.outcome))?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        Ok(hasher.result_str())
+    }
+}
+
+/// The log at a path
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: String,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        AuditLog::with_path(AUDIT_LOG_PATH)
+    }
+}
+
+impl AuditLog {
+    pub fn with_path(path: &str) -> Self {
+        AuditLog { path: path.to_string() }
+    }
+
+    /// Appends an entry. Never fails, the action happened either way
+    pub fn record(&self, action: AuditAction, params: Vec<(String, String)>, outcome: AuditOutcome) {
+        if let Err(e) = self.append(action, params, outcome) {
+            println!("Failed to write the audit log entry for {}: {}", action, e);
+        }
+    }
+
+    fn append(&self, action: AuditAction, params: Vec<(String, String)>, outcome: AuditOutcome) -> Result<()> {
+        let mut logs = OPEN_LOGS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let db = self.open(&mut logs)?;
+        let prev_hash = match db.last()? {
+            Some((_, data)) => bincode::deserialize::<AuditEntry>(&data)?.hash,
+            None => String::from(FIRST_PREV_HASH),
+        };
+        let entry = AuditEntry::new(prev_hash, clock::now_millis(), action, params, outcome)?;
+        db.insert(db.generate_id()?.to_be_bytes(), bincode::serialize(&entry)?)?;
+        db.flush()?;
+        Ok(())
+    }
+
+    /// Every entry, oldest first
+    pub fn entries(&self) -> Result<Vec<AuditEntry>> {
+        let mut logs = OPEN_LOGS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let db = self.open(&mut logs)?;
+        let mut entries = Vec::new();
+        for kv in db.iter() {
+            let (_, data) = kv?;
+            entries.push(bincode::deserialize(&data)?);
+        }
+        Ok(entries)
+    }
+
+    fn open<'a>(&self, logs: &'a mut HashMap<String, sled::Db>) -> Result<&'a sled::Db> {
+        if !logs.contains_key(&self.path) {
+            logs.insert(self.path.clone(), sled::open(&self.path)?);
+        }
+        Ok(&logs[&self.path])
+    }
+}
+
+/// Index of the first entry that doesn't follow from the ones before it, None when the
+/// whole log checks out
+pub fn verify(entries: &[AuditEntry]) -> Option<usize> {
+    let mut prev_hash = FIRST_PREV_HASH;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.prev_hash != prev_hash || entry.compute_hash().ok().as_ref() != Some(&entry.hash) {
+            return Some(i);
+        }
+        prev_hash = &entry.hash;
+    }
+    None
+}
+
+/// An address cut down for the log, enough to tell wallets apart
+pub fn short_address(address: &str) -> String {
+    match address.char_indices().nth(10) {
+        Some((end, _)) => format!("{}…", &address[..end]),
+        None => address.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_hash_chained() {
+        let path = std::env::temp_dir().join(format!("blockjain-audit-{}", rand::random::<u64>()));
+        let log = AuditLog::with_path(path.to_str().unwrap());
+        log.record(AuditAction::DeleteWallet, vec![(String::from("address"), short_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2"))], AuditOutcome::Done);
+        log.record(AuditAction::Rollback, vec![(String::from("height"), String::from("7"))], AuditOutcome::Failed(String::from("syncing")));
+        log.record(AuditAction::ImportChain, Vec::new(), AuditOutcome::Done);
+
+        let entries = log.entries().unwrap();
+        assert_eq!(entries.iter().map(|e| e.action).collect::<Vec<_>>(), vec![AuditAction::DeleteWallet, AuditAction::Rollback, AuditAction::ImportChain]);
+        assert_eq!(entries[0].params[0].1, "1BvBMSEYst…");
+        assert_eq!(verify(&entries), None);
+
+        // Edited, removed and reordered entries
+        let mut edited = entries.clone();
+        edited[1].outcome = AuditOutcome::Done;
+        assert_eq!(verify(&edited), Some(1));
+        assert_eq!(verify(&[entries[0].clone(), entries[2].clone()]), Some(1));
+        assert_eq!(verify(&[entries[1].clone(), entries[0].clone()]), Some(0));
+
+        // A log that can't be written doesn't get in the way
+        AuditLog::with_path("/dev/null/audit").record(AuditAction::RecreateChain, Vec::new(), AuditOutcome::Done);
+        std::fs::remove_dir_all(path).ok();
+    }
+}
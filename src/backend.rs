@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use failure::format_err;
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
+
+use crate::address;
+use crate::block::{Block, INITIAL_TARGET, MAX_TARGET};
+use crate::blockchain::ChainStats;
+use crate::errors::Result;
+use crate::health::{HealthCheck, HealthReport, HealthStatus};
+use crate::maintenance::StoreReport;
+use crate::bandwidth::Throughput;
+use crate::peer_history::PeerHistoryEntry;
+use crate::protocol::Capabilities;
+use crate::server::{BroadcastReport, KnownNode, Server};
+use crate::settings::SETTINGS;
+use crate::transaction::{circulating_supply, Transaction};
+use crate::tx::{TXInput, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::wallet::{ChangeAddressProvider, SameAddress, Wallet, WalletMetadata, Wallets};
+
+/*
+    App backends
+
+    What the app reads from and tells the node, as traits: the chain (ChainView), the wallet
+    store (WalletStore) and the peer-to-peer side (NetworkControl). The node implements them
+    with the UTXO set, `Wallets` and the server. MockBackend implements them with a fixed
+    made up chain, wallets and peers and never touches the disk or the network, so the
+    views can be worked on with `--mock-ui` without a synced node.
+
+    Building transactions still needs the UTXO set itself and isn't covered, in mock mode
+    sends fail for lack of funds.
+*/
+
+/// Chain queries the app makes
+pub trait ChainView: Send + Sync {
+    fn best_height(&self) -> BoxFuture<'_, Result<i32>>;
+    /// Blocks from height `from` to `to`, both included, newest first
+    fn blocks_range(&self, from: i32, to: i32) -> BoxFuture<'_, Result<Vec<Block>>>;
+    fn pruned_height(&self) -> BoxFuture<'_, Result<Option<i32>>>;
+    fn stats(&self) -> BoxFuture<'_, Result<ChainStats>>;
+    /// Sum of the unspent outputs paying `address`
+    fn balance(&self, address: String) -> BoxFuture<'_, Result<i32>>;
+}
+
+/// The wallets the app shows and spends from
+pub trait WalletStore: Send + Sync {
+    /// The wallets shown, change wallets are under the wallet they hold change for
+    fn get_all_address(&self) -> Vec<String>;
+    fn get_wallet(&self, address: &str) -> Option<&Wallet>;
+    fn change_addresses(&self, address: &str) -> Vec<String>;
+    fn change_provider(&self) -> Box<dyn ChangeAddressProvider>;
+    /// Picks up the wallets the change provider created
+    fn load_new(&mut self) -> Result<()>;
+    /// A new wallet, kept until `save_all`
+    fn create_wallet(&mut self) -> String;
+    fn insert(&mut self, address: &str, wallet: Wallet);
+    fn delete_wallet(&mut self, address: &str) -> Result<()>;
+    fn save_all(&self) -> Result<()>;
+    /// Compacts the store behind the wallets, None when there's none
+    fn compact(&mut self) -> Result<Option<StoreReport>>;
+
+    /// The wallet at `address` followed by its change wallets, what sends from it spend
+    fn spending_wallets(&self, address: &str) -> Vec<Wallet> {
+        std::iter::once(address.to_string())
+            .chain(self.change_addresses(address))
+            .filter_map(|address| self.get_wallet(&address).cloned())
+            .collect()
+    }
+
+    /// The addresses of each wallet shown, the wallet's own first, in get_all_address order
+    fn address_groups(&self) -> Vec<Vec<String>> {
+        self.get_all_address().into_iter()
+            .map(|address| {
+                let change = self.change_addresses(&address);
+                std::iter::once(address).chain(change).collect()
+            })
+            .collect()
+    }
+}
+
+/// Peers, the mempool and broadcasting
+pub trait NetworkControl: Send + Sync {
+    fn known_nodes(&self) -> BoxFuture<'_, HashMap<String, KnownNode>>;
+    fn add_peer(&self, address: String) -> BoxFuture<'_, Result<()>>;
+    /// Adds the peer and opens the handshake with it
+    fn connect_peer(&self, address: String) -> BoxFuture<'_, Result<()>>;
+    fn disconnect_peer(&self, address: String) -> BoxFuture<'_, ()>;
+    fn send_transaction(&self, tx: Transaction) -> BoxFuture<'_, Result<BroadcastReport>>;
+    /// Sends a transaction that isn't ours once the mempool rules take it
+    fn broadcast_raw_transaction(&self, tx: Transaction) -> BoxFuture<'_, Result<BroadcastReport>>;
+    fn mempool_transactions(&self) -> BoxFuture<'_, Vec<Transaction>>;
+    /// Fee rate, coins per 1000 bytes, to be mined within `target_blocks`, see Server::estimate_fee
+    fn estimate_fee(&self, target_blocks: u32) -> BoxFuture<'_, Result<i64>>;
+    fn healthcheck(&self) -> BoxFuture<'_, HealthReport>;
+    fn peer_throughput(&self) -> BoxFuture<'_, HashMap<String, Throughput>>;
+    fn peer_history(&self) -> BoxFuture<'_, Result<Vec<PeerHistoryEntry>>>;
+}
+
+impl ChainView for RwLock<UTXOSet> {
+    fn best_height(&self) -> BoxFuture<'_, Result<i32>> {
+        Box::pin(async move { self.read().await.blockchain.read().await.get_best_height() })
+    }
+
+    fn blocks_range(&self, from: i32, to: i32) -> BoxFuture<'_, Result<Vec<Block>>> {
+        Box::pin(async move { self.read().await.blockchain.read().await.get_blocks_range(from, to) })
+    }
+
+    fn pruned_height(&self) -> BoxFuture<'_, Result<Option<i32>>> {
+        Box::pin(async move { self.read().await.blockchain.read().await.pruned_height() })
+    }
+
+    fn stats(&self) -> BoxFuture<'_, Result<ChainStats>> {
+        Box::pin(async move { self.read().await.blockchain.read().await.stats() })
+    }
+
+    fn balance(&self, address: String) -> BoxFuture<'_, Result<i32>> {
+        Box::pin(async move {
+            let balance = self.read().await.get_balance(&address)?;
+            i32::try_from(balance).map_err(|_| format_err!("The balance of {} doesn't fit the wallet view", address))
+        })
+    }
+}
+
+impl WalletStore for Wallets {
+    fn get_all_address(&self) -> Vec<String> {
+        Wallets::get_all_address(self)
+    }
+
+    fn get_wallet(&self, address: &str) -> Option<&Wallet> {
+        Wallets::get_wallet(self, address)
+    }
+
+    fn change_addresses(&self, address: &str) -> Vec<String> {
+        Wallets::change_addresses(self, address)
+    }
+
+    fn change_provider(&self) -> Box<dyn ChangeAddressProvider> {
+        Wallets::change_provider(self)
+    }
+
+    fn load_new(&mut self) -> Result<()> {
+        Wallets::load_new(self)
+    }
+
+    fn create_wallet(&mut self) -> String {
+        Wallets::create_wallet(self)
+    }
+
+    fn insert(&mut self, address: &str, wallet: Wallet) {
+        Wallets::insert(self, address, wallet)
+    }
+
+    fn delete_wallet(&mut self, address: &str) -> Result<()> {
+        Wallets::delete_wallet(self, address)
+    }
+
+    fn save_all(&self) -> Result<()> {
+        Wallets::save_all(self)
+    }
+
+    fn compact(&mut self) -> Result<Option<StoreReport>> {
+        Wallets::compact(self)
+    }
+}
+
+impl NetworkControl for RwLock<Server> {
+    fn known_nodes(&self) -> BoxFuture<'_, HashMap<String, KnownNode>> {
+        Box::pin(async move { self.read().await.get_known_nodes().await })
+    }
+
+    fn add_peer(&self, address: String) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.read().await.add_peer(address).await })
+    }
+
+    fn connect_peer(&self, address: String) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.read().await.connect_peer(&address).await })
+    }
+
+    fn disconnect_peer(&self, address: String) -> BoxFuture<'_, ()> {
+        Box::pin(async move { self.read().await.disconnect_peer(&address).await })
+    }
+
+    fn send_transaction(&self, tx: Transaction) -> BoxFuture<'_, Result<BroadcastReport>> {
+        Box::pin(async move { self.read().await.send_transaction(&tx).await })
+    }
+
+    fn broadcast_raw_transaction(&self, tx: Transaction) -> BoxFuture<'_, Result<BroadcastReport>> {
+        Box::pin(async move { self.read().await.broadcast_raw_transaction(&tx).await })
+    }
+
+    fn mempool_transactions(&self) -> BoxFuture<'_, Vec<Transaction>> {
+        Box::pin(async move { self.read().await.mempool_transactions().await })
+    }
+
+    fn estimate_fee(&self, target_blocks: u32) -> BoxFuture<'_, Result<i64>> {
+        Box::pin(async move { self.read().await.estimate_fee(target_blocks).await })
+    }
+
+    fn healthcheck(&self) -> BoxFuture<'_, HealthReport> {
+        Box::pin(async move { self.read().await.healthcheck().await })
+    }
+
+    fn peer_throughput(&self) -> BoxFuture<'_, HashMap<String, Throughput>> {
+        Box::pin(async move { self.read().await.peer_throughput().await })
+    }
+
+    fn peer_history(&self) -> BoxFuture<'_, Result<Vec<PeerHistoryEntry>>> {
+        Box::pin(async move { self.read().await.peer_history().await })
+    }
+}
+
+// Mock data
+
+pub const MOCK_BLOCKS: usize = 40;
+const MOCK_WALLETS: u8 = 4;
+const MOCK_GENESIS_TIME: u128 = 1_700_000_000_000;
+const MOCK_BLOCK_SPACING: u128 = 60_000;
+
+/// A made up node: `blocks` mined in turn by the mock wallets, every other block also moving
+/// coins between them, and a handful of peers. The same every time.
+pub struct MockBackend {
+    blocks: Vec<Block>, // oldest first
+    wallets: Vec<Wallet>,
+    peers: HashMap<String, KnownNode>,
+    sent: Mutex<Vec<Transaction>>, // what send_transaction was given, it stays in the mempool
+}
+
+impl MockBackend {
+    pub fn new(blocks: usize) -> Result<MockBackend> {
+        let wallets: Vec<Wallet> = (1..=MOCK_WALLETS).map(mock_wallet).collect();
+        let mut chain: Vec<Block> = Vec::new();
+        for height in 0..blocks as i32 {
+            let miner = &wallets[height as usize % wallets.len()];
+            let mut txs = vec![Transaction::new_coinbase(miner.get_address(), format!("Mock block {}", height), height)?];
+            // The previous miner pays the next wallet out of its reward
+            if let Some(parent) = chain.last().filter(|_| height % 2 == 0) {
+                let payer = &wallets[(height as usize - 1) % wallets.len()];
+                txs.push(mock_payment(payer, &parent.get_transactions()[0], &miner.get_address(), 4)?);
+            }
+            let prev_hash = chain.last().map(Block::get_hash).unwrap_or_default();
+            let timestamp = MOCK_GENESIS_TIME + height as u128 * MOCK_BLOCK_SPACING;
+            // The easiest target, mining them at startup stays quick
+            chain.push(Block::new_block_at(txs, prev_hash, height, MAX_TARGET, timestamp)?);
+        }
+
+        let peers = [
+            ("10.0.0.2:8335", Capabilities::TX_RECEIPTS.union(Capabilities::HEADERS_FIRST)),
+            ("10.0.0.3:8335", Capabilities::COMPACT_BLOCKS),
+            ("10.0.0.7:8335", Capabilities::TX_RECEIPTS),
+            ("192.168.1.20:8334", Capabilities::NONE),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (address, capabilities))| {
+            let last_seen = MOCK_GENESIS_TIME + blocks as u128 * MOCK_BLOCK_SPACING - i as u128 * 5_000;
+            (address.to_string(), KnownNode::handshaken(capabilities, last_seen))
+        })
+        .collect();
+
+        Ok(MockBackend { blocks: chain, wallets, peers, sent: Mutex::new(Vec::new()) })
+    }
+
+    /// The mock wallets, in a store that doesn't save
+    pub fn wallets(&self) -> MockWallets {
+        let mut store = Wallets::default();
+        for wallet in &self.wallets {
+            store.insert(&wallet.get_address(), wallet.clone());
+        }
+        MockWallets { wallets: store, next_seed: MOCK_WALLETS + 1 }
+    }
+
+    // Outputs no mock transaction spends
+    fn unspent(&self) -> Vec<&TXOutput> {
+        let txs: Vec<&Transaction> = self.blocks.iter().flat_map(|block| block.get_transactions()).collect();
+        let spent: Vec<(&str, i32)> = txs.iter()
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.vin.iter().map(|vin| (vin.txid.as_str(), vin.vout)))
+            .collect();
+        txs.iter()
+            .flat_map(|tx| tx.vout.iter().enumerate().map(move |(i, out)| (tx.id.as_str(), i as i32, out)))
+            .filter(|(txid, vout, _)| !spent.contains(&(*txid, *vout)))
+            .map(|(_, _, out)| out)
+            .collect()
+    }
+}
+
+impl ChainView for MockBackend {
+    fn best_height(&self) -> BoxFuture<'_, Result<i32>> {
+        Box::pin(async move { Ok(self.blocks.len() as i32 - 1) })
+    }
+
+    fn blocks_range(&self, from: i32, to: i32) -> BoxFuture<'_, Result<Vec<Block>>> {
+        Box::pin(async move {
+            Ok(self.blocks.iter().rev()
+                .filter(|block| (from..=to).contains(&block.get_height()))
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn pruned_height(&self) -> BoxFuture<'_, Result<Option<i32>>> {
+        Box::pin(async move { Ok(None) })
+    }
+
+    fn stats(&self) -> BoxFuture<'_, Result<ChainStats>> {
+        Box::pin(async move {
+            Ok(ChainStats {
+                blocks: self.blocks.len() as u64,
+                transactions: self.blocks.iter().map(|block| block.get_transactions().len() as u64).sum(),
+                coins_issued: self.blocks.iter().map(|block| block.get_transactions()[0].vout[0].value as i64).sum(),
+                circulating_supply: circulating_supply(self.blocks.len() as i32 - 1),
+                utxos: self.unspent().len() as u64,
+                avg_block_interval: (self.blocks.len() > 1).then(|| Duration::from_millis(MOCK_BLOCK_SPACING as u64)),
+                difficulty: INITIAL_TARGET as f64 / MAX_TARGET as f64,
+            })
+        })
+    }
+
+    fn balance(&self, address: String) -> BoxFuture<'_, Result<i32>> {
+        Box::pin(async move {
+            let pub_key_hash = address::address_to_hash(&address)?;
+            Ok(self.unspent().iter().filter(|out| out.pub_key_hash == pub_key_hash).map(|out| out.value).sum())
+        })
+    }
+}
+
+impl NetworkControl for MockBackend {
+    fn known_nodes(&self) -> BoxFuture<'_, HashMap<String, KnownNode>> {
+        Box::pin(async move { self.peers.clone() })
+    }
+
+    fn add_peer(&self, _address: String) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn connect_peer(&self, _address: String) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn disconnect_peer(&self, _address: String) -> BoxFuture<'_, ()> {
+        Box::pin(async move {})
+    }
+
+    fn send_transaction(&self, tx: Transaction) -> BoxFuture<'_, Result<BroadcastReport>> {
+        Box::pin(async move {
+            self.sent.lock().unwrap().push(tx);
+            Ok(BroadcastReport { delivered: self.peers.len(), failed: 0 })
+        })
+    }
+
+    fn broadcast_raw_transaction(&self, tx: Transaction) -> BoxFuture<'_, Result<BroadcastReport>> {
+        self.send_transaction(tx)
+    }
+
+    fn mempool_transactions(&self) -> BoxFuture<'_, Vec<Transaction>> {
+        Box::pin(async move { self.sent.lock().unwrap().clone() })
+    }
+
+    fn estimate_fee(&self, _target_blocks: u32) -> BoxFuture<'_, Result<i64>> {
+        // the mock chain's blocks hold no fees, like a cold start
+        Box::pin(async move { Ok(SETTINGS.min_relay_fee_rate) })
+    }
+
+    fn healthcheck(&self) -> BoxFuture<'_, HealthReport> {
+        Box::pin(async move {
+            HealthReport { checks: vec![HealthCheck {
+                name: String::from("mock"),
+                status: HealthStatus::Ok,
+                detail: String::from("mock backend, no node running"),
+            }] }
+        })
+    }
+
+    fn peer_throughput(&self) -> BoxFuture<'_, HashMap<String, Throughput>> {
+        Box::pin(async move {
+            self.peers.keys().enumerate()
+                .map(|(i, address)| (address.clone(), Throughput { upload: 512.0 * (i + 1) as f64, download: 2048.0 * (i + 1) as f64 }))
+                .collect()
+        })
+    }
+
+    fn peer_history(&self) -> BoxFuture<'_, Result<Vec<PeerHistoryEntry>>> {
+        Box::pin(async move { Ok(Vec::new()) })
+    }
+}
+
+/// The mock wallets. New wallets are deterministic too, nothing is written anywhere
+pub struct MockWallets {
+    wallets: Wallets,
+    next_seed: u8,
+}
+
+impl WalletStore for MockWallets {
+    fn get_all_address(&self) -> Vec<String> {
+        self.wallets.get_all_address()
+    }
+
+    fn get_wallet(&self, address: &str) -> Option<&Wallet> {
+        self.wallets.get_wallet(address)
+    }
+
+    fn change_addresses(&self, address: &str) -> Vec<String> {
+        self.wallets.change_addresses(address)
+    }
+
+    fn change_provider(&self) -> Box<dyn ChangeAddressProvider> {
+        Box::new(SameAddress)
+    }
+
+    fn load_new(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn create_wallet(&mut self) -> String {
+        let wallet = mock_wallet(self.next_seed);
+        self.next_seed = self.next_seed.wrapping_add(1);
+        let address = wallet.get_address();
+        self.wallets.insert(&address, wallet);
+        address
+    }
+
+    fn insert(&mut self, address: &str, wallet: Wallet) {
+        self.wallets.insert(address, wallet);
+    }
+
+    fn delete_wallet(&mut self, address: &str) -> Result<()> {
+        self.wallets.get_wallets_mut().remove(address)
+            .map(|_| ())
+            .ok_or_else(|| failure::err_msg("Wallet not found"))
+    }
+
+    fn save_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<Option<StoreReport>> {
+        Ok(None)
+    }
+}
+
+fn mock_wallet(seed: u8) -> Wallet {
+    let mut wallet = Wallet::from_secret_key(&[seed; 32]);
+    wallet.metadata = WalletMetadata { label: format!("Mock wallet {}", seed), created_at: MOCK_GENESIS_TIME, ..WalletMetadata::default() };
+    wallet
+}
+
+// `amount` of output 0 of `prev_tx` to `to`, the rest back to `from`
+fn mock_payment(from: &Wallet, prev_tx: &Transaction, to: &str, amount: i32) -> Result<Transaction> {
+    let mut tx = Transaction {
+        id: String::new(),
+        vin: vec![TXInput { txid: prev_tx.id.clone(), vout: 0, signature: Vec::new(), pub_key: from.public_key.clone() }],
+        vout: vec![
+            TXOutput::new(amount, to.to_string())?,
+            TXOutput::new(prev_tx.vout[0].value - amount, from.get_address())?,
+        ],
+        data: Vec::new(),
+    };
+    tx.id = tx.hash()?;
+    tx.sign(&from.secret_key, HashMap::from([(prev_tx.id.clone(), prev_tx.clone())]))?;
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainBuilder, WalletFixture};
+    use std::sync::Arc;
+
+    // The same questions to both backends, answered from their own data
+    async fn check_chain(chain: &dyn ChainView, blocks: usize, miner: &str) {
+        let best = chain.best_height().await.unwrap();
+        assert_eq!(best, blocks as i32 - 1);
+        let newest = chain.blocks_range(best - 2, best).await.unwrap();
+        assert_eq!(newest.iter().map(Block::get_height).collect::<Vec<_>>(), vec![best, best - 1, best - 2]);
+        assert_eq!(chain.stats().await.unwrap().blocks, blocks as u64);
+        assert!(chain.balance(miner.to_string()).await.unwrap() > 0);
+        assert!(chain.balance(String::from("not an address")).await.is_err());
+    }
+
+    fn check_wallets(store: &mut dyn WalletStore) {
+        let before = store.get_all_address().len();
+        let address = store.create_wallet();
+        assert!(store.get_wallet(&address).is_some());
+        assert_eq!(store.get_all_address().len(), before + 1);
+    }
+
+    #[test]
+    fn test_node_backend() {
+        let miner = WalletFixture::new(1);
+        let blockchain = Arc::new(RwLock::new(ChainBuilder::new(&miner).empty_blocks(4).build()));
+        let path = std::env::temp_dir().join(format!("blockjain-backend-utxos-{}", rand::random::<u64>()));
+        let utxo_set = Arc::new(RwLock::new(UTXOSet::with_path(blockchain, path.to_str().unwrap()).unwrap()));
+        let server: Arc<RwLock<Server>> = Arc::new(RwLock::new(Server::new("0", "", Arc::clone(&utxo_set)).unwrap()));
+        let chain: Arc<dyn ChainView> = utxo_set.clone();
+        let network: Arc<dyn NetworkControl> = server;
+
+        crate::runtime::RUNTIME.block_on(async {
+            utxo_set.read().await.reindex().await.unwrap();
+            check_chain(&*chain, 5, &miner.address()).await;
+            network.add_peer(String::from("10.0.0.9:8335")).await.unwrap();
+            assert!(network.known_nodes().await.contains_key("10.0.0.9:8335"));
+            assert!(network.mempool_transactions().await.is_empty());
+        });
+        // Only in memory, saving would write to the wallets database
+        check_wallets(&mut Wallets::default());
+        drop(utxo_set);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_mock_backend() {
+        let mock = MockBackend::new(10).unwrap();
+        let again = MockBackend::new(10).unwrap();
+        assert_eq!(mock.blocks.iter().map(Block::get_hash).collect::<Vec<_>>(), again.blocks.iter().map(Block::get_hash).collect::<Vec<_>>());
+
+        let mut wallets = mock.wallets();
+        let addresses = wallets.get_all_address();
+        assert_eq!(addresses.len(), MOCK_WALLETS as usize);
+        crate::runtime::RUNTIME.block_on(async {
+            check_chain(&mock, 10, &addresses[0]).await;
+            // Every coin the chain issued is somewhere
+            let mut total = 0;
+            for address in &addresses {
+                total += mock.balance(address.clone()).await.unwrap();
+            }
+            assert_eq!(total as i64, mock.stats().await.unwrap().coins_issued);
+
+            assert_eq!(mock.known_nodes().await.len(), 4);
+            let tx = mock.blocks[2].get_transactions()[1].clone();
+            assert_eq!(mock.send_transaction(tx.clone()).await.unwrap().delivered, 4);
+            assert_eq!(mock.mempool_transactions().await[0].id, tx.id);
+            assert!(mock.healthcheck().await.is_healthy());
+        });
+        check_wallets(&mut wallets);
+        assert!(wallets.create_wallet() != wallets.create_wallet());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+/*
+    Bandwidth limits
+
+    Messages are read and written in CHUNK_SIZE pieces, each of which takes its bytes from
+    a token bucket first. A chunk that finds the bucket empty reserves its bytes anyway and
+    sleeps until they're earned, so concurrent messages take turns chunk by chunk instead
+    of a large block holding the connection until it's done.
+
+    The bucket holds BURST worth of traffic, short messages go out without waiting.
+*/
+
+pub const CHUNK_SIZE: usize = 4 * 1024;
+const BURST: Duration = Duration::from_millis(100);
+// Throughput shown per peer is averaged over this window
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Token bucket for one direction of traffic. A rate of 0 doesn't limit anything
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64, // negative while chunks wait for bytes they reserved
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket { tokens: Self::capacity(bytes_per_sec), updated: Instant::now() }),
+        }
+    }
+
+    /// Limit in KB/s as in Settings, 0 for none
+    pub fn from_kbps(kbps: u32) -> Self {
+        Self::new(kbps as u64 * 1024)
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.bytes_per_sec > 0
+    }
+
+    /// How long `bytes` take at the limit, zero without one
+    pub fn transfer_time(&self, bytes: usize) -> Duration {
+        if !self.is_limited() {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64)
+    }
+
+    fn capacity(bytes_per_sec: u64) -> f64 {
+        bytes_per_sec as f64 * BURST.as_secs_f64()
+    }
+
+    /// Waits until `bytes` may pass
+    pub async fn consume(&self, bytes: usize) {
+        if !self.is_limited() {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let earned = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_sec as f64;
+            bucket.tokens = (bucket.tokens + earned).min(Self::capacity(self.bytes_per_sec));
+            bucket.updated = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Bytes exchanged with each peer over the last THROUGHPUT_WINDOW
+#[derive(Debug, Default)]
+pub struct TrafficMeter {
+    samples: HashMap<String, VecDeque<(Instant, usize, usize)>>, // when, sent, received
+}
+
+/// Average rates to and from a peer, in bytes per second
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    pub upload: f64,
+    pub download: f64,
+}
+
+impl TrafficMeter {
+    pub fn sent(&mut self, peer: &str, bytes: usize) {
+        self.record(peer, bytes, 0);
+    }
+
+    pub fn received(&mut self, peer: &str, bytes: usize) {
+        self.record(peer, 0, bytes);
+    }
+
+    fn record(&mut self, peer: &str, sent: usize, received: usize) {
+        let now = Instant::now();
+        let samples = self.samples.entry(peer.to_string()).or_default();
+        samples.push_back((now, sent, received));
+        while samples.front().is_some_and(|(at, _, _)| now.duration_since(*at) > THROUGHPUT_WINDOW) {
+            samples.pop_front();
+        }
+    }
+
+    pub fn throughput(&self) -> HashMap<String, Throughput> {
+        let now = Instant::now();
+        let window = THROUGHPUT_WINDOW.as_secs_f64();
+        self.samples.iter()
+            .map(|(peer, samples)| {
+                let recent = samples.iter().filter(|(at, _, _)| now.duration_since(*at) <= THROUGHPUT_WINDOW);
+                let (sent, received) = recent.fold((0, 0), |(s, r), (_, sent, received)| (s + sent, r + received));
+                (peer.clone(), Throughput { upload: sent as f64 / window, download: received as f64 / window })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter_paces_chunks() {
+        let limiter = RateLimiter::new(100 * 1024);
+        let started = Instant::now();
+        // the burst (10 KB) passes at once, the other 40 KB take about 400ms
+        for _ in 0..(50 * 1024 / CHUNK_SIZE) {
+            limiter.consume(CHUNK_SIZE).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(350) && elapsed < Duration::from_millis(700), "{:?}", elapsed);
+
+        let unlimited = RateLimiter::from_kbps(0);
+        let started = Instant::now();
+        unlimited.consume(100 * 1024 * 1024).await;
+        assert!(started.elapsed() < Duration::from_millis(10));
+
+        let mut meter = TrafficMeter::default();
+        meter.sent("a", 5000);
+        meter.received("a", 1000);
+        let throughput = meter.throughput()["a"];
+        assert_eq!(throughput.upload, 5000.0 / THROUGHPUT_WINDOW.as_secs_f64());
+        assert_eq!(throughput.download, 1000.0 / THROUGHPUT_WINDOW.as_secs_f64());
+    }
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use blockchain::conformance::ConformanceClient;
+use blockchain::runtime;
+
+const USAGE: &str = "usage: protocol-conformance <node address> [--listen <address>] [--timeout <seconds>]
+
+Checks that the node at <node address> speaks the peer protocol: the version handshake, addr
+gossip, getblocks, inv and getdata for blocks and transactions, and that malformed and oversized
+messages are dropped. Nodes answer on a new connection, so --listen (127.0.0.1:0 by default)
+must be reachable from the node. Exits 0 when every check passes.";
+
+fn main() {
+    let mut target = None;
+    let mut listen = String::from("127.0.0.1:0");
+    let mut timeout = Duration::from_secs(5);
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => match args.next() {
+                Some(addr) => listen = addr,
+                None => usage_error("--listen needs an address"),
+            },
+            "--timeout" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => timeout = Duration::from_secs(secs),
+                None => usage_error("--timeout needs a number of seconds"),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if target.is_none() && !arg.starts_with('-') => target = Some(arg),
+            _ => usage_error(&format!("unexpected argument {}", arg)),
+        }
+    }
+    let Some(target) = target else {
+        usage_error("no node address given");
+    };
+
+    let report = runtime::RUNTIME.block_on(async {
+        let mut client = ConformanceClient::bind(&target, &listen, timeout).await?;
+        Ok::<_, failure::Error>(client.run().await)
+    });
+    match report {
+        Ok(report) => {
+            print!("{}", report);
+            std::process::exit(if report.passed() { 0 } else { 1 });
+        }
+        Err(e) => {
+            eprintln!("Couldn't listen on {}: {}", listen, e);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn usage_error(problem: &str) -> ! {
+    eprintln!("{}\n\n{}", problem, USAGE);
+    std::process::exit(2);
+}
@@ -1,82 +1,313 @@
 use crate::errors::Result;
-use crate::transaction::Transaction;
+use crate::transaction::{LegacyTransaction, Transaction};
 use std::time::SystemTime;
 use crypto::{ sha2::Sha256, digest::Digest };
 use log::info;
 use serde::{Deserialize, Serialize};
-use merkle_cbt::merkle_tree::Merge;
-use merkle_cbt::merkle_tree::CBMT;
 
-const TARGET_HEXT: usize = 4;
+/*
+    Proof of work target
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+    A block's hash is valid when its first 8 bytes, read as a big-endian number, are below
+    the block's target. Lower targets are harder. Each block carries its target, the chain
+    decides which one it must use (see `Blockchain::next_target`). A block's work is the
+    number of hashes it takes on average, 2^64 / target, and the best chain is the one with
+    the most work summed over its blocks, not necessarily the longest.
+
+    Merkle root
+
+    The header commits to the transactions through the root of a binary tree over their
+    ids: each level hashes pairs of the one below (sha256 of left || right), the last node
+    of an odd level is paired with itself. A transaction's inclusion can then be shown with
+    one sibling per level (`Block::merkle_proof`) to anyone holding just the header.
+*/
+
+// Target of the genesis block and the first retarget window, 16 zero bits
+#[cfg(not(test))]
+pub const INITIAL_TARGET: u64 = 1 << 48;
+// The easiest target retargeting may reach
+#[cfg(not(test))]
+pub const MAX_TARGET: u64 = 1 << 56;
+// Trivial difficulty so tests can mine blocks instantly
+#[cfg(test)]
+pub const INITIAL_TARGET: u64 = 1 << 60;
+#[cfg(test)]
+pub const MAX_TARGET: u64 = u64::MAX;
+
+// Largest block accepted, as serialized with bincode
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+// What a block template keeps free for the header and the coinbase
+pub const BLOCK_RESERVED_SIZE: usize = 1_000;
+
+/// A header and the transactions it commits to. Walks that only need hashes, heights or
+/// timestamps read headers (see `Blockchain::get_header`), the transactions are only read
+/// when the block is
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "StoredBlock")]
 pub struct Block {
+    header: BlockHeader,
+    transactions: Vec<Transaction>,
+}
+
+// How blocks are serialized, to disk, to peers and into chain files. The fields are in the
+// order they had before the header was split out, which keeps the encoding unchanged
+#[derive(Deserialize)]
+struct StoredBlock {
     timestamp: u128,
     transactions: Vec<Transaction>,
     prev_block_hash: String,
+    merkle_root: String,
     hash: String,
     height: i32,
+    target: u64,
     nonce: i32,
 }
 
+#[derive(Serialize)]
+struct StoredBlockRef<'a> {
+    timestamp: u128,
+    transactions: &'a Vec<Transaction>,
+    prev_block_hash: &'a str,
+    merkle_root: &'a str,
+    hash: &'a str,
+    height: i32,
+    target: u64,
+    nonce: i32,
+}
+
+impl From<StoredBlock> for Block {
+    fn from(stored: StoredBlock) -> Block {
+        Block {
+            header: BlockHeader {
+                timestamp: stored.timestamp,
+                prev_block_hash: stored.prev_block_hash,
+                merkle_root: stored.merkle_root,
+                hash: stored.hash,
+                height: stored.height,
+                target: stored.target,
+                nonce: stored.nonce,
+            },
+            transactions: stored.transactions,
+        }
+    }
+}
+
+impl Serialize for Block {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let header = &self.header;
+        StoredBlockRef {
+            timestamp: header.timestamp,
+            transactions: &self.transactions,
+            prev_block_hash: &header.prev_block_hash,
+            merkle_root: &header.merkle_root,
+            hash: &header.hash,
+            height: header.height,
+            target: header.target,
+            nonce: header.nonce,
+        }.serialize(serializer)
+    }
+}
+
+/// How blocks were serialized before they carried a merkle root, in block databases of
+/// schema version 1 (see schema)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootlessBlock {
+    pub timestamp: u128,
+    pub transactions: Vec<LegacyTransaction>,
+    pub prev_block_hash: String,
+    pub hash: String,
+    pub height: i32,
+    pub target: u64,
+    pub nonce: i32,
+}
+
+impl RootlessBlock {
+    /// The block in the layout that followed, with the merkle root of its transactions filled
+    /// in. Its hash stays the one it was mined with, which covered the transactions rather than the root
+    pub fn into_dataless(self) -> Result<DatalessBlock> {
+        let transactions: Vec<Transaction> = self.transactions.iter().cloned().map(Transaction::from).collect();
+        Ok(DatalessBlock {
+            timestamp: self.timestamp,
+            merkle_root: merkle_root(&transactions)?,
+            transactions: self.transactions,
+            prev_block_hash: self.prev_block_hash,
+            hash: self.hash,
+            height: self.height,
+            target: self.target,
+            nonce: self.nonce,
+        })
+    }
+}
+
+/// How blocks were serialized before transactions carried data, in block databases of
+/// schema versions 2 and 3 (see schema), chain files before BJCHAIN3 and messages from peers
+/// of protocol version 1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatalessBlock {
+    pub timestamp: u128,
+    pub transactions: Vec<LegacyTransaction>,
+    pub prev_block_hash: String,
+    pub merkle_root: String,
+    pub hash: String,
+    pub height: i32,
+    pub target: u64,
+    pub nonce: i32,
+}
+
+impl From<DatalessBlock> for Block {
+    fn from(old: DatalessBlock) -> Block {
+        Block::from(StoredBlock {
+            timestamp: old.timestamp,
+            transactions: old.transactions.into_iter().map(Transaction::from).collect(),
+            prev_block_hash: old.prev_block_hash,
+            merkle_root: old.merkle_root,
+            hash: old.hash,
+            height: old.height,
+            target: old.target,
+            nonce: old.nonce,
+        })
+    }
+}
+
+/// Shows that a transaction is in a block: the hashes next to it on the way up the
+/// merkle tree, `index` is its position in the block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub siblings: Vec<String>, // hex, from the leaves up
+}
+
+/// A block without its transactions. Every stored block has its header in the headers tree,
+/// and it's all a pruned node keeps of old blocks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub prev_block_hash: String,
+    pub merkle_root: String, // hex
+    pub hash: String,
+    pub height: i32,
+    pub target: u64,
+    pub nonce: i32,
+}
+
 impl Block {
 
     pub fn get_timestamp(&self) -> u128 {
-        self.timestamp
+        self.header.timestamp
     }
 
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
-    pub(crate) fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+    pub fn get_prev_hash(&self) -> String {
+        self.header.prev_block_hash.clone()
     }
 
     pub fn get_hash(&self) -> String {
-        self.hash.clone()
+        self.header.hash.clone()
+    }
+
+    pub fn get_merkle_root(&self) -> String {
+        self.header.merkle_root.clone()
     }
 
     pub fn get_height(&self) -> i32 {
-        self.height
+        self.header.height
     }
 
     pub fn get_nonce(&self) -> i32 {
-        self.nonce
+        self.header.nonce
+    }
+
+    pub fn get_target(&self) -> u64 {
+        self.header.target
+    }
+
+    /// Serialized bytes, what MAX_BLOCK_SIZE limits
+    pub fn size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
     }
 
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+        Block::new_block(vec![coinbase], String::new(), 0, INITIAL_TARGET).unwrap()
     }
 
     pub fn new_block(
             data: Vec<Transaction>, 
             prev_block_hash: String, 
-            height: i32
+            height: i32,
+            target: u64,
         ) -> Result<Block> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
+        Block::new_block_at(data, prev_block_hash, height, target, timestamp)
+    }
 
+    /// `new_block` with a given timestamp (ms since the epoch)
+    pub fn new_block_at(
+            data: Vec<Transaction>,
+            prev_block_hash: String,
+            height: i32,
+            target: u64,
+            timestamp: u128,
+        ) -> Result<Block> {
         let mut block = Block {
-            timestamp: timestamp,
+            header: BlockHeader {
+                timestamp,
+                prev_block_hash,
+                merkle_root: merkle_root(&data)?,
+                hash: String::new(),
+                height,
+                target,
+                nonce: 0,
+            },
             transactions: data,
-            prev_block_hash,
-            hash: String::new(),
-            height,
-            nonce: 0,
         };
         block.run_proof_of_work()?;
         Ok(block)
     }
 
+    /// Whether the merkle root in the header is the one of the block's transactions
+    pub fn verify_merkle_root(&self) -> Result<bool> {
+        Ok(merkle_root(&self.transactions)? == self.header.merkle_root)
+    }
+
+    /// The path from transaction `txid` to the merkle root, `None` when the block doesn't
+    /// hold it
+    pub fn merkle_proof(&self, txid: &str) -> Option<MerkleProof> {
+        let index = self.transactions.iter().position(|tx| tx.id == txid)?;
+        let mut level = tx_hashes(&self.transactions).ok()?;
+        let mut position = index;
+        let mut siblings = Vec::new();
+        while level.len() > 1 {
+            let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+            siblings.push(hex::encode(sibling));
+            level = next_level(&level);
+            position /= 2;
+        }
+        Some(MerkleProof { index, siblings })
+    }
+
+    /// Checks the proof of work and that the stored hash is the hash of the block's contents
+    pub fn verify_proof_of_work(&self) -> Result<bool> {
+        let mut hasher = Sha256::new();
+        hasher.input(&self.prepare_hash_data()?);
+        Ok(self.validate()? && hasher.result_str() == self.header.hash)
+    }
+
     // private function
     fn run_proof_of_work(&mut self) -> Result<()> {
         info!("Mining the block");
 
         // searches for a nonce for a specific number of 0 requirement.
         while !self.validate()? {
-            self.nonce += 1;
+            self.header.nonce += 1;
         }
 
         let data = self.prepare_hash_data()?;
@@ -86,27 +317,19 @@ impl Block {
         hasher.input(&data[..]);
 
         // updates block's hash
-        self.hash = hasher.result_str();
+        self.header.hash = hasher.result_str();
         Ok(())
     }
 
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
-        }
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&transactions);
-
-        Ok(tree.root())
-    }
     // returns byte array of the hashed block
     fn prepare_hash_data(&self) -> Result<Vec<u8>> {
+        let header = &self.header;
         let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce
+            header.prev_block_hash.clone(),
+            header.merkle_root.clone(),
+            header.timestamp,
+            header.target,
+            header.nonce
         );
 
         let bytes = bincode::serialize(&content)?;
@@ -119,43 +342,146 @@ impl Block {
 
         let mut hasher = Sha256::new();
         hasher.input(&data[..]);
+        let mut hash = [0u8; 32];
+        hasher.result(&mut hash);
 
-        let mut vec1: Vec<u8> = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&hash[..8]);
+        Ok(u64::from_be_bytes(prefix) < self.header.target)
+    }
+}
 
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+/// Whether `proof` leads from transaction `txid` to `root`
+pub fn verify_merkle_proof(root: &str, proof: &MerkleProof, txid: &str) -> bool {
+    let Ok(mut node) = hex::decode(txid) else {
+        return false;
+    };
+    let mut position = proof.index;
+    for sibling in &proof.siblings {
+        let Ok(sibling) = hex::decode(sibling) else {
+            return false;
+        };
+        node = if position.is_multiple_of(2) { hash_pair(&node, &sibling) } else { hash_pair(&sibling, &node) };
+        position /= 2;
     }
+    // a longer index would point past the tree the siblings describe
+    position == 0 && hex::encode(node) == root
 }
 
-struct MergeTX {
+/// Expected number of hashes to find a block below `target`
+pub fn block_work(target: u64) -> u128 {
+    (1u128 << 64) / target.max(1) as u128
+}
 
+// Root of the tree over the transactions' hashes, all zeros for no transactions
+fn merkle_root(transactions: &[Transaction]) -> Result<String> {
+    let mut level = tx_hashes(transactions)?;
+    if level.is_empty() {
+        return Ok(hex::encode([0u8; 32]));
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    Ok(hex::encode(&level[0]))
 }
 
-impl Merge for MergeTX {
-    type Item = Vec<u8>;
-    fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
-        let mut hasher = Sha256::new();
-        let mut data: Vec<u8> = left.clone();
-        data.append(&mut right.clone());
+// The leaves: the hash of each transaction, which is its id when it is well formed
+fn tx_hashes(transactions: &[Transaction]) -> Result<Vec<Vec<u8>>> {
+    transactions.iter().map(|tx| Ok(hex::decode(tx.hash()?)?)).collect()
+}
 
-        hasher.input(&data);
-        let mut re: [u8; 32] = [0; 32];
-        hasher.result(&mut re);
-        re.to_vec()
-    }
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level.chunks(2).map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0]))).collect()
+}
+
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(left);
+    hasher.input(right);
+    let mut hash = vec![0; 32];
+    hasher.result(&mut hash);
+    hash
 }
 
-/*
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{coinbase, WalletFixture};
+
+    fn block_with(count: i32) -> Block {
+        let miner = WalletFixture::new(1);
+        let txs = (0..count).map(|i| coinbase(&miner.address(), i)).collect();
+        Block::new_block(txs, String::new(), 0, INITIAL_TARGET).unwrap()
+    }
+
+    #[test]
+    fn test_merkle_root_of_one_transaction() {
+        let block = block_with(1);
+        let txid = &block.get_transactions()[0].id;
+        // a lone leaf is the root
+        assert_eq!(&block.get_merkle_root(), txid);
+        assert!(block.verify_merkle_root().unwrap());
+        let proof = block.merkle_proof(txid).unwrap();
+        assert_eq!(proof, MerkleProof { index: 0, siblings: Vec::new() });
+        assert!(verify_merkle_proof(&block.get_merkle_root(), &proof, txid));
+        assert!(block.merkle_proof(&"00".repeat(32)).is_none());
+    }
+
+    #[test]
+    fn test_merkle_proofs_with_odd_counts() {
+        for count in [2, 3, 5, 7] {
+            let block = block_with(count);
+            let root = block.get_merkle_root();
+            for tx in block.get_transactions() {
+                let proof = block.merkle_proof(&tx.id).unwrap();
+                assert!(verify_merkle_proof(&root, &proof, &tx.id), "tx {} of {}", proof.index, count);
+            }
+        }
+
+        // With three leaves the last one is paired with itself
+        let block = block_with(3);
+        let ids: Vec<Vec<u8>> = block.get_transactions().iter().map(|tx| hex::decode(&tx.id).unwrap()).collect();
+        let expected = hash_pair(&hash_pair(&ids[0], &ids[1]), &hash_pair(&ids[2], &ids[2]));
+        assert_eq!(block.get_merkle_root(), hex::encode(expected));
+        assert_eq!(block.merkle_proof(&block.get_transactions()[2].id).unwrap().siblings[0], hex::encode(&ids[2]));
+    }
 
     #[test]
-    fn test_blockchain() {
-        let mut b = Blockchain::new();
-        b.add_block("Data".to_string());
-        b.add_block("Data2".to_string());
-        b.add_block("data33".to_string());
-        dbg!(b);
-    }
-}*/
+    fn test_tampered_merkle_proofs_fail() {
+        let block = block_with(5);
+        let root = block.get_merkle_root();
+        let txid = block.get_transactions()[2].id.clone();
+        let proof = block.merkle_proof(&txid).unwrap();
+        assert!(verify_merkle_proof(&root, &proof, &txid));
+
+        let mut sibling = proof.clone();
+        sibling.siblings[1] = "00".repeat(32);
+        assert!(!verify_merkle_proof(&root, &sibling, &txid));
+        let mut moved = proof.clone();
+        moved.index = 3;
+        assert!(!verify_merkle_proof(&root, &moved, &txid));
+        moved.index = 2 + 8; // same path, past the end of the tree
+        assert!(!verify_merkle_proof(&root, &moved, &txid));
+        let mut short = proof.clone();
+        short.siblings.pop();
+        assert!(!verify_merkle_proof(&root, &short, &txid));
+        assert!(!verify_merkle_proof(&root, &proof, &block.get_transactions()[1].id));
+        assert!(!verify_merkle_proof(&block_with(4).get_merkle_root(), &proof, &txid));
+        assert!(!verify_merkle_proof(&root, &proof, "not hex"));
+    }
+
+    #[test]
+    fn test_encoding_kept_the_fields_in_their_old_order() {
+        let block = block_with(2);
+        let data = bincode::serialize(&block).unwrap();
+        // timestamp, then the transactions, then the rest of the header
+        assert_eq!(data[..16], block.get_timestamp().to_le_bytes());
+        assert_eq!(data[16..24], 2u64.to_le_bytes());
+        assert_eq!(data[data.len() - 4..], block.get_nonce().to_le_bytes());
+
+        let decoded: Block = bincode::deserialize(&data).unwrap();
+        assert_eq!(decoded.header(), block.header());
+        assert_eq!(decoded.get_transactions().len(), 2);
+        assert_eq!(bincode::serialize(&decoded).unwrap(), data);
+    }
+}
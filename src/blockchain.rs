@@ -1,68 +1,478 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 
 use failure::format_err;
-use log::{debug, info};
+use log::{debug, error, info};
 
-use crate::block::Block;
-use crate::errors::Result;
-use crate::transaction::Transaction;
-use crate::tx::TXOutputs;
+use crate::address;
+use crate::audit::{self, AuditAction, AuditLog, AuditOutcome};
+use crate::block::{block_work, Block, BlockHeader, INITIAL_TARGET, MAX_BLOCK_SIZE, MAX_TARGET};
+use crate::chain_file::{self, ChainFileReader, ChainFileWriter, ImportSummary};
+use crate::checkpoint::{self, Checkpoint};
+use crate::clock;
+use crate::schema;
+use crate::errors::{BlockRejectReason, ChainOpenError, LookupError, Result, UtxoError};
+use crate::settings::SETTINGS;
+use crate::peer_history::{PeerHistoryEntry, MAX_PEER_HISTORY};
+use crate::snapshot::SnapshotEntry;
+use crate::state_digest::StateDigest;
+use crate::transaction::{self, block_subsidy, circulating_supply, Transaction, TxContext};
+use crate::tx::{TXInput, UnspentOutputs};
+use crate::sent_txs::{SentTx, SentTxIndex};
+use crate::wallet_history::{self, HistoryEntry, OwnedTxIndex};
 
-const TARGET_HEXT: usize = 4;
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
+// The default genesis block pays this placeholder, nobody has its keys
+pub const GENESIS_ADDRESS: &str = "35yLCpZy2MzPzyngA3YstWbyDhyhzjXBcw";
+// Timestamp of every network's genesis block, see `network_genesis`
+const GENESIS_TIMESTAMP: u128 = 1_700_000_000_000;
+
+// db key of the operator checkpoints, height -> block hash
+const CHECKPOINTS_KEY: &str = "CHECKPOINTS";
+// db key of the block a snapshot-synced chain starts from, and the tree holding its UTXO state
+const SNAPSHOT_BASE_KEY: &str = "SNAPSHOT_BASE";
+pub const SNAPSHOT_TREE: &str = "snapshot";
+// tree of removed peers, see peer_history
+const PEER_HISTORY_TREE: &str = "peer_history";
+// tree of the active chain by height, big-endian height -> block hash, and the db key of the
+// tip it's current with. A stale index is rebuilt when read, see `height_index`
+const HEIGHTS_TREE: &str = "heights";
+const HEIGHTS_TIP_KEY: &str = "HEIGHTS_TIP";
+// db key of the running totals behind `stats`, see ChainCounters
+const STATS_KEY: &str = "STATS";
+// Blocks the average block interval of `stats` is taken over
+pub const STATS_INTERVAL_BLOCKS: i32 = 100;
+// Blocks below the tip whose transactions `estimate_fee` looks at
+pub const FEE_ESTIMATE_BLOCKS: usize = 20;
+// tree of the header of every stored or pruned block, block hash -> BlockHeader, and the db
+// key of the lowest height whose block is still stored, see `get_header` and `prune_to_height`
+pub const HEADERS_TREE: &str = "headers";
+const PRUNED_HEIGHT_KEY: &str = "PRUNED_HEIGHT";
+// tree of the chain work up to every stored block, block hash -> big-endian u128, see
+// `chain_work`
+const WORK_TREE: &str = "chain_work";
+pub const BLOCKS_PATH: &str = "blocks";
+// db key of the last repair made when opening the chain, see ChainRepair
+const REPAIR_KEY: &str = "LAST_REPAIR";
+// Blocks in a difficulty window, the target is recomputed at every multiple of it
+pub const RETARGET_INTERVAL: i32 = 20;
+// Most a single retarget changes the target by, either way
+pub const MAX_RETARGET_FACTOR: u128 = 4;
+// Pruning keeps at least this many blocks, enough for the next retarget window and
+// ordinary reorgs
+pub const MIN_PRUNE_KEEP: u32 = 2 * RETARGET_INTERVAL as u32;
+// Blocks the median time past is taken over, a block must be timestamped after it
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// How far ahead of the local clock a block's timestamp may be, in ms
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
 
 /*
     Blockhain struct has methods for dealing with UTXOs, Transactions and Blocks.  
 */
 
+/// The genesis block of the network named `network`. Nothing in it depends on the node or
+/// the time it's created, so fresh nodes on the same network start from the same block and
+/// nodes on different networks can tell they don't share a chain
+pub fn network_genesis(network: &str) -> Result<Block> {
+    let cbtx = Transaction::new_coinbase(GENESIS_ADDRESS.to_string(), format!("Genesis Block Reward of {}", network), 0)?;
+    Block::new_block_at(vec![cbtx], String::new(), 0, INITIAL_TARGET, GENESIS_TIMESTAMP)
+}
+
+/// Target after a retarget window that took `actual` ms instead of `expected`. It changes
+/// by at most MAX_RETARGET_FACTOR either way and never gets easier than MAX_TARGET
+pub fn retarget(target: u64, actual: u128, expected: u128) -> u64 {
+    let expected = expected.max(1);
+    let actual = actual.clamp(expected / MAX_RETARGET_FACTOR, expected * MAX_RETARGET_FACTOR);
+    let adjusted = target as u128 * actual / expected;
+    adjusted.clamp(1, MAX_TARGET as u128) as u64
+}
+
+/*
+    Fee estimates
+
+    `estimate_fee` looks at the fee rates the transactions of the last FEE_ESTIMATE_BLOCKS
+    blocks paid. A target of one block gets the rate that 90% of them paid at most, longer
+    targets a lower percentile, 90 / target_blocks. Never less than the min_relay_fee_rate
+    setting, which is also the answer while there's no fee data, on a new chain or one of
+    empty blocks. The server raises it further when its mempool holds more than the target's
+    blocks of better paying transactions, see Server::estimate_fee.
+*/
+
+/// The rate of the 90 / `target_blocks` percentile of `rates` (ascending), 0 without rates
+pub fn fee_rate_percentile(rates: &[i64], target_blocks: u32) -> i64 {
+    if rates.is_empty() {
+        return 0;
+    }
+    let index = (rates.len() - 1) * 9 / (10 * target_blocks.max(1) as usize);
+    rates[index]
+}
+
+/// LAST was rebuilt when the chain was opened
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ChainRepair {
+    pub timestamp: u128, // ms since the epoch
+    pub problem: String, // the ChainOpenError that was found
+    pub tip: String,
+    pub height: i32,
+}
+
+impl std::fmt::Display for ChainRepair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}. The tip was reset to block {} at height {}", self.problem, self.tip, self.height)
+    }
+}
+
+/// A block of the active chain that can't be read, see `check_integrity`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DamagedBlock {
+    pub hash: String,
+    pub height: Option<i32>, // from its header, None when that's gone too
+    pub reason: String,
+}
+
+/// What `check_integrity` found walking the chain from the tip down
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainHealth {
+    pub tip: String,
+    pub blocks_checked: usize,
+    pub damaged: Vec<DamagedBlock>, // tip first
+    // Height and hash of the highest block with nothing damaged below it, the tip of an
+    // intact chain. None when the walk couldn't get below a damaged block
+    pub last_good: Option<(i32, String)>,
+}
+
+impl ChainHealth {
+    pub fn is_intact(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
+
+impl std::fmt::Display for ChainHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(first) = self.damaged.first() else {
+            return write!(f, "All {} blocks below the tip can be read", self.blocks_checked);
+        };
+        let height = first.height.map_or(String::from("unknown height"), |height| format!("height {}", height));
+        write!(f, "{} damaged blocks, the highest is {} at {}: {}", self.damaged.len(), first.hash, height, first.reason)?;
+        match &self.last_good {
+            Some((height, hash)) => write!(f, ". The chain is intact up to block {} at height {}", hash, height),
+            None => write!(f, ". No intact part of the chain was found below it"),
+        }
+    }
+}
+
+/// How to get a damaged chain going again, see `Blockchain::recover_damaged`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainRecovery {
+    Resync,             // delete it, the node downloads it again from peers
+    TruncateToLastGood, // keep what's intact, the blocks above come again from peers
+}
+
+/// What storing a block did to the best chain
+#[derive(Debug, Clone)]
+pub enum ReorgOutcome {
+    // Already stored, or on a branch no higher than the tip, the tip didn't move
+    Stored,
+    // Built on the tip and became the new tip
+    Extended,
+    // Its branch overtook the tip's. `disconnected` holds the old branch above the fork,
+    // newest first, `connected` the new branch, oldest first
+    Reorganized {
+        fork_height: i32,
+        disconnected: Vec<Block>,
+        connected: Vec<Block>,
+    },
+}
+
+/// Totals of the active chain, see `stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub blocks: u64,
+    pub transactions: u64,
+    pub coins_issued: i64, // coinbase subsidies, without the fees miners claimed
+    pub circulating_supply: i64, // what the subsidy schedule created up to the tip
+    pub utxos: u64,
+    pub avg_block_interval: Option<std::time::Duration>, // over the last STATS_INTERVAL_BLOCKS
+    pub difficulty: f64, // of the next block, 1 at INITIAL_TARGET
+}
+
+// Running totals of the active chain and the tip they're current with
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+struct ChainCounters {
+    tip: String,
+    transactions: i64,
+    coins_issued: i64,
+    utxos: i64,
+}
+
+impl ChainCounters {
+    // Adds what `block` brings to the chain, `sign` -1 takes it away again
+    fn apply(&mut self, block: &Block, sign: i64) {
+        for tx in block.get_transactions() {
+            self.transactions += sign;
+            self.utxos += sign * tx.vout.len() as i64;
+            if tx.is_coinbase() {
+                let paid: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+                self.coins_issued += sign * paid.min(block_subsidy(block.get_height()) as i64);
+            } else {
+                self.utxos -= sign * tx.vin.len() as i64;
+            }
+        }
+    }
+}
+
+// The transactions of a block so far, by txid, and the outputs they spend
+#[derive(Default)]
+struct BlockSpends {
+    txs: HashMap<String, Transaction>,
+    spent: HashSet<(String, i32)>,
+}
+
+impl BlockSpends {
+    fn add(&mut self, tx: &Transaction) {
+        if !tx.is_coinbase() {
+            self.spent.extend(tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)));
+        }
+        self.txs.insert(tx.id.clone(), tx.clone());
+    }
+}
+
+/// What `verify_chain` found. `problem` is the first inconsistency, None for a clean chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainAuditReport {
+    pub blocks_checked: usize,
+    pub transactions_checked: usize,
+    pub problem: Option<AuditProblem>,
+}
+
+/// A block, or a transaction of it, that isn't consistent with the rest of the chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditProblem {
+    pub block: String, // hash it's stored under
+    pub height: i32, // where the chain puts it
+    pub txid: Option<String>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ChainAuditReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Checked {} blocks and {} transactions", self.blocks_checked, self.transactions_checked)?;
+        match &self.problem {
+            None => write!(f, ", no problems found"),
+            Some(problem) => write!(f, ". {}", problem),
+        }
+    }
+}
+
+impl std::fmt::Display for AuditProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Block {} at height {}", self.block, self.height)?;
+        if let Some(txid) = &self.txid {
+            write!(f, ", transaction {}", txid)?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
 #[derive(Debug)]
 pub struct Blockchain {
     // tip - top of the blockchain
     pub tip: String,
     pub db: sled::Db,
+    pub fixed_checkpoints: BTreeMap<i32, String>, // height -> block hash, see checkpoint.rs
 }
 
 pub struct BlockchainIter<'a> {
+    headers: HeaderIter<'a>,
+}
+
+/// Walks the headers down from a block without reading the blocks, see `Blockchain::headers`
+pub struct HeaderIter<'a> {
     current_hash: String,
+    base: Option<String>, // walks of a snapshot-synced chain end here
     bc: &'a Blockchain,
+    error: Option<failure::Error>, // why the walk stopped before the genesis block
 }
 
 impl Blockchain {
 
     // Opens an existing blockchain or creates a new one with a fixed coinbase.
     pub fn new() -> Result<Blockchain> {
-        let db = sled::open("data/blocks")?;
-        let hash = match db.get("LAST")? {
-            Some(last_hash) => last_hash.to_vec(),
-            None => Vec::new(),
-        };
+        Blockchain::open(BLOCKS_PATH)
+    }
 
-        let lasthash = if hash.is_empty() {
-            // If no blocks exist, create the genesis block.
-            Blockchain::create_genesis_block(&db)?
-        } else {
-            String::from_utf8(hash)?
+    /// `open_network` on the configured network
+    pub fn open(path: &str) -> Result<Blockchain> {
+        Blockchain::open_network(path, &SETTINGS.network)
+    }
+
+    /// Opens the block database at `path`, migrating it to the current schema first (see
+    /// schema). An empty one gets the genesis block of `network`, a LAST key that's missing or
+    /// points at a missing block is rebuilt from the highest stored block when that's
+    /// unambiguous (see ChainRepair), otherwise a ChainOpenError is returned. Blocks stored on
+    /// top of the tip that LAST never moved to are made the tip. A chain with headers missing,
+    /// or one of its newest max_blocks_loaded blocks unreadable, is refused with
+    /// ChainOpenError::Damaged, see `recover_damaged`
+    pub fn open_network(path: &str, network: &str) -> Result<Blockchain> {
+        let bc = Blockchain::open_tip(path, network)?;
+        let health = bc.check_headers(SETTINGS.max_blocks_loaded)?;
+        if !health.is_intact() {
+            return Err(ChainOpenError::Damaged(health).into());
+        }
+        Ok(bc)
+    }
+
+    // `open_network` without the integrity pass
+    fn open_tip(path: &str, network: &str) -> Result<Blockchain> {
+        crate::maintenance::recover(path)?;
+        let db = sled::open(path)?;
+        schema::upgrade(&db)?;
+
+        let problem = match db.get("LAST")? {
+            Some(last) => match String::from_utf8(last.to_vec()) {
+                Ok(hash) if db.contains_key(&hash)? => {
+                    let unapplied = Blockchain::unapplied_blocks(&db, &hash)?;
+                    let Some(top) = unapplied.last() else {
+                        return Ok(Blockchain::from_db(hash, db));
+                    };
+                    let problem = ChainOpenError::UnappliedBlocks { tip: hash, blocks: unapplied.len() };
+                    error!("Block database is inconsistent: {}", problem);
+                    let tip = Blockchain::record_repair(&db, problem, top)?;
+                    return Ok(Blockchain::from_db(tip, db));
+                }
+                Ok(hash) => ChainOpenError::DanglingTip { tip: hash },
+                Err(_) => ChainOpenError::DanglingTip { tip: hex::encode(&last) },
+            },
+            None => match Blockchain::stored_blocks(&db)?.len() {
+                // If no blocks exist, create the genesis block.
+                0 => {
+                    let tip = Blockchain::create_genesis_block(&db, network)?;
+                    return Ok(Blockchain::from_db(tip, db));
+                }
+                blocks => ChainOpenError::MissingTip { blocks },
+            },
         };
 
-        Ok(Blockchain { tip: lasthash, db })
+        error!("Block database is inconsistent: {}", problem);
+        let tip = Blockchain::repair_tip(&db, problem)?;
+        Ok(Blockchain::from_db(tip, db))
+    }
+
+    fn from_db(tip: String, db: sled::Db) -> Blockchain {
+        Blockchain { tip, db, fixed_checkpoints: checkpoint::fixed_checkpoints() }
     }
 
-    /// Creates the genesis block with a fixed coinbase transaction.
+    /// Stores the network's genesis block, see `network_genesis`.
     /// Only used when an existing db isn't located on device
-    fn create_genesis_block(db: &sled::Db) -> Result<String> {
-        let fixed_address = "35yLCpZy2MzPzyngA3YstWbyDhyhzjXBcw".to_string();
-        let cbtx = Transaction::new_coinbase(fixed_address, "Genesis Block Reward".to_string())?;
-        let genesis = Block::new_genesis_block(cbtx);
-
-        // Insert the genesis block into the database.
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
+    fn create_genesis_block(db: &sled::Db, network: &str) -> Result<String> {
+        let genesis = network_genesis(network)?;
+
+        // The block and LAST go in together, a crash can't leave one without the other
+        let mut batch = sled::Batch::default();
+        batch.insert(genesis.get_hash().as_bytes(), bincode::serialize(&genesis)?);
+        batch.insert("LAST", genesis.get_hash().as_bytes());
+        schema::mark_current(&mut batch);
+        db.apply_batch(batch)?;
+        Blockchain::store_header(db, genesis.header())?;
         db.flush()?;
 
         Ok( genesis.get_hash() )
     }
+
+    // Every readable block in the db. Block keys are their hex hashes, the other keys are
+    // named. Unreadable ones on the active chain are left to `check_integrity`
+    fn stored_blocks(db: &sled::Db) -> Result<Vec<Block>> {
+        let mut blocks = Vec::new();
+        for kv in db.iter() {
+            let (key, value) = kv?;
+            if key.len() == 64 && key.iter().all(u8::is_ascii_hexdigit) {
+                match bincode::deserialize(&value) {
+                    Ok(block) => blocks.push(block),
+                    Err(e) => error!("Block {} can't be read: {}", String::from_utf8_lossy(&key), e),
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    // Points LAST at the single highest block and records the repair
+    fn repair_tip(db: &sled::Db, problem: ChainOpenError) -> Result<String> {
+        let blocks = Blockchain::stored_blocks(db)?;
+        let Some(height) = blocks.iter().map(|b| b.get_height()).max() else {
+            return Err(problem.into()); // nothing to rebuild from
+        };
+        let highest: Vec<&Block> = blocks.iter().filter(|b| b.get_height() == height).collect();
+        if highest.len() > 1 {
+            return Err(ChainOpenError::AmbiguousTip { problem: problem.to_string(), height, candidates: highest.len() }.into());
+        }
+
+        Blockchain::record_repair(db, problem, highest[0])
+    }
+
+    // Blocks stored on top of `tip`, lowest first, that LAST never followed: what a crash
+    // between storing a block and moving LAST left behind before both went in one batch.
+    // A block is only stored above the tip when it becomes the tip, so these are never a
+    // side branch. Stops where a block has more than one child
+    fn unapplied_blocks(db: &sled::Db, tip: &str) -> Result<Vec<Block>> {
+        let mut children: HashMap<String, Vec<Block>> = HashMap::new();
+        for block in Blockchain::stored_blocks(db)? {
+            children.entry(block.get_prev_hash()).or_default().push(block);
+        }
+        let mut unapplied = Vec::new();
+        let mut hash = tip.to_string();
+        while let Some(mut blocks) = children.remove(&hash).filter(|blocks| blocks.len() == 1) {
+            let block = blocks.remove(0);
+            hash = block.get_hash();
+            unapplied.push(block);
+        }
+        Ok(unapplied)
+    }
+
+    // Points LAST at `tip` and records the repair
+    fn record_repair(db: &sled::Db, problem: ChainOpenError, tip: &Block) -> Result<String> {
+        let (tip, height) = (tip.get_hash(), tip.get_height());
+        let repair = ChainRepair { timestamp: clock::now_millis(), problem: problem.to_string(), tip: tip.clone(), height };
+        println!("Repaired the block database: {}", repair);
+        let mut batch = sled::Batch::default();
+        batch.insert("LAST", tip.as_bytes());
+        batch.insert(REPAIR_KEY, bincode::serialize(&repair)?);
+        db.apply_batch(batch)?;
+        db.flush()?;
+        Ok(tip)
+    }
+
+    /// The repair made when the chain was last opened, if it wasn't shown yet. Removes it
+    pub fn take_repair(&self) -> Result<Option<ChainRepair>> {
+        match self.db.remove(REPAIR_KEY)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the chain at `path`, refused as ChainOpenError::Damaged, opening again: Resync
+    /// deletes it and TruncateToLastGood cuts it back, see `truncate_damaged`. Either way the
+    /// node fetches the missing blocks from peers once it starts. Recorded in `audit`
+    pub fn recover_damaged(path: &str, recovery: ChainRecovery, audit: &AuditLog) -> Result<()> {
+        let (action, result) = match recovery {
+            ChainRecovery::Resync => {
+                println!("Deleting the damaged chain at {} to download it again", path);
+                (AuditAction::RecreateChain, std::fs::remove_dir_all(path).map(|_| 0).map_err(failure::Error::from))
+            }
+            ChainRecovery::TruncateToLastGood => {
+                let result = Blockchain::open_tip(path, &SETTINGS.network).and_then(|mut bc| bc.truncate_damaged());
+                (AuditAction::Rollback, result.map(|removed| removed.len()))
+            }
+        };
+        let mut params = vec![(String::from("path"), path.to_string()), (String::from("reason"), String::from("damaged blocks"))];
+        if let Ok(removed) = &result {
+            params.push((String::from("blocks removed"), removed.to_string()));
+        }
+        audit.record(action, params, AuditOutcome::from(&result));
+        result.map(|_| ())
+    }
     
     // In theory, rarely used
     /*
@@ -77,32 +487,51 @@ impl Blockchain {
                 .temporary(true) // Creates an in-memory database
                 .open()
                 .expect("Failed to create an in-memory database"),
+            fixed_checkpoints: checkpoint::fixed_checkpoints(),
         }
     }
     /// Creates blockchain with a specific address as the rewardee for genesis block reward
     /// For Custom implementations only
     pub fn create_blockchain(address: String) -> Result<Blockchain> {
+        Blockchain::create_at(BLOCKS_PATH, address, &AuditLog::default())
+    }
+
+    /// `create_blockchain` at `path`, recorded in `audit` since it wipes what was there
+    pub fn create_at(path: &str, address: String, audit: &AuditLog) -> Result<Blockchain> {
+        let params = vec![
+            (String::from("path"), path.to_string()),
+            (String::from("genesis address"), audit::short_address(&address)),
+        ];
+        let result = Blockchain::recreate(path, address);
+        audit.record(AuditAction::RecreateChain, params, AuditOutcome::from(&result));
+        result
+    }
+
+    fn recreate(path: &str, address: String) -> Result<Blockchain> {
         println!("Creating new blockchain");
 
-        std::fs::remove_dir_all("data/blocks").ok();
-        let db = sled::open("data/blocks")?;
+        std::fs::remove_dir_all(path).ok();
+        crate::maintenance::recover(path)?;
+        let db = sled::open(path)?;
         debug!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA), 0)?;
         let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = Blockchain {
-            tip: genesis.get_hash(),
-            db,
-        };
+        let mut batch = sled::Batch::default();
+        batch.insert(genesis.get_hash().as_bytes(), bincode::serialize(&genesis)?);
+        batch.insert("LAST", genesis.get_hash().as_bytes());
+        schema::mark_current(&mut batch);
+        db.apply_batch(batch)?;
+        Blockchain::store_header(&db, genesis.header())?;
+        let bc = Blockchain::from_db(genesis.get_hash(), db);
         bc.db.flush()?;
         Ok(bc)
     } 
 
     // ------------- UTXOs -------------
- 
-    // Function for finding all the unspent transactions
-    fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
+
+    /// Scans the whole chain for transactions that still have unspent outputs locked to `address` (a pub key hash).
+    /// Slow, prefer the UTXO set for anything but diagnostics.
+    pub fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
         let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
         let mut unspent_txs: Vec<Transaction> = Vec::new();
 
@@ -143,58 +572,90 @@ impl Blockchain {
     }
 
     // Function for finding UTXOs in transactions
-    pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
-
+    // Fails if the chain can't be walked down to the genesis block
+    pub fn find_utxo(&self) -> Result<HashMap<String, UnspentOutputs>> {
+        let mut utxos: HashMap<String, UnspentOutputs> = HashMap::new();
+        for entry in self.unspent_transactions(&self.tip)? {
+            let outputs = entry.unspent.iter().map(|index| (*index, entry.tx.vout[*index as usize].clone())).collect();
+            utxos.insert(entry.tx.id, UnspentOutputs { outputs });
+        }
+        Ok(utxos)
+    }
 
-        for block in self.iter() {
-            for tx in block.get_transactions() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spent_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
+    /// Digest of the header chain and UTXO set at the active chain's block at `height`, see
+    /// state_digest.rs. Heights below a snapshot base have no block to compute it at
+    pub fn compute_state_digest(&self, height: i32) -> Result<StateDigest> {
+        let hash = self.get_block_by_height(height)?.get_hash();
+        Ok(StateDigest::compute(height, &hash, &self.unspent_transactions(&hash)?))
+    }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
-                    }                    
-                }     
+    /// Transactions with unspent outputs as of block `from`, with the indexes of those outputs
+    pub fn unspent_transactions(&self, from: &str) -> Result<Vec<SnapshotEntry>> {
+        let mut unspent: Vec<SnapshotEntry> = Vec::new();
+        let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
 
-                if !tx.is_coinbase() {
-                    for i in &tx.vin {
-                        match spent_txos.get_mut(&i.txid) {
-                            Some(v) => {
-                                v.push(i.vout);
-                            }
-                            None => {
-                                spent_txos.insert(i.txid.clone(), vec![i.vout]);
-                            }
-                        }
-                    }
+        let mut blocks = self.iter_from(from);
+        for block in &mut blocks {
+            // spends first, a transaction may spend the outputs of an earlier one in its block
+            for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+                for i in &tx.vin {
+                    spent_txos.entry(i.txid.clone()).or_default().push(i.vout);
+                }
+            }
+            for tx in block.get_transactions() {
+                let spent = spent_txos.get(&tx.id);
+                let indexes: Vec<i32> = (0..tx.vout.len() as i32)
+                    .filter(|index| spent.is_none_or(|ids| !ids.contains(index)))
+                    .collect();
+                if !indexes.is_empty() {
+                    unspent.push(SnapshotEntry { tx: tx.clone(), unspent: indexes });
                 }
             }
         }
-        
-        utxos
+        blocks.finish()?;
+
+        // Below the base of a snapshot-synced chain, the snapshot holds what's left
+        for kv in self.db.open_tree(SNAPSHOT_TREE)?.iter() {
+            let (_, v) = kv?;
+            let mut entry: SnapshotEntry = bincode::deserialize(&v)?;
+            if let Some(ids) = spent_txos.get(&entry.tx.id) {
+                entry.unspent.retain(|index| !ids.contains(index));
+            }
+            if !entry.unspent.is_empty() {
+                unspent.push(entry);
+            }
+        }
+
+        Ok(unspent)
+    }
+
+    pub fn iter(&self) -> BlockchainIter<'_> {
+        self.iter_from(&self.tip)
+    }
+
+    /// Walks down from block `hash`
+    pub fn iter_from(&self, hash: &str) -> BlockchainIter<'_> {
+        BlockchainIter { headers: self.headers_from(hash) }
     }
 
+    pub fn headers(&self) -> HeaderIter<'_> {
+        self.headers_from(&self.tip)
+    }
 
-    pub fn iter(&self) -> BlockchainIter {
-        BlockchainIter {
-            current_hash: self.tip.clone(),
-            bc: &self,
+    /// Walks the headers down from block `hash`
+    pub fn headers_from(&self, hash: &str) -> HeaderIter<'_> {
+        let base = match self.db.get(SNAPSHOT_BASE_KEY) {
+            Ok(base) => base.map(|hash| String::from_utf8_lossy(&hash).into_owned()),
+            Err(e) => {
+                error!("Failed to read the snapshot base: {}", e);
+                None
+            }
+        };
+        HeaderIter {
+            current_hash: hash.to_string(),
+            base,
+            bc: self,
+            error: None,
         }
     }
 
@@ -202,20 +663,89 @@ impl Blockchain {
 
     // finds a transaction by its ID
     pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
-        for b in self.iter() {
+        self.find_transaction_from(&self.tip, id)
+    }
+
+    /// Like `find_transaction`, in the chain ending at block `from`
+    pub fn find_transaction_from(&self, from: &str, id: &str) -> Result<Transaction> {
+        let mut blocks = self.iter_from(from);
+        for b in &mut blocks {
             for tx in b.get_transactions() {
                 if tx.id == id {
                     return Ok(tx.clone());
                 }
             }
         }
-        Err(format_err!("Transaction is not found"))
+        blocks.finish()?;
+
+        // Transactions below a snapshot base are only known if they had unspent outputs
+        if let Some(data) = self.db.open_tree(SNAPSHOT_TREE)?.get(id)? {
+            let entry: SnapshotEntry = bincode::deserialize(&data)?;
+            return Ok(entry.tx);
+        }
+        Err(LookupError::TxNotFound(id.to_string()).into())
+    }
+
+    /// The transaction holding output `txid:vout`, None when there's no such output or a block spent it
+    pub fn find_unspent(&self, txid: &str, vout: i32) -> Result<Option<Transaction>> {
+        self.find_unspent_from(&self.tip, txid, vout)
+    }
+
+    /// Like `find_unspent`, in the chain ending at block `from`
+    pub fn find_unspent_from(&self, from: &str, txid: &str, vout: i32) -> Result<Option<Transaction>> {
+        let mut blocks = self.iter_from(from);
+        for b in &mut blocks {
+            // a spend in the same block as the output comes after it, so look for spends first
+            let spent = b.get_transactions().iter()
+                .filter(|tx| !tx.is_coinbase())
+                .any(|tx| tx.vin.iter().any(|vin| vin.txid == txid && vin.vout == vout));
+            if spent {
+                return Ok(None);
+            }
+            if let Some(tx) = b.get_transactions().iter().find(|tx| tx.id == txid) {
+                let exists = usize::try_from(vout).is_ok_and(|index| index < tx.vout.len());
+                return Ok(exists.then(|| tx.clone()));
+            }
+        }
+        blocks.finish()?;
+
+        if let Some(data) = self.db.open_tree(SNAPSHOT_TREE)?.get(txid)? {
+            let entry: SnapshotEntry = bincode::deserialize(&data)?;
+            if entry.unspent.contains(&vout) {
+                return Ok(Some(entry.tx));
+            }
+        }
+        Ok(None)
     }
 
     fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        self.get_prev_txs_from(&self.tip, tx)
+    }
+
+    fn get_prev_txs_from(&self, from: &str, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_txs = HashMap::new();
         for vin in &tx.vin {
-            let prev_tx = self.find_transaction(&vin.txid)?;
+            let prev_tx = self.find_transaction_from(from, &vin.txid)?;
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        Ok(prev_txs)
+    }
+
+    // Previous transactions of `tx` as spent by a block on top of `from` after the
+    // transactions of `block`: each input must spend an output that's unspent on that branch
+    // or one of theirs, and that none of them spent
+    fn get_unspent_prev_txs_from(&self, from: &str, tx: &Transaction, block: &BlockSpends) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.vin {
+            let unspent = if block.spent.contains(&(vin.txid.clone(), vin.vout)) {
+                None
+            } else if let Some(parent) = block.txs.get(&vin.txid) {
+                let exists = usize::try_from(vin.vout).is_ok_and(|index| index < parent.vout.len());
+                exists.then(|| parent.clone())
+            } else {
+                self.find_unspent_from(from, &vin.txid, vin.vout)?
+            };
+            let prev_tx = unspent.ok_or_else(|| UtxoError::MissingUtxo { txid: vin.txid.clone(), vout: vin.vout })?;
             prev_txs.insert(prev_tx.id.clone(), prev_tx);
         }
         Ok(prev_txs)
@@ -228,13 +758,44 @@ impl Blockchain {
         Ok(())
     }
 
-     /// VerifyTransaction verifies transaction input signatures
+    /// Signs inputs spending outputs of several wallets, `keys` maps pub_key_hash to secret key
+    pub fn sign_transaction_with_keys(&self, tx: &mut Transaction, keys: &HashMap<Vec<u8>, Vec<u8>>) -> Result<()> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.sign_with_keys(keys, prev_txs)
+    }
+
+     /// VerifyTransaction verifies transaction input signatures, and that each input's key
+     /// owns the output it spends (an `OwnershipError` otherwise)
      pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool> {
+        self.verify_transaction_from(&self.tip, tx)
+    }
+
+    /// Whether `tx` may follow `in_block` in a block on the tip, as `mine_block` checks it:
+    /// it spends unspent outputs of the chain or of `in_block` that none of those spent, keeps
+    /// to the rules of `transaction::validate` and its signatures verify
+    pub fn verify_in_block(&self, tx: &Transaction, in_block: &[Transaction]) -> Result<bool> {
         if tx.is_coinbase() {
             return Ok(true);
         }
-        let prev_txs = self.get_prev_txs(tx)?;
-        tx.verify(prev_txs)
+        let mut block = BlockSpends::default();
+        for earlier in in_block {
+            block.add(earlier);
+        }
+        let prev_txs = self.get_unspent_prev_txs_from(&self.tip, tx, &block)?;
+        if transaction::validate(tx, &prev_txs, TxContext::Block).is_err() {
+            return Ok(false);
+        }
+        tx.verify_at(prev_txs, self.get_best_height()? + 1)
+    }
+
+    // Verifies against the chain ending at block `from`, which may be a side branch
+    fn verify_transaction_from(&self, from: &str, tx: &Transaction) -> Result<bool> {
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
+        let prev_txs = self.get_prev_txs_from(from, tx)?;
+        // as it would be in the next block
+        tx.verify_at(prev_txs, self.get_block(from)?.get_height() + 1)
     }
 
     // ------------- BLOCKS -------------
@@ -243,116 +804,2347 @@ impl Blockchain {
      pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
-        // Verifies transactions
+        // updates what the last hash is
+        let lasthash = String::from_utf8(self.db.get("LAST")?.ok_or(LookupError::NoTip)?.to_vec())?;
+        let parent = self.get_block(&lasthash)?;
+        let height = parent.get_height() + 1;
+
+        // Verifies transactions, in order: they may spend the outputs of earlier ones, no two
+        // of them the same output
+        let mut block = BlockSpends::default();
         for tx in &transactions {
-            if !self.verify_transacton(tx)? {
+            let prev_txs = if tx.is_coinbase() { HashMap::new() } else { self.get_unspent_prev_txs_from(&lasthash, tx, &block)? };
+            if let Err(rule) = transaction::validate(tx, &prev_txs, TxContext::Block) {
+                return Err(format_err!("ERROR: Invalid transaction {}: {}", tx.id, rule));
+            }
+            if !tx.is_coinbase() && !tx.verify_at(prev_txs, height)? {
                 return Err(format_err!("ERROR: Invalid transaction"));
             }
+            block.add(tx);
+        }
+        if let Some(coinbase) = transactions.iter().find(|tx| tx.is_coinbase() && tx.coinbase_height() != Some(height)) {
+            return Err(format_err!("ERROR: Coinbase {} isn't made for height {}", coinbase.id, height));
         }
 
-        // updates what the last hash is
-        let lasthash = self.db.get("LAST")?.unwrap();
-
-        let newblock = Block::new_block(
+        // a clock behind the last blocks would give a timestamp peers refuse
+        let timestamp = clock::now_millis().max(self.median_time_past(&lasthash)? + 1);
+        let newblock = Block::new_block_at(
             transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            self.get_best_height()? + 1,
+            lasthash,
+            height,
+            self.next_target(Some(&parent))?,
+            timestamp,
         )?;
+        // peers would refuse it, see mempool::fill_block
+        if newblock.size()? > MAX_BLOCK_SIZE {
+            return Err(format_err!("ERROR: Block of {} bytes is larger than MAX_BLOCK_SIZE", newblock.size()?));
+        }
 
-        // k: hash, v: serialized
-        // k: last, v: hash
-        self.db.insert(newblock.get_hash(), bincode::serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
-
-        self.tip = newblock.get_hash();
+        let work = self.chain_work(&parent.get_hash())? + block_work(newblock.get_target());
+        self.store_block(&newblock, true)?;
+        self.store_work(&newblock.get_hash(), work)?;
+        self.follow_heights(&[], std::slice::from_ref(&newblock));
+        self.follow_stats(&[], std::slice::from_ref(&newblock));
+        self.follow_owned_txs(&[], std::slice::from_ref(&newblock));
+        self.follow_sent_txs(&[], std::slice::from_ref(&newblock));
         Ok(newblock)
     }
 
 
+    /// Stores a block after `validate_block`, and makes it the tip when it's the highest
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        let data = bincode::serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
+        self.handle_potential_reorg(block).map(|_| ())
+    }
+
+    /// Like `add_block`, and tells whether the tip moved and which blocks left and joined the
+    /// best chain when the block's branch overtook the tip's
+    pub fn handle_potential_reorg(&mut self, block: Block) -> Result<ReorgOutcome> {
+        if self.has_block(&block.get_hash())? {
+            return Ok(ReorgOutcome::Stored);
         }
-        self.db.insert(block.get_hash(), data)?;
+        self.validate_block(&block)?;
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.tip = block.get_hash();
+        // The branch with the most work wins, the first one seen on a tie
+        let work = self.chain_work(&block.get_prev_hash())? + block_work(block.get_target());
+        let outcome = if work <= self.get_best_work()? {
+            ReorgOutcome::Stored
+        } else if block.get_prev_hash() == self.tip {
+            ReorgOutcome::Extended
+        } else {
+            let (fork_height, disconnected, connected) = self.fork_branches(&block)?;
+            info!("Reorganization at height {}: {} blocks disconnected, {} connected", fork_height, disconnected.len(), connected.len());
+            ReorgOutcome::Reorganized { fork_height, disconnected, connected }
+        };
+
+        self.store_block(&block, !matches!(outcome, ReorgOutcome::Stored))?;
+        self.store_work(&block.get_hash(), work)?;
+        match &outcome {
+            ReorgOutcome::Stored => {}
+            ReorgOutcome::Extended => {
+                self.follow_heights(&[], std::slice::from_ref(&block));
+                self.follow_stats(&[], std::slice::from_ref(&block));
+                self.follow_owned_txs(&[], std::slice::from_ref(&block));
+                self.follow_sent_txs(&[], std::slice::from_ref(&block));
+            }
+            ReorgOutcome::Reorganized { disconnected, connected, .. } => {
+                self.follow_heights(disconnected, connected);
+                self.follow_stats(disconnected, connected);
+                self.follow_owned_txs(disconnected, connected);
+                self.follow_sent_txs(disconnected, connected);
+            }
+        }
+        Ok(outcome)
+    }
+
+    // Writes `block` (k: hash, v: serialized) and, when it becomes the tip, LAST (k: last,
+    // v: hash) in one batch, so a crash can't store a block the tip doesn't know about. Its
+    // header follows, a crash before that leaves it to `get_header`. The height and wallet
+    // history indexes follow afterwards and rebuild themselves when they fall behind, the
+    // UTXO set is reindexed when the node starts
+    fn store_block(&mut self, block: &Block, make_tip: bool) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        batch.insert(block.get_hash().as_bytes(), bincode::serialize(block)?);
+        if make_tip {
+            batch.insert("LAST", block.get_hash().as_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        Blockchain::store_header(&self.db, block.header())?;
+        if make_tip {
             self.db.flush()?;
+            self.tip = block.get_hash();
         }
         Ok(())
     }
 
-    // GetBlock finds a block by its hash and returns it
-    pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = bincode::deserialize(&data.to_vec())?;
-        Ok(block)
+    // Headers only go in after their blocks and out before them, so there's never a header
+    // of a block that was neither stored nor pruned
+    fn store_header(db: &sled::Db, header: &BlockHeader) -> Result<()> {
+        db.open_tree(HEADERS_TREE)?.insert(header.hash.as_bytes(), bincode::serialize(header)?)?;
+        Ok(())
     }
 
-     /// get_best_height returns the height of the latest block
-     pub fn get_best_height(&self) -> Result<i32> {
-        let lasthash = if let Some(h) = self.db.get("LAST")? {
-            h
-        } else {
-            return Ok(-1);
-        };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = bincode::deserialize(&last_data.to_vec())?;
-        Ok(last_block.get_height())
-    }
+    // Walks down from the tip and from `block` (not stored yet) to the block both branches
+    // share, returns its height and the blocks above it on either side
+    fn fork_branches(&self, block: &Block) -> Result<(i32, Vec<Block>, Vec<Block>)> {
+        let mut disconnected = Vec::new();
+        let mut connected = vec![block.clone()];
+        let mut old = self.get_block(&self.tip)?;
+        let mut new_hash = block.get_prev_hash();
 
-    pub fn get_block_hashes(&self) -> Vec<String> {
-        let mut list = Vec::new();
-        for b in self.iter() {
-            list.push(b.get_hash());
+        while old.get_hash() != new_hash {
+            let new = self.get_block(&new_hash)
+                .map_err(|_| format_err!("Block {} shares no ancestor with the tip", block.get_hash()))?;
+            if new.get_height() >= old.get_height() {
+                new_hash = new.get_prev_hash();
+                connected.push(new);
+            } else {
+                let prev_hash = old.get_prev_hash();
+                disconnected.push(old);
+                old = self.get_block(&prev_hash)
+                    .map_err(|_| format_err!("Block {} shares no ancestor with the tip", block.get_hash()))?;
+            }
         }
-        list
+        connected.reverse();
+        Ok((old.get_height(), disconnected, connected))
     }
 
+    /// Rolls back every block above `height`, see `rollback`
+    pub fn rollback_to_height(&mut self, height: i32) -> Result<Vec<Block>> {
+        let best = self.get_best_height()?;
+        if !(0..=best).contains(&height) {
+            return Err(format_err!("Can't roll back to height {}, the tip is at height {}", height, best));
+        }
+        self.rollback((best - height) as usize)
+    }
 
-}
-
-impl<'a> Iterator for BlockchainIter<'a> {
-    type Item = Block;
+    /// Disconnects and deletes the top `n` blocks, for getting rid of a bad block without
+    /// starting over. Returns them newest first, like the disconnected branch of a
+    /// reorganization. The genesis block can't be rolled back, nor blocks whose parent isn't
+    /// stored (below a snapshot base or the pruned height). The UTXO set isn't touched, see
+    /// `Server::rollback`
+    pub fn rollback(&mut self, n: usize) -> Result<Vec<Block>> {
+        let height = self.get_best_height()?;
+        if n as i64 > height as i64 {
+            return Err(format_err!("Can't roll back {} blocks, the tip is at height {} and the genesis block stays", n, height));
+        }
+        let mut removed = Vec::with_capacity(n);
+        let mut tip = self.tip.clone();
+        for _ in 0..n {
+            let block = self.get_block(&tip)?;
+            tip = block.get_prev_hash();
+            removed.push(block);
+        }
+        if n > 0 && !self.db.contains_key(&tip)? {
+            return Err(format_err!("Can't roll back to height {}, that block isn't stored", height - n as i32));
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encode_block) = self.bc.db.get(&self.current_hash){
-            return match encode_block {
-                Some(b) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
-                        self.current_hash = block.get_prev_hash();
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None
-            };
+        let headers = self.db.open_tree(HEADERS_TREE)?;
+        for block in &removed {
+            headers.remove(block.get_hash().as_bytes())?;
         }
-        None
+        // Deleted along with moving LAST, or opening the chain would make them the tip again
+        let mut batch = sled::Batch::default();
+        batch.insert("LAST", tip.as_bytes());
+        for block in &removed {
+            batch.remove(block.get_hash().as_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        let work = self.db.open_tree(WORK_TREE)?;
+        for block in &removed {
+            work.remove(block.get_hash().as_bytes())?;
+        }
+        self.tip = tip;
+        self.follow_heights(&removed, &[]);
+        self.follow_stats(&removed, &[]);
+        self.follow_owned_txs(&removed, &[]);
+        self.follow_sent_txs(&removed, &[]);
+        self.db.flush()?;
+        info!("Rolled back {} blocks to height {}", n, height - n as i32);
+        Ok(removed)
     }
-}
 
+    /// Checks a block before it's stored: its proof of work, that it extends a stored block
+    /// at the next height (a genesis block extends nothing, and only starts a chain without
+    /// blocks) with the target due there, that
+    /// it's timestamped after the median time past and at most MAX_FUTURE_BLOCK_TIME ahead
+    /// of our clock, checkpoints, and the signatures of its transactions, that they spend
+    /// outputs unspent on its branch and none twice, and a coinbase paying no more than the
+    /// subsidy and fees. Signatures of blocks the latest checkpoint builds on aren't checked.
+    /// Rejections are returned as `BlockRejectReason`
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        self.check_block(block, self.tip.is_empty())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_add_block() {
-        //let mut b = Blockchain::create_blockchain().unwrap();
-        let b = Blockchain::new().unwrap();
+    // `validate_block`, a genesis block is only taken when `genesis_allowed`
+    fn check_block(&self, block: &Block, genesis_allowed: bool) -> Result<()> {
+        let hash = block.get_hash();
+        let size = block.size()?;
+        if size > MAX_BLOCK_SIZE {
+            return Err(BlockRejectReason::TooLarge { hash, size }.into());
+        }
+        if !block.verify_proof_of_work()? {
+            return Err(BlockRejectReason::BadProofOfWork { hash }.into());
+        }
+        if !block.verify_merkle_root()? {
+            return Err(BlockRejectReason::BadMerkleRoot { hash }.into());
+        }
 
-        /*b.add_block("Data".to_string());
-        b.add_block("Data2".to_string());
-        b.add_block("data33".to_string());*/
-        
-        for item in b.iter(){
-            println!("item {:?}", item);
+        let prev_hash = block.get_prev_hash();
+        let parent_height = if prev_hash.is_empty() && genesis_allowed {
+            -1
+        } else if prev_hash.is_empty() {
+            return Err(BlockRejectReason::UnknownParent { hash, prev_hash }.into());
+        } else if self.db.contains_key(&prev_hash)? {
+            self.get_block(&prev_hash)?.get_height()
+        } else {
+            return Err(BlockRejectReason::UnknownParent { hash, prev_hash }.into());
+        };
+        if block.get_height() != parent_height + 1 {
+            return Err(BlockRejectReason::BadHeight { hash, height: block.get_height(), parent_height }.into());
         }
-    }
-}
\ No newline at end of file
+        let parent = if prev_hash.is_empty() { None } else { Some(self.get_block(&prev_hash)?) };
+        let expected = self.next_target(parent.as_ref())?;
+        if block.get_target() != expected {
+            return Err(BlockRejectReason::BadTarget { hash, target: block.get_target(), expected }.into());
+        }
+        let timestamp = block.get_timestamp();
+        if !prev_hash.is_empty() {
+            let median = self.median_time_past(&prev_hash)?;
+            if timestamp <= median {
+                return Err(BlockRejectReason::TimeTooOld { hash, timestamp, median }.into());
+            }
+        }
+        if timestamp > clock::now_millis() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockRejectReason::TimeTooNew { hash, timestamp }.into());
+        }
+
+        if self.violates_checkpoint(block)? {
+            return Err(BlockRejectReason::CheckpointViolation { hash }.into());
+        }
+        let vouched = self.vouched_by_checkpoint(block)?;
+        if block.get_height() >= SETTINGS.coinbase_height_from
+            && block.get_transactions().iter().any(|tx| tx.is_coinbase() && tx.coinbase_height() != Some(block.get_height()))
+        {
+            return Err(BlockRejectReason::BadCoinbaseHeight { hash, height: block.get_height() }.into());
+        }
+        let mut fees = 0;
+        let mut spends = BlockSpends::default();
+        for tx in block.get_transactions() {
+            if tx.is_coinbase() {
+                spends.add(tx);
+                continue;
+            }
+            // inputs are looked up among the unspent outputs of the block's own branch and of
+            // the transactions before it in the block, one that's missing or already spent is
+            // as invalid as a bad signature
+            let Ok(prev_txs) = self.get_unspent_prev_txs_from(&prev_hash, tx, &spends) else {
+                return Err(BlockRejectReason::InvalidTransaction { hash, txid: tx.id.clone() }.into());
+            };
+            match transaction::validate(tx, &prev_txs, TxContext::Block) {
+                Ok(fee) if vouched || tx.verify_at(prev_txs, block.get_height()).unwrap_or(false) => fees += fee,
+                _ => return Err(BlockRejectReason::InvalidTransaction { hash, txid: tx.id.clone() }.into()),
+            }
+            spends.add(tx);
+        }
+        let value: i64 = block.get_transactions().iter()
+            .filter(|tx| tx.is_coinbase())
+            .flat_map(|tx| tx.vout.iter().map(|out| out.value as i64))
+            .sum();
+        let allowed = block_subsidy(block.get_height()) as i64 + fees;
+        if value > allowed {
+            return Err(BlockRejectReason::CoinbaseTooLarge { hash, value, allowed }.into());
+        }
+        Ok(())
+    }
+
+    /// Starts the chain over from a snapshot: `entries` is the UTXO state right after block
+    /// `base_hash` and `blocks` (oldest first) must build on it. Blocks below the base are
+    /// never looked at again, walks stop at the base.
+    pub fn apply_snapshot(&mut self, base_hash: &str, entries: Vec<SnapshotEntry>, blocks: Vec<Block>) -> Result<()> {
+        let mut prev_hash = base_hash.to_string();
+        let mut prev_height = None;
+        for block in &blocks {
+            if block.get_prev_hash() != prev_hash || prev_height.is_some_and(|h| block.get_height() != h + 1) {
+                return Err(format_err!("Snapshot block {} doesn't extend the chain before it", block.get_hash()));
+            }
+            if !block.verify_proof_of_work()? {
+                return Err(format_err!("Snapshot block {} has an invalid proof of work", block.get_hash()));
+            }
+            if !block.verify_merkle_root()? {
+                return Err(format_err!("Snapshot block {} doesn't match its merkle root", block.get_hash()));
+            }
+            if block.size()? > MAX_BLOCK_SIZE {
+                return Err(format_err!("Snapshot block {} is larger than a block may be", block.get_hash()));
+            }
+            if self.violates_checkpoint(block)? {
+                return Err(format_err!("Snapshot block {} conflicts with a checkpoint", block.get_hash()));
+            }
+            prev_hash = block.get_hash();
+            prev_height = Some(block.get_height());
+        }
+        let tip = blocks.last().ok_or_else(|| format_err!("Snapshot without blocks"))?.get_hash();
+
+        let tree = self.db.open_tree(SNAPSHOT_TREE)?;
+        tree.clear()?;
+        for entry in &entries {
+            tree.insert(entry.tx.id.as_bytes(), bincode::serialize(entry)?)?;
+        }
+        let mut batch = sled::Batch::default();
+        for block in &blocks {
+            batch.insert(block.get_hash().as_bytes(), bincode::serialize(block)?);
+        }
+        batch.insert(SNAPSHOT_BASE_KEY, base_hash.as_bytes());
+        batch.insert("LAST", tip.as_bytes());
+        self.db.apply_batch(batch)?;
+        for block in &blocks {
+            Blockchain::store_header(&self.db, block.header())?;
+        }
+        self.db.flush()?;
+
+        self.tip = tip;
+        Ok(())
+    }
+
+    /// Target a block on top of `parent` (None for a genesis block) must use. It's the
+    /// parent's, except every RETARGET_INTERVAL blocks where it's scaled by how long the
+    /// last window took against `expected_block_interval`
+    pub fn next_target(&self, parent: Option<&Block>) -> Result<u64> {
+        let Some(parent) = parent else {
+            return Ok(INITIAL_TARGET);
+        };
+        if (parent.get_height() + 1) % RETARGET_INTERVAL != 0 {
+            return Ok(parent.get_target());
+        }
+
+        let mut first = parent.header().clone();
+        for _ in 1..RETARGET_INTERVAL {
+            match self.get_header(&first.prev_block_hash)? {
+                Some(header) => first = header,
+                // the window reaches below a snapshot base, nothing to measure
+                None => return Ok(parent.get_target()),
+            }
+        }
+        let actual = parent.get_timestamp().saturating_sub(first.timestamp);
+        let expected = SETTINGS.expected_block_interval as u128 * 1000 * (RETARGET_INTERVAL - 1) as u128;
+        Ok(retarget(parent.get_target(), actual, expected))
+    }
+
+    /// Median timestamp of block `hash` and the MEDIAN_TIME_SPAN - 1 blocks below it, fewer
+    /// near the genesis block or a snapshot base. A block on top of `hash` must be
+    /// timestamped after it
+    pub fn median_time_past(&self, hash: &str) -> Result<u128> {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut next = self.get_header(hash)?.ok_or_else(|| LookupError::BlockNotFound(hash.to_string()))?;
+        loop {
+            timestamps.push(next.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN || next.prev_block_hash.is_empty() {
+                break;
+            }
+            match self.get_header(&next.prev_block_hash)? {
+                Some(header) => next = header,
+                None => break,
+            }
+        }
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    /// Whether the block is stored, or was and got pruned
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.db.contains_key(block_hash)? || self.db.open_tree(HEADERS_TREE)?.contains_key(block_hash)?)
+    }
+
+    /// Header of a stored or pruned block, read without the block. Blocks stored before
+    /// headers had a tree of their own get theirs written here when first asked for
+    pub fn get_header(&self, block_hash: &str) -> Result<Option<BlockHeader>> {
+        let headers = self.db.open_tree(HEADERS_TREE)?;
+        if let Some(data) = headers.get(block_hash)? {
+            return Ok(Some(bincode::deserialize(&data)?));
+        }
+        let Some(data) = self.db.get(block_hash)? else {
+            return Ok(None);
+        };
+        let header = bincode::deserialize::<Block>(&data)?.header().clone();
+        headers.insert(block_hash.as_bytes(), bincode::serialize(&header)?)?;
+        Ok(Some(header))
+    }
+
+    // GetBlock finds a block by its hash and returns it
+    pub fn get_block(&self, block_hash: &str) -> Result<Block> {
+        let data = self.db.get(block_hash)?
+            .ok_or_else(|| LookupError::BlockNotFound(block_hash.to_string()))?;
+        // deserialize straight from the sled buffer, no intermediate copy
+        let block = bincode::deserialize(&data)?;
+        Ok(block)
+    }
+
+     /// get_best_height returns the height of the latest block
+     pub fn get_best_height(&self) -> Result<i32> {
+        let lasthash = if let Some(h) = self.db.get("LAST")? {
+            h
+        } else {
+            return Ok(-1);
+        };
+        let lasthash = String::from_utf8_lossy(&lasthash).into_owned();
+        let last = self.get_header(&lasthash)?.ok_or(LookupError::BlockNotFound(lasthash))?;
+        Ok(last.height)
+    }
+
+    pub fn get_block_hashes(&self) -> Vec<String> {
+        self.headers().map(|header| header.hash).collect()
+    }
+
+    // ------------- CHAIN WORK -------------
+
+    /// Work of the chain ending at block `hash`, the sum of `block_work` over it and the
+    /// blocks below it. Work is stored as blocks are added, blocks from before it was tracked
+    /// get theirs filled in here. Below a snapshot base, whose blocks we never saw, every
+    /// block is counted with the target of the lowest one we have
+    pub fn chain_work(&self, hash: &str) -> Result<u128> {
+        let tree = self.db.open_tree(WORK_TREE)?;
+        let mut missing: Vec<BlockHeader> = Vec::new();
+        let mut next = hash.to_string();
+        let mut work = loop {
+            if let Some(data) = tree.get(&next)? {
+                break u128::from_be_bytes(data.as_ref().try_into()?);
+            }
+            match self.get_header(&next)? {
+                Some(header) => {
+                    next = header.prev_block_hash.clone();
+                    missing.push(header);
+                }
+                None => break missing.last().map_or(0, |lowest| lowest.height as u128 * block_work(lowest.target)),
+            }
+        };
+        for header in missing.iter().rev() {
+            work += block_work(header.target);
+            tree.insert(header.hash.as_bytes(), &work.to_be_bytes())?;
+        }
+        Ok(work)
+    }
+
+    /// Work of the active chain, 0 without blocks
+    pub fn get_best_work(&self) -> Result<u128> {
+        if self.tip.is_empty() {
+            return Ok(0);
+        }
+        self.chain_work(&self.tip)
+    }
+
+    fn store_work(&self, hash: &str, work: u128) -> Result<()> {
+        self.db.open_tree(WORK_TREE)?.insert(hash.as_bytes(), &work.to_be_bytes())?;
+        Ok(())
+    }
+
+    // ------------- HEIGHT INDEX -------------
+
+    /// Hash of the active chain's genesis block, None for a chain without one (empty or
+    /// started from a snapshot)
+    pub fn genesis_hash(&self) -> Result<Option<String>> {
+        match self.height_index()?.get(0u32.to_be_bytes())? {
+            Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The block of the active chain at `height`
+    pub fn get_block_by_height(&self, height: i32) -> Result<Block> {
+        let hash = match u32::try_from(height) {
+            Ok(key) => self.height_index()?.get(key.to_be_bytes())?,
+            Err(_) => None,
+        };
+        let hash = hash.ok_or_else(|| format_err!("No block at height {}", height))?;
+        self.get_block(&String::from_utf8(hash.to_vec())?)
+    }
+
+    /// Blocks of the active chain from height `from` to `to`, both included, newest first.
+    /// Heights below a snapshot base have no blocks and are skipped
+    pub fn get_blocks_range(&self, from: i32, to: i32) -> Result<Vec<Block>> {
+        let (from, to) = (from.max(0) as u32, to);
+        if to < from as i32 {
+            return Ok(Vec::new());
+        }
+        let mut blocks = Vec::new();
+        for kv in self.height_index()?.range(from.to_be_bytes()..=(to as u32).to_be_bytes()).rev() {
+            let (_, hash) = kv?;
+            blocks.push(self.get_block(&String::from_utf8(hash.to_vec())?)?);
+        }
+        Ok(blocks)
+    }
+
+    // The height index, rebuilt from a walk down from the tip when it isn't current with it
+    // (an older build added blocks, an update failed)
+    fn height_index(&self) -> Result<sled::Tree> {
+        let tree = self.db.open_tree(HEIGHTS_TREE)?;
+        if self.db.get(HEIGHTS_TIP_KEY)?.as_deref() != Some(self.tip.as_bytes()) {
+            info!("Height index is behind the tip, rebuilding");
+            tree.clear()?;
+            let mut walk = self.headers();
+            for header in &mut walk {
+                tree.insert((header.height as u32).to_be_bytes(), header.hash.as_bytes())?;
+            }
+            walk.finish()?;
+            self.db.insert(HEIGHTS_TIP_KEY, self.tip.as_bytes())?;
+        }
+        Ok(tree)
+    }
+
+    // Moves the height index along with the tip. Like `follow_owned_txs` failures are only
+    // logged, the index is rebuilt when next read
+    fn follow_heights(&self, disconnected: &[Block], connected: &[Block]) {
+        let result = self.db.open_tree(HEIGHTS_TREE).map_err(failure::Error::from).and_then(|tree| {
+            let old_tip = match disconnected.first() {
+                Some(block) => block.get_hash(),
+                None => connected.first().map(|block| block.get_prev_hash()).unwrap_or_default(),
+            };
+            if self.db.get(HEIGHTS_TIP_KEY)?.as_deref() != Some(old_tip.as_bytes()) {
+                return Ok(()); // already behind
+            }
+            for block in disconnected {
+                tree.remove((block.get_height() as u32).to_be_bytes())?;
+            }
+            for block in connected {
+                tree.insert((block.get_height() as u32).to_be_bytes(), block.get_hash().as_bytes())?;
+            }
+            self.db.insert(HEIGHTS_TIP_KEY, self.tip.as_bytes())?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Failed to update the height index: {}", e);
+        }
+    }
+
+    // ------------- STATS -------------
+
+    /// Totals of the active chain. The counters follow the tip as blocks connect and
+    /// disconnect, the chain is only walked when they're stale (first call, a snapshot was
+    /// loaded, an update failed). A snapshot-synced or pruned chain counts the transactions
+    /// and coins of the blocks it walked, plus the unspent outputs of its snapshot
+    pub fn stats(&self) -> Result<ChainStats> {
+        let counters = self.chain_counters()?;
+        let tip = self.get_block(&self.tip)?;
+        let height = tip.get_height();
+        let avg_block_interval = self.get_block_by_height((height - STATS_INTERVAL_BLOCKS).max(0)).ok()
+            .filter(|first| first.get_height() < height)
+            .map(|first| {
+                let spacing = tip.get_timestamp().saturating_sub(first.get_timestamp()) / (height - first.get_height()) as u128;
+                std::time::Duration::from_millis(spacing as u64)
+            });
+        Ok(ChainStats {
+            blocks: height as u64 + 1,
+            transactions: counters.transactions.max(0) as u64,
+            coins_issued: counters.coins_issued,
+            circulating_supply: circulating_supply(height),
+            utxos: counters.utxos.max(0) as u64,
+            avg_block_interval,
+            difficulty: INITIAL_TARGET as f64 / self.next_target(Some(&tip))? as f64,
+        })
+    }
+
+    // The stored counters, recounted from a walk down from the tip when they're stale
+    fn chain_counters(&self) -> Result<ChainCounters> {
+        if let Some(data) = self.db.get(STATS_KEY)? {
+            let counters: ChainCounters = bincode::deserialize(&data)?;
+            if counters.tip == self.tip {
+                return Ok(counters);
+            }
+        }
+        info!("Chain statistics are behind the tip, recounting");
+        let mut counters = ChainCounters { tip: self.tip.clone(), ..Default::default() };
+        let mut walk = self.iter();
+        for block in &mut walk {
+            counters.apply(&block, 1);
+        }
+        walk.finish()?;
+        for kv in self.db.open_tree(SNAPSHOT_TREE)?.iter() {
+            let (_, data) = kv?;
+            let entry: SnapshotEntry = bincode::deserialize(&data)?;
+            counters.utxos += entry.unspent.len() as i64;
+        }
+        self.db.insert(STATS_KEY, bincode::serialize(&counters)?)?;
+        Ok(counters)
+    }
+
+    // Moves the counters along with the tip, like `follow_heights`. Counters that are
+    // already behind are left for `chain_counters` to recount
+    fn follow_stats(&self, disconnected: &[Block], connected: &[Block]) {
+        let result = self.db.get(STATS_KEY).map_err(failure::Error::from).and_then(|data| {
+            let Some(data) = data else {
+                return Ok(()); // never counted
+            };
+            let mut counters: ChainCounters = bincode::deserialize(&data)?;
+            let old_tip = match disconnected.first() {
+                Some(block) => block.get_hash(),
+                None => connected.first().map(|block| block.get_prev_hash()).unwrap_or_default(),
+            };
+            if counters.tip != old_tip {
+                return Ok(());
+            }
+            for block in disconnected {
+                counters.apply(block, -1);
+            }
+            for block in connected {
+                counters.apply(block, 1);
+            }
+            counters.tip = self.tip.clone();
+            self.db.insert(STATS_KEY, bincode::serialize(&counters)?)?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Failed to update the chain statistics: {}", e);
+        }
+    }
+
+    // ------------- FEE ESTIMATES -------------
+
+    /// Suggested fee rate, in coins per 1000 bytes like DEFAULT_FEE_RATE, for a transaction
+    /// to be mined within `target_blocks` blocks. See Fee estimates
+    pub fn estimate_fee(&self, target_blocks: u32) -> i64 {
+        let rates = self.recent_fee_rates();
+        fee_rate_percentile(&rates, target_blocks).max(SETTINGS.min_relay_fee_rate)
+    }
+
+    // Fee rates the transactions of the last FEE_ESTIMATE_BLOCKS blocks paid, ascending.
+    // Transactions whose inputs can't be found (pruned, below a snapshot) are left out
+    fn recent_fee_rates(&self) -> Vec<i64> {
+        let blocks: Vec<Block> = self.iter().take(FEE_ESTIMATE_BLOCKS).collect();
+        let mut known: HashMap<String, Transaction> = blocks.iter()
+            .flat_map(|block| block.get_transactions().iter().map(|tx| (tx.id.clone(), tx.clone())))
+            .collect();
+
+        let mut rates = Vec::new();
+        for block in &blocks {
+            for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+                let inputs: Option<i64> = tx.vin.iter()
+                    .map(|vin| {
+                        if !known.contains_key(&vin.txid) {
+                            match self.find_transaction_from(&block.get_prev_hash(), &vin.txid) {
+                                Ok(prev_tx) => { known.insert(prev_tx.id.clone(), prev_tx); }
+                                Err(e) => debug!("No fee rate for {}: {}", tx.id, e),
+                            }
+                        }
+                        let prev_tx = known.get(&vin.txid)?;
+                        prev_tx.vout.get(usize::try_from(vin.vout).ok()?).map(|out| out.value as i64)
+                    })
+                    .sum();
+                let outputs: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+                let size = bincode::serialized_size(tx).unwrap_or(0) as i64;
+                if let Some(inputs) = inputs.filter(|inputs| *inputs >= outputs && size > 0) {
+                    rates.push((inputs - outputs) * 1000 / size);
+                }
+            }
+        }
+        rates.sort_unstable();
+        rates
+    }
+
+    // ------------- PRUNING -------------
+
+    /// Deletes the blocks of the active chain more than `keep_last_n` below the tip, keeping
+    /// their headers. The UTXO state below the remaining blocks goes to the snapshot tree, the
+    /// same way a snapshot-synced chain starts, so transactions spending old outputs are still
+    /// validated. Returns how many blocks were deleted
+    pub fn prune_to_height(&mut self, keep_last_n: u32) -> Result<usize> {
+        if keep_last_n < MIN_PRUNE_KEEP {
+            return Err(format_err!("Pruning must keep at least {} blocks", MIN_PRUNE_KEEP));
+        }
+        let base_height = self.get_best_height()? - keep_last_n as i32;
+        if base_height < self.pruned_height()?.unwrap_or(0) {
+            return Ok(0);
+        }
+        let Some(base) = self.height_index()?.get((base_height as u32).to_be_bytes())? else {
+            return Ok(0); // below the base of a snapshot
+        };
+        let base = String::from_utf8(base.to_vec())?;
+
+        // The new UTXO state and base go in first, a crash before the blocks are deleted
+        // only leaves some of them behind
+        let entries = self.unspent_transactions(&base)?;
+        let mut pruned = Vec::new();
+        let mut walk = self.headers_from(&base);
+        for header in &mut walk {
+            pruned.push(header);
+        }
+        walk.finish()?;
+
+        let snapshot = self.db.open_tree(SNAPSHOT_TREE)?;
+        snapshot.clear()?;
+        for entry in &entries {
+            snapshot.insert(entry.tx.id.as_bytes(), bincode::serialize(entry)?)?;
+        }
+        self.db.insert(SNAPSHOT_BASE_KEY, base.as_bytes())?;
+        self.db.insert(PRUNED_HEIGHT_KEY, &(base_height + 1).to_be_bytes())?;
+        self.db.flush()?;
+
+        let heights = self.height_index()?;
+        for header in &pruned {
+            self.db.remove(header.hash.as_bytes())?;
+            heights.remove((header.height as u32).to_be_bytes())?;
+        }
+        self.db.flush()?;
+        info!("Pruned {} blocks, blocks are stored from height {}", pruned.len(), base_height + 1);
+        Ok(pruned.len())
+    }
+
+    /// Lowest height whose block is stored when the chain was pruned, None when it never was
+    pub fn pruned_height(&self) -> Result<Option<i32>> {
+        match self.db.get(PRUNED_HEIGHT_KEY)? {
+            Some(data) => {
+                let bytes: [u8; 4] = data.as_ref().try_into().map_err(|_| format_err!("Corrupted pruned height"))?;
+                Ok(Some(i32::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // ------------- EXPORT / IMPORT -------------
+
+    /// Writes the chain, genesis block first, to a chain file at `path` (see chain_file).
+    /// `progress` gets the blocks written and the total. Returns how many were written
+    pub fn export_to_file(&self, path: &Path, progress: &mut dyn FnMut(usize, usize)) -> Result<usize> {
+        if self.db.contains_key(SNAPSHOT_BASE_KEY)? {
+            return Err(format_err!("The chain was pruned or started from a snapshot, its early blocks aren't stored"));
+        }
+        let mut hashes = Vec::new();
+        let mut walk = self.iter();
+        for block in &mut walk {
+            hashes.push(block.get_hash());
+        }
+        walk.finish()?;
+        hashes.reverse();
+
+        let digest = self.compute_state_digest(hashes.len() as i32 - 1)?;
+        let mut file = ChainFileWriter::create(path, hashes.len(), &digest)?;
+        for (written, hash) in hashes.iter().enumerate() {
+            file.write_block(&self.get_block(hash)?)?;
+            progress(written + 1, hashes.len());
+        }
+        file.finish()?;
+        Ok(hashes.len())
+    }
+
+    /// Adds the blocks of a chain file through `add_block`, blocks already stored are skipped.
+    /// The file has to start from our genesis block, unless our chain is nothing but a genesis
+    /// block: that one is replaced. A file whose blocks don't add up to its state digest is
+    /// refused before anything is added. `progress` gets the blocks read and the total.
+    /// The UTXO set isn't touched, the caller reindexes it
+    pub fn import_from_file(&mut self, path: &Path, progress: &mut dyn FnMut(usize, usize)) -> Result<ImportSummary> {
+        chain_file::verify_state_digest(path)?;
+        let mut file = ChainFileReader::open(path)?;
+        let total = file.block_count();
+        let genesis = file.next_block()?.ok_or_else(|| format_err!("The chain file holds no blocks"))?;
+        if genesis.get_height() != 0 || !genesis.get_prev_hash().is_empty() {
+            return Err(format_err!("The chain file doesn't start with a genesis block"));
+        }
+
+        let mut summary = ImportSummary::default();
+        if self.has_block(&genesis.get_hash())? {
+            summary.known += 1;
+        } else if self.get_best_height()? <= 0 {
+            self.replace_genesis(genesis)?;
+            summary.added += 1;
+        } else {
+            return Err(format_err!(
+                "The chain file starts from another genesis block ({}), only a node without blocks of its own can import it",
+                genesis.get_hash()
+            ));
+        }
+        progress(1, total);
+
+        while let Some(block) = file.next_block()? {
+            if self.has_block(&block.get_hash())? {
+                summary.known += 1;
+            } else {
+                self.add_block(block)?;
+                summary.added += 1;
+            }
+            progress(summary.added + summary.known, total);
+        }
+        Ok(summary)
+    }
+
+    // Swaps the genesis block of a chain that has nothing else for `genesis`. The indexes
+    // notice the new tip and rebuild themselves
+    fn replace_genesis(&mut self, genesis: Block) -> Result<()> {
+        self.check_block(&genesis, true)?;
+        self.db.open_tree(HEADERS_TREE)?.remove(self.tip.as_bytes())?;
+        let mut batch = sled::Batch::default();
+        batch.remove(self.tip.as_bytes());
+        batch.insert(genesis.get_hash().as_bytes(), bincode::serialize(&genesis)?);
+        batch.insert("LAST", genesis.get_hash().as_bytes());
+        self.db.apply_batch(batch)?;
+        Blockchain::store_header(&self.db, genesis.header())?;
+        self.db.flush()?;
+        self.tip = genesis.get_hash();
+        Ok(())
+    }
+
+    // ------------- AUDIT -------------
+
+    /// Reads every block from the tip down, following the headers so damaged blocks don't
+    /// end the walk. Each one is logged with its hash. Below a missing header there's no way
+    /// to go on, the report ends there
+    pub fn check_integrity(&self) -> Result<ChainHealth> {
+        self.check_headers(usize::MAX)
+    }
+
+    // `check_integrity` reading only the newest `bodies` blocks, below them the walk goes by
+    // the headers alone. Opening the chain reads the ones the app shows first, a damaged
+    // block deeper down turns up when it's read
+    fn check_headers(&self, bodies: usize) -> Result<ChainHealth> {
+        let mut health = ChainHealth { tip: self.tip.clone(), blocks_checked: 0, damaged: Vec::new(), last_good: None };
+        let mut next = self.tip.clone();
+        let mut walk = self.headers();
+        for header in &mut walk {
+            health.blocks_checked += 1;
+            next = header.prev_block_hash.clone();
+            if health.blocks_checked > bodies {
+                if health.last_good.is_none() {
+                    health.last_good = Some((header.height, header.hash));
+                }
+                continue;
+            }
+            let reason = match self.db.get(&header.hash)? {
+                None => Some(String::from("the block is missing")),
+                Some(data) => match bincode::deserialize::<Block>(&data) {
+                    Ok(block) if block.get_hash() == header.hash => None,
+                    Ok(block) => Some(format!("the block stored there is {}", block.get_hash())),
+                    Err(e) => Some(format!("the block can't be read: {}", e)),
+                },
+            };
+            match reason {
+                Some(reason) => {
+                    error!("Damaged block {} at height {}: {}", header.hash, header.height, reason);
+                    health.damaged.push(DamagedBlock { hash: header.hash, height: Some(header.height), reason });
+                    health.last_good = None;
+                }
+                None if health.last_good.is_none() => health.last_good = Some((header.height, header.hash)),
+                None => {}
+            }
+        }
+        if let Err(e) = walk.finish() {
+            error!("Damaged block {}: {}", next, e);
+            health.damaged.push(DamagedBlock { hash: next, height: None, reason: e.to_string() });
+            health.last_good = None;
+        }
+        Ok(health)
+    }
+
+    /// Deletes the blocks above the last good block of a damaged chain (see
+    /// `check_integrity`), readable or not, and makes it the tip. The indexes rebuild
+    /// themselves for the new tip, the UTXO set is reindexed when the node starts. Returns
+    /// the deleted hashes, tip first
+    pub fn truncate_damaged(&mut self) -> Result<Vec<String>> {
+        let health = self.check_integrity()?;
+        let Some((height, last_good)) = health.last_good else {
+            return Err(format_err!("{}, re-sync the chain instead", health));
+        };
+        let removed: Vec<String> = self.headers().map(|header| header.hash).take_while(|hash| *hash != last_good).collect();
+
+        // Headers out before their blocks, see `store_header`
+        let headers = self.db.open_tree(HEADERS_TREE)?;
+        for hash in &removed {
+            headers.remove(hash.as_bytes())?;
+        }
+        let mut batch = sled::Batch::default();
+        batch.insert("LAST", last_good.as_bytes());
+        for hash in &removed {
+            batch.remove(hash.as_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        let work = self.db.open_tree(WORK_TREE)?;
+        for hash in &removed {
+            work.remove(hash.as_bytes())?;
+        }
+        self.db.flush()?;
+        self.tip = last_good;
+        info!("Truncated the chain to block {} at height {}, {} blocks deleted", self.tip, height, removed.len());
+        Ok(removed)
+    }
+
+    /// Checks the stored chain from the tip down: every block is stored under its own hash,
+    /// has valid proof of work and the height below its child's, and the walk ends at the
+    /// genesis block (or the snapshot base of a pruned or snapshot-synced chain). Then, oldest
+    /// block first, that every input spends an existing output no earlier input spent, with a
+    /// valid signature. Stops at the first problem. Blocks only hold hashes of their
+    /// transactions, so the whole chain is read into memory
+    pub fn verify_chain(&self) -> Result<ChainAuditReport> {
+        let base = self.db.get(SNAPSHOT_BASE_KEY)?.map(|hash| String::from_utf8_lossy(&hash).into_owned());
+        let mut report = ChainAuditReport { blocks_checked: 0, transactions_checked: 0, problem: None };
+        let problem = |block: &str, height: i32, txid: Option<&str>, reason: String| AuditProblem {
+            block: block.to_string(),
+            height,
+            txid: txid.map(str::to_string),
+            reason,
+        };
+
+        // Structure, tip first
+        let mut blocks = Vec::new();
+        let mut hash = self.tip.clone();
+        let mut height = None; // the tip's is whatever it says
+        while !hash.is_empty() && base.as_ref() != Some(&hash) {
+            let block = match self.db.get(&hash)? {
+                None => Err(String::from("the block is missing")),
+                Some(data) => bincode::deserialize::<Block>(&data).map_err(|e| format!("the block can't be read: {}", e)),
+            };
+            let expected = height.unwrap_or_else(|| block.as_ref().map_or(-1, |block| block.get_height()));
+            let reason = match &block {
+                Err(reason) => Some(reason.clone()),
+                Ok(block) if block.get_hash() != hash => Some(format!("the stored block is {}", block.get_hash())),
+                Ok(block) if !block.verify_proof_of_work()? => Some(String::from("the proof of work doesn't match the block")),
+                Ok(block) if block.get_height() != expected => Some(format!("the block says height {}", block.get_height())),
+                Ok(block) if block.get_prev_hash().is_empty() && expected != 0 => Some(String::from("a genesis block above height 0")),
+                Ok(_) => None,
+            };
+            if let Some(reason) = reason {
+                report.problem = Some(problem(&hash, expected, None, reason));
+                return Ok(report);
+            }
+            let block = block.expect("checked above");
+            hash = block.get_prev_hash();
+            height = Some(expected - 1);
+            report.blocks_checked += 1;
+            blocks.push(block);
+        }
+
+        // Spends, oldest first. Below a snapshot base the snapshot holds what's unspent
+        let mut txs: HashMap<String, Transaction> = HashMap::new();
+        let mut spent: HashSet<(String, i32)> = HashSet::new();
+        for entry in self.db.open_tree(SNAPSHOT_TREE)?.iter() {
+            let entry: SnapshotEntry = bincode::deserialize(&entry?.1)?;
+            for vout in 0..entry.tx.vout.len() as i32 {
+                if !entry.unspent.contains(&vout) {
+                    spent.insert((entry.tx.id.clone(), vout));
+                }
+            }
+            txs.insert(entry.tx.id.clone(), entry.tx);
+        }
+        for block in blocks.iter().rev() {
+            for tx in block.get_transactions() {
+                report.transactions_checked += 1;
+                if !tx.is_coinbase() {
+                    let mut reason = None;
+                    let mut prev_txs = HashMap::new();
+                    for vin in &tx.vin {
+                        let output = txs.get(&vin.txid).filter(|prev| usize::try_from(vin.vout).is_ok_and(|index| index < prev.vout.len()));
+                        let Some(prev) = output else {
+                            reason = Some(format!("spends {}:{}, which doesn't exist", vin.txid, vin.vout));
+                            break;
+                        };
+                        if !spent.insert((vin.txid.clone(), vin.vout)) {
+                            reason = Some(format!("spends {}:{} again", vin.txid, vin.vout));
+                            break;
+                        }
+                        prev_txs.insert(prev.id.clone(), prev.clone());
+                    }
+                    if reason.is_none() && !tx.verify_at(prev_txs, block.get_height()).unwrap_or(false) {
+                        reason = Some(String::from("a signature is invalid"));
+                    }
+                    if let Some(reason) = reason {
+                        report.problem = Some(problem(&block.get_hash(), block.get_height(), Some(&tx.id), reason));
+                        return Ok(report);
+                    }
+                }
+                txs.insert(tx.id.clone(), tx.clone());
+            }
+        }
+        Ok(report)
+    }
+
+    // ------------- PEER HISTORY -------------
+
+    /// Appends a removed peer to the history, dropping the oldest entries past MAX_PEER_HISTORY
+    pub fn record_peer_removal(&self, entry: &PeerHistoryEntry) -> Result<()> {
+        let tree = self.db.open_tree(PEER_HISTORY_TREE)?;
+        tree.insert(self.db.generate_id()?.to_be_bytes(), bincode::serialize(entry)?)?;
+        while tree.len() > MAX_PEER_HISTORY {
+            tree.pop_min()?;
+        }
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Removed peers, newest first
+    pub fn get_peer_history(&self) -> Result<Vec<PeerHistoryEntry>> {
+        let mut entries = Vec::new();
+        for kv in self.db.open_tree(PEER_HISTORY_TREE)?.iter().rev() {
+            let (_, data) = kv?;
+            entries.push(bincode::deserialize(&data)?);
+        }
+        Ok(entries)
+    }
+
+    // ------------- WALLET HISTORY -------------
+
+    // Moves the owned transactions index along with the tip. Failures are only logged, the
+    // index falls behind the tip and `wallet_history` rebuilds it
+    fn follow_owned_txs(&self, disconnected: &[Block], connected: &[Block]) {
+        let result = OwnedTxIndex::open(&self.db).and_then(|index| {
+            for block in disconnected {
+                if index.tip()?.as_ref() == Some(&block.get_hash()) {
+                    index.disconnect_block(block)?;
+                }
+            }
+            for block in connected {
+                // an index that's behind already needs a rescan
+                if index.tip()?.unwrap_or_default() != block.get_prev_hash() {
+                    break;
+                }
+                index.connect_block(block, &|vin: &TXInput| self.spent_value(block, vin))?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Failed to update the wallet history index: {}", e);
+        }
+    }
+
+    // Value of the output `vin` of a transaction in `block` spends
+    fn spent_value(&self, block: &Block, vin: &TXInput) -> Result<i32> {
+        let prev_tx = match block.get_transactions().iter().find(|tx| tx.id == vin.txid) {
+            Some(tx) => tx.clone(),
+            None => self.find_transaction_from(&block.get_prev_hash(), &vin.txid)?,
+        };
+        prev_tx.vout.get(vin.vout as usize)
+            .map(|out| out.value)
+            .ok_or_else(|| format_err!("Output {}:{} doesn't exist", vin.txid, vin.vout))
+    }
+
+    /// Adds `addresses` to the wallet history index, the ones that weren't there yet get
+    /// their history from a scan of the chain
+    pub fn track_wallets(&self, addresses: &[String]) -> Result<()> {
+        let index = OwnedTxIndex::open(&self.db)?;
+        let tracked = index.tracked()?;
+        let new: HashSet<String> = addresses.iter().filter(|a| !tracked.contains(*a)).cloned().collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+        for address in &new {
+            index.track(address)?;
+        }
+        // on an empty index the scan brings the tip marker along
+        let all = if tracked.is_empty() { new.clone() } else { tracked.union(&new).cloned().collect() };
+        self.rescan_owned_txs(&index, &new, new == all)
+    }
+
+    /// Drops `address` and its entries from the wallet history index
+    pub fn untrack_wallet(&self, address: &str) -> Result<()> {
+        OwnedTxIndex::open(&self.db)?.remove_address(address)
+    }
+
+    /// Transactions of a tracked address, newest first. Read straight from the index unless
+    /// it isn't current with the tip, then every tracked address is rescanned first
+    pub fn wallet_history(&self, address: &str) -> Result<Vec<HistoryEntry>> {
+        let index = OwnedTxIndex::open(&self.db)?;
+        if index.tip()?.as_ref() != Some(&self.tip) {
+            info!("Wallet history index is behind the tip, rescanning");
+            let tracked = index.tracked()?;
+            self.rescan_owned_txs(&index, &tracked, true)?;
+        }
+        index.history(address)
+    }
+
+    // Rebuilds the entries of `addresses` from the chain, oldest block first. `complete` when
+    // that's every tracked address, which makes the index current with the tip
+    fn rescan_owned_txs(&self, index: &OwnedTxIndex, addresses: &HashSet<String>, complete: bool) -> Result<()> {
+        for address in addresses {
+            index.clear_entries(address)?;
+        }
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut walk = self.iter();
+        blocks.extend(&mut walk);
+        walk.finish()?;
+
+        // outputs paying the scanned addresses, the only ones their inputs can spend
+        let mut owned_outputs: HashMap<(String, i32), i32> = HashMap::new();
+        for block in blocks.iter().rev() {
+            for tx in block.get_transactions() {
+                let spent_value = |vin: &TXInput| match owned_outputs.get(&(vin.txid.clone(), vin.vout)) {
+                    Some(value) => Ok(*value),
+                    None => self.spent_value(block, vin),
+                };
+                for (address, entry) in wallet_history::entries_for(tx, block, addresses, &spent_value)? {
+                    index.insert(&address, &entry)?;
+                }
+                for (vout, out) in tx.vout.iter().enumerate() {
+                    if addresses.contains(&address::wallet_address(&out.pub_key_hash)) {
+                        owned_outputs.insert((tx.id.clone(), vout as i32), out.value);
+                    }
+                }
+            }
+        }
+        if complete {
+            index.set_tip(&self.tip)?;
+        }
+        Ok(())
+    }
+
+    // ------------- SENT TRANSACTIONS -------------
+
+    // Moves the sent transactions along with the tip, like `follow_owned_txs`
+    fn follow_sent_txs(&self, disconnected: &[Block], connected: &[Block]) {
+        let result = SentTxIndex::open(&self.db).and_then(|index| {
+            for block in disconnected {
+                if index.tip()?.as_ref() == Some(&block.get_hash()) {
+                    index.disconnect_block(block)?;
+                }
+            }
+            for block in connected {
+                if index.tip()?.unwrap_or_default() != block.get_prev_hash() {
+                    break;
+                }
+                index.connect_block(block)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("Failed to update the sent transactions: {}", e);
+        }
+    }
+
+    /// Records `tx`, just sent from our wallets and paying `destinations`, as pending
+    pub fn record_sent(&self, tx: &Transaction, destinations: &[String], created_at: u128) -> Result<SentTx> {
+        let mut inputs: i64 = 0;
+        for vin in &tx.vin {
+            let prev_tx = self.find_transaction(&vin.txid)?;
+            inputs += prev_tx.vout.get(vin.vout as usize)
+                .ok_or_else(|| format_err!("Output {}:{} doesn't exist", vin.txid, vin.vout))?
+                .value as i64;
+        }
+        let outputs: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+        let sent = SentTx::new(tx, destinations, inputs - outputs, created_at);
+
+        let index = SentTxIndex::open(&self.db)?;
+        // nothing to rescan before the first send
+        if index.tip()?.is_none() {
+            index.set_tip(&self.tip)?;
+        }
+        index.insert(&sent)?;
+        Ok(sent)
+    }
+
+    pub fn sent_transaction(&self, txid: &str) -> Result<Option<SentTx>> {
+        SentTxIndex::open(&self.db)?.get(txid)
+    }
+
+    /// Our sends, newest first. Statuses are rescanned from the chain first when they aren't
+    /// current with the tip
+    pub fn sent_transactions(&self) -> Result<Vec<SentTx>> {
+        let index = SentTxIndex::open(&self.db)?;
+        if index.tip()?.as_ref() != Some(&self.tip) {
+            info!("Sent transactions are behind the tip, rescanning");
+            index.reset()?;
+            let mut blocks: Vec<Block> = Vec::new();
+            let mut walk = self.iter();
+            blocks.extend(&mut walk);
+            walk.finish()?;
+            for block in blocks.iter().rev() {
+                index.apply_block(block)?;
+            }
+            index.set_tip(&self.tip)?;
+        }
+        index.all()
+    }
+
+    // ------------- CHECKPOINTS -------------
+
+    /// Stores an operator checkpoint (the signature must be checked by the caller).
+    /// Returns false when the same checkpoint was already known.
+    pub fn add_checkpoint(&self, checkpoint: &Checkpoint) -> Result<bool> {
+        if self.get_checkpoints()?.get(&checkpoint.height) == Some(&checkpoint.hash) {
+            return Ok(false);
+        }
+        if let Some(current) = self.iter().find(|b| b.get_height() == checkpoint.height) {
+            if current.get_hash() != checkpoint.hash {
+                println!("Our chain conflicts with the checkpoint at height {}", checkpoint.height);
+            }
+        }
+
+        let mut stored = self.operator_checkpoints()?;
+        stored.insert(checkpoint.height, checkpoint.hash.clone());
+        self.db.insert(CHECKPOINTS_KEY, bincode::serialize(&stored)?)?;
+        self.db.flush()?;
+        Ok(true)
+    }
+
+    fn operator_checkpoints(&self) -> Result<BTreeMap<i32, String>> {
+        match self.db.get(CHECKPOINTS_KEY)? {
+            Some(data) => Ok(bincode::deserialize(&data)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// The stored operator checkpoints with the fixed ones, a fixed one wins at the same height
+    pub fn get_checkpoints(&self) -> Result<BTreeMap<i32, String>> {
+        let mut checkpoints = self.operator_checkpoints()?;
+        checkpoints.extend(self.fixed_checkpoints.iter().map(|(height, hash)| (*height, hash.clone())));
+        Ok(checkpoints)
+    }
+
+    /// The highest checkpoint, (height, block hash)
+    pub fn latest_checkpoint(&self) -> Result<Option<(i32, String)>> {
+        Ok(self.get_checkpoints()?.pop_last())
+    }
+
+    /// True when `block` is below the latest checkpoint on its branch: walking down the headers
+    /// from the checkpointed block gets to it. Its signatures are vouched for, see checkpoint.rs
+    pub fn vouched_by_checkpoint(&self, block: &Block) -> Result<bool> {
+        let Some((height, mut hash)) = self.latest_checkpoint()? else {
+            return Ok(false);
+        };
+        if block.get_height() >= height {
+            return Ok(false);
+        }
+        // the block itself may not be stored yet, its child says what it is
+        while let Some(header) = self.get_header(&hash)? {
+            if header.height <= block.get_height() + 1 {
+                return Ok(header.height == block.get_height() + 1 && header.prev_block_hash == block.get_hash());
+            }
+            hash = header.prev_block_hash;
+        }
+        Ok(false)
+    }
+
+    /// True when `block` sits at a checkpointed height with another hash, or builds on a block that does.
+    /// Ancestors missing from the db can't be checked and are assumed fine.
+    pub fn violates_checkpoint(&self, block: &Block) -> Result<bool> {
+        let checkpoints = self.get_checkpoints()?;
+
+        // the closest checkpoint at or below the block
+        let (height, hash) = match checkpoints.range(..=block.get_height()).next_back() {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(false),
+        };
+
+        let mut current = block.clone();
+        while current.get_height() > *height {
+            match self.db.get(current.get_prev_hash())? {
+                Some(data) => current = bincode::deserialize(&data)?,
+                None => return Ok(false),
+            }
+        }
+        Ok(current.get_height() == *height && current.get_hash() != *hash)
+    }
+
+
+}
+
+impl BlockchainIter<'_> {
+    /// Err if the walk ended on a missing or unreadable block rather than after the genesis block
+    pub fn finish(self) -> Result<()> {
+        self.headers.finish()
+    }
+}
+
+impl<'a> Iterator for BlockchainIter<'a> {
+    type Item = Block;
+
+    // Follows the headers and reads each block, stops on the first that can't be read
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.headers.next()?;
+        match self.headers.bc.db.get(&header.hash) {
+            Ok(Some(b)) => match bincode::deserialize::<Block>(&b) {
+                Ok(block) => Some(block),
+                Err(e) => self.headers.fail(&header.hash, format_err!("corrupted block: {}", e)),
+            },
+            Ok(None) => self.headers.fail(&header.hash, format_err!("block is missing")),
+            Err(e) => self.headers.fail(&header.hash, e.into()),
+        }
+    }
+}
+
+impl HeaderIter<'_> {
+    /// Err if the walk ended on a missing or unreadable header rather than after the genesis block
+    pub fn finish(self) -> Result<()> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn fail<T>(&mut self, hash: &str, e: failure::Error) -> Option<T> {
+        error!("Chain walk stopped at block {}: {}", hash, e);
+        self.error = Some(e);
+        self.current_hash.clear();
+        None
+    }
+}
+
+impl<'a> Iterator for HeaderIter<'a> {
+    type Item = BlockHeader;
+
+    // Stops on the first header that can't be read, see `finish`
+    fn next(&mut self) -> Option<Self::Item> {
+        // the genesis block's prev hash, an empty chain or the snapshot base
+        if self.current_hash.is_empty() || self.base.as_ref() == Some(&self.current_hash) {
+            return None;
+        }
+
+        let hash = self.current_hash.clone();
+        match self.bc.get_header(&hash) {
+            Ok(Some(header)) => {
+                self.current_hash = header.prev_block_hash.clone();
+                Some(header)
+            }
+            Ok(None) => self.fail(&hash, format_err!("block is missing")),
+            Err(e) => self.fail(&hash, format_err!("corrupted block: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{coinbase, reopen, ChainBuilder, TxBuilder, WalletFixture};
+
+    #[test]
+    fn test_add_block() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(2);
+        let stale = chain.next_block(Vec::new());
+        let chain = chain.empty_blocks(1);
+        let next = chain.next_block(Vec::new());
+        let mut bc = chain.build();
+
+        // A block at or below the best height is stored without moving the tip
+        let tip = bc.tip.clone();
+        bc.add_block(stale.clone()).unwrap();
+        assert_eq!(bc.tip, tip);
+        assert_eq!(bc.get_block(&stale.get_hash()).unwrap().get_height(), 3);
+
+        // A higher block becomes the tip, adding it again changes nothing
+        bc.add_block(next.clone()).unwrap();
+        bc.add_block(next.clone()).unwrap();
+        assert_eq!(bc.tip, next.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 4);
+        assert_eq!(bc.get_block_hashes().len(), 5);
+    }
+
+    #[test]
+    fn test_longer_fork_reorganizes() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&alice).empty_blocks(1);
+        let fork = chain.tip();
+        let reward = fork.get_transactions()[0].clone();
+
+        // The tip's branch pays bob, the competing one pays carol, then spends that on the branch
+        let a1 = chain.next_block(vec![TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).build()]);
+        let to_carol = TxBuilder::new(&alice).spend(&reward, 0).pay(&carol.address(), 10).build();
+        let b1 = Block::new_block(vec![coinbase(&carol.address(), 2), to_carol.clone()], fork.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let from_carol = TxBuilder::new(&carol).spend(&to_carol, 0).pay(&bob.address(), 10).build();
+        let b2 = Block::new_block(vec![coinbase(&carol.address(), 3), from_carol], b1.get_hash(), 3, INITIAL_TARGET).unwrap();
+        let mut bc = chain.build();
+
+        assert!(matches!(bc.handle_potential_reorg(a1.clone()).unwrap(), ReorgOutcome::Extended));
+        assert!(matches!(bc.handle_potential_reorg(b1.clone()).unwrap(), ReorgOutcome::Stored));
+        assert_eq!(bc.tip, a1.get_hash());
+
+        match bc.handle_potential_reorg(b2.clone()).unwrap() {
+            ReorgOutcome::Reorganized { fork_height, disconnected, connected } => {
+                assert_eq!(fork_height, 1);
+                assert_eq!(disconnected.iter().map(Block::get_hash).collect::<Vec<_>>(), vec![a1.get_hash()]);
+                assert_eq!(connected.iter().map(Block::get_hash).collect::<Vec<_>>(), vec![b1.get_hash(), b2.get_hash()]);
+            }
+            outcome => panic!("expected a reorganization, got {:?}", outcome),
+        }
+        assert_eq!(bc.tip, b2.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+        assert!(bc.find_transaction(&a1.get_transactions()[1].id).is_err());
+        assert!(matches!(bc.handle_potential_reorg(b2).unwrap(), ReorgOutcome::Stored));
+    }
+
+    #[test]
+    fn test_stats_follow_the_tip() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2).address();
+        let reward = coinbase(&alice.address(), 0);
+        let payment = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob, 6).pay(&alice.address(), 3).build();
+        let mut bc = ChainBuilder::new(&alice).block(vec![payment]).build();
+        let recount = |bc: &Blockchain| {
+            bc.db.remove(STATS_KEY).unwrap();
+            bc.stats().unwrap()
+        };
+
+        // Two coinbases and the payment, which spent one output and made two
+        let stats = bc.stats().unwrap();
+        assert_eq!((stats.blocks, stats.transactions, stats.coins_issued, stats.utxos), (2, 3, 20, 3));
+        assert_eq!(stats.difficulty, 1.0);
+        assert!(stats.avg_block_interval.is_some());
+
+        // A fee-claiming coinbase issues no more than the subsidy
+        let claimed = Transaction::new_coinbase_with_fees(alice.address(), String::from("claimed"), 2, 1).unwrap();
+        bc.mine_block(vec![claimed]).unwrap();
+        let stats = bc.stats().unwrap();
+        assert_eq!((stats.blocks, stats.transactions, stats.coins_issued, stats.utxos), (3, 4, 30, 4));
+        assert_eq!(stats, recount(&bc));
+
+        bc.rollback(2).unwrap();
+        let stats = bc.stats().unwrap();
+        assert_eq!((stats.blocks, stats.transactions, stats.coins_issued, stats.utxos), (1, 1, 10, 1));
+        assert_eq!(stats.avg_block_interval, None);
+        assert_eq!(stats, recount(&bc));
+    }
+
+    #[test]
+    fn test_fee_estimates_follow_recent_blocks() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2).address();
+        let chain = || ChainBuilder::new(&alice).empty_blocks(9);
+
+        // Nothing paid a fee yet
+        assert_eq!(chain().build().estimate_fee(1), SETTINGS.min_relay_fee_rate);
+
+        // Fees of 0 to 9 coins, all the same size
+        let payments: Vec<Transaction> = (0..10)
+            .map(|height| TxBuilder::new(&alice).spend(&coinbase(&alice.address(), height), 0).pay(&bob, 10 - height).build())
+            .collect();
+        let size = bincode::serialized_size(&payments[0]).unwrap() as i64;
+        let rate = |fee: i64| fee * 1000 / size;
+        let bc = chain().block(payments).build();
+
+        assert_eq!(bc.estimate_fee(1), rate(8));
+        assert_eq!(bc.estimate_fee(3), rate(2).max(SETTINGS.min_relay_fee_rate));
+        assert_eq!(bc.estimate_fee(100), SETTINGS.min_relay_fee_rate);
+        assert!(bc.estimate_fee(1) >= bc.estimate_fee(2));
+    }
+
+    #[test]
+    fn test_rollback_then_mine_again() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2).address();
+        let reward = coinbase(&alice.address(), 0);
+        let payment = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob, 10).build();
+        let mut bc = ChainBuilder::new(&alice).block(vec![payment.clone()]).empty_blocks(2).build();
+        let hashes = bc.get_block_hashes();
+
+        assert!(bc.rollback(4).is_err());
+        assert_eq!(bc.tip, hashes[0]);
+        let removed = bc.rollback(2).unwrap();
+        assert_eq!(removed.iter().map(Block::get_hash).collect::<Vec<_>>(), hashes[..2].to_vec());
+        assert_eq!((bc.get_best_height().unwrap(), bc.tip.clone()), (1, hashes[2].clone()));
+        assert!(!bc.has_block(&hashes[0]).unwrap());
+        assert!(bc.get_block_by_height(2).is_err());
+
+        // The block holding the payment goes too, its transaction is handed back
+        let removed = bc.rollback(1).unwrap();
+        let txs: Vec<&Transaction> = removed.iter().flat_map(|b| b.get_transactions()).filter(|tx| !tx.is_coinbase()).collect();
+        assert_eq!(txs.iter().map(|tx| tx.id.clone()).collect::<Vec<_>>(), vec![payment.id.clone()]);
+        assert!(bc.rollback(1).is_err());
+        assert_eq!(bc.rollback(0).unwrap().len(), 0);
+
+        // Mining continues on the genesis block, the payment can be mined again
+        let block = bc.mine_block(vec![payment.clone()]).unwrap();
+        assert_eq!(block.get_height(), 1);
+        assert_eq!(bc.get_block_by_height(1).unwrap().get_hash(), block.get_hash());
+        assert_eq!(bc.find_transaction(&payment.id).unwrap().id, payment.id);
+        assert!(bc.verify_chain().unwrap().problem.is_none());
+    }
+
+    #[test]
+    fn test_height_index_follows_the_active_chain() {
+        let alice = WalletFixture::new(1);
+        let carol = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&alice).empty_blocks(2);
+        let fork = chain.tip();
+        let a3 = chain.next_block(Vec::new());
+        let b3 = Block::new_block(vec![coinbase(&carol.address(), 3)], fork.get_hash(), 3, INITIAL_TARGET).unwrap();
+        let b4 = Block::new_block(vec![coinbase(&carol.address(), 4)], b3.get_hash(), 4, INITIAL_TARGET).unwrap();
+        let mut bc = chain.build();
+        let hashes = |blocks: Vec<Block>| blocks.iter().map(Block::get_hash).collect::<Vec<_>>();
+
+        bc.add_block(a3.clone()).unwrap();
+        assert_eq!(bc.get_block_by_height(2).unwrap().get_hash(), fork.get_hash());
+        assert_eq!(bc.get_block_by_height(3).unwrap().get_hash(), a3.get_hash());
+        assert!(bc.get_block_by_height(4).is_err());
+        assert!(bc.get_block_by_height(-1).is_err());
+
+        // A stale fork block at the same height isn't indexed
+        bc.add_block(b3.clone()).unwrap();
+        assert_eq!(bc.get_block_by_height(3).unwrap().get_hash(), a3.get_hash());
+
+        // Until its branch takes over
+        bc.add_block(b4.clone()).unwrap();
+        assert_eq!(bc.db.get(HEIGHTS_TIP_KEY).unwrap().as_deref(), Some(b4.get_hash().as_bytes()));
+        assert_eq!(hashes(bc.get_blocks_range(2, 10).unwrap()), vec![b4.get_hash(), b3.get_hash(), fork.get_hash()]);
+        assert_eq!(hashes(bc.get_blocks_range(-5, 0).unwrap()), vec![bc.iter().last().unwrap().get_hash()]);
+        assert!(bc.get_blocks_range(3, 2).unwrap().is_empty());
+
+        // A stale index is rebuilt the same
+        let kept = hashes(bc.get_blocks_range(0, 4).unwrap());
+        bc.db.remove(HEIGHTS_TIP_KEY).unwrap();
+        assert_eq!(hashes(bc.get_blocks_range(0, 4).unwrap()), kept);
+        assert_eq!(kept, bc.get_block_hashes());
+    }
+
+    #[test]
+    fn test_retarget_moves_towards_the_spacing() {
+        let target = INITIAL_TARGET;
+        assert_eq!(retarget(target, 500, 1000), target / 2); // too fast, harder
+        assert_eq!(retarget(target, 2000, 1000), target * 2); // too slow, easier
+        assert_eq!(retarget(target, 1000, 1000), target);
+
+        // Clamped to MAX_RETARGET_FACTOR, and to the easiest target
+        assert_eq!(retarget(target, 1, 1000), target / 4);
+        assert_eq!(retarget(target, 1_000_000, 1000), target * 4);
+        assert_eq!(retarget(MAX_TARGET / 2, 1_000_000, 1000), MAX_TARGET);
+        assert_eq!(retarget(1, 0, 1000), 1);
+    }
+
+    // A chain up to the first retarget height with blocks `spacing` ms apart
+    fn timed_chain(spacing: u128) -> (Blockchain, Block) {
+        let miner = WalletFixture::new(1);
+        let mut bc = Blockchain::default_empty();
+        let start = 1_700_000_000_000;
+        let mut tip = Block::new_block_at(vec![coinbase(&miner.address(), 0)], String::new(), 0, INITIAL_TARGET, start).unwrap();
+        bc.add_block(tip.clone()).unwrap();
+        for height in 1..RETARGET_INTERVAL {
+            let target = bc.next_target(Some(&tip)).unwrap();
+            assert_eq!(target, INITIAL_TARGET);
+            tip = Block::new_block_at(vec![coinbase(&miner.address(), height)], tip.get_hash(), height, target, start + spacing * height as u128).unwrap();
+            bc.add_block(tip.clone()).unwrap();
+        }
+        (bc, tip)
+    }
+
+    #[test]
+    fn test_next_target_follows_block_times() {
+        let spacing = SETTINGS.expected_block_interval as u128 * 1000;
+        let miner = WalletFixture::new(1);
+
+        // Twice as fast as configured: half the target
+        let (mut bc, tip) = timed_chain(spacing / 2);
+        let target = bc.next_target(Some(&tip)).unwrap();
+        assert_eq!(target, INITIAL_TARGET / 2);
+
+        // The old target isn't accepted anymore
+        let stale = Block::new_block(vec![coinbase(&miner.address(), RETARGET_INTERVAL)], tip.get_hash(), RETARGET_INTERVAL, INITIAL_TARGET).unwrap();
+        let rejection = bc.add_block(stale.clone()).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+        assert_eq!(rejection, BlockRejectReason::BadTarget { hash: stale.get_hash(), target: INITIAL_TARGET, expected: target });
+        let retargeted = Block::new_block(vec![coinbase(&miner.address(), RETARGET_INTERVAL)], tip.get_hash(), RETARGET_INTERVAL, target).unwrap();
+        bc.add_block(retargeted.clone()).unwrap();
+        assert_eq!(bc.tip, retargeted.get_hash());
+        // and it sticks until the next window
+        assert_eq!(bc.next_target(Some(&retargeted)).unwrap(), target);
+
+        // Slower: easier, but by MAX_RETARGET_FACTOR at most
+        let (bc, tip) = timed_chain(spacing * 3);
+        assert_eq!(bc.next_target(Some(&tip)).unwrap(), INITIAL_TARGET * 3);
+        let (bc, tip) = timed_chain(spacing * 10);
+        assert_eq!(bc.next_target(Some(&tip)).unwrap(), INITIAL_TARGET * 4);
+    }
+
+    // Extends `parent` by `count` blocks mined by `miner`, `spacing` ms apart, each with the
+    // target due. Returns the last one
+    fn extend(bc: &mut Blockchain, parent: &Block, miner: &WalletFixture, count: i32, spacing: u128) -> Block {
+        let mut tip = parent.clone();
+        for _ in 0..count {
+            let height = tip.get_height() + 1;
+            let target = bc.next_target(Some(&tip)).unwrap();
+            tip = Block::new_block_at(vec![coinbase(&miner.address(), height)], tip.get_hash(), height, target, tip.get_timestamp() + spacing).unwrap();
+            bc.add_block(tip.clone()).unwrap();
+        }
+        tip
+    }
+
+    #[test]
+    fn test_heavier_fork_beats_longer_one() {
+        let spacing = SETTINGS.expected_block_interval as u128 * 1000;
+        let (slow_miner, fast_miner) = (WalletFixture::new(1), WalletFixture::new(2));
+        let chain = ChainBuilder::new(&slow_miner);
+        let genesis = chain.tip();
+        let mut bc = chain.build();
+        let work = block_work(INITIAL_TARGET);
+        assert_eq!(bc.get_best_work().unwrap(), work);
+
+        // Slow blocks make the target easier at the retarget, 25 blocks high
+        let slow = extend(&mut bc, &genesis, &slow_miner, RETARGET_INTERVAL + 5, spacing * 4);
+        assert_eq!(slow.get_target(), INITIAL_TARGET * 4);
+        assert_eq!(bc.tip, slow.get_hash());
+        let slow_work = RETARGET_INTERVAL as u128 * work + 6 * block_work(INITIAL_TARGET * 4);
+        assert_eq!(bc.get_best_work().unwrap(), slow_work);
+
+        // Fast ones make it harder. The first harder block already outweighs the longer branch
+        let below_retarget = extend(&mut bc, &genesis, &fast_miner, RETARGET_INTERVAL - 1, spacing / 4);
+        assert_eq!(bc.tip, slow.get_hash());
+        let target = bc.next_target(Some(&below_retarget)).unwrap();
+        assert_eq!(target, INITIAL_TARGET / 4);
+        let heavier = Block::new_block_at(vec![coinbase(&fast_miner.address(), RETARGET_INTERVAL)], below_retarget.get_hash(),
+            RETARGET_INTERVAL, target, below_retarget.get_timestamp() + spacing / 4).unwrap();
+        let ReorgOutcome::Reorganized { fork_height, disconnected, connected } = bc.handle_potential_reorg(heavier.clone()).unwrap() else {
+            panic!("expected a reorganization");
+        };
+        assert_eq!((fork_height, disconnected.len(), connected.len()), (0, 25, 20));
+        assert_eq!(bc.tip, heavier.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), RETARGET_INTERVAL);
+        assert_eq!(bc.get_best_work().unwrap(), RETARGET_INTERVAL as u128 * work + block_work(target));
+        assert!(bc.get_best_work().unwrap() > slow_work);
+
+        // Work the tree lost is filled in again from the blocks
+        bc.db.drop_tree(WORK_TREE).unwrap();
+        assert_eq!(bc.chain_work(&slow.get_hash()).unwrap(), slow_work);
+        assert_eq!(bc.get_best_work().unwrap(), RETARGET_INTERVAL as u128 * work + block_work(target));
+    }
+
+    #[test]
+    fn test_add_block_rejects_invalid_blocks() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let chain = chain.empty_blocks(1);
+        let tip = chain.tip();
+        let mut bc = chain.build();
+        let reject = |bc: &mut Blockchain, block: Block| bc.add_block(block).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+
+        // The nonce is the last field, changing it breaks the proof of work. The test target
+        // is easy enough that some changes don't
+        let bytes = bincode::serialize(&ChainBuilder::new(&other).next_block(Vec::new())).unwrap();
+        let nonce = bytes.len() - 4;
+        let tampered = (1..=u8::MAX)
+            .map(|flip| {
+                let mut bytes = bytes.clone();
+                bytes[nonce] ^= flip;
+                bincode::deserialize::<Block>(&bytes).unwrap()
+            })
+            .find(|block| !block.verify_proof_of_work().unwrap())
+            .unwrap();
+        assert!(matches!(reject(&mut bc, tampered), BlockRejectReason::BadProofOfWork { .. }));
+
+        // Other transactions under a properly mined header: the proof of work still holds,
+        // the merkle root doesn't
+        let mined = Block::new_block(vec![coinbase(&miner.address(), 2)], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let swapped = (mined.get_timestamp(), vec![coinbase(&other.address(), 2)], mined.get_prev_hash(), mined.get_merkle_root(),
+            mined.get_hash(), mined.get_height(), mined.get_target(), mined.get_nonce());
+        let swapped: Block = bincode::deserialize(&bincode::serialize(&swapped).unwrap()).unwrap();
+        assert!(swapped.verify_proof_of_work().unwrap());
+        assert_eq!(reject(&mut bc, swapped), BlockRejectReason::BadMerkleRoot { hash: mined.get_hash() });
+
+        // Coinbase data past MAX_BLOCK_SIZE
+        let padded = Transaction::new_coinbase(miner.address(), "x".repeat(MAX_BLOCK_SIZE), 2).unwrap();
+        let oversized = Block::new_block(vec![padded], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let size = oversized.size().unwrap();
+        assert!(size > MAX_BLOCK_SIZE);
+        assert_eq!(reject(&mut bc, oversized.clone()), BlockRejectReason::TooLarge { hash: oversized.get_hash(), size });
+
+        // A payment changed after signing, in a block mined properly on the tip
+        let mut forged = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 10).build();
+        forged.vout[0].value = 100;
+        let block = Block::new_block(vec![coinbase(&miner.address(), 2), forged.clone()], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::InvalidTransaction { hash, txid: forged.id });
+
+        // Signed properly, but paying out more than it spends
+        let inflating = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 20).build();
+        let block = Block::new_block(vec![coinbase(&miner.address(), 2), inflating.clone()], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::InvalidTransaction { hash, txid: inflating.id });
+
+        let orphan = Block::new_block(vec![coinbase(&miner.address(), 2)], "00ab".repeat(16), 2, INITIAL_TARGET).unwrap();
+        assert!(matches!(reject(&mut bc, orphan), BlockRejectReason::UnknownParent { .. }));
+        // A genesis block of its own, valid but for the chain already having one
+        let second_genesis = Block::new_block(vec![coinbase(&other.address(), 0)], String::new(), 0, INITIAL_TARGET).unwrap();
+        assert!(second_genesis.verify_proof_of_work().unwrap());
+        let hash = second_genesis.get_hash();
+        assert_eq!(reject(&mut bc, second_genesis), BlockRejectReason::UnknownParent { hash: hash.clone(), prev_hash: String::new() });
+        assert!(!bc.has_block(&hash).unwrap());
+        let skipping = Block::new_block(vec![coinbase(&miner.address(), 5)], tip.get_hash(), 5, INITIAL_TARGET).unwrap();
+        assert!(matches!(reject(&mut bc, skipping), BlockRejectReason::BadHeight { parent_height: 1, .. }));
+
+        // Nothing was stored
+        assert_eq!(bc.tip, tip.get_hash());
+        assert_eq!(bc.get_block_hashes().len(), 2);
+    }
+
+    #[test]
+    fn test_add_block_rejects_double_spends() {
+        let miner = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let to_bob = TxBuilder::new(&miner).spend(&reward, 0).pay(&bob.address(), 10).build();
+        let to_carol = TxBuilder::new(&miner).spend(&reward, 0).pay(&carol.address(), 10).build();
+        let chain = chain.block(vec![to_bob.clone()]);
+        let tip = chain.tip();
+        let mut bc = chain.build();
+        let reject = |bc: &mut Blockchain, block: Block| bc.add_block(block).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+
+        // The reward was spent on this branch already
+        let block = Block::new_block(vec![coinbase(&miner.address(), 2), to_carol.clone()], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::InvalidTransaction { hash, txid: to_carol.id.clone() });
+        assert!(bc.mine_block(vec![coinbase(&miner.address(), 2), to_carol]).is_err());
+
+        // Two transactions of the same block spending bob's output
+        let first = TxBuilder::new(&bob).spend(&to_bob, 0).pay(&carol.address(), 10).build();
+        let second = TxBuilder::new(&bob).spend(&to_bob, 0).pay(&miner.address(), 10).build();
+        let block = Block::new_block(vec![coinbase(&miner.address(), 2), first.clone(), second.clone()], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::InvalidTransaction { hash, txid: second.id.clone() });
+        assert!(bc.mine_block(vec![coinbase(&miner.address(), 2), first.clone(), second]).is_err());
+        assert_eq!(bc.tip, tip.get_hash());
+
+        // Either of them alone is fine
+        let block = Block::new_block(vec![coinbase(&miner.address(), 2), first], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        bc.add_block(block).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_transactions_spend_outputs_of_earlier_ones_in_the_block() {
+        let miner = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tip = chain.tip();
+        let mut bc = chain.build();
+        let parent = TxBuilder::new(&miner).spend(&reward, 0).pay(&bob.address(), 10).build();
+        let child = TxBuilder::new(&bob).spend(&parent, 0).pay(&miner.address(), 10).build();
+        let reject = |bc: &mut Blockchain, block: Block| bc.add_block(block).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+
+        // Only after the parent
+        let block = Block::new_block(vec![coinbase(&miner.address(), 1), child.clone(), parent.clone()], tip.get_hash(), 1, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::InvalidTransaction { hash, txid: child.id.clone() });
+        assert!(bc.verify_in_block(&child, &[]).is_err());
+        assert!(bc.verify_in_block(&child, std::slice::from_ref(&parent)).unwrap());
+
+        let block = Block::new_block(vec![coinbase(&miner.address(), 1), parent.clone(), child.clone()], tip.get_hash(), 1, INITIAL_TARGET).unwrap();
+        bc.add_block(block).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        assert!(bc.find_unspent(&parent.id, 0).unwrap().is_none());
+
+        // mine_block takes them the same way
+        let to_bob = TxBuilder::new(&miner).spend(&child, 0).pay(&bob.address(), 10).build();
+        let back = TxBuilder::new(&bob).spend(&to_bob, 0).pay(&miner.address(), 10).build();
+        assert!(bc.mine_block(vec![back.clone(), to_bob.clone()]).is_err());
+        bc.mine_block(vec![to_bob, back.clone()]).unwrap();
+        assert!(bc.find_unspent(&back.id, 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_rejects_coinbase_without_its_height() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(1);
+        let tip = chain.tip();
+        let mut bc = chain.build();
+        let reject = |bc: &mut Blockchain, block: Block| bc.add_block(block).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+
+        // The coinbase of the block below, which would take the txid of its reward
+        let copied = tip.get_transactions()[0].clone();
+        let block = Block::new_block(vec![copied], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::BadCoinbaseHeight { hash, height: 2 });
+
+        // and one that doesn't start with a height at all
+        let mut headless = coinbase(&miner.address(), 2);
+        headless.vin[0].pub_key = b"no".to_vec();
+        headless.id = headless.hash().unwrap();
+        let block = Block::new_block(vec![headless], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::BadCoinbaseHeight { hash, height: 2 });
+
+        let block = Block::new_block(vec![coinbase(&miner.address(), 2)], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        bc.add_block(block).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_rejects_inflated_coinbase() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let chain = chain.empty_blocks(1);
+        let tip = chain.tip();
+        let mut bc = chain.build();
+        let reject = |bc: &mut Blockchain, block: Block| bc.add_block(block).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+        let claiming = |fees| Transaction::new_coinbase_with_fees(miner.address(), String::from("claimed"), 2, fees).unwrap();
+
+        // Nothing to claim without other transactions
+        let block = Block::new_block(vec![claiming(1)], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let hash = block.get_hash();
+        assert_eq!(reject(&mut bc, block), BlockRejectReason::CoinbaseTooLarge { hash, value: 11, allowed: 10 });
+
+        // 6 + 3 out of 10 leaves a fee of 1, and no more
+        let pay = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 6).pay(&miner.address(), 3).build();
+        let block = Block::new_block(vec![claiming(2), pay.clone()], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        assert!(matches!(reject(&mut bc, block), BlockRejectReason::CoinbaseTooLarge { value: 12, allowed: 11, .. }));
+        let block = Block::new_block(vec![claiming(1), pay], tip.get_hash(), 2, INITIAL_TARGET).unwrap();
+        bc.add_block(block).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+        assert_eq!(bc.stats().unwrap().circulating_supply, 30);
+    }
+
+    #[test]
+    fn test_block_timestamps_follow_median_time_past() {
+        let miner = WalletFixture::new(1);
+        let mut bc = Blockchain::default_empty();
+        let block_at = |bc: &Blockchain, parent: &Block, timestamp: u128| {
+            let height = parent.get_height() + 1;
+            Block::new_block_at(vec![coinbase(&miner.address(), height)], parent.get_hash(), height, bc.next_target(Some(parent)).unwrap(), timestamp).unwrap()
+        };
+        let reject = |bc: &mut Blockchain, block: Block| bc.add_block(block).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+
+        // A minute apart, the last one out of order but still after the median
+        let start = 1_700_000_000_000;
+        let genesis = Block::new_block_at(vec![coinbase(&miner.address(), 0)], String::new(), 0, INITIAL_TARGET, start).unwrap();
+        bc.add_block(genesis.clone()).unwrap();
+        assert_eq!(bc.median_time_past(&genesis.get_hash()).unwrap(), start);
+        let mut tip = genesis;
+        for height in 1..=11 {
+            tip = block_at(&bc, &tip, start + height * 60_000);
+            bc.add_block(tip.clone()).unwrap();
+        }
+        let median = start + 6 * 60_000;
+        assert_eq!(bc.median_time_past(&tip.get_hash()).unwrap(), median);
+        let earlier = block_at(&bc, &tip, median + 1);
+        bc.add_block(earlier.clone()).unwrap();
+        assert_eq!(bc.tip, earlier.get_hash());
+
+        // At the median or before it
+        let at_median = bc.median_time_past(&earlier.get_hash()).unwrap();
+        let stale = block_at(&bc, &earlier, at_median);
+        assert_eq!(reject(&mut bc, stale.clone()), BlockRejectReason::TimeTooOld { hash: stale.get_hash(), timestamp: at_median, median: at_median });
+        let before_genesis = block_at(&bc, &earlier, start - 1);
+        assert!(matches!(reject(&mut bc, before_genesis), BlockRejectReason::TimeTooOld { .. }));
+
+        // Too far ahead of our clock, but fine a little ahead
+        let timestamp = clock::now_millis() + MAX_FUTURE_BLOCK_TIME + 60_000;
+        let future = block_at(&bc, &earlier, timestamp);
+        assert_eq!(reject(&mut bc, future.clone()), BlockRejectReason::TimeTooNew { hash: future.get_hash(), timestamp });
+        let ahead = block_at(&bc, &earlier, clock::now_millis() + MAX_FUTURE_BLOCK_TIME / 2);
+        bc.add_block(ahead.clone()).unwrap();
+
+        // Mining on blocks ahead of our clock stamps the new one after their median
+        for _ in 0..MEDIAN_TIME_SPAN {
+            let tip = bc.get_block(&bc.tip).unwrap();
+            bc.add_block(block_at(&bc, &tip, tip.get_timestamp() + 1)).unwrap();
+        }
+        let median = bc.median_time_past(&bc.tip).unwrap();
+        assert!(median > clock::now_millis());
+        let mined = bc.mine_block(Vec::new()).unwrap();
+        assert!(mined.get_timestamp() > median);
+    }
+
+    #[test]
+    fn test_mine_block() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let mut bc = chain.build();
+
+        let genesis_hash = bc.tip.clone();
+        let tx = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 10).build();
+        let block = bc.mine_block(vec![tx.clone()]).unwrap();
+        assert_eq!(block.get_height(), 1);
+        assert_eq!(block.get_prev_hash(), genesis_hash);
+        assert_eq!(bc.tip, block.get_hash());
+
+        // A transaction whose outputs were changed after signing is rejected
+        let mut forged = TxBuilder::new(&other).spend(&tx, 0).pay(&other.address(), 10).build();
+        forged.vout[0].value = 100;
+        assert!(bc.mine_block(vec![forged]).is_err());
+        assert_eq!(bc.tip, block.get_hash());
+    }
+
+    #[test]
+    fn test_pruned_chain_keeps_validating() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let genesis = chain.tip();
+        let pay = TxBuilder::new(&miner).spend(&genesis.get_transactions()[0], 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let mut bc = chain.block(vec![pay.clone()]).empty_blocks(MIN_PRUNE_KEEP as usize + 10).build();
+        let best = bc.get_best_height().unwrap();
+
+        assert!(bc.prune_to_height(MIN_PRUNE_KEEP - 1).is_err());
+        assert_eq!(bc.pruned_height().unwrap(), None);
+        assert_eq!(bc.prune_to_height(MIN_PRUNE_KEEP).unwrap(), 12);
+        assert_eq!(bc.pruned_height().unwrap(), Some(12));
+        assert_eq!(bc.prune_to_height(MIN_PRUNE_KEEP).unwrap(), 0);
+
+        // Old blocks are gone but still known, their headers are kept
+        assert!(bc.get_block(&genesis.get_hash()).is_err());
+        assert!(bc.has_block(&genesis.get_hash()).unwrap());
+        assert_eq!(bc.get_header(&genesis.get_hash()).unwrap(), Some(genesis.header().clone()));
+        assert!(bc.get_block_by_height(11).is_err());
+        assert_eq!(bc.get_blocks_range(0, best).unwrap().len(), MIN_PRUNE_KEEP as usize);
+        assert_eq!(bc.get_block_hashes().len(), MIN_PRUNE_KEEP as usize);
+
+        // Outputs of pruned blocks are still spendable, the next block prunes one more
+        let spend = TxBuilder::new(&other).spend(&pay, 0).pay(&miner.address(), 4).build();
+        bc.mine_block(vec![spend]).unwrap();
+        assert!(bc.find_transaction(&pay.id).is_ok());
+        assert_eq!(bc.prune_to_height(MIN_PRUNE_KEEP).unwrap(), 1);
+        assert_eq!(bc.pruned_height().unwrap(), Some(13));
+        assert!(bc.find_unspent(&pay.id, 0).unwrap().is_none());
+        assert!(bc.find_unspent(&pay.id, 1).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_find_utxo() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner)
+            .spend(&reward, 0)
+            .pay(&other.address(), 4)
+            .pay(&miner.address(), 6)
+            .build();
+        let bc = chain.block(vec![tx.clone()]).build();
+
+        let utxos = bc.find_utxo().unwrap();
+        // the genesis reward is spent, the new coinbase and both outputs of tx are not
+        assert!(!utxos.contains_key(&reward.id));
+        assert_eq!(utxos.len(), 2);
+        let outputs = &utxos[&tx.id].outputs;
+        assert_eq!(outputs.len(), 2);
+        assert!(outputs[&0].can_be_unlock_with(&other.pub_key_hash()));
+        assert_eq!(outputs[&0].value, 4);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let operator = WalletFixture::new(9);
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(1);
+        let fork_base = chain.tip().get_hash();
+        let chain = chain.empty_blocks(2);
+        let next = chain.next_block(Vec::new());
+        let mut bc = chain.build();
+        let pinned = bc.get_block_hashes()[1].clone(); // height 2
+
+        let checkpoint = Checkpoint::sign(2, &pinned, &operator.wallet.secret_key).unwrap();
+        assert!(bc.add_checkpoint(&checkpoint).unwrap());
+        assert!(!bc.add_checkpoint(&checkpoint).unwrap());
+
+        // A fork replacing the pinned block is refused, and so is anything built on it
+        let fork = Block::new_block(vec![coinbase(&WalletFixture::new(2).address(), 2)], fork_base, 2, INITIAL_TARGET).unwrap();
+        assert!(bc.add_block(fork.clone()).is_err());
+        bc.db.insert(fork.get_hash(), bincode::serialize(&fork).unwrap()).unwrap();
+        let fork_child = Block::new_block(vec![coinbase(&miner.address(), 3)], fork.get_hash(), 3, INITIAL_TARGET).unwrap();
+        assert!(bc.violates_checkpoint(&fork_child).unwrap());
+
+        // Blocks on the pinned chain are still accepted
+        bc.add_block(next.clone()).unwrap();
+        assert_eq!(bc.tip, next.get_hash());
+
+        // Checkpoints survive reopening the database
+        let path = std::env::temp_dir().join(format!("blockjain-blocks-{}", rand::random::<u64>()));
+        {
+            let stored = Blockchain::from_db(String::new(), sled::open(&path).unwrap());
+            stored.add_checkpoint(&checkpoint).unwrap();
+        }
+        let reopened = Blockchain::from_db(String::new(), sled::open(&path).unwrap());
+        assert_eq!(reopened.get_checkpoints().unwrap().get(&2), Some(&pinned));
+        drop(reopened);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_fixed_checkpoints() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let chain = chain.empty_blocks(1);
+        let fork_base = chain.tip().get_hash();
+        let chain = chain.empty_blocks(1);
+        let mut bc = chain.build();
+        let pinned = bc.get_block_hashes()[0].clone(); // height 2
+        bc.fixed_checkpoints.insert(2, pinned.clone());
+        assert_eq!(bc.latest_checkpoint().unwrap(), Some((2, pinned.clone())));
+
+        // A fork block at the pinned height is refused although its proof of work is fine
+        let fork = Block::new_block(vec![coinbase(&WalletFixture::new(2).address(), 2)], fork_base.clone(), 2, INITIAL_TARGET).unwrap();
+        assert!(fork.verify_proof_of_work().unwrap());
+        let hash = fork.get_hash();
+        assert_eq!(bc.add_block(fork).unwrap_err().downcast::<BlockRejectReason>().unwrap(), BlockRejectReason::CheckpointViolation { hash });
+
+        // An operator checkpoint higher up becomes the latest, a fixed one isn't stored with them
+        let operator = Checkpoint::sign(9, &"00ab".repeat(16), &WalletFixture::new(9).wallet.secret_key).unwrap();
+        assert!(bc.add_checkpoint(&operator).unwrap());
+        assert_eq!(bc.latest_checkpoint().unwrap(), Some((9, "00ab".repeat(16))));
+        assert_eq!(bc.operator_checkpoints().unwrap().len(), 1);
+
+        // Below the latest checkpoint but off its branch, transactions are checked as usual
+        let mut forged = TxBuilder::new(&miner).spend(&reward, 0).pay(&miner.address(), 10).build();
+        forged.vin[0].signature[0] ^= 1;
+        let below = Block::new_block(vec![coinbase(&miner.address(), 2), forged.clone()], fork_base.clone(), 2, INITIAL_TARGET).unwrap();
+        let rejection = |bc: &Blockchain, block: &Block| bc.validate_block(block).unwrap_err().downcast::<BlockRejectReason>().unwrap();
+        let unsigned = BlockRejectReason::InvalidTransaction { hash: below.get_hash(), txid: forged.id.clone() };
+        bc.fixed_checkpoints.clear();
+        assert_eq!(rejection(&bc, &below), unsigned);
+
+        // Under the checkpointed block its signatures are vouched for, nothing else is
+        bc.db.remove(CHECKPOINTS_KEY).unwrap();
+        let pin_child_of = |bc: &mut Blockchain, block: &Block| {
+            let child = Block::new_block(vec![coinbase(&miner.address(), 3)], block.get_hash(), 3, INITIAL_TARGET).unwrap();
+            Blockchain::store_header(&bc.db, child.header()).unwrap();
+            bc.fixed_checkpoints = BTreeMap::from([(3, child.get_hash())]);
+        };
+        pin_child_of(&mut bc, &below);
+        assert!(bc.vouched_by_checkpoint(&below).unwrap());
+        bc.validate_block(&below).unwrap();
+        let claiming = Transaction::new_coinbase_with_fees(miner.address(), String::new(), 2, 5).unwrap();
+        let inflated = Block::new_block(vec![claiming], fork_base, 2, INITIAL_TARGET).unwrap();
+        pin_child_of(&mut bc, &inflated);
+        let hash = inflated.get_hash();
+        assert_eq!(rejection(&bc, &inflated), BlockRejectReason::CoinbaseTooLarge { hash, value: 15, allowed: 10 });
+
+        bc.fixed_checkpoints.clear();
+        assert_eq!(rejection(&bc, &below), unsigned);
+    }
+
+    #[test]
+    fn test_corrupted_block_stops_walk_with_error() {
+        let miner = WalletFixture::new(1);
+        let bc = ChainBuilder::new(&miner).empty_blocks(3).build();
+        assert!(bc.find_utxo().is_ok());
+
+        // garble a block in the middle of the chain
+        let hashes = bc.get_block_hashes();
+        bc.db.insert(hashes[2].as_str(), vec![0xff; 16]).unwrap();
+
+        let mut blocks = bc.iter();
+        assert_eq!(blocks.by_ref().count(), 2);
+        assert!(blocks.finish().is_err());
+        assert!(bc.find_utxo().is_err());
+        assert!(bc.get_block(&hashes[2]).is_err());
+
+        // a missing block is reported the same way
+        bc.db.remove(hashes[2].as_str()).unwrap();
+        assert!(bc.find_utxo().is_err());
+    }
+
+    #[test]
+    fn test_headers_are_read_without_their_blocks() {
+        let miner = WalletFixture::new(1);
+        let mut bc = ChainBuilder::new(&miner).empty_blocks(4).build();
+        let rolled_back = bc.rollback(1).unwrap();
+        assert_eq!(bc.get_header(&rolled_back[0].get_hash()).unwrap(), None);
+        let hashes = bc.get_block_hashes();
+        assert_eq!(hashes.len(), 4);
+
+        // garbled blocks below the tip don't get in the way of walking the headers
+        bc.db.insert(hashes[2].as_str(), vec![0xff; 16]).unwrap();
+        assert_eq!(bc.get_block_hashes(), hashes);
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+        let mut walk = bc.headers();
+        assert_eq!(walk.by_ref().map(|header| header.height).collect::<Vec<_>>(), vec![3, 2, 1, 0]);
+        assert!(walk.finish().is_ok());
+        assert!(bc.find_utxo().is_err());
+
+        // a db from before the headers tree gets them from the blocks
+        bc.db.open_tree(HEADERS_TREE).unwrap().clear().unwrap();
+        let tip = bc.get_block(&bc.tip).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+        assert!(bc.db.open_tree(HEADERS_TREE).unwrap().contains_key(&bc.tip).unwrap());
+        assert_eq!(bc.get_header(&bc.tip).unwrap().as_ref(), Some(tip.header()));
+    }
+
+    // A chain at a temporary path, opened once so it has its headers. Tip first
+    fn opened_chain(blocks: usize) -> (String, Vec<Block>) {
+        let miner = WalletFixture::new(1);
+        let blocks: Vec<Block> = ChainBuilder::new(&miner).empty_blocks(blocks).build().iter().collect();
+        let path = stored_chain(&blocks);
+        let bc = reopen(|| Blockchain::open_network(&path, "main")).unwrap();
+        assert!(bc.check_integrity().unwrap().is_intact());
+        (path, blocks)
+    }
+
+    fn damage(path: &str) -> ChainHealth {
+        match reopen(|| Blockchain::open_network(path, "main")).err().unwrap().downcast::<ChainOpenError>() {
+            Ok(ChainOpenError::Damaged(health)) => health,
+            other => panic!("expected a damaged chain, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unreadable_block_is_reported_and_truncated() {
+        let (path, blocks) = opened_chain(3);
+        let audit = AuditLog::with_path("/dev/null/audit");
+        // flip the bytes of the transaction count of the block at height 2
+        let hash = blocks[1].get_hash();
+        {
+            let db = reopen(|| sled::open(&path)).unwrap();
+            let mut stored = db.get(&hash).unwrap().unwrap().to_vec();
+            for byte in &mut stored[16..24] {
+                *byte ^= 0xff;
+            }
+            db.insert(hash.as_str(), stored).unwrap();
+            db.flush().unwrap();
+        }
+
+        let health = damage(&path);
+        assert_eq!(health.blocks_checked, 4);
+        assert_eq!(health.damaged.len(), 1);
+        assert_eq!((health.damaged[0].hash.as_str(), health.damaged[0].height), (hash.as_str(), Some(2)));
+        assert_eq!(health.last_good, Some((1, blocks[2].get_hash())));
+
+        Blockchain::recover_damaged(&path, ChainRecovery::TruncateToLastGood, &audit).unwrap();
+        let bc = reopen(|| Blockchain::open_network(&path, "main")).unwrap();
+        assert_eq!(bc.tip, blocks[2].get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        assert!(!bc.has_block(&blocks[0].get_hash()).unwrap());
+        assert!(!bc.has_block(&hash).unwrap());
+        drop(bc);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_opening_reads_only_the_newest_blocks() {
+        let (path, blocks) = opened_chain(3);
+        // the genesis block can't be read, its header is fine
+        let hash = blocks[3].get_hash();
+        let bc = reopen(|| Blockchain::open_network(&path, "main")).unwrap();
+        bc.db.insert(hash.as_str(), vec![0xff; 16]).unwrap();
+
+        let health = bc.check_headers(3);
+        assert!(health.as_ref().is_ok_and(|health| health.is_intact() && health.blocks_checked == 4));
+        assert_eq!(health.unwrap().last_good, Some((3, blocks[0].get_hash())));
+        let health = bc.check_integrity().unwrap();
+        assert_eq!(health.damaged.iter().map(|block| block.hash.clone()).collect::<Vec<_>>(), vec![hash]);
+        drop(bc);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_broken_chain_can_only_be_resynced() {
+        let (path, blocks) = opened_chain(3);
+        let audit = AuditLog::with_path("/dev/null/audit");
+        // block 1 and its header are gone, nothing below block 2 can be reached
+        let hash = blocks[2].get_hash();
+        {
+            let db = reopen(|| sled::open(&path)).unwrap();
+            db.open_tree(HEADERS_TREE).unwrap().remove(hash.as_str()).unwrap();
+            db.remove(hash.as_str()).unwrap();
+            db.flush().unwrap();
+        }
+
+        let health = damage(&path);
+        assert_eq!(health.blocks_checked, 2);
+        assert_eq!(health.damaged, vec![DamagedBlock { hash, height: None, reason: String::from("block is missing") }]);
+        assert_eq!(health.last_good, None);
+        assert!(health.to_string().contains("No intact part"));
+
+        assert!(Blockchain::recover_damaged(&path, ChainRecovery::TruncateToLastGood, &audit).is_err());
+        Blockchain::recover_damaged(&path, ChainRecovery::Resync, &audit).unwrap();
+        assert!(!Path::new(&path).exists());
+        let bc = reopen(|| Blockchain::open_network(&path, "main")).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+        drop(bc);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_verify_chain_pinpoints_the_first_problem() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2).address();
+        let reward = coinbase(&alice.address(), 0);
+        let payment = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob, 10).build();
+        let builder = ChainBuilder::new(&alice).block(vec![payment]).empty_blocks(2);
+
+        // A second spend of the same output, stored without going through add_block
+        let again = TxBuilder::new(&alice).spend(&reward, 0).pay(&alice.address(), 10).build();
+        let double_spend = builder.next_block(vec![again.clone()]);
+        let bc = builder.build();
+        let report = bc.verify_chain().unwrap();
+        assert_eq!((report.blocks_checked, report.transactions_checked, report.problem), (4, 5, None));
+
+        let mut bad = Blockchain::from_db(double_spend.get_hash(), bc.db.clone());
+        bad.db.insert(double_spend.get_hash(), bincode::serialize(&double_spend).unwrap()).unwrap();
+        let problem = bad.verify_chain().unwrap().problem.unwrap();
+        assert_eq!((problem.block, problem.height, problem.txid), (double_spend.get_hash(), 4, Some(again.id)));
+        assert!(problem.reason.contains("again"));
+
+        // One byte of a stored block flipped, the nonce at its end
+        bad.tip = bc.tip.clone();
+        let hash = bc.get_block_by_height(2).unwrap().get_hash();
+        let mut stored = bc.db.get(&hash).unwrap().unwrap().to_vec();
+        let last = stored.len() - 1;
+        stored[last] ^= 1;
+        bc.db.insert(hash.as_str(), stored).unwrap();
+        let report = bc.verify_chain().unwrap();
+        let problem = report.problem.unwrap();
+        assert_eq!((problem.block, problem.height, problem.txid), (hash, 2, None));
+        assert!(problem.reason.contains("proof of work"));
+        assert_eq!(report.blocks_checked, 1);
+    }
+
+    // A block db of the current schema at a temporary path holding `blocks`, without LAST
+    fn stored_chain(blocks: &[Block]) -> String {
+        let path = std::env::temp_dir().join(format!("blockjain-blocks-{}", rand::random::<u64>()));
+        let db = sled::open(&path).unwrap();
+        for block in blocks {
+            db.insert(block.get_hash(), bincode::serialize(block).unwrap()).unwrap();
+        }
+        db.insert(schema::SCHEMA_VERSION_KEY, &schema::SCHEMA_VERSION.to_be_bytes()).unwrap();
+        db.flush().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_open_creates_genesis_atomically() {
+        let path = stored_chain(&[]);
+        let bc = reopen(|| Blockchain::open(&path)).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+        assert_eq!(bc.take_repair().unwrap(), None);
+        drop(bc);
+
+        // Opening again finds the same tip
+        let tip = reopen(|| Blockchain::open(&path)).unwrap().tip;
+        assert_eq!(reopen(|| Blockchain::open(&path)).unwrap().tip, tip);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_recreating_the_chain_is_audited() {
+        let path = stored_chain(&[]);
+        let log = AuditLog::with_path(&format!("{}-audit", path));
+        let (alice, bob) = (WalletFixture::new(1), WalletFixture::new(2));
+        let first = reopen(|| Blockchain::create_at(&path, alice.address(), &log)).unwrap().tip;
+        let second = reopen(|| Blockchain::create_at(&path, bob.address(), &log)).unwrap().tip;
+        assert_ne!(first, second);
+
+        // Retries on a held lock are recorded as failures
+        let entries = log.entries().unwrap();
+        let done: Vec<&audit::AuditEntry> = entries.iter().filter(|e| e.outcome == AuditOutcome::Done).collect();
+        assert!(entries.iter().all(|e| e.action == AuditAction::RecreateChain));
+        assert_eq!(done.len(), 2);
+        assert_eq!(done[1].params[1], (String::from("genesis address"), audit::short_address(&bob.address())));
+        assert_eq!(audit::verify(&entries), None);
+        std::fs::remove_dir_all(&path).ok();
+        std::fs::remove_dir_all(format!("{}-audit", path)).ok();
+    }
+
+    #[test]
+    fn test_open_repairs_missing_tip() {
+        let miner = WalletFixture::new(1);
+        let blocks: Vec<Block> = ChainBuilder::new(&miner).empty_blocks(2).build().iter().collect();
+        let path = stored_chain(&blocks);
+
+        let bc = reopen(|| Blockchain::open(&path)).unwrap();
+        assert_eq!(bc.tip, blocks[0].get_hash());
+        let repair = bc.take_repair().unwrap().unwrap();
+        assert_eq!(repair.problem, ChainOpenError::MissingTip { blocks: 3 }.to_string());
+        assert_eq!(repair.height, 2);
+        assert_eq!(bc.take_repair().unwrap(), None);
+        drop(bc);
+
+        // The repair was written, there's nothing left to fix
+        let bc = reopen(|| Blockchain::open(&path)).unwrap();
+        assert_eq!(bc.tip, blocks[0].get_hash());
+        assert_eq!(bc.take_repair().unwrap(), None);
+        drop(bc);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_open_repairs_dangling_tip() {
+        let miner = WalletFixture::new(1);
+        let blocks: Vec<Block> = ChainBuilder::new(&miner).empty_blocks(1).build().iter().collect();
+        let path = stored_chain(&blocks);
+        reopen(|| sled::open(&path)).unwrap().insert("LAST", "ab".repeat(32).as_bytes()).unwrap();
+
+        let bc = reopen(|| Blockchain::open(&path)).unwrap();
+        assert_eq!(bc.tip, blocks[0].get_hash());
+        let repair = bc.take_repair().unwrap().unwrap();
+        assert_eq!(repair.problem, ChainOpenError::DanglingTip { tip: "ab".repeat(32) }.to_string());
+        drop(bc);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_open_follows_unapplied_blocks() {
+        let miner = WalletFixture::new(1);
+        let blocks: Vec<Block> = ChainBuilder::new(&miner).empty_blocks(2).build().iter().collect();
+        let path = stored_chain(&blocks);
+        // Both blocks were stored but LAST stayed at the genesis block
+        reopen(|| sled::open(&path)).unwrap().insert("LAST", blocks[2].get_hash().as_bytes()).unwrap();
+
+        let bc = reopen(|| Blockchain::open(&path)).unwrap();
+        assert_eq!(bc.tip, blocks[0].get_hash());
+        assert_eq!(bc.get_block_by_height(1).unwrap().get_hash(), blocks[1].get_hash());
+        let repair = bc.take_repair().unwrap().unwrap();
+        assert_eq!(repair.problem, ChainOpenError::UnappliedBlocks { tip: blocks[2].get_hash(), blocks: 2 }.to_string());
+        assert_eq!(repair.height, 2);
+        drop(bc);
+
+        // A rolled back chain stays rolled back
+        let mut bc = reopen(|| Blockchain::open(&path)).unwrap();
+        assert_eq!(bc.take_repair().unwrap(), None);
+        bc.rollback(1).unwrap();
+        drop(bc);
+        let bc = reopen(|| Blockchain::open(&path)).unwrap();
+        assert_eq!(bc.tip, blocks[1].get_hash());
+        assert_eq!(bc.take_repair().unwrap(), None);
+        drop(bc);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_open_refuses_ambiguous_tip() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(1);
+        let fork = chain.next_block(vec![coinbase(&WalletFixture::new(2).address(), 2)]);
+        let mut blocks: Vec<Block> = chain.empty_blocks(1).build().iter().collect();
+        blocks.push(fork);
+        let path = stored_chain(&blocks);
+
+        let err = reopen(|| Blockchain::open(&path)).unwrap_err();
+        match err.downcast_ref::<ChainOpenError>() {
+            Some(ChainOpenError::AmbiguousTip { height, candidates, .. }) => assert_eq!((*height, *candidates), (2, 2)),
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        // Nothing was written
+        assert!(reopen(|| sled::open(&path)).unwrap().get("LAST").unwrap().is_none());
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use failure::format_err;
+use tokio::sync::RwLock;
+
+use crate::audit::{AuditAction, AuditOutcome};
+use crate::block::{Block, DatalessBlock};
+use crate::errors::Result;
+use crate::protocol::MAX_MESSAGE_SIZE;
+use crate::server::Server;
+use crate::snapshot::SnapshotEntry;
+use crate::state_digest::{StateDigest, STATE_DIGEST_VERSION};
+use crate::utxoset::UTXOSet;
+
+/*
+    Chain files
+
+    A portable copy of the chain for moving a node to another machine, the sled directory
+    depends on the sled version and the platform. The file is
+
+        MAGIC               8 bytes
+        block count         u64, little endian
+        digest length       u32, little endian
+        state digest        bincode StateDigest at the last block
+        per block, genesis first:
+            length          u32, little endian
+            block           bincode, as stored and sent to peers
+
+    Files from before transactions carried data start with DATALESS_MAGIC and hold their
+    blocks as DatalessBlock. Files from before state digests start with LEGACY_MAGIC, go
+    straight from the block count to the blocks and hold them as DatalessBlock too.
+
+    Nothing in the file is trusted: importing adds every block through
+    `Blockchain::add_block`. Before that, `verify_state_digest` replays the file's
+    transactions and refuses a file whose state doesn't match its digest, which is also how
+    a recipient checks a copy against the digest its sender published. A file is written to
+    <path>.part and only renamed to <path> once complete, so an interrupted export leaves no
+    file that looks whole.
+*/
+
+pub const MAGIC: &[u8; 8] = b"BJCHAIN3";
+pub const DATALESS_MAGIC: &[u8; 8] = b"BJCHAIN2";
+pub const LEGACY_MAGIC: &[u8; 8] = b"BJCHAIN1";
+// Far more than a StateDigest takes
+const MAX_DIGEST_LEN: u32 = 1024;
+pub const FILE_EXTENSION: &str = "bjchain";
+
+/// Blocks an import added, and those that were already stored
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub known: usize,
+}
+
+pub struct ChainFileWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    part: PathBuf, // written until finish
+}
+
+impl ChainFileWriter {
+    /// Starts a file of `block_count` blocks, the last of which is at the state `digest`
+    pub fn create(path: &Path, block_count: usize, digest: &StateDigest) -> Result<ChainFileWriter> {
+        let part = PathBuf::from(format!("{}.part", path.display()));
+        let mut file = BufWriter::new(File::create(&part)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&(block_count as u64).to_le_bytes())?;
+        let digest = bincode::serialize(digest)?;
+        file.write_all(&(digest.len() as u32).to_le_bytes())?;
+        file.write_all(&digest)?;
+        Ok(ChainFileWriter { file, path: path.to_path_buf(), part })
+    }
+
+    pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        let data = bincode::serialize(block)?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Flushes the file to disk and gives it its name
+    pub fn finish(self) -> Result<()> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.part, &self.path)?;
+        Ok(())
+    }
+}
+
+pub struct ChainFileReader {
+    file: BufReader<File>,
+    block_count: usize,
+    digest: Option<StateDigest>, // None in legacy files
+    dataless: bool,              // blocks are DatalessBlock
+    read: usize,
+}
+
+impl ChainFileReader {
+    pub fn open(path: &Path) -> Result<ChainFileReader> {
+        let not_chain_file = || format_err!("{} isn't a chain file", path.display());
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        let mut count = [0u8; 8];
+        if file.read_exact(&mut magic).is_err() || ![MAGIC, DATALESS_MAGIC, LEGACY_MAGIC].contains(&&magic) || file.read_exact(&mut count).is_err() {
+            return Err(not_chain_file());
+        }
+
+        let mut digest = None;
+        if &magic != LEGACY_MAGIC {
+            let mut len = [0u8; 4];
+            file.read_exact(&mut len).map_err(|_| not_chain_file())?;
+            let len = u32::from_le_bytes(len);
+            if len > MAX_DIGEST_LEN {
+                return Err(format_err!("The state digest of {} is {} bytes, more than a digest can be", path.display(), len));
+            }
+            let mut data = vec![0u8; len as usize];
+            file.read_exact(&mut data).map_err(|_| not_chain_file())?;
+            digest = Some(bincode::deserialize(&data).map_err(|e| format_err!("The state digest of {} is corrupted: {}", path.display(), e))?);
+        }
+        let dataless = &magic != MAGIC;
+        Ok(ChainFileReader { file, block_count: u64::from_le_bytes(count) as usize, digest, dataless, read: 0 })
+    }
+
+    /// Blocks the file says it holds
+    pub fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    /// The state the file says its last block is at, None for legacy files
+    pub fn state_digest(&self) -> Option<&StateDigest> {
+        self.digest.as_ref()
+    }
+
+    /// The next block, None after the last one
+    pub fn next_block(&mut self) -> Result<Option<Block>> {
+        if self.read == self.block_count {
+            return Ok(None);
+        }
+        let truncated = |read: usize, total: usize| format_err!("The chain file ends after {} of {} blocks", read, total);
+
+        let mut len = [0u8; 4];
+        let mut read_exact = |buf: &mut [u8]| match self.file.read_exact(buf) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(truncated(self.read, self.block_count)),
+            other => Ok(other?),
+        };
+        read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as u64;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(format_err!("Block {} of the chain file is {} bytes, more than a block can be", self.read, len));
+        }
+        let mut data = vec![0u8; len as usize];
+        read_exact(&mut data)?;
+        let block = if self.dataless {
+            bincode::deserialize::<DatalessBlock>(&data).map(Block::from)
+        } else {
+            bincode::deserialize(&data)
+        };
+        let block = block.map_err(|e| format_err!("Block {} of the chain file is corrupted: {}", self.read, e))?;
+        self.read += 1;
+        Ok(Some(block))
+    }
+}
+
+/// Replays the transactions of the chain file at `path` and checks the state they end at
+/// against the file's digest. Returns the digest, None for a legacy file without one. No
+/// proof of work or signature is checked, that's left to the import
+pub fn verify_state_digest(path: &Path) -> Result<Option<StateDigest>> {
+    let mut file = ChainFileReader::open(path)?;
+    let Some(digest) = file.state_digest().cloned() else {
+        return Ok(None);
+    };
+    if digest.version != STATE_DIGEST_VERSION {
+        return Err(format_err!(
+            "The chain file's state digest has format version {}, this release knows version {}",
+            digest.version, STATE_DIGEST_VERSION
+        ));
+    }
+
+    let mut utxos: HashMap<String, SnapshotEntry> = HashMap::new();
+    let mut last = None;
+    while let Some(block) = file.next_block()? {
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    if let Some(entry) = utxos.get_mut(&vin.txid) {
+                        entry.unspent.retain(|index| *index != vin.vout);
+                    }
+                }
+            }
+            let unspent = (0..tx.vout.len() as i32).collect();
+            utxos.insert(tx.id.clone(), SnapshotEntry { tx: tx.clone(), unspent });
+        }
+        last = Some((block.get_height(), block.get_hash()));
+    }
+
+    let entries: Vec<SnapshotEntry> = utxos.into_values().collect();
+    if last != Some((digest.height, digest.block_hash.clone())) || !digest.matches(&entries) {
+        return Err(format_err!("The chain file doesn't match its state digest {}", digest));
+    }
+    Ok(Some(digest))
+}
+
+/// Imports the chain file at `path` and rebuilds the UTXO set. Like compaction it's refused
+/// while the node syncs. `progress` gets the blocks read and the total. The file may replace
+/// the chain, so the import goes into the node's audit log
+pub async fn import_chain(
+    utxo_set: &Arc<RwLock<UTXOSet>>,
+    server: &Server,
+    path: &Path,
+    progress: impl FnMut(usize, usize) + Send,
+) -> Result<ImportSummary> {
+    let result = import(utxo_set, server, path, progress).await;
+    let mut params = vec![(String::from("file"), path.display().to_string())];
+    if let Ok(summary) = &result {
+        params.push((String::from("blocks added"), summary.added.to_string()));
+    }
+    server.audit_log().record(AuditAction::ImportChain, params, AuditOutcome::from(&result));
+    result
+}
+
+async fn import(
+    utxo_set: &Arc<RwLock<UTXOSet>>,
+    server: &Server,
+    path: &Path,
+    mut progress: impl FnMut(usize, usize) + Send,
+) -> Result<ImportSummary> {
+    if server.is_syncing().await {
+        return Err(format_err!("The node is syncing, try again once it's done"));
+    }
+    let utxo = utxo_set.read().await;
+    let result = utxo.blockchain.write().await.import_from_file(path, &mut progress);
+    // Blocks added before a failure stay, the UTXO set has to follow them either way
+    utxo.reindex().await?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{dataless_block, ChainBuilder, TxBuilder, WalletFixture};
+
+    fn temp_file() -> PathBuf {
+        std::env::temp_dir().join(format!("blockjain-chain-{}.{}", rand::random::<u64>(), FILE_EXTENSION))
+    }
+
+    #[test]
+    fn test_export_and_import_between_nodes() {
+        let miner = WalletFixture::new(1);
+        let source = ChainBuilder::new(&miner).empty_blocks(4).build();
+        let path = temp_file();
+        let mut reported = Vec::new();
+        assert_eq!(source.export_to_file(&path, &mut |done, total| reported.push((done, total))).unwrap(), 5);
+        assert_eq!(reported.last(), Some(&(5, 5)));
+        assert!(!PathBuf::from(format!("{}.part", path.display())).exists());
+
+        // A fresh node takes the file's genesis block in place of its own
+        let mut fresh = ChainBuilder::new(&WalletFixture::new(2)).build();
+        let summary = fresh.import_from_file(&path, &mut |_, _| {}).unwrap();
+        assert_eq!(summary, ImportSummary { added: 5, known: 0 });
+        assert_eq!(fresh.tip, source.tip);
+        assert_eq!(fresh.get_block_hashes(), source.get_block_hashes());
+        assert_eq!(fresh.get_block_by_height(0).unwrap().get_hash(), source.get_block_by_height(0).unwrap().get_hash());
+
+        // A node on the same chain only adds what it's missing
+        let start = temp_file();
+        let mut writer = ChainFileWriter::create(&start, 2, &source.compute_state_digest(1).unwrap()).unwrap();
+        for height in 0..2 {
+            writer.write_block(&source.get_block_by_height(height).unwrap()).unwrap();
+        }
+        writer.finish().unwrap();
+        let mut behind = ChainBuilder::new(&WalletFixture::new(2)).build();
+        behind.import_from_file(&start, &mut |_, _| {}).unwrap();
+        assert_eq!(behind.get_best_height().unwrap(), 1);
+        let summary = behind.import_from_file(&path, &mut |_, _| {}).unwrap();
+        assert_eq!(summary, ImportSummary { added: 3, known: 2 });
+        assert_eq!(behind.tip, source.tip);
+
+        // A node with blocks of another chain refuses it
+        let mut other = ChainBuilder::new(&WalletFixture::new(3)).empty_blocks(1).build();
+        let tip = other.tip.clone();
+        assert!(other.import_from_file(&path, &mut |_, _| {}).unwrap_err().to_string().contains("another genesis block"));
+        assert_eq!(other.tip, tip);
+
+        fs::remove_file(&path).ok();
+        fs::remove_file(&start).ok();
+    }
+
+    #[test]
+    fn test_damaged_chain_files() {
+        let miner = WalletFixture::new(1);
+        let source = ChainBuilder::new(&miner).empty_blocks(2).build();
+        let path = temp_file();
+        source.export_to_file(&path, &mut |_, _| {}).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let import = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            ChainBuilder::new(&WalletFixture::new(2)).build().import_from_file(&path, &mut |_, _| {}).unwrap_err().to_string()
+        };
+
+        assert!(import(&bytes[..bytes.len() - 10]).contains("ends after 2 of 3 blocks"));
+        assert!(import(b"not a chain file").contains("isn't a chain file"));
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1; // the nonce of the last block
+        assert!(import(&tampered).contains("proof of work"));
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_refuses_files_not_matching_their_state_digest() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let source = chain.block(vec![tx]).empty_blocks(2).build();
+        let path = temp_file();
+        source.export_to_file(&path, &mut |_, _| {}).unwrap();
+        let digest = source.compute_state_digest(source.get_best_height().unwrap()).unwrap();
+        assert_eq!(verify_state_digest(&path).unwrap(), Some(digest.clone()));
+
+        // The same blocks under the digest of a state with one output worth a coin more
+        let mut entries = source.unspent_transactions(&source.tip).unwrap();
+        let index = entries[0].unspent[0] as usize;
+        entries[0].tx.vout[index].value += 1;
+        let forged = StateDigest::compute(digest.height, &digest.block_hash, &entries);
+        let mut writer = ChainFileWriter::create(&path, 4, &forged).unwrap();
+        for height in 0..4 {
+            writer.write_block(&source.get_block_by_height(height).unwrap()).unwrap();
+        }
+        writer.finish().unwrap();
+        assert!(verify_state_digest(&path).unwrap_err().to_string().contains("doesn't match its state digest"));
+        let mut fresh = ChainBuilder::new(&WalletFixture::new(3)).build();
+        let tip = fresh.tip.clone();
+        assert!(fresh.import_from_file(&path, &mut |_, _| {}).is_err());
+        assert_eq!(fresh.tip, tip);
+
+        // Files from before digests, with blocks from before transaction data, are imported unchecked
+        let mut legacy = LEGACY_MAGIC.to_vec();
+        legacy.extend_from_slice(&4u64.to_le_bytes());
+        for height in 0..4 {
+            let block = bincode::serialize(&dataless_block(&source.get_block_by_height(height).unwrap())).unwrap();
+            legacy.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            legacy.extend_from_slice(&block);
+        }
+        fs::write(&path, legacy).unwrap();
+        assert_eq!(verify_state_digest(&path).unwrap(), None);
+        assert_eq!(fresh.import_from_file(&path, &mut |_, _| {}).unwrap().added, 4);
+        assert_eq!(fresh.tip, source.tip);
+
+        fs::remove_file(&path).ok();
+    }
+}
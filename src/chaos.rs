@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/*
+    Network chaos (development only, `chaos` feature)
+
+    Localhost never loses or delays anything, so reorgs, propagation and recovery are tested
+    against conditions made up here. Every outgoing message asks `Chaos::fate` before
+    send_data connects, and is
+
+        - delayed by latency_ms plus up to jitter_ms,
+        - dropped with drop_probability. The sender sees a successful send, as it would
+          when a packet is lost after leaving,
+        - or refused while the link is in a scheduled disconnect: out of every
+          disconnect_every_ms the peer is unreachable for the last disconnect_for_ms.
+          Refused sends don't count towards the peer's no_response_counter, so the
+          schedule doesn't get the peer removed.
+
+    Links without an entry in `peers` use `default`. The configuration is read from
+    CHAOS_CONFIG_PATH when the server is created and can be changed from the debug console.
+    Builds without the feature contain none of this.
+*/
+
+pub const CHAOS_CONFIG_PATH: &str = "chaos.json";
+
+/// Conditions on the link to one peer. The default is a perfect link
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LinkChaos {
+    pub latency_ms: u64,
+    pub jitter_ms: u64,
+    pub drop_probability: f64, // 0.0 to 1.0
+    pub disconnect_every_ms: u64, // 0 never disconnects
+    pub disconnect_for_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosConfig {
+    pub default: LinkChaos,
+    pub peers: HashMap<String, LinkChaos>, // by peer address
+    pub seed: Option<u64>, // repeatable drops and jitter, random without
+}
+
+impl ChaosConfig {
+    /// A missing file is a perfect network
+    pub fn load(path: &str) -> ChaosConfig {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                println!("Ignoring {}: {}", path, e);
+                ChaosConfig::default()
+            }),
+            Err(_) => ChaosConfig::default(),
+        }
+    }
+
+    pub fn link(&self, addr: &str) -> &LinkChaos {
+        self.peers.get(addr).unwrap_or(&self.default)
+    }
+}
+
+/// What happens to one outgoing message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fate {
+    Deliver(Duration), // after this delay
+    Drop,
+    Disconnected,
+}
+
+pub struct Chaos {
+    config: Mutex<ChaosConfig>,
+    rng: Mutex<StdRng>,
+    started: Instant, // disconnect schedules count from here
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Chaos {
+        Chaos {
+            rng: Mutex::new(Self::rng(&config)),
+            config: Mutex::new(config),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Chaos {
+        Self::new(ChaosConfig::load(path))
+    }
+
+    fn rng(config: &ChaosConfig) -> StdRng {
+        match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    pub fn config(&self) -> ChaosConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Replaces the configuration, a seed restarts its sequence
+    pub fn set_config(&self, config: ChaosConfig) {
+        *self.rng.lock().unwrap() = Self::rng(&config);
+        *self.config.lock().unwrap() = config;
+    }
+
+    pub fn fate(&self, addr: &str) -> Fate {
+        let link = self.config.lock().unwrap().link(addr).clone();
+        if link.disconnect_every_ms > 0 {
+            let phase = self.started.elapsed().as_millis() as u64 % link.disconnect_every_ms;
+            if phase + link.disconnect_for_ms >= link.disconnect_every_ms {
+                return Fate::Disconnected;
+            }
+        }
+
+        let mut rng = self.rng.lock().unwrap();
+        if link.drop_probability > 0.0 && rng.gen_bool(link.drop_probability.min(1.0)) {
+            return Fate::Drop;
+        }
+        let jitter = if link.jitter_ms > 0 { rng.gen_range(0..=link.jitter_ms) } else { 0 };
+        Fate::Deliver(Duration::from_millis(link.latency_ms + jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fates_follow_the_link_settings() {
+        let lossy = LinkChaos { latency_ms: 20, jitter_ms: 10, drop_probability: 0.3, ..LinkChaos::default() };
+        let down = LinkChaos { disconnect_every_ms: 1000, disconnect_for_ms: 1000, ..LinkChaos::default() };
+        let config = ChaosConfig {
+            default: lossy,
+            peers: HashMap::from([(String::from("127.0.0.1:2"), down)]),
+            seed: Some(7),
+        };
+        let chaos = Chaos::new(config.clone());
+
+        let fates: Vec<Fate> = (0..1000).map(|_| chaos.fate("127.0.0.1:1")).collect();
+        let dropped = fates.iter().filter(|fate| **fate == Fate::Drop).count();
+        assert!((250..350).contains(&dropped), "{} of 1000 dropped", dropped);
+        for fate in &fates {
+            if let Fate::Deliver(delay) = fate {
+                assert!((Duration::from_millis(20)..=Duration::from_millis(30)).contains(delay));
+            }
+        }
+        assert_eq!(chaos.fate("127.0.0.1:2"), Fate::Disconnected);
+
+        // Same seed, same fates
+        chaos.set_config(config);
+        let again: Vec<Fate> = (0..1000).map(|_| chaos.fate("127.0.0.1:1")).collect();
+        assert_eq!(fates, again);
+
+        // Perfect by default
+        assert_eq!(Chaos::new(ChaosConfig::default()).fate("127.0.0.1:1"), Fate::Deliver(Duration::ZERO));
+    }
+}
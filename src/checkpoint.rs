@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Result;
+use crate::settings::SETTINGS;
+
+/*
+    Operator checkpoints
+
+    On a private network the operator can pin "the block at height H is X" by signing it
+    with the operator key. Nodes configured with the operator's public key
+    (Settings: operator_public_key) store the checkpoint and refuse any block that
+    contradicts it, so a longer fork that doesn't contain X can't replace the chain.
+
+    Fixed checkpoints
+
+    BUILTIN_CHECKPOINTS and Settings: checkpoints pin blocks the same way without a
+    signature, they're trusted because they come with the build or the configuration.
+    Every network creates its own genesis block on first start, so the built-in list is
+    empty until a network's history is worth pinning.
+
+    Blocks the highest checkpoint (fixed or operator) builds on skip the signature checks
+    of their transactions, the pinned block vouches for them. Everything else about them is
+    checked, and blocks of other branches below the checkpoint are checked in full. A block
+    is only known to be under the pinned block once the pinned block's header is stored.
+*/
+
+/// (height, block hash) pinned in every build
+pub const BUILTIN_CHECKPOINTS: &[(i32, &str)] = &[];
+
+/// The built-in checkpoints with the configured ones, a configured one wins at the same height
+pub fn fixed_checkpoints() -> BTreeMap<i32, String> {
+    let mut checkpoints: BTreeMap<i32, String> = BUILTIN_CHECKPOINTS.iter()
+        .map(|(height, hash)| (*height, hash.to_string()))
+        .collect();
+    checkpoints.extend(SETTINGS.checkpoints.iter().cloned());
+    checkpoints
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub height: i32,
+    pub hash: String,
+    pub signature: Vec<u8>,
+}
+
+impl Checkpoint {
+    /// Signing tool for the operator: pins block `hash` at `height` with the operator's secret key
+    pub fn sign(height: i32, hash: &str, operator_secret_key: &[u8]) -> Result<Checkpoint> {
+        let secret_key: &[u8; 32] = operator_secret_key
+            .try_into()
+            .map_err(|_| format_err!("Operator secret key must be 32 bytes"))?;
+        let signing_key = SigningKey::from_bytes(secret_key);
+        let signature = signing_key.sign(&Checkpoint::signed_data(height, hash)?);
+
+        Ok(Checkpoint {
+            height,
+            hash: hash.to_string(),
+            signature: signature.to_bytes().to_vec(),
+        })
+    }
+
+    // true when the checkpoint was signed by the owner of `operator_public_key`
+    pub fn verify(&self, operator_public_key: &[u8]) -> bool {
+        let public_key = match <&[u8; 32]>::try_from(operator_public_key) {
+            Ok(bytes) => match VerifyingKey::from_bytes(bytes) {
+                Ok(key) => key,
+                Err(_) => return false,
+            },
+            Err(_) => return false,
+        };
+        let signature = match <&[u8; 64]>::try_from(self.signature.as_slice()) {
+            Ok(bytes) => Signature::from_bytes(bytes),
+            Err(_) => return false,
+        };
+
+        match Checkpoint::signed_data(self.height, &self.hash) {
+            Ok(data) => public_key.verify(&data, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // What the operator signs, prefixed so the signature can't be reused for anything else
+    fn signed_data(height: i32, hash: &str) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&("opcheckpoint", height, hash))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::WalletFixture;
+
+    #[test]
+    fn test_checkpoint_signature() {
+        let operator = WalletFixture::new(1).wallet;
+        let someone_else = WalletFixture::new(2).wallet;
+
+        let checkpoint = Checkpoint::sign(5000, "00ab", &operator.secret_key).unwrap();
+        assert!(checkpoint.verify(&operator.public_key));
+        assert!(!checkpoint.verify(&someone_else.public_key));
+
+        // Changing either pinned field invalidates the signature
+        let mut moved = checkpoint.clone();
+        moved.height = 5001;
+        assert!(!moved.verify(&operator.public_key));
+        let mut replaced = checkpoint.clone();
+        replaced.hash = String::from("00cd");
+        assert!(!replaced.verify(&operator.public_key));
+
+        // Malformed keys and signatures are rejected rather than panicking
+        assert!(!checkpoint.verify(&[1, 2, 3]));
+        let mut truncated = checkpoint;
+        truncated.signature.truncate(10);
+        assert!(!truncated.verify(&operator.public_key));
+        assert!(Checkpoint::sign(1, "00ab", &[0; 5]).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/*
+    Network time and tip age
+
+    Peers put their clock in the version message. The median offset to them corrects a
+    local clock that's off, so a node whose clock runs late doesn't take an old tip for a
+    fresh one. Fewer than MIN_TIME_SAMPLES peers, or a median beyond MAX_TIME_ADJUSTMENT,
+    leave the local clock as it is.
+
+    The tip counts as stalled once it's older than `stale_tip_multiple` expected block
+    intervals (Settings) while peers are connected: blocks should be arriving but aren't.
+
+    A maintenance tick that comes far later than its interval means the machine was asleep.
+    Linux stops the monotonic clock during suspend and other systems don't, so both it and
+    the wall clock are compared with the interval and the larger gap counts.
+*/
+
+pub const MIN_TIME_SAMPLES: usize = 3;
+pub const MAX_TIME_ADJUSTMENT: Duration = Duration::from_secs(70 * 60);
+// Peers remembered for the median, the oldest sample is dropped past this
+pub const MAX_TIME_SAMPLES: usize = 200;
+// Shortest gap between ticks taken for a sleep, shorter ones are scheduling delays
+pub const MIN_SLEEP: Duration = Duration::from_secs(60);
+
+/// Local time in milliseconds since the epoch, like block timestamps
+pub fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis())
+}
+
+/// Clock offsets to peers, in milliseconds (peer minus local)
+#[derive(Debug, Default)]
+pub struct NetworkClock {
+    offsets: HashMap<String, i64>,
+    order: Vec<String>, // peers by first sample, oldest first
+}
+
+impl NetworkClock {
+    pub fn add_sample(&mut self, peer: &str, peer_time: u128, local_time: u128) {
+        let offset = (peer_time as i128 - local_time as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+        if self.offsets.insert(peer.to_string(), offset).is_none() {
+            self.order.push(peer.to_string());
+            if self.order.len() > MAX_TIME_SAMPLES {
+                let oldest = self.order.remove(0);
+                self.offsets.remove(&oldest);
+            }
+        }
+    }
+
+    /// Median offset to peers, None when there are too few samples
+    pub fn median_offset(&self) -> Option<i64> {
+        if self.offsets.len() < MIN_TIME_SAMPLES {
+            return None;
+        }
+        let mut offsets: Vec<i64> = self.offsets.values().copied().collect();
+        offsets.sort();
+        Some(offsets[offsets.len() / 2])
+    }
+
+    /// Median offset to peers, 0 when there are too few or it's implausibly large
+    pub fn offset(&self) -> i64 {
+        match self.median_offset() {
+            Some(median) if median.unsigned_abs() as u128 <= MAX_TIME_ADJUSTMENT.as_millis() => median,
+            _ => 0,
+        }
+    }
+
+    /// Network-adjusted time in milliseconds since the epoch
+    pub fn now(&self) -> u128 {
+        (now_millis() as i128 + self.offset() as i128).max(0) as u128
+    }
+
+    /// Forgets every sample, the next version messages take new ones
+    pub fn reset(&mut self) {
+        self.offsets.clear();
+        self.order.clear();
+    }
+}
+
+/// Notices ticks of a periodic timer that came much later than `interval`
+#[derive(Debug)]
+pub struct WakeDetector {
+    interval: Duration,
+    last: Option<(Duration, u128)>, // monotonic time and wall clock (ms) of the previous tick
+}
+
+impl WakeDetector {
+    pub fn new(interval: Duration) -> Self {
+        WakeDetector { interval, last: None }
+    }
+
+    /// For timers whose period changes, the next tick is measured against `interval`
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Records a tick at `monotonic` (time since any fixed start) and `wall` (ms since the
+    /// epoch). Returns how long the machine slept since the previous tick, if it did
+    pub fn tick(&mut self, monotonic: Duration, wall: u128) -> Option<Duration> {
+        let slept = self.last.and_then(|(last_monotonic, last_wall)| {
+            let wall_elapsed = Duration::from_millis(wall.saturating_sub(last_wall) as u64);
+            slept_between_ticks(self.interval, monotonic.saturating_sub(last_monotonic), wall_elapsed)
+        });
+        self.last = Some((monotonic, wall));
+        slept
+    }
+}
+
+/// How long the machine slept between two ticks `interval` apart, given the monotonic and
+/// wall clock time that passed between them. None for ordinary delays
+pub fn slept_between_ticks(interval: Duration, monotonic: Duration, wall: Duration) -> Option<Duration> {
+    let gap = monotonic.max(wall).saturating_sub(interval);
+    (gap >= MIN_SLEEP.max(interval * 2)).then_some(gap)
+}
+
+/// How old a block with `timestamp` (ms) is at `now` (ms). Zero for blocks from the future
+pub fn tip_age(timestamp: u128, now: u128) -> Duration {
+    Duration::from_millis(now.saturating_sub(timestamp) as u64)
+}
+
+/// Whether a tip of `tip_age` means the node stopped following the chain.
+/// A `multiple` of 0 turns the check off, and without peers there's nothing to follow.
+pub fn is_stalled(tip_age: Duration, block_interval: Duration, multiple: u32, peers: usize) -> bool {
+    peers > 0 && multiple > 0 && tip_age > block_interval * multiple
+}
+
+/// "3 hours", "1 day", ... the largest whole unit
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (count, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stalled_boundaries() {
+        let interval = Duration::from_secs(600);
+        let limit = interval * 6;
+
+        assert!(!is_stalled(limit, interval, 6, 3)); // exactly at the limit is fine
+        assert!(is_stalled(limit + Duration::from_millis(1), interval, 6, 3));
+        assert!(!is_stalled(Duration::ZERO, interval, 6, 3));
+
+        // No peers or a multiple of 0: never stalled
+        assert!(!is_stalled(limit * 10, interval, 6, 0));
+        assert!(!is_stalled(limit * 10, interval, 0, 3));
+
+        // The multiple moves the boundary
+        assert!(is_stalled(interval * 2 + Duration::from_secs(1), interval, 2, 1));
+        assert!(!is_stalled(interval * 2 + Duration::from_secs(1), interval, 3, 1));
+    }
+
+    #[test]
+    fn test_network_clock() {
+        let mut clock = NetworkClock::default();
+        let local = 1_000_000_000;
+
+        clock.add_sample("a", local + 5_000, local);
+        clock.add_sample("b", local + 7_000, local);
+        assert_eq!(clock.offset(), 0); // too few peers yet
+
+        clock.add_sample("c", local - 1_000, local);
+        assert_eq!(clock.offset(), 5_000);
+
+        // A peer's newer sample replaces its old one
+        clock.add_sample("c", local + 9_000, local);
+        assert_eq!(clock.offset(), 7_000);
+
+        // Medians beyond the cap are ignored
+        let far = MAX_TIME_ADJUSTMENT.as_millis() + 1;
+        for peer in ["a", "b", "c"] {
+            clock.add_sample(peer, local + far, local);
+        }
+        assert_eq!(clock.offset(), 0);
+
+        assert_eq!(tip_age(local, local + 3_600_000), Duration::from_secs(3600));
+        assert_eq!(tip_age(local + 1, local), Duration::ZERO);
+        assert_eq!(format_age(Duration::from_secs(3 * 3600 + 59)), "3 hours");
+        assert_eq!(format_age(Duration::from_secs(60)), "1 minute");
+
+        clock.reset();
+        assert_eq!(clock.median_offset(), None);
+    }
+
+    // Feeds ticks given as (monotonic seconds, wall clock seconds) and returns the sleeps found
+    fn sleeps(interval: u64, ticks: &[(u64, u64)]) -> Vec<Option<u64>> {
+        let mut detector = WakeDetector::new(Duration::from_secs(interval));
+        ticks.iter()
+            .map(|(monotonic, wall)| detector.tick(Duration::from_secs(*monotonic), *wall as u128 * 1000))
+            .map(|slept| slept.map(|d| d.as_secs()))
+            .collect()
+    }
+
+    #[test]
+    fn test_wake_detection() {
+        // Regular ticks, and a few seconds of scheduling delay, are no sleep
+        assert_eq!(sleeps(20, &[(0, 1000), (20, 1020), (45, 1045), (65, 1065)]), vec![None; 4]);
+
+        // Linux: the monotonic clock stood still overnight, the wall clock didn't
+        let night = 7 * 3600;
+        assert_eq!(sleeps(20, &[(0, 1000), (20, 1020 + night), (40, 1040 + night)]), vec![None, Some(night), None]);
+
+        // Other systems: both clocks kept going
+        assert_eq!(sleeps(20, &[(0, 1000), (20 + night, 1020 + night)]), vec![None, Some(night)]);
+
+        // A gap needs to be a minute and two intervals long
+        assert_eq!(sleeps(20, &[(0, 1000), (79, 1079), (160, 1160)]), vec![None, None, Some(61)]);
+        assert_eq!(sleeps(600, &[(0, 1000), (1799, 2799), (3599, 4599)]), vec![None, None, Some(1200)]);
+
+        // The wall clock set back isn't a sleep
+        assert_eq!(sleeps(20, &[(0, 5000), (20, 1000), (40, 1020)]), vec![None; 3]);
+    }
+}
@@ -0,0 +1,205 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+/*
+    Coin selection
+
+    Which of a wallet's spendable outputs fund a payment. The candidates are the outputs the
+    wallet may spend right now as (txid, output index, value): outputs locked by pending sends
+    are left out before selection, as immature coinbase outputs will be once the chain has a
+    maturity rule, so no strategy can pick them.
+
+        LargestFirst    fewest inputs
+        SmallestFirst   spends dust first, at the cost of larger transactions
+        ExactMatch      outputs adding up to exactly the amount, so there's no change, found
+                        by a depth first search of at most EXACT_MATCH_TRIES steps. Falls back
+                        to LargestFirst when there's none
+        Random          in random order, so the picks tell less about the wallet
+
+    Ties keep the candidates' order. When the candidates don't cover the amount every one of
+    them is picked, the caller reports what they add up to.
+
+    `sweep_dust` adds the outputs below the dust threshold to a selection. Nobody pays with
+    them alone, so they'd stay in the UTXO set for good; spent along with the others they end
+    up in the change.
+*/
+
+const EXACT_MATCH_TRIES: usize = 100_000;
+
+/// An output the wallet can spend: (txid, output index, value)
+pub type Candidate = (String, i32, i32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSelection {
+    #[default]
+    LargestFirst,
+    SmallestFirst,
+    ExactMatch,
+    Random,
+}
+
+impl CoinSelection {
+    pub const ALL: [CoinSelection; 4] = [CoinSelection::LargestFirst, CoinSelection::SmallestFirst, CoinSelection::ExactMatch, CoinSelection::Random];
+}
+
+impl fmt::Display for CoinSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoinSelection::LargestFirst => write!(f, "Largest first"),
+            CoinSelection::SmallestFirst => write!(f, "Smallest first"),
+            CoinSelection::ExactMatch => write!(f, "Exact match if possible"),
+            CoinSelection::Random => write!(f, "Random"),
+        }
+    }
+}
+
+/// Indexes into `candidates` of the outputs `strategy` spends on `amount`, in the order picked
+pub fn select(candidates: &[Candidate], amount: i32, strategy: CoinSelection) -> Vec<usize> {
+    select_with_rng(candidates, amount, strategy, &mut rand::thread_rng())
+}
+
+/// `select` with the order of CoinSelection::Random drawn from `rng`
+pub fn select_with_rng(candidates: &[Candidate], amount: i32, strategy: CoinSelection, rng: &mut impl Rng) -> Vec<usize> {
+    if amount <= 0 {
+        return Vec::new();
+    }
+    let mut order: Vec<usize> = (0..candidates.len()).collect();
+    match strategy {
+        CoinSelection::LargestFirst => order.sort_by_key(|&i| Reverse(candidates[i].2)),
+        CoinSelection::SmallestFirst => order.sort_by_key(|&i| candidates[i].2),
+        CoinSelection::ExactMatch => {
+            order.sort_by_key(|&i| Reverse(candidates[i].2));
+            if let Some(exact) = exact_match(candidates, &order, amount) {
+                return exact;
+            }
+        }
+        CoinSelection::Random => order.shuffle(rng),
+    }
+
+    let mut total = 0i64;
+    order.into_iter()
+        .take_while(|&i| {
+            let covered = total >= amount as i64;
+            total += candidates[i].2 as i64;
+            !covered
+        })
+        .collect()
+}
+
+/// `picked` followed by every other candidate worth less than `dust_threshold`. An empty
+/// selection stays empty
+pub fn sweep_dust(candidates: &[Candidate], mut picked: Vec<usize>, dust_threshold: i32) -> Vec<usize> {
+    if picked.is_empty() {
+        return picked;
+    }
+    let dust: Vec<usize> = (0..candidates.len())
+        .filter(|i| candidates[*i].2 < dust_threshold && !picked.contains(i))
+        .collect();
+    picked.extend(dust);
+    picked
+}
+
+// Outputs adding up to exactly `amount`, tried largest first (`order`). Includes each output
+// before trying without it and backs out of branches that can't reach the amount anymore
+fn exact_match(candidates: &[Candidate], order: &[usize], amount: i32) -> Option<Vec<usize>> {
+    let values: Vec<i64> = order.iter().map(|&i| candidates[i].2 as i64).collect();
+    // what the outputs from position k on add up to
+    let mut remaining = vec![0i64; values.len() + 1];
+    for k in (0..values.len()).rev() {
+        remaining[k] = remaining[k + 1] + values[k];
+    }
+
+    let amount = amount as i64;
+    let mut picked: Vec<usize> = Vec::new(); // positions in `values`
+    let (mut total, mut k) = (0i64, 0);
+    for _ in 0..EXACT_MATCH_TRIES {
+        if total == amount {
+            return Some(picked.into_iter().map(|position| order[position]).collect());
+        }
+        if k == values.len() || total + remaining[k] < amount {
+            // drop the last output picked and go on without it
+            let last = picked.pop()?;
+            total -= values[last];
+            k = last + 1;
+        } else {
+            if values[k] > 0 && total + values[k] <= amount {
+                picked.push(k);
+                total += values[k];
+            }
+            k += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn candidates(values: &[i32]) -> Vec<Candidate> {
+        values.iter().enumerate().map(|(i, &value)| (format!("tx{}", i), 0, value)).collect()
+    }
+
+    fn picked_values(candidates: &[Candidate], picks: &[usize]) -> Vec<i32> {
+        picks.iter().map(|&i| candidates[i].2).collect()
+    }
+
+    #[test]
+    fn test_each_strategy_picks() {
+        let set = candidates(&[5, 1, 8, 3, 3, 12]);
+        let pick = |amount, strategy| picked_values(&set, &select(&set, amount, strategy));
+
+        assert_eq!(pick(14, CoinSelection::LargestFirst), vec![12, 8]);
+        assert_eq!(pick(6, CoinSelection::SmallestFirst), vec![1, 3, 3]);
+        // 11 = 8 + 3, no change
+        assert_eq!(pick(11, CoinSelection::ExactMatch), vec![8, 3]);
+        assert_eq!(pick(20, CoinSelection::ExactMatch), vec![12, 8]);
+        // nothing adds up to 33 exactly
+        assert_eq!(pick(33, CoinSelection::ExactMatch), vec![12, 8, 5, 3, 3, 1]);
+        assert_eq!(pick(32, CoinSelection::ExactMatch), vec![12, 8, 5, 3, 3, 1]);
+
+        // ties keep the candidates' order
+        assert_eq!(select(&set, 3, CoinSelection::SmallestFirst), vec![1, 3]);
+        assert_eq!(select(&set, 6, CoinSelection::ExactMatch), vec![0, 1]);
+
+        // not enough: everything
+        for strategy in CoinSelection::ALL {
+            assert_eq!(select(&set, 100, strategy).len(), set.len(), "{}", strategy);
+            assert!(select(&set, 0, strategy).is_empty(), "{}", strategy);
+        }
+    }
+
+    #[test]
+    fn test_dust_rides_along_with_a_selection() {
+        let set = candidates(&[1, 9, 1, 2, 6]);
+        let picked = select(&set, 5, CoinSelection::LargestFirst);
+        assert_eq!(picked, vec![1]);
+        assert_eq!(picked_values(&set, &sweep_dust(&set, picked.clone(), 2)), vec![9, 1, 1]);
+        assert_eq!(sweep_dust(&set, picked.clone(), 0), picked);
+        // already picked, not added twice
+        assert_eq!(sweep_dust(&set, vec![0, 1], 2), vec![0, 1, 2]);
+        assert!(sweep_dust(&set, Vec::new(), 2).is_empty());
+    }
+
+    #[test]
+    fn test_random_picks_cover_the_amount() {
+        let set = candidates(&[4, 4, 4, 4, 4, 4, 4, 4]);
+        let draw = |seed| select_with_rng(&set, 9, CoinSelection::Random, &mut StdRng::seed_from_u64(seed));
+
+        let picks = draw(7);
+        assert_eq!(picks.len(), 3);
+        assert_eq!(draw(7), picks);
+        assert!((0..20).any(|seed| draw(seed) != picks));
+        for seed in 0..20 {
+            let mut picks = draw(seed);
+            picks.sort();
+            picks.dedup();
+            assert_eq!(picks.len(), 3);
+        }
+    }
+}
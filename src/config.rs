@@ -0,0 +1,333 @@
+use std::fmt;
+use std::fs;
+
+use failure::format_err;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::errors::Result;
+use crate::settings::{Settings, SETTINGS_PATH};
+
+/*
+    Effective configuration
+
+    A few settings can also come from the environment or the command line. Each is taken
+    from the highest layer that sets it:
+
+        defaults  <  settings.json  <  environment  <  command line
+
+        setting          settings.json key   environment           flag
+        data directory   data_dir            BLOCKJAIN_DATA_DIR    --data-dir <dir>
+        headless         headless            BLOCKJAIN_HEADLESS    --headless
+        port             server_port         BLOCKJAIN_PORT        --port <port>
+        bootstrap node   bootstrap_node      BLOCKJAIN_BOOTSTRAP   --bootstrap <host:port>
+        fullscreen       fullscreen          (none)                (none)
+        allow rollback   (none)              (none)                --allow-rollback
+
+    `resolve` is a pure function of the three layers. The result remembers where every
+    value came from, so the startup log and the Settings tab can show why a value applied,
+    and it's checked for combinations that are probably mistakes.
+*/
+
+pub const ENV_DATA_DIR: &str = "BLOCKJAIN_DATA_DIR";
+pub const ENV_HEADLESS: &str = "BLOCKJAIN_HEADLESS";
+pub const ENV_PORT: &str = "BLOCKJAIN_PORT";
+pub const ENV_BOOTSTRAP: &str = "BLOCKJAIN_BOOTSTRAP";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSource::Default => "default",
+            ConfigSource::File => "settings.json",
+            ConfigSource::Env => "environment",
+            ConfigSource::Cli => "command line",
+        })
+    }
+}
+
+/// What one source sets, None where it says nothing
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigLayer {
+    pub data_dir: Option<String>,
+    pub headless: Option<bool>,
+    pub port: Option<String>,
+    pub bootstrap_node: Option<String>,
+    pub fullscreen: Option<bool>,
+    pub allow_rollback: Option<bool>,
+}
+
+impl ConfigLayer {
+    /// The keys present in the settings file. Keys it doesn't have are left to the defaults
+    /// even though `Settings` fills them in
+    pub fn from_file(path: &str) -> ConfigLayer {
+        let Ok(contents) = fs::read_to_string(path) else {
+            return ConfigLayer::default();
+        };
+        let Ok(Value::Object(file)) = serde_json::from_str::<Value>(&contents) else {
+            return ConfigLayer::default(); // Settings::load falls back to the defaults too
+        };
+        let text = |key: &str| file.get(key).and_then(Value::as_str).map(str::to_string);
+        let flag = |key: &str| file.get(key).and_then(Value::as_bool);
+        ConfigLayer {
+            data_dir: text("data_dir"),
+            headless: flag("headless"),
+            port: text("server_port"),
+            bootstrap_node: text("bootstrap_node"),
+            fullscreen: flag("fullscreen"),
+            allow_rollback: None,
+        }
+    }
+
+    /// The variables `var` returns a value for
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> ConfigLayer {
+        ConfigLayer {
+            data_dir: var(ENV_DATA_DIR),
+            headless: var(ENV_HEADLESS).map(|value| matches!(value.as_str(), "1" | "true" | "yes")),
+            port: var(ENV_PORT),
+            bootstrap_node: var(ENV_BOOTSTRAP),
+            fullscreen: None,
+            allow_rollback: None,
+        }
+    }
+
+    /// The flags among `args` (without the program name). Other arguments are left alone,
+    /// they belong to the rest of the program
+    pub fn from_args(args: &[String]) -> Result<ConfigLayer> {
+        let mut layer = ConfigLayer::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next().cloned()).ok_or_else(|| format_err!("{} needs a value", flag));
+            match flag {
+                "--data-dir" => layer.data_dir = Some(value()?),
+                "--port" => layer.port = Some(value()?),
+                "--bootstrap" => layer.bootstrap_node = Some(value()?),
+                "--headless" => layer.headless = Some(true),
+                "--allow-rollback" => layer.allow_rollback = Some(true),
+                _ => {}
+            }
+        }
+        Ok(layer)
+    }
+}
+
+/// A value and the layer it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sourced<T> {
+    pub value: T,
+    pub source: ConfigSource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveConfig {
+    pub data_dir: Sourced<String>, // the node runs from here, its databases are under data/<network>/
+    pub headless: Sourced<bool>,   // run the node without the window
+    pub port: Sourced<String>,
+    pub bootstrap_node: Sourced<String>,
+    pub fullscreen: Sourced<bool>,
+    pub allow_rollback: Sourced<bool>, // rolling back blocks off devnet, see Server::rollback_to_height
+}
+
+/// Something in the configuration that is allowed but probably not meant
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigWarning {
+    FullscreenWhileHeadless,
+    InvalidPort(String),
+    PrivilegedPort(u16),
+    BootstrapIsSelf(String),
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigWarning::FullscreenWhileHeadless => write!(f, "fullscreen is set but the node runs headless, there's no window"),
+            ConfigWarning::InvalidPort(port) => write!(f, "port {:?} isn't a port number", port),
+            ConfigWarning::PrivilegedPort(port) => write!(f, "port {} is below 1024, binding it usually needs administrator rights", port),
+            ConfigWarning::BootstrapIsSelf(addr) => write!(f, "the bootstrap node {} is this node", addr),
+        }
+    }
+}
+
+/// Picks every value from the highest layer that sets it, see the module comment
+pub fn resolve(file: &ConfigLayer, env: &ConfigLayer, cli: &ConfigLayer) -> EffectiveConfig {
+    let defaults = Settings::default();
+    fn pick<T: Clone>(default: T, layers: [(Option<&T>, ConfigSource); 3]) -> Sourced<T> {
+        layers.into_iter().rev()
+            .find_map(|(value, source)| value.map(|value| Sourced { value: value.clone(), source }))
+            .unwrap_or(Sourced { value: default, source: ConfigSource::Default })
+    }
+    macro_rules! layered {
+        ($field:ident, $default:expr) => {
+            pick($default, [
+                (file.$field.as_ref(), ConfigSource::File),
+                (env.$field.as_ref(), ConfigSource::Env),
+                (cli.$field.as_ref(), ConfigSource::Cli),
+            ])
+        };
+    }
+
+    EffectiveConfig {
+        data_dir: layered!(data_dir, defaults.data_dir),
+        headless: layered!(headless, defaults.headless),
+        port: layered!(port, defaults.server_port),
+        bootstrap_node: layered!(bootstrap_node, defaults.bootstrap_node),
+        fullscreen: layered!(fullscreen, defaults.fullscreen),
+        allow_rollback: layered!(allow_rollback, false),
+    }
+}
+
+impl EffectiveConfig {
+    /// Reads settings.json, the environment and the command line of this process
+    pub fn load() -> Result<EffectiveConfig> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Ok(resolve(
+            &ConfigLayer::from_file(&SETTINGS_PATH.to_string_lossy()),
+            &ConfigLayer::from_env(|name| std::env::var(name).ok()),
+            &ConfigLayer::from_args(&args)?,
+        ))
+    }
+
+    /// (setting, value, source) of every setting, for display
+    pub fn rows(&self) -> Vec<(&'static str, String, ConfigSource)> {
+        vec![
+            ("Data directory", self.data_dir.value.clone(), self.data_dir.source),
+            ("Headless", self.headless.value.to_string(), self.headless.source),
+            ("Port", self.port.value.clone(), self.port.source),
+            ("Bootstrap node", self.bootstrap_node.value.clone(), self.bootstrap_node.source),
+            ("Fullscreen", self.fullscreen.value.to_string(), self.fullscreen.source),
+            ("Allow rollback", self.allow_rollback.value.to_string(), self.allow_rollback.source),
+        ]
+    }
+
+    pub fn warnings(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+        if self.headless.value && self.fullscreen.value {
+            warnings.push(ConfigWarning::FullscreenWhileHeadless);
+        }
+        match self.port.value.parse::<u16>() {
+            Ok(0) | Err(_) => warnings.push(ConfigWarning::InvalidPort(self.port.value.clone())),
+            Ok(port) if port < 1024 => warnings.push(ConfigWarning::PrivilegedPort(port)),
+            Ok(_) => {}
+        }
+        if let Some((host, port)) = self.bootstrap_node.value.rsplit_once(':') {
+            if port == self.port.value && matches!(host, "127.0.0.1" | "localhost" | "0.0.0.0") {
+                warnings.push(ConfigWarning::BootstrapIsSelf(self.bootstrap_node.value.clone()));
+            }
+        }
+        warnings
+    }
+}
+
+impl fmt::Display for EffectiveConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value, source) in self.rows() {
+            writeln!(f, "  {:<16} {:<24} ({})", name, value, source)?;
+        }
+        Ok(())
+    }
+}
+
+/// The configuration of this process. A malformed command line falls back to the other layers
+pub static CONFIG: Lazy<EffectiveConfig> = Lazy::new(|| {
+    EffectiveConfig::load().unwrap_or_else(|e| {
+        eprintln!("Ignoring the command line: {}", e);
+        resolve(
+            &ConfigLayer::from_file(&SETTINGS_PATH.to_string_lossy()),
+            &ConfigLayer::from_env(|name| std::env::var(name).ok()),
+            &ConfigLayer::default(),
+        )
+    })
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConfigSource::*;
+
+    fn layer(port: Option<&str>, headless: Option<bool>) -> ConfigLayer {
+        ConfigLayer { port: port.map(str::to_string), headless, ..ConfigLayer::default() }
+    }
+
+    #[test]
+    fn test_each_setting_comes_from_the_highest_layer() {
+        // file, env, cli -> resolved port and its source, headless and its source
+        let cases = [
+            (layer(None, None), layer(None, None), layer(None, None), ("8334", Default), (false, Default)),
+            (layer(Some("9000"), None), layer(None, None), layer(None, None), ("9000", File), (false, Default)),
+            (layer(Some("9000"), Some(true)), layer(Some("9100"), None), layer(None, None), ("9100", Env), (true, File)),
+            (layer(Some("9000"), None), layer(Some("9100"), Some(false)), layer(Some("9200"), Some(true)), ("9200", Cli), (true, Cli)),
+            (layer(None, Some(true)), layer(None, Some(false)), layer(Some("9200"), None), ("9200", Cli), (false, Env)),
+        ];
+        for (i, (file, env, cli, (port, port_source), (headless, headless_source))) in cases.into_iter().enumerate() {
+            let config = resolve(&file, &env, &cli);
+            assert_eq!(config.port, Sourced { value: port.to_string(), source: port_source }, "case {}", i);
+            assert_eq!(config.headless, Sourced { value: headless, source: headless_source }, "case {}", i);
+            assert_eq!(config.bootstrap_node.source, Default, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn test_layers_are_read_from_their_sources() {
+        let args: Vec<String> = ["--healthcheck", "--port", "9001", "--data-dir=/srv/node", "--headless", "--allow-rollback", "extra"]
+            .iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(ConfigLayer::from_args(&args).unwrap(), ConfigLayer {
+            data_dir: Some(String::from("/srv/node")),
+            headless: Some(true),
+            port: Some(String::from("9001")),
+            allow_rollback: Some(true),
+            ..ConfigLayer::default()
+        });
+        assert!(ConfigLayer::from_args(&[String::from("--port")]).is_err());
+
+        let env = ConfigLayer::from_env(|name| match name {
+            ENV_HEADLESS => Some(String::from("1")),
+            ENV_BOOTSTRAP => Some(String::from("10.0.0.2:8335")),
+            _ => None,
+        });
+        assert_eq!((env.headless, env.bootstrap_node.as_deref(), env.port), (Some(true), Some("10.0.0.2:8335"), None));
+
+        // Only the keys written in the file count as set there
+        let path = std::env::temp_dir().join(format!("blockjain-settings-{}.json", rand::random::<u64>()));
+        fs::write(&path, r#"{ "server_port": "9002", "fullscreen": true }"#).unwrap();
+        let file = ConfigLayer::from_file(path.to_str().unwrap());
+        assert_eq!(file, ConfigLayer { port: Some(String::from("9002")), fullscreen: Some(true), ..ConfigLayer::default() });
+        fs::remove_file(&path).ok();
+        assert_eq!(ConfigLayer::from_file("no/such/settings.json"), ConfigLayer::default());
+    }
+
+    #[test]
+    fn test_warning_rules() {
+        let config = |file: ConfigLayer| resolve(&file, &ConfigLayer::default(), &ConfigLayer::default());
+        let cases = [
+            (ConfigLayer::default(), vec![]),
+            (ConfigLayer { headless: Some(true), fullscreen: Some(true), ..ConfigLayer::default() }, vec![ConfigWarning::FullscreenWhileHeadless]),
+            (ConfigLayer { headless: Some(true), ..ConfigLayer::default() }, vec![]),
+            (layer(Some("80"), None), vec![ConfigWarning::PrivilegedPort(80)]),
+            (layer(Some("1024"), None), vec![]),
+            (layer(Some("http"), None), vec![ConfigWarning::InvalidPort(String::from("http"))]),
+            (
+                ConfigLayer { bootstrap_node: Some(String::from("127.0.0.1:8334")), ..ConfigLayer::default() },
+                vec![ConfigWarning::BootstrapIsSelf(String::from("127.0.0.1:8334"))],
+            ),
+            (
+                ConfigLayer { port: Some(String::from("8335")), bootstrap_node: Some(String::from("localhost:8335")), ..ConfigLayer::default() },
+                vec![ConfigWarning::BootstrapIsSelf(String::from("localhost:8335"))],
+            ),
+            (ConfigLayer { bootstrap_node: Some(String::from("10.0.0.2:8334")), ..ConfigLayer::default() }, vec![]),
+        ];
+        for (i, (file, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(config(file).warnings(), expected, "case {}", i);
+        }
+    }
+}
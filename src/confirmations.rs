@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+/*
+    Incoming payment confirmations
+
+    Payments to our wallets are watched from the block that mines them until they're buried
+    under `target` blocks. Confirmations follow the tip height, so a reorganization that
+    lowers the tip, or disconnects the block holding the payment, moves them back again.
+    A payment leaves the list once it reaches the target.
+*/
+
+pub const DEFAULT_CONFIRMATION_TARGET: u32 = 6;
+
+/// A payment to one of our wallets that hasn't reached the confirmation target
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncomingPayment {
+    pub txid: String,
+    pub address: String,
+    pub amount: i32,
+    pub height: Option<i32>, // block holding the payment, None after a reorg disconnected it
+    pub confirmations: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchList {
+    target: u32,
+    tip_height: i32,
+    payments: Vec<IncomingPayment>, // oldest first
+}
+
+impl WatchList {
+    pub fn new(target: u32) -> Self {
+        WatchList { target: target.max(1), tip_height: -1, payments: Vec::new() }
+    }
+
+    pub fn target(&self) -> u32 {
+        self.target
+    }
+
+    pub fn payments(&self) -> &[IncomingPayment] {
+        &self.payments
+    }
+
+    pub fn payments_to<'a>(&'a self, address: &'a str) -> impl Iterator<Item = &'a IncomingPayment> + 'a {
+        self.payments.iter().filter(move |p| p.address == address)
+    }
+
+    /// Starts watching `amount`, everything `txid` pays `address`, mined at `height`.
+    /// Returns payments that reached the target
+    pub fn watch(&mut self, txid: &str, address: &str, amount: i32, height: i32) -> Vec<IncomingPayment> {
+        match self.payments.iter_mut().find(|p| p.txid == txid && p.address == address) {
+            Some(payment) => payment.height = Some(height), // seen again, or mined again after a reorg
+            None => self.payments.push(IncomingPayment {
+                txid: txid.to_string(),
+                address: address.to_string(),
+                amount,
+                height: Some(height),
+                confirmations: 0,
+            }),
+        }
+        self.tip_changed(self.tip_height.max(height))
+    }
+
+    /// Recounts confirmations for a new tip, which may be lower than before after a reorg.
+    /// Returns payments that reached the target, they aren't watched anymore
+    pub fn tip_changed(&mut self, tip_height: i32) -> Vec<IncomingPayment> {
+        self.tip_height = tip_height;
+        for payment in &mut self.payments {
+            payment.confirmations = match payment.height {
+                Some(height) if height <= tip_height => (tip_height - height + 1) as u32,
+                _ => 0,
+            };
+        }
+
+        let target = self.target;
+        let (reached, watching) = self.payments.drain(..).partition(|p| p.confirmations >= target);
+        self.payments = watching;
+        reached
+    }
+
+    /// A reorg took the blocks holding `txids` out of the chain. Their payments stay
+    /// watched with no confirmations until they're mined again
+    pub fn disconnected(&mut self, txids: &[String]) {
+        for payment in &mut self.payments {
+            if txids.contains(&payment.txid) {
+                payment.height = None;
+                payment.confirmations = 0;
+            }
+        }
+    }
+
+    /// Incoming amounts per address, for a quick summary
+    pub fn totals(&self) -> HashMap<&str, i32> {
+        let mut totals = HashMap::new();
+        for payment in &self.payments {
+            *totals.entry(payment.address.as_str()).or_insert(0) += payment.amount;
+        }
+        totals
+    }
+}
+
+impl Default for WatchList {
+    fn default() -> Self {
+        WatchList::new(DEFAULT_CONFIRMATION_TARGET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirmations_follow_the_tip() {
+        let mut watch = WatchList::new(3);
+        watch.tip_changed(10);
+
+        assert!(watch.watch("a", "alice", 5, 11).is_empty());
+        assert_eq!(watch.payments()[0].confirmations, 1);
+
+        assert!(watch.tip_changed(12).is_empty());
+        assert_eq!(watch.payments()[0].confirmations, 2);
+
+        // Seeing the block again changes nothing
+        watch.watch("a", "alice", 5, 11);
+        watch.watch("b", "alice", 2, 12);
+        assert_eq!(watch.payments().len(), 2);
+        assert_eq!(watch.totals()["alice"], 7);
+    }
+
+    #[test]
+    fn test_reorg_moves_confirmations_back() {
+        let mut watch = WatchList::new(6);
+        watch.watch("a", "alice", 5, 10);
+        watch.tip_changed(13);
+        assert_eq!(watch.payments()[0].confirmations, 4);
+
+        // A shorter replacement chain
+        watch.tip_changed(11);
+        assert_eq!(watch.payments()[0].confirmations, 2);
+
+        // The block holding the payment is gone
+        watch.disconnected(&[String::from("a")]);
+        watch.tip_changed(12);
+        assert_eq!(watch.payments()[0].height, None);
+        assert_eq!(watch.payments()[0].confirmations, 0);
+
+        // Mined again on the new branch
+        watch.watch("a", "alice", 5, 12);
+        assert_eq!(watch.payments()[0].confirmations, 1);
+        assert_eq!(watch.payments()[0].amount, 5);
+    }
+
+    #[test]
+    fn test_target_reached_once() {
+        let mut watch = WatchList::new(2);
+        assert!(watch.watch("a", "alice", 5, 10).is_empty());
+
+        let reached = watch.tip_changed(11);
+        assert_eq!(reached.len(), 1);
+        assert_eq!(reached[0].txid, "a");
+        assert_eq!(reached[0].confirmations, 2);
+        assert!(watch.payments().is_empty());
+
+        assert!(watch.watch("b", "bob", 1, 11).is_empty());
+
+        // Already deep enough when first seen
+        let reached = watch.watch("c", "carol", 1, 5);
+        assert_eq!(reached.iter().map(|p| p.txid.as_str()).collect::<Vec<_>>(), vec!["c"]);
+        assert!(watch.tip_changed(11).is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::clock;
+use crate::errors::Result;
+use crate::protocol::{
+    self, cmd_to_bytes, Capabilities, GetBlockmsg, GetDatamsg, Invmsg, Message, Versionmsg, CMD_LEN, MAX_MESSAGE_SIZE, VERSION,
+};
+
+// Bytes of each frame kept in the transcript
+const TRANSCRIPT_BYTES: usize = 48;
+// Well past MAX_MESSAGE_SIZE, more than the socket buffers hold, so it can't all be taken in
+// by a node that stopped reading
+const OVERSIZED_LEN: u64 = MAX_MESSAGE_SIZE + 4 * 1024 * 1024;
+// Writing the oversized message may be throttled by the target's download limit
+const OVERSIZED_TIMEOUT: Duration = Duration::from_secs(60);
+const WRITE_CHUNK: usize = 64 * 1024;
+
+type CheckOutcome = std::result::Result<(), String>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A frame, or what happened to the connection, as seen by the checking client
+#[derive(Debug, Clone)]
+pub struct TranscriptEntry {
+    pub direction: Direction,
+    pub note: String,
+    pub len: usize,
+    pub head: Vec<u8>, // the first TRANSCRIPT_BYTES of the frame
+}
+
+impl fmt::Display for TranscriptEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        if self.len == 0 {
+            return write!(f, "{} {}", arrow, self.note);
+        }
+        let more = if self.len > self.head.len() { "…" } else { "" };
+        write!(f, "{} {:<14} {:>9} bytes  {}{}", arrow, self.note, self.len, hex::encode(&self.head), more)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub failure: Option<String>,
+    pub transcript: Vec<TranscriptEntry>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConformanceReport {
+    pub target: String,
+    pub checks: Vec<CheckResult>,
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(CheckResult::passed)
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Protocol conformance of {}", self.target)?;
+        for check in &self.checks {
+            match &check.failure {
+                None => writeln!(f, "PASS {}", check.name)?,
+                Some(reason) => writeln!(f, "FAIL {}: {}", check.name, reason)?,
+            }
+            for entry in &check.transcript {
+                writeln!(f, "    {}", entry)?;
+            }
+        }
+        let passed = self.checks.iter().filter(|check| check.passed()).count();
+        writeln!(f, "{}/{} checks passed", passed, self.checks.len())
+    }
+}
+
+/// A peer that exercises the node at `target` and checks its answers. It listens on its own
+/// address too, since nodes answer on a new connection to the sender's `addr_from`
+pub struct ConformanceClient {
+    target: String,
+    addr: String,
+    timeout: Duration,
+    inbox: mpsc::UnboundedReceiver<Vec<u8>>,
+    listening: JoinHandle<()>,
+    transcript: Vec<TranscriptEntry>,
+    block_hashes: Vec<String>,
+}
+
+impl Drop for ConformanceClient {
+    fn drop(&mut self) {
+        self.listening.abort();
+    }
+}
+
+impl ConformanceClient {
+    /// Listens on `listen`, which the target must be able to connect to. Answers are awaited
+    /// for `timeout`
+    pub async fn bind(target: &str, listen: &str, timeout: Duration) -> Result<ConformanceClient> {
+        let listener = TcpListener::bind(listen).await?;
+        let addr = listener.local_addr()?.to_string();
+        let (sender, inbox) = mpsc::unbounded_channel();
+        let listening = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut frame = Vec::new();
+                    let mut limited = (&mut stream).take(MAX_MESSAGE_SIZE + 1);
+                    if limited.read_to_end(&mut frame).await.is_ok() && !frame.is_empty() {
+                        let _ = sender.send(frame);
+                    }
+                });
+            }
+        });
+
+        Ok(ConformanceClient {
+            target: target.to_string(),
+            addr,
+            timeout,
+            inbox,
+            listening,
+            transcript: Vec::new(),
+            block_hashes: Vec::new(),
+        })
+    }
+
+    /// Runs every check in order. Later checks use what earlier ones learned, the block
+    /// hashes from getblocks for instance
+    pub async fn run(&mut self) -> ConformanceReport {
+        let mut checks = Vec::new();
+
+        self.begin();
+        let outcome = self.handshake().await;
+        checks.push(self.finish("handshake", outcome));
+
+        self.begin();
+        let outcome = self.addr_gossip().await;
+        checks.push(self.finish("addr gossip", outcome));
+
+        self.begin();
+        let outcome = self.get_blocks().await;
+        checks.push(self.finish("getblocks", outcome));
+
+        self.begin();
+        let outcome = self.get_data_block().await;
+        checks.push(self.finish("getdata block", outcome));
+
+        self.begin();
+        let outcome = self.get_data_missing().await;
+        checks.push(self.finish("getdata not found", outcome));
+
+        self.begin();
+        let outcome = self.inv_tx().await;
+        checks.push(self.finish("inv tx", outcome));
+
+        self.begin();
+        let outcome = self.malformed_frames().await;
+        checks.push(self.finish("malformed frames", outcome));
+
+        self.begin();
+        let outcome = self.oversized_message().await;
+        checks.push(self.finish("oversized message", outcome));
+
+        ConformanceReport {
+            target: self.target.clone(),
+            checks,
+        }
+    }
+
+    // ---------------------------------- CHECKS ----------------------------------
+
+    // Our version says we have no blocks, so a node with any answers with its own
+    async fn handshake(&mut self) -> CheckOutcome {
+        self.send("version", &self.version()).await?;
+        let version = self.expect("version", |msg| match msg {
+            Message::Version(version) => Some(version),
+            _ => None,
+        }).await?;
+        if version.version < 1 {
+            return Err(format!("the target advertises protocol version {}", version.version));
+        }
+        Ok(())
+    }
+
+    async fn addr_gossip(&mut self) -> CheckOutcome {
+        self.send("version", &self.version()).await?;
+        self.expect("addr", |msg| match msg {
+            Message::Addr(addr) => Some(addr),
+            _ => None,
+        }).await?;
+        Ok(())
+    }
+
+    async fn get_blocks(&mut self) -> CheckOutcome {
+        self.send("getblocks", &GetBlockmsg { addr_from: self.addr.clone() }).await?;
+        let inv = self.expect("block inv", |msg| match msg {
+            Message::Inv(inv) if inv.kind == "block" => Some(inv),
+            _ => None,
+        }).await?;
+        if inv.items.is_empty() {
+            return Err(String::from("the block inv lists no blocks, not even the genesis block"));
+        }
+        self.block_hashes = inv.items;
+        Ok(())
+    }
+
+    // Asks for the newest block, nodes may not serve the historical ones
+    async fn get_data_block(&mut self) -> CheckOutcome {
+        let Some(hash) = self.block_hashes.first().cloned() else {
+            return Err(String::from("no block hash to ask for, getblocks failed"));
+        };
+        self.send("getdata", &self.get_data("block", &hash)).await?;
+        self.expect(&format!("block {}", hash), |msg| match msg {
+            Message::Block(block) if block.block.get_hash() == hash => Some(block),
+            _ => None,
+        }).await?;
+        Ok(())
+    }
+
+    async fn get_data_missing(&mut self) -> CheckOutcome {
+        for kind in ["block", "tx"] {
+            let id = unknown_id();
+            self.send("getdata", &self.get_data(kind, &id)).await?;
+            self.expect(&format!("notfound for {} {}", kind, id), |msg| match msg {
+                Message::NotFound(not_found) if not_found.kind == kind && not_found.id == id => Some(not_found),
+                _ => None,
+            }).await?;
+        }
+        Ok(())
+    }
+
+    // A transaction the target doesn't have gets asked for
+    async fn inv_tx(&mut self) -> CheckOutcome {
+        let txid = unknown_id();
+        let inv = Invmsg {
+            addr_from: self.addr.clone(),
+            kind: String::from("tx"),
+            items: vec![txid.clone()],
+        };
+        self.send("inv", &inv).await?;
+        self.expect(&format!("getdata for tx {}", txid), |msg| match msg {
+            Message::GetData(get_data) if get_data.kind == "tx" && get_data.id == txid => Some(get_data),
+            _ => None,
+        }).await?;
+        Ok(())
+    }
+
+    // Each frame is dropped without an answer, and the target keeps serving others
+    async fn malformed_frames(&mut self) -> CheckOutcome {
+        let mut unknown = cmd_to_bytes("nosuchcmd").to_vec();
+        unknown.extend_from_slice(&[1, 2, 3, 4]);
+        // a length prefix claiming far more items than the frame holds
+        let mut undecodable = cmd_to_bytes("inv").to_vec();
+        undecodable.extend_from_slice(&u64::MAX.to_le_bytes());
+        let frames = [
+            ("truncated", cmd_to_bytes("version")[..CMD_LEN / 2].to_vec()),
+            ("unknown cmd", unknown),
+            ("undecodable", undecodable),
+        ];
+
+        for (note, frame) in frames {
+            let mut stream = self.send_frame(note, &frame).await?;
+            let mut reply = Vec::new();
+            match tokio::time::timeout(self.timeout, stream.read_to_end(&mut reply)).await {
+                Ok(_) if reply.is_empty() => self.record(Direction::Received, "connection closed", &[], 0),
+                Ok(_) => {
+                    self.record(Direction::Received, "reply", &reply, reply.len());
+                    return Err(format!("the target answered a {} frame with {} bytes", note, reply.len()));
+                }
+                Err(_) => return Err(format!("the target kept the connection open after a {} frame", note)),
+            }
+        }
+        self.still_answers().await
+    }
+
+    // The target stops reading past MAX_MESSAGE_SIZE and drops the connection
+    async fn oversized_message(&mut self) -> CheckOutcome {
+        let mut stream = self.connect().await?;
+        let mut head = cmd_to_bytes("inv").to_vec();
+        head.resize(TRANSCRIPT_BYTES, 0);
+        self.record(Direction::Sent, "oversized inv", &head, OVERSIZED_LEN as usize);
+
+        let chunk = vec![0u8; WRITE_CHUNK];
+        let mut written = 0u64;
+        let write = async {
+            while written < OVERSIZED_LEN {
+                let data = if written == 0 { &head } else { &chunk };
+                stream.write_all(data).await?;
+                written += data.len() as u64;
+            }
+            stream.shutdown().await
+        };
+        match tokio::time::timeout(OVERSIZED_TIMEOUT, write).await {
+            Ok(Ok(())) => {
+                return Err(format!("the target read all {} bytes, {} more than MAX_MESSAGE_SIZE", written, written - MAX_MESSAGE_SIZE));
+            }
+            Ok(Err(e)) => {
+                let note = format!("connection dropped after {} bytes: {}", written, e);
+                self.record(Direction::Received, &note, &[], 0);
+            }
+            Err(_) => return Err(format!("the target stopped reading after {} bytes but kept the connection open", written)),
+        }
+        self.still_answers().await
+    }
+
+    async fn still_answers(&mut self) -> CheckOutcome {
+        self.send("getblocks", &GetBlockmsg { addr_from: self.addr.clone() }).await?;
+        self.expect("block inv", |msg| match msg {
+            Message::Inv(inv) if inv.kind == "block" => Some(inv),
+            _ => None,
+        }).await
+            .map(|_| ())
+            .map_err(|e| format!("the target stopped answering afterwards: {}", e))
+    }
+
+    // ---------------------------------- HELPERS ----------------------------------
+
+    fn version(&self) -> Versionmsg {
+        Versionmsg {
+            addr_from: self.addr.clone(),
+            version: VERSION,
+            best_height: -1,
+            capabilities: Capabilities::NONE,
+            timestamp: clock::now_millis() as u64,
+            checkpoint: None,
+            genesis: String::new(), // unknown, so we're on any network
+            best_work: 0, // compared by height then, -1 is behind anyone
+        }
+    }
+
+    fn get_data(&self, kind: &str, id: &str) -> GetDatamsg {
+        GetDatamsg {
+            addr_from: self.addr.clone(),
+            kind: kind.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    // Answers to an earlier check that came in late aren't counted for the next one
+    fn begin(&mut self) {
+        while self.inbox.try_recv().is_ok() {}
+        self.transcript.clear();
+    }
+
+    fn finish(&mut self, name: &'static str, outcome: CheckOutcome) -> CheckResult {
+        CheckResult {
+            name,
+            failure: outcome.err(),
+            transcript: std::mem::take(&mut self.transcript),
+        }
+    }
+
+    fn record(&mut self, direction: Direction, note: &str, frame: &[u8], len: usize) {
+        self.transcript.push(TranscriptEntry {
+            direction,
+            note: note.to_string(),
+            len,
+            head: frame[..frame.len().min(TRANSCRIPT_BYTES)].to_vec(),
+        });
+    }
+
+    async fn connect(&mut self) -> std::result::Result<TcpStream, String> {
+        match tokio::time::timeout(self.timeout, TcpStream::connect(&self.target)).await {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(e)) => Err(format!("couldn't connect to {}: {}", self.target, e)),
+            Err(_) => Err(format!("connecting to {} timed out", self.target)),
+        }
+    }
+
+    async fn send<T: Serialize>(&mut self, cmd: &str, msg: &T) -> CheckOutcome {
+        let frame = protocol::encode(cmd, msg).map_err(|e| e.to_string())?;
+        self.send_frame(cmd, &frame).await.map(drop)
+    }
+
+    // Writes one frame and ends our side of the connection, the target reads until then
+    async fn send_frame(&mut self, note: &str, frame: &[u8]) -> std::result::Result<TcpStream, String> {
+        let mut stream = self.connect().await?;
+        self.record(Direction::Sent, note, frame, frame.len());
+        let write = async {
+            stream.write_all(frame).await?;
+            stream.shutdown().await
+        };
+        match tokio::time::timeout(self.timeout, write).await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(format!("sending {} failed: {}", note, e)),
+            Err(_) => Err(format!("sending {} timed out", note)),
+        }
+    }
+
+    // Waits for a message `pick` accepts. Others are recorded and skipped, a frame that
+    // doesn't decode fails the check
+    async fn expect<T>(&mut self, what: &str, pick: impl Fn(Message) -> Option<T>) -> std::result::Result<T, String> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let frame = match tokio::time::timeout_at(deadline, self.inbox.recv()).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Err(String::from("stopped listening for answers")),
+                Err(_) => return Err(format!("no {} within {:?}", what, self.timeout)),
+            };
+            match protocol::bytes_to_cmd(&frame) {
+                Ok(msg) => {
+                    self.record(Direction::Received, msg.command(), &frame, frame.len());
+                    if let Some(found) = pick(msg) {
+                        return Ok(found);
+                    }
+                }
+                Err(e) => {
+                    self.record(Direction::Received, "undecodable", &frame, frame.len());
+                    return Err(format!("the target sent a frame that doesn't decode: {}", e));
+                }
+            }
+        }
+    }
+}
+
+// A block or transaction id nobody has
+fn unknown_id() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A node with framing disabled: it takes in whatever arrives, any size, and never answers
+    #[tokio::test]
+    async fn test_fails_against_node_without_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let node = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut sink = Vec::new();
+                    let _ = stream.read_to_end(&mut sink).await;
+                });
+            }
+        });
+
+        let mut client = ConformanceClient::bind(&target, "127.0.0.1:0", Duration::from_millis(300)).await.unwrap();
+        let report = client.run().await;
+        assert!(!report.passed());
+        assert!(report.checks.iter().all(|check| !check.passed()), "{}", report);
+
+        let oversized = report.checks.iter().find(|check| check.name == "oversized message").unwrap();
+        assert!(oversized.failure.as_ref().unwrap().contains("more than MAX_MESSAGE_SIZE"));
+        assert!(report.to_string().contains("FAIL handshake: no version within"));
+        node.abort();
+    }
+}
@@ -0,0 +1,447 @@
+use std::sync::Arc;
+
+use failure::format_err;
+use tokio::sync::RwLock;
+
+use crate::address;
+use crate::block::Block;
+use crate::chain_file;
+#[cfg(feature = "chaos")]
+use crate::chaos::{ChaosConfig, CHAOS_CONFIG_PATH};
+use crate::errors::Result;
+use crate::proof_of_funds;
+use crate::raw::{self, Decoded};
+use crate::server::Server;
+use crate::settings::{ChainType, SETTINGS};
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+
+/*
+    Debug console
+
+    Small text commands for inspecting the chain, the UTXO set and the node while developing.
+    The UI parses a line with `parse` and runs it with `execute` on the runtime.
+*/
+
+// Most blocks `mine N` makes in one go
+const MAX_MINE_BLOCKS: u32 = 100;
+
+const HELP: &str = "Available commands:
+  block <height|hash>  show a block
+  tx <id>              show a transaction from the mempool or the chain
+  utxo <address>       unspent outputs and balance of an address
+  peers                known peers
+  mempool              transactions waiting to be mined
+  supply               circulating and burned coins
+  decode <hex>         parse the raw hex of a block or transaction
+  proof <file>         check a proof of funds against this node's chain
+  digest [<height>]    state digest of the chain at a height, the tip by default
+  digest <file>        check a chain file against its state digest
+  mine <n>             mine n empty blocks (devnet only)
+  rollback <n>         disconnect and delete the top n blocks, their transactions go back
+                       to the mempool (devnet or --allow-rollback only)
+  chaos [<setting>]    network chaos on outgoing messages (chaos builds only): off, reload,
+                       loss=<0..1>, latency=<ms>, jitter=<ms>, disconnect=<every ms>/<for ms>
+  help                 this list";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleCommand {
+    BlockByHeight(i32),
+    BlockByHash(String),
+    Tx(String),
+    Utxo(String),
+    Peers,
+    Mempool,
+    Supply,
+    Decode(String),
+    VerifyProof(String), // path of the proof of funds
+    StateDigest(Option<i32>), // None for the tip
+    VerifyChainFile(String),
+    Mine(u32),
+    Rollback(usize),
+    Chaos(Option<ChaosSetting>), // None shows the configuration
+    Help,
+}
+
+// Changes `chaos` makes, to the link every peer without its own settings uses
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChaosSetting {
+    Off,
+    Reload, // from the chaos config file
+    Loss(f64),
+    Latency(u64),
+    Jitter(u64),
+    Disconnect { every_ms: u64, for_ms: u64 },
+}
+
+/// Parses one console line. The error is meant to be shown as is and lists the available commands.
+pub fn parse(line: &str) -> std::result::Result<ConsoleCommand, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command.to_lowercase(),
+        None => return Err(HELP.to_string()),
+    };
+    let argument = words.next();
+    if words.next().is_some() {
+        return Err(format!("Too many arguments for '{}'\n{}", command, HELP));
+    }
+
+    let missing = |what: &str| Err(format!("'{}' needs {}\n{}", command, what, HELP));
+    let parsed = match (command.as_str(), argument) {
+        ("block", Some(arg)) => match arg.parse::<i32>() {
+            Ok(height) if height >= 0 => ConsoleCommand::BlockByHeight(height),
+            Ok(_) => return Err(String::from("Block height can't be negative")),
+            Err(_) => ConsoleCommand::BlockByHash(arg.to_string()),
+        },
+        ("block", None) => return missing("a height or a hash"),
+        ("tx", Some(id)) => ConsoleCommand::Tx(id.to_string()),
+        ("tx", None) => return missing("a transaction id"),
+        ("utxo", Some(address)) => ConsoleCommand::Utxo(address.to_string()),
+        ("utxo", None) => return missing("an address"),
+        ("decode", Some(hex)) => ConsoleCommand::Decode(hex.to_string()),
+        ("decode", None) => return missing("the hex of a block or transaction"),
+        ("proof", Some(path)) => ConsoleCommand::VerifyProof(path.to_string()),
+        ("proof", None) => return missing("the path of a proof of funds"),
+        ("digest", Some(arg)) => match arg.parse::<i32>() {
+            Ok(height) if height >= 0 => ConsoleCommand::StateDigest(Some(height)),
+            Ok(_) => return Err(String::from("Block height can't be negative")),
+            Err(_) => ConsoleCommand::VerifyChainFile(arg.to_string()),
+        },
+        ("digest", None) => ConsoleCommand::StateDigest(None),
+        ("mine", Some(count)) => match count.parse::<u32>() {
+            Ok(n) if (1..=MAX_MINE_BLOCKS).contains(&n) => ConsoleCommand::Mine(n),
+            _ => return Err(format!("'mine' takes a number of blocks from 1 to {}", MAX_MINE_BLOCKS)),
+        },
+        ("mine", None) => return missing("a number of blocks"),
+        ("rollback", Some(count)) => match count.parse::<usize>() {
+            Ok(n) if n > 0 => ConsoleCommand::Rollback(n),
+            _ => return Err(String::from("'rollback' takes a number of blocks from 1")),
+        },
+        ("rollback", None) => return missing("a number of blocks"),
+        ("chaos", Some(setting)) => ConsoleCommand::Chaos(Some(parse_chaos_setting(setting)?)),
+        ("chaos", None) => ConsoleCommand::Chaos(None),
+        ("peers", None) => ConsoleCommand::Peers,
+        ("mempool", None) => ConsoleCommand::Mempool,
+        ("supply", None) => ConsoleCommand::Supply,
+        ("help", None) => ConsoleCommand::Help,
+        ("peers" | "mempool" | "supply" | "help", Some(_)) => {
+            return Err(format!("'{}' takes no arguments\n{}", command, HELP))
+        }
+        _ => return Err(format!("Unknown command '{}'\n{}", command, HELP)),
+    };
+    Ok(parsed)
+}
+
+fn parse_chaos_setting(setting: &str) -> std::result::Result<ChaosSetting, String> {
+    let invalid = || format!("Invalid chaos setting '{}'\n{}", setting, HELP);
+    let ms = |value: &str| value.parse::<u64>().map_err(|_| invalid());
+    let parsed = match setting.to_lowercase().split_once('=') {
+        None if setting.eq_ignore_ascii_case("off") => ChaosSetting::Off,
+        None if setting.eq_ignore_ascii_case("reload") => ChaosSetting::Reload,
+        Some(("loss", value)) => match value.parse::<f64>() {
+            Ok(p) if (0.0..=1.0).contains(&p) => ChaosSetting::Loss(p),
+            _ => return Err(String::from("'loss' is a probability from 0 to 1")),
+        },
+        Some(("latency", value)) => ChaosSetting::Latency(ms(value)?),
+        Some(("jitter", value)) => ChaosSetting::Jitter(ms(value)?),
+        Some(("disconnect", value)) => {
+            let (every, down) = value.split_once('/').ok_or_else(invalid)?;
+            let (every_ms, for_ms) = (ms(every)?, ms(down)?);
+            if for_ms > every_ms {
+                return Err(String::from("A disconnect can't last longer than its period"));
+            }
+            ChaosSetting::Disconnect { every_ms, for_ms }
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(parsed)
+}
+
+/// Runs a parsed command against the node and returns the text to print
+pub async fn execute(
+    command: &ConsoleCommand,
+    utxo_set: &Arc<RwLock<UTXOSet>>,
+    server: &Arc<RwLock<Server>>,
+) -> Result<String> {
+    match command {
+        ConsoleCommand::BlockByHeight(height) => {
+            let block = utxo_set.read().await.blockchain.read().await.get_block_by_height(*height)?;
+            Ok(format_block(&block))
+        }
+        ConsoleCommand::BlockByHash(hash) => {
+            let block = utxo_set.read().await.blockchain.read().await.get_block(hash)?;
+            Ok(format_block(&block))
+        }
+        ConsoleCommand::Tx(id) => {
+            let in_mempool = server.read().await.mempool_transactions().await
+                .into_iter()
+                .find(|tx| tx.id == *id);
+            match in_mempool {
+                Some(tx) => Ok(format!("(in mempool)\n{}", format_transaction(&tx))),
+                None => {
+                    let tx = utxo_set.read().await.blockchain.read().await.find_transaction(id)?;
+                    Ok(format_transaction(&tx))
+                }
+            }
+        }
+        ConsoleCommand::Utxo(address) => {
+            let pub_key_hash = address::address_to_hash(address)?;
+            let utxos = utxo_set.read().await.find_utxo(&pub_key_hash)?;
+
+            let mut out = String::new();
+            for output in &utxos.outputs {
+                out.push_str(&format!("  {:>10}\n", output.value));
+            }
+            let balance: i32 = utxos.outputs.iter().map(|o| o.value).sum();
+            out.push_str(&format!("{} outputs, balance {}\n", utxos.outputs.len(), balance));
+            Ok(out)
+        }
+        ConsoleCommand::Peers => {
+            let mut peers: Vec<_> = server.read().await.get_known_nodes().await.into_iter().collect();
+            peers.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let mut out = format!("{} known peers\n", peers.len());
+            for (address, node) in peers {
+                out.push_str(&format!(
+                    "  {:<22} no response: {} misbehavior: {}\n",
+                    address, node.no_response_counter(), node.misbehavior_score()
+                ));
+            }
+            Ok(out)
+        }
+        ConsoleCommand::Mempool => {
+            let txs = server.read().await.mempool_transactions().await;
+            let mut out = format!("{} transactions in mempool\n", txs.len());
+            for tx in txs {
+                let total: i32 = tx.vout.iter().map(|o| o.value).sum();
+                out.push_str(&format!("  {} {} in, {} out, {} coins\n", tx.id, tx.vin.len(), tx.vout.len(), total));
+            }
+            Ok(out)
+        }
+        ConsoleCommand::Supply => {
+            let supply = utxo_set.read().await.supply()?;
+            Ok(format!("circulating {}\nburned      {}\n", supply.circulating, supply.burned))
+        }
+        ConsoleCommand::Decode(hex) => match raw::decode_hex(hex).or_else(|e| Transaction::from_hex(hex).map(Decoded::Transaction).map_err(|_| e))? {
+            Decoded::Block(block) => {
+                let mut out = format_block(&block);
+                for tx in block.get_transactions() {
+                    out.push_str(&format_transaction(tx));
+                }
+                Ok(out)
+            }
+            Decoded::Transaction(tx) => Ok(format_transaction(&tx)),
+        },
+        ConsoleCommand::VerifyProof(path) => {
+            let document = std::fs::read_to_string(path)?;
+            let utxo_set = utxo_set.read().await;
+            let report = proof_of_funds::verify_proof_of_funds(&document, &*utxo_set.blockchain.read().await)?;
+            let mut out = format!(
+                "{} at block {} (height {}), challenge {:?}\n",
+                if report.is_valid() { "valid" } else { "NOT VALID" }, report.block_hash, report.height, report.challenge,
+            );
+            for check in &report.addresses {
+                out.push_str(&format!(
+                    "  {} claims {}, holds {}, signature {}\n",
+                    check.address, check.claimed, check.actual, if check.signed { "valid" } else { "invalid" },
+                ));
+            }
+            out.push_str(&format!("{} coins proven\n", report.proven_total()));
+            Ok(out)
+        }
+        ConsoleCommand::StateDigest(height) => {
+            let utxo_set = utxo_set.read().await;
+            let blockchain = utxo_set.blockchain.read().await;
+            let height = match height {
+                Some(height) => *height,
+                None => blockchain.get_best_height()?,
+            };
+            Ok(format!("{}\n", blockchain.compute_state_digest(height)?))
+        }
+        ConsoleCommand::VerifyChainFile(path) => match chain_file::verify_state_digest(std::path::Path::new(path))? {
+            Some(digest) => Ok(format!("matches its state digest {}\n", digest)),
+            None => Ok(String::from("a chain file from before state digests, nothing to check\n")),
+        },
+        ConsoleCommand::Mine(count) => {
+            if SETTINGS.chain != ChainType::Devnet {
+                return Err(format_err!("'mine' is only available on devnet"));
+            }
+            let mut out = String::new();
+            for _ in 0..*count {
+                let block = server.read().await.mine_empty_block().await?;
+                out.push_str(&format!("mined block {} at height {}\n", block.get_hash(), block.get_height()));
+            }
+            Ok(out)
+        }
+        ConsoleCommand::Rollback(count) => {
+            let removed = server.read().await.rollback(*count).await?;
+            let mut out = String::new();
+            for block in &removed {
+                out.push_str(&format!("disconnected block {} at height {}\n", block.get_hash(), block.get_height()));
+            }
+            Ok(out)
+        }
+        ConsoleCommand::Chaos(setting) => execute_chaos(setting.as_ref(), server).await,
+        ConsoleCommand::Help => Ok(HELP.to_string()),
+    }
+}
+
+#[cfg(feature = "chaos")]
+async fn execute_chaos(setting: Option<&ChaosSetting>, server: &Arc<RwLock<Server>>) -> Result<String> {
+    let server = server.read().await;
+    let chaos = server.chaos();
+    let mut config = chaos.config();
+    match setting {
+        None => {}
+        Some(ChaosSetting::Off) => config = ChaosConfig::default(),
+        Some(ChaosSetting::Reload) => config = ChaosConfig::load(CHAOS_CONFIG_PATH),
+        Some(ChaosSetting::Loss(p)) => config.default.drop_probability = *p,
+        Some(ChaosSetting::Latency(ms)) => config.default.latency_ms = *ms,
+        Some(ChaosSetting::Jitter(ms)) => config.default.jitter_ms = *ms,
+        Some(ChaosSetting::Disconnect { every_ms, for_ms }) => {
+            config.default.disconnect_every_ms = *every_ms;
+            config.default.disconnect_for_ms = *for_ms;
+        }
+    }
+    if setting.is_some() {
+        chaos.set_config(config.clone());
+    }
+    Ok(serde_json::to_string_pretty(&config)? + "\n")
+}
+
+#[cfg(not(feature = "chaos"))]
+async fn execute_chaos(_: Option<&ChaosSetting>, _: &Arc<RwLock<Server>>) -> Result<String> {
+    Err(format_err!("This build doesn't have network chaos, rebuild with --features chaos"))
+}
+
+fn format_block(block: &Block) -> String {
+    let mut out = format!(
+        "block {}\n  height:    {}\n  prev hash: {}\n  timestamp: {}\n  nonce:     {}\n  {} transactions\n",
+        block.get_hash(),
+        block.get_height(),
+        block.get_prev_hash(),
+        block.get_timestamp(),
+        block.get_nonce(),
+        block.get_transactions().len(),
+    );
+    for tx in block.get_transactions() {
+        out.push_str(&format!("    {}\n", tx.id));
+    }
+    out
+}
+
+fn format_transaction(tx: &Transaction) -> String {
+    let mut out = format!("tx {}{}\n", tx.id, if tx.is_coinbase() { " (coinbase)" } else { "" });
+    if !tx.is_coinbase() {
+        for vin in &tx.vin {
+            out.push_str(&format!("  in  {}:{} from {}\n", vin.txid, vin.vout, vin.get_address()));
+        }
+    }
+    for (index, vout) in tx.vout.iter().enumerate() {
+        if vout.is_burn() {
+            out.push_str(&format!("  out {} {} burned\n", index, vout.value));
+            continue;
+        }
+        let address = address::wallet_address(&vout.pub_key_hash);
+        out.push_str(&format!(
+            "  out {} {} to {}\n",
+            index,
+            vout.value,
+            address
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainBuilder, TxBuilder, WalletFixture};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("block 120"), Ok(ConsoleCommand::BlockByHeight(120)));
+        assert_eq!(parse("  BLOCK   00ab "), Ok(ConsoleCommand::BlockByHash(String::from("00ab"))));
+        assert_eq!(parse("tx abc"), Ok(ConsoleCommand::Tx(String::from("abc"))));
+        assert_eq!(parse("utxo 1BoatSLRHtKNngkdXEeobR76b53LETtpyT"), Ok(ConsoleCommand::Utxo(String::from("1BoatSLRHtKNngkdXEeobR76b53LETtpyT"))));
+        assert_eq!(parse("peers"), Ok(ConsoleCommand::Peers));
+        assert_eq!(parse("mempool"), Ok(ConsoleCommand::Mempool));
+        assert_eq!(parse("supply"), Ok(ConsoleCommand::Supply));
+        assert_eq!(parse("mine 3"), Ok(ConsoleCommand::Mine(3)));
+        assert_eq!(parse("rollback 2"), Ok(ConsoleCommand::Rollback(2)));
+        assert_eq!(parse("proof pof.json"), Ok(ConsoleCommand::VerifyProof(String::from("pof.json"))));
+        assert_eq!(parse("decode 00ff"), Ok(ConsoleCommand::Decode(String::from("00ff"))));
+        assert_eq!(parse("digest"), Ok(ConsoleCommand::StateDigest(None)));
+        assert_eq!(parse("digest 4"), Ok(ConsoleCommand::StateDigest(Some(4))));
+        assert_eq!(parse("digest chain.bjchain"), Ok(ConsoleCommand::VerifyChainFile(String::from("chain.bjchain"))));
+        assert_eq!(parse("help"), Ok(ConsoleCommand::Help));
+        assert_eq!(parse("chaos"), Ok(ConsoleCommand::Chaos(None)));
+        assert_eq!(parse("chaos loss=0.3"), Ok(ConsoleCommand::Chaos(Some(ChaosSetting::Loss(0.3)))));
+        assert_eq!(parse("chaos disconnect=5000/500"), Ok(ConsoleCommand::Chaos(Some(ChaosSetting::Disconnect { every_ms: 5000, for_ms: 500 }))));
+
+        // Unknown commands and empty lines list what's available
+        assert!(parse("reorg 5").unwrap_err().contains("Available commands"));
+        assert!(parse("   ").unwrap_err().contains("mempool"));
+
+        // Missing, extra and malformed arguments
+        assert!(parse("block").is_err());
+        assert!(parse("block -1").is_err());
+        assert!(parse("tx").is_err());
+        assert!(parse("decode").is_err());
+        assert!(parse("peers all").is_err());
+        assert!(parse("utxo a b").is_err());
+        assert!(parse("mine 0").is_err());
+        assert!(parse("mine lots").is_err());
+        assert!(parse("rollback 0").is_err());
+        assert!(parse(&format!("mine {}", MAX_MINE_BLOCKS + 1)).is_err());
+        assert!(parse("chaos loss=2").is_err());
+        assert!(parse("chaos latency=soon").is_err());
+        assert!(parse("chaos disconnect=500/5000").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_commands() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let chain = chain.block(vec![tx.clone()]);
+        let chain_tip = chain.tip();
+        let tip = chain_tip.get_hash();
+
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo_set = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), path.to_str().unwrap()).unwrap()));
+        utxo_set.read().await.reindex().await.unwrap();
+        let server = Arc::new(RwLock::new(Server::new("0", "", Arc::clone(&utxo_set)).unwrap()));
+
+        let run = |line: &str| {
+            let command = parse(line).unwrap();
+            let utxo_set = Arc::clone(&utxo_set);
+            let server = Arc::clone(&server);
+            async move { execute(&command, &utxo_set, &server).await }
+        };
+
+        assert!(run("block 1").await.unwrap().contains(&tip));
+        assert!(run(&format!("block {}", tip)).await.unwrap().contains("height:    1"));
+        assert!(run("block 7").await.is_err());
+        assert!(run(&format!("tx {}", tx.id)).await.unwrap().contains(&other.address()));
+        assert!(run("tx missing").await.is_err());
+        assert!(run(&format!("utxo {}", other.address())).await.unwrap().contains("1 outputs, balance 4"));
+        assert!(run(&format!("utxo {}", miner.address())).await.unwrap().contains("balance 16"));
+        assert!(run("peers").await.unwrap().contains("1 known peers"));
+        assert!(run("mempool").await.unwrap().contains("0 transactions"));
+        assert!(run("supply").await.unwrap().contains("circulating 20"));
+        assert!(run("digest").await.unwrap().contains(&format!("height 1, block {}", tip)));
+        assert!(run("digest 7").await.is_err());
+        let block_hex = raw::block_hex(&chain_tip).unwrap();
+        assert!(run(&format!("decode {}", block_hex)).await.unwrap().contains(&other.address()));
+        assert!(run(&format!("decode {}", &block_hex[..40])).await.unwrap_err().to_string().contains("at byte"));
+
+        if SETTINGS.chain != ChainType::Devnet {
+            assert!(run("mine 1").await.is_err());
+        }
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::BLOCKS_PATH;
+use crate::errors::Result;
+use crate::maintenance::dir_size;
+use crate::settings::SETTINGS;
+use crate::wallet::WALLETS_PATH;
+
+/*
+    Disk space
+
+    When the disk fills, sled writes start failing half way through connecting a block and
+    the partial state is hard to recover. The maintenance loop measures the space left on
+    the filesystem holding the block database:
+
+        - below disk_warn_mb the UI warns, with the size of each store,
+        - below disk_stop_mb the node stops taking blocks: received ones are refused, none
+          are requested and nothing is mined. Wallets and transactions keep working, they
+          write little.
+
+    A level is only left for a better one once the free space is RESUME_MARGIN above its
+    threshold, so a disk hovering around a threshold doesn't flap between them.
+*/
+
+// Of a threshold, as a divisor (a tenth)
+const RESUME_MARGIN: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum DiskLevel {
+    Ok,
+    Low,  // warned
+    Full, // blocks paused
+}
+
+/// Bytes used by one store
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreUsage {
+    pub name: String,
+    pub bytes: u64,
+}
+
+/// The free space thresholds and the level last measured against them
+#[derive(Debug, Clone)]
+pub struct DiskMonitor {
+    warn_bytes: u64, // 0 never warns
+    stop_bytes: u64, // 0 never pauses
+    level: DiskLevel,
+    free: Option<u64>, // last measured, None before the first check
+}
+
+impl DiskMonitor {
+    pub fn new(warn_bytes: u64, stop_bytes: u64) -> DiskMonitor {
+        DiskMonitor { warn_bytes, stop_bytes, level: DiskLevel::Ok, free: None }
+    }
+
+    pub fn from_settings() -> DiskMonitor {
+        DiskMonitor::new(SETTINGS.disk_warn_mb * 1024 * 1024, SETTINGS.disk_stop_mb * 1024 * 1024)
+    }
+
+    pub fn level(&self) -> DiskLevel {
+        self.level
+    }
+
+    pub fn free(&self) -> Option<u64> {
+        self.free
+    }
+
+    /// Moves to the level `free` bytes put the disk at, returns it when it changed
+    pub fn update(&mut self, free: u64) -> Option<DiskLevel> {
+        self.free = Some(free);
+        let raised = |threshold: u64| threshold + threshold / RESUME_MARGIN;
+        let stop = if self.level == DiskLevel::Full { raised(self.stop_bytes) } else { self.stop_bytes };
+        let warn = if self.level >= DiskLevel::Low { raised(self.warn_bytes) } else { self.warn_bytes };
+
+        let level = if free < stop {
+            DiskLevel::Full
+        } else if free < warn {
+            DiskLevel::Low
+        } else {
+            DiskLevel::Ok
+        };
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+}
+
+/// Bytes available to us on the filesystem holding `path`
+pub fn free_space(path: &str) -> Result<u64> {
+    Ok(fs2::available_space(Path::new(path))?)
+}
+
+/// Sizes of the block, UTXO and wallet stores
+pub fn store_usage(utxo_path: &str) -> Vec<StoreUsage> {
+    [("Blocks", BLOCKS_PATH), ("UTXO set", utxo_path), ("Wallets", WALLETS_PATH)]
+        .into_iter()
+        .map(|(name, path)| StoreUsage { name: name.to_string(), bytes: dir_size(Path::new(path)) })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_levels_follow_free_space() {
+        let mut disk = DiskMonitor::new(1000 * MB, 200 * MB);
+        assert_eq!(disk.update(5000 * MB), None);
+        assert_eq!(disk.update(900 * MB), Some(DiskLevel::Low));
+        assert_eq!(disk.update(800 * MB), None);
+        assert_eq!(disk.update(150 * MB), Some(DiskLevel::Full));
+
+        // Freeing just past a threshold isn't enough to resume
+        assert_eq!(disk.update(210 * MB), None);
+        assert_eq!(disk.level(), DiskLevel::Full);
+        assert_eq!(disk.update(250 * MB), Some(DiskLevel::Low));
+        assert_eq!(disk.update(1050 * MB), None);
+        assert_eq!(disk.update(1200 * MB), Some(DiskLevel::Ok));
+
+        // Straight from full to ok, and back
+        assert_eq!(disk.update(10 * MB), Some(DiskLevel::Full));
+        assert_eq!(disk.update(2000 * MB), Some(DiskLevel::Ok));
+
+        // Zero thresholds turn a level off
+        let mut never = DiskMonitor::new(0, 0);
+        assert_eq!(never.update(0), None);
+        let mut warn_only = DiskMonitor::new(1000 * MB, 0);
+        assert_eq!(warn_only.update(0), Some(DiskLevel::Low));
+    }
+}
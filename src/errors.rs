@@ -1 +1,201 @@
+// failure_derive 0.1 emits its impls inside anonymous consts, which newer
+// compilers flag as non-local definitions.
+#![allow(non_local_definitions)]
+
+use failure::Fail;
+use crate::blockchain::ChainHealth;
+
 pub type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Debug, Fail)]
+pub enum WalletImportError {
+    #[fail(display = "Invalid secret key format")]
+    InvalidSecretKeyFormat,
+    #[fail(display = "Not a wallet file in any known format")]
+    UnknownFormat,
+    #[fail(display = "Wallet was created by a newer version (format {}), update to import it", version)]
+    NewerVersion { version: u32 },
+    #[fail(display = "Wallet is encrypted, a password is required")]
+    PasswordRequired,
+    #[fail(display = "Wrong password or corrupted wallet")]
+    WrongPassword,
+    #[fail(display = "Secret key doesn't match the stored address or public key")]
+    KeyMismatch,
+}
+
+/// Why the wallets database couldn't be read
+#[derive(Debug, Fail, PartialEq)]
+pub enum WalletStoreError {
+    #[fail(display = "Wallet {} was created by a newer version (record version {}), update to open it", address, version)]
+    NewerVersion { address: String, version: u32 },
+    #[fail(display = "Wallet {} has a truncated record", address)]
+    Truncated { address: String },
+}
+
+#[derive(Debug, Fail)]
+pub enum ProtocolError {
+    #[fail(display = "Message is shorter than its command header")]
+    Truncated,
+    #[fail(display = "Message exceeds the maximum size")]
+    MessageTooLarge,
+    #[fail(display = "Peer {} misbehaved: {}", peer, reason)]
+    Misbehavior { peer: String, reason: String, score: u32 },
+}
+
+/// The block database is inconsistent, usually after a crash while writing it, or has blocks
+/// that can't be read
+#[derive(Debug, Fail, PartialEq)]
+pub enum ChainOpenError {
+    #[fail(display = "The tip (LAST) is missing while {} blocks are stored", blocks)]
+    MissingTip { blocks: usize },
+    #[fail(display = "The tip (LAST) points at block {}, which isn't stored", tip)]
+    DanglingTip { tip: String },
+    #[fail(display = "{}, and {} blocks share the highest height {} so the tip can't be rebuilt", problem, candidates, height)]
+    AmbiguousTip { problem: String, height: i32, candidates: usize },
+    #[fail(display = "The tip (LAST) is block {}, while {} stored blocks extend it", tip, blocks)]
+    UnappliedBlocks { tip: String, blocks: usize },
+    #[fail(display = "{}", _0)]
+    Damaged(ChainHealth),
+}
+
+/// The block database is in a layout this build can't migrate, see schema
+#[derive(Debug, Fail, PartialEq)]
+pub enum SchemaError {
+    #[fail(display = "The block database has schema version {}, this build reads up to version {}. Run a newer build", found, supported)]
+    TooNew { found: u32, supported: u32 },
+    #[fail(display = "The block database holds blocks in a layout too old to migrate, it has to be recreated")]
+    UnknownLayout,
+}
+
+/// Something looked up in the block database isn't there, usually a peer asking for what
+/// we don't have
+#[derive(Debug, Fail, PartialEq)]
+pub enum LookupError {
+    #[fail(display = "Block {} is not found", _0)]
+    BlockNotFound(String),
+    #[fail(display = "Transaction {} is not found", _0)]
+    TxNotFound(String),
+    #[fail(display = "The chain has no tip (LAST)")]
+    NoTip,
+}
+
+/// The UTXO set can't follow a block, see UTXOSet::update
+#[derive(Debug, Fail, PartialEq)]
+pub enum UtxoError {
+    #[fail(display = "Output {}:{} isn't in the UTXO set", txid, vout)]
+    MissingUtxo { txid: String, vout: i32 },
+}
+
+/// Why a payment request URI couldn't be read
+#[derive(Debug, Fail, PartialEq)]
+pub enum PaymentRequestError {
+    #[fail(display = "Not a payment request")]
+    NotARequest,
+    #[fail(display = "Invalid address in payment request: {}", _0)]
+    InvalidAddress(String),
+    #[fail(display = "Payment request without an amount")]
+    MissingAmount,
+    #[fail(display = "Invalid amount in payment request: '{}', amounts are whole coins", _0)]
+    InvalidAmount(String),
+    #[fail(display = "Payment request memo is {} characters long, the limit is {}", len, max)]
+    MemoTooLong { len: usize, max: usize },
+    #[fail(display = "Payment request isn't properly encoded")]
+    BadEncoding,
+}
+
+/// Why hex given to the decoder isn't a block or a transaction, see raw
+#[derive(Debug, Fail, PartialEq)]
+pub enum DecodeError {
+    #[fail(display = "Not valid hex: {}", _0)]
+    BadHex(String),
+    #[fail(display = "{} bytes is over the {} byte message limit", len, max)]
+    TooLarge { len: usize, max: u64 },
+    #[fail(display = "Not a {}: {} at byte {}", kind, reason, offset)]
+    Malformed { kind: &'static str, offset: usize, reason: String },
+    #[fail(display = "Peers would reject this {}: {}", kind, reason)]
+    OutOfRange { kind: &'static str, reason: String },
+    #[fail(display = "Raw transaction version {} is unknown, this build reads version {}", found, supported)]
+    UnknownVersion { found: u8, supported: u8 },
+}
+
+impl DecodeError {
+    /// How far decoding got before failing
+    pub fn offset(&self) -> usize {
+        match self {
+            DecodeError::Malformed { offset, .. } => *offset,
+            _ => 0,
+        }
+    }
+}
+
+/// Why a block was kept out of the chain
+#[derive(Debug, Fail, PartialEq)]
+pub enum BlockRejectReason {
+    #[fail(display = "Block {} has an invalid proof of work", hash)]
+    BadProofOfWork { hash: String },
+    #[fail(display = "Block {} has a merkle root that doesn't match its transactions", hash)]
+    BadMerkleRoot { hash: String },
+    #[fail(display = "Block {} is {} bytes, more than a block may hold", hash, size)]
+    TooLarge { hash: String, size: usize },
+    #[fail(display = "Block {} uses target {:x}, the chain expects {:x}", hash, target, expected)]
+    BadTarget { hash: String, target: u64, expected: u64 },
+    #[fail(display = "Block {} builds on {}, which isn't stored", hash, prev_hash)]
+    UnknownParent { hash: String, prev_hash: String },
+    #[fail(display = "Block {} is at height {} but its parent is at {}", hash, height, parent_height)]
+    BadHeight { hash: String, height: i32, parent_height: i32 },
+    #[fail(display = "Block {} holds invalid transaction {}", hash, txid)]
+    InvalidTransaction { hash: String, txid: String },
+    #[fail(display = "Block {} conflicts with an operator checkpoint", hash)]
+    CheckpointViolation { hash: String },
+    #[fail(display = "Block {} is timestamped {}, not after the median time past {}", hash, timestamp, median)]
+    TimeTooOld { hash: String, timestamp: u128, median: u128 },
+    #[fail(display = "Block {} is timestamped {}, too far ahead of our clock", hash, timestamp)]
+    TimeTooNew { hash: String, timestamp: u128 },
+    #[fail(display = "Block {} has a coinbase paying {}, more than the {} of its subsidy and fees", hash, value, allowed)]
+    CoinbaseTooLarge { hash: String, value: i64, allowed: i64 },
+    #[fail(display = "Block {} has a coinbase that doesn't start with its height {}", hash, height)]
+    BadCoinbaseHeight { hash: String, height: i32 },
+}
+
+/// An input's public key doesn't hash to the lock of the output it spends
+#[derive(Debug, Fail, PartialEq)]
+pub enum OwnershipError {
+    #[fail(display = "Input {}:{} spends an output locked to {}, not to its key", txid, vout, owner)]
+    NotOwned { txid: String, vout: i32, owner: String },
+}
+
+/// Which consensus rule a transaction breaks, see transaction::validate
+#[derive(Debug, Fail, PartialEq)]
+pub enum TxRuleError {
+    #[fail(display = "Coinbase outside a block")]
+    CoinbaseOutsideBlock,
+    #[fail(display = "No inputs or no outputs")]
+    Empty,
+    #[fail(display = "Output {} pays {}, outputs must pay something", vout, value)]
+    NonPositiveOutput { vout: usize, value: i32 },
+    #[fail(display = "Spends {}:{} twice", txid, vout)]
+    DuplicateInput { txid: String, vout: i32 },
+    #[fail(display = "Spends {}:{}, which doesn't exist", txid, vout)]
+    MissingOutput { txid: String, vout: i32 },
+    #[fail(display = "Pays {} out of {} of inputs", outputs, inputs)]
+    OutputsExceedInputs { inputs: i64, outputs: i64 },
+}
+
+/// Why a relayed transaction was kept out of the mempool
+#[derive(Debug, Fail, PartialEq)]
+pub enum TxRejectReason {
+    #[fail(display = "Malformed transaction: {}", _0)]
+    Malformed(String),
+    #[fail(display = "Invalid transaction: {}", _0)]
+    Invalid(TxRuleError),
+    #[fail(display = "Invalid signature")]
+    BadSignature,
+    #[fail(display = "Input {}:{} doesn't exist or is already spent", txid, vout)]
+    InputUnavailable { txid: String, vout: i32 },
+    #[fail(display = "Pays {} in fees, replacing the mempool transactions spending its inputs takes {}", fee, required)]
+    ReplacementFeeTooLow { fee: i64, required: i64 },
+    #[fail(display = "Output {} pays {}, below the dust threshold of {}", vout, value, threshold)]
+    Dust { vout: usize, value: i32, threshold: i32 },
+    #[fail(display = "Carries {} bytes of data, more than the {} relayed", len, max)]
+    DataTooLarge { len: usize, max: usize },
+}
@@ -0,0 +1,166 @@
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::block::Block;
+use crate::disk::{DiskLevel, StoreUsage};
+use crate::mempool::EvictionReason;
+use crate::peer_history::RemovalReason;
+use crate::receipt::TxAck;
+use crate::utxoset::BalanceMismatch;
+
+// The UI hears about connected blocks at most this often
+pub const BLOCK_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Things happening inside the node that the UI wants to know about.
+/// The server pushes them through an mpsc channel set with `Server::set_event_sender`.
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    // Blocks added to the chain since the last update, mined locally or received from peers
+    BlocksConnected(BlocksConnected),
+    // A valid operator checkpoint was stored
+    CheckpointReceived {
+        height: i32,
+        hash: String,
+    },
+    // The tip stopped advancing while peers are connected, a resync was started.
+    // Sent once until the next block connects
+    TipStalled {
+        tip_age: Duration,
+    },
+    // A longer branch replaced the blocks above `fork_height`, the transactions only the old
+    // branch held went back to the mempool. After a rollback nothing is connected
+    ChainReorganized {
+        fork_height: i32,
+        disconnected: usize,
+        connected: usize,
+    },
+    // A peer was dropped from the known nodes and written to the peer history
+    PeerRemoved {
+        address: String,
+        reason: RemovalReason,
+    },
+    // The machine slept for `slept`, peers are being contacted again
+    ResumedFromSleep {
+        slept: Duration,
+    },
+    // Free space crossed a threshold, see disk. At Full blocks are paused
+    DiskSpace {
+        level: DiskLevel,
+        free: u64,
+        stores: Vec<StoreUsage>,
+    },
+    // A transaction left the mempool without being mined, see mempool
+    MempoolEvicted {
+        txid: String,
+        reason: EvictionReason,
+    },
+    // A peer signed for one of the transactions we sent, the signature was checked
+    TxAcknowledged {
+        ack: TxAck,
+    },
+    // Debug check: the UTXO set disagrees with a fresh chain scan after connecting a block
+    BalanceMismatch {
+        block_hash: String,
+        mismatches: Vec<BalanceMismatch>,
+    },
+}
+
+/// Summary of one or more connected blocks
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlocksConnected {
+    pub count: usize,
+    pub height: i32, // highest block among them
+    pub txids: Vec<String>,
+}
+
+impl BlocksConnected {
+    pub fn merge(&mut self, other: BlocksConnected) {
+        self.count += other.count;
+        self.height = self.height.max(other.height);
+        self.txids.extend(other.txids);
+    }
+}
+
+impl From<&Block> for BlocksConnected {
+    fn from(block: &Block) -> Self {
+        BlocksConnected {
+            count: 1,
+            height: block.get_height(),
+            txids: block.get_transactions().iter().map(|tx| tx.id.clone()).collect(),
+        }
+    }
+}
+
+/// Forwards connected blocks to `events`, merged so at most one `BlocksConnected` goes out per `period`.
+/// Runs until every sender of `blocks` is dropped, then flushes what's left.
+pub async fn coalesce_blocks(
+    mut blocks: mpsc::UnboundedReceiver<BlocksConnected>,
+    events: mpsc::Sender<NodeEvent>,
+    period: Duration,
+) {
+    let mut pending: Option<BlocksConnected> = None;
+    let mut ticker = tokio::time::interval(period);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            received = blocks.recv() => match received {
+                Some(batch) => match &mut pending {
+                    Some(p) => p.merge(batch),
+                    None => pending = Some(batch),
+                },
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if let Some(batch) = pending.take() {
+                    if events.send(NodeEvent::BlocksConnected(batch)).await.is_err() {
+                        return; // nobody is listening anymore
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(batch) = pending {
+        let _ = events.send(NodeEvent::BlocksConnected(batch)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_block_bursts_are_coalesced() {
+        let period = Duration::from_millis(50);
+        let (blocks, blocks_rx) = mpsc::unbounded_channel();
+        let (events, mut events_rx) = mpsc::channel(100);
+        let started = Instant::now();
+        let coalescer = tokio::spawn(coalesce_blocks(blocks_rx, events, period));
+
+        // Two sync bursts with a pause in between
+        for height in 0..1000 {
+            blocks.send(BlocksConnected { count: 1, height, txids: vec![format!("tx{}", height)] }).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        for height in 1000..1500 {
+            blocks.send(BlocksConnected { count: 1, height, txids: Vec::new() }).unwrap();
+        }
+        drop(blocks);
+        coalescer.await.unwrap();
+        let elapsed = started.elapsed();
+
+        let mut updates = Vec::new();
+        while let Ok(NodeEvent::BlocksConnected(batch)) = events_rx.try_recv() {
+            updates.push(batch);
+        }
+
+        // one update per elapsed period at most, plus the final flush
+        let max_updates = (elapsed.as_millis() / period.as_millis()) as usize + 2;
+        assert!(updates.len() >= 2 && updates.len() <= max_updates, "{} updates in {:?}", updates.len(), elapsed);
+        assert_eq!(updates.iter().map(|b| b.count).sum::<usize>(), 1500);
+        assert_eq!(updates.iter().map(|b| b.txids.len()).sum::<usize>(), 1000);
+        assert_eq!(updates.last().unwrap().height, 1499);
+    }
+}
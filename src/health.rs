@@ -0,0 +1,197 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Blockchain;
+use crate::clock::{self, MAX_TIME_ADJUSTMENT};
+use crate::disk::DiskLevel;
+use crate::maintenance::format_size;
+use crate::utxoset::UTXOSet;
+
+/*
+    Health checks
+
+    A single report for supervisors (systemd, container runtimes) and the status bar. Each
+    check has a status and a line of detail; the report is as healthy as its worst check.
+    Degraded means the node works but isn't following the network (no peers, no blocks),
+    Failing means it can't work at all (unreadable databases, no listener).
+
+    A running node answers `gethealth` from local connections with its report, that's what
+    `--healthcheck` asks for.
+*/
+
+// UTXO entries decoded to tell whether the set is readable
+const UTXO_SAMPLE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+    Failing,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthStatus::Ok => write!(f, "ok"),
+            HealthStatus::Degraded => write!(f, "degraded"),
+            HealthStatus::Failing => write!(f, "failing"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub name: String,
+    pub status: HealthStatus,
+    pub detail: String,
+}
+
+impl HealthCheck {
+    fn new(name: &str, status: HealthStatus, detail: String) -> Self {
+        HealthCheck { name: name.to_string(), status, detail }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    /// The worst status of any check
+    pub fn status(&self) -> HealthStatus {
+        self.checks.iter().map(|c| c.status).max().unwrap_or(HealthStatus::Ok)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.status() == HealthStatus::Ok
+    }
+
+    /// Checks that aren't ok, worst first
+    pub fn problems(&self) -> Vec<&HealthCheck> {
+        let mut problems: Vec<&HealthCheck> = self.checks.iter().filter(|c| c.status != HealthStatus::Ok).collect();
+        problems.sort_by_key(|c| std::cmp::Reverse(c.status));
+        problems
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.status())?;
+        for check in &self.checks {
+            writeln!(f, "  {:<10} {:<8} {}", check.name, check.status, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// The block database answers and the tip block can be read
+pub fn check_block_db(blockchain: &Blockchain) -> HealthCheck {
+    if blockchain.tip.is_empty() {
+        return HealthCheck::new("blocks", HealthStatus::Degraded, String::from("no blocks yet"));
+    }
+    match blockchain.get_block(&blockchain.tip) {
+        Ok(block) => HealthCheck::new("blocks", HealthStatus::Ok, format!("tip at height {}", block.get_height())),
+        Err(e) => HealthCheck::new("blocks", HealthStatus::Failing, format!("tip unreadable: {}", e)),
+    }
+}
+
+/// The UTXO database opens and its entries decode
+pub fn check_utxo_db(utxo_set: &UTXOSet) -> HealthCheck {
+    match utxo_set.sample_entries(UTXO_SAMPLE) {
+        Ok(count) => HealthCheck::new("utxos", HealthStatus::Ok, format!("{} entries readable", count)),
+        Err(e) => HealthCheck::new("utxos", HealthStatus::Failing, format!("unreadable: {}", e)),
+    }
+}
+
+pub fn check_listener(listening: bool, address: &str) -> HealthCheck {
+    if listening {
+        HealthCheck::new("listener", HealthStatus::Ok, format!("listening on {}", address))
+    } else {
+        HealthCheck::new("listener", HealthStatus::Failing, format!("not listening on {}", address))
+    }
+}
+
+/// `last_seen`: time since a peer last sent a message, None if none ever did
+pub fn check_peers(last_seen: Option<Duration>, window: Duration) -> HealthCheck {
+    match last_seen {
+        Some(age) if age <= window => {
+            HealthCheck::new("peers", HealthStatus::Ok, format!("last message {} ago", clock::format_age(age)))
+        }
+        Some(age) => HealthCheck::new(
+            "peers",
+            HealthStatus::Degraded,
+            format!("no peer messages for {}", clock::format_age(age)),
+        ),
+        None => HealthCheck::new("peers", HealthStatus::Degraded, String::from("no peer seen yet")),
+    }
+}
+
+/// `median_offset`: median clock offset to peers in ms, None without enough samples
+pub fn check_clock(median_offset: Option<i64>) -> HealthCheck {
+    match median_offset {
+        Some(offset) if offset.unsigned_abs() as u128 > MAX_TIME_ADJUSTMENT.as_millis() => HealthCheck::new(
+            "clock",
+            HealthStatus::Degraded,
+            format!("{} off from peers, beyond what's corrected", clock::format_age(Duration::from_millis(offset.unsigned_abs()))),
+        ),
+        Some(offset) => HealthCheck::new("clock", HealthStatus::Ok, format!("{} ms off from peers", offset)),
+        None => HealthCheck::new("clock", HealthStatus::Ok, String::from("too few peers to compare")),
+    }
+}
+
+/// `free`: bytes left where the blocks are when last measured, None before the first check
+pub fn check_disk(level: DiskLevel, free: Option<u64>) -> HealthCheck {
+    let Some(free) = free else {
+        return HealthCheck::new("disk", HealthStatus::Ok, String::from("not measured yet"));
+    };
+    match level {
+        DiskLevel::Ok => HealthCheck::new("disk", HealthStatus::Ok, format!("{} free", format_size(free))),
+        DiskLevel::Low => HealthCheck::new("disk", HealthStatus::Degraded, format!("only {} free", format_size(free))),
+        DiskLevel::Full => HealthCheck::new("disk", HealthStatus::Failing, format!("{} free, blocks paused", format_size(free))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainBuilder, WalletFixture};
+
+    #[test]
+    fn test_no_peers_is_degraded() {
+        let window = Duration::from_secs(30 * 60);
+        assert_eq!(check_peers(None, window).status, HealthStatus::Degraded);
+        assert_eq!(check_peers(Some(Duration::from_secs(31 * 60)), window).status, HealthStatus::Degraded);
+        assert_eq!(check_peers(Some(Duration::from_secs(60)), window).status, HealthStatus::Ok);
+
+        let skewed = MAX_TIME_ADJUSTMENT.as_millis() as i64 + 1;
+        assert_eq!(check_clock(Some(-skewed)).status, HealthStatus::Degraded);
+        assert_eq!(check_clock(Some(500)).status, HealthStatus::Ok);
+
+        let report = HealthReport {
+            checks: vec![check_listener(true, "127.0.0.1:8334"), check_peers(None, window)],
+        };
+        assert_eq!(report.status(), HealthStatus::Degraded);
+        assert!(!report.is_healthy());
+        assert_eq!(report.problems()[0].name, "peers");
+    }
+
+    #[test]
+    fn test_unreadable_tip_is_failing() {
+        let miner = WalletFixture::new(1);
+        let mut blockchain = ChainBuilder::new(&miner).empty_blocks(2).build();
+        assert_eq!(check_block_db(&blockchain).status, HealthStatus::Ok);
+
+        let tip = blockchain.tip.clone();
+        blockchain.db.insert(tip.as_bytes(), b"garbage".to_vec()).unwrap();
+        let check = check_block_db(&blockchain);
+        assert_eq!(check.status, HealthStatus::Failing, "{}", check.detail);
+
+        blockchain.tip = String::from("missing");
+        let report = HealthReport { checks: vec![check_peers(None, Duration::ZERO), check_block_db(&blockchain)] };
+        assert_eq!(report.status(), HealthStatus::Failing);
+        assert_eq!(report.problems()[0].name, "blocks");
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use failure::format_err;
+use rand::rngs::OsRng;
+
+use crate::errors::Result;
+
+/*
+    Node identity
+
+    Every node has an ed25519 key of its own, apart from any wallet, to sign what it tells
+    other nodes about itself (see receipt.rs). It's created on first start and kept as hex
+    at NODE_KEY_PATH, the public key identifies the node across restarts and addresses.
+*/
+
+pub const NODE_KEY_PATH: &str = "node_key";
+
+pub struct NodeIdentity {
+    signing_key: SigningKey,
+}
+
+impl NodeIdentity {
+    /// A fresh key that isn't saved anywhere
+    pub fn generate() -> NodeIdentity {
+        NodeIdentity { signing_key: SigningKey::generate(&mut OsRng) }
+    }
+
+    /// Reads the key at `path`, or creates and saves one when there's no file yet
+    pub fn load_or_create(path: &str) -> Result<NodeIdentity> {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let secret_key: [u8; 32] = hex::decode(text.trim())?.try_into()
+                    .map_err(|_| format_err!("The node key in {} isn't 32 bytes", path))?;
+                Ok(NodeIdentity { signing_key: SigningKey::from_bytes(&secret_key) })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let identity = NodeIdentity::generate();
+                if let Some(dir) = Path::new(path).parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(path, hex::encode(identity.signing_key.to_bytes()))?;
+                Ok(identity)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Hex
+    pub fn public_key(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Hex signature of `message`
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.signing_key.sign(message).to_bytes())
+    }
+}
+
+/// Whether `signature` (hex) over `message` was made by the node with `public_key` (hex)
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let (Ok(public_key), Ok(signature)) = (hex::decode(public_key), hex::decode(signature)) else {
+        return false;
+    };
+    let Some(key) = <&[u8; 32]>::try_from(public_key.as_slice()).ok().and_then(|bytes| VerifyingKey::from_bytes(bytes).ok()) else {
+        return false;
+    };
+    let Ok(signature) = <&[u8; 64]>::try_from(signature.as_slice()) else {
+        return false;
+    };
+    key.verify(message, &Signature::from_bytes(signature)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_survives_restarts() {
+        let path = std::env::temp_dir().join(format!("blockjain-identity-{}", rand::random::<u64>())).join("node_key");
+        let path = path.to_str().unwrap();
+        let created = NodeIdentity::load_or_create(path).unwrap();
+        let loaded = NodeIdentity::load_or_create(path).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+        assert_ne!(created.public_key(), NodeIdentity::generate().public_key());
+
+        let signature = loaded.sign(b"hello");
+        assert!(verify_signature(&created.public_key(), b"hello", &signature));
+        assert!(!verify_signature(&created.public_key(), b"hullo", &signature));
+        assert!(!verify_signature("00ff", b"hello", &signature));
+
+        fs::write(path, "abcd").unwrap();
+        assert!(NodeIdentity::load_or_create(path).is_err());
+        fs::remove_dir_all(Path::new(path).parent().unwrap()).ok();
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/*
+    Idle mode
+
+    While the window is minimized nobody is looking at it, so the app and the node slow down:
+
+    - periodic work runs `hidden_interval_multiplier` (see Settings) times less often, the
+      node's maintenance loop included
+    - work that only feeds the view (DeferredWork) is put off and done right away when the
+      window is restored
+    - the UI only wakes for its timers and to take in node events
+
+    Mining, block relay and answering peers don't depend on any of it. eframe reports
+    minimizing but not a window covered by others, which keeps running at the usual pace.
+*/
+
+/// Whether the window is on screen, shared by the UI that sets it and the node that
+/// slows down while it's hidden. Visible until told otherwise, which headless nodes never are
+#[derive(Debug, Clone)]
+pub struct Visibility {
+    hidden: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility { hidden: Arc::new(watch::Sender::new(false)) }
+    }
+}
+
+impl Visibility {
+    pub fn is_hidden(&self) -> bool {
+        *self.hidden.borrow()
+    }
+
+    /// Subscribers only hear about actual changes
+    pub fn set_hidden(&self, hidden: bool) {
+        self.hidden.send_if_modified(|current| std::mem::replace(current, hidden) != hidden);
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.hidden.subscribe()
+    }
+}
+
+/// `interval` stretched `multiplier` times while hidden. A multiplier of 0 or 1 keeps the
+/// usual pace
+pub fn scaled_interval(interval: Duration, hidden: bool, multiplier: u32) -> Duration {
+    if hidden {
+        interval * multiplier.max(1)
+    } else {
+        interval
+    }
+}
+
+/// Work the UI puts off while hidden
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferredWork {
+    ChainStats,
+    WalletHistory,
+    Health,
+}
+
+/// The UI's side of idle mode: whether it's hidden and what it put off meanwhile
+#[derive(Debug, Default)]
+pub struct IdleState {
+    hidden: bool,
+    deferred: Vec<DeferredWork>, // in the order it was first put off
+}
+
+impl IdleState {
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Records the window's state. Returns the work to catch up on when it was just
+    /// restored, nothing otherwise
+    pub fn set_hidden(&mut self, hidden: bool) -> Vec<DeferredWork> {
+        let restored = self.hidden && !hidden;
+        self.hidden = hidden;
+        if restored {
+            std::mem::take(&mut self.deferred)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Whether `work` has to wait for the window, in which case it's remembered (once)
+    pub fn defer(&mut self, work: DeferredWork) -> bool {
+        if self.hidden && !self.deferred.contains(&work) {
+            self.deferred.push(work);
+        }
+        self.hidden
+    }
+
+    /// `interval`, see `scaled_interval`
+    pub fn interval(&self, interval: Duration, multiplier: u32) -> Duration {
+        scaled_interval(interval, self.hidden, multiplier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intervals_stretch_only_while_hidden() {
+        let base = Duration::from_secs(20);
+        assert_eq!(scaled_interval(base, false, 4), base);
+        assert_eq!(scaled_interval(base, true, 4), Duration::from_secs(80));
+        assert_eq!(scaled_interval(base, true, 1), base);
+        assert_eq!(scaled_interval(base, true, 0), base);
+
+        let mut idle = IdleState::default();
+        assert_eq!(idle.interval(base, 3), base);
+        idle.set_hidden(true);
+        assert_eq!(idle.interval(base, 3), Duration::from_secs(60));
+        idle.set_hidden(false);
+        assert_eq!(idle.interval(base, 3), base);
+    }
+
+    #[test]
+    fn test_deferred_work_is_caught_up_on_restore() {
+        let mut idle = IdleState::default();
+        assert!(!idle.defer(DeferredWork::ChainStats));
+        assert!(idle.set_hidden(true).is_empty());
+
+        assert!(idle.defer(DeferredWork::Health));
+        assert!(idle.defer(DeferredWork::ChainStats));
+        assert!(idle.defer(DeferredWork::Health));
+        // still hidden, nothing to catch up on yet
+        assert!(idle.set_hidden(true).is_empty());
+
+        assert_eq!(idle.set_hidden(false), vec![DeferredWork::Health, DeferredWork::ChainStats]);
+        assert!(idle.set_hidden(false).is_empty());
+        assert!(!idle.defer(DeferredWork::Health));
+        idle.set_hidden(true);
+        assert!(idle.set_hidden(false).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_visibility_notifies_changes_only() {
+        let visibility = Visibility::default();
+        let mut changes = visibility.subscribe();
+        assert!(!visibility.is_hidden());
+
+        visibility.set_hidden(false);
+        assert!(!changes.has_changed().unwrap());
+        visibility.clone().set_hidden(true);
+        assert!(changes.has_changed().unwrap());
+        assert!(*changes.borrow_and_update());
+        assert!(visibility.is_hidden());
+    }
+}
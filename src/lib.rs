@@ -0,0 +1,105 @@
+//! BlockJain node library: the chain, wallets, UTXO set and peer-to-peer server.
+//!
+//! The desktop application (`gui` feature) is a thin binary on top of this crate.
+//! Headless tools can use [`node::start`] to run a node without any graphics dependencies.
+
+/// Pub key hashes and the addresses encoding them
+pub mod address;
+/// Address labels and the names views show counterparties by
+pub mod address_book;
+/// Hash-chained log of destructive actions
+pub mod audit;
+/// What the app reads from the node, behind traits, and a mock of it
+pub mod backend;
+/// Upload and download limits for peer traffic
+pub mod bandwidth;
+/// Blocks and their proof of work
+pub mod block;
+/// The block database and chain queries
+pub mod blockchain;
+/// Exporting the chain to a portable file and importing it back
+pub mod chain_file;
+/// Shared `Result` alias and typed errors
+pub mod errors;
+/// Operator-signed checkpoints for private networks
+pub mod checkpoint;
+/// Artificial latency, loss and disconnects for testing on localhost
+#[cfg(feature = "chaos")]
+pub mod chaos;
+/// Network-adjusted time and the age of the chain tip
+pub mod clock;
+/// Strategies for picking the outputs that fund a payment
+pub mod coin_selection;
+/// The effective node configuration and where each value came from
+pub mod config;
+/// Checks that a node speaks the peer protocol, see the protocol-conformance binary
+pub mod conformance;
+/// Confirmation progress of incoming payments
+pub mod confirmations;
+/// Text commands for inspecting the node while developing
+pub mod console;
+/// Free disk space checks that pause block downloads before the disk fills
+pub mod disk;
+/// Events the node reports to its embedder (usually the UI)
+pub mod events;
+/// Unconfirmed transaction packages and their fee rates
+pub mod mempool;
+/// Node health checks for supervisors and the status bar
+pub mod health;
+/// The node's own signing key
+pub mod identity;
+/// Slowing the app and node down while the window is minimized
+pub mod idle;
+/// Compacting the sled databases
+pub mod maintenance;
+/// Layout of the peer graph in the Peers tab
+pub mod network_map;
+/// Bootstrapping a running node
+pub mod node;
+/// Payments queued to go out together in one transaction
+pub mod outbox;
+/// Our sends waiting for a block, their locked inputs and expiry
+pub mod pending;
+/// Payment request URIs that fill in the transaction form
+pub mod payment_request;
+/// Why peers were removed or banned
+pub mod peer_history;
+/// Signed statements of the coins our addresses hold at a block
+pub mod proof_of_funds;
+/// Peer message encoding, decoding and limits, shared by the node and other clients
+pub mod protocol;
+/// Blocks and transactions as raw hex, and decoding it back
+pub mod raw;
+/// Peer-signed acknowledgments of the transactions we broadcast
+pub mod receipt;
+/// The global tokio runtime
+pub mod runtime;
+/// Versions of the block database layout and the migrations between them
+pub mod schema;
+/// Our sends and their way from pending to confirmed
+pub mod sent_txs;
+/// Peer-to-peer networking
+pub mod server;
+/// Application and node settings loaded from settings.json
+pub mod settings;
+/// UTXO snapshots for syncing new nodes without the full history
+pub mod snapshot;
+/// Stable digests of the chain state for checking snapshots and chain files
+pub mod state_digest;
+/// Transactions, signing and fees
+pub mod transaction;
+/// Transaction inputs and outputs
+pub mod tx;
+/// Deterministic wallets, transactions and chains for tests
+#[cfg(test)]
+pub mod testing;
+/// UTXO snapshot files for bootstrapping a node
+pub mod utxo_file;
+/// The unspent transaction output set
+pub mod utxoset;
+/// Keypairs and the wallet store
+pub mod wallet;
+/// Wallet file formats, current and legacy
+pub mod wallet_format;
+/// Index of the transactions touching our wallets
+pub mod wallet_history;
@@ -1,41 +1,64 @@
-use crate::errors::Result;
 use eframe::egui;
 use egui::{FontData, FontFamily};
 use egui_extras::install_image_loaders;
-use crate::settings::SETTINGS;
-
-mod block;
-mod transaction;
-mod errors;
-mod blockchain;
-mod tx;
-mod wallet;
-mod utxoset;
-mod server;
-mod runtime;
+use blockchain::runtime;
+use blockchain::config::CONFIG;
+use blockchain::settings::{self, SETTINGS, SETTINGS_PATH};
+use blockchain::wallet::Wallets;
+use once_cell::sync::Lazy;
+
 mod app;
-mod settings;
 
 fn main() -> eframe::Result {
     env_logger::init();
 
+    // `blockchain --healthcheck` asks the running node for its health, exits 0 when it's ok
+    if std::env::args().any(|arg| arg == "--healthcheck") {
+        let report = runtime::RUNTIME.block_on(blockchain::node::healthcheck(&CONFIG.port.value));
+        print!("{}", report);
+        std::process::exit(if report.is_healthy() { 0 } else { 1 });
+    }
+
+    // Settings, flags and environment, see config.rs
+    println!("Node configuration:\n{}", *CONFIG);
+    for warning in CONFIG.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    if CONFIG.headless.value {
+        use_data_dir();
+        return run_headless();
+    }
+
     // Application options
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size(egui::vec2(SETTINGS.resolution.0, SETTINGS.resolution.1))
             .with_icon(load_icon("resources/images/icon.png"))
-            .with_min_inner_size([800.0, 400.0]),
+            .with_min_inner_size([800.0, 400.0])
+            .with_fullscreen(CONFIG.fullscreen.value),
         centered: true,
         ..Default::default()
     };    
 
+    // `blockchain --mock-ui` shows made up data instead of starting a node, for working on
+    // the views. The data directory isn't used at all
+    let mock_ui = std::env::args().any(|arg| arg == "--mock-ui");
+    if !mock_ui {
+        use_data_dir(); // after the icon, resources are next to the program
+    }
+
     // Initialize the app asynchronously using the global runtime
     let app = runtime::RUNTIME.block_on(async {
-        match app::MyApp::initialize_async().await {
+        let initialized = if mock_ui {
+            app::MyApp::with_mock_backend().await
+        } else {
+            app::MyApp::initialize_async().await
+        };
+        match initialized {
             Ok(initialized_app) => initialized_app,
             Err(e) => {
                 eprintln!("Failed to initialize app asynchronously: {}", e);
-                app::MyApp::default()
+                app::MyApp::with_startup_error(&e)
             }
         }
     });
@@ -56,6 +79,37 @@ fn main() -> eframe::Result {
 
 // Helpers
 
+// Moves to the network's directory in the configured data directory, data/<network>/, the
+// databases are relative to it. settings.json stays where it was found
+fn use_data_dir() {
+    Lazy::force(&SETTINGS_PATH);
+    let Some(network_dir) = settings::network_dir(&SETTINGS.network) else {
+        eprintln!("Invalid network name {:?}, use letters, digits, '-' and '_'", SETTINGS.network);
+        std::process::exit(1);
+    };
+    let dir = std::path::Path::new(&CONFIG.data_dir.value).join(network_dir);
+    if let Err(e) = std::fs::create_dir_all(&dir).and_then(|_| std::env::set_current_dir(&dir)) {
+        eprintln!("Couldn't use the data directory {}: {}", dir.display(), e);
+        std::process::exit(1);
+    }
+}
+
+// The node without the window, until Ctrl+C
+fn run_headless() -> eframe::Result {
+    let result = runtime::RUNTIME.block_on(async {
+        let mining_address = Wallets::new()?.get_all_address().first().cloned().unwrap_or_default();
+        let _node = blockchain::node::start(&CONFIG.port.value, &mining_address, None).await?;
+        println!("Running headless on port {}, Ctrl+C stops the node", CONFIG.port.value);
+        tokio::signal::ctrl_c().await?;
+        Ok::<(), failure::Error>(())
+    });
+    if let Err(e) = result {
+        eprintln!("The node stopped: {}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
 fn load_icon(path: &str) -> eframe::egui::IconData {
     let (icon_rgba, icon_width, icon_height) = {
         let image = image::open(path)
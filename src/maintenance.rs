@@ -0,0 +1,239 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::format_err;
+use tokio::sync::RwLock;
+
+use crate::blockchain::BLOCKS_PATH;
+use crate::clock;
+use crate::errors::Result;
+use crate::server::Server;
+use crate::utxoset::UTXOSet;
+
+/*
+    Database compaction
+
+    sled never gives back the space of deleted or overwritten data. A store is compacted by
+    copying every tree into a fresh database next to it (`<path>.compact`), checking the
+    copy, and swapping the directories:
+
+        1. <path>.compact is written, flushed and marked with COMPLETE_MARKER
+        2. <path>  -> <path>.old
+        3. <path>.compact -> <path>
+        4. <path>.old is removed
+
+    A crash at any point leaves either the original or the complete copy, `recover` puts
+    whichever is right back at <path> before the store is opened again.
+*/
+
+const COMPLETE_MARKER: &str = "COMPACTED";
+// When compaction last ran, in ms since the epoch
+pub const LAST_COMPACTION_FILE: &str = "last_compaction";
+
+/// Size of one store before and after compaction, in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreReport {
+    pub name: String,
+    pub before: u64,
+    pub after: u64,
+}
+
+fn sidecar(path: &str, suffix: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.trim_end_matches('/'), suffix))
+}
+
+/// Bytes used by the files under `path`
+pub fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Finishes or rolls back a compaction of the store at `path` that was interrupted.
+/// Must run before the store is opened
+pub fn recover(path: &str) -> Result<()> {
+    let (copy, old) = (sidecar(path, "compact"), sidecar(path, "old"));
+    if !Path::new(path).exists() {
+        if copy.join(COMPLETE_MARKER).exists() {
+            println!("Finishing the interrupted compaction of {}", path);
+            fs::rename(&copy, path)?;
+            fs::remove_file(Path::new(path).join(COMPLETE_MARKER))?;
+        } else if old.exists() {
+            println!("Rolling back the interrupted compaction of {}", path);
+            fs::rename(&old, path)?;
+        }
+    }
+    if copy.exists() {
+        fs::remove_dir_all(&copy)?;
+    }
+    if old.exists() {
+        fs::remove_dir_all(&old)?;
+    }
+    Ok(())
+}
+
+// Copies every tree of `db` into a fresh database at `copy` and marks it complete
+fn rewrite(db: &sled::Db, copy: &Path) -> Result<()> {
+    if copy.exists() {
+        fs::remove_dir_all(copy)?;
+    }
+    let fresh = sled::open(copy)?;
+    for name in db.tree_names() {
+        let (source, target) = (db.open_tree(&name)?, fresh.open_tree(&name)?);
+        for kv in source.iter() {
+            let (key, value) = kv?;
+            target.insert(key, value)?;
+        }
+    }
+    fresh.flush()?;
+    if fresh.checksum()? != db.checksum()? {
+        return Err(format_err!("The compacted copy of {} doesn't match the original", copy.display()));
+    }
+    drop(fresh);
+    fs::write(copy.join(COMPLETE_MARKER), b"")?;
+    Ok(())
+}
+
+/// Compacts the store at `path` that `db` has open, and reopens `db` on the compacted copy.
+/// Nothing else may hold the store open
+pub fn compact_db(db: &mut sled::Db, path: &str, name: &str) -> Result<StoreReport> {
+    db.flush()?;
+    let before = dir_size(Path::new(path));
+    let (copy, old) = (sidecar(path, "compact"), sidecar(path, "old"));
+
+    rewrite(db, &copy)?;
+    fs::rename(path, &old)?;
+    fs::rename(&copy, path)?;
+    fs::remove_file(Path::new(path).join(COMPLETE_MARKER))?;
+    *db = sled::open(path)?; // drops the handle on the old directory
+    fs::remove_dir_all(&old)?;
+
+    Ok(StoreReport { name: name.to_string(), before, after: dir_size(Path::new(path)) })
+}
+
+/// Compacts the block and UTXO stores of a running node, the wallets are compacted by
+/// whoever holds them (Wallets::compact). Refuses while blocks or a snapshot are being
+/// downloaded, or while the UTXO set is busy (a reindex)
+pub async fn compact_node(utxo_set: &Arc<RwLock<UTXOSet>>, server: &Server) -> Result<Vec<StoreReport>> {
+    if server.is_syncing().await {
+        return Err(format_err!("The node is syncing, try again once it's done"));
+    }
+    let mut utxo = utxo_set.try_write().map_err(|_| format_err!("The UTXO set is busy, try again later"))?;
+    let chain = Arc::clone(&utxo.blockchain);
+    let mut blockchain = chain.try_write().map_err(|_| format_err!("The chain is busy, try again later"))?;
+
+    let reports = vec![
+        compact_db(&mut blockchain.db, BLOCKS_PATH, "Blocks")?,
+        utxo.compact()?,
+    ];
+    fs::write(LAST_COMPACTION_FILE, clock::now_millis().to_string())?;
+    Ok(reports)
+}
+
+/// Whether compaction last ran more than `interval` ago. A zero interval never schedules it
+pub fn compaction_due(interval: Duration) -> bool {
+    if interval.is_zero() {
+        return false;
+    }
+    let last: u128 = fs::read_to_string(LAST_COMPACTION_FILE).ok()
+        .and_then(|text| text.trim().parse().ok())
+        .unwrap_or(0);
+    clock::now_millis().saturating_sub(last) > interval.as_millis()
+}
+
+/// "12.3 MB", "512 KB", ...
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store() -> String {
+        let path = std::env::temp_dir().join(format!("blockjain-compact-{}", rand::random::<u64>()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_compaction_keeps_contents() {
+        let path = temp_store();
+        let mut db = sled::open(&path).unwrap();
+        let tree = db.open_tree("snapshot").unwrap();
+        for i in 0u32..20_000 {
+            db.insert(i.to_be_bytes(), vec![7; 200]).unwrap();
+            tree.insert(i.to_be_bytes(), vec![8; 50]).unwrap();
+        }
+        db.flush().unwrap();
+        // Most of it is dead space afterwards
+        for i in 100u32..20_000 {
+            db.remove(i.to_be_bytes()).unwrap();
+            tree.remove(i.to_be_bytes()).unwrap();
+        }
+        let checksum = db.checksum().unwrap();
+
+        let report = compact_db(&mut db, &path, "Test").unwrap();
+        assert!(report.after < report.before, "{:?}", report);
+        assert_eq!(db.checksum().unwrap(), checksum);
+        assert_eq!(db.len(), 100);
+        assert_eq!(db.open_tree("snapshot").unwrap().get(99u32.to_be_bytes()).unwrap().unwrap().to_vec(), vec![8; 50]);
+
+        // The store at the path is the compacted one, nothing is left next to it
+        drop((db, tree));
+        let reopened = sled::open(&path).unwrap();
+        assert_eq!(reopened.checksum().unwrap(), checksum);
+        assert!(!sidecar(&path, "compact").exists() && !sidecar(&path, "old").exists());
+        drop(reopened);
+        fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_recover_interrupted_compaction() {
+        let path = temp_store();
+        let (copy, old) = (sidecar(&path, "compact"), sidecar(&path, "old"));
+        let store = |at: &Path, value: &[u8]| {
+            let db = sled::open(at).unwrap();
+            db.insert("key", value).unwrap();
+            db.flush().unwrap();
+        };
+        let value = |at: &str| sled::open(at).unwrap().get("key").unwrap().unwrap().to_vec();
+
+        // Crashed while writing the copy: the original stays
+        store(Path::new(&path), b"original");
+        store(&copy, b"partial");
+        recover(&path).unwrap();
+        assert_eq!(value(&path), b"original");
+        assert!(!copy.exists());
+
+        // Crashed between the renames with a complete copy: the copy takes over
+        fs::rename(&path, &old).unwrap();
+        store(&copy, b"compacted");
+        fs::write(copy.join(COMPLETE_MARKER), b"").unwrap();
+        recover(&path).unwrap();
+        assert_eq!(value(&path), b"compacted");
+        assert!(!old.exists() && !copy.exists());
+        assert!(!Path::new(&path).join(COMPLETE_MARKER).exists());
+
+        // Crashed after moving the original away, before the copy was complete
+        fs::rename(&path, &old).unwrap();
+        store(&copy, b"partial");
+        recover(&path).unwrap();
+        assert_eq!(value(&path), b"compacted");
+
+        fs::remove_dir_all(&path).ok();
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+use failure::format_err;
+
+use crate::block::{BLOCK_RESERVED_SIZE, MAX_BLOCK_SIZE};
+use crate::errors::{Result, TxRejectReason, TxRuleError};
+use crate::transaction::{self, min_fee_bump, Transaction, TxContext, MAX_TX_DATA};
+
+/*
+    Mempool packages
+
+    A transaction spending outputs of other unconfirmed transactions can only be mined
+    together with them, so miners judge it by the fee rate of the whole package: the
+    transaction plus its unconfirmed ancestors. The detail popup shows the same numbers
+    the block template uses to order transactions.
+*/
+
+/*
+    Replace by fee
+
+    A transaction spending outputs that mempool transactions already spend replaces them,
+    along with their descendants, when it pays more in fees than all of them together by
+    at least `min_fee_bump` of its own size. Otherwise the first spend stays. A stuck send
+    can this way go out again with a higher fee, see Transaction::bump_fee.
+
+    Mempool limits
+
+    A transaction that stays unmined for the expiry age leaves the mempool, and while the
+    mempool is over its size limit the lowest package fee rates go first, the ones a block
+    template would take last. A transaction leaves with its mempool descendants, which
+    can't be mined without it.
+*/
+
+// How far ancestry is followed in either direction
+pub const MAX_ANCESTRY_DEPTH: usize = 25;
+
+/// Why a transaction left the mempool without a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    Expired,
+    MempoolFull,
+    Replaced, // by a transaction paying a higher fee
+}
+
+impl fmt::Display for EvictionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvictionReason::Expired => write!(f, "Expired"),
+            EvictionReason::MempoolFull => write!(f, "Mempool full"),
+            EvictionReason::Replaced => write!(f, "Replaced by a higher fee"),
+        }
+    }
+}
+
+/// A mempool transaction and its unconfirmed relatives
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PackageStats {
+    pub ancestors: Vec<String>,   // unconfirmed transactions it spends from, nearest first
+    pub descendants: Vec<String>, // unconfirmed transactions spending from it, nearest first
+    pub size: usize,              // serialized bytes of the transaction and its ancestors
+    pub fee: i64,                 // fees of the transaction and its ancestors
+}
+
+impl PackageStats {
+    /// Effective fee rate of the package, in coins per 1000 bytes like `DEFAULT_FEE_RATE`
+    pub fn fee_rate(&self) -> i64 {
+        if self.size == 0 {
+            return 0;
+        }
+        self.fee * 1000 / self.size as i64
+    }
+}
+
+/// Inputs minus outputs. Inputs are looked up in the mempool first, then with
+/// `confirmed_value(txid, vout)` for outputs of mined transactions.
+pub fn fee(
+    tx: &Transaction,
+    mempool: &HashMap<String, Transaction>,
+    confirmed_value: &impl Fn(&str, i32) -> Option<i32>,
+) -> Result<i64> {
+    if tx.is_coinbase() {
+        return Ok(0);
+    }
+
+    let mut inputs: i64 = 0;
+    for vin in &tx.vin {
+        let value = match mempool.get(&vin.txid) {
+            Some(parent) => parent.vout.get(vin.vout as usize).map(|out| out.value),
+            None => confirmed_value(&vin.txid, vin.vout),
+        };
+        inputs += value.ok_or_else(|| format_err!("Input {}:{} of {} not found", vin.txid, vin.vout, tx.id))? as i64;
+    }
+    let outputs: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+    Ok(inputs - outputs)
+}
+
+/// Every (txid, output index) the transactions of `mempool` spend, with the spending txid
+pub fn spent_outpoints(mempool: &HashMap<String, Transaction>) -> HashMap<(String, i32), String> {
+    mempool.values()
+        .flat_map(|tx| tx.vin.iter().map(|vin| ((vin.txid.clone(), vin.vout), tx.id.clone())))
+        .collect()
+}
+
+/// Checks a relayed transaction before it enters the mempool: the rules of
+/// `transaction::validate`, that it pays
+/// no output below `dust_threshold` and carries at most MAX_TX_DATA bytes of data, that every
+/// input is unspent, and its signatures as the next block, at `height`, checks them. Inputs
+/// are looked up in the mempool first, then with
+/// `confirmed_unspent(txid, vout)`, which gives the transaction holding an unspent mined output.
+/// Inputs mempool transactions already spend (`mempool_spends`, see `spent_outpoints`) make it
+/// a replacement, the txids it replaces are returned. Rejections are returned as `TxRejectReason`.
+pub fn check_admission(
+    tx: &Transaction,
+    mempool: &HashMap<String, Transaction>,
+    mempool_spends: &HashMap<(String, i32), String>,
+    dust_threshold: i32,
+    height: i32,
+    confirmed_unspent: &impl Fn(&str, i32) -> Result<Option<Transaction>>,
+) -> Result<Vec<String>> {
+    if tx.is_coinbase() {
+        return Err(TxRejectReason::Invalid(TxRuleError::CoinbaseOutsideBlock).into());
+    }
+    if tx.data.len() > MAX_TX_DATA {
+        return Err(TxRejectReason::DataTooLarge { len: tx.data.len(), max: MAX_TX_DATA }.into());
+    }
+    if tx_size(tx)? > MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE {
+        return Err(TxRejectReason::Malformed(String::from("too large for any block")).into());
+    }
+
+    let mut conflicts = HashSet::new();
+    let mut prev_txs = HashMap::new();
+    for vin in &tx.vin {
+        let unavailable = || TxRejectReason::InputUnavailable { txid: vin.txid.clone(), vout: vin.vout };
+        if let Some(spender) = mempool_spends.get(&(vin.txid.clone(), vin.vout)) {
+            conflicts.insert(spender.as_str());
+        }
+
+        let prev_tx = match mempool.get(&vin.txid) {
+            Some(parent) => parent.clone(),
+            None => confirmed_unspent(&vin.txid, vin.vout)?.ok_or_else(unavailable)?,
+        };
+        prev_txs.insert(prev_tx.id.clone(), prev_tx);
+    }
+
+    let paid_fee = transaction::validate(tx, &prev_txs, TxContext::Mempool).map_err(TxRejectReason::Invalid)?;
+    if let Some((vout, out)) = tx.vout.iter().enumerate().find(|(_, out)| out.value < dust_threshold) {
+        return Err(TxRejectReason::Dust { vout, value: out.value, threshold: dust_threshold }.into());
+    }
+
+    let mut replaced = Vec::new();
+    for txid in conflicts {
+        for id in std::iter::once(txid.to_string()).chain(descendants(txid, mempool)) {
+            if !replaced.contains(&id) {
+                replaced.push(id);
+            }
+        }
+    }
+    if let Some(vin) = tx.vin.iter().find(|vin| replaced.contains(&vin.txid)) {
+        return Err(TxRejectReason::Malformed(format!("spends {}:{}, which it replaces", vin.txid, vin.vout)).into());
+    }
+    if !replaced.is_empty() {
+        let confirmed_value = |txid: &str, vout: i32| {
+            let prev_tx = confirmed_unspent(txid, vout).ok()??;
+            prev_tx.vout.get(usize::try_from(vout).ok()?).map(|out| out.value)
+        };
+        let mut required = min_fee_bump(tx_size(tx)?) as i64;
+        for id in &replaced {
+            required += fee(&mempool[id], mempool, &confirmed_value)?;
+        }
+        if paid_fee < required {
+            return Err(TxRejectReason::ReplacementFeeTooLow { fee: paid_fee, required }.into());
+        }
+    }
+
+    match tx.verify_at(prev_txs, height) {
+        Ok(true) => Ok(replaced),
+        _ => Err(TxRejectReason::BadSignature.into()),
+    }
+}
+
+/// Walks the mempool around `txid`, at most MAX_ANCESTRY_DEPTH levels up and down
+pub fn package_stats(
+    txid: &str,
+    mempool: &HashMap<String, Transaction>,
+    confirmed_value: &impl Fn(&str, i32) -> Option<i32>,
+) -> Result<PackageStats> {
+    let tx = mempool.get(txid).ok_or_else(|| format_err!("Transaction {} is not in the mempool", txid))?;
+
+    let ancestors = walk(txid, |id| {
+        mempool[id].vin.iter()
+            .filter(|vin| mempool.contains_key(&vin.txid))
+            .map(|vin| vin.txid.clone())
+            .collect()
+    });
+    let descendants = descendants(txid, mempool);
+
+    let mut stats = PackageStats {
+        size: tx_size(tx)?,
+        fee: fee(tx, mempool, confirmed_value)?,
+        ancestors,
+        descendants,
+    };
+    for id in &stats.ancestors {
+        stats.size += tx_size(&mempool[id])?;
+        stats.fee += fee(&mempool[id], mempool, confirmed_value)?;
+    }
+    Ok(stats)
+}
+
+/// Order in which a block template takes mempool transactions: highest package fee
+/// rate first, every transaction after its unconfirmed parents
+pub fn block_order(mempool: &HashMap<String, Transaction>, stats: &HashMap<String, PackageStats>) -> Vec<String> {
+    let rate = |id: &String| stats.get(id).map_or(0, |s| s.fee_rate());
+    let mut by_rate: Vec<&String> = mempool.keys().collect();
+    by_rate.sort_by(|a, b| rate(b).cmp(&rate(a)).then_with(|| a.cmp(b)));
+
+    let mut order = Vec::new();
+    let mut placed = HashSet::new();
+    for id in by_rate {
+        place(id, mempool, &mut placed, &mut order, 0);
+    }
+    order
+}
+
+/// Package fee rate of the transaction at which `target_blocks` blocks of `max_block_size`
+/// bytes fill up, going down the block order, what a new transaction has to beat to get into
+/// one of them. None when the whole mempool fits
+pub fn pressure_fee_rate(
+    mempool: &HashMap<String, Transaction>,
+    stats: &HashMap<String, PackageStats>,
+    target_blocks: u32,
+    max_block_size: usize,
+) -> Result<Option<i64>> {
+    let space = target_blocks.max(1) as usize * max_block_size;
+    let mut filled = 0;
+    for id in block_order(mempool, stats) {
+        filled += tx_size(&mempool[&id])?;
+        if filled > space {
+            return Ok(Some(stats.get(&id).map_or(0, |s| s.fee_rate())));
+        }
+    }
+    Ok(None)
+}
+
+/// Takes transactions from `candidates`, in block order, while they fit in `max_size`
+/// bytes. A transaction whose parent among the candidates was left out is skipped too,
+/// what's left over goes into a later block.
+pub fn fill_block(candidates: Vec<Transaction>, max_size: usize) -> Result<Vec<Transaction>> {
+    let ids: HashSet<String> = candidates.iter().map(|tx| tx.id.clone()).collect();
+    let mut taken = HashSet::new();
+    let mut block = Vec::new();
+    let mut size = 0;
+    for tx in candidates {
+        let tx_bytes = tx_size(&tx)?;
+        let parents_taken = tx.vin.iter().all(|vin| !ids.contains(&vin.txid) || taken.contains(&vin.txid));
+        if size + tx_bytes <= max_size && parents_taken {
+            size += tx_bytes;
+            taken.insert(tx.id.clone());
+            block.push(tx);
+        }
+    }
+    Ok(block)
+}
+
+/// Transactions to drop, in order: those added (`added`, ms since the epoch) at least `ttl`
+/// before `now`, oldest first, then while the rest is over `max_size` bytes the lowest
+/// package fee rates of `stats`, older first among equal rates. Each comes with its
+/// descendants. A zero `ttl` or `max_size` disables that limit.
+pub fn evictions(
+    mempool: &HashMap<String, Transaction>,
+    added: &HashMap<String, u128>,
+    stats: &HashMap<String, PackageStats>,
+    now: u128,
+    ttl: Duration,
+    max_size: usize,
+) -> Result<Vec<(String, EvictionReason)>> {
+    let added_at = |id: &String| added.get(id).copied().unwrap_or(0);
+    let mut evicted = Vec::new();
+    let mut gone = HashSet::new();
+
+    if !ttl.is_zero() {
+        let mut expired: Vec<&String> = mempool.keys()
+            .filter(|id| now.saturating_sub(added_at(id)) >= ttl.as_millis())
+            .collect();
+        expired.sort_by(|a, b| added_at(a).cmp(&added_at(b)).then_with(|| a.cmp(b)));
+        for id in expired {
+            evict(id, mempool, EvictionReason::Expired, &mut gone, &mut evicted);
+        }
+    }
+
+    if max_size > 0 {
+        let mut size = 0;
+        for (_, tx) in mempool.iter().filter(|(id, _)| !gone.contains(*id)) {
+            size += tx_size(tx)?;
+        }
+        let rate = |id: &String| stats.get(id).map_or(0, |s| s.fee_rate());
+        let mut by_rate: Vec<&String> = mempool.keys().filter(|id| !gone.contains(*id)).collect();
+        by_rate.sort_by(|a, b| rate(a).cmp(&rate(b)).then_with(|| added_at(a).cmp(&added_at(b))).then_with(|| a.cmp(b)));
+        for id in by_rate {
+            if size <= max_size {
+                break;
+            }
+            for removed in evict(id, mempool, EvictionReason::MempoolFull, &mut gone, &mut evicted) {
+                size -= tx_size(&mempool[&removed])?;
+            }
+        }
+    }
+    Ok(evicted)
+}
+
+// Adds `id` and its descendants not already `gone` to `evicted`, returns them
+fn evict(
+    id: &str,
+    mempool: &HashMap<String, Transaction>,
+    reason: EvictionReason,
+    gone: &mut HashSet<String>,
+    evicted: &mut Vec<(String, EvictionReason)>,
+) -> Vec<String> {
+    let mut removed = Vec::new();
+    for txid in std::iter::once(id.to_string()).chain(descendants(id, mempool)) {
+        if gone.insert(txid.clone()) {
+            evicted.push((txid.clone(), reason));
+            removed.push(txid);
+        }
+    }
+    removed
+}
+
+// Mempool transactions spending from `txid`, nearest first
+fn descendants(txid: &str, mempool: &HashMap<String, Transaction>) -> Vec<String> {
+    walk(txid, |id| {
+        mempool.values()
+            .filter(|child| child.vin.iter().any(|vin| vin.txid == id))
+            .map(|child| child.id.clone())
+            .collect()
+    })
+}
+
+// Appends `id` after its unplaced mempool parents
+fn place(id: &str, mempool: &HashMap<String, Transaction>, placed: &mut HashSet<String>, order: &mut Vec<String>, depth: usize) {
+    if placed.contains(id) || depth > MAX_ANCESTRY_DEPTH {
+        return;
+    }
+    for vin in &mempool[id].vin {
+        if mempool.contains_key(&vin.txid) {
+            place(&vin.txid, mempool, placed, order, depth + 1);
+        }
+    }
+    if placed.insert(id.to_string()) {
+        order.push(id.to_string());
+    }
+}
+
+// Breadth first from `start` over `next`, without `start` itself
+fn walk(start: &str, next: impl Fn(&str) -> Vec<String>) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut seen = HashSet::from([start.to_string()]);
+    let mut level = vec![start.to_string()];
+
+    for _ in 0..MAX_ANCESTRY_DEPTH {
+        let mut next_level = Vec::new();
+        for id in &level {
+            for related in next(id) {
+                if seen.insert(related.clone()) {
+                    found.push(related.clone());
+                    next_level.push(related);
+                }
+            }
+        }
+        if next_level.is_empty() {
+            break;
+        }
+        level = next_level;
+    }
+    found
+}
+
+fn tx_size(tx: &Transaction) -> Result<usize> {
+    Ok(bincode::serialized_size(tx)? as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{coinbase, TxBuilder, WalletFixture};
+    use crate::tx::TXInput;
+    use crate::transaction::SIGHASH_ACTIVATION_HEIGHT;
+
+    #[test]
+    fn test_package_of_three_transaction_chain() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+
+        // confirmed 10 -> a (fee 1) -> b (fee 0) -> c (fee 5)
+        let reward = coinbase(&alice.address(), 1);
+        let a = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 9).build();
+        let b = TxBuilder::new(&bob).spend(&a, 0).pay(&alice.address(), 9).build();
+        let c = TxBuilder::new(&alice).spend(&b, 0).pay(&bob.address(), 4).build();
+
+        let mempool: HashMap<String, Transaction> = [&a, &b, &c].iter().map(|tx| (tx.id.clone(), (*tx).clone())).collect();
+        let confirmed = |txid: &str, vout: i32| (txid == reward.id).then(|| reward.vout[vout as usize].value);
+        let size = |tx: &Transaction| bincode::serialized_size(tx).unwrap() as usize;
+
+        let stats = package_stats(&c.id, &mempool, &confirmed).unwrap();
+        assert_eq!(stats.ancestors, vec![b.id.clone(), a.id.clone()]);
+        assert!(stats.descendants.is_empty());
+        assert_eq!(stats.size, size(&a) + size(&b) + size(&c));
+        assert_eq!(stats.fee, 6);
+        assert_eq!(stats.fee_rate(), 6000 / stats.size as i64);
+
+        let stats = package_stats(&a.id, &mempool, &confirmed).unwrap();
+        assert!(stats.ancestors.is_empty());
+        assert_eq!(stats.descendants, vec![b.id.clone(), c.id.clone()]);
+        assert_eq!((stats.size, stats.fee), (size(&a), 1));
+
+        let stats = package_stats(&b.id, &mempool, &confirmed).unwrap();
+        assert_eq!((stats.ancestors.len(), stats.descendants.len()), (1, 1));
+        assert_eq!((stats.size, stats.fee), (size(&a) + size(&b), 1));
+
+        // An input that can't be found anywhere makes the fee unknown
+        assert!(package_stats(&a.id, &mempool, &|_: &str, _: i32| None).is_err());
+
+        // c pays for its parents but still comes after them
+        let all: HashMap<String, PackageStats> = mempool.keys()
+            .map(|id| (id.clone(), package_stats(id, &mempool, &confirmed).unwrap()))
+            .collect();
+        assert_eq!(block_order(&mempool, &all), vec![a.id, b.id, c.id]);
+    }
+
+    #[test]
+    fn test_admission_turns_away_dust() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 1);
+        let tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 8).pay(&alice.address(), 1).build();
+        let confirmed = |txid: &str, _: i32| Ok((txid == reward.id).then(|| reward.clone()));
+
+        let rejection = check_admission(&tx, &HashMap::new(), &HashMap::new(), 2, 1, &confirmed).unwrap_err().downcast::<TxRejectReason>().unwrap();
+        assert_eq!(rejection, TxRejectReason::Dust { vout: 1, value: 1, threshold: 2 });
+        check_admission(&tx, &HashMap::new(), &HashMap::new(), 1, 1, &confirmed).unwrap();
+        check_admission(&tx, &HashMap::new(), &HashMap::new(), 0, 1, &confirmed).unwrap();
+    }
+
+    #[test]
+    fn test_admission_takes_legacy_signatures_until_the_chain_stops_accepting_them() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 1);
+        let legacy = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 9).build_legacy();
+        let confirmed = |txid: &str, _: i32| Ok((txid == reward.id).then(|| reward.clone()));
+
+        check_admission(&legacy, &HashMap::new(), &HashMap::new(), 0, SIGHASH_ACTIVATION_HEIGHT - 1, &confirmed).unwrap();
+        let rejection = check_admission(&legacy, &HashMap::new(), &HashMap::new(), 0, SIGHASH_ACTIVATION_HEIGHT, &confirmed)
+            .unwrap_err().downcast::<TxRejectReason>().unwrap();
+        assert_eq!(rejection, TxRejectReason::BadSignature);
+    }
+
+    #[test]
+    fn test_admission_turns_away_oversized_data() {
+        let alice = WalletFixture::new(1);
+        let reward = coinbase(&alice.address(), 1);
+        let confirmed = |txid: &str, _: i32| Ok((txid == reward.id).then(|| reward.clone()));
+        let with_data = |len: usize| TxBuilder::new(&alice).spend(&reward, 0).pay(&alice.address(), 9).data(vec![7; len]).build();
+
+        check_admission(&with_data(MAX_TX_DATA), &HashMap::new(), &HashMap::new(), 0, 1, &confirmed).unwrap();
+        let rejection = check_admission(&with_data(MAX_TX_DATA + 1), &HashMap::new(), &HashMap::new(), 0, 1, &confirmed)
+            .unwrap_err().downcast::<TxRejectReason>().unwrap();
+        assert_eq!(rejection, TxRejectReason::DataTooLarge { len: MAX_TX_DATA + 1, max: MAX_TX_DATA });
+    }
+
+    #[test]
+    fn test_replacement_pays_the_replaced_fees_plus_the_minimum_bump() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 1);
+        let original = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 8).build(); // fee 2
+        let child = TxBuilder::new(&bob).spend(&original, 0).pay(&bob.address(), 7).build(); // fee 1
+        let mempool: HashMap<String, Transaction> = [&original, &child].iter().map(|tx| (tx.id.clone(), (*tx).clone())).collect();
+        let spends = spent_outpoints(&mempool);
+        let confirmed = |txid: &str, _: i32| Ok((txid == reward.id).then(|| reward.clone()));
+        let replacement = |to_bob: i32| TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), to_bob).build();
+        let admit = |tx: &Transaction| check_admission(tx, &mempool, &spends, 0, 1, &confirmed);
+        assert_eq!(min_fee_bump(tx_size(&replacement(7)).unwrap()), 1);
+
+        // 2 + 1 replaced, plus 1
+        let rejection = admit(&replacement(7)).unwrap_err().downcast::<TxRejectReason>().unwrap();
+        assert_eq!(rejection, TxRejectReason::ReplacementFeeTooLow { fee: 3, required: 4 });
+        assert_eq!(admit(&replacement(6)).unwrap(), vec![original.id.clone(), child.id.clone()]);
+
+        // Nothing to replace without a conflict
+        assert!(check_admission(&replacement(7), &HashMap::new(), &HashMap::new(), 0, 1, &confirmed).unwrap().is_empty());
+
+        // Spending the child's input replaces only the child, spending an output of a replaced transaction fails
+        let spends_child_input = TxBuilder::new(&bob).spend(&original, 0).pay(&bob.address(), 1).build();
+        assert_eq!(admit(&spends_child_input).unwrap(), vec![child.id.clone()]);
+        // left unsigned, it's turned away before the signatures are checked
+        let mut spends_replaced = replacement(1);
+        spends_replaced.vin.push(TXInput { txid: original.id.clone(), vout: 0, ..spends_replaced.vin[0].clone() });
+        let rejection = admit(&spends_replaced).unwrap_err().downcast::<TxRejectReason>().unwrap();
+        assert!(matches!(rejection, TxRejectReason::Malformed(_)));
+    }
+
+    #[test]
+    fn test_evictions_take_expired_then_lowest_fee_rates() {
+        let alice = WalletFixture::new(1);
+        let rewards: Vec<Transaction> = (0..3).map(|height| coinbase(&alice.address(), height)).collect();
+        let cheap = TxBuilder::new(&alice).spend(&rewards[0], 0).pay(&alice.address(), 9).build(); // fee 1
+        let rich = TxBuilder::new(&alice).spend(&rewards[1], 0).pay(&alice.address(), 5).build(); // fee 5
+        let mid = TxBuilder::new(&alice).spend(&rewards[2], 0).pay(&alice.address(), 7).build(); // fee 3
+        let child = TxBuilder::new(&alice).spend(&cheap, 0).pay(&alice.address(), 7).build(); // fee 2
+
+        let txs = [&cheap, &rich, &mid, &child];
+        let mempool: HashMap<String, Transaction> = txs.iter().map(|tx| (tx.id.clone(), (*tx).clone())).collect();
+        let added: HashMap<String, u128> = txs.iter().zip([0, 1000, 2000, 3000]).map(|(tx, at)| (tx.id.clone(), at)).collect();
+        let confirmed = |txid: &str, vout: i32| rewards.iter().find(|r| r.id == txid).map(|r| r.vout[vout as usize].value);
+        let stats: HashMap<String, PackageStats> = mempool.keys()
+            .map(|id| (id.clone(), package_stats(id, &mempool, &confirmed).unwrap()))
+            .collect();
+        let ttl = Duration::from_secs(10);
+        let size = |tx: &Transaction| tx_size(tx).unwrap();
+        let evictions = |now: u128, ttl: Duration, max_size: usize| evictions(&mempool, &added, &stats, now, ttl, max_size).unwrap();
+        use EvictionReason::*;
+
+        assert!(evictions(9999, ttl, 0).is_empty());
+        assert!(evictions(u128::MAX, Duration::ZERO, 0).is_empty());
+        // cheap expires first and takes its child along
+        assert_eq!(evictions(10_000, ttl, 0), vec![(cheap.id.clone(), Expired), (child.id.clone(), Expired)]);
+        assert_eq!(
+            evictions(11_000, ttl, 0),
+            vec![(cheap.id.clone(), Expired), (child.id.clone(), Expired), (rich.id.clone(), Expired)]
+        );
+
+        // The lowest package rate goes first, here with its child, until the rest fits
+        assert_eq!(evictions(0, ttl, size(&rich) + size(&mid)), vec![(cheap.id.clone(), MempoolFull), (child.id.clone(), MempoolFull)]);
+        assert_eq!(
+            evictions(0, ttl, size(&rich)),
+            vec![(cheap.id.clone(), MempoolFull), (child.id.clone(), MempoolFull), (mid.id.clone(), MempoolFull)]
+        );
+        assert_eq!(
+            evictions(10_000, ttl, size(&rich)),
+            vec![(cheap.id.clone(), Expired), (child.id.clone(), Expired), (mid.id.clone(), MempoolFull)]
+        );
+    }
+
+    #[test]
+    fn test_pressure_is_the_rate_where_the_target_blocks_fill_up() {
+        let alice = WalletFixture::new(1);
+        let rewards: Vec<Transaction> = (0..3).map(|height| coinbase(&alice.address(), height)).collect();
+        let cheap = TxBuilder::new(&alice).spend(&rewards[0], 0).pay(&alice.address(), 9).build(); // fee 1
+        let rich = TxBuilder::new(&alice).spend(&rewards[1], 0).pay(&alice.address(), 5).build(); // fee 5
+        let mid = TxBuilder::new(&alice).spend(&rewards[2], 0).pay(&alice.address(), 7).build(); // fee 3
+
+        let mempool: HashMap<String, Transaction> = [&cheap, &rich, &mid].iter().map(|tx| (tx.id.clone(), (*tx).clone())).collect();
+        let confirmed = |txid: &str, vout: i32| rewards.iter().find(|r| r.id == txid).map(|r| r.vout[vout as usize].value);
+        let stats: HashMap<String, PackageStats> = mempool.keys()
+            .map(|id| (id.clone(), package_stats(id, &mempool, &confirmed).unwrap()))
+            .collect();
+        let largest = mempool.values().map(|tx| tx_size(tx).unwrap()).max().unwrap();
+        let pressure = |target_blocks: u32| pressure_fee_rate(&mempool, &stats, target_blocks, largest).unwrap();
+
+        // a block of one transaction takes rich, mid is where it's full
+        assert_eq!(pressure(1), Some(stats[&mid.id].fee_rate()));
+        assert_eq!(pressure(2), Some(stats[&cheap.id].fee_rate()));
+        assert_eq!(pressure(3), None);
+        assert_eq!(pressure_fee_rate(&mempool, &stats, 1, usize::MAX / 4).unwrap(), None);
+    }
+
+    #[test]
+    fn test_fill_block_splits_a_large_mempool() {
+        let alice = WalletFixture::new(1);
+        let rewards: Vec<Transaction> = (0..10_000).map(|height| coinbase(&alice.address(), height)).collect();
+        let mut left: Vec<Transaction> = rewards.iter()
+            .map(|reward| TxBuilder::new(&alice).spend(reward, 0).pay(&alice.address(), 9).build())
+            .collect();
+        let max_size = MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE;
+
+        let mut blocks = Vec::new();
+        while !left.is_empty() {
+            let block = fill_block(left.clone(), max_size).unwrap();
+            assert!(!block.is_empty());
+            let size: usize = block.iter().map(|tx| tx_size(tx).unwrap()).sum();
+            assert!(size <= max_size);
+            let ids: HashSet<String> = block.iter().map(|tx| tx.id.clone()).collect();
+            left.retain(|tx| !ids.contains(&tx.id));
+            blocks.push(block);
+        }
+        assert!(blocks.len() > 1);
+        assert_eq!(blocks.iter().map(Vec::len).sum::<usize>(), 10_000);
+
+        // A child doesn't go in without its parent
+        let reward = coinbase(&alice.address(), 0);
+        let parent = TxBuilder::new(&alice).spend(&reward, 0).pay(&alice.address(), 9).build();
+        let child = TxBuilder::new(&alice).spend(&parent, 0).pay(&alice.address(), 8).build();
+        let room = tx_size(&parent).unwrap() + tx_size(&child).unwrap() - 1;
+        let ids = |txs: Vec<Transaction>| txs.into_iter().map(|tx| tx.id).collect::<Vec<_>>();
+        assert_eq!(ids(fill_block(vec![parent.clone(), child.clone()], room).unwrap()), vec![parent.id.clone()]);
+        assert_eq!(ids(fill_block(vec![child, parent.clone()], usize::MAX).unwrap()), vec![parent.id]);
+    }
+}
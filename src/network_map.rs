@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::TAU;
+
+/*
+    Network map layout
+
+    Our node sits at the origin. Peers we've heard from directly are spread evenly on a ring
+    of radius 1, each owning an equal sector of the circle. Nodes we only know from gossip
+    go on an outer ring (SECOND_RING), fanned out inside the sector of the first direct peer
+    that reported them. Gossiped nodes nobody on the ring reported (the bootstrap node,
+    peers added by hand) get one extra sector of their own, which has no peer in it.
+
+    Positions are in ring units, the UI scales them to the space it has.
+*/
+
+pub const SECOND_RING: f32 = 1.8;
+// Share of a sector its fan of second-degree nodes may use, the rest keeps fans apart
+const FAN_WIDTH: f32 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapRole {
+    Local,
+    Direct,
+    Gossiped,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapNode {
+    pub address: String, // empty for our node
+    pub role: MapRole,
+    pub pos: (f32, f32),
+    pub parent: Option<usize>, // index of the node it's drawn connected to
+}
+
+/// Lays out `direct` peers and the `gossiped` nodes that aren't direct. `reported` holds
+/// the nodes each direct peer told us about. Index 0 is our node, then the direct peers
+/// in the given order, then the gossiped ones
+pub fn layout(direct: &[String], gossiped: &[String], reported: &HashMap<String, Vec<String>>) -> Vec<MapNode> {
+    let mut nodes = vec![MapNode { address: String::new(), role: MapRole::Local, pos: (0.0, 0.0), parent: None }];
+
+    // Each gossiped node joins the fan of the first direct peer reporting it
+    let direct_set: HashSet<&String> = direct.iter().collect();
+    let mut placed: HashSet<&String> = HashSet::new();
+    let mut fans: Vec<Vec<&String>> = vec![Vec::new(); direct.len()];
+    for (i, peer) in direct.iter().enumerate() {
+        for node in reported.get(peer).into_iter().flatten() {
+            if gossiped.contains(node) && !direct_set.contains(node) && placed.insert(node) {
+                fans[i].push(node);
+            }
+        }
+    }
+    let orphans: Vec<&String> = gossiped.iter()
+        .filter(|node| !direct_set.contains(node) && !placed.contains(node))
+        .collect();
+
+    let sectors = direct.len() + usize::from(!orphans.is_empty());
+    if sectors == 0 {
+        return nodes;
+    }
+    let sector = TAU / sectors as f32;
+
+    for (i, peer) in direct.iter().enumerate() {
+        nodes.push(MapNode { address: peer.clone(), role: MapRole::Direct, pos: polar(1.0, sector * i as f32), parent: Some(0) });
+    }
+    for (i, fan) in fans.iter().enumerate() {
+        for (address, angle) in fan.iter().zip(fan_angles(sector * i as f32, sector, fan.len())) {
+            nodes.push(MapNode { address: (*address).clone(), role: MapRole::Gossiped, pos: polar(SECOND_RING, angle), parent: Some(i + 1) });
+        }
+    }
+    for (address, angle) in orphans.iter().zip(fan_angles(sector * direct.len() as f32, sector, orphans.len())) {
+        nodes.push(MapNode { address: (*address).clone(), role: MapRole::Gossiped, pos: polar(SECOND_RING, angle), parent: None });
+    }
+    nodes
+}
+
+// `count` angles spread evenly over FAN_WIDTH of the sector centered on `center`
+fn fan_angles(center: f32, sector: f32, count: usize) -> impl Iterator<Item = f32> {
+    let width = sector * FAN_WIDTH;
+    (0..count).map(move |i| center - width / 2.0 + width * (i as f32 + 0.5) / count as f32)
+}
+
+fn polar(radius: f32, angle: f32) -> (f32, f32) {
+    (radius * angle.cos(), radius * angle.sin())
+}
+
+/// The node closest to `point` within `tolerance`, all in ring units
+pub fn hit_test(nodes: &[MapNode], point: (f32, f32), tolerance: f32) -> Option<usize> {
+    nodes.iter()
+        .enumerate()
+        .map(|(i, node)| (i, (node.pos.0 - point.0).hypot(node.pos.1 - point.1)))
+        .filter(|(_, distance)| *distance <= tolerance)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn angle(pos: (f32, f32)) -> f32 {
+        pos.1.atan2(pos.0).rem_euclid(TAU)
+    }
+
+    #[test]
+    fn test_direct_peers_on_the_ring() {
+        let direct = strings(&["a", "b", "c", "d"]);
+        let nodes = layout(&direct, &[], &HashMap::new());
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(nodes[0].role, MapRole::Local);
+        for (i, node) in nodes[1..].iter().enumerate() {
+            assert_eq!(node.address, direct[i]);
+            assert!((node.pos.0.hypot(node.pos.1) - 1.0).abs() < 1e-5);
+            assert!((angle(node.pos) - TAU / 4.0 * i as f32).abs() < 1e-5);
+            assert_eq!(node.parent, Some(0));
+        }
+
+        assert_eq!(layout(&[], &[], &HashMap::new()).len(), 1);
+    }
+
+    #[test]
+    fn test_gossiped_nodes_fan_out_from_their_reporter() {
+        let direct = strings(&["a", "b"]);
+        let gossiped = strings(&["x", "y", "z", "lonely"]);
+        let reported = HashMap::from([
+            (String::from("a"), strings(&["x", "y", "b"])), // b is direct, drawn once
+            (String::from("b"), strings(&["y", "z"])),      // y is already in a's fan
+        ]);
+        let nodes = layout(&direct, &gossiped, &reported);
+        let find = |address: &str| nodes.iter().find(|n| n.address == address).unwrap();
+
+        assert_eq!(nodes.len(), 1 + 2 + 4);
+        assert_eq!(nodes.iter().filter(|n| n.address == "b").count(), 1);
+        assert_eq!(find("x").parent, Some(1));
+        assert_eq!(find("y").parent, Some(1));
+        assert_eq!(find("z").parent, Some(2));
+        assert_eq!(find("lonely").parent, None);
+
+        // Three sectors of 120°, fans stay inside their own
+        let sector = TAU / 3.0;
+        for (address, center) in [("x", 0.0), ("y", 0.0), ("z", sector), ("lonely", 2.0 * sector)] {
+            let node = find(address);
+            assert_eq!(node.role, MapRole::Gossiped);
+            assert!((node.pos.0.hypot(node.pos.1) - SECOND_RING).abs() < 1e-5);
+            let offset = (angle(node.pos) - center + TAU / 2.0).rem_euclid(TAU) - TAU / 2.0;
+            assert!(offset.abs() <= sector * FAN_WIDTH / 2.0, "{} is {} off its sector", address, offset);
+        }
+        assert_ne!(find("x").pos, find("y").pos);
+    }
+
+    #[test]
+    fn test_hit_test() {
+        let nodes = layout(&strings(&["a", "b"]), &[], &HashMap::new());
+        assert_eq!(hit_test(&nodes, (0.05, 0.0), 0.1), Some(0));
+        assert_eq!(hit_test(&nodes, (0.95, 0.05), 0.1), Some(1));
+        assert_eq!(hit_test(&nodes, (-1.0, 0.02), 0.1), Some(2));
+        assert_eq!(hit_test(&nodes, (0.5, 0.5), 0.1), None);
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use log::{error, warn};
+use tokio::sync::{mpsc, RwLock};
+
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::events::NodeEvent;
+use crate::health::{HealthCheck, HealthReport, HealthStatus};
+use crate::identity::{NodeIdentity, NODE_KEY_PATH};
+use crate::server::{self, Server};
+use crate::utxoset::UTXOSet;
+
+/// Handles to the pieces of a running node
+pub struct Node {
+    pub blockchain: Arc<RwLock<Blockchain>>,
+    pub utxo_set: Arc<RwLock<UTXOSet>>,
+    pub server: Arc<RwLock<Server>>,
+}
+
+/// Opens (or creates) the local chain, reindexes the UTXO set and starts the server on `port`
+/// in the background. Must be called from within a tokio runtime.
+/// Mined block rewards go to `mining_address`, an empty address disables mining.
+pub async fn start(port: &str, mining_address: &str, events: Option<mpsc::Sender<NodeEvent>>) -> Result<Node> {
+    // This can either load the existing blockchain or create a new genesis block.
+    let blockchain = Arc::new(RwLock::new(Blockchain::new()?));
+    let utxo_set = Arc::new(RwLock::new(UTXOSet::new(Arc::clone(&blockchain))?));
+    // The UTXO set is updated after each block is stored, a crash in between leaves it
+    // behind the chain. Reindexing here brings it back in line
+    if let Some(interrupted) = utxo_set.read().await.interrupted_update()? {
+        let report = utxo_set.read().await.verify_against_chain().await?;
+        warn!("The last UTXO set update ({}) didn't finish. {}", interrupted, report);
+    }
+    utxo_set.write().await.reindex().await?;
+
+    let mut server = Server::new(port, mining_address, Arc::clone(&utxo_set))?;
+    server.set_identity(NodeIdentity::load_or_create(NODE_KEY_PATH)?);
+    if let Some(sender) = events {
+        server.set_event_sender(sender);
+    }
+    let server = Arc::new(RwLock::new(server));
+
+    tokio::spawn({
+        let server_clone = Arc::clone(&server);
+        async move {
+            if let Err(e) = Server::start_server(server_clone).await {
+                error!("Server error: {}", e);
+            }
+        }
+    });
+
+    Ok(Node {
+        blockchain,
+        utxo_set,
+        server,
+    })
+}
+
+impl Node {
+    pub async fn healthcheck(&self) -> HealthReport {
+        self.server.read().await.healthcheck().await
+    }
+}
+
+/// Health report of the node running on this machine at `port`, as `--healthcheck` prints it.
+/// A node that doesn't answer is failing
+pub async fn healthcheck(port: &str) -> HealthReport {
+    let addr = format!("127.0.0.1:{}", port);
+    match server::request_health(&addr).await {
+        Ok(report) => report,
+        Err(e) => HealthReport {
+            checks: vec![HealthCheck {
+                name: String::from("node"),
+                status: HealthStatus::Failing,
+                detail: format!("no answer from {}: {}", addr, e),
+            }],
+        },
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use crate::address;
+
+/*
+    Outbox
+
+    With batch sends on, Send queues the payment here instead of broadcasting it. Flushing
+    a wallet pays all of its queued payments in one transaction, an output per payment and
+    one change output, so a burst of payments spends one set of inputs instead of one each.
+
+    Payments are checked when their batch is taken: one whose address doesn't parse or whose
+    amount isn't positive is marked Invalid and stays behind for the user to remove, the rest
+    of the batch goes out without it. A payment can be removed until its batch is sending,
+    and once sent it keeps the txid of its batch. A batch that fails to send puts its
+    payments back in the queue.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaymentStatus {
+    Queued,
+    Invalid(String), // why, kept out of every batch
+    Sending,
+    Sent(String), // txid of the batch
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedPayment {
+    pub id: u64,
+    pub from: String, // address of the paying wallet
+    pub to: String,
+    pub amount: i32,
+    pub queued_at: u128, // ms since the epoch
+    pub status: PaymentStatus,
+}
+
+/// Payments waiting for their batch, oldest first
+#[derive(Debug, Default)]
+pub struct Outbox {
+    payments: Vec<QueuedPayment>,
+    next_id: u64,
+}
+
+impl Outbox {
+    pub fn add(&mut self, from: &str, to: &str, amount: i32, now: u128) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.payments.push(QueuedPayment {
+            id,
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            queued_at: now,
+            status: PaymentStatus::Queued,
+        });
+        id
+    }
+
+    pub fn payments(&self) -> &[QueuedPayment] {
+        &self.payments
+    }
+
+    /// Removes a payment that isn't sending or sent, false when there's no such payment
+    pub fn remove(&mut self, id: u64) -> bool {
+        let removable = |p: &QueuedPayment| p.id == id && matches!(p.status, PaymentStatus::Queued | PaymentStatus::Invalid(_));
+        let Some(index) = self.payments.iter().position(removable) else {
+            return false;
+        };
+        self.payments.remove(index);
+        true
+    }
+
+    /// Drops the payments that were sent
+    pub fn clear_sent(&mut self) {
+        self.payments.retain(|p| !matches!(p.status, PaymentStatus::Sent(_)));
+    }
+
+    /// Wallets whose oldest queued payment waited longer than `max_wait` at `now`. A zero
+    /// `max_wait` never flushes on its own
+    pub fn due(&self, now: u128, max_wait: Duration) -> Vec<String> {
+        if max_wait.is_zero() {
+            return Vec::new();
+        }
+        let mut wallets: Vec<String> = Vec::new();
+        for payment in &self.payments {
+            if payment.status == PaymentStatus::Queued
+                && now.saturating_sub(payment.queued_at) > max_wait.as_millis()
+                && !wallets.contains(&payment.from)
+            {
+                wallets.push(payment.from.clone());
+            }
+        }
+        wallets
+    }
+
+    /// The queued payments of wallet `from` that pass the checks, marked Sending. The others
+    /// are marked Invalid
+    pub fn take_batch(&mut self, from: &str) -> Vec<QueuedPayment> {
+        let mut batch = Vec::new();
+        for payment in self.payments.iter_mut().filter(|p| p.from == from && p.status == PaymentStatus::Queued) {
+            payment.status = if address::address_to_hash(&payment.to).is_err() {
+                PaymentStatus::Invalid(format!("{} isn't a valid address", payment.to))
+            } else if payment.amount <= 0 {
+                PaymentStatus::Invalid(String::from("the amount must be greater than zero"))
+            } else {
+                batch.push(payment.clone());
+                PaymentStatus::Sending
+            };
+        }
+        batch
+    }
+
+    /// Marks the payments of a batch sent in transaction `txid`
+    pub fn sent(&mut self, ids: &[u64], txid: &str) {
+        self.set_status(ids, PaymentStatus::Sent(txid.to_string()));
+    }
+
+    /// Puts the payments of a batch that couldn't be sent back in the queue
+    pub fn failed(&mut self, ids: &[u64]) {
+        self.set_status(ids, PaymentStatus::Queued);
+    }
+
+    fn set_status(&mut self, ids: &[u64], status: PaymentStatus) {
+        for payment in self.payments.iter_mut().filter(|p| ids.contains(&p.id) && p.status == PaymentStatus::Sending) {
+            payment.status = status.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::WalletFixture;
+
+    #[test]
+    fn test_batch_status_follows_each_payment() {
+        let alice = WalletFixture::new(1).address();
+        let other_wallet = WalletFixture::new(2).address();
+        let bob = WalletFixture::new(3).address();
+        let mut outbox = Outbox::default();
+
+        let first = outbox.add(&alice, &bob, 6, 1_000);
+        let typo = outbox.add(&alice, "notanaddress", 3, 1_000);
+        let removed = outbox.add(&alice, &bob, 4, 1_000);
+        let second = outbox.add(&alice, &other_wallet, 2, 2_000);
+        let elsewhere = outbox.add(&other_wallet, &bob, 1, 1_000);
+        assert!(outbox.remove(removed));
+        assert!(!outbox.remove(removed));
+
+        // The bad address only holds itself back
+        let batch = outbox.take_batch(&alice);
+        let ids: Vec<u64> = batch.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![first, second]);
+        assert_eq!(batch.iter().map(|p| p.amount).sum::<i32>(), 8);
+        let status = |outbox: &Outbox, id: u64| outbox.payments().iter().find(|p| p.id == id).unwrap().status.clone();
+        assert!(matches!(status(&outbox, typo), PaymentStatus::Invalid(_)));
+        assert_eq!(status(&outbox, elsewhere), PaymentStatus::Queued);
+
+        // Nothing sending can be removed or batched again
+        assert!(!outbox.remove(first));
+        assert!(outbox.take_batch(&alice).is_empty());
+
+        // A failed send queues them again, a successful one maps them to the batch txid
+        outbox.failed(&ids);
+        assert_eq!(status(&outbox, first), PaymentStatus::Queued);
+        let ids: Vec<u64> = outbox.take_batch(&alice).iter().map(|p| p.id).collect();
+        outbox.sent(&ids, "batchtxid");
+        assert_eq!(status(&outbox, first), PaymentStatus::Sent(String::from("batchtxid")));
+        assert_eq!(status(&outbox, second), PaymentStatus::Sent(String::from("batchtxid")));
+        outbox.clear_sent();
+        assert_eq!(outbox.payments().len(), 2);
+        assert!(outbox.remove(typo));
+
+        // Auto flush picks wallets whose oldest payment waited long enough
+        assert_eq!(outbox.due(1_500, Duration::from_millis(400)), vec![other_wallet.clone()]);
+        assert!(outbox.due(1_200, Duration::from_millis(400)).is_empty());
+        assert!(outbox.due(10_000, Duration::ZERO).is_empty());
+    }
+}
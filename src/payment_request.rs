@@ -0,0 +1,160 @@
+use crate::address;
+use crate::errors::PaymentRequestError;
+
+/*
+    Payment requests
+
+    A payment request is a URI the payee hands out so the payer's transaction form fills
+    itself in:
+
+        blockjain:<address>?amount=<coins>&memo=<percent-encoded text>
+
+    Amounts are whole coins, the chain has no smaller unit ("2" and "2.0" are fine, "1.5"
+    isn't). Parameters other than amount and memo are ignored so newer requests still open
+    in older nodes.
+*/
+
+pub const URI_SCHEME: &str = "blockjain";
+pub const MAX_MEMO_LEN: usize = 120; // characters, after decoding
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentRequest {
+    pub address: String,
+    pub amount: i32,
+    pub memo: String, // empty for none
+}
+
+impl PaymentRequest {
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!("{}:{}?amount={}", URI_SCHEME, self.address, self.amount);
+        if !self.memo.is_empty() {
+            uri.push_str("&memo=");
+            uri.push_str(&percent_encode(&self.memo));
+        }
+        uri
+    }
+
+    pub fn from_uri(uri: &str) -> Result<PaymentRequest, PaymentRequestError> {
+        let rest = uri.trim()
+            .strip_prefix(URI_SCHEME)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .ok_or(PaymentRequestError::NotARequest)?;
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if address::address_to_hash(address).is_err() {
+            return Err(PaymentRequestError::InvalidAddress(address.to_string()));
+        }
+
+        let mut amount = None;
+        let mut memo = String::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key {
+                "amount" => amount = Some(parse_amount(value)?),
+                "memo" => memo = percent_decode(value)?,
+                _ => {} // unknown parameters are tolerated
+            }
+        }
+        if memo.chars().count() > MAX_MEMO_LEN {
+            return Err(PaymentRequestError::MemoTooLong { len: memo.chars().count(), max: MAX_MEMO_LEN });
+        }
+
+        Ok(PaymentRequest {
+            address: address.to_string(),
+            amount: amount.ok_or(PaymentRequestError::MissingAmount)?,
+            memo,
+        })
+    }
+
+    /// Whether `text` looks like a payment request rather than a plain address
+    pub fn is_uri(text: &str) -> bool {
+        text.trim().starts_with(&format!("{}:", URI_SCHEME))
+    }
+}
+
+fn parse_amount(value: &str) -> Result<i32, PaymentRequestError> {
+    let invalid = || PaymentRequestError::InvalidAmount(value.to_string());
+    let whole = match value.split_once('.') {
+        Some((whole, fraction)) if fraction.chars().all(|c| c == '0') => whole,
+        Some(_) => return Err(invalid()),
+        None => value,
+    };
+    whole.parse::<i32>().ok().filter(|amount| *amount > 0).ok_or_else(invalid)
+}
+
+// Everything but unreserved characters (RFC 3986) as %XX of its UTF-8 bytes
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// '+' is taken as a space, like in form encoding
+fn percent_decode(text: &str) -> Result<String, PaymentRequestError> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok()).ok_or(PaymentRequestError::BadEncoding)?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| PaymentRequestError::BadEncoding)?);
+                rest = &tail[2..];
+            }
+            b'+' => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| PaymentRequestError::BadEncoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::WalletFixture;
+
+    #[test]
+    fn test_payment_request_round_trip() {
+        let address = WalletFixture::new(1).address();
+        for memo in ["", "Invoice42", "Rent for März & co. 100%", "a+b=c?"] {
+            let request = PaymentRequest { address: address.clone(), amount: 15, memo: memo.to_string() };
+            let uri = request.to_uri();
+            assert!(PaymentRequest::is_uri(&uri));
+            assert_eq!(PaymentRequest::from_uri(&uri).unwrap(), request);
+        }
+
+        // Hand written ones
+        let parsed = PaymentRequest::from_uri(&format!("blockjain:{}?memo=Invoice+42&amount=3.0&label=shop", address)).unwrap();
+        assert_eq!((parsed.amount, parsed.memo.as_str()), (3, "Invoice 42"));
+    }
+
+    #[test]
+    fn test_malformed_payment_requests() {
+        let address = WalletFixture::new(1).address();
+        let parse = |uri: String| PaymentRequest::from_uri(&uri).unwrap_err();
+
+        assert_eq!(parse(address.clone()), PaymentRequestError::NotARequest);
+        assert_eq!(parse(format!("bitcoin:{}?amount=1", address)), PaymentRequestError::NotARequest);
+        assert_eq!(parse(format!("blockjain:{}", address)), PaymentRequestError::MissingAmount);
+        assert_eq!(parse(format!("blockjain:{}?memo=hi", address)), PaymentRequestError::MissingAmount);
+        assert_eq!(parse(String::from("blockjain:notanaddress?amount=1")), PaymentRequestError::InvalidAddress(String::from("notanaddress")));
+        for amount in ["1.5", "-2", "0", "lots", ""] {
+            assert_eq!(parse(format!("blockjain:{}?amount={}", address, amount)), PaymentRequestError::InvalidAmount(amount.to_string()));
+        }
+        assert_eq!(parse(format!("blockjain:{}?amount=1&memo=%E", address)), PaymentRequestError::BadEncoding);
+        assert_eq!(parse(format!("blockjain:{}?amount=1&memo=%FF", address)), PaymentRequestError::BadEncoding);
+
+        let long = "x".repeat(MAX_MEMO_LEN + 1);
+        assert_eq!(parse(format!("blockjain:{}?amount=1&memo={}", address, long)), PaymentRequestError::MemoTooLong { len: MAX_MEMO_LEN + 1, max: MAX_MEMO_LEN });
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/*
+    Peer history
+
+    Every peer dropped from `known_nodes` leaves an entry in the "peer_history" tree of the
+    block database, so it's still possible to tell later why a peer is gone. Keys are
+    big-endian ids from `Db::generate_id`, which keeps the tree in the order the peers were
+    removed; only the newest MAX_PEER_HISTORY entries are kept.
+*/
+
+pub const MAX_PEER_HISTORY: usize = 100;
+
+/// Why a peer was dropped from the known nodes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RemovalReason {
+    // Didn't accept connections several times in a row
+    Unreachable,
+    // Reached the ban score, with the last misbehavior
+    Banned(String),
+    // Removed by the user
+    Manual,
+}
+
+impl fmt::Display for RemovalReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemovalReason::Unreachable => write!(f, "Unreachable"),
+            RemovalReason::Banned(reason) => write!(f, "Banned: {}", reason),
+            RemovalReason::Manual => write!(f, "Removed manually"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerHistoryEntry {
+    pub timestamp: u128, // ms since the epoch
+    pub address: String,
+    pub reason: RemovalReason,
+}
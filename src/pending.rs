@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+use std::fs;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::errors::Result;
+use crate::receipt::{BroadcastReceipt, TxAck};
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+
+/*
+    Pending sends
+
+    Transactions sent from this node that no block holds yet. Their inputs are locked so
+    coin selection doesn't spend them a second time. A send still pending after the expiry
+    age is abandoned: its inputs are unlocked and it can be retried with a higher fee.
+
+    Abandoned sends are kept, the network may still mine them. When a block holds one of
+    our sends it's settled, and every other send spending one of its inputs (a retry, or
+    the abandoned original of a confirmed retry) is settled as conflicted. Balances come
+    from the UTXO set, which follows the chain on its own.
+
+    Peers acknowledging a send (see receipt.rs) are recorded with it, one ack per peer.
+*/
+
+pub const PENDING_SENDS_PATH: &str = "pending_sends.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SendState {
+    Pending,
+    Abandoned,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingSend {
+    pub tx: Transaction,
+    pub sent_at: u128, // ms since the epoch
+    pub state: SendState,
+    pub replaces: Option<String>, // txid of the abandoned send this one retries
+    #[serde(default)]
+    pub acks: Vec<TxAck>,
+}
+
+impl PendingSend {
+    pub fn receipt(&self) -> BroadcastReceipt {
+        BroadcastReceipt { txid: self.tx.id.clone(), sent_at: self.sent_at, acks: self.acks.clone() }
+    }
+}
+
+/// How a send left the ledger
+#[derive(Debug, Clone)]
+pub enum Settled {
+    Confirmed(PendingSend),
+    Conflicted(PendingSend), // another transaction spending its inputs confirmed
+}
+
+/// The ledger of pending sends, saved to `path` after every change. Without a path it's
+/// only kept in memory
+#[derive(Debug, Default)]
+pub struct PendingSends {
+    path: Option<String>,
+    sends: Vec<PendingSend>,
+}
+
+impl PendingSends {
+    /// Reads the ledger at `path`, empty when there's no file yet
+    pub fn load(path: &str) -> Result<PendingSends> {
+        let sends = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(PendingSends { path: Some(path.to_string()), sends })
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            fs::write(path, serde_json::to_string_pretty(&self.sends)?)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a send. The pending send it `replaces` is abandoned, the replacement spends its inputs
+    pub fn record(&mut self, tx: Transaction, sent_at: u128, replaces: Option<String>) -> Result<()> {
+        if let Some(original) = self.sends.iter_mut().find(|send| Some(&send.tx.id) == replaces.as_ref()) {
+            original.state = SendState::Abandoned;
+        }
+        self.sends.push(PendingSend { tx, sent_at, state: SendState::Pending, replaces, acks: Vec::new() });
+        self.save()
+    }
+
+    /// Adds a peer's ack to the send it's for and returns the send's receipt. None when the
+    /// send isn't in the ledger or the peer already acknowledged it
+    pub fn add_ack(&mut self, ack: TxAck) -> Result<Option<BroadcastReceipt>> {
+        let Some(send) = self.sends.iter_mut().find(|send| send.tx.id == ack.txid) else {
+            return Ok(None);
+        };
+        if send.acks.iter().any(|known| known.public_key == ack.public_key) {
+            return Ok(None);
+        }
+        send.acks.push(ack);
+        let receipt = send.receipt();
+        self.save()?;
+        Ok(Some(receipt))
+    }
+
+    /// Receipts of the sends at least one peer acknowledged
+    pub fn receipts(&self) -> Vec<BroadcastReceipt> {
+        self.sends.iter().filter(|send| !send.acks.is_empty()).map(PendingSend::receipt).collect()
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&PendingSend> {
+        self.sends.iter().find(|send| send.tx.id == txid)
+    }
+
+    pub fn txids(&self) -> Vec<String> {
+        self.sends.iter().map(|send| send.tx.id.clone()).collect()
+    }
+
+    /// Inputs of the sends that are still pending
+    pub fn locked_outpoints(&self) -> HashSet<(String, i32)> {
+        self.sends.iter()
+            .filter(|send| send.state == SendState::Pending)
+            .flat_map(|send| send.tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)))
+            .collect()
+    }
+
+    /// Abandons the sends pending for longer than `max_age` at `now` (ms since the epoch) and
+    /// returns them. A zero `max_age` never abandons anything
+    pub fn expire(&mut self, now: u128, max_age: Duration) -> Result<Vec<PendingSend>> {
+        if max_age.is_zero() {
+            return Ok(Vec::new());
+        }
+        let mut abandoned = Vec::new();
+        for send in &mut self.sends {
+            if send.state == SendState::Pending && now.saturating_sub(send.sent_at) > max_age.as_millis() {
+                send.state = SendState::Abandoned;
+                abandoned.push(send.clone());
+            }
+        }
+        if !abandoned.is_empty() {
+            self.save()?;
+        }
+        Ok(abandoned)
+    }
+
+    /// Settles the sends among `mined` (txids found in blocks) and the sends conflicting with
+    /// them, which are removed from the ledger
+    pub fn reconcile(&mut self, mined: &[String]) -> Result<Vec<Settled>> {
+        let confirmed: Vec<PendingSend> = self.sends.iter().filter(|send| mined.contains(&send.tx.id)).cloned().collect();
+        if confirmed.is_empty() {
+            return Ok(Vec::new());
+        }
+        let spent: HashSet<(&String, i32)> = confirmed.iter()
+            .flat_map(|send| send.tx.vin.iter().map(|vin| (&vin.txid, vin.vout)))
+            .collect();
+
+        let mut settled = Vec::new();
+        let mut kept = Vec::new();
+        for send in &self.sends {
+            if mined.contains(&send.tx.id) {
+                settled.push(Settled::Confirmed(send.clone()));
+            } else if send.tx.vin.iter().any(|vin| spent.contains(&(&vin.txid, vin.vout))) {
+                settled.push(Settled::Conflicted(send.clone()));
+            } else {
+                kept.push(send.clone());
+            }
+        }
+        self.sends = kept;
+        self.save()?;
+        Ok(settled)
+    }
+}
+
+/// Makes the UTXO set's coin selection skip the inputs of pending sends
+pub async fn apply_locks(sends: &PendingSends, utxo_set: &RwLock<UTXOSet>) {
+    utxo_set.write().await.set_locked_outpoints(sends.locked_outpoints());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::coin_selection::CoinSelection;
+    use crate::testing::{ChainBuilder, TxBuilder, WalletFixture};
+
+    const HOUR: u128 = 60 * 60 * 1000;
+
+    #[tokio::test]
+    async fn test_expiry_releases_inputs() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let utxo_path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), utxo_path.to_str().unwrap()).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+
+        let ledger_path = std::env::temp_dir().join(format!("blockjain-pending-{}.json", rand::random::<u64>()));
+        let mut sends = PendingSends::load(ledger_path.to_str().unwrap()).unwrap();
+        let send = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 4).build();
+        sends.record(send.clone(), 1_000 * HOUR, None).unwrap();
+        apply_locks(&sends, &utxo).await;
+        assert_eq!(utxo.read().await.find_spendable_outputs(&alice.pub_key_hash(), 10, CoinSelection::default()).unwrap().0, 0);
+
+        // Not yet
+        let max_age = Duration::from_secs(24 * 60 * 60);
+        assert!(sends.expire(1_024 * HOUR, max_age).unwrap().is_empty());
+        assert!(sends.expire(1_100 * HOUR, Duration::ZERO).unwrap().is_empty());
+
+        let abandoned = sends.expire(1_024 * HOUR + 1, max_age).unwrap();
+        assert_eq!(abandoned.len(), 1);
+        assert_eq!(abandoned[0].tx.id, send.id);
+        assert!(sends.locked_outpoints().is_empty());
+        apply_locks(&sends, &utxo).await;
+        assert_eq!(utxo.read().await.find_spendable_outputs(&alice.pub_key_hash(), 10, CoinSelection::default()).unwrap().0, 10);
+
+        // Abandoned once, even when checked again, and after a restart
+        assert!(sends.expire(1_100 * HOUR, max_age).unwrap().is_empty());
+        let reloaded = PendingSends::load(ledger_path.to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.get(&send.id).unwrap().state, SendState::Abandoned);
+
+        fs::remove_file(&ledger_path).ok();
+        fs::remove_dir_all(&utxo_path).ok();
+    }
+
+    #[test]
+    fn test_late_confirmation_settles_the_retry() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = crate::testing::coinbase(&alice.address(), 1);
+        let original = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 4).build();
+        let retry = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 3).build();
+        let other = TxBuilder::new(&bob).spend(&original, 0).pay(&alice.address(), 6).build();
+
+        let mut sends = PendingSends::default();
+        sends.record(original.clone(), 0, None).unwrap();
+        sends.expire(2 * HOUR, Duration::from_secs(60)).unwrap();
+        sends.record(retry.clone(), 2 * HOUR, Some(original.id.clone())).unwrap();
+        sends.record(other.clone(), 2 * HOUR, None).unwrap();
+        assert_eq!(sends.locked_outpoints().len(), 2);
+
+        // Blocks without our sends change nothing
+        assert!(sends.reconcile(&[String::from("unrelated")]).unwrap().is_empty());
+
+        // The abandoned original made it into a block after all, the retry can't anymore
+        let settled = sends.reconcile(std::slice::from_ref(&original.id)).unwrap();
+        assert_eq!(settled.len(), 2);
+        assert!(matches!(&settled[0], Settled::Confirmed(send) if send.tx.id == original.id && send.state == SendState::Abandoned));
+        assert!(matches!(&settled[1], Settled::Conflicted(send) if send.tx.id == retry.id));
+        assert_eq!(sends.txids(), vec![other.id.clone()]);
+        assert_eq!(sends.locked_outpoints(), HashSet::from([(original.id.clone(), 0)]));
+
+        // A send bumped while still pending is abandoned, its replacement keeps the inputs locked
+        let bumped = TxBuilder::new(&bob).spend(&original, 0).pay(&alice.address(), 5).build();
+        sends.record(bumped, 3 * HOUR, Some(other.id.clone())).unwrap();
+        assert_eq!(sends.get(&other.id).unwrap().state, SendState::Abandoned);
+        assert_eq!(sends.locked_outpoints(), HashSet::from([(original.id, 0)]));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::address;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::wallet::Wallet;
+
+/*
+    Proof of funds
+
+    A JSON document showing a verifier that we control addresses holding some coins as of
+    a block, without giving away any key. It lists each address with its balance at the
+    block (height and hash) and the challenge the verifier asked us to include, so an old
+    proof can't be replayed. Every address signs the hash of all of that with its own key
+    and comes with its public key, which must hash to the address.
+
+    Verifying needs a node on the same chain: the block must be on our best chain, the
+    balances are recomputed at that block and every signature is checked.
+*/
+
+pub const FILE_EXTENSION: &str = "json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressProof {
+    pub address: String,
+    pub balance: i32,
+    pub public_key: String, // hex
+    pub signature: String,  // hex, over the document hash
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProofOfFunds {
+    pub height: i32,
+    pub block_hash: String,
+    pub challenge: String,
+    pub addresses: Vec<AddressProof>,
+}
+
+/// What checking one address of a proof found
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressCheck {
+    pub address: String,
+    pub claimed: i32,
+    pub actual: i32, // at the proof's block, in our chain
+    pub signed: bool, // by the key of the address
+}
+
+impl AddressCheck {
+    pub fn is_valid(&self) -> bool {
+        self.signed && self.claimed == self.actual
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedReport {
+    pub height: i32,
+    pub block_hash: String,
+    pub challenge: String,
+    pub addresses: Vec<AddressCheck>,
+}
+
+impl VerifiedReport {
+    /// Every address signed and holds what it claims
+    pub fn is_valid(&self) -> bool {
+        !self.addresses.is_empty() && self.addresses.iter().all(AddressCheck::is_valid)
+    }
+
+    /// Coins the valid addresses proved
+    pub fn proven_total(&self) -> i64 {
+        self.addresses.iter().filter(|check| check.is_valid()).map(|check| check.actual as i64).sum()
+    }
+}
+
+impl ProofOfFunds {
+    /// Signs the balances of `wallets` at the tip of `blockchain`
+    pub fn create(blockchain: &Blockchain, wallets: &[&Wallet], challenge: &str) -> Result<ProofOfFunds> {
+        if wallets.is_empty() {
+            return Err(format_err!("Select at least one address"));
+        }
+        let tip = blockchain.get_block(&blockchain.tip)?;
+        let balances = balances_at(blockchain, &tip.get_hash())?;
+        let mut proof = ProofOfFunds {
+            height: tip.get_height(),
+            block_hash: tip.get_hash(),
+            challenge: challenge.to_string(),
+            addresses: wallets.iter().map(|wallet| AddressProof {
+                address: wallet.get_address(),
+                balance: balances.get(&address::pub_key_to_hash(&wallet.public_key)).copied().unwrap_or(0),
+                public_key: hex::encode(&wallet.public_key),
+                signature: String::new(),
+            }).collect(),
+        };
+
+        let hash = proof.document_hash()?;
+        for (entry, wallet) in proof.addresses.iter_mut().zip(wallets) {
+            let secret_key: &[u8; 32] = wallet.secret_key.as_slice().try_into()
+                .map_err(|_| format_err!("The key of {} isn't 32 bytes", entry.address))?;
+            entry.signature = hex::encode(SigningKey::from_bytes(secret_key).sign(&hash).to_bytes());
+        }
+        Ok(proof)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(document: &str) -> Result<ProofOfFunds> {
+        serde_json::from_str(document).map_err(|e| format_err!("Not a proof of funds: {}", e))
+    }
+
+    // What every address signs: the whole document but the keys and signatures, prefixed so
+    // the signature can't be reused for anything else
+    fn document_hash(&self) -> Result<Vec<u8>> {
+        let balances: Vec<(&str, i32)> = self.addresses.iter().map(|a| (a.address.as_str(), a.balance)).collect();
+        let data = bincode::serialize(&("proofoffunds", self.height, &self.block_hash, &self.challenge, balances))?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        let mut hash = vec![0; hasher.output_bytes()];
+        hasher.result(&mut hash);
+        Ok(hash)
+    }
+}
+
+/// Checks the signatures of a proof of funds and its balances against `blockchain`. Fails
+/// when the document can't be read or its block isn't on our best chain
+pub fn verify_proof_of_funds(document: &str, blockchain: &Blockchain) -> Result<VerifiedReport> {
+    let proof = ProofOfFunds::from_json(document)?;
+    let on_our_chain = blockchain.get_block_by_height(proof.height).is_ok_and(|block| block.get_hash() == proof.block_hash);
+    if !on_our_chain {
+        return Err(format_err!("Block {} at height {} isn't on this node's chain", proof.block_hash, proof.height));
+    }
+
+    let balances = balances_at(blockchain, &proof.block_hash)?;
+    let hash = proof.document_hash()?;
+    let addresses = proof.addresses.iter().map(|entry| {
+        let actual = address::address_to_hash(&entry.address).ok()
+            .and_then(|hash| balances.get(&hash).copied())
+            .unwrap_or(0);
+        AddressCheck {
+            address: entry.address.clone(),
+            claimed: entry.balance,
+            actual,
+            signed: signed_by_address(entry, &hash),
+        }
+    }).collect();
+
+    Ok(VerifiedReport { height: proof.height, block_hash: proof.block_hash, challenge: proof.challenge, addresses })
+}
+
+// The public key hashes to the address and made the signature
+fn signed_by_address(entry: &AddressProof, document_hash: &[u8]) -> bool {
+    let (Ok(public_key), Ok(signature)) = (hex::decode(&entry.public_key), hex::decode(&entry.signature)) else {
+        return false;
+    };
+    if address::pub_key_to_address(&public_key) != entry.address {
+        return false;
+    }
+    let Some(key) = <&[u8; 32]>::try_from(public_key.as_slice()).ok().and_then(|bytes| VerifyingKey::from_bytes(bytes).ok()) else {
+        return false;
+    };
+    let Ok(signature) = <&[u8; 64]>::try_from(signature.as_slice()) else {
+        return false;
+    };
+    key.verify(document_hash, &Signature::from_bytes(signature)).is_ok()
+}
+
+// Balance of every pub key hash right after block `hash`
+fn balances_at(blockchain: &Blockchain, hash: &str) -> Result<HashMap<Vec<u8>, i32>> {
+    let mut balances: HashMap<Vec<u8>, i32> = HashMap::new();
+    for entry in blockchain.unspent_transactions(hash)? {
+        for index in entry.unspent {
+            if let Some(out) = entry.tx.vout.get(index as usize) {
+                *balances.entry(out.pub_key_hash.clone()).or_insert(0) += out.value;
+            }
+        }
+    }
+    Ok(balances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{coinbase, ChainBuilder, TxBuilder, WalletFixture};
+
+    #[test]
+    fn test_proof_of_funds() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let payment = TxBuilder::new(&alice).spend(&coinbase(&alice.address(), 0), 0).pay(&bob.address(), 4).pay(&alice.address(), 6).build();
+        let bc = ChainBuilder::new(&alice).block(vec![payment]).build();
+        let alice_balance = balances_at(&bc, &bc.tip).unwrap()[&alice.pub_key_hash()];
+
+        let proof = ProofOfFunds::create(&bc, &[&alice.wallet, &bob.wallet], "nonce-42").unwrap();
+        assert_eq!((proof.height, proof.block_hash.as_str()), (1, bc.tip.as_str()));
+        let report = verify_proof_of_funds(&proof.to_json().unwrap(), &bc).unwrap();
+        assert!(report.is_valid());
+        assert_eq!(report.challenge, "nonce-42");
+        assert_eq!(report.proven_total(), alice_balance as i64 + 4);
+
+        // A raised balance is neither held nor signed
+        let mut tampered = proof.clone();
+        tampered.addresses[1].balance = 400;
+        let report = verify_proof_of_funds(&tampered.to_json().unwrap(), &bc).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.addresses[1], AddressCheck { address: bob.address(), claimed: 400, actual: 4, signed: false });
+        assert!(!report.addresses[0].signed); // the document changed for every address
+
+        // Bob's entry signed by carol, with bob's public key or with her own
+        let carol = WalletFixture::new(3).wallet;
+        let carol_key = SigningKey::from_bytes(carol.secret_key.as_slice().try_into().unwrap());
+        let carol_signature = hex::encode(carol_key.sign(&proof.document_hash().unwrap()).to_bytes());
+        let mut impostor = proof.clone();
+        impostor.addresses[1].signature = carol_signature.clone();
+        let report = verify_proof_of_funds(&impostor.to_json().unwrap(), &bc).unwrap();
+        assert_eq!(report.addresses.iter().map(|check| check.signed).collect::<Vec<_>>(), vec![true, false]);
+        assert!(!report.is_valid());
+        impostor.addresses[1].public_key = hex::encode(&carol.public_key);
+        assert!(!verify_proof_of_funds(&impostor.to_json().unwrap(), &bc).unwrap().addresses[1].signed);
+
+        // A block this node doesn't have on its chain, or no proof at all
+        let other = ChainBuilder::new(&WalletFixture::new(4)).empty_blocks(1).build();
+        assert!(verify_proof_of_funds(&proof.to_json().unwrap(), &other).is_err());
+        assert!(verify_proof_of_funds("{}", &bc).is_err());
+        assert!(ProofOfFunds::create(&bc, &[], "").is_err());
+    }
+}
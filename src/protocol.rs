@@ -0,0 +1,701 @@
+use bincode::Options;
+use failure::format_err;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, DatalessBlock};
+use crate::checkpoint::Checkpoint;
+use crate::errors::{ProtocolError, Result};
+use crate::receipt::TxAck;
+use crate::snapshot::{DatalessSnapshotEntry, SnapshotEntry, SnapshotManifest};
+use crate::state_digest::StateDigest;
+use crate::transaction::{LegacyTransaction, Transaction};
+
+// A message is a CMD_LEN byte command followed by the bincode encoded payload, one message per
+// connection: the sender writes it and shuts the connection down
+
+pub const CMD_LEN: usize = 12;
+// 2: transactions carry data. Blocks, transactions and snapshots from version 1 peers are
+// still read, version 1 peers can't read them from this one
+pub const VERSION: i32 = 2;
+// Asks a node for its health report, answered on the same connection and only to local peers
+pub const HEALTH_CMD: &str = "gethealth";
+
+// Sanity limits for data received from peers
+pub const MAX_MESSAGE_SIZE: u64 = 32 * 1024 * 1024;
+pub const MAX_HEIGHT: i32 = 100_000_000;
+pub const MAX_INV_ITEMS: usize = 50_000;
+pub const MAX_ADDR_ITEMS: usize = 1_000;
+pub const MAX_TX_OUTPUTS: i32 = 10_000;
+pub const MAX_SNAPSHOT_CHUNKS: u32 = 1_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Blockmsg {
+    pub addr_from: String,
+    pub block: Block,
+}
+
+// Block message of nodes from before transaction data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatalessBlockmsg {
+    pub addr_from: String,
+    pub block: DatalessBlock,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetBlockmsg{
+    pub addr_from: String,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetDatamsg{
+    pub addr_from: String,
+    pub kind: String,
+    pub id: String,
+}
+
+// Answer to getdata for something the node doesn't have or won't serve
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotFoundmsg {
+    pub addr_from: String,
+    pub kind: String,
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Invmsg {
+    pub addr_from: String,
+    pub kind: String,
+    pub items: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Txmsg {
+    pub addr_from: String,
+    pub transaction: Transaction,
+}
+
+// Transaction message of nodes from before transaction data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatalessTxmsg {
+    pub addr_from: String,
+    pub transaction: LegacyTransaction,
+}
+
+// Signed by a peer that admitted a transaction we sent with "acktx"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxAckmsg {
+    pub addr_from: String,
+    pub ack: TxAck,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Addrmsg {
+    pub addr_from: String,
+    pub addr_list: Vec<String>, // the sender's known nodes
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Versionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    pub best_height: i32,
+    pub capabilities: Capabilities,
+    pub timestamp: u64, // sender's clock in ms since the epoch, 0 when unknown
+    pub checkpoint: Option<(i32, String)>, // sender's latest checkpoint, (height, block hash)
+    pub genesis: String, // hash of the sender's genesis block, empty when unknown
+    pub best_work: u128, // work of the sender's chain, see Blockchain::chain_work. 0 when unknown
+}
+
+// Version message of nodes from before chain work was advertised
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorklessVersionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    pub best_height: i32,
+    pub capabilities: Capabilities,
+    pub timestamp: u64,
+    pub checkpoint: Option<(i32, String)>,
+    pub genesis: String,
+}
+
+// Version message of nodes from before the genesis block was advertised
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenesislessVersionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    pub best_height: i32,
+    pub capabilities: Capabilities,
+    pub timestamp: u64,
+    pub checkpoint: Option<(i32, String)>,
+}
+
+// Version message of nodes from before checkpoints were advertised
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UncheckpointedVersionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    pub best_height: i32,
+    pub capabilities: Capabilities,
+    pub timestamp: u64,
+}
+
+// Version message of nodes from before timestamps were added
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UntimedVersionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    pub best_height: i32,
+    pub capabilities: Capabilities,
+}
+
+// Version message of nodes from before capabilities were added
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LegacyVersionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    pub best_height: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpCheckpointmsg {
+    pub addr_from: String,
+    pub checkpoint: Checkpoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetSnapshotmsg {
+    pub addr_from: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshotmsg {
+    pub addr_from: String,
+    pub manifest: SnapshotManifest,
+    pub recent_blocks: Vec<Block>,
+    pub state_digest: Option<StateDigest>, // of the UTXO state at the base block, None when unknown
+}
+
+// Snapshot message of nodes from before transaction data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatalessSnapshotmsg {
+    pub addr_from: String,
+    pub manifest: SnapshotManifest,
+    pub recent_blocks: Vec<DatalessBlock>,
+    pub state_digest: Option<StateDigest>,
+}
+
+// Snapshot message of nodes from before state digests
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DigestlessSnapshotmsg {
+    pub addr_from: String,
+    pub manifest: SnapshotManifest,
+    pub recent_blocks: Vec<DatalessBlock>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapChunkmsg {
+    pub addr_from: String,
+    pub root: String,
+    pub index: u32,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+// Snapshot chunk message of nodes from before transaction data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatalessSnapChunkmsg {
+    pub addr_from: String,
+    pub root: String,
+    pub index: u32,
+    pub entries: Vec<DatalessSnapshotEntry>,
+}
+
+/// A decoded peer message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Addr(Addrmsg),
+    Version(Versionmsg),
+    Tx(Txmsg),
+    AckTx(Txmsg), // a transaction whose sender wants a TxAck back when it's admitted
+    TxAck(TxAckmsg),
+    GetData(GetDatamsg),
+    NotFound(NotFoundmsg),
+    GetBlock(GetBlockmsg),
+    Inv(Invmsg),
+    Block(Blockmsg),
+    OpCheckpoint(OpCheckpointmsg),
+    GetSnapshot(GetSnapshotmsg),
+    Snapshot(Snapshotmsg),
+    SnapChunk(SnapChunkmsg),
+}
+
+impl Message {
+    /// The command the message was sent with
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Addr(_) => "addr",
+            Message::Version(_) => "version",
+            Message::Tx(_) => "tx",
+            Message::AckTx(_) => "acktx",
+            Message::TxAck(_) => "txack",
+            Message::GetData(_) => "getdata",
+            Message::NotFound(_) => "notfound",
+            Message::GetBlock(_) => "getblocks",
+            Message::Inv(_) => "inv",
+            Message::Block(_) => "block",
+            Message::OpCheckpoint(_) => "opcheckpoint",
+            Message::GetSnapshot(_) => "getsnapshot",
+            Message::Snapshot(_) => "snapshot",
+            Message::SnapChunk(_) => "snapchunk",
+        }
+    }
+
+    /// Listening address of the peer that sent the message, if it says
+    pub fn sender(&self) -> Option<&str> {
+        let addr_from = match self {
+            Message::Addr(m) => &m.addr_from,
+            Message::Version(m) => &m.addr_from,
+            Message::Tx(m) | Message::AckTx(m) => &m.addr_from,
+            Message::TxAck(m) => &m.addr_from,
+            Message::GetData(m) => &m.addr_from,
+            Message::NotFound(m) => &m.addr_from,
+            Message::GetBlock(m) => &m.addr_from,
+            Message::Inv(m) => &m.addr_from,
+            Message::Block(m) => &m.addr_from,
+            Message::OpCheckpoint(m) => &m.addr_from,
+            Message::GetSnapshot(m) => &m.addr_from,
+            Message::Snapshot(m) => &m.addr_from,
+            Message::SnapChunk(m) => &m.addr_from,
+        };
+        Some(addr_from)
+    }
+}
+
+/// Optional protocol features a node supports, advertised in the version handshake
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const COMPACT_BLOCKS: Capabilities = Capabilities(1);
+    pub const BLOCK_FILTERS: Capabilities = Capabilities(1 << 1);
+    pub const ENCRYPTION: Capabilities = Capabilities(1 << 2);
+    pub const HEADERS_FIRST: Capabilities = Capabilities(1 << 3);
+    pub const TX_RECEIPTS: Capabilities = Capabilities(1 << 4);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// The frame for `msg`, as sent to peers
+pub fn encode<T: Serialize>(cmd: &str, msg: &T) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&(cmd_to_bytes(cmd), msg))?)
+}
+
+/// Decodes a message read from a peer and checks its ranges
+pub fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
+    if bytes.len() < CMD_LEN {
+        return Err(ProtocolError::Truncated.into());
+    }
+
+    let mut cmd = Vec::new();
+
+    // A slice of the first CMD_LEN bytes from bytes
+    let cmd_bytes = &bytes[..CMD_LEN];
+
+    //  A slice of the remaining bytes after the command
+    let data = &bytes[CMD_LEN..];
+    for b in cmd_bytes {
+        if 0 != *b {
+            cmd.push(*b);
+        }
+    }
+
+    let msg = if cmd == "addr".as_bytes() {
+        Message::Addr(decode(data)?)
+    } else if cmd == "block".as_bytes() {
+        Message::Block(decode_block(data)?)
+    } else if cmd == "inv".as_bytes() {
+        Message::Inv(decode(data)?)
+    } else if cmd == "getblocks".as_bytes() {
+        Message::GetBlock(decode(data)?)
+    } else if cmd == "getdata".as_bytes() {
+        Message::GetData(decode(data)?)
+    } else if cmd == "notfound".as_bytes() {
+        Message::NotFound(decode(data)?)
+    } else if cmd == "tx".as_bytes() {
+        Message::Tx(decode_tx(data)?)
+    } else if cmd == "acktx".as_bytes() {
+        Message::AckTx(decode_tx(data)?)
+    } else if cmd == "txack".as_bytes() {
+        Message::TxAck(decode(data)?)
+    } else if cmd == "version".as_bytes() {
+        Message::Version(decode_version(data)?)
+    } else if cmd == "opcheckpoint".as_bytes() {
+        Message::OpCheckpoint(decode(data)?)
+    } else if cmd == "getsnapshot".as_bytes() {
+        Message::GetSnapshot(decode(data)?)
+    } else if cmd == "snapshot".as_bytes() {
+        Message::Snapshot(decode_snapshot(data)?)
+    } else if cmd == "snapchunk".as_bytes() {
+        Message::SnapChunk(decode_snap_chunk(data)?)
+    } else {
+        return Err(format_err!("Unknown command {:?}", String::from_utf8_lossy(&cmd)));
+    };
+
+    validate_message(&msg)?;
+    Ok(msg)
+}
+
+// Same encoding as bincode::deserialize, but refuses to allocate past MAX_MESSAGE_SIZE
+fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    Ok(bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_SIZE)
+        .deserialize(data)?)
+}
+
+// Older peers send transactions without data, in blocks, tx messages and snapshots
+fn decode_block(data: &[u8]) -> Result<Blockmsg> {
+    if let Ok(msg) = decode::<Blockmsg>(data) {
+        return Ok(msg);
+    }
+    let old: DatalessBlockmsg = decode(data)?;
+    Ok(Blockmsg { addr_from: old.addr_from, block: old.block.into() })
+}
+
+fn decode_tx(data: &[u8]) -> Result<Txmsg> {
+    if let Ok(msg) = decode::<Txmsg>(data) {
+        return Ok(msg);
+    }
+    let old: DatalessTxmsg = decode(data)?;
+    Ok(Txmsg { addr_from: old.addr_from, transaction: old.transaction.into() })
+}
+
+fn decode_snap_chunk(data: &[u8]) -> Result<SnapChunkmsg> {
+    if let Ok(msg) = decode::<SnapChunkmsg>(data) {
+        return Ok(msg);
+    }
+    let old: DatalessSnapChunkmsg = decode(data)?;
+    Ok(SnapChunkmsg {
+        addr_from: old.addr_from,
+        root: old.root,
+        index: old.index,
+        entries: old.entries.into_iter().map(SnapshotEntry::from).collect(),
+    })
+}
+
+// Older peers send snapshots without transaction data, older ones without a state digest
+fn decode_snapshot(data: &[u8]) -> Result<Snapshotmsg> {
+    if let Ok(msg) = decode::<Snapshotmsg>(data) {
+        return Ok(msg);
+    }
+    if let Ok(old) = decode::<DatalessSnapshotmsg>(data) {
+        return Ok(Snapshotmsg {
+            addr_from: old.addr_from,
+            manifest: old.manifest,
+            recent_blocks: old.recent_blocks.into_iter().map(Block::from).collect(),
+            state_digest: old.state_digest,
+        });
+    }
+    let old: DigestlessSnapshotmsg = decode(data)?;
+    Ok(Snapshotmsg {
+        addr_from: old.addr_from,
+        manifest: old.manifest,
+        recent_blocks: old.recent_blocks.into_iter().map(Block::from).collect(),
+        state_digest: None,
+    })
+}
+
+// Older peers send no chain work, older ones no genesis block, older ones no checkpoint,
+// older ones no timestamp, and the oldest no capabilities either. They're treated as having
+// unknown work, on our network, pinning nothing, having an unknown clock and supporting nothing
+fn decode_version(data: &[u8]) -> Result<Versionmsg> {
+    if let Ok(msg) = decode::<Versionmsg>(data) {
+        return Ok(msg);
+    }
+    if let Ok(old) = decode::<WorklessVersionmsg>(data) {
+        return Ok(Versionmsg {
+            addr_from: old.addr_from,
+            version: old.version,
+            best_height: old.best_height,
+            capabilities: old.capabilities,
+            timestamp: old.timestamp,
+            checkpoint: old.checkpoint,
+            genesis: old.genesis,
+            best_work: 0,
+        });
+    }
+    if let Ok(old) = decode::<GenesislessVersionmsg>(data) {
+        return Ok(Versionmsg {
+            addr_from: old.addr_from,
+            version: old.version,
+            best_height: old.best_height,
+            capabilities: old.capabilities,
+            timestamp: old.timestamp,
+            checkpoint: old.checkpoint,
+            genesis: String::new(),
+            best_work: 0,
+        });
+    }
+    if let Ok(old) = decode::<UncheckpointedVersionmsg>(data) {
+        return Ok(Versionmsg {
+            addr_from: old.addr_from,
+            version: old.version,
+            best_height: old.best_height,
+            capabilities: old.capabilities,
+            timestamp: old.timestamp,
+            checkpoint: None,
+            genesis: String::new(),
+            best_work: 0,
+        });
+    }
+    if let Ok(untimed) = decode::<UntimedVersionmsg>(data) {
+        return Ok(Versionmsg {
+            addr_from: untimed.addr_from,
+            version: untimed.version,
+            best_height: untimed.best_height,
+            capabilities: untimed.capabilities,
+            timestamp: 0,
+            checkpoint: None,
+            genesis: String::new(),
+            best_work: 0,
+        });
+    }
+    let legacy: LegacyVersionmsg = decode(data)?;
+    Ok(Versionmsg {
+        addr_from: legacy.addr_from,
+        version: legacy.version,
+        best_height: legacy.best_height,
+        capabilities: Capabilities::NONE,
+        timestamp: 0,
+        checkpoint: None,
+        genesis: String::new(),
+        best_work: 0,
+    })
+}
+
+// Range checks on fields that are trusted later on (heights, output indexes, list lengths)
+fn validate_message(msg: &Message) -> Result<()> {
+    let violation = |peer: &str, score: u32, reason: String| -> Result<()> {
+        Err(ProtocolError::Misbehavior {
+            peer: peer.to_string(),
+            reason,
+            score,
+        }.into())
+    };
+
+    match msg {
+        Message::Version(v) => {
+            // -1 means the peer has no blocks yet
+            if v.best_height < -1 || v.best_height > MAX_HEIGHT {
+                return violation(&v.addr_from, 20, format!("best_height {} out of range", v.best_height));
+            }
+            if let Some((height, _)) = v.checkpoint.as_ref().filter(|(height, _)| *height < 0 || *height > MAX_HEIGHT) {
+                return violation(&v.addr_from, 20, format!("checkpoint height {} out of range", height));
+            }
+        }
+        Message::Inv(inv) => {
+            if inv.items.len() > MAX_INV_ITEMS {
+                return violation(&inv.addr_from, 20, format!("inv with {} items", inv.items.len()));
+            }
+        }
+        Message::Addr(msg) => {
+            if msg.addr_list.len() > MAX_ADDR_ITEMS {
+                return violation(&msg.addr_from, 20, format!("addr message with {} entries", msg.addr_list.len()));
+            }
+        }
+        Message::Tx(msg) | Message::AckTx(msg) => {
+            if let Err(reason) = validate_tx_indices(&msg.transaction) {
+                return violation(&msg.addr_from, 50, reason);
+            }
+        }
+        Message::Block(msg) => {
+            if msg.block.get_height() < 0 || msg.block.get_height() > MAX_HEIGHT {
+                return violation(&msg.addr_from, 50, format!("block height {} out of range", msg.block.get_height()));
+            }
+            for tx in msg.block.get_transactions() {
+                if let Err(reason) = validate_tx_indices(tx) {
+                    return violation(&msg.addr_from, 50, reason);
+                }
+            }
+        }
+        Message::OpCheckpoint(msg) => {
+            if msg.checkpoint.height < 0 || msg.checkpoint.height > MAX_HEIGHT {
+                return violation(&msg.addr_from, 20, format!("checkpoint height {} out of range", msg.checkpoint.height));
+            }
+        }
+        Message::Snapshot(msg) => {
+            if msg.manifest.height < 0 || msg.manifest.height > MAX_HEIGHT {
+                return violation(&msg.addr_from, 20, format!("snapshot height {} out of range", msg.manifest.height));
+            }
+            if msg.manifest.chunk_hashes.len() > MAX_SNAPSHOT_CHUNKS as usize {
+                return violation(&msg.addr_from, 20, format!("snapshot with {} chunks", msg.manifest.chunk_hashes.len()));
+            }
+            for block in &msg.recent_blocks {
+                for tx in block.get_transactions() {
+                    if let Err(reason) = validate_tx_indices(tx) {
+                        return violation(&msg.addr_from, 50, reason);
+                    }
+                }
+            }
+        }
+        Message::SnapChunk(msg) => {
+            if msg.index >= MAX_SNAPSHOT_CHUNKS {
+                return violation(&msg.addr_from, 20, format!("snapshot chunk {} out of range", msg.index));
+            }
+            for entry in &msg.entries {
+                if entry.unspent.iter().any(|index| *index < 0 || *index as usize >= entry.tx.vout.len()) {
+                    return violation(&msg.addr_from, 50, format!("snapshot entry {} with a bad output index", entry.tx.id));
+                }
+            }
+        }
+        Message::GetData(_) | Message::NotFound(_) | Message::GetBlock(_) | Message::GetSnapshot(_) | Message::TxAck(_) => {}
+    }
+    Ok(())
+}
+
+/// Input indexes must be usable as array indexes, the coinbase's -1 being the only exception
+pub fn validate_tx_indices(tx: &Transaction) -> std::result::Result<(), String> {
+    if tx.is_coinbase() {
+        return Ok(());
+    }
+    for vin in &tx.vin {
+        if vin.vout < 0 || vin.vout >= MAX_TX_OUTPUTS {
+            return Err(format!("tx {} spends output index {}", tx.id, vin.vout));
+        }
+    }
+    Ok(())
+}
+
+/// The command name, zero padded to CMD_LEN
+pub fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
+    let mut data = [0; CMD_LEN];
+    for (i, d) in cmd.as_bytes().iter().enumerate() {
+        data[i] = *d;
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{dataless_block, legacy_tx, ChainBuilder, WalletFixture};
+    use crate::tx::{TXInput, TXOutput};
+
+    fn misbehavior_score(result: Result<Message>) -> u32 {
+        match result.err().unwrap().downcast::<ProtocolError>() {
+            Ok(ProtocolError::Misbehavior { score, .. }) => score,
+            other => panic!("expected misbehavior, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_fields() {
+        // negative output index in a relayed transaction
+        let tx = Transaction {
+            id: String::from("abc"),
+            vin: vec![TXInput { txid: String::from("def"), vout: -2, signature: Vec::new(), pub_key: Vec::new() }],
+            vout: vec![TXOutput { value: 1, pub_key_hash: vec![0; 20] }],
+            data: Vec::new(),
+        };
+        let msg = Txmsg { addr_from: String::from("127.0.0.1:1"), transaction: tx };
+        let bytes = encode("tx", &msg).unwrap();
+        assert!(misbehavior_score(bytes_to_cmd(&bytes)) > 0);
+
+        // a million inventory items
+        let msg = Invmsg {
+            addr_from: String::from("127.0.0.1:1"),
+            kind: String::from("block"),
+            items: vec![String::from("a"); 1_000_000],
+        };
+        let bytes = encode("inv", &msg).unwrap();
+        assert!(misbehavior_score(bytes_to_cmd(&bytes)) > 0);
+
+        // a length prefix claiming far more items than the message holds
+        let mut bytes = cmd_to_bytes("addr").to_vec();
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(bytes_to_cmd(&bytes).is_err());
+
+        let msg = Versionmsg { addr_from: String::from("127.0.0.1:1"), version: VERSION, best_height: -5, capabilities: Capabilities::NONE, timestamp: 0, checkpoint: None, genesis: String::new(), best_work: 0 };
+        let bytes = encode("version", &msg).unwrap();
+        assert!(misbehavior_score(bytes_to_cmd(&bytes)) > 0);
+
+        // shorter than the command header
+        assert!(bytes_to_cmd(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_version_without_work_decodes_as_unknown_work() {
+        let old = WorklessVersionmsg {
+            addr_from: String::from("127.0.0.1:1"),
+            version: VERSION,
+            best_height: 7,
+            capabilities: Capabilities::NONE,
+            timestamp: 0,
+            checkpoint: None,
+            genesis: String::from("abc"),
+        };
+        let Ok(Message::Version(msg)) = bytes_to_cmd(&encode("version", &old).unwrap()) else {
+            panic!("expected a version message");
+        };
+        assert_eq!((msg.best_height, msg.genesis.as_str(), msg.best_work), (7, "abc", 0));
+    }
+
+    #[test]
+    fn test_snapshot_without_digest_decodes() {
+        let manifest = SnapshotManifest {
+            height: 3,
+            block_hash: String::from("abc"),
+            chunk_hashes: Vec::new(),
+            root: String::from("def"),
+            signature: Vec::new(),
+        };
+        let old = DigestlessSnapshotmsg { addr_from: String::from("127.0.0.1:1"), manifest: manifest.clone(), recent_blocks: Vec::new() };
+        let Ok(Message::Snapshot(msg)) = bytes_to_cmd(&encode("snapshot", &old).unwrap()) else {
+            panic!("expected a snapshot message");
+        };
+        assert_eq!((msg.manifest, msg.state_digest), (manifest, None));
+    }
+
+    #[test]
+    fn test_messages_from_before_transaction_data_decode() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(1);
+        let tip = chain.tip();
+        let reward = tip.get_transactions()[0].clone();
+        let addr_from = String::from("127.0.0.1:1");
+
+        let old = DatalessBlockmsg { addr_from: addr_from.clone(), block: dataless_block(&tip) };
+        let Ok(Message::Block(msg)) = bytes_to_cmd(&encode("block", &old).unwrap()) else {
+            panic!("expected a block message");
+        };
+        assert_eq!(bincode::serialize(&msg.block).unwrap(), bincode::serialize(&tip).unwrap());
+        assert!(msg.block.verify_merkle_root().unwrap());
+
+        let old = DatalessTxmsg { addr_from: addr_from.clone(), transaction: legacy_tx(&reward) };
+        let Ok(Message::AckTx(msg)) = bytes_to_cmd(&encode("acktx", &old).unwrap()) else {
+            panic!("expected an acktx message");
+        };
+        assert_eq!((msg.transaction.hash().unwrap(), msg.transaction.data.len()), (reward.hash().unwrap(), 0));
+
+        let old = DatalessSnapChunkmsg {
+            addr_from,
+            root: String::from("def"),
+            index: 0,
+            entries: vec![DatalessSnapshotEntry { tx: legacy_tx(&reward), unspent: vec![0] }],
+        };
+        let Ok(Message::SnapChunk(msg)) = bytes_to_cmd(&encode("snapchunk", &old).unwrap()) else {
+            panic!("expected a snapchunk message");
+        };
+        assert_eq!(msg.entries[0].tx.id, reward.id);
+        assert_eq!(msg.entries[0].unspent, vec![0]);
+    }
+}
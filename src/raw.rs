@@ -0,0 +1,237 @@
+use std::io::{self, Read};
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::block::{Block, DatalessBlock};
+use crate::errors::{DecodeError, Result};
+use crate::protocol::{validate_tx_indices, MAX_HEIGHT, MAX_MESSAGE_SIZE};
+use crate::transaction::{LegacyTransaction, Transaction};
+
+/*
+    Raw encoding
+
+    Blocks and transactions in the bytes the node stores and sends (bincode with fixed-size
+    integers), as hex for copying into other tools. Decoding goes through the same size
+    limit and range checks as messages from peers, and a failure reports the offset of the
+    byte where the field that couldn't be read starts. Hex of the layouts from before
+    transactions carried data still decodes.
+
+    Raw transactions (Transaction::to_hex) lead with a version byte, RAW_TX_VERSION, so the
+    layout after it can change without old hex being misread.
+*/
+
+pub const RAW_TX_VERSION: u8 = 1;
+
+/// What a piece of hex turned out to be
+#[derive(Debug, Clone)]
+pub enum Decoded {
+    Block(Block),
+    Transaction(Transaction),
+}
+
+pub fn block_hex(block: &Block) -> Result<String> {
+    encode(block)
+}
+
+pub fn tx_hex(tx: &Transaction) -> Result<String> {
+    encode(tx)
+}
+
+/// RAW_TX_VERSION, then the bytes of `tx`
+pub fn raw_tx_hex(tx: &Transaction) -> Result<String> {
+    Ok(format!("{:02x}{}", RAW_TX_VERSION, tx_hex(tx)?))
+}
+
+/// Reads the hex of `raw_tx_hex`. Offsets in errors count the version byte
+pub fn decode_raw_tx(hex: &str) -> std::result::Result<Transaction, DecodeError> {
+    let bytes = hex::decode(hex.trim()).map_err(|e| DecodeError::BadHex(e.to_string()))?;
+    if bytes.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(DecodeError::TooLarge { len: bytes.len(), max: MAX_MESSAGE_SIZE });
+    }
+    let Some((&version, body)) = bytes.split_first() else {
+        return Err(DecodeError::Malformed { kind: "raw transaction", offset: 0, reason: String::from("no version byte") });
+    };
+    if version != RAW_TX_VERSION {
+        return Err(DecodeError::UnknownVersion { found: version, supported: RAW_TX_VERSION });
+    }
+    let tx = decode_as::<Transaction>(body, "raw transaction").map_err(|e| match e {
+        DecodeError::Malformed { kind, offset, reason } => DecodeError::Malformed { kind, offset: offset + 1, reason },
+        other => other,
+    })?;
+    validate_tx_indices(&tx).map_err(|reason| DecodeError::OutOfRange { kind: "transaction", reason })?;
+    Ok(tx)
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<String> {
+    Ok(hex::encode(bincode::serialize(value)?))
+}
+
+/// Decodes the hex of a block or a transaction, whichever it is
+pub fn decode_hex(hex: &str) -> std::result::Result<Decoded, DecodeError> {
+    let bytes = hex::decode(hex.trim()).map_err(|e| DecodeError::BadHex(e.to_string()))?;
+    if bytes.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(DecodeError::TooLarge { len: bytes.len(), max: MAX_MESSAGE_SIZE });
+    }
+
+    let block = decode_as::<Block>(&bytes, "block")
+        .or_else(|e| decode_as::<DatalessBlock>(&bytes, "block").map(Block::from).map_err(|_| e));
+    let not_a_block = match block {
+        Ok(block) => {
+            if block.get_height() < 0 || block.get_height() > MAX_HEIGHT {
+                return Err(DecodeError::OutOfRange { kind: "block", reason: format!("height {} out of range", block.get_height()) });
+            }
+            for tx in block.get_transactions() {
+                validate_tx_indices(tx).map_err(|reason| DecodeError::OutOfRange { kind: "block", reason })?;
+            }
+            return Ok(Decoded::Block(block));
+        }
+        Err(e) => e,
+    };
+    let tx = decode_as::<Transaction>(&bytes, "transaction")
+        .or_else(|e| decode_as::<LegacyTransaction>(&bytes, "transaction").map(Transaction::from).map_err(|_| e));
+    match tx {
+        Ok(tx) => {
+            validate_tx_indices(&tx).map_err(|reason| DecodeError::OutOfRange { kind: "transaction", reason })?;
+            Ok(Decoded::Transaction(tx))
+        }
+        // The reading that got further is most likely what was meant
+        Err(not_a_tx) => Err(if not_a_tx.offset() > not_a_block.offset() { not_a_tx } else { not_a_block }),
+    }
+}
+
+// The whole of `bytes` as a T, the same encoding and limit as messages from peers
+fn decode_as<T: DeserializeOwned>(bytes: &[u8], kind: &'static str) -> std::result::Result<T, DecodeError> {
+    let mut reader = OffsetReader { data: bytes, offset: 0 };
+    let decoded = bincode::options()
+        .with_fixint_encoding()
+        .with_limit(MAX_MESSAGE_SIZE)
+        .deserialize_from(&mut reader);
+    match decoded {
+        Ok(_) if reader.offset < bytes.len() => Err(DecodeError::Malformed {
+            kind,
+            offset: reader.offset,
+            reason: format!("{} bytes left over", bytes.len() - reader.offset),
+        }),
+        Ok(value) => Ok(value),
+        Err(e) => Err(DecodeError::Malformed { kind, offset: reader.offset, reason: e.to_string() }),
+    }
+}
+
+// Counts what was read. A read_exact that can't be filled takes nothing, so after a
+// failure `offset` is where the field that failed starts
+struct OffsetReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Read for OffsetReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.data.len() - self.offset);
+        buf[..n].copy_from_slice(&self.data[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let left = self.data.len() - self.offset;
+        if buf.len() > left {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("needs {} bytes, {} left", buf.len(), left)));
+        }
+        self.read(buf).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::INITIAL_TARGET;
+    use crate::testing::{coinbase, legacy_tx, TxBuilder, WalletFixture};
+
+    #[test]
+    fn test_raw_hex_round_trips() {
+        let alice = WalletFixture::new(1);
+        let reward = coinbase(&alice.address(), 0);
+        let tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&WalletFixture::new(2).address(), 10).build();
+        let block = Block::new_block(vec![coinbase(&alice.address(), 1), tx.clone()], String::from("ab"), 1, INITIAL_TARGET).unwrap();
+
+        match decode_hex(&block_hex(&block).unwrap()).unwrap() {
+            Decoded::Block(decoded) => {
+                assert_eq!(decoded.get_hash(), block.get_hash());
+                assert!(decoded.verify_proof_of_work().unwrap());
+                assert_eq!(block_hex(&decoded).unwrap(), block_hex(&block).unwrap());
+            }
+            other => panic!("decoded a block as {:?}", other),
+        }
+        match decode_hex(&format!(" {}\n", tx_hex(&tx).unwrap().to_uppercase())).unwrap() {
+            Decoded::Transaction(decoded) => assert_eq!(tx_hex(&decoded).unwrap(), tx_hex(&tx).unwrap()),
+            other => panic!("decoded a transaction as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_errors_point_at_the_failing_byte() {
+        let alice = WalletFixture::new(1);
+        let tx = coinbase(&alice.address(), 0);
+        let bytes = bincode::serialize(&tx).unwrap();
+
+        // Cut inside the length of the last output's pub key hash, that's where reading stops
+        let hash_len = tx.vout.last().unwrap().pub_key_hash.len();
+        let outputs_end = bytes.len() - bincode::serialized_size(&tx.data).unwrap() as usize;
+        let truncated = &bytes[..outputs_end - hash_len - 6];
+        match decode_hex(&hex::encode(truncated)).unwrap_err() {
+            DecodeError::Malformed { kind, offset, .. } => assert_eq!((kind, offset), ("transaction", outputs_end - hash_len - 8)),
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        match decode_hex(&hex::encode(&extra)).unwrap_err() {
+            DecodeError::Malformed { kind, offset, .. } => assert_eq!((kind, offset), ("transaction", bytes.len())),
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        // Hex from before transactions carried data
+        let legacy = bincode::serialize(&legacy_tx(&tx)).unwrap();
+        match decode_hex(&hex::encode(legacy)).unwrap() {
+            Decoded::Transaction(decoded) => assert_eq!(tx_hex(&decoded).unwrap(), tx_hex(&tx).unwrap()),
+            other => panic!("decoded a transaction as {:?}", other),
+        }
+
+        assert!(matches!(decode_hex("abc"), Err(DecodeError::BadHex(_))));
+        assert!(matches!(decode_hex("zz"), Err(DecodeError::BadHex(_))));
+
+        // Same range checks as relayed transactions
+        let mut bad_index = TxBuilder::new(&alice).spend(&tx, 0).pay(&alice.address(), 10).build();
+        bad_index.vin[0].vout = -3;
+        assert!(matches!(decode_hex(&tx_hex(&bad_index).unwrap()), Err(DecodeError::OutOfRange { kind: "transaction", .. })));
+    }
+
+    #[test]
+    fn test_raw_transactions_carry_a_version() {
+        let alice = WalletFixture::new(1);
+        let reward = coinbase(&alice.address(), 0);
+        let tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&WalletFixture::new(2).address(), 10).build();
+
+        let raw = tx.to_hex().unwrap();
+        assert_eq!(&raw[..2], "01");
+        assert_eq!(tx_hex(&Transaction::from_hex(&raw).unwrap()).unwrap(), tx_hex(&tx).unwrap());
+
+        // Offsets count the version byte
+        let body_len = raw.len() / 2 - 1;
+        match Transaction::from_hex(&raw[..raw.len() - 2]).unwrap_err() {
+            DecodeError::Malformed { kind, offset, .. } => {
+                assert_eq!(kind, "raw transaction");
+                assert!(offset > 0 && offset <= body_len);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(matches!(Transaction::from_hex(""), Err(DecodeError::Malformed { offset: 0, .. })));
+        assert!(matches!(Transaction::from_hex(&raw[1..]), Err(DecodeError::BadHex(_))));
+        assert_eq!(
+            Transaction::from_hex(&format!("02{}", &raw[2..])).unwrap_err(),
+            DecodeError::UnknownVersion { found: 2, supported: RAW_TX_VERSION }
+        );
+    }
+}
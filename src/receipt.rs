@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Result;
+use crate::identity::{self, NodeIdentity};
+
+/*
+    Broadcast receipts
+
+    Evidence that we broadcast a transaction at some time, for when it gets stuck. Peers
+    advertising Capabilities::TX_RECEIPTS that admit one of our transactions to their
+    mempool answer with an acknowledgment: the txid, their node key and their clock, signed
+    with that key. A transaction they refuse gets no answer.
+
+    The acks are kept with the pending send and can be exported as JSON. Anyone can check
+    the signatures, what they prove is that the owners of those keys had the transaction
+    at the times they signed.
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxAck {
+    pub txid: String,
+    pub public_key: String, // hex, the peer's node key
+    pub timestamp: u128,    // ms since the epoch, the peer's clock
+    pub signature: String,  // hex
+}
+
+impl TxAck {
+    pub fn sign(identity: &NodeIdentity, txid: &str, timestamp: u128) -> Result<TxAck> {
+        let public_key = identity.public_key();
+        let signature = identity.sign(&TxAck::signed_data(txid, &public_key, timestamp)?);
+        Ok(TxAck { txid: txid.to_string(), public_key, timestamp, signature })
+    }
+
+    /// Whether the ack was signed by the node key it names
+    pub fn verify(&self) -> bool {
+        match TxAck::signed_data(&self.txid, &self.public_key, self.timestamp) {
+            Ok(data) => identity::verify_signature(&self.public_key, &data, &self.signature),
+            Err(_) => false,
+        }
+    }
+
+    // Prefixed so the signature can't be reused for anything else
+    fn signed_data(txid: &str, public_key: &str, timestamp: u128) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&("txack", txid, public_key, timestamp))?)
+    }
+}
+
+/// The acks collected for one of our transactions, as exported
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BroadcastReceipt {
+    pub txid: String,
+    pub sent_at: u128, // ms since the epoch
+    pub acks: Vec<TxAck>,
+}
+
+impl BroadcastReceipt {
+    /// When the last peer acknowledged, None without acks
+    pub fn acknowledged_at(&self) -> Option<u128> {
+        self.acks.iter().map(|ack| ack.timestamp).max()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ack_signatures() {
+        let peer = NodeIdentity::generate();
+        let ack = TxAck::sign(&peer, "ab01", 1_000).unwrap();
+        assert!(ack.verify());
+        assert_eq!(ack.public_key, peer.public_key());
+
+        // Every signed field counts
+        for tamper in [
+            |ack: &mut TxAck| ack.txid = String::from("ab02"),
+            |ack: &mut TxAck| ack.timestamp += 1,
+            |ack: &mut TxAck| ack.public_key = NodeIdentity::generate().public_key(),
+            |ack: &mut TxAck| ack.signature = String::from("not hex"),
+        ] {
+            let mut tampered = ack.clone();
+            tamper(&mut tampered);
+            assert!(!tampered.verify());
+        }
+
+        // Survives the export
+        let receipt = BroadcastReceipt { txid: String::from("ab01"), sent_at: 900, acks: vec![ack.clone(), TxAck::sign(&peer, "ab01", 1_500).unwrap()] };
+        let exported: BroadcastReceipt = serde_json::from_str(&receipt.to_json().unwrap()).unwrap();
+        assert_eq!(exported, receipt);
+        assert!(exported.acks.iter().all(TxAck::verify));
+        assert_eq!(exported.acknowledged_at(), Some(1_500));
+    }
+}
@@ -1,8 +1,96 @@
+use std::future::Future;
+use std::time::Duration;
+
 use once_cell::sync::Lazy;
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
 // Define a globally accessible runtime
 pub static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
     println!("RUNTIME initialized");
     Runtime::new().expect("Failed to create Tokio runtime")
 });
+
+/// Background work spawned on RUNTIME that has to stop before the databases are closed.
+/// Tasks are dropped at their next await point once `shutdown` is called, so they never
+/// stop halfway through the synchronous part of a sled write.
+#[derive(Clone, Default)]
+pub struct BackgroundTasks {
+    tracker: TaskTracker,
+    cancel: CancellationToken,
+}
+
+impl BackgroundTasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns `task` on RUNTIME. Returns false, without spawning, once shutdown began.
+    pub fn spawn<F>(&self, task: F) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.tracker.is_closed() {
+            return false;
+        }
+        let cancel = self.cancel.clone();
+        self.tracker.spawn_on(
+            async move {
+                tokio::select! {
+                    _ = cancel.cancelled() => {}
+                    _ = task => {}
+                }
+            },
+            RUNTIME.handle(),
+        );
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracker.is_empty()
+    }
+
+    /// Rejects new tasks, cancels the running ones and waits up to `timeout` for them.
+    /// False when some were still running at the timeout.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.tracker.close();
+        self.cancel.cancel();
+        tokio::time::timeout(timeout, self.tracker.wait()).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_shutdown_cancels_long_tasks() {
+        let tasks = BackgroundTasks::new();
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let flag = Arc::clone(&finished);
+        assert!(tasks.spawn(async move {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            flag.store(true, Ordering::SeqCst);
+        }));
+        assert_eq!(tasks.len(), 1);
+
+        let started = Instant::now();
+        assert!(tasks.shutdown(Duration::from_secs(3)).await);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(tasks.is_empty());
+        assert!(!finished.load(Ordering::SeqCst)); // cancelled, not completed
+
+        // Nothing new starts once shutdown began
+        assert!(!tasks.spawn(async {}));
+        assert!(tasks.is_empty());
+    }
+}
@@ -0,0 +1,296 @@
+use bincode::Options;
+use failure::format_err;
+use log::{error, info};
+
+use crate::block::{Block, DatalessBlock, RootlessBlock};
+use crate::blockchain::{HEADERS_TREE, SNAPSHOT_TREE};
+use crate::errors::{Result, SchemaError};
+use crate::snapshot::{DatalessSnapshotEntry, SnapshotEntry};
+
+/*
+    Block database schema
+
+    The block database records the version of its layout under SCHEMA_VERSION_KEY, written
+    when it's created. Opening it runs the migrations from that version up to
+    SCHEMA_VERSION, one version at a time, each finishing by writing the version it reached
+    so an interrupted upgrade picks up where it stopped. A database of a newer version than
+    this build knows is refused untouched. Blocks that can't be read are skipped, opening the
+    chain reports them (see Blockchain::check_integrity).
+
+        1   blocks without a merkle root (RootlessBlock)
+        2   blocks with a merkle root
+        3   the header of every block in the headers tree as well
+        4   transactions that may carry data, in blocks and snapshot entries
+
+    Databases from before the version key are version 1 or 2, told apart by which layout
+    their blocks decode as. Older layouts aren't read, those databases have to be recreated.
+
+    Migrated version 1 blocks keep the hash they were mined with. It covered the transaction
+    list instead of the merkle root, so they no longer pass a proof of work check (verify_chain
+    reports them) and peers running this build won't take them.
+*/
+
+pub const SCHEMA_VERSION: u32 = 4;
+pub const SCHEMA_VERSION_KEY: &str = "SCHEMA_VERSION";
+
+/// Version of the database layout, None for a database from before versions were recorded
+pub fn schema_version(db: &sled::Db) -> Result<Option<u32>> {
+    match db.get(SCHEMA_VERSION_KEY)? {
+        Some(data) => {
+            let bytes: [u8; 4] = data.as_ref().try_into().map_err(|_| format_err!("Corrupted schema version"))?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Goes in the batch that creates a database
+pub fn mark_current(batch: &mut sled::Batch) {
+    batch.insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_be_bytes());
+}
+
+/// Brings the database up to SCHEMA_VERSION. An empty database is left for the caller to
+/// create. Returns the version it was at
+pub fn upgrade(db: &sled::Db) -> Result<u32> {
+    let version = match schema_version(db)? {
+        Some(version) => version,
+        None => match stored_layout(db)? {
+            Some(version) => version,
+            None => return Ok(SCHEMA_VERSION),
+        },
+    };
+    if version > SCHEMA_VERSION {
+        return Err(SchemaError::TooNew { found: version, supported: SCHEMA_VERSION }.into());
+    }
+    if version < SCHEMA_VERSION {
+        migrate(db, version, SCHEMA_VERSION)?;
+    }
+    Ok(version)
+}
+
+/// Runs the migrations taking the database from version `from` to `to`
+pub fn migrate(db: &sled::Db, from: u32, to: u32) -> Result<()> {
+    for version in from..to {
+        info!("Migrating the block database from schema version {} to {}", version, version + 1);
+        match version {
+            1 => add_merkle_roots(db)?,
+            2 => fill_headers(db)?,
+            3 => add_transaction_data(db)?,
+            _ => return Err(format_err!("No migration from schema version {}", version)),
+        }
+        db.flush()?;
+    }
+    Ok(())
+}
+
+// Version of an unversioned database from how its blocks decode, None when it has none
+fn stored_layout(db: &sled::Db) -> Result<Option<u32>> {
+    let Some((hash, data)) = block_entries(db).next().transpose()? else {
+        return Ok(None);
+    };
+    if bincode::deserialize::<DatalessBlock>(&data).is_ok_and(|block| block.hash.as_bytes() == hash.as_ref()) {
+        return Ok(Some(2));
+    }
+    if bincode::deserialize::<RootlessBlock>(&data).is_ok_and(|block| block.hash.as_bytes() == hash.as_ref()) {
+        return Ok(Some(1));
+    }
+    Err(SchemaError::UnknownLayout.into())
+}
+
+// Block keys are their hex hashes, the other keys are named
+fn block_entries(db: &sled::Db) -> impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>> {
+    db.iter().filter(|kv| kv.as_ref().map_or(true, |(key, _)| key.len() == 64 && key.iter().all(u8::is_ascii_hexdigit)))
+}
+
+// 1 -> 2: re-encodes every block with the merkle root of its transactions, all in one batch
+fn add_merkle_roots(db: &sled::Db) -> Result<()> {
+    let mut batch = sled::Batch::default();
+    for kv in block_entries(db) {
+        let (hash, data) = kv?;
+        let Ok(block) = bincode::deserialize::<RootlessBlock>(&data) else {
+            error!("Block {} can't be read, left as it is", String::from_utf8_lossy(&hash));
+            continue;
+        };
+        batch.insert(hash, bincode::serialize(&block.into_dataless()?)?);
+    }
+    batch.insert(SCHEMA_VERSION_KEY, &2u32.to_be_bytes());
+    db.apply_batch(batch)?;
+    Ok(())
+}
+
+// 2 -> 3: a header for every block. Headers already there are rewritten with the same value
+fn fill_headers(db: &sled::Db) -> Result<()> {
+    let headers = db.open_tree(HEADERS_TREE)?;
+    for kv in block_entries(db) {
+        let (hash, data) = kv?;
+        let Ok(block) = bincode::deserialize::<DatalessBlock>(&data) else {
+            error!("Block {} can't be read, it gets no header", String::from_utf8_lossy(&hash));
+            continue;
+        };
+        headers.insert(hash, bincode::serialize(Block::from(block).header())?)?;
+    }
+    db.insert(SCHEMA_VERSION_KEY, &3u32.to_be_bytes())?;
+    Ok(())
+}
+
+// 3 -> 4: re-encodes every block and snapshot entry with transactions that carry no data.
+// Ids and headers are unchanged, the headers tree is left alone. Entries are decoded
+// strictly, ones an interrupted run already re-encoded don't decode and are left as they are
+fn add_transaction_data(db: &sled::Db) -> Result<()> {
+    let mut batch = sled::Batch::default();
+    for kv in block_entries(db) {
+        let (hash, data) = kv?;
+        let Ok(block) = decode_exact::<DatalessBlock>(&data) else {
+            error!("Block {} can't be read, left as it is", String::from_utf8_lossy(&hash));
+            continue;
+        };
+        batch.insert(hash, bincode::serialize(&Block::from(block))?);
+    }
+    let snapshot = db.open_tree(SNAPSHOT_TREE)?;
+    let mut entries = sled::Batch::default();
+    for kv in snapshot.iter() {
+        let (txid, data) = kv?;
+        let Ok(entry) = decode_exact::<DatalessSnapshotEntry>(&data) else {
+            continue;
+        };
+        entries.insert(txid, bincode::serialize(&SnapshotEntry::from(entry))?);
+    }
+    // the entries go first, the version is only written with the blocks
+    snapshot.apply_batch(entries)?;
+    snapshot.flush()?;
+    batch.insert(SCHEMA_VERSION_KEY, &4u32.to_be_bytes());
+    db.apply_batch(batch)?;
+    Ok(())
+}
+
+// bincode::deserialize, refusing bytes left over
+fn decode_exact<T: serde::de::DeserializeOwned>(data: &[u8]) -> bincode::Result<T> {
+    bincode::options().with_fixint_encoding().deserialize(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::testing::{dataless_block, legacy_tx, ChainBuilder, TxBuilder, WalletFixture};
+
+    fn temp_path() -> String {
+        let path = std::env::temp_dir().join(format!("blockjain-schema-{}", rand::random::<u64>()));
+        path.to_str().unwrap().to_string()
+    }
+
+    // A database the way a build from before merkle roots and schema versions left it
+    fn rootless_db(blocks: &[Block]) -> String {
+        let path = temp_path();
+        let db = sled::open(&path).unwrap();
+        for block in blocks {
+            let old = RootlessBlock {
+                timestamp: block.get_timestamp(),
+                transactions: block.get_transactions().iter().map(legacy_tx).collect(),
+                prev_block_hash: block.get_prev_hash(),
+                hash: block.get_hash(),
+                height: block.get_height(),
+                target: block.get_target(),
+                nonce: block.get_nonce(),
+            };
+            db.insert(block.get_hash(), bincode::serialize(&old).unwrap()).unwrap();
+        }
+        db.insert("LAST", blocks.last().unwrap().get_hash().as_bytes()).unwrap();
+        db.flush().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_rootless_database_is_migrated_on_open() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(3).build();
+        let mut blocks: Vec<Block> = chain.iter().collect();
+        blocks.reverse();
+        let path = rootless_db(&blocks);
+
+        let bc = Blockchain::open_network(&path, "main").unwrap();
+        assert_eq!(schema_version(&bc.db).unwrap(), Some(SCHEMA_VERSION));
+        assert_eq!(bc.tip, blocks[3].get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+        for block in &blocks {
+            let migrated = bc.get_block(&block.get_hash()).unwrap();
+            assert_eq!(migrated.header(), block.header());
+            assert!(migrated.verify_merkle_root().unwrap());
+        }
+        let headers = bc.db.open_tree(HEADERS_TREE).unwrap();
+        assert_eq!(headers.len(), 4);
+        assert!(bc.find_utxo().is_ok());
+
+        // migrating again finds nothing to do
+        assert_eq!(upgrade(&bc.db).unwrap(), SCHEMA_VERSION);
+        drop(bc);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_dataless_database_is_migrated_on_open() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner).spend(&reward, 0).pay(&WalletFixture::new(2).address(), 4).build();
+        let chain = chain.block(vec![tx]).build();
+        let blocks: Vec<Block> = chain.iter().collect();
+
+        // A version 3 database, with a snapshot entry the way a snapshot-synced node kept it
+        let path = temp_path();
+        let db = sled::open(&path).unwrap();
+        let headers = db.open_tree(HEADERS_TREE).unwrap();
+        for block in &blocks {
+            db.insert(block.get_hash(), bincode::serialize(&dataless_block(block)).unwrap()).unwrap();
+            headers.insert(block.get_hash(), bincode::serialize(block.header()).unwrap()).unwrap();
+        }
+        let entry = DatalessSnapshotEntry { tx: legacy_tx(&reward), unspent: vec![0] };
+        db.open_tree(SNAPSHOT_TREE).unwrap().insert(reward.id.as_bytes(), bincode::serialize(&entry).unwrap()).unwrap();
+        db.insert("LAST", blocks[0].get_hash().as_bytes()).unwrap();
+        db.insert(SCHEMA_VERSION_KEY, &3u32.to_be_bytes()).unwrap();
+        db.flush().unwrap();
+        drop(headers);
+        drop(db);
+
+        let bc = Blockchain::open_network(&path, "main").unwrap();
+        assert_eq!(schema_version(&bc.db).unwrap(), Some(SCHEMA_VERSION));
+        assert_eq!(bc.tip, blocks[0].get_hash());
+        let read_snapshot = |bc: &Blockchain| -> SnapshotEntry {
+            bincode::deserialize(&bc.db.open_tree(SNAPSHOT_TREE).unwrap().get(reward.id.as_bytes()).unwrap().unwrap()).unwrap()
+        };
+        let migrated = read_snapshot(&bc);
+        assert_eq!((migrated.tx.id.as_str(), migrated.tx.hash().unwrap(), migrated.unspent.clone()), (reward.id.as_str(), reward.id.clone(), vec![0]));
+
+        // Running the migration over blocks and entries it already re-encoded leaves them be
+        add_transaction_data(&bc.db).unwrap();
+        assert_eq!(read_snapshot(&bc).tx.id, reward.id);
+        for block in &blocks {
+            let read = bc.get_block(&block.get_hash()).unwrap();
+            assert_eq!(read.header(), block.header());
+            assert_eq!(bincode::serialize(&read).unwrap(), bincode::serialize(block).unwrap());
+        }
+        drop(bc);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_new_and_unknown_versions() {
+        let path = temp_path();
+        let bc = Blockchain::open_network(&path, "main").unwrap();
+        assert_eq!(schema_version(&bc.db).unwrap(), Some(SCHEMA_VERSION));
+
+        // written by a newer build, refused without touching it
+        bc.db.insert(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_be_bytes()).unwrap();
+        let err = upgrade(&bc.db).unwrap_err();
+        assert_eq!(err.downcast_ref::<SchemaError>(), Some(&SchemaError::TooNew { found: SCHEMA_VERSION + 1, supported: SCHEMA_VERSION }));
+        assert_eq!(schema_version(&bc.db).unwrap(), Some(SCHEMA_VERSION + 1));
+
+        // unversioned, with blocks in a layout no migration reads
+        bc.db.remove(SCHEMA_VERSION_KEY).unwrap();
+        bc.db.insert(bc.tip.as_str(), vec![0xff; 16]).unwrap();
+        let err = upgrade(&bc.db).unwrap_err();
+        assert_eq!(err.downcast_ref::<SchemaError>(), Some(&SchemaError::UnknownLayout));
+        drop(bc);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
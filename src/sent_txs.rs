@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::address;
+use crate::block::Block;
+use crate::errors::Result;
+use crate::transaction::Transaction;
+use crate::wallet_history::BURN_COUNTERPARTY;
+
+/*
+    Sent transactions
+
+    The "sent_txs" tree of the block database records what this node's wallets sent, with
+    whom it paid, so a send can be followed from pending to buried under blocks. Keys:
+
+        t:<txid>    SentTx
+        TIP         hash of the block the statuses are current with
+
+    The chain updates the statuses as blocks connect and disconnect: a send is mined by the
+    block holding it, or conflicted by one holding another transaction spending its inputs
+    (a fee bump, usually). Disconnecting that block makes it pending again. When TIP doesn't
+    match the chain tip the statuses are rebuilt by a rescan, see `Blockchain::sent_transactions`.
+*/
+
+pub const SENT_TXS_TREE: &str = "sent_txs";
+const TIP_KEY: &str = "TIP";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SentStatus {
+    Pending,
+    Mined(i32),              // height of the block holding it
+    Conflicted(String, i32), // txid and height of the mined transaction spending its inputs
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SentTx {
+    pub txid: String,
+    pub recipients: Vec<(String, i64)>, // address, or BURN_COUNTERPARTY, and what it was paid
+    pub amount: i64, // paid to the recipients
+    pub fee: i64,
+    pub created_at: u128, // ms since the epoch
+    pub inputs: Vec<(String, i32)>,
+    pub status: SentStatus,
+}
+
+impl SentTx {
+    /// A pending send of `tx` paying `destinations`. Its other outputs are change
+    pub fn new(tx: &Transaction, destinations: &[String], fee: i64, created_at: u128) -> SentTx {
+        let mut recipients: Vec<(String, i64)> = Vec::new();
+        for destination in destinations {
+            if recipients.iter().any(|(known, _)| known == destination) {
+                continue;
+            }
+            let paid: i64 = tx.vout.iter()
+                .filter(|out| match destination.as_str() {
+                    BURN_COUNTERPARTY => out.is_burn(),
+                    _ => !out.is_burn() && address::wallet_address(&out.pub_key_hash) == *destination,
+                })
+                .map(|out| out.value as i64)
+                .sum();
+            recipients.push((destination.clone(), paid));
+        }
+        SentTx {
+            txid: tx.id.clone(),
+            amount: recipients.iter().map(|(_, paid)| paid).sum(),
+            recipients,
+            fee,
+            created_at,
+            inputs: tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)).collect(),
+            status: SentStatus::Pending,
+        }
+    }
+
+    /// Blocks on top of and including the one holding it at `tip_height`, 0 unless mined
+    pub fn confirmations(&self, tip_height: i32) -> u32 {
+        match self.status {
+            SentStatus::Mined(height) if height <= tip_height => (tip_height - height + 1) as u32,
+            _ => 0,
+        }
+    }
+}
+
+pub struct SentTxIndex {
+    tree: sled::Tree,
+}
+
+impl SentTxIndex {
+    pub fn open(db: &sled::Db) -> Result<SentTxIndex> {
+        Ok(SentTxIndex { tree: db.open_tree(SENT_TXS_TREE)? })
+    }
+
+    pub fn insert(&self, sent: &SentTx) -> Result<()> {
+        self.tree.insert(format!("t:{}", sent.txid), bincode::serialize(sent)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, txid: &str) -> Result<Option<SentTx>> {
+        match self.tree.get(format!("t:{}", txid))? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every recorded send, newest first
+    pub fn all(&self) -> Result<Vec<SentTx>> {
+        let mut sends = Vec::new();
+        for kv in self.tree.scan_prefix("t:") {
+            let (_, data) = kv?;
+            sends.push(bincode::deserialize::<SentTx>(&data)?);
+        }
+        sends.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| a.txid.cmp(&b.txid)));
+        Ok(sends)
+    }
+
+    /// Hash of the block the statuses are current with
+    pub fn tip(&self) -> Result<Option<String>> {
+        Ok(self.tree.get(TIP_KEY)?.map(|hash| String::from_utf8_lossy(&hash).into_owned()))
+    }
+
+    pub fn set_tip(&self, hash: &str) -> Result<()> {
+        self.tree.insert(TIP_KEY, hash.as_bytes())?;
+        Ok(())
+    }
+
+    /// Settles the pending sends `block` mines or conflicts with and moves the tip to it
+    pub fn connect_block(&self, block: &Block) -> Result<()> {
+        self.apply_block(block)?;
+        self.set_tip(&block.get_hash())
+    }
+
+    /// Makes the sends `block` settled pending again and moves the tip to its parent
+    pub fn disconnect_block(&self, block: &Block) -> Result<()> {
+        let txids: HashSet<&String> = block.get_transactions().iter().map(|tx| &tx.id).collect();
+        for mut sent in self.all()? {
+            let settled_here = match &sent.status {
+                SentStatus::Pending => false,
+                SentStatus::Mined(height) => *height == block.get_height() && txids.contains(&sent.txid),
+                SentStatus::Conflicted(txid, height) => *height == block.get_height() && txids.contains(txid),
+            };
+            if settled_here {
+                sent.status = SentStatus::Pending;
+                self.insert(&sent)?;
+            }
+        }
+        self.set_tip(&block.get_prev_hash())
+    }
+
+    /// Makes every send pending, for a rescan to settle them again block by block
+    pub fn reset(&self) -> Result<()> {
+        for mut sent in self.all()? {
+            if sent.status != SentStatus::Pending {
+                sent.status = SentStatus::Pending;
+                self.insert(&sent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Settles the pending sends `block` mines or conflicts with
+    pub fn apply_block(&self, block: &Block) -> Result<()> {
+        let pending: Vec<SentTx> = self.all()?.into_iter().filter(|sent| sent.status == SentStatus::Pending).collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        for tx in block.get_transactions() {
+            for sent in &pending {
+                let status = if tx.id == sent.txid {
+                    SentStatus::Mined(block.get_height())
+                } else if tx.vin.iter().any(|vin| sent.inputs.contains(&(vin.txid.clone(), vin.vout))) {
+                    SentStatus::Conflicted(tx.id.clone(), block.get_height())
+                } else {
+                    continue;
+                };
+                self.insert(&SentTx { status, ..sent.clone() })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::INITIAL_TARGET;
+    use crate::testing::{coinbase, ChainBuilder, TxBuilder, WalletFixture};
+
+    #[test]
+    fn test_sends_follow_connects_reorgs_and_bumps() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&alice);
+        let genesis = chain.tip();
+        let reward = genesis.get_transactions()[0].clone();
+        let bc = &mut chain.build();
+
+        let pay = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 3).build();
+        let sent = bc.record_sent(&pay, &[bob.address()], 5).unwrap();
+        assert_eq!((sent.recipients, sent.amount, sent.fee), (vec![(bob.address(), 6)], 6, 1));
+        let status = |bc: &crate::blockchain::Blockchain, txid: &str| {
+            bc.sent_transactions().unwrap().into_iter().find(|sent| sent.txid == txid).unwrap().status
+        };
+        assert_eq!(status(bc, &pay.id), SentStatus::Pending);
+
+        // Mined, then buried
+        let block = Block::new_block(vec![coinbase(&alice.address(), 1), pay.clone()], genesis.get_hash(), 1, INITIAL_TARGET).unwrap();
+        let next = Block::new_block_at(vec![coinbase(&alice.address(), 2)], block.get_hash(), 2, INITIAL_TARGET, block.get_timestamp() + 1).unwrap();
+        bc.add_block(block).unwrap();
+        bc.add_block(next).unwrap();
+        let mined = bc.sent_transactions().unwrap().remove(0);
+        assert_eq!(mined.status, SentStatus::Mined(1));
+        assert_eq!(mined.confirmations(2), 2);
+
+        // A longer branch without it makes it pending again, and a rescan agrees
+        let fork = Block::new_block(vec![coinbase(&carol.address(), 1)], genesis.get_hash(), 1, INITIAL_TARGET).unwrap();
+        let fork2 = Block::new_block_at(vec![coinbase(&carol.address(), 2)], fork.get_hash(), 2, INITIAL_TARGET, fork.get_timestamp() + 1).unwrap();
+        let fork3 = Block::new_block_at(vec![coinbase(&carol.address(), 3)], fork2.get_hash(), 3, INITIAL_TARGET, fork.get_timestamp() + 2).unwrap();
+        for block in [fork, fork2, fork3.clone()] {
+            bc.add_block(block).unwrap();
+        }
+        assert_eq!(status(bc, &pay.id), SentStatus::Pending);
+        SentTxIndex::open(&bc.db).unwrap().set_tip("stale").unwrap();
+        assert_eq!(status(bc, &pay.id), SentStatus::Pending);
+
+        // Its fee bump is mined instead
+        let bumped = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 2).build();
+        bc.record_sent(&bumped, &[bob.address()], 6).unwrap();
+        let block = Block::new_block(vec![coinbase(&alice.address(), 4), bumped.clone()], fork3.get_hash(), 4, INITIAL_TARGET).unwrap();
+        bc.add_block(block).unwrap();
+        assert_eq!(status(bc, &bumped.id), SentStatus::Mined(4));
+        assert_eq!(status(bc, &pay.id), SentStatus::Conflicted(bumped.id.clone(), 4));
+        SentTxIndex::open(&bc.db).unwrap().set_tip("stale").unwrap();
+        assert_eq!(status(bc, &pay.id), SentStatus::Conflicted(bumped.id, 4));
+    }
+}
@@ -4,92 +4,159 @@ use futures::StreamExt;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tokio::time::{interval, Duration};
-use tokio::sync::RwLock;
+use tokio::time::Duration;
+use tokio::sync::{mpsc, Mutex, RwLock};
 use std::sync::Arc;
-use std::collections::HashMap;
-use futures::stream::FuturesUnordered;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet};
 use failure::format_err;
-use serde::{Deserialize, Serialize};
 
-use crate::errors::Result;
+use crate::bandwidth::{RateLimiter, Throughput, TrafficMeter, CHUNK_SIZE};
+use crate::errors::{BlockRejectReason, LookupError, ProtocolError, Result, TxRejectReason};
+use crate::health::{self, HealthReport};
+use crate::protocol::{
+    bytes_to_cmd, cmd_to_bytes, encode, Addrmsg, Blockmsg, Capabilities, GetBlockmsg, GetDatamsg, GetSnapshotmsg, Invmsg, Message,
+    NotFoundmsg, OpCheckpointmsg, SnapChunkmsg, Snapshotmsg, TxAckmsg, Txmsg, Versionmsg, HEALTH_CMD, MAX_MESSAGE_SIZE, VERSION,
+};
+use crate::identity::NodeIdentity;
 use crate::transaction::Transaction;
-use crate::block::Block;
+use crate::audit::{AuditAction, AuditLog, AuditOutcome};
+use crate::block::{Block, BLOCK_RESERVED_SIZE, MAX_BLOCK_SIZE};
+use crate::blockchain::{Blockchain, ReorgOutcome, BLOCKS_PATH};
+#[cfg(feature = "chaos")]
+use crate::chaos::{Chaos, Fate, CHAOS_CONFIG_PATH};
+use crate::checkpoint::Checkpoint;
+use crate::clock::{self, NetworkClock, WakeDetector};
+use crate::disk::{self, DiskLevel, DiskMonitor};
+use crate::idle::{self, Visibility};
+use crate::events::{coalesce_blocks, BlocksConnected, NodeEvent, BLOCK_EVENT_INTERVAL};
+use crate::mempool::{self, EvictionReason, PackageStats};
+use crate::peer_history::{PeerHistoryEntry, RemovalReason};
+use crate::receipt::TxAck;
 use crate::utxoset::UTXOSet;
+use crate::config::CONFIG;
+use crate::settings::{ChainType, SETTINGS};
+use crate::snapshot::{Snapshot, SnapshotDownload, TrustAnchor, SNAPSHOT_INTERVAL, SNAPSHOT_RECENT_BLOCKS};
 
 // Shitam jabut public serverim ar blockchain implementation nevis localhost
 const KNOWN_NODE1: &str = "127.0.0.1:8335";
-const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+// Upper bound for connecting to and writing to a single peer
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+// Period of the state, snapshot and tip age checks
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(20);
+
+// Peers reaching this misbehavior score get dropped
+const BAN_SCORE: u32 = 100;
+// Peers asked for blocks when syncing
+const SYNC_PEERS: usize = 4;
+// Blocks deeper than this below the tip are historical, see Settings::serve_historical_blocks
+const RECENT_BLOCK_DEPTH: i32 = 6;
+// Blocks whose parent hasn't arrived yet, the oldest are dropped past this
+const MAX_ORPHAN_BLOCKS: usize = 100;
 
 /*
     Kad tx aizsutits / new block izveidots vajag updatot application UI
 */
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Blockmsg {
-    addr_from: String,
-    block: Block,
+/// Outcome of sending one message to several peers
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BroadcastReport {
+    pub delivered: usize,
+    pub failed: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetBlockmsg{
-    addr_from: String,
+// What this node advertises
+const LOCAL_CAPABILITIES: Capabilities = Capabilities::TX_RECEIPTS;
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct KnownNode {
+    no_response_counter: i8,
+    misbehavior_score: u32,
+    #[serde(default)]
+    capabilities: Capabilities, // from the peer's last version message
+    #[serde(default)]
+    version: Option<i32>, // protocol version from the peer's last version message
+    #[serde(default)]
+    last_seen: Option<u128>, // ms since the epoch of the peer's last message, None if it never sent one
+    #[serde(default)]
+    latency: Option<Duration>, // how long the last connection to the peer took to open
+    #[serde(default)]
+    reported_peers: Vec<String>, // from the peer's last addr message
 }
 
+impl KnownNode {
+    /// A peer that went through the handshake with our protocol version, for peer lists
+    /// made without a server
+    pub fn handshaken(capabilities: Capabilities, last_seen: u128) -> KnownNode {
+        KnownNode { capabilities, version: Some(VERSION), last_seen: Some(last_seen), ..KnownNode::default() }
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetDatamsg{
-    addr_from: String,
-    kind: String,
-    id: String,
-}
+    pub fn no_response_counter(&self) -> i8 {
+        self.no_response_counter
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Invmsg {
-    addr_from: String,
-    kind: String,
-    items: Vec<String>,
-}
+    pub fn misbehavior_score(&self) -> u32 {
+        self.misbehavior_score
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Txmsg {
-    addr_from: String,
-    transaction: Transaction,
-}
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-struct Versionmsg {
-    addr_from: String,
-    version: i32,
-    best_height: i32,
-}
+    pub fn version(&self) -> Option<i32> {
+        self.version
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum Message {
-    Addr(Vec<String>),
-    Version(Versionmsg),
-    Tx(Txmsg),
-    GetData(GetDatamsg),
-    GetBlock(GetBlockmsg),
-    Inv(Invmsg),
-    Block(Blockmsg),
-}
+    pub fn last_seen(&self) -> Option<u128> {
+        self.last_seen
+    }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct KnownNode {
-    no_response_counter: i8,
-    // Other information about the node.
-    // last_seen_time?
-    // Version ?
-    // State ?
-    // ...
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn reported_peers(&self) -> &[String] {
+        &self.reported_peers
+    }
 }
 
 // - Server -
+// Shared as Arc<RwLock<Server>>, but only the setup before start_server needs the write
+// lock. Message handlers take &self and run concurrently under the read lock, the state
+// they change lives in `inner`, so a handler waiting on a slow peer holds up nobody else
 pub struct Server {
     node_address: String,
     mining_address: String,
+    send_timeout: Duration,
+    max_concurrent_sends: usize,
+    capabilities: Capabilities,
+    operator_key: Option<Vec<u8>>, // checkpoints are ignored without one
+    snapshot_anchor: TrustAnchor,
+    serve_snapshots: bool,
+    snapshot_signing_key: Option<Vec<u8>>,
+    events: Option<mpsc::Sender<NodeEvent>>,
+    connected_blocks: Option<mpsc::UnboundedSender<BlocksConnected>>,
+    block_interval: Duration,
+    stale_tip_multiple: u32,
+    stalled: AtomicBool, // TipStalled was sent, reset when a block connects
+    upload: RateLimiter,
+    download: Arc<RateLimiter>, // shared with the connection tasks, which read before locking the server
+    serve_historical_blocks: bool,
+    prune_keep_blocks: u32, // 0 doesn't prune
+    listening: AtomicBool, // the listener is bound
+    visibility: Visibility, // the window's, maintenance slows down while it's hidden
+    mining: Mutex<()>, // one handle_tx mines the mempool at a time
+    allow_rollback: bool, // devnet, or --allow-rollback
+    audit: AuditLog, // rollbacks and chain imports are recorded here
+    identity: NodeIdentity, // signs TxAcks
+    tx_receipts: bool, // our transactions ask capable peers for a TxAck
+    dust_threshold: i32, // relayed transactions paying an output below it are rejected
+    verify_block_connect: bool, // see Server::verify_block_connect
+    mempool_ttl: Duration, // zero keeps unmined transactions
+    max_mempool_size: usize, // bytes, 0 for no limit
+    health_peer_window: Duration,
+    #[cfg(feature = "chaos")]
+    chaos: Chaos, // outgoing messages are delayed, dropped or refused
 
     inner: RwLock<ServerInner>,
 }
@@ -100,33 +167,124 @@ struct ServerInner {
     // utxo is imported from app.rs, that's why it needs to be Arc. and RwLock.
     utxo: Arc<RwLock<UTXOSet>>,
     blocks_in_transit: Vec<String>,
+    orphan_blocks: Vec<Block>, // oldest first
     mempool: HashMap<String, Transaction>,
+    mempool_spends: HashMap<(String, i32), String>, // outputs the mempool spends and by what, see mempool::spent_outpoints
+    mempool_added: HashMap<String, u128>, // when each mempool transaction came in, ms since the epoch
+    package_stats: HashMap<String, PackageStats>, // cache, emptied whenever the mempool changes
+    awaiting_acks: HashSet<String>, // txids we sent with "acktx", acks for anything else are dropped
+    snapshot: Option<Arc<Snapshot>>, // served to new nodes, rebuilt every SNAPSHOT_INTERVAL blocks
+    snapshot_download: Option<SnapshotDownload>,
+    clock: NetworkClock,
+    traffic: TrafficMeter,
+    last_peer_message: Option<std::time::Instant>,
+    disk: DiskMonitor,
 
 }
 
 impl Server {
     pub fn new(port: &str, miner_address: &str, utxo: Arc<RwLock<UTXOSet>>) -> Result<Server> {
         let mut node_set = HashMap::new();
-        node_set.insert(String::from(KNOWN_NODE1), KnownNode {
-            no_response_counter: 0
-        }); // bootstrap node
+        node_set.insert(String::from(KNOWN_NODE1), KnownNode::default()); // bootstrap node
 
         Ok(Server {
             node_address: String::from("127.0.0.1:") + port, 
             mining_address: miner_address.to_string(),
+            send_timeout: SEND_TIMEOUT,
+            max_concurrent_sends: SETTINGS.max_concurrent_sends.max(1),
+            capabilities: LOCAL_CAPABILITIES,
+            operator_key: hex::decode(&SETTINGS.operator_public_key).ok().filter(|key| key.len() == 32),
+            snapshot_anchor: TrustAnchor::from_settings(),
+            serve_snapshots: SETTINGS.serve_snapshots,
+            snapshot_signing_key: hex::decode(&SETTINGS.snapshot_signing_key).ok().filter(|key| key.len() == 32),
+            events: None,
+            connected_blocks: None,
+            block_interval: Duration::from_secs(SETTINGS.expected_block_interval),
+            stale_tip_multiple: SETTINGS.stale_tip_multiple,
+            stalled: AtomicBool::new(false),
+            upload: RateLimiter::from_kbps(SETTINGS.max_upload_kbps),
+            download: Arc::new(RateLimiter::from_kbps(SETTINGS.max_download_kbps)),
+            serve_historical_blocks: SETTINGS.serve_historical_blocks,
+            prune_keep_blocks: SETTINGS.prune_keep_blocks,
+            listening: AtomicBool::new(false),
+            visibility: Visibility::default(),
+            mining: Mutex::new(()),
+            allow_rollback: SETTINGS.chain == ChainType::Devnet || CONFIG.allow_rollback.value,
+            audit: AuditLog::default(),
+            identity: NodeIdentity::generate(),
+            tx_receipts: SETTINGS.tx_receipts,
+            dust_threshold: SETTINGS.dust_threshold,
+            verify_block_connect: SETTINGS.verify_block_connect,
+            mempool_ttl: Duration::from_secs(SETTINGS.mempool_expiry_hours * 60 * 60),
+            max_mempool_size: SETTINGS.max_mempool_mb as usize * 1024 * 1024,
+            health_peer_window: Duration::from_secs(SETTINGS.health_peer_window_mins * 60),
+            #[cfg(feature = "chaos")]
+            chaos: Chaos::from_file(CHAOS_CONFIG_PATH),
 
             // thread-safe inner
             inner: RwLock::new(ServerInner {
                 known_nodes: node_set,
                 utxo,
                 blocks_in_transit: Vec::new(),
+                orphan_blocks: Vec::new(),
                 mempool: HashMap::new(),
+                mempool_spends: HashMap::new(),
+                mempool_added: HashMap::new(),
+                package_stats: HashMap::new(),
+                awaiting_acks: HashSet::new(),
+                snapshot: None,
+                snapshot_download: None,
+                clock: NetworkClock::default(),
+                traffic: TrafficMeter::default(),
+                last_peer_message: None,
+                disk: DiskMonitor::from_settings(),
             }),
         })
     }
 
+    // Where the server reports NodeEvents, usually the UI. Must be called within the tokio runtime.
+    pub fn set_event_sender(&mut self, sender: mpsc::Sender<NodeEvent>) {
+        // Connected blocks are batched so syncing thousands of them doesn't flood the UI
+        let (blocks, blocks_rx) = mpsc::unbounded_channel();
+        tokio::spawn(coalesce_blocks(blocks_rx, sender.clone(), BLOCK_EVENT_INTERVAL));
+
+        self.connected_blocks = Some(blocks);
+        self.events = Some(sender);
+    }
+
+    /// Where the UI tells the node whether the window is on screen
+    pub fn visibility(&self) -> Visibility {
+        self.visibility.clone()
+    }
+
+    /// Where the node records destructive actions
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    // The node key saved with the node's data, new() starts with a throwaway one
+    pub fn set_identity(&mut self, identity: NodeIdentity) {
+        self.identity = identity;
+    }
+
+    fn block_connected(&self, block: &Block) {
+        self.stalled.store(false, Ordering::Relaxed);
+        if let Some(blocks) = &self.connected_blocks {
+            let _ = blocks.send(block.into());
+        }
+    }
+
+    async fn emit(&self, event: NodeEvent) {
+        if let Some(sender) = &self.events {
+            if let Err(e) = sender.send(event).await {
+                println!("Failed to emit node event: {}", e);
+            }
+        }
+    }
+
     pub async fn start_server(server: Arc<RwLock<Self>>) -> Result<()> {
         let listener = TcpListener::bind(&server.read().await.node_address).await?;
+        server.read().await.listening.store(true, Ordering::Relaxed);
         println!(
             "Start server at {}, mining address: {}",
             server.read().await.node_address,
@@ -135,27 +293,72 @@ impl Server {
 
         //println!("Server instance: {:?} start_server", Arc::as_ptr(&server));
 
-        // Spawn a task for periodic blockchain state checks
+        // Spawn a task for periodic blockchain state checks, less often while the window is
+        // minimized (see idle)
         let server_clone = Arc::clone(&server);
         tokio::spawn(async move {
-            let mut interval_timer = interval(Duration::from_secs(20));
+            let mut visibility = server_clone.read().await.visibility.subscribe();
+            let mut wake = WakeDetector::new(MAINTENANCE_INTERVAL);
+            let started = std::time::Instant::now();
             
             loop {
-                interval_timer.tick().await;
-
+                let hidden = *visibility.borrow_and_update();
+                if let Some(slept) = wake.tick(started.elapsed(), clock::now_millis()) {
+                    server_clone.read().await.resume_after_sleep(slept).await;
+                }
+                // Put off while hidden, unless space is already running out
+                if !hidden || server_clone.read().await.inner.read().await.disk.level() != DiskLevel::Ok {
+                    if let Err(e) = server_clone.read().await.check_disk_space().await {
+                        println!("Error while checking disk space: {}", e);
+                    }
+                }
                 if let Err(e) = server_clone.read().await.check_and_update_blockchain_state().await {
                     println!("Error during blockchain state check: {}", e);
                 }
+                if let Err(e) = server_clone.read().await.refresh_snapshot().await {
+                    println!("Error while building the snapshot: {}", e);
+                }
+                if let Err(e) = server_clone.read().await.check_tip_age().await {
+                    println!("Error while checking the tip age: {}", e);
+                }
+                if let Err(e) = server_clone.read().await.prune().await {
+                    println!("Error while pruning blocks: {}", e);
+                }
+                if let Err(e) = server_clone.read().await.evict_mempool(clock::now_millis()).await {
+                    println!("Error while evicting mempool transactions: {}", e);
+                }
+
+                // Delayed rather than made up in a burst after the machine slept, cut short
+                // when the window comes back
+                let period = idle::scaled_interval(MAINTENANCE_INTERVAL, hidden, SETTINGS.hidden_interval_multiplier);
+                wake.set_interval(period);
+                tokio::select! {
+                    _ = tokio::time::sleep(period) => {}
+                    Ok(()) = visibility.changed(), if hidden => {}
+                }
             }
         });
 
         // Handle incoming connections
         loop {
             match listener.accept().await {
-                Ok((stream, _)) => {
+                Ok((mut stream, peer)) => {
                     let server_clone = Arc::clone(&server);
+                    let download = Arc::clone(&server.read().await.download);
                     tokio::spawn(async move {
-                        if let Err(e) = server_clone.write().await.handle_connection(stream).await {
+                        // A throttled read mustn't hold the server lock, other messages keep coming in
+                        let result = match read_message(&mut stream, &download).await {
+                            Ok(buffer) if buffer.starts_with(&cmd_to_bytes(HEALTH_CMD)) && peer.ip().is_loopback() => {
+                                let report = server_clone.read().await.healthcheck().await;
+                                match bincode::serialize(&report) {
+                                    Ok(data) => stream.write_all(&data).await.map_err(Into::into),
+                                    Err(e) => Err(e.into()),
+                                }
+                            }
+                            Ok(buffer) => server_clone.read().await.handle_message(buffer).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = result {
                             println!("Error handling connection: {}", e);
                         }
                     });
@@ -170,7 +373,14 @@ impl Server {
 
     async fn check_and_update_blockchain_state(&self) -> Result<()> {
         let best_height = self.get_best_height().await?;
-        if best_height == -1 {
+        if best_height <= 0 && self.snapshot_anchor.is_enabled() && !self.disk_full().await {
+            // A new node: start from a trusted snapshot rather than the whole history
+            if self.inner.read().await.snapshot_download.is_none() {
+                if let Some(peer) = self.pick_peers_with(Capabilities::NONE, 1).await.pop() {
+                    self.request_snapshot(&peer).await?;
+                }
+            }
+        } else if best_height == -1 {
             self.request_blocks().await?;
         } else {
             let peers: Vec<String> = {
@@ -180,9 +390,9 @@ impl Server {
             if peers.is_empty() {
                 println!("Empty known_nodes list");
             } else {                
-                for peer in peers {
-                    self.send_version(&peer).await?;
-                }
+                let data = self.version_message().await?;
+                let report = self.broadcast(peers, data).await;
+                println!("Version sent to {} peers, {} failed", report.delivered, report.failed);
             }
         }
         Ok(())
@@ -190,11 +400,125 @@ impl Server {
 
     
 
-    pub async fn add_peer(&mut self, new_peer_ip:String ) -> Result<()>{
+    /// Age of the tip block by network-adjusted time, None for an empty chain
+    pub async fn tip_age(&self) -> Result<Option<Duration>> {
+        let utxo = self.inner.read().await.utxo.clone();
+        let tip = utxo.read().await.blockchain.read().await.iter().next();
+        let now = self.inner.read().await.clock.now();
+        Ok(tip.map(|block| clock::tip_age(block.get_timestamp(), now)))
+    }
+
+    // Warns once and resyncs whenever the tip stopped advancing while peers are connected
+    async fn check_tip_age(&self) -> Result<()> {
+        let Some(tip_age) = self.tip_age().await? else {
+            return Ok(());
+        };
+        let peers = self.inner.read().await.known_nodes.len();
+        if !clock::is_stalled(tip_age, self.block_interval, self.stale_tip_multiple, peers) {
+            return Ok(());
+        }
+
+        if self.disk_full().await {
+            return Ok(()); // not stalled, paused
+        }
+        if !self.stalled.swap(true, Ordering::Relaxed) {
+            println!("Chain tip is {} old, resyncing", clock::format_age(tip_age));
+            self.emit(NodeEvent::TipStalled { tip_age }).await;
+        }
+        self.resync_from_all_peers().await
+    }
+
+    // The bootstrap nodes may have been dropped as unresponsive, they're added back
+    // and every known peer is asked for blocks rather than SYNC_PEERS of them
+    async fn resync_from_all_peers(&self) -> Result<()> {
+        self.add_bootstrap_nodes().await;
+        let peers: Vec<String> = self.get_known_nodes().await.into_keys().collect();
+        let data = self.get_blocks_message()?;
+        let report = self.broadcast(peers, data).await;
+        println!("Resync: blocks requested from {} peers, {} failed", report.delivered, report.failed);
+        Ok(())
+    }
+
+    // Measures the free space where the blocks are, see disk
+    async fn check_disk_space(&self) -> Result<()> {
+        let free = disk::free_space(BLOCKS_PATH)?;
+        self.apply_free_space(free).await;
+        Ok(())
+    }
+
+    async fn apply_free_space(&self, free: u64) {
+        let (previous, changed) = {
+            let mut inner = self.inner.write().await;
+            let previous = inner.disk.level();
+            (previous, inner.disk.update(free))
+        };
+        let Some(level) = changed else {
+            return;
+        };
+        println!("Disk space is {:?}, {} free", level, crate::maintenance::format_size(free));
+        let utxo = self.inner.read().await.utxo.clone();
+        let stores = disk::store_usage(utxo.read().await.path());
+        self.emit(NodeEvent::DiskSpace { level, free, stores }).await;
+
+        // Catch up on what was missed while paused
+        if previous == DiskLevel::Full {
+            if let Err(e) = self.request_blocks().await {
+                println!("Error requesting blocks after the disk pause: {}", e);
+            }
+        }
+    }
+
+    /// Whether blocks are paused for lack of disk space
+    pub async fn disk_full(&self) -> bool {
+        self.inner.read().await.disk.level() == DiskLevel::Full
+    }
+
+    // Deletes blocks deeper than prune_keep_blocks on pruned nodes. Blocks connected since
+    // the last round are pruned together, most rounds find nothing to do
+    async fn prune(&self) -> Result<()> {
+        if self.prune_keep_blocks == 0 {
+            return Ok(());
+        }
+        let utxo = self.inner.read().await.utxo.clone();
+        let utxo = utxo.read().await;
+        let pruned = utxo.blockchain.write().await.prune_to_height(self.prune_keep_blocks)?;
+        if pruned > 0 {
+            println!("Pruned {} blocks", pruned);
+        }
+        Ok(())
+    }
+
+    async fn add_bootstrap_nodes(&self) {
+        let mut inner = self.inner.write().await;
+        for seed in [KNOWN_NODE1, CONFIG.bootstrap_node.value.as_str()] {
+            if !seed.is_empty() && seed != self.node_address {
+                inner.known_nodes.entry(seed.to_string()).or_default();
+            }
+        }
+    }
+
+    // Connections failed while the machine slept aren't the peers' fault, and the clock
+    // samples are from before the sleep. Peers are greeted again right away, which also
+    // starts syncing whatever was missed
+    async fn resume_after_sleep(&self, slept: Duration) {
+        println!("Resumed after {} of sleep, reconnecting", clock::format_age(slept));
+        self.add_bootstrap_nodes().await;
+        {
+            let mut inner = self.inner.write().await;
+            for node in inner.known_nodes.values_mut() {
+                node.no_response_counter = 0;
+            }
+            inner.clock.reset();
+        }
+        self.emit(NodeEvent::ResumedFromSleep { slept }).await;
+        if let Err(e) = self.check_and_update_blockchain_state().await {
+            println!("Error during blockchain state check after sleep: {}", e);
+        }
+    }
+
+    pub async fn add_peer(&self, new_peer_ip:String ) -> Result<()>{
         //println!("Before adding peer, nodes: {:?}", self.inner.read().await.known_nodes);
-        self.inner.write().await.known_nodes.insert(new_peer_ip, KnownNode {
-            no_response_counter: 0,
-        });
+        self.inner.write().await.known_nodes.insert(new_peer_ip, KnownNode::default());
         //println!("After adding peer, nodes: {:?}", self.inner.read().await.known_nodes);
 
         /*let nodes = self.inner.read().await;
@@ -206,27 +530,53 @@ impl Server {
     }
 
 
-    // Requests blocks from known_nodes
+    // Up to `n` peers that advertised `capability`, or any peers when none did
+    pub async fn pick_peers_with(&self, capability: Capabilities, n: usize) -> Vec<String> {
+        let known_nodes = self.get_known_nodes().await;
+        let mut capable: Vec<String> = known_nodes.iter()
+            .filter(|(_, node)| node.capabilities.contains(capability))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        if capable.is_empty() {
+            capable = known_nodes.into_keys().collect();
+        }
+        capable.truncate(n);
+        capable
+    }
+
+    // Requests blocks from known_nodes, headers-first peers preferred
     async fn request_blocks(&self) -> Result<()> {
-        for node in self.get_known_nodes().await {
-            self.send_get_blocks(&node.0).await?
+        if self.disk_full().await {
+            return Ok(());
         }
+        let peers = self.pick_peers_with(Capabilities::HEADERS_FIRST, SYNC_PEERS).await;
+        let data = self.get_blocks_message()?;
+        self.broadcast(peers, data).await;
         Ok(())
     }
 
     // ---------------------------------- SENDS ----------------------------------
 
     async fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+        if addr == self.node_address {
             return Ok(());
         }
 
+        #[cfg(feature = "chaos")]
+        match self.chaos.fate(addr) {
+            Fate::Deliver(delay) => tokio::time::sleep(delay).await,
+            Fate::Drop => return Ok(()),
+            Fate::Disconnected => return Err(format_err!("Failed to connect to {}: disconnected by network chaos", addr)),
+        }
+
         //println!("🔵 Attempting connection to {}", addr);
         
-        let mut stream = match TcpStream::connect(addr).await {
-            Ok(s) => {
+        let connecting = std::time::Instant::now();
+        let mut stream = match tokio::time::timeout(self.send_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(s)) => {
                 let mut guard = self.inner.write().await;
                 if let Some(node) = guard.known_nodes.get_mut(addr) {
+                    node.latency = Some(connecting.elapsed());
                     if node.no_response_counter > 0 {
                         // Basically a reset on successful connection if the previous connections were unsuccessful
                         node.no_response_counter = 0;
@@ -236,7 +586,11 @@ impl Server {
                 // Return stream
                 s
             },
-            Err(e) => {
+            result => {
+                let e = match result {
+                    Ok(Err(e)) => e.to_string(),
+                    _ => String::from("connection timed out"),
+                };
                 println!("❌ Failed to connect to {}: {}", addr, e);
 
                 let remove_node = {
@@ -257,20 +611,56 @@ impl Server {
                 
                 // Perform removal outside the lock
                 if let Some(node_to_remove) = remove_node {
-                    self.remove_node(&node_to_remove).await;
+                    self.remove_node(&node_to_remove, RemovalReason::Unreachable).await;
                 }
 
-                return Ok(());
+                return Err(format_err!("Failed to connect to {}: {}", addr, e));
             }
         };
 
         //println!("🟢 Writing data to {}", addr);
 
-        let _ = stream.write(data).await;
-
-        //println!("✅ Data sent successfully to {}", addr);
+        // Written chunk by chunk within the upload limit, which also extends the timeout
+        let write = async {
+            for chunk in data.chunks(CHUNK_SIZE) {
+                self.upload.consume(chunk.len()).await;
+                stream.write_all(chunk).await?;
+            }
+            Ok::<(), std::io::Error>(())
+        };
+        match tokio::time::timeout(self.send_timeout + self.upload.transfer_time(data.len()), write).await {
+            Ok(Ok(())) => {
+                self.inner.write().await.traffic.sent(addr, data.len());
+                Ok(())
+            }
+            Ok(Err(e)) => Err(format_err!("Failed to send data to {}: {}", addr, e)),
+            Err(_) => Err(format_err!("Timed out sending data to {}", addr)),
+        }
+    }
 
-        Ok(())
+    // Sends `data` to every peer, at most `max_concurrent_sends` at a time, so one slow peer
+    // doesn't hold up the others. Don't call this while holding the inner lock,
+    // send_data takes it to update the peer's no_response_counter.
+    async fn broadcast(&self, peers: Vec<String>, data: Vec<u8>) -> BroadcastReport {
+        let data = &data;
+        let results: Vec<Result<()>> = futures::stream::iter(peers)
+            .filter(|peer| futures::future::ready(*peer != self.node_address))
+            .map(|peer| async move {
+                let result = self.send_data(&peer, data).await;
+                if let Err(e) = &result {
+                    println!("Broadcast to {} failed: {}", peer, e);
+                }
+                result
+            })
+            .buffer_unordered(self.max_concurrent_sends)
+            .collect()
+            .await;
+
+        let delivered = results.iter().filter(|r| r.is_ok()).count();
+        BroadcastReport {
+            delivered,
+            failed: results.len() - delivered,
+        }
     }
 
     async fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
@@ -279,56 +669,98 @@ impl Server {
             addr_from: self.node_address.clone(),
             block: b.clone()
         };
-        let data = bincode::serialize(&(cmd_to_bytes("block"), data))?;
+        let data = encode("block", &data)?;
         self.send_data(addr, &data).await
     }
 
     async fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
         println!("send inv message to: {} kind: {} data: {:?}", addr, kind, items);
+        let data = self.inv_message(kind, items)?;
+        self.send_data(addr, &data).await
+    }
+
+    // Announces inventory to several peers at once
+    async fn broadcast_inv(&self, peers: Vec<String>, kind: &str, items: Vec<String>) -> Result<BroadcastReport> {
+        println!("broadcast inv message to {} peers kind: {} data: {:?}", peers.len(), kind, items);
+        let data = self.inv_message(kind, items)?;
+        Ok(self.broadcast(peers, data).await)
+    }
+
+    fn inv_message(&self, kind: &str, items: Vec<String>) -> Result<Vec<u8>> {
         let data = Invmsg {
             addr_from: self.node_address.clone(),
             kind: kind.to_string(),
             items,
         };
-        let data = bincode::serialize(&(cmd_to_bytes("inv"), data))?;
-        self.send_data(addr, &data).await
+        encode("inv", &data)
     }
 
-    pub async fn send_tx(&self, addr: String, tx: &Transaction) -> Result<()> {
-        println!("send tx to: {} txid: {}", &addr, &tx.id);
+    fn tx_message(&self, tx: &Transaction) -> Result<Vec<u8>> {
+        self.tx_message_with(tx, "tx")
+    }
+
+    // "tx", or "acktx" to ask for a TxAck
+    fn tx_message_with(&self, tx: &Transaction, cmd: &str) -> Result<Vec<u8>> {
         let data = Txmsg {
             addr_from: self.node_address.clone(),
             transaction: tx.clone(),
         };
-        let data = bincode::serialize(&(cmd_to_bytes("tx"), data))?;
+        encode(cmd, &data)
+    }
+
+    // Failing to answer doesn't stop the transaction from being relayed or mined
+    async fn send_tx_ack(&self, addr: &str, txid: &str) {
+        println!("send tx ack to: {} txid: {}", addr, txid);
+        let sent = async {
+            let data = TxAckmsg {
+                addr_from: self.node_address.clone(),
+                ack: TxAck::sign(&self.identity, txid, clock::now_millis())?,
+            };
+            let data = encode("txack", &data)?;
+            self.send_data(addr, &data).await
+        };
+        if let Err(e) = sent.await {
+            println!("Couldn't acknowledge tx {} to {}: {}", txid, addr, e);
+        }
+    }
+
+    pub async fn send_tx(&self, addr: String, tx: &Transaction) -> Result<()> {
+        println!("send tx to: {} txid: {}", &addr, &tx.id);
+        let data = self.tx_message(tx)?;
         self.send_data(&addr, &data).await
     }
 
     async fn send_version(&self, addr: &str) -> Result<()> {
         //println!("🔵 Sending version info to: {}", addr);
+        let data = self.version_message().await?;
+        self.send_data(addr, &data).await
+    }
 
+    async fn version_message(&self) -> Result<Vec<u8>> {
         let data = Versionmsg {
             addr_from: self.node_address.clone(),
             best_height: self.get_best_height().await?,
             version: VERSION,
+            capabilities: self.capabilities,
+            timestamp: clock::now_millis() as u64,
+            checkpoint: self.latest_checkpoint().await?,
+            genesis: self.genesis_hash().await?.unwrap_or_default(),
+            best_work: self.get_best_work().await?,
         };
-
-        let data = bincode::serialize(&(cmd_to_bytes("version"), data))?;
-        //println!("🟢 Serialized data, now sending...");
-
-        let result = self.send_data(addr, &data).await;
-        //println!("✅ Finished send_version for {}", addr);
-
-        result
+        encode("version", &data)
     }
 
     async fn send_get_blocks(&self, addr: &str) -> Result<()> {
         println!("send get blocks message to: {}", addr);
+        let data = self.get_blocks_message()?;
+        self.send_data(addr, &data).await
+    }
+
+    fn get_blocks_message(&self) -> Result<Vec<u8>> {
         let data = GetBlockmsg {
             addr_from: self.node_address.clone(),
         };
-        let data = bincode::serialize(&(cmd_to_bytes("getblocks"), data))?;
-        self.send_data(addr, &data).await
+        encode("getblocks", &data)
     }
 
     async fn send_get_data(&self, addr: &str, kind: &str, id:&str) -> Result<()> {
@@ -338,48 +770,150 @@ impl Server {
             kind: kind.to_string(),
             id: id.to_string(),
         };
-        let data = bincode::serialize(&(cmd_to_bytes("getdata"), data))?;
+        let data = encode("getdata", &data)?;
         self.send_data(addr, &data).await
 
     }
 
+    async fn send_not_found(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+        println!("send not found message to: {} kind: {} id: {}", addr, kind, id);
+        let data = NotFoundmsg {
+            addr_from: self.node_address.clone(),
+            kind: kind.to_string(),
+            id: id.to_string(),
+        };
+        let data = encode("notfound", &data)?;
+        self.send_data(addr, &data).await
+    }
+
     // sends known_nodes to addr
     async fn send_addr(&self, addr: &str) -> Result<()> {
         println!("Send address info to: {}", addr);
-        let nodes = self.get_known_nodes().await;
-        let data = bincode::serialize(&(cmd_to_bytes("addr"), nodes))?;
+        let data = Addrmsg {
+            addr_from: self.node_address.clone(),
+            addr_list: self.get_known_nodes().await.into_keys().collect(),
+        };
+        let data = encode("addr", &data)?;
         self.send_data(addr, &data).await
     }
     
-    // Sends a transaction to every known_node
-    pub async fn send_transaction(&self, tx: &Transaction) -> Result<()> {
-        println!("Hushhush");
+    // Stores an operator checkpoint and sends it to every known node
+    /// Asks `addr` for its UTXO snapshot. What comes back is checked against the snapshot trust settings
+    pub async fn request_snapshot(&self, addr: &str) -> Result<()> {
+        println!("request snapshot from: {}", addr);
+        self.inner.write().await.snapshot_download = Some(SnapshotDownload::new(addr));
+        let data = GetSnapshotmsg { addr_from: self.node_address.clone() };
+        self.send_data(addr, &encode("getsnapshot", &data)?).await
+    }
 
-        // There are no nodes. Not even localhost.
-        for node in &self.get_known_nodes().await {
-            println!("Known_node: {}", node.0);
+    // Rebuilds the served snapshot once the tip moved SNAPSHOT_INTERVAL blocks past it
+    pub async fn refresh_snapshot(&self) -> Result<()> {
+        if !self.serve_snapshots {
+            return Ok(());
+        }
+        let best_height = self.get_best_height().await?;
+        let current = self.inner.read().await.snapshot.as_ref().map(|s| s.tip_height());
+        if current.is_some_and(|height| best_height - height < SNAPSHOT_INTERVAL) {
+            return Ok(());
+        }
+        if best_height <= SNAPSHOT_RECENT_BLOCKS as i32 {
+            return Ok(());
         }
 
-        let futures: FuturesUnordered<_> = self.get_known_nodes().await
-            .into_iter()
-            .map(|node| self.send_tx(node.to_owned().0, &tx)) // Pass owned String
-            .collect();
+        let snapshot = {
+            let inner = self.inner.read().await;
+            let utxo = inner.utxo.read().await;
+            let blockchain = utxo.blockchain.read().await;
+            Snapshot::build(&blockchain, SNAPSHOT_RECENT_BLOCKS, self.snapshot_signing_key.as_deref())?
+        };
+        println!("Snapshot at height {}, root {}", snapshot.manifest.height, snapshot.manifest.root);
+        self.inner.write().await.snapshot = Some(Arc::new(snapshot));
+        Ok(())
+    }
 
-        futures.for_each_concurrent(None, |result| async {
-            if let Err(e) = result {
-                println!("Failed to send transaction: {}", e);
-            }
-        }).await;
+    pub async fn broadcast_checkpoint(&self, checkpoint: &Checkpoint) -> Result<BroadcastReport> {
+        self.inner.read().await
+            .utxo.read().await
+            .blockchain.read().await.add_checkpoint(checkpoint)?;
 
-        Ok(())
+        let peers: Vec<String> = self.get_known_nodes().await.into_keys().collect();
+        let data = self.checkpoint_message(checkpoint)?;
+        Ok(self.broadcast(peers, data).await)
+    }
+
+    fn checkpoint_message(&self, checkpoint: &Checkpoint) -> Result<Vec<u8>> {
+        let data = OpCheckpointmsg {
+            addr_from: self.node_address.clone(),
+            checkpoint: checkpoint.clone(),
+        };
+        encode("opcheckpoint", &data)
+    }
+
+    /// Mines a block holding only the coinbase and announces it, like handle_tx does for mempool
+    /// transactions. Meant for development networks, callers decide whether it's allowed.
+    pub async fn mine_empty_block(&self) -> Result<Block> {
+        if self.mining_address.is_empty() {
+            return Err(format_err!("This node has no mining address"));
+        }
+        if self.disk_full().await {
+            return Err(format_err!("Disk full, mining is paused"));
+        }
+
+        let height = self.get_best_height().await? + 1;
+        let cbtx = Transaction::new_coinbase(self.mining_address.clone(), String::new(), height)?;
+        let new_block = self.mine_block(vec![cbtx]).await?;
+        self.block_connected(&new_block);
+        self.connect_mined(&new_block).await?;
+
+        let peers: Vec<String> = self.get_known_nodes().await.into_keys().collect();
+        self.broadcast_inv(peers, "block", vec![new_block.get_hash()]).await?;
+
+        Ok(new_block)
+    }
+
+    // Sends a transaction to every known_node. Peers that sign receipts are asked for one
+    pub async fn send_transaction(&self, tx: &Transaction) -> Result<BroadcastReport> {
+        let known_nodes = self.get_known_nodes().await;
+        let asks_ack = |node: &KnownNode| self.tx_receipts && node.capabilities.contains(Capabilities::TX_RECEIPTS);
+        let acking: Vec<String> = known_nodes.iter().filter(|(_, node)| asks_ack(node)).map(|(addr, _)| addr.clone()).collect();
+        let peers: Vec<String> = known_nodes.iter().filter(|(_, node)| !asks_ack(node)).map(|(addr, _)| addr.clone()).collect();
+        println!("send tx {} to {} known nodes", &tx.id, acking.len() + peers.len());
+
+        if !acking.is_empty() {
+            self.inner.write().await.awaiting_acks.insert(tx.id.clone());
+        }
+        let acked = self.broadcast(acking, self.tx_message_with(tx, "acktx")?).await;
+        let plain = self.broadcast(peers, self.tx_message(tx)?).await;
+        let report = BroadcastReport {
+            delivered: acked.delivered + plain.delivered,
+            failed: acked.failed + plain.failed,
+        };
+        println!("Transaction {} delivered to {} peers, {} failed", &tx.id, report.delivered, report.failed);
+
+        Ok(report)
+    }
+
+    /// A transaction from elsewhere, raw hex pasted in say: it has to pass the mempool rules
+    /// (a TxRejectReason otherwise) and is then sent like our own
+    pub async fn broadcast_raw_transaction(&self, tx: &Transaction) -> Result<BroadcastReport> {
+        self.admit_to_mempool(tx).await?;
+        self.send_transaction(tx).await
     }
 
     // ---------------------------------- HANDLES ----------------------------------
 
-    async fn handle_addr(&mut self, msg: Vec<String>) -> Result<()> {
+    async fn handle_addr(&self, msg: Addrmsg) -> Result<()> {
         println!("receive address msg: {:#?}", msg);
-        for node in msg {
-            let _ = self.add_peer(node).await;
+        let reported: Vec<String> = msg.addr_list.into_iter()
+            .filter(|node| *node != self.node_address && *node != msg.addr_from)
+            .collect();
+
+        let mut inner = self.inner.write().await;
+        for node in &reported {
+            inner.known_nodes.entry(node.clone()).or_default(); // new nodes are contacted later
+        }
+        if let Some(sender) = inner.known_nodes.get_mut(&msg.addr_from) {
+            sender.reported_peers = reported;
         }
         Ok(())
     }
@@ -387,21 +921,186 @@ impl Server {
     // called when a block gets sent to server
     async fn handle_block(&self, msg: Blockmsg) -> Result<()> {
         println!("receive block msg: {}, {}", msg.addr_from, msg.block.get_hash());
-        self.add_block(msg.block).await?;
+        let block = msg.block;
+        // Not the peer's fault, it isn't penalized
+        if self.disk_full().await {
+            self.replace_in_transit(Vec::new()).await;
+            return Err(format_err!("Disk full, block {} refused", block.get_hash()));
+        }
+        match self.add_block(block.clone()).await {
+            Ok(()) => {
+                self.block_connected(&block);
+                self.connect_orphans(&block.get_hash()).await;
+            }
+            Err(e) => match e.downcast_ref::<BlockRejectReason>() {
+                Some(BlockRejectReason::UnknownParent { .. }) => self.add_orphan(block.clone()).await,
+                Some(reason) => {
+                    println!("Rejected block {} from {}: {}", block.get_hash(), msg.addr_from, reason);
+                    self.penalize_peer(&msg.addr_from, block_reject_score(reason), &reason.to_string()).await;
+                    return Err(e);
+                }
+                None => return Err(e),
+            },
+        }
 
         let mut in_transit = self.get_in_transit().await;
-        if in_transit.len() > 0 {
+        if !in_transit.is_empty() {
             let block_hash = &in_transit[0];
             self.send_get_data(&msg.addr_from, "block", block_hash).await?;
             in_transit.remove(0);
             self.replace_in_transit(in_transit).await;
-        } else {
-            self.utxo_reindex().await?;
         }
 
         Ok(())
     }
 
+    async fn handle_opcheckpoint(&self, msg: OpCheckpointmsg) -> Result<()> {
+        let checkpoint = msg.checkpoint;
+        println!("receive checkpoint msg: {}, height {} = {}", msg.addr_from, checkpoint.height, checkpoint.hash);
+
+        let operator_key = match &self.operator_key {
+            Some(key) => key,
+            None => {
+                println!("No operator key configured, ignoring checkpoint");
+                return Ok(());
+            }
+        };
+
+        if !checkpoint.verify(operator_key) {
+            self.penalize_peer(&msg.addr_from, 50, "checkpoint with an invalid operator signature").await;
+            return Err(format_err!("Invalid checkpoint signature from {}", msg.addr_from));
+        }
+
+        let is_new = self.inner.read().await
+            .utxo.read().await
+            .blockchain.read().await.add_checkpoint(&checkpoint)?;
+        if !is_new {
+            return Ok(());
+        }
+
+        self.emit(NodeEvent::CheckpointReceived {
+            height: checkpoint.height,
+            hash: checkpoint.hash.clone(),
+        }).await;
+
+        // Relay so it reaches nodes the operator doesn't know about
+        let peers: Vec<String> = self.get_known_nodes().await
+            .into_keys()
+            .filter(|peer| *peer != msg.addr_from)
+            .collect();
+        let data = self.checkpoint_message(&checkpoint)?;
+        self.broadcast(peers, data).await;
+
+        Ok(())
+    }
+
+    async fn handle_get_snapshot(&self, msg: GetSnapshotmsg) -> Result<()> {
+        println!("receive getsnapshot msg: {}", msg.addr_from);
+        if !self.serve_snapshots {
+            return Ok(());
+        }
+        self.refresh_snapshot().await?;
+        let snapshot = match self.inner.read().await.snapshot.clone() {
+            Some(snapshot) => snapshot,
+            None => return Err(format_err!("No snapshot to serve yet")),
+        };
+
+        let manifest = Snapshotmsg {
+            addr_from: self.node_address.clone(),
+            manifest: snapshot.manifest.clone(),
+            recent_blocks: snapshot.recent_blocks.clone(),
+            state_digest: Some(snapshot.state_digest.clone()),
+        };
+        self.send_data(&msg.addr_from, &encode("snapshot", &manifest)?).await?;
+
+        for (index, entries) in snapshot.chunks.iter().enumerate() {
+            let chunk = SnapChunkmsg {
+                addr_from: self.node_address.clone(),
+                root: snapshot.manifest.root.clone(),
+                index: index as u32,
+                entries: entries.clone(),
+            };
+            self.send_data(&msg.addr_from, &encode("snapchunk", &chunk)?).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_snapshot(&self, msg: Snapshotmsg) -> Result<()> {
+        println!("receive snapshot msg: {}, height {} root {}", msg.addr_from, msg.manifest.height, msg.manifest.root);
+        {
+            let mut inner = self.inner.write().await;
+            let download = match &mut inner.snapshot_download {
+                Some(download) if download.peer == msg.addr_from && download.manifest.is_none() => download,
+                _ => return Ok(()), // not asked for
+            };
+            if let Err(e) = self.snapshot_anchor.check(&msg.manifest) {
+                inner.snapshot_download = None;
+                drop(inner);
+                self.penalize_peer(&msg.addr_from, 50, &e.to_string()).await;
+                return Err(e);
+            }
+            download.manifest = Some(msg.manifest);
+            download.recent_blocks = msg.recent_blocks;
+            download.state_digest = msg.state_digest;
+        }
+        self.finish_snapshot().await
+    }
+
+    async fn handle_snap_chunk(&self, msg: SnapChunkmsg) -> Result<()> {
+        {
+            let mut inner = self.inner.write().await;
+            let download = match &mut inner.snapshot_download {
+                Some(download) if download.peer == msg.addr_from => download,
+                _ => return Ok(()),
+            };
+            if download.manifest.as_ref().is_some_and(|m| m.root != msg.root) {
+                return Ok(()); // left over from another snapshot
+            }
+            download.chunks.insert(msg.index, msg.entries);
+        }
+        self.finish_snapshot().await
+    }
+
+    // Applies the snapshot being downloaded once every chunk arrived
+    async fn finish_snapshot(&self) -> Result<()> {
+        let (base_hash, entries, blocks, digest) = {
+            let inner = self.inner.read().await;
+            let download = match &inner.snapshot_download {
+                Some(download) => download,
+                None => return Ok(()),
+            };
+            match download.complete() {
+                Ok(Some((manifest, entries))) => {
+                    (manifest.block_hash.clone(), entries, download.recent_blocks.clone(), download.state_digest.clone())
+                }
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    let peer = download.peer.clone();
+                    drop(inner);
+                    self.inner.write().await.snapshot_download = None;
+                    self.penalize_peer(&peer, 50, &e.to_string()).await;
+                    return Err(e);
+                }
+            }
+        };
+        self.inner.write().await.snapshot_download = None;
+
+        let tip = blocks.last().cloned();
+        self.inner.read().await
+            .utxo.read().await
+            .blockchain.write().await
+            .apply_snapshot(&base_hash, entries, blocks)?;
+        self.utxo_reindex().await?;
+        if let Some(tip) = tip {
+            println!("Synced from snapshot, height {}", tip.get_height());
+            if let Some(digest) = digest {
+                println!("Snapshot state digest {}", digest);
+            }
+            self.block_connected(&tip);
+        }
+        Ok(())
+    }
+
     async fn handle_get_blocks(&self, msg: GetBlockmsg) -> Result<()> {
         println!("receive get blocks msg: {:#?}", msg);
         let block_hashes = self.get_block_hashes().await;
@@ -419,73 +1118,153 @@ impl Server {
     async fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
         println!("receive get data msg: {:#?}", msg);
         if msg.kind == "block" {
-            let block = self.get_block(&msg.id).await?;
+            let block = match self.get_block(&msg.id).await {
+                Ok(block) => block,
+                // Pruned, or never had it
+                Err(e) if e.downcast_ref::<LookupError>().is_some() => {
+                    return self.send_not_found(&msg.addr_from, &msg.kind, &msg.id).await;
+                }
+                Err(e) => return Err(e),
+            };
+            // New blocks are still relayed when historical ones aren't served
+            if !self.serve_historical_blocks && block.get_height() < self.get_best_height().await? - RECENT_BLOCK_DEPTH {
+                return self.send_not_found(&msg.addr_from, &msg.kind, &msg.id).await;
+            }
             self.send_block(&msg.addr_from, &block).await?;
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).await.unwrap();
+            let Some(tx) = self.get_mempool_tx(&msg.id).await else {
+                return self.send_not_found(&msg.addr_from, &msg.kind, &msg.id).await;
+            };
             self.send_tx(msg.addr_from, &tx).await?;
         }
         Ok(())
     }
 
-    async fn handle_version(&mut self, msg: Versionmsg) -> Result<()> {
+    // The block won't come from this peer, don't wait for it
+    async fn handle_not_found(&self, msg: NotFoundmsg) {
+        println!("receive not found msg: {} {} {}", msg.addr_from, msg.kind, msg.id);
+        if msg.kind == "block" {
+            self.inner.write().await.blocks_in_transit.retain(|hash| *hash != msg.id);
+        }
+    }
+
+    async fn handle_version(&self, msg: Versionmsg) -> Result<()> {
         println!("receive version msg: {:#?}", msg);
 
-        let my_best_height = self.get_best_height().await?;
+        // A peer with another genesis block is on another network, none of its blocks or
+        // peers are of use. It's dropped instead of added
+        if !msg.genesis.is_empty() && self.genesis_hash().await?.is_some_and(|ours| ours != msg.genesis) {
+            println!("{} starts from genesis block {}, it's on another network", msg.addr_from, msg.genesis);
+            self.penalize_peer(&msg.addr_from, BAN_SCORE, &format!("On another network, genesis block {}", msg.genesis)).await;
+            return Ok(());
+        }
 
-        if my_best_height < msg.best_height {
-            println!("my_best_height < msg.best_height");
-            let _ = self.send_get_blocks(&msg.addr_from).await;
-        } else if my_best_height > msg.best_height {
+        if !self.node_is_known(&msg.addr_from).await {
+            let _ = self.add_peer(msg.addr_from.clone()).await;
+        }
+        if let Some(node) = self.inner.write().await.known_nodes.get_mut(&msg.addr_from) {
+            node.capabilities = msg.capabilities;
+            node.version = Some(msg.version);
+        }
+        if msg.timestamp > 0 {
+            self.inner.write().await.clock.add_sample(&msg.addr_from, msg.timestamp as u128, clock::now_millis());
+        }
+
+        // A peer pinning another block at one of our checkpoints is on another chain
+        if let Some((height, hash)) = &msg.checkpoint {
+            let ours = self.inner.read().await.utxo.read().await.blockchain.read().await.get_checkpoints()?;
+            if ours.get(height).is_some_and(|pinned| pinned != hash) {
+                println!("{} pins another block at checkpoint height {}, not syncing from it", msg.addr_from, height);
+                self.send_addr(&msg.addr_from).await?;
+                return Ok(());
+            }
+        }
+
+        // Chains are compared by work, or by height with peers that don't advertise it
+        let ordering = if msg.best_work > 0 {
+            self.get_best_work().await?.cmp(&msg.best_work)
+        } else {
+            self.get_best_height().await?.cmp(&msg.best_height)
+        };
+
+        if ordering.is_lt() && !self.disk_full().await {
+            println!("{} has a heavier chain", msg.addr_from);
+            let _ = self.send_get_blocks(&msg.addr_from).await;
+        } else if ordering.is_gt() {
             println!("man lielaks");
             let _ = self.send_version(&msg.addr_from).await;
         }
 
         self.send_addr(&msg.addr_from).await?;
-
-        if !self.node_is_known(&msg.addr_from).await {
-            let _ = self.add_peer(msg.addr_from).await;
-        }
         Ok(())
     }
 
     // How to handle a received Tx msg
-    async fn handle_tx(&self, msg: Txmsg) -> Result<()> {
+    // `ack` answers with a TxAck once the transaction is in the mempool
+    async fn handle_tx(&self, msg: Txmsg, ack: bool) -> Result<()> {
         println!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
 
-        self.insert_mempool(msg.transaction.clone()).await;
+        if self.inner.read().await.mempool.contains_key(&msg.transaction.id) {
+            if ack {
+                self.send_tx_ack(&msg.addr_from, &msg.transaction.id).await;
+            }
+            return Ok(()); // already admitted and relayed
+        }
+        if let Err(e) = self.admit_to_mempool(&msg.transaction).await {
+            if let Some(reason) = e.downcast_ref::<TxRejectReason>() {
+                println!("Rejected tx {} from {}: {}", &msg.transaction.id, msg.addr_from, reason);
+                self.penalize_peer(&msg.addr_from, reject_score(reason), &reason.to_string()).await;
+            }
+            return Err(e);
+        }
+        if ack {
+            self.send_tx_ack(&msg.addr_from, &msg.transaction.id).await;
+        }
 
         let known_nodes = self.get_known_nodes().await;
 
         if self.node_address == KNOWN_NODE1 {
             // if the node is KNOWN_NODE1 then it broadcasts the transaction to all other known nodes except the sender
-            for node in known_nodes {
-                if node.0 != self.node_address && node.0 != msg.addr_from {
-                    self.send_inv(&node.0, "tx", vec![msg.transaction.id.clone()]).await?;
-                }
-            }
+            let peers: Vec<String> = known_nodes
+                .into_keys()
+                .filter(|node| *node != msg.addr_from)
+                .collect();
+            self.broadcast_inv(peers, "tx", vec![msg.transaction.id.clone()]).await?;
         } else {
+            // Another handler mining meanwhile takes what's in the mempool, this one waits for
+            // it and mines what's left
+            let mining = self.mining.lock().await;
             let mut mempool = self.get_mempool().await;
             println!("Current mempool: {:#?}", &mempool);
+            let mut mined = Vec::new();
 
             // if there are txs in mempool and this node is a miner node
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
+            if !mempool.is_empty() && !self.mining_address.is_empty() && !self.disk_full().await {
                 loop {
                     let mut txs: Vec<Transaction> = Vec::new();
 
-                    // verify txs in mempool
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx).await? {
-                            txs.push(tx.clone());
+                    // verify txs in mempool, best paying packages first. Parents come before their
+                    // children, which are verified against the ones taken. What doesn't verify,
+                    // like the child of a parent that didn't, stays in the mempool
+                    for txid in self.block_order(&mempool).await? {
+                        let tx = &mempool[&txid];
+                        match self.verify_tx(tx, &txs).await {
+                            Ok(true) => txs.push(tx.clone()),
+                            Ok(false) => println!("Not mining tx {}: invalid", txid),
+                            Err(e) => println!("Not mining tx {}: {}", txid, e),
                         }
                     }
+                    // as many as fit, the rest waits for the next block of this loop
+                    let mut txs = mempool::fill_block(txs, MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE)?;
 
                     if txs.is_empty() {
-                        return Ok(());
+                        break;
                     }
 
-                    // create new coinbase with miner node as recipient and push at the end of txs
-                    let cbtx = Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
+                    // create new coinbase with miner node as recipient, claiming the fees, and push at the end of txs
+                    let fees = self.block_fees(&txs).await?;
+                    let height = self.get_best_height().await? + 1;
+                    let cbtx = Transaction::new_coinbase_with_fees(self.mining_address.clone(), String::new(), height, fees as i32)?;
                     txs.push(cbtx);
 
 
@@ -493,39 +1272,70 @@ impl Server {
                         mempool.remove(&tx.id);
                     }
 
-                    // creates new block and reindexes node's utxo
+                    // creates new block and brings node's utxo up to it
                     let new_block = self.mine_block(txs).await?;
-                    self.utxo_reindex().await?;
+                    self.block_connected(&new_block);
+                    self.connect_mined(&new_block).await?;
+                    self.reorganize_mempool(&[], std::slice::from_ref(&new_block)).await;
+                    mined.push(new_block.get_hash());
 
-                    // Broadcasts the new block to other known nodes.
-                    for node in self.get_known_nodes().await {
-                        if node.0 != self.node_address {
-                            self.send_inv(&node.0, "block", vec![new_block.get_hash()]).await?;
-                        }
-                    }
-
-                    if mempool.len() == 0 {
+                    if mempool.is_empty() {
                         break;
                     }
                 }
+            }
+            drop(mining);
 
-                // clears mempool
-                self.clear_mempool().await;
+            // Broadcasts the new blocks to other known nodes.
+            for hash in mined {
+                let peers: Vec<String> = self.get_known_nodes().await.into_keys().collect();
+                self.broadcast_inv(peers, "block", vec![hash]).await?;
             }
         }
 
         Ok(())
     }
 
+    async fn handle_tx_ack(&self, msg: TxAckmsg) {
+        println!("receive tx ack msg: {} {}", msg.addr_from, &msg.ack.txid);
+        if !self.inner.read().await.awaiting_acks.contains(&msg.ack.txid) {
+            println!("Ignoring an ack from {} for tx {}, which we didn't ask for", msg.addr_from, &msg.ack.txid);
+            return;
+        }
+        if !msg.ack.verify() {
+            self.penalize_peer(&msg.addr_from, 20, "ack with a bad signature").await;
+            return;
+        }
+        self.emit(NodeEvent::TxAcknowledged { ack: msg.ack }).await;
+    }
+
     async fn handle_inv(&self, msg: Invmsg) -> Result<()> {
         println!("receive inv msg: {:#?}", msg);
 
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
+            if self.disk_full().await {
+                return Ok(());
+            }
+            // Blocks we have aren't fetched again, so a sync that was cut off resumes where
+            // it stopped
+            let wanted: Vec<String> = {
+                let inner = self.inner.read().await;
+                let utxo = inner.utxo.read().await;
+                let blockchain = utxo.blockchain.read().await;
+                msg.items.iter()
+                    .filter(|hash| !blockchain.has_block(hash).unwrap_or(false))
+                    .cloned()
+                    .collect()
+            };
+
+            // Hashes are listed newest first, parents are fetched before their children
+            let Some(block_hash) = wanted.last() else {
+                return Ok(());
+            };
             self.send_get_data(&msg.addr_from, "block", block_hash).await?;
 
             let mut new_in_transit = Vec::new();
-            for b in &msg.items {
+            for b in wanted.iter().rev() {
                 if b != block_hash {
                     new_in_transit.push(b.clone());
                 }
@@ -548,6 +1358,24 @@ impl Server {
 
     // ------------- help functions -------------
 
+    async fn genesis_hash(&self) -> Result<Option<String>> {
+        self.inner.read().await
+             .utxo.read().await
+             .blockchain.read().await.genesis_hash()
+    }
+
+    async fn latest_checkpoint(&self) -> Result<Option<(i32, String)>> {
+        self.inner.read().await
+             .utxo.read().await
+             .blockchain.read().await.latest_checkpoint()
+    }
+
+    pub async fn get_best_work(&self) -> Result<u128> {
+        self.inner.read().await
+             .utxo.read().await
+             .blockchain.read().await.get_best_work()
+    }
+
     pub async fn get_best_height(&self) -> Result<i32> {
         self.inner.read().await
              .utxo.read().await
@@ -555,22 +1383,74 @@ impl Server {
     }
 
     async fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.read().await.mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
-        }
+        self.inner.read().await.mempool.get(addr).cloned()
     }
 
     async fn get_mempool(&self) -> HashMap<String, Transaction> {
         self.inner.read().await.mempool.clone()
     }
 
-    async fn insert_mempool(&self, tx: Transaction) {
-        self.inner.write().await.mempool.insert(tx.id.clone(), tx);
+    pub async fn mempool_transactions(&self) -> Vec<Transaction> {
+        self.inner.read().await.mempool.values().cloned().collect()
+    }
+
+    /// Unconfirmed ancestry, package size and fee of a mempool transaction
+    pub async fn package_stats(&self, txid: &str) -> Result<PackageStats> {
+        let mut inner = self.inner.write().await;
+        if let Some(stats) = inner.package_stats.get(txid) {
+            return Ok(stats.clone());
+        }
+
+        let stats = {
+            let utxo = inner.utxo.read().await;
+            let blockchain = utxo.blockchain.read().await;
+            mempool::package_stats(txid, &inner.mempool, &|id: &str, vout: i32| confirmed_value(&blockchain, id, vout))?
+        };
+        inner.package_stats.insert(txid.to_string(), stats.clone());
+        Ok(stats)
+    }
+
+    // Block template order of `txs`
+    async fn block_order(&self, txs: &HashMap<String, Transaction>) -> Result<Vec<String>> {
+        Ok(mempool::block_order(txs, &self.packages_of(txs).await))
+    }
+
+    // Package stats of `txs`, computed like the detail popup's
+    async fn packages_of(&self, txs: &HashMap<String, Transaction>) -> HashMap<String, PackageStats> {
+        let inner = self.inner.read().await;
+        let utxo = inner.utxo.read().await;
+        let blockchain = utxo.blockchain.read().await;
+
+        let confirmed = |id: &str, vout: i32| confirmed_value(&blockchain, id, vout);
+        txs.keys()
+            .filter_map(|id| Some((id.clone(), mempool::package_stats(id, txs, &confirmed).ok()?)))
+            .collect()
+    }
+
+    /// Blockchain::estimate_fee, raised above the mempool's pressure: one more than the rate
+    /// at which `target_blocks` blocks fill up with better paying transactions
+    pub async fn estimate_fee(&self, target_blocks: u32) -> Result<i64> {
+        let from_chain = {
+            let inner = self.inner.read().await;
+            let utxo = inner.utxo.read().await;
+            let blockchain = utxo.blockchain.read().await;
+            blockchain.estimate_fee(target_blocks)
+        };
+        let txs = self.inner.read().await.mempool.clone();
+        let stats = self.packages_of(&txs).await;
+        let pressure = mempool::pressure_fee_rate(&txs, &stats, target_blocks, MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE)?;
+        Ok(pressure.map_or(from_chain, |rate| from_chain.max(rate + 1)))
     }
 
-    async fn clear_mempool(&self) {
-        self.inner.write().await.mempool.clear()
+    // Inputs minus outputs of `txs`, which may spend each other
+    async fn block_fees(&self, txs: &[Transaction]) -> Result<i64> {
+        let inner = self.inner.read().await;
+        let utxo = inner.utxo.read().await;
+        let blockchain = utxo.blockchain.read().await;
+
+        let in_block: HashMap<String, Transaction> = txs.iter().map(|tx| (tx.id.clone(), tx.clone())).collect();
+        let confirmed = |id: &str, vout: i32| confirmed_value(&blockchain, id, vout);
+        txs.iter().map(|tx| mempool::fee(tx, &in_block, &confirmed)).sum()
     }
 
     async fn get_block(&self, block_hash: &str) -> Result<Block> {
@@ -579,16 +1459,156 @@ impl Server {
              .blockchain.read().await.get_block(block_hash)
     }
 
-    async fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
+    // Checks `tx` and adds it to the mempool under one lock, so of two transactions spending
+    // the same output only the first gets in, unless the second pays enough to replace it.
+    // Rejections come back as TxRejectReason
+    async fn admit_to_mempool(&self, tx: &Transaction) -> Result<()> {
+        let replaced = {
+            let mut inner = self.inner.write().await;
+            let replaced = {
+                let utxo = inner.utxo.read().await;
+                let blockchain = utxo.blockchain.read().await;
+                let confirmed_unspent = |txid: &str, vout: i32| blockchain.find_unspent(txid, vout);
+                let height = blockchain.get_best_height()? + 1;
+                mempool::check_admission(tx, &inner.mempool, &inner.mempool_spends, self.dust_threshold, height, &confirmed_unspent)?
+            };
+            remove_from_mempool(&mut inner, |txid, _| replaced.contains(txid));
+            add_to_mempool(&mut inner, tx.clone(), clock::now_millis());
+            replaced
+        };
+
+        for txid in replaced {
+            println!("Transaction {} replaced by {}", txid, tx.id);
+            self.emit(NodeEvent::MempoolEvicted { txid, reason: EvictionReason::Replaced }).await;
+        }
+        Ok(())
+    }
+
+    // Drops expired transactions and, while over the size limit, the lowest fee rates,
+    // see mempool::evictions. `now` is ms since the epoch
+    async fn evict_mempool(&self, now: u128) -> Result<Vec<(String, EvictionReason)>> {
+        let evicted = {
+            let mut inner = self.inner.write().await;
+            let evicted = {
+                let utxo = inner.utxo.read().await;
+                let blockchain = utxo.blockchain.read().await;
+                let confirmed = |id: &str, vout: i32| confirmed_value(&blockchain, id, vout);
+                let stats: HashMap<String, PackageStats> = inner.mempool.keys()
+                    .filter_map(|id| Some((id.clone(), mempool::package_stats(id, &inner.mempool, &confirmed).ok()?)))
+                    .collect();
+                mempool::evictions(&inner.mempool, &inner.mempool_added, &stats, now, self.mempool_ttl, self.max_mempool_size)?
+            };
+            let txids: HashSet<&String> = evicted.iter().map(|(txid, _)| txid).collect();
+            remove_from_mempool(&mut inner, |txid, _| txids.contains(txid));
+            evicted
+        };
+
+        for (txid, reason) in &evicted {
+            println!("Evicted transaction {} from the mempool: {}", txid, reason);
+            self.emit(NodeEvent::MempoolEvicted { txid: txid.clone(), reason: *reason }).await;
+        }
+        Ok(evicted)
+    }
+
+    // Whether `tx` can be mined after `in_block`, see Blockchain::verify_in_block
+    async fn verify_tx(&self, tx: &Transaction, in_block: &[Transaction]) -> Result<bool> {
         self.inner.read().await
             .utxo.read().await
-            .blockchain.read().await.verify_transacton(tx)
+            .blockchain.read().await.verify_in_block(tx, in_block)
+    }
+
+    // Drops the peer and records why in the peer history
+    async fn remove_node(&self, addr: &str, reason: RemovalReason) {
+        println!("Removing Node: {} ({})", &addr, reason);
+        let (removed, utxo) = {
+            let mut guard = self.inner.write().await;
+            (guard.known_nodes.remove(addr).is_some(), Arc::clone(&guard.utxo))
+        };
+        if !removed {
+            return;
+        }
+
+        let entry = PeerHistoryEntry { timestamp: clock::now_millis(), address: addr.to_string(), reason: reason.clone() };
+        if let Err(e) = utxo.read().await.blockchain.read().await.record_peer_removal(&entry) {
+            println!("Failed to record the removal of {}: {}", addr, e);
+        }
+        self.emit(NodeEvent::PeerRemoved { address: addr.to_string(), reason }).await;
+    }
+
+    /// Opens contact with a node learned from gossip by sending it our version
+    pub async fn connect_peer(&self, addr: &str) -> Result<()> {
+        self.inner.write().await.known_nodes.entry(addr.to_string()).or_default();
+        self.send_version(addr).await
+    }
+
+    /// Removes a peer on the user's request
+    pub async fn disconnect_peer(&self, addr: &str) {
+        self.remove_node(addr, RemovalReason::Manual).await;
+    }
+
+    /// Average upload and download rates per peer over the last few seconds
+    #[cfg(feature = "chaos")]
+    pub fn chaos(&self) -> &Chaos {
+        &self.chaos
+    }
+
+    pub async fn peer_throughput(&self) -> HashMap<String, Throughput> {
+        self.inner.read().await.traffic.throughput()
+    }
+
+    /// Whether blocks or a snapshot are being downloaded
+    pub async fn is_syncing(&self) -> bool {
+        let inner = self.inner.read().await;
+        !inner.blocks_in_transit.is_empty() || inner.snapshot_download.is_some()
+    }
+
+    /// Databases, listener, peers and clock, see health
+    pub async fn healthcheck(&self) -> HealthReport {
+        let (utxo, last_peer_message, median_offset, disk_level, disk_free) = {
+            let inner = self.inner.read().await;
+            (Arc::clone(&inner.utxo), inner.last_peer_message, inner.clock.median_offset(), inner.disk.level(), inner.disk.free())
+        };
+        let utxo = utxo.read().await;
+        let blocks = health::check_block_db(&*utxo.blockchain.read().await);
+
+        HealthReport {
+            checks: vec![
+                blocks,
+                health::check_utxo_db(&utxo),
+                health::check_listener(self.listening.load(Ordering::Relaxed), &self.node_address),
+                health::check_peers(last_peer_message.map(|at| at.elapsed()), self.health_peer_window),
+                health::check_clock(median_offset),
+                health::check_disk(disk_level, disk_free),
+            ],
+        }
     }
 
-    async fn remove_node(&self, addr: &str) {
-        println!("Removing Node: {}", &addr);
-        self.inner.write().await.known_nodes.remove(addr);
-        println!("Successful removal");
+    /// Removed and banned peers, newest first
+    pub async fn peer_history(&self) -> Result<Vec<PeerHistoryEntry>> {
+        let utxo = Arc::clone(&self.inner.read().await.utxo);
+        let history = utxo.read().await.blockchain.read().await.get_peer_history();
+        history
+    }
+
+    // Adds to the peer's misbehavior score and drops it once it reaches BAN_SCORE
+    async fn penalize_peer(&self, addr: &str, score: u32, reason: &str) {
+        println!("Peer {} misbehaved (+{}): {}", addr, score, reason);
+
+        let ban = {
+            let mut guard = self.inner.write().await;
+            match guard.known_nodes.get_mut(addr) {
+                Some(node) => {
+                    node.misbehavior_score += score;
+                    node.misbehavior_score >= BAN_SCORE
+                }
+                None => false,
+            }
+        };
+
+        if ban {
+            println!("{} reached the ban score", addr);
+            self.remove_node(addr, RemovalReason::Banned(reason.to_string())).await;
+        }
     }
 
     /*async fn add_nodes(&self, addr: &str) {
@@ -600,7 +1620,7 @@ impl Server {
     }
 
     async fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.read().await.known_nodes.get(addr).is_some()
+        self.inner.read().await.known_nodes.contains_key(addr)
     }
 
     //
@@ -613,10 +1633,154 @@ impl Server {
         self.inner.read().await.blocks_in_transit.clone()
     }
 
+    // Stores the block and keeps the UTXO set on the best chain. The mempool drops what the
+    // new blocks confirm or conflict with, and takes back what a reorganization abandoned
     async fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.write().await
-            .utxo.write().await
-            .blockchain.write().await.add_block(block)
+        let (outcome, followed) = {
+            let inner = self.inner.write().await;
+            let utxo = inner.utxo.write().await;
+            let mut blockchain = utxo.blockchain.write().await;
+            let outcome = blockchain.handle_potential_reorg(block.clone())?;
+            let followed = match &outcome {
+                ReorgOutcome::Stored => Ok(()),
+                ReorgOutcome::Extended => utxo.update(&block),
+                ReorgOutcome::Reorganized { disconnected, connected, .. } => utxo.reorganize(disconnected, connected, &blockchain),
+            };
+            (outcome, followed)
+        };
+        match (&followed, &outcome) {
+            (Ok(()), ReorgOutcome::Extended) => self.verify_block_connect(&block).await,
+            (Ok(()), ReorgOutcome::Reorganized { connected, .. }) => {
+                for block in connected {
+                    self.verify_block_connect(block).await;
+                }
+            }
+            (Ok(()), ReorgOutcome::Stored) => {}
+            // e.g. UtxoError::MissingUtxo, the set is rebuilt from the chain, which has what it missed
+            (Err(e), _) => {
+                println!("UTXO set couldn't follow block {}, reindexing: {}", block.get_hash(), e);
+                self.utxo_reindex().await?;
+            }
+        }
+
+        if let ReorgOutcome::Extended = outcome {
+            self.reorganize_mempool(&[], std::slice::from_ref(&block)).await;
+        }
+        if let ReorgOutcome::Reorganized { fork_height, disconnected, connected } = outcome {
+            println!("Chain reorganized at height {}: {} blocks replaced by {}", fork_height, disconnected.len(), connected.len());
+            self.reorganize_mempool(&disconnected, &connected).await;
+            // the block itself is reported by the caller
+            for block in &connected[..connected.len() - 1] {
+                self.block_connected(block);
+            }
+            self.emit(NodeEvent::ChainReorganized { fork_height, disconnected: disconnected.len(), connected: connected.len() }).await;
+        }
+        Ok(())
+    }
+
+    /// Rolls back the top `n` blocks, see `rollback_to_height`
+    pub async fn rollback(&self, n: usize) -> Result<Vec<Block>> {
+        let height = self.get_best_height().await? as i64 - n as i64;
+        self.rollback_to_height(height.clamp(-1, i32::MAX as i64) as i32).await
+    }
+
+    /// Disconnects the blocks above `height` (see `Blockchain::rollback`), rebuilds the UTXO
+    /// set and returns their transactions to the mempool when they are still valid. A
+    /// development tool, refused unless the node runs on devnet or with --allow-rollback,
+    /// and while syncing since the blocks would come right back. Every attempt goes into
+    /// the audit log
+    pub async fn rollback_to_height(&self, height: i32) -> Result<Vec<Block>> {
+        let result = self.roll_back_blocks(height).await;
+        let mut params = vec![(String::from("height"), height.to_string())];
+        if let Ok(removed) = &result {
+            params.push((String::from("blocks removed"), removed.len().to_string()));
+        }
+        self.audit.record(AuditAction::Rollback, params, AuditOutcome::from(&result));
+        result
+    }
+
+    async fn roll_back_blocks(&self, height: i32) -> Result<Vec<Block>> {
+        if !self.allow_rollback {
+            return Err(format_err!("Rolling back blocks is only allowed on devnet or with --allow-rollback"));
+        }
+        if self.is_syncing().await {
+            return Err(format_err!("The node is syncing, try again once it's done"));
+        }
+        let removed = {
+            let inner = self.inner.write().await;
+            let utxo = inner.utxo.write().await;
+            let removed = utxo.blockchain.write().await.rollback_to_height(height)?;
+            utxo.reindex().await?;
+            removed
+        };
+        let Some(lowest) = removed.last() else {
+            return Ok(removed);
+        };
+        let fork_height = lowest.get_height() - 1;
+        println!("Rolled back {} blocks to height {}", removed.len(), fork_height);
+        self.reorganize_mempool(&removed, &[]).await;
+        self.emit(NodeEvent::ChainReorganized { fork_height, disconnected: removed.len(), connected: 0 }).await;
+        Ok(removed)
+    }
+
+    // Drops what the `connected` blocks confirmed or spent from the mempool, along with the
+    // outputs it marked spent, then returns the transactions of the abandoned blocks that
+    // are still valid on the new branch
+    async fn reorganize_mempool(&self, disconnected: &[Block], connected: &[Block]) {
+        let confirmed: HashSet<&String> = connected.iter()
+            .flat_map(|block| block.get_transactions().iter().map(|tx| &tx.id))
+            .collect();
+        let spent: HashSet<(&String, i32)> = connected.iter()
+            .flat_map(|block| block.get_transactions().iter().filter(|tx| !tx.is_coinbase()))
+            .flat_map(|tx| tx.vin.iter().map(|vin| (&vin.txid, vin.vout)))
+            .collect();
+        remove_from_mempool(&mut *self.inner.write().await, |txid, tx| {
+            confirmed.contains(txid) || tx.vin.iter().any(|vin| spent.contains(&(&vin.txid, vin.vout)))
+        });
+
+        // oldest first, so parents are back before their children
+        for block in disconnected.iter().rev() {
+            for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase() && !confirmed.contains(&tx.id)) {
+                if let Err(e) = self.admit_to_mempool(tx).await {
+                    println!("Transaction {} of an abandoned block dropped: {}", tx.id, e);
+                }
+            }
+        }
+    }
+
+    // Keeps a block until its parent is added
+    async fn add_orphan(&self, block: Block) {
+        println!("Block {} arrived before its parent {}", block.get_hash(), block.get_prev_hash());
+        let orphans = &mut self.inner.write().await.orphan_blocks;
+        if orphans.iter().any(|orphan| orphan.get_hash() == block.get_hash()) {
+            return;
+        }
+        orphans.push(block);
+        if orphans.len() > MAX_ORPHAN_BLOCKS {
+            orphans.remove(0);
+        }
+    }
+
+    // Adds the orphans descending from `parent`, which was just added
+    async fn connect_orphans(&self, parent: &str) {
+        let mut parents = vec![parent.to_string()];
+        while let Some(parent) = parents.pop() {
+            let children: Vec<Block> = {
+                let orphans = &mut self.inner.write().await.orphan_blocks;
+                let (children, rest) = orphans.drain(..).partition(|orphan| orphan.get_prev_hash() == parent);
+                *orphans = rest;
+                children
+            };
+            for child in children {
+                match self.add_block(child.clone()).await {
+                    Ok(()) => {
+                        self.block_connected(&child);
+                        parents.push(child.get_hash());
+                    }
+                    Err(e) => println!("Dropped orphan block {}: {}", child.get_hash(), e),
+                }
+            }
+        }
     }
 
     async fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
@@ -625,6 +1789,52 @@ impl Server {
             .blockchain.write().await.mine_block(txs)
     }
 
+    // Moves the UTXO set up to a block `mine_block` put on top of the tip, like add_block
+    // does for blocks from peers
+    async fn connect_mined(&self, block: &Block) -> Result<()> {
+        let followed = self.inner.read().await
+            .utxo.read().await
+            .update(block);
+        match followed {
+            Ok(()) => self.verify_block_connect(block).await,
+            Err(e) => {
+                println!("UTXO set couldn't follow block {}, reindexing: {}", block.get_hash(), e);
+                self.utxo_reindex().await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Debug harness (Settings: verify_block_connect), compares the balances of the addresses
+    // touched by the block against a fresh chain scan. Runs right after the UTXO set followed
+    // the block on its own, a reindex would only compare the scan with itself
+    async fn verify_block_connect(&self, block: &Block) {
+        if !self.verify_block_connect {
+            return;
+        }
+
+        let result = self.inner.read().await
+            .utxo.read().await
+            .verify_block_connect(block).await;
+
+        match result {
+            Ok(mismatches) if mismatches.is_empty() => {}
+            Ok(mismatches) => {
+                for m in &mismatches {
+                    println!(
+                        "Balance mismatch after block {}: {} chain {} utxo {}",
+                        block.get_hash(), m.address, m.chain_balance, m.utxo_balance
+                    );
+                }
+                self.emit(NodeEvent::BalanceMismatch {
+                    block_hash: block.get_hash(),
+                    mismatches,
+                }).await;
+            }
+            Err(e) => println!("Failed to verify block {}: {}", block.get_hash(), e),
+        }
+    }
+
     async fn utxo_reindex(&self) -> Result<()> {
         self.inner.write().await
             .utxo.write().await.reindex().await
@@ -632,12 +1842,29 @@ impl Server {
 
     // ---------------- Main Handle -------------------
 
-    async fn handle_connection(&mut self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer).await?;
-        println!("Accept request: length {}", count);
+    async fn handle_message(&self, buffer: Vec<u8>) -> Result<()> {
+        println!("Accept request: length {}", buffer.len());
 
-        let cmd:Message = bytes_to_cmd(&buffer)?;
+        let cmd: Message = match bytes_to_cmd(&buffer) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                if let Some(ProtocolError::Misbehavior { peer, reason, score }) = e.downcast_ref::<ProtocolError>() {
+                    self.penalize_peer(peer, *score, reason).await;
+                }
+                return Err(e);
+            }
+        };
+
+        println!("cmd: {}", cmd.command());
+
+        if let Some(peer) = cmd.sender() {
+            let mut inner = self.inner.write().await;
+            inner.traffic.received(peer, buffer.len());
+            inner.last_peer_message = Some(std::time::Instant::now());
+            if let Some(node) = inner.known_nodes.get_mut(peer) {
+                node.last_seen = Some(clock::now_millis());
+            }
+        }
 
         match cmd {
             Message::Addr(data) => self.handle_addr(data).await?,
@@ -645,60 +1872,1410 @@ impl Server {
             Message::Inv(data) => self.handle_inv(data).await?,
             Message::GetBlock(data) => self.handle_get_blocks(data).await?,
             Message::GetData(data) => self.handle_get_data(data).await?,
-            Message::Tx(data) => self.handle_tx(data).await?,
+            Message::NotFound(data) => self.handle_not_found(data).await,
+            Message::Tx(data) => self.handle_tx(data, false).await?,
+            Message::AckTx(data) => self.handle_tx(data, true).await?,
+            Message::TxAck(data) => self.handle_tx_ack(data).await,
             Message::Version(data) => self.handle_version(data).await?,
+            Message::OpCheckpoint(data) => self.handle_opcheckpoint(data).await?,
+            Message::GetSnapshot(data) => self.handle_get_snapshot(data).await?,
+            Message::Snapshot(data) => self.handle_snapshot(data).await?,
+            Message::SnapChunk(data) => self.handle_snap_chunk(data).await?,
         }
 
         Ok(())
     }
 }
 
-//
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-
-    // A slice of the first CMD_LEN bytes from bytes
-    let cmd_bytes = &bytes[..CMD_LEN];
-
-    //  A slice of the remaining bytes after the command
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
-    }
-    println!("cmd: {}", String::from_utf8(cmd.clone())?);
-
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = bincode::deserialize(data)?;
-        Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = bincode::deserialize(data)?;
-        Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
-        let data: Invmsg = bincode::deserialize(data)?;
-        Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlockmsg = bincode::deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
-        let data: GetDatamsg = bincode::deserialize(data)?;
-        Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
-        let data: Txmsg = bincode::deserialize(data)?;
-        Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
-        let data: Versionmsg = bincode::deserialize(data)?;
-        Ok(Message::Version(data))
-    } else {
-        Err(format_err!("Unknown command in the server"))
+/// Asks the node listening on `addr`, which must be local, for its health report
+pub async fn request_health(addr: &str) -> Result<HealthReport> {
+    let mut stream = tokio::time::timeout(SEND_TIMEOUT, TcpStream::connect(addr)).await
+        .map_err(|_| format_err!("Connecting to {} timed out", addr))??;
+    stream.write_all(&cmd_to_bytes(HEALTH_CMD)).await?;
+    stream.shutdown().await?;
+
+    let mut reply = Vec::new();
+    tokio::time::timeout(SEND_TIMEOUT, stream.read_to_end(&mut reply)).await
+        .map_err(|_| format_err!("{} didn't answer the health check", addr))??;
+    Ok(bincode::deserialize(&reply)?)
+}
+
+// Reads a whole message from a peer within the download limit. Fails past MAX_MESSAGE_SIZE
+async fn read_message(stream: &mut TcpStream, download: &RateLimiter) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0; if download.is_limited() { CHUNK_SIZE } else { 16 * CHUNK_SIZE }];
+    loop {
+        let count = stream.read(&mut chunk).await?;
+        if count == 0 {
+            return Ok(buffer);
+        }
+        buffer.extend_from_slice(&chunk[..count]);
+        if buffer.len() as u64 > MAX_MESSAGE_SIZE {
+            return Err(ProtocolError::MessageTooLarge.into());
+        }
+        download.consume(count).await;
     }
 }
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
+// Mempool entries, the outputs they spend and when they came in (`added`) change together
+fn add_to_mempool(inner: &mut ServerInner, tx: Transaction, added: u128) {
+    inner.mempool_spends.extend(tx.vin.iter().map(|vin| ((vin.txid.clone(), vin.vout), tx.id.clone())));
+    inner.mempool_added.insert(tx.id.clone(), added);
+    inner.mempool.insert(tx.id.clone(), tx);
+    inner.package_stats.clear();
+}
+
+fn remove_from_mempool(inner: &mut ServerInner, remove: impl Fn(&String, &Transaction) -> bool) {
+    inner.mempool.retain(|txid, tx| !remove(txid, tx));
+    let ServerInner { mempool, mempool_added, .. } = inner;
+    mempool_added.retain(|txid, _| mempool.contains_key(txid));
+    inner.mempool_spends = mempool::spent_outpoints(&inner.mempool);
+    inner.package_stats.clear();
+}
+
+// Misbehavior score for relaying a rejected transaction. Spent inputs and conflicts can
+// come from an honest peer that hasn't seen the latest block or the other spend yet, dust
+// from one with a lower threshold
+fn reject_score(reason: &TxRejectReason) -> u32 {
+    match reason {
+        TxRejectReason::Malformed(_) | TxRejectReason::Invalid(_) | TxRejectReason::BadSignature
+        | TxRejectReason::DataTooLarge { .. } => 50,
+        TxRejectReason::InputUnavailable { .. } | TxRejectReason::ReplacementFeeTooLow { .. } => 10,
+        TxRejectReason::Dust { .. } => 0,
+    }
+}
+
+// Misbehavior score for relaying a rejected block. Only a peer on another checkpointed
+// chain sends a checkpoint violation honestly
+fn block_reject_score(reason: &BlockRejectReason) -> u32 {
+    match reason {
+        BlockRejectReason::BadProofOfWork { .. } | BlockRejectReason::BadMerkleRoot { .. } | BlockRejectReason::BadHeight { .. }
+        | BlockRejectReason::BadTarget { .. } | BlockRejectReason::TooLarge { .. } | BlockRejectReason::TimeTooOld { .. }
+        | BlockRejectReason::CoinbaseTooLarge { .. } | BlockRejectReason::BadCoinbaseHeight { .. } => 100,
+        BlockRejectReason::InvalidTransaction { .. } => 50,
+        BlockRejectReason::CheckpointViolation { .. } => 20,
+        // our clock may be the one that's off, and the block is fine once it catches up
+        BlockRejectReason::UnknownParent { .. } | BlockRejectReason::TimeTooNew { .. } => 0,
+    }
+}
+
+// Value of output `vout` of a mined transaction
+fn confirmed_value(blockchain: &Blockchain, txid: &str, vout: i32) -> Option<i32> {
+    let tx = blockchain.find_transaction(txid).ok()?;
+    tx.vout.get(usize::try_from(vout).ok()?).map(|out| out.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::INITIAL_TARGET;
+    use crate::coin_selection::CoinSelection;
+    use crate::health::HealthStatus;
+    use crate::protocol::{LegacyVersionmsg, UncheckpointedVersionmsg, CMD_LEN};
+    use crate::blockchain::Blockchain;
+    use crate::conformance::ConformanceClient;
+    #[cfg(feature = "chaos")]
+    use crate::chaos::{ChaosConfig, LinkChaos};
+    use crate::testing::{coinbase, ChainBuilder, TxBuilder, UtxoFixture, WalletFixture};
+    use crate::transaction::SendMode;
+    use crate::wallet::SameAddress;
+    use crate::tx::{TXOutputs, UnspentOutputs};
+    use crate::utxoset::BalanceMismatch;
+    use std::time::Instant;
+
+    fn test_server() -> Server {
+        let bc = Arc::new(RwLock::new(Blockchain::default_empty()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::in_memory(bc)));
+        let mut server = Server::new("0", "", utxo).unwrap();
+        server.send_timeout = Duration::from_millis(500);
+        server
     }
-    data
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_broadcast_with_hanging_peer() {
+        // accepts the connection but never reads from it
+        let hanging = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peers = vec![hanging.local_addr().unwrap().to_string()];
+        let hold = tokio::spawn(async move {
+            let (stream, _) = hanging.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(stream);
+        });
+
+        let mut readers = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap().to_string());
+            readers.push(tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                (buf.len(), Instant::now())
+            }));
+        }
+
+        // big enough to fill the socket buffers of the peer that doesn't read
+        let data = vec![7u8; 16 * 1024 * 1024];
+        let server = test_server();
+
+        let start = Instant::now();
+        let report = server.broadcast(peers, data.clone()).await;
+        assert_eq!(report, BroadcastReport { delivered: 2, failed: 1 });
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        for reader in readers {
+            let (len, done) = reader.await.unwrap();
+            assert_eq!(len, data.len());
+            assert!(done.duration_since(start) < Duration::from_secs(2));
+        }
+        hold.abort();
+    }
+
+    #[tokio::test]
+    async fn test_requests_prefer_capable_peers() {
+        // Both peers accept whatever the handshake sends back
+        let mut peers = Vec::new();
+        let mut listeners = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap().to_string());
+            listeners.push(tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = Vec::new();
+                    let _ = stream.read_to_end(&mut buf).await;
+                }
+            }));
+        }
+        let (capable, legacy) = (peers[0].clone(), peers[1].clone());
+
+        let server = test_server();
+        server.inner.write().await.known_nodes.clear();
+
+        let msg = Versionmsg {
+            addr_from: capable.clone(),
+            version: VERSION,
+            best_height: -1,
+            capabilities: Capabilities::COMPACT_BLOCKS.union(Capabilities::ENCRYPTION),
+            timestamp: 0,
+            checkpoint: None,
+            genesis: String::new(),
+            best_work: 0,
+        };
+        let Message::Version(msg) = bytes_to_cmd(&bincode::serialize(&(cmd_to_bytes("version"), msg)).unwrap()).unwrap() else {
+            panic!("expected a version message");
+        };
+        server.handle_version(msg).await.unwrap();
+
+        // A node from before capabilities existed
+        let msg = LegacyVersionmsg { addr_from: legacy.clone(), version: VERSION, best_height: -1 };
+        let Message::Version(msg) = bytes_to_cmd(&bincode::serialize(&(cmd_to_bytes("version"), msg)).unwrap()).unwrap() else {
+            panic!("expected a version message");
+        };
+        assert_eq!(msg.capabilities, Capabilities::NONE);
+        server.handle_version(msg).await.unwrap();
+
+        let known_nodes = server.get_known_nodes().await;
+        assert!(known_nodes[&capable].capabilities().contains(Capabilities::COMPACT_BLOCKS));
+        assert_eq!(known_nodes[&legacy].capabilities(), Capabilities::NONE);
+
+        // Compact block requests only go to the peer that supports them
+        assert_eq!(server.pick_peers_with(Capabilities::COMPACT_BLOCKS, 2).await, vec![capable.clone()]);
+        assert_eq!(server.pick_peers_with(Capabilities::COMPACT_BLOCKS.union(Capabilities::ENCRYPTION), 2).await, vec![capable]);
+        // Nobody advertises filters, so any peer will do
+        let mut fallback = server.pick_peers_with(Capabilities::BLOCK_FILTERS, 2).await;
+        fallback.sort();
+        peers.sort();
+        assert_eq!(fallback, peers);
+        assert_eq!(server.pick_peers_with(Capabilities::BLOCK_FILTERS, 1).await.len(), 1);
+
+        for listener in listeners {
+            listener.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_version_advertises_latest_checkpoint() {
+        let server = test_server();
+        let pinned = "00ab".repeat(16);
+        server.inner.read().await.utxo.read().await.blockchain.write().await.fixed_checkpoints.insert(4, pinned.clone());
+
+        let Message::Version(msg) = bytes_to_cmd(&server.version_message().await.unwrap()).unwrap() else {
+            panic!("expected a version message");
+        };
+        assert_eq!(msg.checkpoint, Some((4, pinned)));
+
+        // Nodes from before checkpoints were advertised pin nothing
+        let old = UncheckpointedVersionmsg { addr_from: String::from("127.0.0.1:1"), version: VERSION, best_height: 3, capabilities: Capabilities::NONE, timestamp: 7 };
+        let Message::Version(msg) = bytes_to_cmd(&bincode::serialize(&(cmd_to_bytes("version"), old)).unwrap()).unwrap() else {
+            panic!("expected a version message");
+        };
+        assert_eq!((msg.best_height, msg.timestamp, msg.checkpoint), (3, 7, None));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_tip_warns_once_and_resyncs() {
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_address = peer.local_addr().unwrap().to_string();
+        let requests = tokio::spawn(async move {
+            let mut commands = Vec::new();
+            while let Ok(Ok((mut stream, _))) = tokio::time::timeout(Duration::from_millis(500), peer.accept()).await {
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                commands.push(String::from_utf8_lossy(&buf[..CMD_LEN]).trim_end_matches('\0').to_string());
+            }
+            commands
+        });
+
+        let chain = ChainBuilder::new(&WalletFixture::new(1));
+        let tip = chain.tip();
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &temp_path("stall")).unwrap()));
+        let mut server = Server::new("0", "", utxo).unwrap();
+        server.send_timeout = Duration::from_millis(500);
+        server.block_interval = Duration::from_millis(1);
+        server.stale_tip_multiple = 2;
+        let (events, mut received) = mpsc::channel(16);
+        server.set_event_sender(events);
+        server.inner.write().await.known_nodes = HashMap::from([(peer_address, KnownNode::default())]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // BlocksConnected may come through as well
+        let mut stalls = || std::iter::from_fn(|| received.try_recv().ok())
+            .filter(|event| matches!(event, NodeEvent::TipStalled { .. }))
+            .count();
+
+        // Warned once, but every check resyncs
+        server.check_tip_age().await.unwrap();
+        server.check_tip_age().await.unwrap();
+        assert_eq!(stalls(), 1);
+        assert_eq!(requests.await.unwrap(), vec!["getblocks", "getblocks"]);
+        // The dropped bootstrap node is tried again
+        assert!(server.get_known_nodes().await.contains_key(KNOWN_NODE1));
+
+        // A connected block clears it, a tip that's still old warns again
+        server.block_connected(&tip);
+        server.check_tip_age().await.unwrap();
+        assert_eq!(stalls(), 1);
+
+        // Without peers nothing is expected to arrive
+        server.block_connected(&tip);
+        server.inner.write().await.known_nodes.clear();
+        server.check_tip_age().await.unwrap();
+        assert_eq!(stalls(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_txs_are_neither_stored_nor_relayed() {
+        // Another peer of the bootstrap node, recording what gets relayed to it
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_address = other.local_addr().unwrap().to_string();
+        let relayed = tokio::spawn(async move {
+            let mut commands = Vec::new();
+            while let Ok(Ok((mut stream, _))) = tokio::time::timeout(Duration::from_millis(500), other.accept()).await {
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                commands.push(String::from_utf8_lossy(&buf[..CMD_LEN]).trim_end_matches('\0').to_string());
+            }
+            commands
+        });
+
+        let miner = WalletFixture::new(1);
+        let payee = WalletFixture::new(2).address();
+        let genesis_reward = coinbase(&miner.address(), 0);
+        let spend = TxBuilder::new(&miner).spend(&genesis_reward, 0).pay(&payee, 10).build();
+        let chain = ChainBuilder::new(&miner).block(vec![spend]);
+        let reward = chain.tip().get_transactions()[0].clone(); // still unspent
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &temp_path("admission")).unwrap()));
+        let mut server = Server::new("0", "", utxo).unwrap();
+        server.node_address = String::from(KNOWN_NODE1); // the bootstrap node relays transactions
+        server.send_timeout = Duration::from_millis(500);
+        let sender = String::from("127.0.0.1:1");
+        server.inner.write().await.known_nodes = HashMap::from([
+            (sender.clone(), KnownNode::default()),
+            (other_address, KnownNode::default()),
+        ]);
+        let submit = |transaction: Transaction| server.handle_tx(Txmsg { addr_from: sender.clone(), transaction }, false);
+        let rejection = |result: Result<()>| result.err().unwrap().downcast::<TxRejectReason>().unwrap();
+
+        // The genesis reward was spent in block 1
+        let double_spend = TxBuilder::new(&miner).spend(&genesis_reward, 0).pay(&payee, 10).build();
+        assert_eq!(
+            rejection(submit(double_spend).await),
+            TxRejectReason::InputUnavailable { txid: genesis_reward.id.clone(), vout: 0 }
+        );
+
+        let mut forged = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 10).build();
+        forged.vin[0].signature[0] ^= 1;
+        assert_eq!(rejection(submit(forged).await), TxRejectReason::BadSignature);
+
+        assert!(server.mempool_transactions().await.is_empty());
+        assert_eq!(server.get_known_nodes().await[&sender].misbehavior_score, 60);
+
+        // A valid spend of the same output is admitted and relayed
+        let valid = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 10).build();
+        submit(valid.clone()).await.unwrap();
+        assert_eq!(server.mempool_transactions().await.len(), 1);
+        assert_eq!(relayed.await.unwrap(), vec!["inv"]);
+    }
+
+    #[tokio::test]
+    async fn test_raw_transactions_pass_the_mempool_rules_before_broadcast() {
+        let miner = WalletFixture::new(1);
+        let payee = WalletFixture::new(2).address();
+        let chain = ChainBuilder::new(&miner).block(vec![]);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &temp_path("raw_broadcast")).unwrap()));
+        let server = Server::new("0", "", utxo).unwrap();
+        server.inner.write().await.known_nodes.clear();
+
+        let mut forged = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 10).build();
+        forged.vin[0].signature[0] ^= 1;
+        let forged = Transaction::from_hex(&forged.to_hex().unwrap()).unwrap();
+        let err = server.broadcast_raw_transaction(&forged).await.unwrap_err();
+        assert_eq!(err.downcast::<TxRejectReason>().unwrap(), TxRejectReason::BadSignature);
+        assert!(server.mempool_transactions().await.is_empty());
+
+        let valid = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 10).build();
+        let valid = Transaction::from_hex(&valid.to_hex().unwrap()).unwrap();
+        server.broadcast_raw_transaction(&valid).await.unwrap();
+        let mempool: Vec<String> = server.mempool_transactions().await.into_iter().map(|tx| tx.id).collect();
+        assert_eq!(mempool, vec![valid.id]);
+    }
+
+    #[tokio::test]
+    async fn test_mempool_double_spends_replace_only_with_a_higher_fee() {
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_address = other.local_addr().unwrap().to_string();
+        let relayed = record_messages(other);
+
+        let miner = WalletFixture::new(1);
+        let payee = WalletFixture::new(2).address();
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let first = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 9).build(); // fee 1
+        let same_fee = TxBuilder::new(&miner).spend(&reward, 0).pay(&WalletFixture::new(3).address(), 9).build();
+        let bumped = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 8).build();
+        let bumped_again = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 7).build();
+        let block = chain.next_block(vec![first.clone()]);
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &temp_path("conflicts")).unwrap()));
+        let mut server = Server::new("0", "", utxo).unwrap();
+        server.node_address = String::from(KNOWN_NODE1);
+        server.send_timeout = Duration::from_millis(500);
+        let (events, mut received) = mpsc::channel(16);
+        server.set_event_sender(events);
+        let sender = String::from("127.0.0.1:1");
+        server.inner.write().await.known_nodes = HashMap::from([
+            (sender.clone(), KnownNode::default()),
+            (other_address, KnownNode::default()),
+        ]);
+        let submit = |transaction: Transaction| server.handle_tx(Txmsg { addr_from: sender.clone(), transaction }, false);
+        let rejection = |result: Result<()>| result.err().unwrap().downcast::<TxRejectReason>().unwrap();
+        let mempool = || async { server.mempool_transactions().await.into_iter().map(|tx| tx.id).collect::<Vec<_>>() };
+
+        // The first spend stays unless a replacement pays at least one more than it
+        submit(first.clone()).await.unwrap();
+        assert_eq!(rejection(submit(same_fee).await), TxRejectReason::ReplacementFeeTooLow { fee: 1, required: 2 });
+        assert_eq!(mempool().await, vec![first.id.clone()]);
+        submit(bumped.clone()).await.unwrap();
+        assert_eq!(mempool().await, vec![bumped.id.clone()]);
+        assert!(matches!(
+            received.try_recv().unwrap(),
+            NodeEvent::MempoolEvicted { txid, reason: EvictionReason::Replaced } if txid == first.id
+        ));
+        let relayed: Vec<Vec<String>> = relayed.await.unwrap().iter().map(|bytes| match bytes_to_cmd(bytes).unwrap() {
+            Message::Inv(msg) => msg.items,
+            _ => panic!("expected an inv"),
+        }).collect();
+        assert_eq!(relayed, vec![vec![first.id.clone()], vec![bumped.id.clone()]]);
+
+        // The original was mined after all: the replacement is evicted, and another can't spend the output
+        server.add_block(block).await.unwrap();
+        assert!(mempool().await.is_empty());
+        assert!(server.inner.read().await.mempool_spends.is_empty());
+        assert_eq!(rejection(submit(bumped_again).await), TxRejectReason::InputUnavailable { txid: reward.id, vout: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_expired_mempool_transactions_are_evicted_with_descendants() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(1);
+        let old_reward = coinbase(&miner.address(), 0); // the genesis block's
+        let new_reward = chain.tip().get_transactions()[0].clone();
+        let parent = TxBuilder::new(&miner).spend(&old_reward, 0).pay(&miner.address(), 9).build();
+        let child = TxBuilder::new(&miner).spend(&parent, 0).pay(&miner.address(), 8).build();
+        let recent = TxBuilder::new(&miner).spend(&new_reward, 0).pay(&miner.address(), 9).build();
+
+        let path = temp_path("eviction");
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &path).unwrap()));
+        let mut server = Server::new("0", "", utxo).unwrap();
+        server.mempool_ttl = Duration::from_secs(10);
+        server.max_mempool_size = 0;
+        let (events, mut received) = mpsc::channel(16);
+        server.set_event_sender(events);
+        {
+            let mut inner = server.inner.write().await;
+            add_to_mempool(&mut inner, parent.clone(), 0);
+            add_to_mempool(&mut inner, child.clone(), 5_000);
+            add_to_mempool(&mut inner, recent.clone(), 5_000);
+        }
+
+        assert!(server.evict_mempool(9_999).await.unwrap().is_empty());
+        let evicted = server.evict_mempool(10_000).await.unwrap();
+        assert_eq!(evicted, vec![(parent.id.clone(), EvictionReason::Expired), (child.id.clone(), EvictionReason::Expired)]);
+        for txid in [&parent.id, &child.id] {
+            match received.try_recv().unwrap() {
+                NodeEvent::MempoolEvicted { txid: evicted, reason: EvictionReason::Expired } => assert_eq!(&evicted, txid),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+
+        let inner = server.inner.read().await;
+        assert_eq!(inner.mempool.keys().collect::<Vec<_>>(), vec![&recent.id]);
+        assert_eq!(inner.mempool_added.keys().collect::<Vec<_>>(), vec![&recent.id]);
+        assert_eq!(inner.mempool_spends, HashMap::from([((new_reward.id.clone(), 0), recent.id.clone())]));
+        drop(inner);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    // Commands received on `listener` until nothing connects for 500ms, with the messages
+    fn record_messages(listener: TcpListener) -> tokio::task::JoinHandle<Vec<Vec<u8>>> {
+        tokio::spawn(async move {
+            let mut messages = Vec::new();
+            while let Ok(Ok((mut stream, _))) = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await {
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await.unwrap();
+                messages.push(buf);
+            }
+            messages
+        })
+    }
+
+    #[tokio::test]
+    async fn test_admitted_txs_are_acknowledged() {
+        let wallet = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let wallet_address = wallet.local_addr().unwrap().to_string();
+        let received = record_messages(wallet);
+
+        let miner = WalletFixture::new(1);
+        let payee = WalletFixture::new(2).address();
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &temp_path("acks")).unwrap()));
+        let mut peer = Server::new("0", "", utxo).unwrap();
+        peer.send_timeout = Duration::from_millis(500);
+        peer.inner.write().await.known_nodes.clear();
+        let submit = |transaction: Transaction| peer.handle_tx(Txmsg { addr_from: wallet_address.clone(), transaction }, true);
+
+        // Rejected: no ack
+        let mut forged = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 10).build();
+        forged.vin[0].signature[0] ^= 1;
+        assert!(submit(forged).await.is_err());
+        // Admitted, and asked again once it's in the mempool
+        let valid = TxBuilder::new(&miner).spend(&reward, 0).pay(&payee, 10).build();
+        submit(valid.clone()).await.unwrap();
+        submit(valid.clone()).await.unwrap();
+
+        let acks: Vec<TxAck> = received.await.unwrap().iter().map(|bytes| match bytes_to_cmd(bytes).unwrap() {
+            Message::TxAck(msg) => msg.ack,
+            other => panic!("expected a tx ack, got {:?}", other),
+        }).collect();
+        assert_eq!(acks.len(), 2);
+        for ack in &acks {
+            assert_eq!(ack.txid, valid.id);
+            assert_eq!(ack.public_key, peer.identity.public_key());
+            assert!(ack.verify());
+        }
+
+        // The sender only passes on acks it asked for and that verify
+        let (events, mut events_rx) = mpsc::channel(10);
+        let mut sender = test_server();
+        sender.events = Some(events);
+        let ack_msg = |ack: TxAck| TxAckmsg { addr_from: String::from("127.0.0.1:1"), ack };
+        sender.handle_tx_ack(ack_msg(acks[0].clone())).await;
+        assert!(events_rx.try_recv().is_err());
+        sender.inner.write().await.awaiting_acks.insert(valid.id.clone());
+        let mut forged_ack = acks[0].clone();
+        forged_ack.timestamp += 1;
+        sender.handle_tx_ack(ack_msg(forged_ack)).await;
+        assert!(events_rx.try_recv().is_err());
+        sender.handle_tx_ack(ack_msg(acks[0].clone())).await;
+        assert!(matches!(events_rx.try_recv(), Ok(NodeEvent::TxAcknowledged { ack }) if ack == acks[0]));
+    }
+
+    #[tokio::test]
+    async fn test_receipts_are_only_asked_of_capable_peers() {
+        let mut peers = Vec::new();
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap().to_string());
+            received.push(record_messages(listener));
+        }
+        let mut server = test_server();
+        server.tx_receipts = true;
+        let capable = KnownNode { capabilities: Capabilities::TX_RECEIPTS, ..KnownNode::default() };
+        server.inner.write().await.known_nodes = HashMap::from([
+            (peers[0].clone(), capable),
+            (peers[1].clone(), KnownNode::default()),
+        ]);
+
+        let tx = coinbase(&WalletFixture::new(1).address(), 1);
+        let report = server.send_transaction(&tx).await.unwrap();
+        assert_eq!(report, BroadcastReport { delivered: 2, failed: 0 });
+        let mut commands = Vec::new();
+        for handle in received {
+            commands.push(handle.await.unwrap().iter().map(|buf| String::from_utf8_lossy(&buf[..CMD_LEN]).trim_end_matches('\0').to_string()).collect::<Vec<_>>());
+        }
+        assert_eq!(commands, vec![vec!["acktx"], vec!["tx"]]);
+        assert!(server.inner.read().await.awaiting_acks.contains(&tx.id));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_blocks_penalized_and_orphans_connected() {
+        let miner = WalletFixture::new(1);
+        let blocks: Vec<Block> = ChainBuilder::new(&miner).empty_blocks(3).build().iter().collect();
+        let (parent, child) = (blocks[1].clone(), blocks[0].clone()); // heights 2 and 3
+        let mut bc = Blockchain::default_empty();
+        for block in blocks[2..].iter().rev() {
+            bc.add_block(block.clone()).unwrap();
+        }
+        // Spends an output that was never mined
+        let unknown = TxBuilder::new(&miner).spend(&coinbase(&miner.address(), 99), 0).pay(&miner.address(), 10).build();
+        let forged = Block::new_block(vec![coinbase(&miner.address(), 4), unknown.clone()], child.get_hash(), 4, INITIAL_TARGET).unwrap();
+
+        let path = temp_path("blocks");
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(bc)), &path).unwrap()));
+        let server = Server::new("0", "", utxo).unwrap();
+        let sender = String::from("127.0.0.1:1");
+        server.inner.write().await.known_nodes = HashMap::from([(sender.clone(), KnownNode::default())]);
+        let submit = |block: Block| server.handle_block(Blockmsg { addr_from: sender.clone(), block });
+
+        // A block arriving before its parent waits for it
+        submit(child.clone()).await.unwrap();
+        assert_eq!(server.get_best_height().await.unwrap(), 1);
+        submit(parent.clone()).await.unwrap();
+        assert_eq!(server.get_best_height().await.unwrap(), 3);
+        assert!(server.inner.read().await.orphan_blocks.is_empty());
+
+        let rejection = submit(forged.clone()).await.unwrap_err().downcast::<BlockRejectReason>().unwrap();
+        assert_eq!(rejection, BlockRejectReason::InvalidTransaction { hash: forged.get_hash(), txid: unknown.id });
+        assert_eq!(server.get_best_height().await.unwrap(), 3);
+        assert_eq!(server.get_known_nodes().await[&sender].misbehavior_score, 50);
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_reorg_moves_utxos_and_mempool_to_the_longer_branch() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let genesis_reward = coinbase(&alice.address(), 0);
+        let chain = ChainBuilder::new(&alice).empty_blocks(1);
+        let fork = chain.tip();
+        let reward = fork.get_transactions()[0].clone();
+
+        // Only the old branch holds `kept`, `conflicted` spends what the new branch spends
+        let kept = TxBuilder::new(&alice).spend(&genesis_reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 4).build();
+        let conflicted = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).build();
+        let a1 = chain.next_block(vec![kept.clone(), conflicted.clone()]);
+        let to_carol = TxBuilder::new(&alice).spend(&reward, 0).pay(&carol.address(), 5).pay(&alice.address(), 5).build();
+        let b1 = Block::new_block(vec![coinbase(&carol.address(), 2), to_carol.clone()], fork.get_hash(), 2, INITIAL_TARGET).unwrap();
+        let b2 = Block::new_block(vec![coinbase(&carol.address(), 3)], b1.get_hash(), 3, INITIAL_TARGET).unwrap();
+        let unconfirmed = TxBuilder::new(&alice).spend(&to_carol, 1).pay(&bob.address(), 5).build();
+
+        let path = temp_path("reorg");
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &path).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+        let mut server = Server::new("0", "", utxo.clone()).unwrap();
+        let (events, mut received) = mpsc::channel(16);
+        server.set_event_sender(events);
+
+        server.add_block(a1).await.unwrap();
+        server.add_block(b1).await.unwrap();
+        add_to_mempool(&mut *server.inner.write().await, unconfirmed.clone(), clock::now_millis());
+        add_to_mempool(&mut *server.inner.write().await, to_carol.clone(), clock::now_millis());
+        server.add_block(b2).await.unwrap();
+
+        // The incrementally updated set matches a fresh scan of the new branch
+        let utxo = utxo.read().await;
+        let expected: HashMap<String, Vec<u8>> = utxo.blockchain.read().await.find_utxo().unwrap()
+            .into_iter()
+            .map(|(txid, outs)| (txid, bincode::serialize(&outs).unwrap()))
+            .collect();
+        let actual: HashMap<String, Vec<u8>> = utxo.db.iter()
+            .map(|kv| kv.unwrap())
+            .map(|(k, v)| (String::from_utf8(k.to_vec()).unwrap(), v.to_vec()))
+            .collect();
+        assert_eq!(actual, expected);
+
+        // kept is back, to_carol is confirmed, conflicted can't be mined anymore
+        let mut mempool: Vec<String> = server.mempool_transactions().await.into_iter().map(|tx| tx.id).collect();
+        mempool.sort();
+        let mut expected = vec![kept.id, unconfirmed.id];
+        expected.sort();
+        assert_eq!(mempool, expected);
+
+        let reorganized = std::iter::from_fn(|| received.try_recv().ok())
+            .find(|event| matches!(event, NodeEvent::ChainReorganized { .. }));
+        assert!(matches!(reorganized, Some(NodeEvent::ChainReorganized { fork_height: 1, disconnected: 1, connected: 2 })));
+
+        drop(utxo);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_rollback_returns_transactions_to_the_mempool() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 0);
+        let payment = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).build();
+        let chain = ChainBuilder::new(&alice).block(vec![payment.clone()]).empty_blocks(1);
+
+        let path = temp_path("rollback");
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &path).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+        let mut server = Server::new("0", "", utxo.clone()).unwrap();
+        server.audit = AuditLog::with_path(&format!("{}-audit", path));
+        let (events, mut received) = mpsc::channel(16);
+        server.set_event_sender(events);
+        server.allow_rollback = false;
+        assert!(server.rollback(1).await.is_err());
+        assert_eq!(server.get_best_height().await.unwrap(), 2);
+        server.allow_rollback = true;
+
+        assert!(server.rollback(3).await.is_err());
+        let removed = server.rollback(2).await.unwrap();
+        assert_eq!(removed.iter().map(Block::get_height).collect::<Vec<_>>(), vec![2, 1]);
+        let ids: Vec<String> = server.mempool_transactions().await.into_iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![payment.id.clone()]);
+        assert!(utxo.read().await.find_utxo(&bob.pub_key_hash()).unwrap().outputs.is_empty());
+        let rolled_back = std::iter::from_fn(|| received.try_recv().ok())
+            .find(|event| matches!(event, NodeEvent::ChainReorganized { .. }));
+        assert!(matches!(rolled_back, Some(NodeEvent::ChainReorganized { fork_height: 0, disconnected: 2, connected: 0 })));
+
+        // Refused and failed attempts are in the audit log too
+        let outcomes: Vec<bool> = server.audit.entries().unwrap().iter().map(|e| e.outcome == AuditOutcome::Done).collect();
+        assert_eq!(outcomes, vec![false, false, true]);
+
+        std::fs::remove_dir_all(&path).ok();
+        std::fs::remove_dir_all(format!("{}-audit", path)).ok();
+    }
+
+    #[tokio::test]
+    async fn test_rollback_to_height_restores_the_earlier_state() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let to_bob = TxBuilder::new(&alice).spend(&coinbase(&alice.address(), 0), 0).pay(&bob.address(), 4).pay(&alice.address(), 6).build();
+        let to_carol = TxBuilder::new(&bob).spend(&to_bob, 0).pay(&carol.address(), 2).pay(&bob.address(), 2).build();
+        let pending = TxBuilder::new(&alice).spend(&coinbase(&alice.address(), 1), 0).pay(&carol.address(), 2).pay(&alice.address(), 8).build();
+        let at_7 = || ChainBuilder::new(&alice).empty_blocks(7);
+        let chain = at_7().block(vec![to_bob.clone()]).block(vec![to_carol.clone()]).empty_blocks(1).build();
+
+        let path = temp_path("rollback-to-height");
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), &path).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+        let mut server = Server::new("0", "", utxo.clone()).unwrap();
+        server.audit = AuditLog::with_path(&format!("{}-audit", path));
+        server.allow_rollback = true;
+        add_to_mempool(&mut *server.inner.write().await, pending.clone(), clock::now_millis());
+        assert_eq!(server.get_best_height().await.unwrap(), 10);
+
+        let removed = server.rollback_to_height(7).await.unwrap();
+        assert_eq!(removed.iter().map(Block::get_height).collect::<Vec<_>>(), vec![10, 9, 8]);
+        assert_eq!(server.get_best_height().await.unwrap(), 7);
+
+        // The same balances as a chain that never went past height 7
+        let expected = UtxoFixture::new(at_7().build()).await;
+        for wallet in [&alice, &bob, &carol] {
+            let balance = |outputs: TXOutputs| outputs.outputs.iter().map(|out| out.value).sum::<i32>();
+            assert_eq!(
+                balance(utxo.read().await.find_utxo(&wallet.pub_key_hash()).unwrap()),
+                balance(expected.find_utxo(&wallet.pub_key_hash()).unwrap()),
+            );
+        }
+
+        // What was pending, and the transactions of the removed blocks
+        let mut mempool: Vec<String> = server.mempool_transactions().await.into_iter().map(|tx| tx.id).collect();
+        let mut returned = vec![pending.id, to_bob.id, to_carol.id];
+        mempool.sort();
+        returned.sort();
+        assert_eq!(mempool, returned);
+
+        assert!(server.rollback_to_height(8).await.is_err());
+        std::fs::remove_dir_all(&path).ok();
+        std::fs::remove_dir_all(format!("{}-audit", path)).ok();
+    }
+
+    #[tokio::test]
+    async fn test_upload_limit_paces_large_blocks() {
+        // Peer reporting every message once it has arrived completely
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_address = peer.local_addr().unwrap().to_string();
+        let (arrived, mut arrivals) = mpsc::unbounded_channel();
+        let receiving = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = peer.accept().await.unwrap();
+                let arrived = arrived.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    stream.read_to_end(&mut buf).await.unwrap();
+                    let _ = arrived.send(String::from_utf8_lossy(&buf[..CMD_LEN]).trim_end_matches('\0').to_string());
+                });
+            }
+        });
+
+        let mut server = test_server();
+        server.upload = RateLimiter::from_kbps(50);
+        let server = Arc::new(server);
+
+        // A block of about 150 KB
+        let coinbase = Transaction::new_coinbase(WalletFixture::new(1).address(), "x".repeat(150 * 1024), 1).unwrap();
+        let block = Block::new_block(vec![coinbase], String::new(), 1, INITIAL_TARGET).unwrap();
+
+        let started = Instant::now();
+        let sending = {
+            let server = Arc::clone(&server);
+            let peer_address = peer_address.clone();
+            tokio::spawn(async move { server.send_block(&peer_address, &block).await.unwrap() })
+        };
+        // Small messages sent meanwhile don't wait for the whole block
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        for _ in 0..3 {
+            server.send_version(&peer_address).await.unwrap();
+        }
+        sending.await.unwrap();
+        let elapsed = started.elapsed();
+
+        // 150 KB less the 5 KB burst at 50 KB/s
+        assert!(elapsed >= Duration::from_millis(2500) && elapsed < Duration::from_millis(4500), "{:?}", elapsed);
+        let mut order = Vec::new();
+        while order.len() < 4 {
+            order.push(tokio::time::timeout(Duration::from_secs(1), arrivals.recv()).await.unwrap().unwrap());
+        }
+        assert_eq!(order, vec!["version", "version", "version", "block"]);
+        assert!(server.peer_throughput().await[&peer_address].upload > 0.0);
+
+        receiving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_getdata_for_missing_items_answers_not_found() {
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_address = peer.local_addr().unwrap().to_string();
+        let server = test_server();
+
+        // A pruned block and a transaction that left the mempool
+        for kind in ["block", "tx"] {
+            let msg = GetDatamsg { addr_from: peer_address.clone(), kind: kind.to_string(), id: "f".repeat(64) };
+            server.handle_get_data(msg).await.unwrap();
+
+            let (mut stream, _) = peer.accept().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            match bytes_to_cmd(&buf).unwrap() {
+                Message::NotFound(msg) => assert_eq!((msg.kind.as_str(), msg.id), (kind, "f".repeat(64))),
+                _ => panic!("{} wasn't answered with notfound", kind),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_disk_pauses_blocks() {
+        let miner = WalletFixture::new(1);
+        let blocks: Vec<Block> = ChainBuilder::new(&miner).empty_blocks(1).build().iter().collect();
+        let mut bc = Blockchain::default_empty();
+        bc.add_block(blocks[1].clone()).unwrap();
+        let path = temp_path("disk");
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(bc)), &path).unwrap()));
+        let mut server = Server::new("0", &miner.address(), utxo).unwrap();
+        let (events, mut received) = mpsc::channel(10);
+        server.set_event_sender(events);
+        let sender = String::from("127.0.0.1:1");
+        server.inner.write().await.known_nodes = HashMap::from([(sender.clone(), KnownNode::default())]);
+        server.inner.write().await.disk = DiskMonitor::new(1000, 100);
+        let submit = |block: Block| server.handle_block(Blockmsg { addr_from: sender.clone(), block });
+        let disk_status = || async { server.healthcheck().await.checks.into_iter().find(|c| c.name == "disk").unwrap().status };
+
+        server.apply_free_space(50).await;
+        assert!(matches!(received.recv().await, Some(NodeEvent::DiskSpace { level: DiskLevel::Full, free: 50, .. })));
+        assert_eq!(disk_status().await, HealthStatus::Failing);
+
+        // Refused without blaming the peer, nothing is mined
+        assert!(submit(blocks[0].clone()).await.is_err());
+        assert_eq!(server.get_best_height().await.unwrap(), 0);
+        assert_eq!(server.get_known_nodes().await[&sender].misbehavior_score, 0);
+        assert!(server.mine_empty_block().await.is_err());
+
+        server.apply_free_space(5000).await;
+        assert!(matches!(received.recv().await, Some(NodeEvent::DiskSpace { level: DiskLevel::Ok, .. })));
+        assert_eq!(disk_status().await, HealthStatus::Ok);
+        submit(blocks[0].clone()).await.unwrap();
+        assert_eq!(server.get_best_height().await.unwrap(), 1);
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_sleep_forgives_peers() {
+        let mut server = test_server();
+        let (events, mut received) = mpsc::channel(10);
+        server.set_event_sender(events);
+        {
+            let mut inner = server.inner.write().await;
+            inner.known_nodes.get_mut(KNOWN_NODE1).unwrap().no_response_counter = 3;
+            for (peer, offset) in [("a", 1_000), ("b", 2_000), ("c", 3_000)] {
+                inner.clock.add_sample(peer, 1_000_000 + offset, 1_000_000);
+            }
+        }
+
+        let slept = Duration::from_secs(7 * 3600);
+        server.resume_after_sleep(slept).await;
+
+        // The sleep is forgotten, though the greeting that followed counted one failure again
+        let inner = server.inner.read().await;
+        assert!(inner.known_nodes.get(KNOWN_NODE1).unwrap().no_response_counter <= 1);
+        assert_eq!(inner.clock.median_offset(), None);
+        assert!(matches!(received.recv().await, Some(NodeEvent::ResumedFromSleep { slept: s }) if s == slept));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peer_is_recorded() {
+        let closed = free_port(); // nothing listens here
+        let peer_address = format!("127.0.0.1:{}", closed);
+
+        let chain = ChainBuilder::new(&WalletFixture::new(1));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &temp_path("history")).unwrap()));
+        let mut server = Server::new("0", "", utxo).unwrap();
+        let (events, mut received) = mpsc::channel(16);
+        server.set_event_sender(events);
+        server.inner.write().await.known_nodes.insert(peer_address.clone(), KnownNode::default());
+
+        // Three failures are tolerated, the fourth drops the peer
+        for _ in 0..3 {
+            assert!(server.send_data(&peer_address, b"ping").await.is_err());
+        }
+        assert!(server.get_known_nodes().await.contains_key(&peer_address));
+        assert!(server.peer_history().await.unwrap().is_empty());
+
+        assert!(server.send_data(&peer_address, b"ping").await.is_err());
+        assert!(!server.get_known_nodes().await.contains_key(&peer_address));
+
+        let history = server.peer_history().await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].address, peer_address);
+        assert_eq!(history[0].reason, RemovalReason::Unreachable);
+        assert!(matches!(
+            received.try_recv(),
+            Ok(NodeEvent::PeerRemoved { address, reason: RemovalReason::Unreachable }) if address == peer_address
+        ));
+
+        // Newest first
+        server.disconnect_peer(KNOWN_NODE1).await;
+        let history = server.peer_history().await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].reason, RemovalReason::Manual);
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_utxo_entry_is_reported_by_the_next_block() {
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(1);
+        let reward = coinbase(&miner.address(), 0); // the genesis block's
+        let next = chain.next_block(Vec::new());
+        let path = temp_path("verify-connect");
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain.build())), &path).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+
+        // 5 coins the chain never paid
+        {
+            let utxo = utxo.read().await;
+            let mut outs: UnspentOutputs = bincode::deserialize(&utxo.db.get(&reward.id).unwrap().unwrap()).unwrap();
+            outs.outputs.get_mut(&0).unwrap().value += 5;
+            utxo.db.insert(reward.id.as_bytes(), bincode::serialize(&outs).unwrap()).unwrap();
+        }
+
+        let mut server = Server::new("0", "", utxo).unwrap();
+        server.verify_block_connect = true;
+        let (events, mut received) = mpsc::channel(16);
+        server.set_event_sender(events);
+        server.add_block(next.clone()).await.unwrap();
+
+        let mismatches = std::iter::from_fn(|| received.try_recv().ok())
+            .find_map(|event| match event {
+                NodeEvent::BalanceMismatch { block_hash, mismatches } if block_hash == next.get_hash() => Some(mismatches),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(mismatches, vec![BalanceMismatch { address: miner.address(), chain_balance: 30, utxo_balance: 35 }]);
+        drop(server);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("blockjain-{}-utxos-{}", name, rand::random::<u64>()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn free_port() -> String {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string()
+    }
+
+    // A server on a new chain of `network`, and the paths of its block and UTXO databases
+    async fn fresh_node(network: &str) -> (Server, [String; 2]) {
+        let blocks_path = temp_path(&format!("{}-blocks", network));
+        let utxo_path = temp_path(network);
+        let bc = Blockchain::open_network(&blocks_path, network).unwrap();
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(bc)), &utxo_path).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+        let mut server = Server::new(&free_port(), "", utxo).unwrap();
+        server.send_timeout = Duration::from_millis(500);
+        (server, [blocks_path, utxo_path])
+    }
+
+    #[tokio::test]
+    async fn test_fresh_nodes_on_one_network_converge() {
+        let miner = WalletFixture::new(1);
+        let (first, first_paths) = fresh_node("testnet").await;
+        let (second, second_paths) = fresh_node("testnet").await;
+        let (other, other_paths) = fresh_node("othernet").await;
+        let genesis = first.genesis_hash().await.unwrap().unwrap();
+        assert_eq!(second.genesis_hash().await.unwrap(), Some(genesis.clone()));
+        assert_ne!(other.genesis_hash().await.unwrap(), Some(genesis));
+
+        // The first node mines ahead
+        let start = clock::now_millis();
+        for height in 1..=3 {
+            let tip = first.inner.read().await.utxo.read().await.blockchain.read().await.get_block_by_height(height - 1).unwrap();
+            let block = Block::new_block_at(vec![coinbase(&miner.address(), height)], tip.get_hash(), height, INITIAL_TARGET, start + height as u128).unwrap();
+            first.add_block(block).await.unwrap();
+        }
+
+        let (first_address, other_address) = (first.node_address.clone(), other.node_address.clone());
+        let [first, second, other] = [first, second, other].map(|server| Arc::new(RwLock::new(server)));
+        let running: Vec<_> = [&first, &second, &other].into_iter().map(|server| tokio::spawn(Server::start_server(Arc::clone(server)))).collect();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The second says hello, finds the first ahead and downloads its blocks
+        second.read().await.send_version(&first_address).await.unwrap();
+        let synced = async {
+            while second.read().await.get_block_hashes().await != first.read().await.get_block_hashes().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), synced).await.expect("the chains never converged");
+        assert_eq!(second.read().await.get_best_height().await.unwrap(), 3);
+
+        // A node on another network is dropped as soon as it says hello
+        first.read().await.inner.write().await.known_nodes.insert(other_address.clone(), KnownNode::default());
+        other.read().await.send_version(&first_address).await.unwrap();
+        let dropped = async {
+            while first.read().await.get_known_nodes().await.contains_key(&other_address) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), dropped).await.expect("the node on another network was kept");
+        let history = first.read().await.peer_history().await.unwrap();
+        assert!(matches!(&history[0].reason, RemovalReason::Banned(reason) if reason.starts_with("On another network")));
+        assert_eq!(first.read().await.get_best_height().await.unwrap(), 3);
+
+        for handle in running {
+            handle.abort();
+        }
+        for path in first_paths.iter().chain(&second_paths).chain(&other_paths) {
+            std::fs::remove_dir_all(path).ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_conformance_against_local_node() {
+        let (server, paths) = fresh_node("conformance").await;
+        let target = server.node_address.clone();
+        let server = Arc::new(RwLock::new(server));
+        let running = tokio::spawn(Server::start_server(Arc::clone(&server)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut client = ConformanceClient::bind(&target, "127.0.0.1:0", Duration::from_secs(5)).await.unwrap();
+        let report = client.run().await;
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.checks.len(), 8);
+
+        running.abort();
+        for path in &paths {
+            std::fs::remove_dir_all(path).ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_sync_from_long_chain() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let payment = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let chain = chain.block(vec![payment.clone()]).empty_blocks(1999);
+        let source_blocks: Vec<Block> = chain.build().iter().collect();
+
+        // Serving node
+        let mut source_chain = Blockchain::default_empty();
+        for block in source_blocks.iter().rev() {
+            source_chain.add_block(block.clone()).unwrap();
+        }
+        let source_path = temp_path("source");
+        let source_utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(source_chain)), &source_path).unwrap()));
+        source_utxo.read().await.reindex().await.unwrap();
+        let mut source = Server::new(&free_port(), "", Arc::clone(&source_utxo)).unwrap();
+        source.serve_snapshots = true;
+        let source_address = source.node_address.clone();
+        source.refresh_snapshot().await.unwrap();
+        let root = source.inner.read().await.snapshot.as_ref().unwrap().manifest.root.clone();
+        let source = Arc::new(RwLock::new(source));
+        let serving = tokio::spawn(Server::start_server(Arc::clone(&source)));
+
+        // Fresh node that trusts the source's snapshot root
+        let fresh_path = temp_path("fresh");
+        let fresh_utxo = Arc::new(RwLock::new(UTXOSet::with_path(
+            Arc::new(RwLock::new(ChainBuilder::new(&WalletFixture::new(3)).build())),
+            &fresh_path,
+        ).unwrap()));
+        fresh_utxo.read().await.reindex().await.unwrap();
+        let mut fresh = Server::new(&free_port(), "", Arc::clone(&fresh_utxo)).unwrap();
+        fresh.snapshot_anchor = TrustAnchor::Root(root);
+        let fresh = Arc::new(RwLock::new(fresh));
+        let running = tokio::spawn(Server::start_server(Arc::clone(&fresh)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        fresh.read().await.request_snapshot(&source_address).await.unwrap();
+        let synced = async {
+            while fresh.read().await.get_best_height().await.unwrap() < 2000 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), synced).await.unwrap();
+        let snapshot_sync = started.elapsed();
+
+        // Same balances as the source
+        let balance = |utxo: &UTXOSet, wallet: &WalletFixture| {
+            utxo.find_utxo(&wallet.pub_key_hash()).unwrap().outputs.iter().map(|o| o.value).sum::<i32>()
+        };
+        for wallet in [&miner, &other] {
+            assert_eq!(balance(&*fresh_utxo.read().await, wallet), balance(&*source_utxo.read().await, wallet));
+        }
+        assert_eq!(balance(&*fresh_utxo.read().await, &other), 4);
+
+        // Outputs mined long before the snapshot can be spent and verified
+        let spend = Transaction::new_utxo(std::slice::from_ref(&other.wallet), &miner.address(), SendMode::Amount { amount: 3, fee: 0 }, CoinSelection::default(), Vec::new(), &SameAddress, &fresh_utxo).await.unwrap();
+        assert!(fresh_utxo.read().await.blockchain.read().await.verify_transacton(&spend).unwrap());
+
+        // Downloading every block instead, with a single reindex at the end (cheaper than
+        // updating per block, so this undercounts a real full sync)
+        let started = Instant::now();
+        let full = UtxoFixture::new(Blockchain::default_empty()).await;
+        for block in source_blocks.iter().rev() {
+            full.blockchain.write().await.add_block(block.clone()).unwrap();
+        }
+        full.reindex().await.unwrap();
+        let full_sync = started.elapsed();
+        assert!(snapshot_sync < full_sync, "snapshot sync {:?}, full sync {:?}", snapshot_sync, full_sync);
+
+        serving.abort();
+        running.abort();
+        std::fs::remove_dir_all(&source_path).ok();
+        std::fs::remove_dir_all(&fresh_path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_operator_checkpoint_between_nodes() {
+        let operator = WalletFixture::new(9);
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(1);
+        let fork_base = chain.tip().get_hash();
+        let chain = chain.empty_blocks(2);
+        let bc = chain.build();
+        let pinned = bc.get_block_hashes()[1].clone(); // height 2
+
+        // Receiving node, configured with the operator's key
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+        let utxo_path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(bc)), utxo_path.to_str().unwrap()).unwrap()));
+        let mut receiver = Server::new(&port, "", Arc::clone(&utxo)).unwrap();
+        receiver.operator_key = Some(operator.wallet.public_key.clone());
+        let receiver_address = receiver.node_address.clone();
+        let receiver = Arc::new(RwLock::new(receiver));
+        let running = tokio::spawn(Server::start_server(Arc::clone(&receiver)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Operator node broadcasts the checkpoint
+        let operator_node = test_server();
+        operator_node.inner.write().await.known_nodes = HashMap::from([(receiver_address.clone(), KnownNode::default())]);
+
+        // A forged checkpoint is dropped, the signed one is stored
+        let forged = Checkpoint::sign(2, "00ff", &miner.wallet.secret_key).unwrap();
+        operator_node.send_data(&receiver_address, &operator_node.checkpoint_message(&forged).unwrap()).await.unwrap();
+        let checkpoint = Checkpoint::sign(2, &pinned, &operator.wallet.secret_key).unwrap();
+        let report = operator_node.broadcast_checkpoint(&checkpoint).await.unwrap();
+        assert_eq!(report.delivered, 1);
+
+        let stored = async {
+            loop {
+                let checkpoints = utxo.read().await.blockchain.read().await.get_checkpoints().unwrap();
+                if !checkpoints.is_empty() {
+                    return checkpoints;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let checkpoints = tokio::time::timeout(Duration::from_secs(2), stored).await.unwrap();
+        assert_eq!(checkpoints, std::collections::BTreeMap::from([(2, pinned)]));
+
+        // The receiving node now rejects a fork block at the pinned height
+        let fork = Block::new_block(vec![coinbase(&WalletFixture::new(2).address(), 2)], fork_base, 2, INITIAL_TARGET).unwrap();
+        operator_node.send_block(&receiver_address, &fork).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(utxo.read().await.blockchain.read().await.db.get(fork.get_hash()).unwrap().is_none());
+
+        running.abort();
+        std::fs::remove_dir_all(&utxo_path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_block_over_the_wire_leaves_the_server_running() {
+        let miner = WalletFixture::new(1);
+        let fixture = UtxoFixture::new(ChainBuilder::new(&miner).empty_blocks(1).build()).await;
+        let port = free_port();
+        let utxo = Arc::new(RwLock::new(UTXOSet::clone(&fixture)));
+        let server = Arc::new(RwLock::new(Server::new(&port, "", utxo).unwrap()));
+        let running = tokio::spawn(Server::start_server(Arc::clone(&server)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_address = peer.local_addr().unwrap().to_string();
+        for _ in 0..2 {
+            let id = hex::encode(rand::random::<[u8; 32]>());
+            let msg = GetDatamsg { addr_from: peer_address.clone(), kind: String::from("block"), id: id.clone() };
+            let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+            stream.write_all(&bincode::serialize(&(cmd_to_bytes("getdata"), msg)).unwrap()).await.unwrap();
+            drop(stream);
+
+            let (mut stream, _) = peer.accept().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            assert!(matches!(bytes_to_cmd(&buf).unwrap(), Message::NotFound(msg) if msg.id == id));
+        }
+        assert!(request_health(&format!("127.0.0.1:{}", port)).await.is_ok());
+        assert!(!running.is_finished());
+
+        running.abort();
+        let _ = running.await;
+    }
+
+    #[tokio::test]
+    async fn test_miner_collects_the_fees_it_mines() {
+        let miner = WalletFixture::new(1);
+        let alice = WalletFixture::new(2);
+        let fixture = UtxoFixture::new(ChainBuilder::new(&alice).build()).await; // alice: 10
+        let utxo = Arc::new(RwLock::new(UTXOSet::clone(&fixture)));
+        let server = Server::new(&free_port(), &miner.address(), Arc::clone(&utxo)).unwrap();
+
+        let mode = SendMode::Amount { amount: 5, fee: 2 };
+        let tx = Transaction::new_utxo(std::slice::from_ref(&alice.wallet), &WalletFixture::new(3).address(), mode, CoinSelection::default(), Vec::new(), &SameAddress, &utxo).await.unwrap();
+        assert_eq!(tx.vout.iter().map(|out| out.value).collect::<Vec<_>>(), vec![5, 3]);
+        server.handle_tx(Txmsg { addr_from: String::from("127.0.0.1:1"), transaction: tx }, false).await.unwrap();
+
+        assert_eq!(server.get_best_height().await.unwrap(), 1);
+        assert_eq!(utxo.read().await.get_balance(&miner.address()).unwrap(), 12); // the subsidy and the fee
+        assert_eq!(utxo.read().await.get_balance(&alice.address()).unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_children_of_mempool_transactions_are_mined_with_them() {
+        let miner = WalletFixture::new(1);
+        let alice = WalletFixture::new(2);
+        let bob = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&alice);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let fixture = UtxoFixture::new(chain.build()).await;
+        let utxo = Arc::new(RwLock::new(UTXOSet::clone(&fixture)));
+        let server = Server::new(&free_port(), &miner.address(), Arc::clone(&utxo)).unwrap();
+        let parent = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 9).build(); // fee 1
+        let child = TxBuilder::new(&bob).spend(&parent, 0).pay(&alice.address(), 8).build(); // fee 1
+
+        // The parent is still unconfirmed when the child comes in, both go into the next block
+        add_to_mempool(&mut *server.inner.write().await, parent.clone(), clock::now_millis());
+        server.handle_tx(Txmsg { addr_from: String::from("127.0.0.1:1"), transaction: child.clone() }, false).await.unwrap();
+
+        assert_eq!(server.get_best_height().await.unwrap(), 1);
+        assert!(server.mempool_transactions().await.is_empty());
+        assert_eq!(utxo.read().await.get_balance(&miner.address()).unwrap(), 12);
+        assert_eq!(utxo.read().await.get_balance(&alice.address()).unwrap(), 8);
+        assert_eq!(utxo.read().await.get_balance(&bob.address()).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_send_doesnt_hold_up_other_messages() {
+        // A peer whose accept queue is full, connecting to it hangs until the send times out
+        let stuck = tokio::net::TcpSocket::new_v4().unwrap();
+        stuck.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let stuck = stuck.listen(0).unwrap();
+        let stuck_address = stuck.local_addr().unwrap().to_string();
+        let _queued = std::net::TcpStream::connect(&stuck_address).unwrap(); // fills the queue
+
+        let miner = WalletFixture::new(1);
+        let fixture = UtxoFixture::new(ChainBuilder::new(&miner).build()).await;
+        let port = free_port();
+        let utxo = Arc::new(RwLock::new(UTXOSet::clone(&fixture)));
+        let server = Server::new(&port, &miner.address(), utxo).unwrap();
+        server.inner.write().await.known_nodes = HashMap::from([(stuck_address, KnownNode::default())]);
+        let server = Arc::new(RwLock::new(server));
+        let running = tokio::spawn(Server::start_server(Arc::clone(&server)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let send = |message: Vec<u8>| {
+            let port = port.clone();
+            async move {
+                let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+                stream.write_all(&message).await.unwrap();
+            }
+        };
+
+        // The tx gets mined and the new block announced to the stuck peer
+        let transaction = TxBuilder::new(&miner).spend(&coinbase(&miner.address(), 0), 0).pay(&WalletFixture::new(2).address(), 3).build();
+        let tx = Txmsg { addr_from: String::from("127.0.0.1:1"), transaction };
+        send(bincode::serialize(&(cmd_to_bytes("tx"), tx)).unwrap()).await;
+        let mined = Instant::now();
+        while fixture.blockchain.read().await.get_best_height().unwrap() == 0 {
+            assert!(mined.elapsed() < Duration::from_secs(5), "the tx wasn't mined");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Meanwhile another peer says hello
+        let version_peer = format!("127.0.0.1:{}", free_port());
+        let version = Versionmsg {
+            addr_from: version_peer.clone(), version: VERSION, best_height: 1,
+            capabilities: Capabilities::NONE, timestamp: 0, checkpoint: None, genesis: String::new(),
+            best_work: 0,
+        };
+        let sent = Instant::now();
+        send(bincode::serialize(&(cmd_to_bytes("version"), version)).unwrap()).await;
+        loop {
+            let known = server.read().await.get_known_nodes().await;
+            if known.get(&version_peer).is_some_and(|node| node.version() == Some(VERSION)) {
+                break;
+            }
+            assert!(sent.elapsed() < Duration::from_secs(1), "the version message waited for the stuck send");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(SEND_TIMEOUT > Duration::from_secs(1));
+
+        running.abort();
+        let _ = running.await;
+    }
+
+    #[tokio::test]
+    async fn test_health_report_over_the_wire() {
+        let miner = WalletFixture::new(1);
+        let fixture = UtxoFixture::new(ChainBuilder::new(&miner).empty_blocks(1).build()).await;
+        let port = free_port();
+        let utxo = Arc::new(RwLock::new(UTXOSet::clone(&fixture)));
+        let server = Arc::new(RwLock::new(Server::new(&port, "", utxo).unwrap()));
+        let running = tokio::spawn(Server::start_server(Arc::clone(&server)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // No peer has said anything yet
+        let report = request_health(&format!("127.0.0.1:{}", port)).await.unwrap();
+        assert_eq!(report.status(), health::HealthStatus::Degraded, "{}", report);
+        assert_eq!(report.problems().iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["peers"]);
+
+        running.abort();
+        let _ = running.await;
+        assert!(request_health(&format!("127.0.0.1:{}", port)).await.is_err());
+    }
+
+    // A running node with `blocks` (oldest first) and the given network conditions
+    #[cfg(feature = "chaos")]
+    async fn chaos_node(blocks: &[Block], chaos: ChaosConfig, path: &str) -> (Arc<RwLock<Server>>, tokio::task::JoinHandle<Result<()>>) {
+        let mut chain = Blockchain::default_empty();
+        for block in blocks {
+            chain.add_block(block.clone()).unwrap();
+        }
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+        let server = Server::new(&free_port(), "", utxo).unwrap();
+        server.chaos.set_config(chaos);
+        let server = Arc::new(RwLock::new(server));
+        let running = tokio::spawn(Server::start_server(Arc::clone(&server)));
+        (server, running)
+    }
+
+    // Runs the periodic state check, which is how a node picks up a sync that lost messages,
+    // every 100ms until `node` reaches `height`. Returns the rounds it took
+    #[cfg(feature = "chaos")]
+    async fn sync_rounds(node: &Arc<RwLock<Server>>, height: i32, limit: Duration) -> usize {
+        let rounds = async {
+            let mut rounds = 0;
+            while node.read().await.get_best_height().await.unwrap() < height {
+                rounds += 1;
+                node.read().await.check_and_update_blockchain_state().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            rounds
+        };
+        tokio::time::timeout(limit, rounds).await.expect("sync didn't complete")
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn test_sync_recovers_from_message_loss() {
+        let blocks: Vec<Block> = ChainBuilder::new(&WalletFixture::new(1)).empty_blocks(30).build().iter().collect();
+        let blocks: Vec<Block> = blocks.into_iter().rev().collect();
+        let lossy = |seed| ChaosConfig {
+            default: LinkChaos { latency_ms: 2, jitter_ms: 3, drop_probability: 0.3, ..LinkChaos::default() },
+            seed: Some(seed),
+            ..ChaosConfig::default()
+        };
+        let (source_path, fresh_path) = (temp_path("lossy-source"), temp_path("lossy-fresh"));
+        let (source, serving) = chaos_node(&blocks, lossy(1), &source_path).await;
+        let (fresh, running) = chaos_node(&blocks[..1], lossy(2), &fresh_path).await;
+        let source_address = source.read().await.node_address.clone();
+        fresh.read().await.add_peer(source_address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Every message is lost 30% of the time in both directions, so a single round
+        // practically never gets all 30 blocks across
+        let rounds = sync_rounds(&fresh, 30, Duration::from_secs(60)).await;
+        assert!(rounds > 1, "synced in {} rounds", rounds);
+        assert_eq!(fresh.read().await.get_block_hashes().await, source.read().await.get_block_hashes().await);
+
+        serving.abort();
+        running.abort();
+        std::fs::remove_dir_all(&source_path).ok();
+        std::fs::remove_dir_all(&fresh_path).ok();
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn test_sync_completes_despite_periodic_disconnects() {
+        let blocks: Vec<Block> = ChainBuilder::new(&WalletFixture::new(1)).empty_blocks(30).build().iter().collect();
+        let blocks: Vec<Block> = blocks.into_iter().rev().collect();
+        let flaky = ChaosConfig {
+            default: LinkChaos { latency_ms: 5, disconnect_every_ms: 300, disconnect_for_ms: 100, ..LinkChaos::default() },
+            ..ChaosConfig::default()
+        };
+        let (source_path, fresh_path) = (temp_path("flaky-source"), temp_path("flaky-fresh"));
+        let (source, serving) = chaos_node(&blocks, flaky.clone(), &source_path).await;
+        let (fresh, running) = chaos_node(&blocks[..1], flaky, &fresh_path).await;
+        let source_address = source.read().await.node_address.clone();
+        fresh.read().await.add_peer(source_address.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        sync_rounds(&fresh, 30, Duration::from_secs(60)).await;
+        assert_eq!(fresh.read().await.get_block_hashes().await, source.read().await.get_block_hashes().await);
+        // Refused sends don't count against the peer
+        assert!(fresh.read().await.get_known_nodes().await.contains_key(&source_address));
+
+        serving.abort();
+        running.abort();
+        std::fs::remove_dir_all(&source_path).ok();
+        std::fs::remove_dir_all(&fresh_path).ok();
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{ Serialize, Deserialize };
 use std::fs;
-use serde_json;
+use std::path::{Path, PathBuf};
 use once_cell::sync::Lazy;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -10,19 +10,85 @@ pub enum NodeType {
     Miner, // Mines blocks
 }
 
+// Which chain the node runs on. Development-only tools are limited to Devnet
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ChainType {
+    Mainnet,
+    Devnet,
+}
+
+// Which UTXO snapshots a new node accepts instead of downloading the whole chain
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SnapshotTrust {
+    Disabled,
+    TrustedRoot,    // root equal to snapshot_root
+    OperatorSigned, // root signed with operator_public_key
+}
+
+// Network of settings.json files from before networks were named
+pub const DEFAULT_NETWORK: &str = "mainnet";
+
+/// Directory of the network's databases, relative to the data directory. None when the
+/// name isn't a plain directory name
+pub fn network_dir(network: &str) -> Option<PathBuf> {
+    let plain = !network.is_empty() && network.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    plain.then(|| Path::new("data").join(network))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)] // fields missing from an older settings.json fall back to their defaults
 pub struct Settings {
     pub fullscreen: bool,
     pub resolution: (f32, f32),
     pub default_wallet: String,
     pub max_blocks_loaded: usize,
+    pub confirmation_target: u32, // incoming payments are tracked until this deep
+    pub compact_interval_days: u32, // databases are compacted on exit when this many days passed since the last time. 0 disables
+    pub pending_expiry_hours: u64, // our sends without a block for this long are abandoned and their coins unlocked. 0 disables
+    pub batch_sends: bool, // Send queues payments in the outbox, to go out together in one transaction
+    pub batch_flush_secs: u64, // queued payments older than this are sent without waiting for "Send batch now". 0 disables
+    pub hidden_interval_multiplier: u32, // periodic work runs this many times less often while the window is minimized, see idle.rs
 
     // Node Settings
+    pub data_dir: String, // the node runs from here. BLOCKJAIN_DATA_DIR and --data-dir override it, see config.rs
+    pub network: String, // picks the genesis block, databases are under data/<network>/ of the data directory
+    pub headless: bool, // run the node without the window. BLOCKJAIN_HEADLESS and --headless override it
+    pub chain: ChainType,
     pub node_type: NodeType,
     pub blockchain_state_check_interval: u64,
     pub preferred_miner_address: String,
     pub server_port: String,    // [PORT]
     pub bootstrap_node: String, // 198.2.2.5:[PORT]
+    pub max_concurrent_sends: usize, // Outgoing connections opened at once when broadcasting
+    pub tx_receipts: bool, // ask peers to sign for the transactions we send, see receipt.rs
+    pub burn_address: String, // Treated as unspendable like the genesis placeholder. Empty for none
+    pub dust_threshold: i32, // outputs worth less are dust: our change that small goes to the fee, relayed transactions paying it are rejected. 0 disables
+    pub min_relay_fee_rate: i64, // coins per 1000 bytes, the lowest fee rate estimates suggest and what they fall back to without fee data
+    pub sweep_dust: bool, // coin selection spends the wallet's dust along with the outputs it picks
+    pub change_addresses: bool, // change goes to a new address of the sending wallet instead of back to its own, see wallet.rs
+    pub mempool_expiry_hours: u64, // unmined transactions older than this leave the mempool. 0 keeps them
+    pub max_mempool_mb: u64, // above this the lowest fee rates leave the mempool, see mempool.rs. 0 for no limit
+    pub expected_block_interval: u64, // seconds between blocks on this network
+    pub stale_tip_multiple: u32, // tip older than this many intervals while peers are connected warns and resyncs. 0 disables
+    pub max_upload_kbps: u32, // 0 for no limit
+    pub max_download_kbps: u32, // 0 for no limit
+    pub serve_historical_blocks: bool, // false answers requests for old blocks with notfound, new ones are still relayed
+    pub health_peer_window_mins: u64, // the health check is degraded when no peer sent anything for this long
+    pub prune_keep_blocks: u32, // delete blocks deeper than this, keeping headers and the UTXO set. 0 keeps every block
+    pub disk_warn_mb: u64, // free space below this warns. 0 disables
+    pub disk_stop_mb: u64, // free space below this pauses block downloads and mining. 0 disables
+
+    // Private network
+    pub operator_public_key: String, // hex, checkpoints signed with it are enforced. Empty ignores them
+    pub checkpoints: Vec<(i32, String)>, // (height, block hash) pinned like the built-in checkpoints
+    pub snapshot_trust: SnapshotTrust,
+    pub snapshot_root: String, // hex, the only snapshot accepted with TrustedRoot
+    pub serve_snapshots: bool,
+    pub snapshot_signing_key: String, // hex operator secret key signing served snapshots. Empty serves them unsigned
+    pub coinbase_height_from: i32, // coinbases of blocks from this height on must start with it. 0 on new networks, one with older blocks sets a height it hasn't reached
+
+    // Diagnostics
+    pub verify_block_connect: bool, // Re-derive touched balances from the chain after every block
 }
 
 impl Default for Settings {
@@ -33,13 +99,53 @@ impl Default for Settings {
             resolution: (1000.0, 600.0),
             default_wallet: String::new(),
             max_blocks_loaded: 50,
+            confirmation_target: 6,
+            compact_interval_days: 7,
+            pending_expiry_hours: 24,
+            batch_sends: false,
+            batch_flush_secs: 0,
+            hidden_interval_multiplier: 4,
 
             // Node Settings
+            data_dir: String::from("."),
+            network: String::from(DEFAULT_NETWORK),
+            headless: false,
+            chain: ChainType::Mainnet,
             node_type: NodeType::Regular,
             preferred_miner_address: String::new(),
             blockchain_state_check_interval: 20,
             server_port: String::from("8334"),
             bootstrap_node: String::from("127.0.0.1:8335"),
+            max_concurrent_sends: 8,
+            tx_receipts: true,
+            burn_address: String::new(),
+            dust_threshold: 2,
+            min_relay_fee_rate: 1,
+            sweep_dust: true,
+            change_addresses: true,
+            mempool_expiry_hours: 336,
+            max_mempool_mb: 50,
+            expected_block_interval: 600,
+            stale_tip_multiple: 6,
+            max_upload_kbps: 0,
+            max_download_kbps: 0,
+            serve_historical_blocks: true,
+            health_peer_window_mins: 30,
+            prune_keep_blocks: 0,
+            disk_warn_mb: 1024,
+            disk_stop_mb: 256,
+
+            // Private network
+            operator_public_key: String::new(),
+            checkpoints: Vec::new(),
+            snapshot_trust: SnapshotTrust::Disabled,
+            snapshot_root: String::new(),
+            serve_snapshots: false,
+            snapshot_signing_key: String::new(),
+            coinbase_height_from: 0,
+
+            // Diagnostics
+            verify_block_connect: false,
         }
     }
 }
@@ -60,9 +166,15 @@ impl Settings {
     }
 }
 
+// settings.json in the directory the program was started from. Absolute, the node may move
+// to its data directory afterwards
+pub static SETTINGS_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    std::env::current_dir().unwrap_or_default().join("settings.json")
+});
+
 // Define a globally accessible Settings instance
 pub static SETTINGS: Lazy<Settings> = Lazy::new(|| {
     // Load settings from a file or use defaults
     println!("Loading global application SETTINGS");
-    Settings::load("settings.json") // Replace with your desired settings file path
+    Settings::load(&SETTINGS_PATH.to_string_lossy())
 });
\ No newline at end of file
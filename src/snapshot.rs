@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::settings::{SnapshotTrust, SETTINGS};
+use crate::state_digest::StateDigest;
+use crate::transaction::{LegacyTransaction, Transaction};
+
+/*
+    Snapshot sync
+
+    A new node on a private network doesn't need every historical block. It asks a peer for
+    `getsnapshot` and gets back the UTXO state at some base block, split into chunks, plus the
+    last SNAPSHOT_RECENT_BLOCKS full blocks on top of it. The chain then starts at the base:
+    walks stop there and the snapshot's transactions stand in for the history below it.
+
+    Blocks don't commit to the UTXO state, so the proof of work says nothing about a snapshot.
+    What makes one acceptable is explicit (Settings: snapshot_trust):
+      - TrustedRoot: the root must equal `snapshot_root`, obtained from a node you trust
+      - OperatorSigned: the root must be signed with the operator key (`operator_public_key`)
+    With Disabled, the default, snapshots are never requested nor accepted.
+
+    Snapshots also carry the state digest of the base block (see state_digest.rs), which the
+    received UTXO state has to match. It's printed once applied, for comparing with the
+    digest an operator published.
+*/
+
+// Full blocks sent along with the UTXO state
+pub const SNAPSHOT_RECENT_BLOCKS: usize = 10;
+// Serving nodes rebuild their snapshot once the tip moved this many blocks past it
+pub const SNAPSHOT_INTERVAL: i32 = 100;
+// Transactions per chunk
+pub const SNAPSHOT_CHUNK_ENTRIES: usize = 500;
+
+/// A transaction with unspent outputs, and the indexes of those outputs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotEntry {
+    pub tx: Transaction,
+    pub unspent: Vec<i32>,
+}
+
+/// How snapshot entries were serialized before transactions carried data, in block
+/// databases before schema version 4 and from peers of protocol version 1
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatalessSnapshotEntry {
+    pub tx: LegacyTransaction,
+    pub unspent: Vec<i32>,
+}
+
+impl From<DatalessSnapshotEntry> for SnapshotEntry {
+    fn from(old: DatalessSnapshotEntry) -> SnapshotEntry {
+        SnapshotEntry { tx: old.tx.into(), unspent: old.unspent }
+    }
+}
+
+/// What a snapshot commits to. The root covers the base block and every chunk hash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    pub height: i32,        // of the base block
+    pub block_hash: String, // the base block, the UTXO state is the one right after it
+    pub chunk_hashes: Vec<String>,
+    pub root: String,
+    pub signature: Vec<u8>, // operator signature over the root, empty when unsigned
+}
+
+/// A snapshot as a serving node keeps it
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub manifest: SnapshotManifest,
+    pub chunks: Vec<Vec<SnapshotEntry>>,
+    pub recent_blocks: Vec<Block>, // oldest first, the first one builds on the base block
+    pub state_digest: StateDigest, // at the base block
+}
+
+impl Snapshot {
+    /// Snapshot of `blockchain` with the last `recent` blocks sent in full,
+    /// signed with `signing_key` (an operator secret key) when given
+    pub fn build(blockchain: &Blockchain, recent: usize, signing_key: Option<&[u8]>) -> Result<Snapshot> {
+        let mut recent_blocks: Vec<Block> = blockchain.iter().take(recent).collect();
+        recent_blocks.reverse();
+        let oldest = recent_blocks.first().ok_or_else(|| format_err!("Can't snapshot an empty chain"))?;
+        if recent_blocks.len() < recent || oldest.get_height() == 0 {
+            return Err(format_err!("Chain too short for a snapshot"));
+        }
+        let height = oldest.get_height() - 1;
+        let block_hash = oldest.get_prev_hash();
+
+        let mut entries = blockchain.unspent_transactions(&block_hash)?;
+        entries.sort_by(|a, b| a.tx.id.cmp(&b.tx.id));
+        let state_digest = StateDigest::compute(height, &block_hash, &entries);
+        let chunks: Vec<Vec<SnapshotEntry>> = entries.chunks(SNAPSHOT_CHUNK_ENTRIES).map(|c| c.to_vec()).collect();
+
+        let chunk_hashes = chunks.iter().map(|c| chunk_hash(c)).collect::<Result<Vec<_>>>()?;
+        let mut manifest = SnapshotManifest {
+            root: root(height, &block_hash, &chunk_hashes)?,
+            height,
+            block_hash,
+            chunk_hashes,
+            signature: Vec::new(),
+        };
+        if let Some(secret_key) = signing_key {
+            manifest.sign(secret_key)?;
+        }
+
+        Ok(Snapshot { manifest, chunks, recent_blocks, state_digest })
+    }
+
+    pub fn tip_height(&self) -> i32 {
+        self.manifest.height + self.recent_blocks.len() as i32
+    }
+}
+
+impl SnapshotManifest {
+    pub fn sign(&mut self, operator_secret_key: &[u8]) -> Result<()> {
+        let secret_key: &[u8; 32] = operator_secret_key
+            .try_into()
+            .map_err(|_| format_err!("Operator secret key must be 32 bytes"))?;
+        let signature = SigningKey::from_bytes(secret_key).sign(&self.signed_data()?);
+        self.signature = signature.to_bytes().to_vec();
+        Ok(())
+    }
+
+    // Root recomputed from the fields and signed by `operator_public_key`
+    pub fn verify_signature(&self, operator_public_key: &[u8]) -> bool {
+        let public_key = match <&[u8; 32]>::try_from(operator_public_key).map(VerifyingKey::from_bytes) {
+            Ok(Ok(key)) => key,
+            _ => return false,
+        };
+        let signature = match <&[u8; 64]>::try_from(self.signature.as_slice()) {
+            Ok(bytes) => Signature::from_bytes(bytes),
+            Err(_) => return false,
+        };
+        match self.signed_data() {
+            Ok(data) => self.root_matches() && public_key.verify(&data, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn root_matches(&self) -> bool {
+        root(self.height, &self.block_hash, &self.chunk_hashes).is_ok_and(|root| root == self.root)
+    }
+
+    fn signed_data(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&("opsnapshot", self.height, &self.block_hash, &self.root))?)
+    }
+}
+
+/// Which snapshots this node accepts, from Settings
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrustAnchor {
+    Disabled,
+    Root(String),
+    Operator(Vec<u8>),
+}
+
+impl TrustAnchor {
+    pub fn from_settings() -> TrustAnchor {
+        match SETTINGS.snapshot_trust {
+            SnapshotTrust::Disabled => TrustAnchor::Disabled,
+            SnapshotTrust::TrustedRoot => TrustAnchor::Root(SETTINGS.snapshot_root.clone()),
+            SnapshotTrust::OperatorSigned => match hex::decode(&SETTINGS.operator_public_key) {
+                Ok(key) if key.len() == 32 => TrustAnchor::Operator(key),
+                _ => TrustAnchor::Disabled,
+            },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != TrustAnchor::Disabled
+    }
+
+    pub fn check(&self, manifest: &SnapshotManifest) -> Result<()> {
+        let trusted = match self {
+            TrustAnchor::Disabled => return Err(format_err!("Snapshot sync is disabled")),
+            TrustAnchor::Root(root) => manifest.root_matches() && manifest.root == *root,
+            TrustAnchor::Operator(key) => manifest.verify_signature(key),
+        };
+        if !trusted {
+            return Err(format_err!("Snapshot at height {} with root {} isn't trusted", manifest.height, manifest.root));
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot being received. Chunks may arrive before the manifest
+#[derive(Debug)]
+pub struct SnapshotDownload {
+    pub peer: String,
+    pub manifest: Option<SnapshotManifest>,
+    pub recent_blocks: Vec<Block>,
+    pub state_digest: Option<StateDigest>, // None from peers that don't send one
+    pub chunks: BTreeMap<u32, Vec<SnapshotEntry>>,
+}
+
+impl SnapshotDownload {
+    pub fn new(peer: &str) -> SnapshotDownload {
+        SnapshotDownload {
+            peer: peer.to_string(),
+            manifest: None,
+            recent_blocks: Vec::new(),
+            state_digest: None,
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// The manifest, blocks and every chunk once they're all here and match the manifest.
+    /// Ok(None) while chunks are missing, Err when a chunk doesn't match its hash or the
+    /// state doesn't match the digest.
+    pub fn complete(&self) -> Result<Option<(&SnapshotManifest, Vec<SnapshotEntry>)>> {
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        if self.chunks.len() < manifest.chunk_hashes.len() {
+            return Ok(None);
+        }
+
+        let mut entries = Vec::new();
+        for (index, expected) in manifest.chunk_hashes.iter().enumerate() {
+            let chunk = self.chunks.get(&(index as u32)).ok_or_else(|| format_err!("Snapshot chunk {} is missing", index))?;
+            if chunk_hash(chunk)? != *expected {
+                return Err(format_err!("Snapshot chunk {} doesn't match the manifest", index));
+            }
+            entries.extend(chunk.iter().cloned());
+        }
+        if let Some(digest) = &self.state_digest {
+            if digest.height != manifest.height || digest.block_hash != manifest.block_hash || !digest.matches(&entries) {
+                return Err(format_err!("Snapshot state doesn't match its digest {}", digest));
+            }
+        }
+        Ok(Some((manifest, entries)))
+    }
+}
+
+pub fn chunk_hash(entries: &[SnapshotEntry]) -> Result<String> {
+    // serialized as before transactions carried data, with the data of those that have some,
+    // so chunks without data keep the hashes older peers published
+    let mut data = bincode::serialize(&(entries.len() as u64))?;
+    for entry in entries {
+        let tx = &entry.tx;
+        data.extend(bincode::serialize(&(&tx.id, &tx.vin, &tx.vout))?);
+        if !tx.data.is_empty() {
+            data.extend(bincode::serialize(&tx.data)?);
+        }
+        data.extend(bincode::serialize(&entry.unspent)?);
+    }
+    let mut hasher = Sha256::new();
+    hasher.input(&data);
+    Ok(hasher.result_str())
+}
+
+fn root(height: i32, block_hash: &str, chunk_hashes: &[String]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.input(&bincode::serialize(&(height, block_hash, chunk_hashes))?);
+    Ok(hasher.result_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainBuilder, TxBuilder, WalletFixture};
+
+    #[test]
+    fn test_snapshot_trust() {
+        let operator = WalletFixture::new(9);
+        let miner = WalletFixture::new(1);
+        let chain = ChainBuilder::new(&miner).empty_blocks(SNAPSHOT_RECENT_BLOCKS + 2).build();
+
+        let snapshot = Snapshot::build(&chain, SNAPSHOT_RECENT_BLOCKS, Some(&operator.wallet.secret_key)).unwrap();
+        let manifest = &snapshot.manifest;
+        assert_eq!(manifest.height, 2);
+        assert_eq!(snapshot.tip_height(), chain.get_best_height().unwrap());
+        assert_eq!(snapshot.recent_blocks[0].get_prev_hash(), manifest.block_hash);
+
+        assert!(TrustAnchor::Root(manifest.root.clone()).check(manifest).is_ok());
+        assert!(TrustAnchor::Operator(operator.wallet.public_key.clone()).check(manifest).is_ok());
+        assert!(TrustAnchor::Operator(miner.wallet.public_key.clone()).check(manifest).is_err());
+        assert!(TrustAnchor::Disabled.check(manifest).is_err());
+
+        // A manifest whose chunk list doesn't add up to its root is rejected either way
+        let mut altered = manifest.clone();
+        altered.chunk_hashes.push(String::from("00"));
+        assert!(TrustAnchor::Root(manifest.root.clone()).check(&altered).is_err());
+        assert!(TrustAnchor::Operator(operator.wallet.public_key.clone()).check(&altered).is_err());
+    }
+
+    #[test]
+    fn test_download_checks_chunks() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let chain = chain.block(vec![tx]).empty_blocks(SNAPSHOT_RECENT_BLOCKS).build();
+        let snapshot = Snapshot::build(&chain, SNAPSHOT_RECENT_BLOCKS, None).unwrap();
+
+        // genesis reward is spent, block 1 has its coinbase and the payment
+        let entries: Vec<SnapshotEntry> = snapshot.chunks.concat();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.tx.id != reward.id));
+
+        let mut download = SnapshotDownload::new("peer");
+        download.chunks.insert(0, snapshot.chunks[0].clone());
+        assert!(download.complete().unwrap().is_none()); // no manifest yet
+        download.manifest = Some(snapshot.manifest.clone());
+        let received = download.complete().unwrap().unwrap().1;
+        let ids = |entries: &[SnapshotEntry]| entries.iter().map(|e| (e.tx.id.clone(), e.unspent.clone())).collect::<Vec<_>>();
+        assert_eq!(ids(&received), ids(&entries));
+
+        download.state_digest = Some(snapshot.state_digest.clone());
+        assert!(download.complete().unwrap().is_some());
+        // chunks matching the manifest, but not the digest
+        let mut entries = snapshot.chunks[0].clone();
+        entries[0].unspent.pop();
+        download.state_digest = Some(StateDigest::compute(snapshot.manifest.height, &snapshot.manifest.block_hash, &entries));
+        assert!(download.complete().unwrap_err().to_string().contains("digest"));
+        download.state_digest = Some(snapshot.state_digest.clone());
+
+        download.chunks.get_mut(&0).unwrap()[0].unspent.push(7);
+        assert!(download.complete().is_err());
+    }
+}
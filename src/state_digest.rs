@@ -0,0 +1,141 @@
+use crypto::{digest::Digest, sha2::Sha256};
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::SnapshotEntry;
+
+/*
+    State digests
+
+    A hash of the chain state at a block that anyone holding the same state computes the
+    same way, so a snapshot or chain file can be checked against a digest published by the
+    operator without validating the chain. The block hash commits to every header below it,
+    which makes the digest cover the header chain as well as the UTXO set.
+
+    Format version 1 is the SHA-256, as lowercase hex, of
+
+        "BJSTATE"           7 bytes
+        format version      u8
+        height              i32, little endian
+        block hash          u64 little endian length, then the hex string
+        output count        u64, little endian
+        per unspent output, ordered by txid then output index:
+            txid            u64 little endian length, then the hex string
+            output index    i32, little endian
+            value           i32, little endian
+            pub key hash    u64 little endian length, then the bytes
+
+    The encoding is spelled out here rather than left to bincode, whose output may change
+    between releases. Changing any of it means a new STATE_DIGEST_VERSION: digests of an
+    older version are refused, never silently compared against the new algorithm.
+*/
+
+pub const STATE_DIGEST_VERSION: u8 = 1;
+const DOMAIN: &[u8; 7] = b"BJSTATE";
+
+/// The state of the chain at `block_hash` (at `height`), see above
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StateDigest {
+    pub version: u8,
+    pub height: i32,
+    pub block_hash: String,
+    pub digest: String,
+}
+
+impl StateDigest {
+    /// Digest of the UTXO set `entries` right after `block_hash`. The entries may come in
+    /// any order
+    pub fn compute(height: i32, block_hash: &str, entries: &[SnapshotEntry]) -> StateDigest {
+        let mut outputs: Vec<(&str, i32, &[u8], i32)> = entries.iter()
+            .flat_map(|entry| entry.unspent.iter().filter_map(move |&index| {
+                let out = entry.tx.vout.get(usize::try_from(index).ok()?)?;
+                Some((entry.tx.id.as_str(), index, out.pub_key_hash.as_slice(), out.value))
+            }))
+            .collect();
+        outputs.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+        let mut data = DOMAIN.to_vec();
+        data.push(STATE_DIGEST_VERSION);
+        data.extend_from_slice(&height.to_le_bytes());
+        put_bytes(&mut data, block_hash.as_bytes());
+        data.extend_from_slice(&(outputs.len() as u64).to_le_bytes());
+        for (txid, index, pub_key_hash, value) in outputs {
+            put_bytes(&mut data, txid.as_bytes());
+            data.extend_from_slice(&index.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+            put_bytes(&mut data, pub_key_hash);
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        StateDigest {
+            version: STATE_DIGEST_VERSION,
+            height,
+            block_hash: block_hash.to_string(),
+            digest: hasher.result_str(),
+        }
+    }
+
+    /// Whether `entries` are the state this digest describes. Digests of another format
+    /// version never match
+    pub fn matches(&self, entries: &[SnapshotEntry]) -> bool {
+        self.version == STATE_DIGEST_VERSION && StateDigest::compute(self.height, &self.block_hash, entries) == *self
+    }
+}
+
+impl std::fmt::Display for StateDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}:{} (height {}, block {})", self.version, self.digest, self.height, self.block_hash)
+    }
+}
+
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainBuilder, TxBuilder, WalletFixture};
+
+    #[test]
+    fn test_digest_covers_every_unspent_output() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let chain = chain.block(vec![tx]).empty_blocks(2).build();
+
+        let height = chain.get_best_height().unwrap();
+        let digest = chain.compute_state_digest(height).unwrap();
+        assert_eq!(digest.version, STATE_DIGEST_VERSION);
+        assert_eq!(digest.block_hash, chain.tip);
+        // deterministic, whatever order the entries come in
+        let mut entries = chain.unspent_transactions(&chain.tip).unwrap();
+        assert!(digest.matches(&entries));
+        entries.reverse();
+        assert!(digest.matches(&entries));
+        assert_eq!(chain.compute_state_digest(height).unwrap(), digest);
+
+        // one output worth a coin more
+        let mut mutated = entries.clone();
+        let index = mutated[0].unspent[0] as usize;
+        mutated[0].tx.vout[index].value += 1;
+        assert_ne!(StateDigest::compute(height, &chain.tip, &mutated), digest);
+        assert!(!digest.matches(&mutated));
+
+        // an output spent
+        let mut spent = entries.clone();
+        spent.retain(|entry| entry.tx.vout.len() < 2);
+        assert!(!digest.matches(&spent));
+
+        // the state of an earlier block
+        let earlier = chain.compute_state_digest(height - 1).unwrap();
+        assert_ne!(earlier.digest, digest.digest);
+
+        let mut future = digest.clone();
+        future.version += 1;
+        assert!(!future.matches(&entries));
+    }
+}
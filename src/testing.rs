@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use ed25519_dalek::{Signer, SigningKey};
+use tokio::sync::RwLock;
+
+use crate::address;
+use crate::block::{Block, DatalessBlock, INITIAL_TARGET};
+use crate::blockchain::Blockchain;
+use crate::settings::SETTINGS;
+use crate::transaction::{LegacyTransaction, Transaction};
+use crate::tx::{TXInput, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallet;
+
+/*
+    Test fixtures
+
+    Builders for wallets, signed transactions and small chains so tests don't need
+    the data/ databases or the network. Blocks are mined at the test difficulty.
+    Fixtures panic on failure, they are only meant to be used from tests.
+*/
+
+/// A wallet whose keys are derived from `seed`, the same seed always gives the same address
+pub struct WalletFixture {
+    pub wallet: Wallet,
+}
+
+impl WalletFixture {
+    pub fn new(seed: u64) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.input(&seed.to_le_bytes());
+        let mut secret_key = [0u8; 32];
+        hasher.result(&mut secret_key);
+
+        Self { wallet: Wallet::from_secret_key(&secret_key) }
+    }
+
+    pub fn address(&self) -> String {
+        self.wallet.get_address()
+    }
+
+    pub fn pub_key_hash(&self) -> Vec<u8> {
+        address::pub_key_to_hash(&self.wallet.public_key)
+    }
+}
+
+/// Coinbase paying the block subsidy to `to`. The data depends on the height only,
+/// so the txid is stable across runs.
+pub fn coinbase(to: &str, height: i32) -> Transaction {
+    Transaction::new_coinbase(to.to_string(), format!("Fixture reward at height {}", height), height).unwrap()
+}
+
+/// `tx` as serialized before transactions carried data. Its data is dropped
+pub fn legacy_tx(tx: &Transaction) -> LegacyTransaction {
+    LegacyTransaction { id: tx.id.clone(), vin: tx.vin.clone(), vout: tx.vout.clone() }
+}
+
+/// `block` as serialized before transactions carried data
+pub fn dataless_block(block: &Block) -> DatalessBlock {
+    DatalessBlock {
+        timestamp: block.get_timestamp(),
+        transactions: block.get_transactions().iter().map(legacy_tx).collect(),
+        prev_block_hash: block.get_prev_hash(),
+        merkle_root: block.get_merkle_root(),
+        hash: block.get_hash(),
+        height: block.get_height(),
+        target: block.get_target(),
+        nonce: block.get_nonce(),
+    }
+}
+
+/// Retries `open` while the database's lock is still held. sled lets go of it from background
+/// threads, opening it again right after a drop can find it still held when the machine is busy
+pub fn reopen<T, E: std::fmt::Display>(open: impl Fn() -> std::result::Result<T, E>) -> std::result::Result<T, E> {
+    let started = std::time::Instant::now();
+    loop {
+        match open() {
+            Err(e) if e.to_string().contains("could not acquire lock") && started.elapsed() < std::time::Duration::from_secs(5) => {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Builds a transaction spending outputs of `from` and signs it against the spent
+/// transactions directly, without looking anything up in a chain or UTXO set.
+pub struct TxBuilder<'a> {
+    from: &'a WalletFixture,
+    // previous transaction, output index
+    spends: Vec<(Transaction, i32)>,
+    outputs: Vec<TXOutput>,
+    data: Vec<u8>,
+}
+
+impl<'a> TxBuilder<'a> {
+    pub fn new(from: &'a WalletFixture) -> Self {
+        Self { from, spends: Vec::new(), outputs: Vec::new(), data: Vec::new() }
+    }
+
+    pub fn spend(mut self, prev_tx: &Transaction, vout: i32) -> Self {
+        self.spends.push((prev_tx.clone(), vout));
+        self
+    }
+
+    pub fn pay(mut self, to: &str, amount: i32) -> Self {
+        self.outputs.push(TXOutput::new(amount, to.to_string()).unwrap());
+        self
+    }
+
+    pub fn burn(mut self, amount: i32) -> Self {
+        self.outputs.push(TXOutput::new_burn(amount));
+        self
+    }
+
+    pub fn data(mut self, data: Vec<u8>) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn build(self) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: self.spends.iter().map(|(prev_tx, vout)| TXInput {
+                txid: prev_tx.id.clone(),
+                vout: *vout,
+                signature: Vec::new(),
+                pub_key: self.from.wallet.public_key.clone(),
+            }).collect(),
+            vout: self.outputs,
+            data: self.data,
+        };
+        tx.id = tx.hash().unwrap();
+
+        let prev_txs: HashMap<String, Transaction> = self.spends.into_iter()
+            .map(|(prev_tx, _)| (prev_tx.id.clone(), prev_tx))
+            .collect();
+        tx.sign(&self.from.wallet.secret_key, prev_txs).unwrap();
+        tx
+    }
+
+    /// Like `build`, signed the way inputs were before the versioned sighash, which is still
+    /// accepted below SIGHASH_ACTIVATION_HEIGHT
+    pub fn build_legacy(self) -> Transaction {
+        let pub_key_hash = self.from.pub_key_hash();
+        let key = SigningKey::from_bytes(self.from.wallet.secret_key.as_slice().try_into().unwrap());
+        let mut tx = self.build();
+        for in_id in 0..tx.vin.len() {
+            let message = tx.legacy_sighash(in_id, &pub_key_hash).unwrap();
+            tx.vin[in_id].signature = key.sign(message.as_bytes()).to_bytes().to_vec();
+        }
+        tx
+    }
+}
+
+// Timestamp of the ChainBuilder genesis block, far enough back that its blocks stay in the
+// past and ones stamped with the current time still build on them
+const GENESIS_TIME: u128 = 1_700_000_000_000;
+
+/// Assembles a chain in a temporary (in-memory) Blockchain. Every block gets a
+/// coinbase paying `miner`, the genesis block is created by `new`.
+pub struct ChainBuilder {
+    blockchain: Blockchain,
+    miner: String,
+}
+
+impl ChainBuilder {
+    pub fn new(miner: &WalletFixture) -> Self {
+        let mut blockchain = Blockchain::default_empty();
+        let genesis = Block::new_block_at(vec![coinbase(&miner.address(), 0)], String::new(), 0, INITIAL_TARGET, GENESIS_TIME).unwrap();
+        blockchain.add_block(genesis).unwrap();
+
+        Self { blockchain, miner: miner.address() }
+    }
+
+    /// Mines a block with `transactions` on top of the current tip
+    pub fn block(mut self, transactions: Vec<Transaction>) -> Self {
+        let block = self.next_block(transactions);
+        self.blockchain.add_block(block).unwrap();
+        self
+    }
+
+    pub fn empty_blocks(mut self, count: usize) -> Self {
+        for _ in 0..count {
+            self = self.block(Vec::new());
+        }
+        self
+    }
+
+    /// A valid block on top of the current tip that isn't added to the chain. It's
+    /// timestamped one `expected_block_interval` after the tip, so however fast blocks are
+    /// mined the difficulty stays where it started
+    pub fn next_block(&self, transactions: Vec<Transaction>) -> Block {
+        let tip = self.tip();
+        let height = tip.get_height() + 1;
+        let mut txs = vec![coinbase(&self.miner, height)];
+        txs.extend(transactions);
+        let target = self.blockchain.next_target(Some(&tip)).unwrap();
+        let timestamp = tip.get_timestamp() + SETTINGS.expected_block_interval as u128 * 1000;
+        Block::new_block_at(txs, tip.get_hash(), height, target, timestamp).unwrap()
+    }
+
+    pub fn tip(&self) -> Block {
+        self.blockchain.get_block(&self.blockchain.tip).unwrap()
+    }
+
+    pub fn build(self) -> Blockchain {
+        self.blockchain
+    }
+}
+
+/// A reindexed UTXO set in its own temporary directory, removed on drop
+pub struct UtxoFixture {
+    utxo_set: UTXOSet,
+    path: PathBuf,
+}
+
+impl UtxoFixture {
+    pub async fn new(blockchain: Blockchain) -> Self {
+        let path = std::env::temp_dir().join(format!("blockjain-fixture-utxos-{}", rand::random::<u64>()));
+        let utxo_set = UTXOSet::with_path(Arc::new(RwLock::new(blockchain)), path.to_str().unwrap()).unwrap();
+        utxo_set.reindex().await.unwrap();
+
+        Self { utxo_set, path }
+    }
+}
+
+impl Deref for UtxoFixture {
+    type Target = UTXOSet;
+
+    fn deref(&self) -> &UTXOSet {
+        &self.utxo_set
+    }
+}
+
+impl Drop for UtxoFixture {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
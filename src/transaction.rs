@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use ed25519_dalek::{VerifyingKey, Verifier, SigningKey, Signature, Signer};
 use crypto::{digest::Digest, sha2::Sha256};
@@ -6,97 +6,424 @@ use failure::format_err;
 use log::error;
 use rand::rngs::OsRng;
 use rand::RngCore;
+use crate::address;
+use crate::coin_selection::{Candidate, CoinSelection};
+use crate::settings::SETTINGS;
 use crate::utxoset::UTXOSet;
-use crate::wallet::Wallet;
-use crate::{ errors::Result, tx::{TXInput, TXOutput}};
+use crate::wallet::{ChangeAddressProvider, Wallet};
+use crate::{ errors::{DecodeError, OwnershipError, Result, TxRuleError}, tx::{TXInput, TXOutput}};
 use serde::{Deserialize, Serialize};
-use bitcoincash_addr::Address;
 
-const SUBSIDY: i32 = 10;
+// Coinbase subsidy of the first blocks, halved every HALVING_INTERVAL blocks down to zero
+pub const INITIAL_SUBSIDY: i32 = 10;
+pub const HALVING_INTERVAL: i32 = 100_000;
 
+// Fee rate used when the user doesn't pick one, in coins per 1000 serialized bytes
+pub const DEFAULT_FEE_RATE: i32 = 1;
+
+// A replacement pays at least this rate of its own size on top of the fees it replaces, see mempool
+pub const MIN_FEE_BUMP_RATE: i32 = 1;
+
+// Largest memo a transaction carries, see Transaction::data
+pub const MAX_TX_DATA: usize = 256;
+
+// Blocks from this height on only take signatures of the sighash, see Signature hashes
+pub const SIGHASH_ACTIVATION_HEIGHT: i32 = 20_000;
+const SIGHASH_VERSION: u32 = 1;
+
+
+/// What a payment from one wallet sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendMode {
+    /// `amount` coins and `fee` for the miner, the rest of the inputs comes back as change
+    Amount { amount: i32, fee: i32 },
+    /// Every coin of the wallet less `fee` in one output, see `Transaction::new_sweep`
+    Sweep { fee: i32 },
+}
+
+/// What a send of an amount would spend, see `Transaction::preview_send`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendPreview {
+    pub inputs: usize,
+    pub total: i32, // of the inputs
+    pub fee: i32, // asked for, dust aside
+    pub change: i32, // 0 for no change output
+    pub dust_folded: i32, // change too small for an output, paid as fee
+}
+
+/*
+    Transaction data
+
+    A transaction may carry up to MAX_TX_DATA bytes of data, a memo like an invoice id, that
+    nothing else reads. It's hashed after the inputs and outputs, so it's signed with them and
+    can't be stripped or changed on the way. Transactions without data hash as they did before
+    the field existed and keep their ids.
+
+    The field changed how transactions are serialized. Older layouts are read as
+    LegacyTransaction: blocks and snapshot entries of block databases before schema version 4
+    (see schema), chain files before BJCHAIN3, raw hex and messages from peers of protocol
+    version 1. Snapshot chunks are hashed like transaction ids, without empty data.
+*/
+
+/*
+    Signature hashes
+
+    Each input signs the SHA-256 digest of the sighash serialization of its transaction, built
+    field by field rather than with bincode so it doesn't move with the wire format:
+
+        SIGHASH_VERSION                     u32
+        number of inputs                    u32
+          per input: txid, vout, lock       the lock of the output it spends for the input
+                                            being signed, empty for the others
+        number of outputs                   u32
+          per output: value, lock
+        data
+
+    Integers are little endian and byte strings are prefixed with their length as a u32.
+    Signatures and public keys of the inputs are left out. Transactions have no version or
+    locktime of their own, the sighash version stands for both.
+
+    Inputs used to sign the hex string id of a trimmed copy of the transaction. Blocks below
+    SIGHASH_ACTIVATION_HEIGHT, and the mempool until the chain gets there, accept either.
+*/
 
 #[derive( Serialize, Deserialize, Debug, Clone )]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    #[serde(default)] // pending sends saved before data
+    pub data: Vec<u8>, // empty for none
+}
+
+/// A transaction as serialized before data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LegacyTransaction {
+    pub id: String,
+    pub vin: Vec<TXInput>,
+    pub vout: Vec<TXOutput>,
+}
+
+impl From<LegacyTransaction> for Transaction {
+    fn from(old: LegacyTransaction) -> Transaction {
+        Transaction { id: old.id, vin: old.vin, vout: old.vout, data: Vec::new() }
+    }
 }
 
 impl Transaction {
 
-    pub async fn new_utxo(wallet: &Wallet, to: &str, amount: i32, utxo: &Arc<tokio::sync::RwLock<UTXOSet>>) -> Result<Transaction> {
+    /// Pays `to` out of `wallets`, spent in order. `selection` picks the inputs of an amount,
+    /// a sweep spends them all. Change goes where `change` says for the first wallet. `data`
+    /// is attached to payments of an amount, see Transaction::data
+    pub async fn new_utxo(
+        wallets: &[Wallet],
+        to: &str,
+        mode: SendMode,
+        selection: CoinSelection,
+        data: Vec<u8>,
+        change: &dyn ChangeAddressProvider,
+        utxo: &Arc<tokio::sync::RwLock<UTXOSet>>,
+    ) -> Result<Transaction> {
+        let from = wallets.first().ok_or_else(|| format_err!("No wallets to fund the transaction"))?;
         println!(
-            "new UTXO Transaction from: {} to: {}",
-            &wallet.get_address(),
+            "new UTXO Transaction from: {} ({} wallets) to: {}",
+            &from.get_address(),
+            wallets.len(),
             &to
         );
 
-        let mut vin = Vec::new();
-        
-        // Raw hash representation for comparison
-        let pub_key_hash = Address::decode(&wallet.get_address()).unwrap().body;
+        match mode {
+            SendMode::Amount { amount, fee } => Transaction::new_paying(wallets, vec![TXOutput::new(amount, to.to_string())?], fee, selection, data, change, utxo).await,
+            SendMode::Sweep { .. } if !data.is_empty() => Err(format_err!("Data can't be attached to a sweep")),
+            SendMode::Sweep { fee } => Transaction::new_sweep(wallets, to, fee, utxo).await,
+        }
+    }
+
+    /// Amount and fee of a `SendMode::Sweep` of the wallets at `fee_rate` right now
+    pub async fn sweep_amount(wallets: &[Wallet], fee_rate: i32, utxo: &Arc<tokio::sync::RwLock<UTXOSet>>) -> Result<(i32, i32)> {
+        let utxo = utxo.read().await;
+        let (mut total, mut inputs) = (0i32, 0);
+        for wallet in unique_wallets(wallets) {
+            check_sweepable(&utxo, wallet)?;
+            for (_, _, value) in utxo.spendable_outputs(&address::pub_key_to_hash(&wallet.public_key))? {
+                total = total.saturating_add(value);
+                inputs += 1;
+            }
+        }
+        send_max_amount(total, inputs, fee_rate)
+    }
+
+    /// Empties the wallets (a wallet and its change wallets) into `to`: one output of every
+    /// coin they hold less `fee`, no change. Fails when a send in the works holds some of
+    /// their outputs, as they wouldn't end up empty
+    pub async fn new_sweep(wallets: &[Wallet], to: &str, fee: i32, utxo: &Arc<tokio::sync::RwLock<UTXOSet>>) -> Result<Transaction> {
+        address::address_to_hash(to)?;
+        if fee < 0 {
+            return Err(format_err!("Invalid fee {}", fee));
+        }
+        let mut spent: Vec<(&Wallet, Candidate)> = Vec::new();
+        {
+            let utxo = utxo.read().await;
+            for wallet in unique_wallets(wallets) {
+                if let Err(e) = check_sweepable(&utxo, wallet) {
+                    utxo.release(spent.into_iter().map(|(_, (txid, vout, _))| (txid, vout)));
+                    return Err(e);
+                }
+                let picked = utxo.reserve_outputs(&address::pub_key_to_hash(&wallet.public_key), |candidates| (0..candidates.len()).collect())?;
+                spent.extend(picked.into_iter().map(|candidate| (wallet, candidate)));
+            }
+        }
+        let total: i64 = spent.iter().map(|(_, (_, _, value))| *value as i64).sum();
+        let swept = if total <= fee as i64 {
+            Err(format_err!("Balance {} doesn't cover the fee {}", total, fee))
+        } else {
+            i32::try_from(total - fee as i64)
+                .map_err(|_| format_err!("Balance {} is too large for one output", total))
+                .and_then(|amount| check_dust(amount, SETTINGS.dust_threshold).map(|_| amount))
+        };
+        let amount = match swept {
+            Ok(amount) => amount,
+            Err(e) => {
+                utxo.read().await.release(spent.into_iter().map(|(_, (txid, vout, _))| (txid, vout)));
+                return Err(e);
+            }
+        };
+        println!("Sweeping {} outputs, {} coins after a fee of {}", spent.len(), amount, fee);
 
-        let acc_v = utxo.read().await.find_spendable_outputs(&pub_key_hash, amount)?;
+        let vin: Vec<TXInput> = spent.into_iter()
+            .map(|(wallet, (txid, vout, _))| TXInput { txid, vout, signature: Vec::new(), pub_key: wallet.public_key.clone() })
+            .collect();
+        let reserved: Vec<(String, i32)> = vin.iter().map(|input| (input.txid.clone(), input.vout)).collect();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout: vec![TXOutput::new(amount, to.to_string())?],
+            data: Vec::new(),
+        };
+        let signed = match tx.hash() {
+            Ok(id) => {
+                tx.id = id;
+                utxo.write().await.blockchain.write().await.sign_transaction_with_keys(&mut tx, &signing_keys(wallets))
+            }
+            Err(e) => Err(e),
+        };
+        // a sweep that isn't sent mustn't keep the wallet's outputs from the next one
+        if let Err(e) = signed {
+            utxo.read().await.release(reserved);
+            return Err(e);
+        }
+
+        Ok(tx)
+    }
+
+    /// Destroys `amount` of the wallets' coins with a burn output, change goes where `change` says
+    pub async fn new_burn(wallets: &[Wallet], amount: i32, change: &dyn ChangeAddressProvider, utxo: &Arc<tokio::sync::RwLock<UTXOSet>>) -> Result<Transaction> {
+        println!("new burn Transaction from {} wallets amount: {}", wallets.len(), amount);
+
+        Transaction::new_paying(wallets, vec![TXOutput::new_burn(amount)], 0, CoinSelection::default(), Vec::new(), change, utxo).await
+    }
+
+    /// Pays every (address, amount) of `payments` from the wallets in one transaction, an
+    /// output per payment in order and one change output after them
+    pub async fn new_batch(wallets: &[Wallet], payments: &[(String, i32)], change: &dyn ChangeAddressProvider, utxo: &Arc<tokio::sync::RwLock<UTXOSet>>) -> Result<Transaction> {
+        println!("new batch Transaction from {} wallets paying {} addresses", wallets.len(), payments.len());
+
+        if payments.is_empty() {
+            return Err(format_err!("No payments to batch"));
+        }
+        let mut outputs = Vec::new();
+        for (to, amount) in payments {
+            outputs.push(TXOutput::new(*amount, to.clone())?);
+        }
+        Transaction::new_paying(wallets, outputs, 0, CoinSelection::default(), Vec::new(), change, utxo).await
+    }
+
+    // Funds `outputs` and `fee` from the wallets' spendable outputs and signs the transaction.
+    // Outputs are taken from the wallets in order, until they cover it, each input is signed
+    // by the wallet owning the output it spends. `selection` picks them within each wallet
+    async fn new_paying(
+        wallets: &[Wallet],
+        outputs: Vec<TXOutput>,
+        fee: i32,
+        selection: CoinSelection,
+        data: Vec<u8>,
+        change: &dyn ChangeAddressProvider,
+        utxo: &Arc<tokio::sync::RwLock<UTXOSet>>,
+    ) -> Result<Transaction> {
+        let change_wallet = wallets.first().ok_or_else(|| format_err!("No wallets to fund the transaction"))?;
+        outputs.iter().try_for_each(|out| check_dust(out.value, SETTINGS.dust_threshold))?;
+        if fee < 0 {
+            return Err(format_err!("The fee can't be negative"));
+        }
+        check_data(&data)?;
+        let needed = outputs.iter().try_fold(fee, |sum, out| sum.checked_add(out.value))
+            .ok_or_else(|| format_err!("The amounts add up to more than can exist"))?;
+
+        let mut total = 0;
+        let mut vin = Vec::new();
+        {
+            let utxo = utxo.read().await;
+            for wallet in unique_wallets(wallets) {
+                if total >= needed {
+                    break;
+                }
+                let (found, spendable) = utxo.reserve_spendable_outputs(&address::pub_key_to_hash(&wallet.public_key), needed - total, selection)?;
+                total += found;
+                vin.extend(Transaction::inputs_for(wallet, spendable));
+            }
+        }
 
-        if acc_v.0 < amount {
+        if total < needed {
             error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
+            utxo.read().await.release(vin.iter().map(|input| (input.txid.clone(), input.vout)));
+            return Err(format_err!("Not Enough balance: current balance {}", total));
         }
 
-        // Construct transaction inputs (vin)
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
+        // Change goes to the address the provider gives. Dust goes to the fee
+        let mut vout = outputs;
+        let (change_value, _) = fold_dust(total - needed, SETTINGS.dust_threshold);
+        if change_value > 0 {
+            let to_change = change.change_address(change_wallet)
+                .and_then(|address| TXOutput::new(change_value, address));
+            match to_change {
+                Ok(out) => vout.push(out),
+                Err(e) => {
+                    utxo.read().await.release(vin.iter().map(|input| (input.txid.clone(), input.vout)));
+                    return Err(e);
+                }
             }
         }
 
-        // Construct transaction outputs (vout)
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
+        let mut tx = Transaction { id: String::new(), vin, vout, data };
+        tx.id = tx.hash()?;
+
+        utxo.write().await.blockchain.write().await.sign_transaction_with_keys(&mut tx, &signing_keys(wallets))?;
+
+        Ok(tx)
+    }
+
+    /// The inputs and change of paying `amount` and `fee` from `wallets` as `new_utxo` would
+    /// right now, without reserving anything
+    pub async fn preview_send(wallets: &[Wallet], amount: i32, fee: i32, selection: CoinSelection, utxo: &Arc<tokio::sync::RwLock<UTXOSet>>) -> Result<SendPreview> {
+        check_dust(amount, SETTINGS.dust_threshold)?;
+        let needed = amount.checked_add(fee).filter(|_| fee >= 0).ok_or_else(|| format_err!("Invalid amount or fee"))?;
+        let utxo = utxo.read().await;
+        let (mut total, mut inputs) = (0, 0);
+        for pub_key_hash in distinct_keys(wallets) {
+            if total >= needed {
+                break;
+            }
+            let (found, spendable) = utxo.find_spendable_outputs(&pub_key_hash, needed - total, selection)?;
+            total += found;
+            inputs += spendable.values().map(|outs| outs.len()).sum::<usize>();
+        }
+        if total < needed {
+            return Err(format_err!("Not Enough balance: current balance {}", total));
+        }
+        let (change, dust_folded) = fold_dust(total - needed, SETTINGS.dust_threshold);
+        Ok(SendPreview { inputs, total, fee, change, dust_folded })
+    }
+
+    /// Rebuilds `original` spending the same inputs with a higher fee: at least `fee_rate`
+    /// and `min_fee_bump` more than the original paid, so it replaces the original in mempools.
+    /// The increase comes out of the change output, or out of the only output of a send max.
+    /// Change left below the dust threshold goes to the fee as well. `wallets` must hold the
+    /// keys of every input, and of the change wallet if any
+    pub async fn bump_fee(original: &Transaction, wallets: &[Wallet], fee_rate: i32, utxo: &Arc<tokio::sync::RwLock<UTXOSet>>) -> Result<Transaction> {
+        println!("bump fee of Transaction {}", &original.id);
 
-        // If there's change, send it back to the sender's address
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?);
+        let mut keys: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        for vin in &original.vin {
+            let pub_key_hash = address::pub_key_to_hash(&vin.pub_key);
+            let wallet = wallets.iter()
+                .find(|wallet| wallet.public_key == vin.pub_key)
+                .ok_or_else(|| format_err!("No wallet for input {}:{}", vin.txid, vin.vout))?;
+            keys.insert(pub_key_hash, wallet.secret_key.clone());
         }
 
-        // Create the transaction
+        let mut inputs: i64 = 0;
+        {
+            let utxo = utxo.read().await;
+            let blockchain = utxo.blockchain.read().await;
+            for vin in &original.vin {
+                let prev_tx = blockchain.find_unspent(&vin.txid, vin.vout)?.ok_or_else(|| {
+                    format_err!("Input {}:{} is already spent, the transaction may have confirmed", vin.txid, vin.vout)
+                })?;
+                inputs += referenced_output(&prev_tx, vin.vout)?.value as i64;
+            }
+        }
+        let outputs: i64 = original.vout.iter().map(|out| out.value as i64).sum();
+        let old_fee = (inputs - outputs) as i32;
+        let size = estimate_size(original.vin.len(), original.vout.len()) + original.data.len();
+        let new_fee = (old_fee + min_fee_bump(size)).max(fee_for_size(size, fee_rate));
+
+        let own = signing_keys(wallets);
+        let change = original.vout.iter().rposition(|out| own.contains_key(&out.pub_key_hash))
+            .or_else(|| (original.vout.len() == 1).then_some(0))
+            .ok_or_else(|| format_err!("Transaction {} has no change to pay a higher fee from", original.id))?;
         let mut tx = Transaction {
             id: String::new(),
-            vin,
-            vout,
+            vin: original.vin.iter().map(|vin| TXInput { signature: Vec::new(), ..vin.clone() }).collect(),
+            vout: original.vout.clone(),
+            data: original.data.clone(),
         };
-
-        // Generate the transaction hash
+        let left = tx.vout[change].value - (new_fee - old_fee);
+        let (kept, _) = fold_dust(left, SETTINGS.dust_threshold);
+        if left <= 0 || (kept == 0 && tx.vout.len() == 1) {
+            return Err(format_err!("Output {} of {} doesn't cover a fee of {}", change, original.id, new_fee));
+        }
+        if kept == 0 {
+            tx.vout.remove(change);
+        } else {
+            tx.vout[change].value = kept;
+        }
         tx.id = tx.hash()?;
 
-        utxo.write().await.blockchain.write().await.sign_transacton(&mut tx, &wallet.secret_key)?;
-        
+        utxo.write().await.blockchain.write().await.sign_transaction_with_keys(&mut tx, &keys)?;
+
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    // Unsigned inputs spending the selected outputs (txid -> output indexes) with the wallet's key
+    fn inputs_for(wallet: &Wallet, spendable: HashMap<String, Vec<i32>>) -> Vec<TXInput> {
+        let mut vin = Vec::new();
+        for (txid, outs) in spendable {
+            for out in outs {
+                vin.push(TXInput {
+                    txid: txid.clone(),
+                    vout: out,
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                });
+            }
+        }
+        vin
+    }
+
+    /// Coinbase of a block at `height`, paying its subsidy
+    pub fn new_coinbase(to: String, data: String, height: i32) -> Result<Transaction> {
+        Transaction::new_coinbase_with_fees(to, data, height, 0)
+    }
+
+    /// Coinbase of a block at `height` paying the subsidy plus `fees`, what the other
+    /// transactions of its block leave over
+    pub fn new_coinbase_with_fees(to: String, mut data: String, height: i32, fees: i32) -> Result<Transaction> {
         // When does this increase someones coinbase ?
         // Where is this used* ^ 
         println!("new coinbase Transaction to: {}", &to);
 
         let mut key: [u8; 32] = [0; 32];
         if data.is_empty() {
-            let mut rand = OsRng::default();
+            let mut rand = OsRng;
             rand.fill_bytes(&mut key);
             data = format!("Reward to '{}'", to);
         }
 
-        let mut pub_key = Vec::from(data.as_bytes());
-        pub_key.append(&mut Vec::from(key));
-        
+        // the height keeps the coinbases of different blocks apart whatever their data
+        let mut pub_key = height.to_le_bytes().to_vec();
+        pub_key.extend(data.as_bytes());
+        pub_key.extend(key);
+
 
         // Coinbase Transaction has no id, no txid
         let mut tx = Transaction {
@@ -107,42 +434,49 @@ impl Transaction {
                 signature: Vec::new(),
                 pub_key,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(block_subsidy(height) + fees, to)?],
+            data: Vec::new(),
         };
 
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    /// The height the input of a coinbase starts with, little endian. Coinbases of blocks
+    /// below Settings: coinbase_height_from may start with anything
+    pub fn coinbase_height(&self) -> Option<i32> {
+        let prefix = self.vin.first().filter(|_| self.is_coinbase())?.pub_key.get(..4)?;
+        Some(i32::from_le_bytes(prefix.try_into().ok()?))
+    }
+
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1 
     }
 
-    /// Verify verifies signatures of Transaction inputs
+    /// Verify verifies signatures of Transaction inputs, which must sign their sighash
     pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
+        self.verify_at(prev_txs, SIGHASH_ACTIVATION_HEIGHT)
+    }
+
+    /// Verifies the signatures for a block at `height`, legacy ones are fine below
+    /// SIGHASH_ACTIVATION_HEIGHT. An input whose key doesn't own the output it spends is an
+    /// `OwnershipError`
+    pub fn verify_at(&self, prev_txs: HashMap<String, Transaction>, height: i32) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
 
         for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
+            if prev_txs.get(&vin.txid).is_none_or(|prev| prev.id.is_empty()) {
                 return Err(format_err!("ERROR: Previous transaction is not correct"));
             }
         }
 
-        let mut tx_copy = self.trim_copy();
-
         for in_id in 0..self.vin.len() {
-            let prev_tx = prev_txs.get(&self.vin[in_id].txid).unwrap();
-
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
+            let prev_tx = &prev_txs[&self.vin[in_id].txid]; // checked above
+            let pub_key_hash = &referenced_output(prev_tx, self.vin[in_id].vout)?.pub_key_hash;
+            check_owner(&self.vin[in_id], pub_key_hash)?;
 
-        
              // Convert public key and signature from bytes
             let public_key_bytes = &self.vin[in_id].pub_key;
             let signature_bytes = &self.vin[in_id].signature;
@@ -168,7 +502,10 @@ impl Transaction {
             let signature = Signature::from_bytes(signature_array);
 
                 // Verify the signature
-            if public_key.verify(tx_copy.id.as_bytes(), &signature).is_err() {
+            let signs = |message: &[u8]| public_key.verify(message, &signature).is_ok();
+            let signed = signs(&self.sighash(in_id, pub_key_hash))
+                || (height < SIGHASH_ACTIVATION_HEIGHT && signs(self.legacy_sighash(in_id, pub_key_hash)?.as_bytes()));
+            if !signed {
                 return Ok(false); // Verification failed
             }
             
@@ -178,63 +515,114 @@ impl Transaction {
     }
 
     pub fn sign(&mut self, private_key: &[u8], prev_txs: HashMap<String, Transaction>) -> Result<()> {
+        self.sign_inputs(prev_txs, |_| signing_key(private_key))
+    }
+
+    /// Signs every input with the key in `keys` (pub_key_hash -> secret key) belonging to
+    /// the output it spends, for inputs owned by different wallets
+    pub fn sign_with_keys(&mut self, keys: &HashMap<Vec<u8>, Vec<u8>>, prev_txs: HashMap<String, Transaction>) -> Result<()> {
+        self.sign_inputs(prev_txs, |pub_key_hash| {
+            let secret_key = keys.get(pub_key_hash)
+                .ok_or_else(|| format_err!("No key for output locked to {}", hex::encode(pub_key_hash)))?;
+            signing_key(secret_key)
+        })
+    }
+
+    // `key_for` gives the key for the pub_key_hash of the output an input spends
+    fn sign_inputs(
+        &mut self,
+        prev_txs: HashMap<String, Transaction>,
+        key_for: impl Fn(&[u8]) -> Result<SigningKey>,
+    ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(())
         }
 
-        // Ensure the private key is the correct length
-        if private_key.len() != 32 {
-            return Err(format_err!("Invalid private key length"));
-        }
-
-         // Convert the private key slice to a fixed-size array
-        let private_key_bytes: &[u8; 32] = private_key
-            .try_into()
-            .map_err(|_| format_err!("Private key must be 32 bytes"))?;
-
-        // Create a SigningKey from the private key bytes
-        let signing_key = SigningKey::from_bytes(private_key_bytes);
-
         for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
+            if prev_txs.get(&vin.txid).is_none_or(|prev| prev.id.is_empty()) {
                 return Err(format_err!("Error: Previous transaction is not corrent"));
             }
         }
-        let mut tx_copy = self.trim_copy();
-
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-
-            // Clear signature and set the public key in the transaction input
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            
-            // Hash the transaction copy
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new(); // Clear public key for signing
-
-            // Sign the transaction hash
-            let signature = signing_key.sign(tx_copy.id.as_bytes());
+        for in_id in 0..self.vin.len() {
+            let prev_tx = &prev_txs[&self.vin[in_id].txid]; // checked above
+            let pub_key_hash = &referenced_output(prev_tx, self.vin[in_id].vout)?.pub_key_hash;
+            // a selection bug would otherwise only show when the signature fails to verify
+            check_owner(&self.vin[in_id], pub_key_hash)?;
+            let signing_key = key_for(pub_key_hash)?;
 
-             // Store the signature in the original transaction input
+            // signatures aren't part of the sighash, the input is signed in place
+            let signature = signing_key.sign(&self.sighash(in_id, pub_key_hash));
             self.vin[in_id].signature = signature.to_bytes().to_vec();
-            //.to_bytes().to_vec();
         }
 
         Ok(())
     }
 
+    /// The digest input `in_id` signs, with `pub_key_hash` the lock of the output it spends.
+    /// See Signature hashes
+    pub fn sighash(&self, in_id: usize, pub_key_hash: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(SIGHASH_VERSION.to_le_bytes());
+        data.extend((self.vin.len() as u32).to_le_bytes());
+        for (index, vin) in self.vin.iter().enumerate() {
+            put_bytes(&mut data, vin.txid.as_bytes());
+            data.extend(vin.vout.to_le_bytes());
+            put_bytes(&mut data, if index == in_id { pub_key_hash } else { &[] });
+        }
+        data.extend((self.vout.len() as u32).to_le_bytes());
+        for out in &self.vout {
+            data.extend(out.value.to_le_bytes());
+            put_bytes(&mut data, &out.pub_key_hash);
+        }
+        put_bytes(&mut data, &self.data);
+
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        let mut digest = vec![0; hasher.output_bytes()];
+        hasher.result(&mut digest);
+        digest
+    }
+
+    // What input `in_id` signed before the sighash: the hex id of a trimmed copy with
+    // `pub_key_hash` in the input
+    pub(crate) fn legacy_sighash(&self, in_id: usize, pub_key_hash: &[u8]) -> Result<String> {
+        let mut tx_copy = self.trim_copy();
+        tx_copy.vin[in_id].pub_key = pub_key_hash.to_vec();
+        tx_copy.hash()
+    }
+
     pub fn hash(&self) -> Result<String> {
-        let mut copy = self.clone();
-        copy.id = String::new();
-        let data = bincode::serialize(&copy)?;
+        // serialized as before data with an empty id, then the data when there is some
+        let mut data = bincode::serialize(&("", &self.vin, &self.vout))?;
+        if !self.data.is_empty() {
+            data.extend(bincode::serialize(&self.data)?);
+        }
         let mut hasher = Sha256::new();
         hasher.input(&data[..]);
         Ok(hasher.result_str())
     }
 
+    /// The transaction as raw hex, versioned, see raw::raw_tx_hex
+    pub fn to_hex(&self) -> Result<String> {
+        crate::raw::raw_tx_hex(self)
+    }
+
+    /// Reads the hex of `to_hex`, truncated or corrupt hex is a DecodeError saying where
+    pub fn from_hex(hex: &str) -> std::result::Result<Transaction, DecodeError> {
+        crate::raw::decode_raw_tx(hex)
+    }
+
+    /// The data as text: itself when it's UTF-8, hex otherwise. None without data
+    pub fn memo(&self) -> Option<String> {
+        if self.data.is_empty() {
+            return None;
+        }
+        match std::str::from_utf8(&self.data) {
+            Ok(text) if !text.chars().any(char::is_control) => Some(text.to_string()),
+            _ => Some(hex::encode(&self.data)),
+        }
+    }
+
     fn trim_copy(&self) -> Transaction {
         let mut vin = Vec::new();
         let mut vout = Vec::new();
@@ -242,7 +630,7 @@ impl Transaction {
         for v in &self.vin{
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
+                vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
             });
@@ -259,20 +647,743 @@ impl Transaction {
             id: self.id.clone(),
             vin,
             vout,
+            data: self.data.clone(),
+        }
+    }
+
+
+
+}
+
+fn signing_key(private_key: &[u8]) -> Result<SigningKey> {
+    // Ensure the private key is the correct length
+    let private_key_bytes: &[u8; 32] = private_key
+        .try_into()
+        .map_err(|_| format_err!("Invalid private key length"))?;
+    Ok(SigningKey::from_bytes(private_key_bytes))
+}
+
+// A sweep empties the wallet, none of its outputs may be held by another send. There's no
+// maturity rule for coinbase outputs yet, every other output is spendable
+fn check_sweepable(utxo: &UTXOSet, wallet: &Wallet) -> Result<()> {
+    let held = utxo.locked_value(&address::pub_key_to_hash(&wallet.public_key))?;
+    if held > 0 {
+        return Err(format_err!("{} coins of {} are held by pending sends, sweep once they confirm", held, wallet.get_address()));
+    }
+    Ok(())
+}
+
+// `wallets` without the repeats of a key, in order
+fn unique_wallets(wallets: &[Wallet]) -> Vec<&Wallet> {
+    let mut unique: Vec<&Wallet> = Vec::new();
+    for wallet in wallets {
+        if !unique.iter().any(|seen| seen.public_key == wallet.public_key) {
+            unique.push(wallet);
+        }
+    }
+    unique
+}
+
+fn distinct_keys(wallets: &[Wallet]) -> Vec<Vec<u8>> {
+    unique_wallets(wallets).into_iter().map(|wallet| address::pub_key_to_hash(&wallet.public_key)).collect()
+}
+
+// pub_key_hash -> secret key of each wallet
+fn signing_keys(wallets: &[Wallet]) -> HashMap<Vec<u8>, Vec<u8>> {
+    wallets.iter().map(|wallet| (address::pub_key_to_hash(&wallet.public_key), wallet.secret_key.clone())).collect()
+}
+
+/// Where a transaction is validated, only a block may hold a coinbase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxContext {
+    Mempool,
+    Block,
+}
+
+/// Checks `tx` against the consensus rules its signatures don't cover: outputs pay something,
+/// no input is spent twice, every input spends an output of its transaction in `prev_txs`, and
+/// the inputs cover the outputs. Returns the fee, 0 for a coinbase
+pub fn validate(tx: &Transaction, prev_txs: &HashMap<String, Transaction>, ctx: TxContext) -> std::result::Result<i64, TxRuleError> {
+    if tx.is_coinbase() {
+        // its value is checked against the subsidy and fees of the block
+        return match ctx {
+            TxContext::Block => Ok(0),
+            TxContext::Mempool => Err(TxRuleError::CoinbaseOutsideBlock),
+        };
+    }
+    if tx.vin.is_empty() || tx.vout.is_empty() {
+        return Err(TxRuleError::Empty);
+    }
+    if let Some((vout, out)) = tx.vout.iter().enumerate().find(|(_, out)| out.value <= 0) {
+        return Err(TxRuleError::NonPositiveOutput { vout, value: out.value });
+    }
+
+    let mut spent = HashSet::new();
+    let mut inputs: i64 = 0;
+    for vin in &tx.vin {
+        if !spent.insert((&vin.txid, vin.vout)) {
+            return Err(TxRuleError::DuplicateInput { txid: vin.txid.clone(), vout: vin.vout });
+        }
+        let output = prev_txs.get(&vin.txid)
+            .and_then(|prev_tx| referenced_output(prev_tx, vin.vout).ok())
+            .ok_or_else(|| TxRuleError::MissingOutput { txid: vin.txid.clone(), vout: vin.vout })?;
+        inputs += output.value as i64;
+    }
+
+    let outputs: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+    if outputs > inputs {
+        return Err(TxRuleError::OutputsExceedInputs { inputs, outputs });
+    }
+    Ok(inputs - outputs)
+}
+
+// The public key of `vin` must hash to `pub_key_hash`, the lock of the output it spends
+fn check_owner(vin: &TXInput, pub_key_hash: &[u8]) -> Result<()> {
+    if address::pub_key_to_hash(&vin.pub_key) != pub_key_hash {
+        return Err(OwnershipError::NotOwned {
+            txid: vin.txid.clone(),
+            vout: vin.vout,
+            owner: address::wallet_address(pub_key_hash),
+        }.into());
+    }
+    Ok(())
+}
+
+// `bytes` prefixed with their length
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend((bytes.len() as u32).to_le_bytes());
+    data.extend(bytes);
+}
+
+// The output an input spends, or an error when the index is out of range
+fn referenced_output(prev_tx: &Transaction, vout: i32) -> Result<&TXOutput> {
+    usize::try_from(vout)
+        .ok()
+        .and_then(|index| prev_tx.vout.get(index))
+        .ok_or_else(|| format_err!("Output {} doesn't exist in transaction {}", vout, prev_tx.id))
+}
+
+/// (change output, dust) of `change` coins left over by a send: change worth less than
+/// `dust_threshold` gets no output and goes to the fee
+pub fn fold_dust(change: i32, dust_threshold: i32) -> (i32, i32) {
+    if change < dust_threshold {
+        (0, change)
+    } else {
+        (change, 0)
+    }
+}
+
+// Refuses data no node would relay
+fn check_data(data: &[u8]) -> Result<()> {
+    if data.len() > MAX_TX_DATA {
+        return Err(format_err!("The data is {} bytes, at most {} can be attached", data.len(), MAX_TX_DATA));
+    }
+    Ok(())
+}
+
+// Refuses an output a node applying `dust_threshold` wouldn't relay
+fn check_dust(value: i32, dust_threshold: i32) -> Result<()> {
+    if value < dust_threshold {
+        return Err(format_err!("An output of {} is below the dust threshold of {}", value, dust_threshold));
+    }
+    Ok(())
+}
+
+/// Coins the coinbase of a block at `height` creates, fees aside. Halves every
+/// HALVING_INTERVAL blocks until it's zero
+pub fn block_subsidy(height: i32) -> i32 {
+    let halvings = height.max(0) / HALVING_INTERVAL;
+    if halvings >= 31 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+/// Coins created by the subsidies of blocks 0 to `height`. Stops growing once the subsidy
+/// reaches zero, so `circulating_supply(i32::MAX)` is the cap
+pub fn circulating_supply(height: i32) -> i64 {
+    let mut supply = 0;
+    let mut era_start: i64 = 0;
+    while era_start <= height as i64 {
+        let subsidy = block_subsidy(era_start as i32) as i64;
+        if subsidy == 0 {
+            break;
         }
+        let era_end = (era_start + HALVING_INTERVAL as i64 - 1).min(height as i64);
+        supply += subsidy * (era_end - era_start + 1);
+        era_start += HALVING_INTERVAL as i64;
+    }
+    supply
+}
+
+/// Serialized size in bytes of a signed transaction with the given number of inputs and outputs
+pub fn estimate_size(inputs: usize, outputs: usize) -> usize {
+    let tx = Transaction {
+        id: "0".repeat(64),
+        vin: vec![TXInput {
+            txid: "0".repeat(64),
+            vout: 0,
+            signature: vec![0; 64],
+            pub_key: vec![0; 32],
+        }; inputs],
+        vout: vec![TXOutput {
+            value: 0,
+            pub_key_hash: vec![0; 20],
+        }; outputs],
+        data: Vec::new(),
+    };
+    bincode::serialized_size(&tx).unwrap_or(0) as usize
+}
+
+/// Fee for `size` bytes at `fee_rate` coins per 1000 bytes, rounded up
+pub fn fee_for_size(size: usize, fee_rate: i32) -> i32 {
+    if fee_rate <= 0 {
+        return 0;
     }
+    ((size as i64 * fee_rate as i64 + 999) / 1000) as i32
+}
 
+/// Smallest fee increase of a replacement of `size` bytes, at least 1
+pub fn min_fee_bump(size: usize) -> i32 {
+    fee_for_size(size, MIN_FEE_BUMP_RATE).max(1)
+}
 
+/// Amount and fee when `total_in` coins spread over `inputs` outputs are sent to a single output
+pub fn send_max_amount(total_in: i32, inputs: usize, fee_rate: i32) -> Result<(i32, i32)> {
+    let fee = fee_for_size(estimate_size(inputs, 1), fee_rate);
+    if inputs == 0 || total_in <= fee {
+        return Err(format_err!(
+            "Balance {} doesn't cover the fee {}",
+            total_in,
+            fee
+        ));
+    }
+    Ok((total_in - fee, fee))
+}
 
+/// Amount, fee and number of inputs of the largest single output that `values` (largest
+/// first) can pay. Every input adds to the fee, so the first n are tried for each n and
+/// outputs worth less than the fee they add are left out
+pub fn max_sendable(values: &[i32], fee_rate: i32) -> Result<(i32, i32, usize)> {
+    let mut best: Option<(i32, i32, usize)> = None;
+    let mut total: i64 = 0;
+    for (i, value) in values.iter().enumerate() {
+        total += *value as i64;
+        let fee = fee_for_size(estimate_size(i + 1, 1), fee_rate);
+        let amount = (total - fee as i64).min(i32::MAX as i64) as i32;
+        if amount > 0 && best.is_none_or(|(most, _, _)| amount > most) {
+            best = Some((amount, fee, i + 1));
+        }
+    }
+    best.ok_or_else(|| format_err!(
+        "Balance {} doesn't cover the fee {}",
+        total,
+        fee_for_size(estimate_size(values.len().max(1), 1), fee_rate)
+    ))
 }
 
-/*pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
-    let mut hasher1 = Sha256::new();
-    hasher1.input(pub_key);
-    hasher1.result(pub_key);
-    
-    let mut hasher2 = Ripemd160::new();
-    hasher2.input(pub_key);
-    pub_key.resize(20, 0);
-    hasher2.result(pub_key);
-}*/
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::WalletStore;
+    use crate::testing::{coinbase, reopen, ChainBuilder, TxBuilder, WalletFixture};
+    use crate::wallet::{SameAddress, Wallets};
+    use std::collections::HashSet;
+    use tokio::sync::RwLock;
+
+    #[test]
+    fn test_block_subsidy_halves_down_to_zero() {
+        assert_eq!(block_subsidy(0), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL - 1), 10);
+        assert_eq!(block_subsidy(HALVING_INTERVAL), 5);
+        assert_eq!(block_subsidy(2 * HALVING_INTERVAL + 7), 2);
+        assert_eq!(block_subsidy(3 * HALVING_INTERVAL), 1);
+        assert_eq!(block_subsidy(4 * HALVING_INTERVAL), 0);
+        assert_eq!(block_subsidy(i32::MAX), 0);
+
+        let coinbase = |height| Transaction::new_coinbase(WalletFixture::new(1).address(), String::from("reward"), height).unwrap().vout[0].value;
+        assert_eq!(coinbase(1), 10);
+        assert_eq!(coinbase(HALVING_INTERVAL + 1), 5);
+        assert_eq!(coinbase(5 * HALVING_INTERVAL), 0);
+
+        assert_eq!(circulating_supply(0), 10);
+        assert_eq!(circulating_supply(HALVING_INTERVAL), 10 * HALVING_INTERVAL as i64 + 5);
+        let cap = (10 + 5 + 2 + 1) * HALVING_INTERVAL as i64;
+        assert_eq!(circulating_supply(4 * HALVING_INTERVAL - 1), cap);
+        assert_eq!(circulating_supply(i32::MAX), cap);
+    }
+
+    #[test]
+    fn test_send_max_amount() {
+        assert_eq!(estimate_size(1, 1), 316);
+
+        // free, default and higher fee rates on a single input
+        assert_eq!(send_max_amount(10, 1, 0).unwrap(), (10, 0));
+        assert_eq!(send_max_amount(10, 1, DEFAULT_FEE_RATE).unwrap(), (9, 1));
+        assert_eq!(send_max_amount(10, 1, 10).unwrap(), (6, 4));
+
+        // more inputs make the transaction bigger and the fee higher
+        let fee = fee_for_size(estimate_size(3, 1), 10);
+        assert_eq!(fee, 7);
+        assert_eq!(send_max_amount(30, 3, 10).unwrap(), (30 - fee, fee));
+
+        // nothing left after the fee, or nothing to spend at all
+        assert!(send_max_amount(4, 1, 10).is_err());
+        assert!(send_max_amount(0, 0, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_leaves_no_change() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let fee_rate = 10;
+
+        for outputs in [1, 3, 50] {
+            let chain = ChainBuilder::new(&alice).empty_blocks(outputs - 1).build(); // a 10 reward each
+            let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+            let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path.to_str().unwrap()).unwrap()));
+            utxo.read().await.reindex().await.unwrap();
+
+            let (amount, fee) = Transaction::sweep_amount(std::slice::from_ref(&alice.wallet), fee_rate, &utxo).await.unwrap();
+            let memo = Transaction::new_utxo(std::slice::from_ref(&alice.wallet), &bob.address(), SendMode::Sweep { fee }, CoinSelection::default(), b"rent".to_vec(), &SameAddress, &utxo).await;
+            let tx = Transaction::new_utxo(std::slice::from_ref(&alice.wallet), &bob.address(), SendMode::Sweep { fee }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo).await.unwrap();
+            assert!(utxo.read().await.blockchain.read().await.verify_transacton(&tx).unwrap());
+
+            // Every output spent into one, the fee is exactly what the signed size costs
+            assert_eq!(tx.vin.len(), outputs);
+            let outputs_paid: Vec<(i32, Vec<u8>)> = tx.vout.iter().map(|out| (out.value, out.pub_key_hash.clone())).collect();
+            assert_eq!(outputs_paid, vec![(amount, bob.pub_key_hash())]);
+            assert_eq!(bincode::serialized_size(&tx).unwrap() as usize, estimate_size(outputs, 1));
+            assert_eq!(fee, fee_for_size(estimate_size(outputs, 1), fee_rate));
+            assert_eq!(amount + fee, 10 * outputs as i32);
+
+            // A sweep doesn't take data
+            assert!(memo.unwrap_err().to_string().contains("sweep"));
+
+            std::fs::remove_dir_all(&path).ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_empties_the_wallet_once() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice).empty_blocks(2).build(); // 3 rewards of 10
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path.to_str().unwrap()).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+        let wallets = std::slice::from_ref(&alice.wallet);
+
+        // A fee the balance doesn't cover reserves nothing
+        let err = Transaction::new_sweep(wallets, &bob.address(), 30, &utxo).await.unwrap_err();
+        assert!(err.to_string().contains("doesn't cover the fee"));
+
+        let (amount, fee) = Transaction::sweep_amount(wallets, 1, &utxo).await.unwrap();
+        assert_eq!(amount + fee, 30);
+        let tx = Transaction::new_utxo(wallets, &bob.address(), SendMode::Sweep { fee }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo).await.unwrap();
+        assert!(utxo.read().await.blockchain.read().await.verify_transacton(&tx).unwrap());
+        assert_eq!(tx.vin.len(), 3);
+        let outputs_paid: Vec<(i32, Vec<u8>)> = tx.vout.iter().map(|out| (out.value, out.pub_key_hash.clone())).collect();
+        assert_eq!(outputs_paid, vec![(amount, bob.pub_key_hash())]);
+
+        // Its inputs are held from the moment it's built, a second sweep can't be
+        let err = Transaction::new_sweep(wallets, &bob.address(), fee, &utxo).await.unwrap_err();
+        assert!(err.to_string().contains("held by pending sends"));
+        assert!(Transaction::sweep_amount(wallets, 1, &utxo).await.is_err());
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_sweep_that_fails_to_sign_holds_nothing() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice).empty_blocks(2).build();
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path.to_str().unwrap()).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+        let mut broken = alice.wallet.clone();
+        broken.secret_key.truncate(3);
+
+        let err = Transaction::new_sweep(std::slice::from_ref(&broken), &bob.address(), 1, &utxo).await.unwrap_err();
+        assert!(err.to_string().contains("Invalid private key length"));
+
+        // Its outputs were given back, the wallet can still be swept
+        let tx = Transaction::new_sweep(std::slice::from_ref(&alice.wallet), &bob.address(), 1, &utxo).await.unwrap();
+        assert_eq!(tx.vin.len(), 3);
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_verify() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 0);
+        let prev_txs = HashMap::from([(reward.id.clone(), reward.clone())]);
+
+        let tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).build();
+        assert!(tx.verify(prev_txs.clone()).unwrap());
+
+        // Changing an output after signing breaks the signature
+        let mut tampered = tx.clone();
+        tampered.vout[0].value = 11;
+        assert!(!tampered.verify(prev_txs).unwrap());
+    }
+
+    #[test]
+    fn test_inputs_the_key_doesnt_own_are_rejected() {
+        let alice = WalletFixture::new(1);
+        let mallory = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&alice);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let bc = chain.build();
+        let prev_txs = HashMap::from([(reward.id.clone(), reward.clone())]);
+        let not_owned = OwnershipError::NotOwned { txid: reward.id.clone(), vout: 0, owner: alice.address() };
+
+        // Signing refuses an input spending someone else's output
+        let mut tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&mallory.address(), 10).build();
+        tx.vin[0].pub_key = mallory.wallet.public_key.clone();
+        let err = tx.sign(&mallory.wallet.secret_key, prev_txs.clone()).unwrap_err();
+        assert_eq!(err.downcast::<OwnershipError>().unwrap(), not_owned);
+
+        // and a valid signature of the input's own key doesn't make it verify
+        let signature = signing_key(&mallory.wallet.secret_key).unwrap().sign(&tx.sighash(0, &alice.pub_key_hash()));
+        tx.vin[0].signature = signature.to_bytes().to_vec();
+        assert_eq!(tx.verify(prev_txs).unwrap_err().downcast::<OwnershipError>().unwrap(), not_owned);
+        assert_eq!(bc.verify_transacton(&tx).unwrap_err().downcast::<OwnershipError>().unwrap(), not_owned);
+    }
+
+    #[test]
+    fn test_sighash_vectors_survive_the_wire() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 0);
+        let prev_txs = HashMap::from([(reward.id.clone(), reward.clone())]);
+        let tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).data(b"invoice 42".to_vec()).build();
+
+        assert_eq!(hex::encode(tx.sighash(0, &alice.pub_key_hash())), "b9d13ae0b45fe737bac7e2d6df2e83c719ce0efcc691e686663e6edd89af51e7");
+        assert_eq!(hex::encode(&tx.vin[0].signature), "238d353d954e2eb638b6bfcdc3f7835fd76ae1738685e1930ec43ba7113392bbc43a577401463980b482a41b0cd4696273e1e3e15a6439542e0089ee3526ef0d");
+
+        // Decoded from the bytes peers send, it hashes and verifies the same
+        let crate::raw::Decoded::Transaction(received) = crate::raw::decode_hex(&crate::raw::tx_hex(&tx).unwrap()).unwrap() else {
+            panic!("not decoded as a transaction");
+        };
+        assert_eq!(received.sighash(0, &alice.pub_key_hash()), tx.sighash(0, &alice.pub_key_hash()));
+        assert!(received.verify(prev_txs.clone()).unwrap());
+
+        // Every field but the signatures and public keys is covered
+        let mut moved = received.clone();
+        moved.vin[0].vout = 1;
+        assert_ne!(moved.sighash(0, &alice.pub_key_hash()), tx.sighash(0, &alice.pub_key_hash()));
+        assert_ne!(tx.sighash(0, &bob.pub_key_hash()), tx.sighash(0, &alice.pub_key_hash()));
+    }
+
+    #[test]
+    fn test_legacy_signatures_only_below_the_activation_height() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 0);
+        let prev_txs = HashMap::from([(reward.id.clone(), reward.clone())]);
+        let tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).build();
+
+        let mut legacy = tx.clone();
+        let message = legacy.legacy_sighash(0, &alice.pub_key_hash()).unwrap();
+        legacy.vin[0].signature = signing_key(&alice.wallet.secret_key).unwrap().sign(message.as_bytes()).to_bytes().to_vec();
+        assert!(legacy.verify_at(prev_txs.clone(), SIGHASH_ACTIVATION_HEIGHT - 1).unwrap());
+        assert!(!legacy.verify_at(prev_txs.clone(), SIGHASH_ACTIVATION_HEIGHT).unwrap());
+        assert!(!legacy.verify(prev_txs.clone()).unwrap());
+
+        // New signatures are good at any height, tampering breaks either
+        assert!(tx.verify_at(prev_txs.clone(), 0).unwrap());
+        legacy.vout[0].value = 11;
+        assert!(!legacy.verify_at(prev_txs, 0).unwrap());
+    }
+
+    #[test]
+    fn test_validate_rules() {
+        let alice = WalletFixture::new(1);
+        let reward = coinbase(&alice.address(), 0); // pays 10
+        let prev_txs = HashMap::from([(reward.id.clone(), reward.clone())]);
+        let tx = TxBuilder::new(&alice).spend(&reward, 0).pay(&alice.address(), 7).build();
+        let check = |tx: &Transaction| validate(tx, &prev_txs, TxContext::Mempool);
+        assert_eq!(check(&tx), Ok(3));
+
+        assert_eq!(check(&reward), Err(TxRuleError::CoinbaseOutsideBlock));
+        assert_eq!(validate(&reward, &HashMap::new(), TxContext::Block), Ok(0));
+
+        let mut empty = tx.clone();
+        empty.vout.clear();
+        assert_eq!(check(&empty), Err(TxRuleError::Empty));
+
+        let mut nothing = tx.clone();
+        nothing.vout[0].value = 0;
+        assert_eq!(check(&nothing), Err(TxRuleError::NonPositiveOutput { vout: 0, value: 0 }));
+
+        let mut twice = tx.clone();
+        twice.vin.push(twice.vin[0].clone());
+        assert_eq!(check(&twice), Err(TxRuleError::DuplicateInput { txid: reward.id.clone(), vout: 0 }));
+
+        // An index past the outputs of the spent transaction is rejected, not a panic
+        let mut out_of_range = tx.clone();
+        out_of_range.vin[0].vout = 5;
+        assert_eq!(check(&out_of_range), Err(TxRuleError::MissingOutput { txid: reward.id.clone(), vout: 5 }));
+        assert_eq!(validate(&tx, &HashMap::new(), TxContext::Block), Err(TxRuleError::MissingOutput { txid: reward.id.clone(), vout: 0 }));
+
+        let mut overpaying = tx.clone();
+        overpaying.vout[0].value = 11;
+        assert_eq!(check(&overpaying), Err(TxRuleError::OutputsExceedInputs { inputs: 10, outputs: 11 }));
+    }
+
+    #[test]
+    fn test_data_is_signed_and_ids_without_it_are_unchanged() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let reward = coinbase(&alice.address(), 0);
+        let prev_txs = HashMap::from([(reward.id.clone(), reward.clone())]);
+
+        // Without data the id is the hash of the layout from before data, taken before signing
+        let plain = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).build();
+        let mut legacy = LegacyTransaction { id: String::new(), vin: plain.vin.clone(), vout: plain.vout.clone() };
+        legacy.vin.iter_mut().for_each(|vin| vin.signature.clear());
+        let mut hasher = Sha256::new();
+        hasher.input(&bincode::serialize(&legacy).unwrap());
+        assert_eq!(plain.id, hasher.result_str());
+        assert_eq!(plain.memo(), None);
+
+        let memo = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).data(b"invoice 42".to_vec()).build();
+        assert_ne!(memo.id, plain.id);
+        assert!(memo.verify(prev_txs.clone()).unwrap());
+        assert_eq!(memo.memo().as_deref(), Some("invoice 42"));
+
+        // The signature covers the data, it can't be changed or stripped on the way
+        let mut changed = memo.clone();
+        changed.data = b"invoice 43".to_vec();
+        assert!(!changed.verify(prev_txs.clone()).unwrap());
+        let mut stripped = memo.clone();
+        stripped.data.clear();
+        assert!(!stripped.verify(prev_txs).unwrap());
+
+        let binary = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 10).data(vec![0, 0xff, 7]).build();
+        assert_eq!(binary.memo().as_deref(), Some("00ff07"));
+    }
+
+    #[tokio::test]
+    async fn test_multi_wallet_funding() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+
+        // alice: 4 change + 10 reward of block 1, bob: 6
+        let chain = ChainBuilder::new(&alice);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let payment = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 4).build();
+        let chain = chain.block(vec![payment]).build();
+
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path.to_str().unwrap()).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+
+        // Neither wallet covers 17 + 1 fee alone
+        assert!(Transaction::new_utxo(std::slice::from_ref(&alice.wallet), &carol.address(), SendMode::Amount { amount: 18, fee: 0 }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo).await.is_err());
+        assert!(Transaction::new_utxo(std::slice::from_ref(&bob.wallet), &carol.address(), SendMode::Amount { amount: 18, fee: 0 }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo).await.is_err());
+
+        let wallets = [alice.wallet.clone(), bob.wallet.clone()];
+        let tx = Transaction::new_utxo(&wallets, &carol.address(), SendMode::Amount { amount: 17, fee: 1 }, CoinSelection::default(), b"rent".to_vec(), &SameAddress, &utxo).await.unwrap();
+        assert!(utxo.read().await.blockchain.read().await.verify_transacton(&tx).unwrap());
+        assert_eq!(tx.memo().as_deref(), Some("rent"));
+
+        // Inputs from both wallets, change back to the first one
+        let signers: Vec<&Vec<u8>> = tx.vin.iter().map(|vin| &vin.pub_key).collect();
+        assert!(signers.contains(&&alice.wallet.public_key) && signers.contains(&&bob.wallet.public_key));
+        let outputs: Vec<(i32, Vec<u8>)> = tx.vout.iter().map(|out| (out.value, out.pub_key_hash.clone())).collect();
+        assert_eq!(outputs, vec![(17, carol.pub_key_hash()), (2, alice.pub_key_hash())]);
+
+        // Together they still can't pay more than 20
+        assert!(Transaction::new_utxo(&wallets, &carol.address(), SendMode::Amount { amount: 20, fee: 1 }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo).await.is_err());
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_back_to_back_sends_spend_different_outputs() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice).empty_blocks(3).build(); // four rewards of 10
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path.to_str().unwrap()).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+
+        let to = bob.address();
+        let send = |amount| Transaction::new_utxo(std::slice::from_ref(&alice.wallet), &to, SendMode::Amount { amount, fee: 0 }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo);
+        let inputs = |tx: &Transaction| -> HashSet<(String, i32)> { tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)).collect() };
+
+        let (first, second) = tokio::join!(send(15), send(15));
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(inputs(&first).is_disjoint(&inputs(&second)));
+        let both: HashSet<(String, i32)> = inputs(&first).union(&inputs(&second)).cloned().collect();
+        assert_eq!(utxo.read().await.locked_outpoints(), both);
+        assert_eq!(utxo.read().await.locked_value(&alice.pub_key_hash()).unwrap(), 40);
+        // every output is taken, and a send that fails keeps nothing reserved
+        assert!(send(5).await.is_err());
+        assert_eq!(utxo.read().await.locked_outpoints(), both);
+
+        // the second one didn't go out, its inputs can be spent again
+        utxo.read().await.release(inputs(&second));
+        let third = send(5).await.unwrap();
+        assert!(inputs(&third).is_subset(&inputs(&second)));
+
+        // mined, the first one's reservations end with its outputs
+        let height = utxo.read().await.blockchain.read().await.get_best_height().unwrap() + 1;
+        let reward = Transaction::new_coinbase(bob.address(), String::new(), height).unwrap();
+        let block = utxo.read().await.blockchain.write().await.mine_block(vec![reward, first]).unwrap();
+        utxo.read().await.update(&block).unwrap();
+        assert_eq!(utxo.read().await.locked_outpoints(), inputs(&third));
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_bump_fee() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice).empty_blocks(1).build();
+
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path.to_str().unwrap()).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+
+        // 6 to bob out of one 10 reward, no fee
+        let original = Transaction::new_utxo(std::slice::from_ref(&alice.wallet), &bob.address(), SendMode::Amount { amount: 6, fee: 0 }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo).await.unwrap();
+        let bumped = Transaction::bump_fee(&original, &[bob.wallet.clone(), alice.wallet.clone()], DEFAULT_FEE_RATE, &utxo).await.unwrap();
+        assert!(utxo.read().await.blockchain.read().await.verify_transacton(&bumped).unwrap());
+        assert_ne!(bumped.id, original.id);
+        let outpoints = |tx: &Transaction| tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)).collect::<Vec<_>>();
+        assert_eq!(outpoints(&bumped), outpoints(&original));
+        assert_eq!(bumped.vout[0].value, 6);
+        assert_eq!(bumped.vout[1].value, 3);
+
+        // Each bump pays more than the last
+        let again = Transaction::bump_fee(&bumped, std::slice::from_ref(&alice.wallet), DEFAULT_FEE_RATE, &utxo).await.unwrap();
+        assert_eq!(again.vout[1].value, 2);
+
+        // Without the key of an input there's nothing to sign with
+        assert!(Transaction::bump_fee(&original, std::slice::from_ref(&bob.wallet), DEFAULT_FEE_RATE, &utxo).await.is_err());
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_batch_pays_every_payment_with_one_change() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&alice).empty_blocks(1).build(); // 20 in two rewards
+
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo = Arc::new(RwLock::new(UTXOSet::with_path(Arc::new(RwLock::new(chain)), path.to_str().unwrap()).unwrap()));
+        utxo.read().await.reindex().await.unwrap();
+
+        let payments = vec![(bob.address(), 6), (carol.address(), 5), (bob.address(), 2)];
+        let tx = Transaction::new_batch(std::slice::from_ref(&alice.wallet), &payments, &SameAddress, &utxo).await.unwrap();
+        assert!(utxo.read().await.blockchain.read().await.verify_transacton(&tx).unwrap());
+        let outputs: Vec<(i32, Vec<u8>)> = tx.vout.iter().map(|out| (out.value, out.pub_key_hash.clone())).collect();
+        assert_eq!(outputs, vec![
+            (6, bob.pub_key_hash()),
+            (5, carol.pub_key_hash()),
+            (2, bob.pub_key_hash()),
+            (7, alice.pub_key_hash()), // change of both rewards
+        ]);
+
+        // The batch is funded as a whole
+        assert!(Transaction::new_batch(std::slice::from_ref(&alice.wallet), &[(bob.address(), 15), (carol.address(), 6)], &SameAddress, &utxo).await.is_err());
+        assert!(Transaction::new_batch(std::slice::from_ref(&alice.wallet), &[], &SameAddress, &utxo).await.is_err());
+
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_change_below_the_dust_threshold_goes_to_the_fee() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice).build(); // one reward of 10
+        let utxo = Arc::new(RwLock::new(UTXOSet::in_memory(Arc::new(RwLock::new(chain)))));
+        utxo.read().await.reindex().await.unwrap();
+        let threshold = SETTINGS.dust_threshold;
+        let to = bob.address();
+        let send = |amount| Transaction::new_utxo(std::slice::from_ref(&alice.wallet), &to, SendMode::Amount { amount, fee: 0 }, CoinSelection::default(), Vec::new(), &SameAddress, &utxo);
+
+        // just below: no change output, the dust is paid as fee
+        let amount = 10 - (threshold - 1);
+        let preview = Transaction::preview_send(std::slice::from_ref(&alice.wallet), amount, 0, CoinSelection::default(), &utxo).await.unwrap();
+        assert_eq!(preview, SendPreview { inputs: 1, total: 10, fee: 0, change: 0, dust_folded: threshold - 1 });
+        let tx = send(amount).await.unwrap();
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.vout[0].value, amount);
+        utxo.read().await.release(tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)));
+
+        // just above: the change is kept
+        let amount = 10 - threshold;
+        let preview = Transaction::preview_send(std::slice::from_ref(&alice.wallet), amount, 0, CoinSelection::default(), &utxo).await.unwrap();
+        assert_eq!((preview.change, preview.dust_folded), (threshold, 0));
+        let tx = send(amount).await.unwrap();
+        let outputs: Vec<(i32, Vec<u8>)> = tx.vout.iter().map(|out| (out.value, out.pub_key_hash.clone())).collect();
+        assert_eq!(outputs, vec![(amount, bob.pub_key_hash()), (threshold, alice.pub_key_hash())]);
+        utxo.read().await.release(tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)));
+
+        // a payment of dust isn't made at all, and reserves nothing
+        assert!(send(threshold - 1).await.is_err());
+        assert_eq!(fold_dust(threshold - 1, threshold), (0, threshold - 1));
+        assert_eq!(fold_dust(threshold, threshold), (threshold, 0));
+        assert!(send(10).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_change_goes_to_a_fresh_wallet_of_the_sender() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice).build(); // one reward of 10
+        let utxo = Arc::new(RwLock::new(UTXOSet::in_memory(Arc::new(RwLock::new(chain)))));
+        utxo.read().await.reindex().await.unwrap();
+
+        let path = std::env::temp_dir().join(format!("blockjain-wallets-{}", rand::random::<u64>()));
+        let path = path.to_str().unwrap().to_string();
+        let mut wallets = reopen(|| Wallets::load(&path)).unwrap();
+        wallets.insert(&alice.address(), alice.wallet.clone());
+        wallets.save_all().unwrap();
+        let change = wallets.change_provider();
+        let send = |wallets: Vec<Wallet>, amount| {
+            let (to, change, utxo) = (bob.address(), &change, &utxo);
+            async move { Transaction::new_utxo(&wallets, &to, SendMode::Amount { amount, fee: 0 }, CoinSelection::default(), Vec::new(), change.as_ref(), utxo).await }
+        };
+
+        let tx = send(wallets.spending_wallets(&alice.address()), 6).await.unwrap();
+        wallets.load_new().unwrap();
+        let change_addresses = wallets.change_addresses(&alice.address());
+        assert_eq!(change_addresses.len(), 1);
+        let outputs: Vec<(i32, Vec<u8>)> = tx.vout.iter().map(|out| (out.value, out.pub_key_hash.clone())).collect();
+        assert_eq!(outputs, vec![(6, bob.pub_key_hash()), (4, address::address_to_hash(&change_addresses[0]).unwrap())]);
+        // shown, and counted, as alice's
+        assert_eq!(wallets.get_all_address(), vec![alice.address()]);
+        assert_eq!(wallets.address_groups(), vec![vec![alice.address(), change_addresses[0].clone()]]);
+
+        let height = utxo.read().await.blockchain.read().await.get_best_height().unwrap() + 1;
+        let reward = Transaction::new_coinbase(bob.address(), String::new(), height).unwrap();
+        let block = utxo.read().await.blockchain.write().await.mine_block(vec![reward, tx]).unwrap();
+        utxo.read().await.update(&block).unwrap();
+
+        // alice spends her change, signed with the change wallet's key, and gets a new one
+        let spending = wallets.spending_wallets(&alice.address());
+        assert_eq!(spending.len(), 2);
+        let tx = send(spending, 2).await.unwrap();
+        assert!(utxo.read().await.blockchain.read().await.verify_transacton(&tx).unwrap());
+        wallets.load_new().unwrap();
+        let change_addresses = wallets.change_addresses(&alice.address());
+        assert_eq!(change_addresses.len(), 2);
+        assert!(!change_addresses.contains(&alice.address()));
+        assert_eq!(wallets.get_all_address(), vec![alice.address()]);
+
+        drop(wallets);
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
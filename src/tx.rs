@@ -1,9 +1,10 @@
-use crypto::{digest::Digest, ripemd160::Ripemd160, sha2::Sha256};
-use bitcoincash_addr::{Address, HashType, Scheme, Network};
-use log::debug;
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
+use crate::address;
+use crate::blockchain::GENESIS_ADDRESS;
 use crate::errors::Result;
-//use crate::transaction::hash_pub_key;
+use crate::settings::SETTINGS;
 
 
 #[derive( Serialize, Deserialize, Debug, Clone )]
@@ -11,6 +12,13 @@ pub struct TXOutputs {
     pub outputs: Vec<TXOutput>,
 }
 
+/// A transaction's outputs that are still unspent, by their index in it (vout). Spending one
+/// leaves the others where they are
+#[derive( Serialize, Deserialize, Debug, Clone, Default )]
+pub struct UnspentOutputs {
+    pub outputs: BTreeMap<i32, TXOutput>,
+}
+
 #[derive( Serialize, Deserialize, Debug, Clone )]
 pub struct TXInput {
     pub txid: String,
@@ -26,32 +34,18 @@ pub struct TXOutput {
 }
 
 
+impl UnspentOutputs {
+    /// Every output of a transaction, none spent yet
+    pub fn new(vout: &[TXOutput]) -> Self {
+        UnspentOutputs { outputs: vout.iter().cloned().enumerate().map(|(index, out)| (index as i32, out)).collect() }
+    }
+}
+
 impl TXInput {
 
     // hashes the public_key and returns the address
     pub fn get_address(&self) -> String {
-        // Hash the public key first with SHA256
-        let mut sha256 = Sha256::new();
-        sha256.input(&self.pub_key);
-        let sha256_result = sha256.result_str(); // Hex string of SHA256 hash
-
-        // Convert the SHA256 hash back to bytes and apply RIPEMD160
-        let mut ripemd160 = Ripemd160::new();
-        ripemd160.input(&hex::decode(sha256_result).unwrap());
-        let ripemd160_bytes = ripemd160.result_str();
-
-        // Convert the RIPEMD160 result into bytes for the address generation
-        let ripemd160_vec = hex::decode(ripemd160_bytes).unwrap();
-
-        let address = Address::new(
-            ripemd160_vec,
-            Scheme::Base58,       // Choose Base58 or CashAddr
-            HashType::Key,        // Public Key Hash
-            Network::Main,     // Use Mainnet or Testnet as appropriate
-        );
-        
-        address.encode().unwrap()
-
+        address::pub_key_to_address(&self.pub_key)
     }
 
     // can_unlock_output_with checks whether the address initiated the transaction
@@ -71,10 +65,28 @@ impl TXOutput {
             value,
             pub_key_hash: Vec::new(),
         };
-        txo.lock(&address);
+        txo.lock(&address)?;
         Ok(txo)
     }
 
+    /// Output that deliberately destroys `value`. No public key hashes to an empty
+    /// key hash, so it can never be spent.
+    pub fn new_burn(value: i32) -> Self {
+        TXOutput {
+            value,
+            pub_key_hash: Vec::new(),
+        }
+    }
+
+    pub fn is_burn(&self) -> bool {
+        self.pub_key_hash.is_empty()
+    }
+
+    // Burned on purpose or paid to a known unspendable address
+    pub fn is_unspendable(&self) -> bool {
+        self.is_burn() || is_unspendable_pub_key_hash(&self.pub_key_hash)
+    }
+
     // "fn checks if the output can be unlocked with the provided data"
     pub fn can_be_unlock_with(&self, unlocking_data: &[u8]) -> bool {
         // you need to ensure that the unlocking_data is consistent in format with the stored pub_key_hash        
@@ -89,7 +101,7 @@ impl TXOutput {
     fn lock(&mut self, address: &str) -> Result<()> {
         //println!("lock()");
 
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = address::address_to_hash(address)?;
         /*debug!("lock: {}", address);
         println!("pub_key_hash: {:?} \n", pub_key_hash);*/
 
@@ -100,3 +112,22 @@ impl TXOutput {
 
 
 }
+
+/// true for addresses nobody holds keys for: the genesis placeholder, the configured
+/// burn address and all-zero hashes. Coins sent there are lost for good.
+pub fn is_unspendable_address(address: &str) -> bool {
+    match address::address_to_hash(address) {
+        Ok(pub_key_hash) => is_unspendable_pub_key_hash(&pub_key_hash),
+        Err(_) => false,
+    }
+}
+
+fn is_unspendable_pub_key_hash(pub_key_hash: &[u8]) -> bool {
+    if pub_key_hash.iter().all(|b| *b == 0) {
+        return true;
+    }
+    [GENESIS_ADDRESS, SETTINGS.burn_address.as_str()]
+        .iter()
+        .filter_map(|address| address::address_to_hash(address).ok())
+        .any(|hash| hash == pub_key_hash)
+}
@@ -0,0 +1,211 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crypto::{digest::Digest, sha2::Sha256};
+use failure::format_err;
+
+use crate::errors::Result;
+
+/*
+    UTXO snapshot files
+
+    The UTXO set at one block, for a node that has the chain but shouldn't have to rebuild the
+    set from genesis (see UTXOSet::export_snapshot and import_snapshot). The file is
+
+        MAGIC               8 bytes
+        height              i32, little endian
+        block hash          u64 little endian length, then the hex string
+        output count        u64, little endian
+        per unspent output, ordered by txid then output index:
+            txid            u64 little endian length, then the hex string
+            output index    i32, little endian
+            value           i32, little endian
+            pub key hash    u64 little endian length, then the bytes
+        content hash        SHA-256 of everything above, 32 bytes
+
+    The same state always gives the same bytes, so nodes exporting at the same block write
+    identical files and can compare content hashes. The hash catches damage, not forgery:
+    the file is as good as whoever handed it out. A file is written to <path>.part and only
+    renamed to <path> once complete, like chain files.
+*/
+
+pub const MAGIC: &[u8; 8] = b"BJUTXO01";
+pub const FILE_EXTENSION: &str = "bjutxo";
+const HASH_LEN: usize = 32;
+
+/// An unspent output, as the file lists it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotOutput {
+    pub txid: String,
+    pub index: i32, // in the transaction's outputs
+    pub value: i32,
+    pub pub_key_hash: Vec<u8>,
+}
+
+/// The UTXO set right after block `block_hash`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoSnapshot {
+    pub height: i32,
+    pub block_hash: String,
+    pub outputs: Vec<SnapshotOutput>,
+}
+
+/// What a snapshot file holds, without the outputs
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotSummary {
+    pub height: i32,
+    pub block_hash: String,
+    pub outputs: usize,
+    pub content_hash: String, // hex
+}
+
+impl std::fmt::Display for SnapshotSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} unspent outputs at height {} (block {}), content hash {}", self.outputs, self.height, self.block_hash, self.content_hash)
+    }
+}
+
+impl UtxoSnapshot {
+    /// The file's bytes. Outputs are sorted first, in whatever order they came
+    pub fn encode(&mut self) -> Vec<u8> {
+        self.outputs.sort_by(|a, b| (&a.txid, a.index).cmp(&(&b.txid, b.index)));
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&self.height.to_le_bytes());
+        put_bytes(&mut data, self.block_hash.as_bytes());
+        data.extend_from_slice(&(self.outputs.len() as u64).to_le_bytes());
+        for out in &self.outputs {
+            put_bytes(&mut data, out.txid.as_bytes());
+            data.extend_from_slice(&out.index.to_le_bytes());
+            data.extend_from_slice(&out.value.to_le_bytes());
+            put_bytes(&mut data, &out.pub_key_hash);
+        }
+        let hash = content_hash(&data);
+        data.extend_from_slice(&hash);
+        data
+    }
+
+    /// Reads a file's bytes, refusing any whose content hash doesn't match
+    pub fn decode(data: &[u8]) -> Result<(UtxoSnapshot, SnapshotSummary)> {
+        if data.len() < MAGIC.len() + HASH_LEN || &data[..MAGIC.len()] != MAGIC {
+            return Err(format_err!("Not a UTXO snapshot file"));
+        }
+        let (content, hash) = data.split_at(data.len() - HASH_LEN);
+        if content_hash(content) != hash {
+            return Err(format_err!("The UTXO snapshot doesn't match its content hash, the file is damaged"));
+        }
+
+        let mut reader = Reader { data: content, at: MAGIC.len() };
+        let height = reader.i32()?;
+        let block_hash = reader.string()?;
+        let count = reader.u64()?;
+        let mut outputs: Vec<SnapshotOutput> = Vec::new();
+        for _ in 0..count {
+            let out = SnapshotOutput {
+                txid: reader.string()?,
+                index: reader.i32()?,
+                value: reader.i32()?,
+                pub_key_hash: reader.bytes()?.to_vec(),
+            };
+            if outputs.last().is_some_and(|last| (&last.txid, last.index) >= (&out.txid, out.index)) {
+                return Err(format_err!("The UTXO snapshot lists output {}:{} out of order", out.txid, out.index));
+            }
+            outputs.push(out);
+        }
+        if reader.at != content.len() {
+            return Err(format_err!("The UTXO snapshot has {} bytes after its outputs", content.len() - reader.at));
+        }
+
+        let summary = SnapshotSummary { height, block_hash: block_hash.clone(), outputs: outputs.len(), content_hash: hex::encode(hash) };
+        Ok((UtxoSnapshot { height, block_hash, outputs }, summary))
+    }
+
+    /// Writes the file at `path`
+    pub fn write(mut self, path: &Path) -> Result<SnapshotSummary> {
+        let data = self.encode();
+        let part = PathBuf::from(format!("{}.part", path.display()));
+        fs::write(&part, &data)?;
+        fs::File::open(&part)?.sync_all()?;
+        fs::rename(&part, path)?;
+        Ok(SnapshotSummary {
+            height: self.height,
+            block_hash: self.block_hash,
+            outputs: self.outputs.len(),
+            content_hash: hex::encode(&data[data.len() - HASH_LEN..]),
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<(UtxoSnapshot, SnapshotSummary)> {
+        UtxoSnapshot::decode(&fs::read(path)?)
+    }
+}
+
+fn content_hash(data: &[u8]) -> [u8; HASH_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut hash = [0u8; HASH_LEN];
+    hasher.result(&mut hash);
+    hash
+}
+
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+// Reads the fields of `data` in order. The content hash matched, so running out means the
+// file was written wrong rather than damaged
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: u64) -> Result<&'a [u8]> {
+        let end = usize::try_from(len).ok()
+            .and_then(|len| self.at.checked_add(len))
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| format_err!("The UTXO snapshot ends in the middle of an output"))?;
+        let bytes = &self.data[self.at..end];
+        self.at = end;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_refuses_outputs_out_of_order() {
+        let output = |txid: &str, index| SnapshotOutput { txid: txid.to_string(), index, value: 5, pub_key_hash: vec![1; 20] };
+        let mut snapshot = UtxoSnapshot { height: 3, block_hash: "ab".repeat(32), outputs: vec![output("bb", 1), output("aa", 0), output("bb", 0)] };
+        let data = snapshot.encode();
+        let (decoded, summary) = UtxoSnapshot::decode(&data).unwrap();
+        assert_eq!(decoded.outputs, vec![output("aa", 0), output("bb", 0), output("bb", 1)]);
+        assert_eq!(summary.outputs, 3);
+
+        // listed twice, under a matching hash so only the order gives it away
+        let mut twice = UtxoSnapshot { outputs: vec![output("aa", 0), output("aa", 0)], ..decoded };
+        let err = UtxoSnapshot::decode(&twice.encode()).unwrap_err();
+        assert!(err.to_string().contains("out of order"), "{}", err);
+
+        assert!(UtxoSnapshot::decode(b"BJCHAIN1").is_err());
+    }
+}
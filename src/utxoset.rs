@@ -1,14 +1,24 @@
-use super::*;
+use crate::address;
+use crate::errors::{Result, UtxoError};
+use crate::tx;
 use crate::block::*;
 use crate::blockchain::*;
-use std::collections::HashMap;
-use std::sync::Arc;
+use crate::clock;
+use crate::coin_selection::{self, Candidate, CoinSelection};
+use crate::maintenance::StoreReport;
+use crate::settings::SETTINGS;
+use crate::utxo_file::{SnapshotOutput, SnapshotSummary, UtxoSnapshot};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use bincode::{deserialize, serialize};
+use sled::transaction::{ConflictableTransactionError, Transactional};
 
-use sled;
-use tx::TXOutputs;
-use log::info;
+use tx::{TXOutput, TXOutputs, UnspentOutputs};
+use failure::format_err;
 
 /*
     An unspent transaction output (UTXO) 
@@ -16,78 +26,503 @@ use log::info;
     This is a separate struct to keep track of UTXOs
     utxo is necessary to efficiently find unspent transaction outputs
 
+    The default tree maps a txid to its outputs that are still unspent (UnspentOutputs), by
+    their index in the transaction, so a spend never moves its siblings. ADDRESS_INDEX_TREE
+    maps a pub key hash to the sorted txids whose entries hold outputs for it, so one key's
+    outputs are a few point lookups. Every write goes through `commit`, which keeps the two in step and writes all of a
+    block in one transaction, and `reindex` rebuilds both (every node start runs it).
+    `import_snapshot` rebuilds them from a snapshot file instead, see utxo_file.rs.
+
+    `get_balance` caches what it sums per key in memory, `commit` drops the keys an entry
+    paid to before and after the write.
+
+    Coin selection skips the outputs our sends spend. `locked` holds the inputs of the sends
+    in the pending ledger (see pending.rs). Reservations cover a send from the moment its
+    inputs are picked until it's there: the builders in transaction.rs pick and reserve in
+    one step, so a send built meanwhile picks other outputs. A reservation ends when a block
+    spends the output, when the send fails to go out (`release`) or after RESERVATION_TIMEOUT.
+
+    While the set is rebuilt, imported or moved to another branch, which take several writes, META_TREE
+    holds UPDATING_KEY. It's still there when the process died halfway, see
+    `interrupted_update`. `verify_against_chain`
+    tells what such a crash, or anything else, left different from the chain.
 */
 
+pub const ADDRESS_INDEX_TREE: &str = "by_address";
+pub const META_TREE: &str = "meta";
+const UPDATING_KEY: &str = "updating"; // the tip moved to, "reindex" or "snapshot"
+pub const RESERVATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Clones share the store
+#[derive(Clone)]
 pub struct UTXOSet{
     pub blockchain: Arc<RwLock<Blockchain>>, // Shared blockchain instance
+    path: String, // sled directory holding the UTXOs
+    pub db: sled::Db, // opened once, every operation goes through it
+    locked: HashSet<(String, i32)>, // inputs of our pending sends, skipped by coin selection
+    reserved: Arc<Mutex<HashMap<(String, i32), u128>>>, // inputs of sends being built or sent, by when (ms)
+    balances: Arc<Mutex<HashMap<Vec<u8>, i64>>>, // by pub key hash, see get_balance
+    dust_sweep: i32, // outputs below this join every selection, see coin_selection::sweep_dust. 0 for none
+}
+
+/// An address whose balance in the UTXO set disagrees with a fresh chain scan
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceMismatch {
+    pub address: String,
+    pub chain_balance: i32,
+    pub utxo_balance: i32,
+}
+
+/// What `verify_against_chain` found, by txid
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UtxoAuditReport {
+    pub entries_checked: usize, // transactions with unspent outputs on the chain
+    pub missing: Vec<String>, // with unspent outputs on the chain, not in the set
+    pub extra: Vec<String>, // in the set, nothing of them unspent on the chain
+    pub mismatched: Vec<EntryMismatch>, // in both, with other outputs
+}
+
+/// A transaction whose unspent outputs in the set aren't the ones the chain leaves
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryMismatch {
+    pub txid: String,
+    pub chain_value: i64,
+    pub stored_value: i64,
+}
+
+impl UtxoAuditReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl std::fmt::Display for UtxoAuditReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Checked the unspent outputs of {} transactions", self.entries_checked)?;
+        if self.is_consistent() {
+            return write!(f, ", the UTXO set matches the chain");
+        }
+        write!(f, ": {} missing, {} extra, {} with other outputs", self.missing.len(), self.extra.len(), self.mismatched.len())
+    }
+}
+
+/// Coins in the UTXO set, split by whether anyone can still spend them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Supply {
+    pub circulating: i64,
+    pub burned: i64, // burn outputs and payments to unspendable addresses
 }
 
 impl UTXOSet {
 
-    pub fn new(blockchain: Arc<RwLock<Blockchain>>) -> Self {
-        Self { blockchain }
+    pub fn new(blockchain: Arc<RwLock<Blockchain>>) -> Result<Self> {
+        Self::with_path(blockchain, "utxos")
     }
 
-    // Updates UTXOs
-    pub async fn reindex(&self) -> Result<()> {
-        if let Err(_e) = std::fs::remove_dir_all("data/utxos") {
-            info!("not exist any utxos to delete");
+    // UTXO set stored somewhere other than utxos. The store stays open (and locked) until
+    // the set is dropped
+    pub fn with_path(blockchain: Arc<RwLock<Blockchain>>, path: &str) -> Result<Self> {
+        crate::maintenance::recover(path)?;
+        let db = sled::open(path)?;
+        Ok(Self { blockchain, path: path.to_string(), db, locked: HashSet::new(), reserved: Arc::default(), balances: Arc::default(), dust_sweep: dust_sweep() })
+    }
+
+    /// A UTXO set kept in memory only, for an app without a node
+    pub fn in_memory(blockchain: Arc<RwLock<Blockchain>>) -> Self {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("Failed to create an in-memory database");
+        Self { blockchain, path: String::new(), db, locked: HashSet::new(), reserved: Arc::default(), balances: Arc::default(), dust_sweep: dust_sweep() }
+    }
+
+    /// Inputs of the pending sends, replaces the previous set. Reservations stay
+    pub fn set_locked_outpoints(&mut self, locked: HashSet<(String, i32)>) {
+        self.locked = locked;
+    }
+
+    /// Outputs worth less than `below` are spent with every selection, 0 stops it
+    pub fn set_dust_sweep(&mut self, below: i32) {
+        self.dust_sweep = below;
+    }
+
+    /// Outputs (txid, index) coin selection leaves alone: the inputs of pending sends and of
+    /// sends being built
+    pub fn locked_outpoints(&self) -> HashSet<(String, i32)> {
+        let now = clock::now_millis();
+        let mut locked = self.locked.clone();
+        locked.extend(self.reserved.lock().unwrap().iter()
+            .filter(|(_, &at)| reservation_live(at, now))
+            .map(|(outpoint, _)| outpoint.clone()));
+        locked
+    }
+
+    /// Ends the reservations of `outpoints`, for a send that didn't go out
+    pub fn release(&self, outpoints: impl IntoIterator<Item = (String, i32)>) {
+        let mut reserved = self.reserved.lock().unwrap();
+        for outpoint in outpoints {
+            reserved.remove(&outpoint);
         }
-        let db = sled::open("data/utxos")?;
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 
+    // Rebuilds the UTXOs and their address index from the chain. The old ones stay until the
+    // chain has been read
+    pub async fn reindex(&self) -> Result<()> {
         let blockchain = self.blockchain.read().await;
-        let utxos = blockchain.find_utxo();
+        let utxos = blockchain.find_utxo()?;
+        self.meta()?.insert(UPDATING_KEY, "reindex")?;
+        self.replace_all(utxos)?;
+        self.meta()?.remove(UPDATING_KEY)?;
+        Ok(())
+    }
 
+    // Replaces every entry and the address index with `utxos`
+    fn replace_all(&self, utxos: HashMap<String, UnspentOutputs>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        let mut owners: HashMap<Vec<u8>, Vec<String>> = HashMap::new();
         for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+            batch.insert(txid.as_bytes(), serialize(&outs)?);
+            for pub_key_hash in owner_keys(&outs) {
+                owners.entry(pub_key_hash).or_default().push(txid.clone());
+            }
+        }
+        let mut index_batch = sled::Batch::default();
+        for (pub_key_hash, mut txids) in owners {
+            txids.sort();
+            index_batch.insert(pub_key_hash, serialize(&txids)?);
         }
 
+        let index = self.address_index()?;
+        let mut balances = self.balances.lock().unwrap();
+        self.db.clear()?;
+        index.clear()?;
+        self.db.apply_batch(batch)?;
+        index.apply_batch(index_batch)?;
+        balances.clear();
         Ok(())
     }
+
+    /// Writes the UTXO set as of the active chain's block at `height` to a snapshot file,
+    /// see utxo_file.rs
+    pub async fn export_snapshot(&self, path: &Path, height: i32) -> Result<SnapshotSummary> {
+        let blockchain = self.blockchain.read().await;
+        let block_hash = blockchain.get_block_by_height(height)?.get_hash();
+        let mut outputs = Vec::new();
+        for entry in blockchain.unspent_transactions(&block_hash)? {
+            for index in entry.unspent {
+                let out = &entry.tx.vout[index as usize];
+                outputs.push(SnapshotOutput { txid: entry.tx.id.clone(), index, value: out.value, pub_key_hash: out.pub_key_hash.clone() });
+            }
+        }
+        UtxoSnapshot { height, block_hash, outputs }.write(path)
+    }
+
+    /// Replaces the set with the one in a snapshot file, then brings it to the tip with the
+    /// blocks above the snapshot's. That block has to be on our active chain at the height
+    /// the file gives
+    pub async fn import_snapshot(&self, path: &Path) -> Result<SnapshotSummary> {
+        let (snapshot, summary) = UtxoSnapshot::read(path)?;
+        let blockchain = self.blockchain.read().await;
+        let ours = blockchain.get_block_by_height(snapshot.height).map(|block| block.get_hash()).ok();
+        if ours.as_deref() != Some(snapshot.block_hash.as_str()) {
+            return Err(format_err!("The snapshot's block {} isn't at height {} of our chain", snapshot.block_hash, snapshot.height));
+        }
+        let mut above = blockchain.get_blocks_range(snapshot.height + 1, blockchain.get_best_height()?)?;
+        above.reverse();
+
+        let mut utxos: HashMap<String, UnspentOutputs> = HashMap::new();
+        for out in snapshot.outputs {
+            utxos.entry(out.txid).or_default()
+                .outputs.insert(out.index, TXOutput { value: out.value, pub_key_hash: out.pub_key_hash });
+        }
+
+        self.meta()?.insert(UPDATING_KEY, "snapshot")?;
+        self.replace_all(utxos)?;
+        for block in &above {
+            self.update(block)?;
+        }
+        self.meta()?.remove(UPDATING_KEY)?;
+        Ok(summary)
+    }
+
+    fn meta(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(META_TREE)?)
+    }
+
+    /// What was being written when the process stopped halfway through an update: the tip of
+    /// the branch the set was moving to, "reindex" or "snapshot". None after a clean update
+    pub fn interrupted_update(&self) -> Result<Option<String>> {
+        Ok(self.meta()?.get(UPDATING_KEY)?.map(|value| String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    /// Recomputes the UTXO set from the chain and compares it with the stored one
+    pub async fn verify_against_chain(&self) -> Result<UtxoAuditReport> {
+        let chain = self.blockchain.read().await.find_utxo()?;
+        let value = |outs: &UnspentOutputs| outs.outputs.values().map(|out| out.value as i64).sum::<i64>();
+        let same = |a: &UnspentOutputs, b: &UnspentOutputs| {
+            a.outputs.len() == b.outputs.len()
+                && a.outputs.iter().zip(&b.outputs)
+                    .all(|((a_index, a), (b_index, b))| a_index == b_index && a.value == b.value && a.pub_key_hash == b.pub_key_hash)
+        };
+
+        let mut report = UtxoAuditReport { entries_checked: chain.len(), ..UtxoAuditReport::default() };
+        let mut stored_txids = HashSet::new();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k.to_vec())?;
+            let stored: UnspentOutputs = deserialize(&v)?;
+            match chain.get(&txid) {
+                // entries of fully spent transactions may be left empty
+                None if stored.outputs.is_empty() => {}
+                None => report.extra.push(txid.clone()),
+                Some(outs) if !same(outs, &stored) => report.mismatched.push(EntryMismatch {
+                    txid: txid.clone(),
+                    chain_value: value(outs),
+                    stored_value: value(&stored),
+                }),
+                Some(_) => {}
+            }
+            stored_txids.insert(txid);
+        }
+        report.missing = chain.keys().filter(|txid| !stored_txids.contains(*txid)).cloned().collect();
+        report.missing.sort();
+        report.extra.sort();
+        report.mismatched.sort_by(|a, b| a.txid.cmp(&b.txid));
+        Ok(report)
+    }
+
+    fn address_index(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(ADDRESS_INDEX_TREE)?)
+    }
+
+    /// Txids whose entries hold outputs for `pub_key_hash`, sorted
+    pub fn indexed_txids(&self, pub_key_hash: &[u8]) -> Result<Vec<String>> {
+        match self.address_index()?.get(pub_key_hash)? {
+            Some(data) => Ok(deserialize(&data)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // The entry of `txid` as `changes` leave it, None when it has no outputs
+    fn changed_entry(&self, changes: &HashMap<String, UnspentOutputs>, txid: &str) -> Result<Option<UnspentOutputs>> {
+        let outs = match changes.get(txid) {
+            Some(outs) => outs.clone(),
+            None => match self.db.get(txid)? {
+                Some(data) => deserialize(&data)?,
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(outs).filter(|outs| !outs.outputs.is_empty()))
+    }
+
+    // Replaces the entries of `changes` (no outputs removes one) and moves each in the address
+    // index from the keys it no longer pays to the ones it now does, all in one transaction
+    fn commit(&self, changes: HashMap<String, UnspentOutputs>) -> Result<()> {
+        let mut entries = sled::Batch::default();
+        let mut lists: HashMap<Vec<u8>, Vec<String>> = HashMap::new(); // index lists changed
+        let mut touched: HashSet<Vec<u8>> = HashSet::new();
+        for (txid, outs) in &changes {
+            let before = match self.db.get(txid)? {
+                Some(data) => owner_keys(&deserialize(&data)?),
+                None => HashSet::new(),
+            };
+            let after = owner_keys(outs);
+            for pub_key_hash in before.symmetric_difference(&after) {
+                let txids = match lists.entry(pub_key_hash.clone()) {
+                    Entry::Occupied(list) => list.into_mut(),
+                    Entry::Vacant(list) => list.insert(self.indexed_txids(pub_key_hash)?),
+                };
+                match (txids.binary_search(txid), after.contains(pub_key_hash)) {
+                    (Err(at), true) => txids.insert(at, txid.clone()),
+                    (Ok(at), false) => { txids.remove(at); }
+                    _ => {}
+                }
+            }
+            touched.extend(before.into_iter().chain(after));
+
+            if outs.outputs.is_empty() {
+                entries.remove(txid.as_bytes());
+            } else {
+                entries.insert(txid.as_bytes(), serialize(outs)?);
+            }
+        }
+        let mut index_lists = sled::Batch::default();
+        for (pub_key_hash, txids) in lists {
+            if txids.is_empty() {
+                index_lists.remove(pub_key_hash);
+            } else {
+                index_lists.insert(pub_key_hash, serialize(&txids)?);
+            }
+        }
+
+        let index = self.address_index()?;
+        let mut balances = self.balances.lock().unwrap();
+        (&*self.db, &index).transaction(|(db, index)| {
+            db.apply_batch(&entries)?;
+            index.apply_batch(&index_lists)?;
+            Ok::<(), ConflictableTransactionError>(())
+        })?;
+        for pub_key_hash in touched {
+            balances.remove(&pub_key_hash);
+        }
+        Ok(())
+    }
+
+    // The entries holding outputs for `pub_key_hash`, in txid order
+    fn owned_entries(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, UnspentOutputs)>> {
+        let mut entries = Vec::new();
+        for txid in self.indexed_txids(pub_key_hash)? {
+            let data = self.db.get(&txid)?
+                .ok_or_else(|| format_err!("The address index lists {} which isn't in the UTXO set", txid))?;
+            entries.push((txid, deserialize(&data)?));
+        }
+        Ok(entries)
+    }
+
+    /// Compacts the store, see maintenance.rs. Nothing else may be using the set
+    pub fn compact(&mut self) -> Result<StoreReport> {
+        if self.path.is_empty() {
+            return Err(format_err!("The UTXO set is only kept in memory"));
+        }
+        crate::maintenance::compact_db(&mut self.db, &self.path, "UTXO set")
+    }
     
     // Update updates the UTXO set with transactions from the Block
-    // The Block is considered to be the tip of a blockchain
+    // The Block is considered to be the tip of a blockchain. All of it is written or, when
+    // it spends an output the set doesn't have (UtxoError::MissingUtxo), nothing
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
-
+        let mut changes: HashMap<String, UnspentOutputs> = HashMap::new();
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
-
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
-                    }
+                    let missing = || UtxoError::MissingUtxo { txid: vin.txid.clone(), vout: vin.vout };
+                    let mut outs = self.changed_entry(&changes, &vin.txid)?.ok_or_else(missing)?;
+                    outs.outputs.remove(&vin.vout).ok_or_else(missing)?;
+                    changes.insert(vin.txid.clone(), outs);
                 }
             }
+            changes.insert(tx.id.clone(), UnspentOutputs::new(&tx.vout));
+        }
+        self.commit(changes)?;
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-            
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+        let spent: HashSet<(&String, i32)> = block.get_transactions().iter()
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.vin.iter().map(|vin| (&vin.txid, vin.vout)))
+            .collect();
+        let now = clock::now_millis();
+        self.reserved.lock().unwrap().retain(|(txid, vout), at| !spent.contains(&(txid, *vout)) && reservation_live(*at, now));
+        Ok(())
+    }
+
+    /// Undoes `update` for `block`, which must be the tip of the chain the set follows. The
+    /// outputs it spent are looked up in the block itself, then in the chain below it
+    pub fn disconnect(&self, block: &Block, blockchain: &Blockchain) -> Result<()> {
+        let mut changes: HashMap<String, UnspentOutputs> = HashMap::new();
+        for tx in block.get_transactions().iter().rev() {
+            changes.insert(tx.id.clone(), UnspentOutputs::default());
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in tx.vin.iter().rev() {
+                let prev_tx = match block.get_transactions().iter().find(|prev| prev.id == vin.txid) {
+                    Some(prev) => prev.clone(),
+                    None => blockchain.find_transaction_from(&block.get_prev_hash(), &vin.txid)?,
+                };
+                let out = prev_tx.vout.get(vin.vout as usize)
+                    .ok_or_else(|| format_err!("Transaction {} spends missing output {}:{}", tx.id, vin.txid, vin.vout))?;
+                let mut outs = self.changed_entry(&changes, &vin.txid)?.unwrap_or_default();
+                outs.outputs.insert(vin.vout, out.clone());
+                changes.insert(vin.txid.clone(), outs);
             }
+        }
+        self.commit(changes)
+    }
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+    /// Moves the set from the old branch to the new one after a reorganization, see
+    /// `Blockchain::handle_potential_reorg`. Each block is written at once, a failure
+    /// leaves the set somewhere between the branches
+    pub fn reorganize(&self, disconnected: &[Block], connected: &[Block], blockchain: &Blockchain) -> Result<()> {
+        self.meta()?.insert(UPDATING_KEY, connected.last().map(Block::get_hash).unwrap_or_default().as_bytes())?;
+        for block in disconnected {
+            self.disconnect(block, blockchain)?;
+        }
+        for block in connected {
+            self.update(block)?;
         }
+        self.meta()?.remove(UPDATING_KEY)?;
         Ok(())
     }
 
+    /// Recomputes the balances of every address touched by `block` from a fresh chain scan
+    /// and compares them with what the UTXO set says. Used as a debugging harness, the
+    /// full scan makes it far too slow to run by default.
+    pub async fn verify_block_connect(&self, block: &Block) -> Result<Vec<BalanceMismatch>> {
+        let mut touched: HashSet<Vec<u8>> = HashSet::new();
+        for tx in block.get_transactions() {
+            for out in &tx.vout {
+                touched.insert(out.pub_key_hash.clone());
+            }
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    touched.insert(address::pub_key_to_hash(&vin.pub_key));
+                }
+            }
+        }
+
+        let mut chain_balances: HashMap<Vec<u8>, i32> = HashMap::new();
+        for outs in self.blockchain.read().await.find_utxo()?.values() {
+            for out in outs.outputs.values() {
+                if touched.contains(&out.pub_key_hash) {
+                    *chain_balances.entry(out.pub_key_hash.clone()).or_insert(0) += out.value;
+                }
+            }
+        }
+
+        let mut utxo_balances: HashMap<Vec<u8>, i32> = HashMap::new();
+        for kv in self.db.iter() {
+            let (_, v) = kv?;
+            let outs: UnspentOutputs = deserialize(&v)?;
+            for out in outs.outputs.into_values() {
+                if touched.contains(&out.pub_key_hash) {
+                    *utxo_balances.entry(out.pub_key_hash).or_insert(0) += out.value;
+                }
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        for pub_key_hash in touched {
+            let chain_balance = chain_balances.get(&pub_key_hash).copied().unwrap_or(0);
+            let utxo_balance = utxo_balances.get(&pub_key_hash).copied().unwrap_or(0);
+            if chain_balance != utxo_balance {
+                mismatches.push(BalanceMismatch {
+                    address: address::wallet_address(&pub_key_hash),
+                    chain_balance,
+                    utxo_balance,
+                });
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    // Decodes up to `limit` entries, returns how many there were
+    pub fn sample_entries(&self, limit: usize) -> Result<usize> {
+        let mut count = 0;
+        for kv in self.db.iter().take(limit) {
+            let (_, v) = kv?;
+            let _: UnspentOutputs = deserialize(&v)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
+        for kv in self.db.iter() {
             kv?;
             counter += 1;
         }
@@ -95,33 +530,106 @@ impl UTXOSet {
         Ok(counter)
     }
 
-    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-        
-        let db = sled::open("data/utxos")?;
+    pub fn supply(&self) -> Result<Supply> {
+        let mut supply = Supply::default();
+        for kv in self.db.iter() {
+            let (_, v) = kv?;
+            let outs: UnspentOutputs = bincode::deserialize(&v)?;
+            for out in outs.outputs.into_values() {
+                if out.is_unspendable() {
+                    supply.burned += out.value as i64;
+                } else {
+                    supply.circulating += out.value as i64;
+                }
+            }
+        }
 
-        for kv in db.iter() {
-            let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-            // txid is the key, outputs are the value
-
-            for out_idx in 0..outs.outputs.len() {
-                // Can the output be unlocked with the public key?
-                if outs.outputs[out_idx].can_be_unlock_with(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+        Ok(supply)
+    }
+
+    /// Outputs of the key covering `amount`, picked by `strategy`, as (total, output indexes by
+    /// txid). Every unlocked output when they don't cover it
+    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32, strategy: CoinSelection) -> Result<(i32, HashMap<String, Vec<i32>>)> {
+        let reserved = self.reserved.lock().unwrap();
+        let candidates = self.unlocked_outputs(pub_key_hash, &reserved)?;
+        let picked = coin_selection::select(&candidates, amount, strategy);
+        let picked = coin_selection::sweep_dust(&candidates, picked, self.dust_sweep).into_iter().map(|i| candidates[i].clone());
+        Ok(by_txid(picked))
+    }
+
+    /// `find_spendable_outputs`, reserving the outputs picked. They're reserved even when
+    /// they don't cover `amount`, the caller releases them
+    pub fn reserve_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32, strategy: CoinSelection) -> Result<(i32, HashMap<String, Vec<i32>>)> {
+        let picked = self.reserve_outputs(pub_key_hash, |candidates| {
+            coin_selection::sweep_dust(candidates, coin_selection::select(candidates, amount, strategy), self.dust_sweep)
+        })?;
+        Ok(by_txid(picked.into_iter()))
+    }
+
+    /// Reserves the outputs `pick` chooses (indexes into the key's unlocked outputs, in txid
+    /// order) and returns them in the order picked. No other send sees them in between
+    pub fn reserve_outputs(&self, pub_key_hash: &[u8], pick: impl FnOnce(&[Candidate]) -> Vec<usize>) -> Result<Vec<Candidate>> {
+        let mut reserved = self.reserved.lock().unwrap();
+        let candidates = self.unlocked_outputs(pub_key_hash, &reserved)?;
+        let picked: Vec<Candidate> = pick(&candidates).into_iter().map(|i| candidates[i].clone()).collect();
+        let now = clock::now_millis();
+        for (txid, out_idx, _) in &picked {
+            reserved.insert((txid.clone(), *out_idx), now);
+        }
+        Ok(picked)
+    }
+
+    /// Every unlocked output the key can spend as (txid, output index, value), largest first
+    pub fn spendable_outputs(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, i32)>> {
+        let mut spendable = self.unlocked_outputs(pub_key_hash, &self.reserved.lock().unwrap())?;
+        spendable.sort_by_key(|(_, _, value)| std::cmp::Reverse(*value));
+
+        Ok(spendable)
+    }
+
+    /// What the key's locked outputs add up to, the coins its pending sends spend
+    pub fn locked_value(&self, pub_key_hash: &[u8]) -> Result<i64> {
+        let locked = self.locked_outpoints();
+        let mut value = 0;
+        for (txid, outs) in self.owned_entries(pub_key_hash)? {
+            for (&out_idx, out) in &outs.outputs {
+                if out.can_be_unlock_with(pub_key_hash) && locked.contains(&(txid.clone(), out_idx)) {
+                    value += out.value as i64;
                 }
             }
         }
+        Ok(value)
+    }
 
-        Ok((accumulated, unspent_outputs))
+    // The outputs the key can spend that no pending send holds or reserved, in txid order
+    fn unlocked_outputs(&self, pub_key_hash: &[u8], reserved: &HashMap<(String, i32), u128>) -> Result<Vec<Candidate>> {
+        let now = clock::now_millis();
+        let mut unlocked = Vec::new();
+        for (txid, outs) in self.owned_entries(pub_key_hash)? {
+            for (&out_idx, out) in &outs.outputs {
+                let outpoint = (txid.clone(), out_idx);
+                if out.can_be_unlock_with(pub_key_hash)
+                    && !self.locked.contains(&outpoint)
+                    && !reserved.get(&outpoint).is_some_and(|&at| reservation_live(at, now))
+                {
+                    unlocked.push((txid.clone(), out_idx, out.value));
+                }
+            }
+        }
+        Ok(unlocked)
+    }
+
+    /// Sum of the unspent outputs paying `address`, cached until a block spends or pays them
+    pub fn get_balance(&self, address: &str) -> Result<i64> {
+        let pub_key_hash = address::address_to_hash(address)?;
+        // held while summing, so a write can't drop the key before a stale sum goes in
+        let mut balances = self.balances.lock().unwrap();
+        if let Some(balance) = balances.get(&pub_key_hash) {
+            return Ok(*balance);
+        }
+        let balance = self.find_utxo(&pub_key_hash)?.outputs.iter().map(|out| out.value as i64).sum();
+        balances.insert(pub_key_hash, balance);
+        Ok(balance)
     }
 
     /// FindUTXO finds UTXOs for a public key hash
@@ -129,16 +637,11 @@ impl UTXOSet {
         let mut utxos = TXOutputs {
             outputs: Vec::new(),
         };
-        let db = sled::open("data/utxos")?;
-
-        for kv in db.iter() {
-            let (_, v) = kv?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-
-            // Goes through all utxos and checks if they are unlocked by that address
-            for out in outs.outputs {
+        for (_, outs) in self.owned_entries(pub_key_hash)? {
+            // The entries can hold outputs for other addresses too
+            for out in outs.outputs.into_values() {
                 if out.can_be_unlock_with(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+                    utxos.outputs.push(out)
                 }
             }
         }
@@ -146,4 +649,415 @@ impl UTXOSet {
         Ok(utxos)
     }
 
-}
\ No newline at end of file
+}
+
+// Keys the outputs pay to
+fn owner_keys(outs: &UnspentOutputs) -> HashSet<Vec<u8>> {
+    outs.outputs.values().map(|out| out.pub_key_hash.clone()).collect()
+}
+
+// (total, output indexes by txid) of picked outputs
+fn by_txid(picked: impl Iterator<Item = Candidate>) -> (i32, HashMap<String, Vec<i32>>) {
+    let mut outputs: HashMap<String, Vec<i32>> = HashMap::new();
+    let mut total = 0;
+    for (txid, out_idx, value) in picked {
+        total += value;
+        outputs.entry(txid).or_default().push(out_idx);
+    }
+    (total, outputs)
+}
+
+// What the settings have coin selection sweep
+fn dust_sweep() -> i32 {
+    if SETTINGS.sweep_dust { SETTINGS.dust_threshold } else { 0 }
+}
+
+fn reservation_live(reserved_at: u128, now: u128) -> bool {
+    now.saturating_sub(reserved_at) <= RESERVATION_TIMEOUT.as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{coinbase, ChainBuilder, TxBuilder, UtxoFixture, WalletFixture};
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallets;
+
+    #[tokio::test]
+    async fn test_corrupted_entry_flagged_on_next_block() {
+        let address = Wallets::default().create_wallet();
+        let mut bc = Blockchain::default_empty();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase(address.clone(), String::new(), 0).unwrap());
+        let genesis_txid = genesis.get_transactions()[0].id.clone();
+        bc.add_block(genesis).unwrap();
+
+        let path = std::env::temp_dir().join(format!("blockjain-utxos-{}", rand::random::<u64>()));
+        let utxo_set = UTXOSet::with_path(Arc::new(RwLock::new(bc)), path.to_str().unwrap()).unwrap();
+        utxo_set.reindex().await.unwrap();
+
+        // Corrupt the genesis reward
+        let mut outs: UnspentOutputs = deserialize(&utxo_set.db.get(&genesis_txid).unwrap().unwrap()).unwrap();
+        outs.outputs.get_mut(&0).unwrap().value += 5;
+        utxo_set.db.insert(genesis_txid.as_bytes(), serialize(&outs).unwrap()).unwrap();
+
+        // The next block paying the same address surfaces it
+        let block = utxo_set.blockchain.write().await
+            .mine_block(vec![Transaction::new_coinbase(address.clone(), String::new(), 1).unwrap()])
+            .unwrap();
+        utxo_set.update(&block).unwrap();
+
+        let mismatches = utxo_set.verify_block_connect(&block).await.unwrap();
+        assert_eq!(mismatches, vec![BalanceMismatch { address, chain_balance: 20, utxo_balance: 25 }]);
+
+        drop(utxo_set);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_coinbases_with_the_same_data_keep_apart() {
+        let miner = WalletFixture::new(1);
+        let utxo_set = UtxoFixture::new(ChainBuilder::new(&miner).build()).await;
+
+        // Two empty blocks whose coinbases carry the same data, as the app's mine-now path does
+        let mut txids = Vec::new();
+        for height in [1, 2] {
+            let cbtx = Transaction::new_coinbase(miner.address(), String::from("reward!"), height).unwrap();
+            assert_eq!(cbtx.coinbase_height(), Some(height));
+            let block = utxo_set.blockchain.write().await.mine_block(vec![cbtx.clone()]).unwrap();
+            utxo_set.update(&block).unwrap();
+            txids.push(cbtx.id);
+        }
+        assert_ne!(txids[0], txids[1]);
+        assert_eq!(utxo_set.get_balance(&miner.address()).unwrap(), 30);
+        assert_index_consistent(&utxo_set);
+
+        // A coinbase made for another height isn't mined
+        let stale = Transaction::new_coinbase(miner.address(), String::from("reward!"), 2).unwrap();
+        assert!(utxo_set.blockchain.write().await.mine_block(vec![stale]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner)
+            .spend(&reward, 0)
+            .pay(&other.address(), 3)
+            .pay(&miner.address(), 7)
+            .build();
+        let block = chain.next_block(vec![tx]);
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+
+        utxo_set.blockchain.write().await.add_block(block.clone()).unwrap();
+        utxo_set.update(&block).unwrap();
+
+        // Applying the block incrementally ends where a full reindex would
+        let balance = |outs: TXOutputs| outs.outputs.iter().map(|o| o.value).sum::<i32>();
+        assert_eq!(balance(utxo_set.find_utxo(&miner.pub_key_hash()).unwrap()), 17);
+        assert_eq!(balance(utxo_set.find_utxo(&other.pub_key_hash()).unwrap()), 3);
+        assert_eq!(utxo_set.count_transactions().unwrap(), 2);
+        assert!(utxo_set.verify_block_connect(&block).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_supply_counts_burns() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let tx = TxBuilder::new(&miner)
+            .spend(&reward, 0)
+            .burn(4)
+            .pay(GENESIS_ADDRESS, 1)
+            .pay(&other.address(), 2)
+            .pay(&miner.address(), 3)
+            .build();
+        let chain = chain.block(vec![tx]);
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+
+        // 20 coins mined, 4 burned on purpose and 1 sent to the genesis placeholder
+        assert_eq!(utxo_set.supply().unwrap(), Supply { circulating: 15, burned: 5 });
+        // Burned coins don't show up in anyone's balance
+        let balance = |outs: TXOutputs| outs.outputs.iter().map(|o| o.value).sum::<i32>();
+        assert_eq!(balance(utxo_set.find_utxo(&miner.pub_key_hash()).unwrap()), 13);
+        assert_eq!(balance(utxo_set.find_utxo(&other.pub_key_hash()).unwrap()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_tasks_share_the_store() {
+        let miner = WalletFixture::new(1);
+        let utxo_set = UtxoFixture::new(ChainBuilder::new(&miner).empty_blocks(3).build()).await;
+
+        // Used to fail now and then on the store's lock, or find it deleted by the reindex
+        let tasks: Vec<_> = (0..8).map(|i| {
+            let utxo_set = UTXOSet::clone(&utxo_set);
+            let pub_key_hash = miner.pub_key_hash();
+            tokio::spawn(async move {
+                if i % 2 == 0 {
+                    utxo_set.reindex().await.map(|_| ())
+                } else {
+                    utxo_set.find_utxo(&pub_key_hash).map(|_| ())
+                }
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(utxo_set.count_transactions().unwrap(), 4);
+    }
+
+    // The address index as a full scan of the entries would build it
+    fn assert_index_consistent(utxo_set: &UTXOSet) {
+        let mut expected: HashMap<Vec<u8>, Vec<String>> = HashMap::new();
+        for kv in utxo_set.db.iter() {
+            let (k, v) = kv.unwrap();
+            for pub_key_hash in owner_keys(&deserialize(&v).unwrap()) {
+                expected.entry(pub_key_hash).or_default().push(String::from_utf8(k.to_vec()).unwrap());
+            }
+        }
+        let indexed: HashMap<Vec<u8>, Vec<String>> = utxo_set.address_index().unwrap().iter()
+            .map(|kv| kv.unwrap())
+            .map(|(k, v)| (k.to_vec(), deserialize(&v).unwrap()))
+            .collect();
+        assert_eq!(indexed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_address_index_follows_spends() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let split = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let block1 = chain.next_block(vec![split.clone()]);
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+        assert_index_consistent(&utxo_set);
+
+        utxo_set.blockchain.write().await.add_block(block1.clone()).unwrap();
+        utxo_set.update(&block1).unwrap();
+        assert_index_consistent(&utxo_set);
+        // the genesis reward is spent, nothing points at it anymore
+        assert!(!utxo_set.indexed_txids(&miner.pub_key_hash()).unwrap().contains(&reward.id));
+        assert_eq!(utxo_set.indexed_txids(&other.pub_key_hash()).unwrap(), vec![split.id.clone()]);
+
+        // other spends all it has, split is left with the miner's change only
+        let back = TxBuilder::new(&other).spend(&split, 0).pay(&miner.address(), 4).build();
+        let block2 = Block::new_block(vec![coinbase(&miner.address(), 2), back], block1.get_hash(), 2, INITIAL_TARGET).unwrap();
+        utxo_set.blockchain.write().await.add_block(block2.clone()).unwrap();
+        utxo_set.update(&block2).unwrap();
+        assert_index_consistent(&utxo_set);
+        assert!(utxo_set.indexed_txids(&other.pub_key_hash()).unwrap().is_empty());
+        assert!(utxo_set.find_utxo(&other.pub_key_hash()).unwrap().outputs.is_empty());
+        let balance = |outs: TXOutputs| outs.outputs.iter().map(|o| o.value).sum::<i32>();
+        assert_eq!(balance(utxo_set.find_utxo(&miner.pub_key_hash()).unwrap()), 30);
+        assert_eq!(utxo_set.find_spendable_outputs(&miner.pub_key_hash(), 30, CoinSelection::default()).unwrap().0, 30);
+
+        // and back
+        let chain = utxo_set.blockchain.read().await;
+        utxo_set.disconnect(&block2, &chain).unwrap();
+        assert_index_consistent(&utxo_set);
+        assert_eq!(balance(utxo_set.find_utxo(&other.pub_key_hash()).unwrap()), 4);
+    }
+
+    #[tokio::test]
+    async fn test_spends_keep_the_indexes_of_their_siblings() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let chain = ChainBuilder::new(&alice);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let split = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 3).pay(&carol.address(), 4).pay(&alice.address(), 3).build();
+        let chain = chain.block(vec![split.clone()]);
+        let block1 = chain.tip();
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+        let outpoints = |wallet: &WalletFixture| utxo_set.spendable_outputs(&wallet.pub_key_hash()).unwrap()
+            .into_iter().filter(|(txid, _, _)| *txid == split.id).map(|(_, vout, _)| vout).collect::<Vec<_>>();
+
+        // carol spends the middle output, alice's is still (split, 2)
+        let by_carol = TxBuilder::new(&carol).spend(&split, 1).pay(&bob.address(), 4).build();
+        let block2 = Block::new_block(vec![coinbase(&alice.address(), 2), by_carol], block1.get_hash(), 2, INITIAL_TARGET).unwrap();
+        utxo_set.blockchain.write().await.add_block(block2.clone()).unwrap();
+        utxo_set.update(&block2).unwrap();
+        assert_eq!(outpoints(&bob), vec![0]);
+        assert_eq!(outpoints(&alice), vec![2]);
+
+        // then its sibling
+        let by_alice = TxBuilder::new(&alice).spend(&split, 2).pay(&bob.address(), 3).build();
+        let block3 = Block::new_block(vec![coinbase(&alice.address(), 3), by_alice], block2.get_hash(), 3, INITIAL_TARGET).unwrap();
+        utxo_set.blockchain.write().await.add_block(block3.clone()).unwrap();
+        utxo_set.update(&block3).unwrap();
+        assert_eq!(outpoints(&bob), vec![0]);
+        assert!(outpoints(&alice).is_empty());
+        assert_eq!(utxo_set.get_balance(&bob.address()).unwrap(), 10);
+        assert!(utxo_set.verify_against_chain().await.unwrap().is_consistent());
+
+        // a reindex ends in the same place, disconnecting puts the outputs back at their indexes
+        utxo_set.reindex().await.unwrap();
+        assert_eq!(utxo_set.get_balance(&bob.address()).unwrap(), 10);
+        assert_eq!(utxo_set.get_balance(&carol.address()).unwrap(), 0);
+        let chain = utxo_set.blockchain.read().await;
+        utxo_set.disconnect(&block3, &chain).unwrap();
+        utxo_set.disconnect(&block2, &chain).unwrap();
+        assert_eq!(utxo_set.get_balance(&carol.address()).unwrap(), 4);
+        assert_eq!(outpoints(&alice), vec![2]);
+        assert_index_consistent(&utxo_set);
+    }
+
+    #[tokio::test]
+    async fn test_audit_finds_entries_that_differ_from_the_chain() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let split = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let block = chain.next_block(vec![split.clone()]);
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+        utxo_set.blockchain.write().await.add_block(block.clone()).unwrap();
+        utxo_set.update(&block).unwrap();
+        assert_eq!(utxo_set.interrupted_update().unwrap(), None);
+        let report = utxo_set.verify_against_chain().await.unwrap();
+        assert!(report.is_consistent(), "{}", report);
+        assert_eq!(report.entries_checked, 2);
+
+        // one entry lost, one that should be gone and one worth a coin more
+        let coinbase_id = block.get_transactions()[0].id.clone();
+        utxo_set.db.remove(&coinbase_id).unwrap();
+        utxo_set.db.insert(reward.id.as_bytes(), serialize(&UnspentOutputs::new(&reward.vout)).unwrap()).unwrap();
+        let mut outs: UnspentOutputs = deserialize(&utxo_set.db.get(&split.id).unwrap().unwrap()).unwrap();
+        outs.outputs.get_mut(&1).unwrap().value += 1;
+        utxo_set.db.insert(split.id.as_bytes(), serialize(&outs).unwrap()).unwrap();
+
+        let report = utxo_set.verify_against_chain().await.unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(report.missing, vec![coinbase_id]);
+        assert_eq!(report.extra, vec![reward.id.clone()]);
+        assert_eq!(report.mismatched, vec![EntryMismatch { txid: split.id.clone(), chain_value: 10, stored_value: 11 }]);
+
+        // a crash halfway through a block leaves the marker, reindexing clears it
+        utxo_set.meta().unwrap().insert(UPDATING_KEY, block.get_hash().as_bytes()).unwrap();
+        assert_eq!(utxo_set.interrupted_update().unwrap(), Some(block.get_hash()));
+        utxo_set.reindex().await.unwrap();
+        assert_eq!(utxo_set.interrupted_update().unwrap(), None);
+        assert!(utxo_set.verify_against_chain().await.unwrap().is_consistent());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trips_and_catches_changes() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let split = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 4).pay(&miner.address(), 6).build();
+        let chain = chain.block(vec![split.clone()]);
+        let pay_back = TxBuilder::new(&other).spend(&split, 0).pay(&miner.address(), 4).build();
+        let chain = chain.block(vec![pay_back]);
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+
+        let dir = std::env::temp_dir().join(format!("blockjain-utxo-snapshot-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("at-1.bjutxo");
+        let exported = utxo_set.export_snapshot(&path, 1).await.unwrap();
+        assert_eq!(exported.height, 1);
+        assert_eq!(exported.outputs, 3); // the split's two outputs and the block's reward
+
+        // imported and brought back to the tip, the set matches the chain
+        utxo_set.db.clear().unwrap();
+        let imported = utxo_set.import_snapshot(&path).await.unwrap();
+        assert_eq!(imported, exported);
+        assert_eq!(utxo_set.interrupted_update().unwrap(), None);
+        assert!(utxo_set.verify_against_chain().await.unwrap().is_consistent());
+        assert_eq!(utxo_set.get_balance(&other.address()).unwrap(), 0);
+
+        let again = dir.join("again.bjutxo");
+        utxo_set.export_snapshot(&again, 1).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&again).unwrap());
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let err = utxo_set.import_snapshot(&path).await.unwrap_err();
+        assert!(err.to_string().contains("content hash"), "{}", err);
+
+        // a snapshot of another chain
+        let stranger = WalletFixture::new(3);
+        let theirs = UtxoFixture::new(ChainBuilder::new(&stranger).empty_blocks(1).build()).await;
+        let foreign = dir.join("foreign.bjutxo");
+        theirs.export_snapshot(&foreign, 1).await.unwrap();
+        let err = utxo_set.import_snapshot(&foreign).await.unwrap_err();
+        assert!(err.to_string().contains("isn't at height 1"), "{}", err);
+        assert!(utxo_set.verify_against_chain().await.unwrap().is_consistent());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_spending_an_unknown_output_changes_nothing() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+
+        // the reward is fine, the second input isn't anywhere
+        let mut unknown = coinbase(&miner.address(), 7);
+        unknown.id = "00".repeat(32);
+        let spend = TxBuilder::new(&miner).spend(&reward, 0).spend(&unknown, 0).pay(&other.address(), 10).build();
+        let tip = utxo_set.blockchain.read().await.tip.clone();
+        let block = Block::new_block(vec![coinbase(&miner.address(), 1), spend], tip, 1, INITIAL_TARGET).unwrap();
+
+        let err = utxo_set.update(&block).unwrap_err();
+        assert_eq!(err.downcast_ref::<UtxoError>(), Some(&UtxoError::MissingUtxo { txid: unknown.id.clone(), vout: 0 }));
+        // nothing of the block was written, the reward is still unspent
+        assert_eq!(utxo_set.count_transactions().unwrap(), 1);
+        assert_eq!(utxo_set.get_balance(&miner.address()).unwrap(), 10);
+        assert_index_consistent(&utxo_set);
+        assert!(utxo_set.verify_against_chain().await.unwrap().is_consistent());
+
+        // so is an output index past the end of an entry
+        let mut past_end = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 10).build();
+        past_end.vin[0].vout = 3;
+        let block = Block::new_block(vec![coinbase(&miner.address(), 1), past_end], utxo_set.blockchain.read().await.tip.clone(), 1, INITIAL_TARGET).unwrap();
+        let err = utxo_set.update(&block).unwrap_err();
+        assert_eq!(err.downcast_ref::<UtxoError>(), Some(&UtxoError::MissingUtxo { txid: reward.id.clone(), vout: 3 }));
+        assert_eq!(utxo_set.count_transactions().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cached_balances_follow_payments() {
+        let miner = WalletFixture::new(1);
+        let other = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&miner);
+        let reward = chain.tip().get_transactions()[0].clone();
+        let payment = TxBuilder::new(&miner).spend(&reward, 0).pay(&other.address(), 3).pay(&miner.address(), 7).build();
+        let block = chain.next_block(vec![payment]);
+        let utxo_set = UtxoFixture::new(chain.build()).await;
+
+        // every output paying the key, whatever the index says
+        let recount = |wallet: &WalletFixture| -> i64 {
+            utxo_set.db.iter()
+                .map(|kv| deserialize::<UnspentOutputs>(&kv.unwrap().1).unwrap())
+                .flat_map(|outs| outs.outputs.into_values())
+                .filter(|out| out.pub_key_hash == wallet.pub_key_hash())
+                .map(|out| out.value as i64)
+                .sum()
+        };
+
+        assert_eq!(utxo_set.get_balance(&miner.address()).unwrap(), 10);
+        assert_eq!(utxo_set.get_balance(&other.address()).unwrap(), 0);
+        assert_eq!(utxo_set.balances.lock().unwrap().len(), 2);
+
+        utxo_set.blockchain.write().await.add_block(block.clone()).unwrap();
+        utxo_set.update(&block).unwrap();
+        assert!(utxo_set.balances.lock().unwrap().is_empty());
+        for wallet in [&miner, &other] {
+            assert_eq!(utxo_set.get_balance(&wallet.address()).unwrap(), recount(wallet));
+            // cached now
+            assert_eq!(utxo_set.get_balance(&wallet.address()).unwrap(), recount(wallet));
+        }
+        assert_eq!(utxo_set.get_balance(&miner.address()).unwrap(), 17);
+        assert_eq!(utxo_set.get_balance(&other.address()).unwrap(), 3);
+        assert!(utxo_set.get_balance("not an address").is_err());
+    }
+}
@@ -1,17 +1,134 @@
 use std::collections::HashMap;
-use crate::errors::Result;
+use crate::address;
+use crate::audit::{self, AuditAction, AuditLog, AuditOutcome};
+use crate::clock;
+use crate::errors::{Result, WalletStoreError};
+use crate::maintenance::StoreReport;
 
-use bitcoincash_addr::{Address, HashType, Scheme, Network};
-use crypto::{digest::Digest, ripemd160::Ripemd160, sha2::Sha256};
+use crypto::{digest::Digest, sha2::Sha256};
 use ed25519_dalek::SigningKey;
 
 use rand::rngs::OsRng;
 use serde::{Serialize, Deserialize};
 
+pub const WALLETS_PATH: &str = "wallets";
+
+/*
+    Wallet records
+
+    Every wallet is stored under its address as RECORD_MAGIC, a little endian u32 version
+    and that version's bincode payload. Databases from before records hold a bare bincode
+    `WalletV0`, which is version 0. Loading decodes the version it finds, upgrades it one
+    version at a time to WALLET_RECORD_VERSION and writes the upgraded record back right
+    away. A version above ours fails with WalletStoreError::NewerVersion and the record is
+    left as it is.
+
+    The current version's payload is `Wallet` itself. Before changing Wallet, copy it as
+    WalletV<n>, bump WALLET_RECORD_VERSION and add its decoder and upgrade step to
+    StoredWallet. fixtures/wallet_records holds a record of every version.
+*/
+
+pub const WALLET_RECORD_VERSION: u32 = 2;
+const RECORD_MAGIC: &[u8; 4] = b"BJWR";
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
     pub secret_key: Vec<u8>,
     pub public_key: Vec<u8>,
+    pub metadata: WalletMetadata,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WalletMetadata {
+    pub label: String, // empty for none
+    pub created_at: u128, // ms since the epoch, 0 when unknown (restored from a key)
+    pub change_of: String, // address of the wallet this one holds change for, empty for a wallet of its own
+}
+
+/// A wallet as stored by record version 1, before change wallets
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletV1 {
+    pub secret_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub metadata: WalletMetadataV1,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WalletMetadataV1 {
+    pub label: String,
+    pub created_at: u128,
+}
+
+/// A wallet as stored before records, and as legacy wallet files hold it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletV0 {
+    pub secret_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+// A record decoded as the version it was written with
+enum StoredWallet {
+    V0(WalletV0),
+    V1(WalletV1),
+    V2(Wallet),
+}
+
+impl StoredWallet {
+    fn decode(version: u32, payload: &[u8]) -> Result<StoredWallet> {
+        match version {
+            0 => Ok(StoredWallet::V0(bincode::deserialize(payload)?)),
+            1 => Ok(StoredWallet::V1(bincode::deserialize(payload)?)),
+            _ => Ok(StoredWallet::V2(bincode::deserialize(payload)?)), // newer ones were turned away
+        }
+    }
+
+    // One version up, the current version stays as it is
+    fn upgrade(self) -> StoredWallet {
+        match self {
+            StoredWallet::V0(old) => StoredWallet::V1(WalletV1 {
+                secret_key: old.secret_key,
+                public_key: old.public_key,
+                metadata: WalletMetadataV1::default(),
+            }),
+            StoredWallet::V1(old) => StoredWallet::V2(Wallet {
+                secret_key: old.secret_key,
+                public_key: old.public_key,
+                metadata: WalletMetadata { label: old.metadata.label, created_at: old.metadata.created_at, change_of: String::new() },
+            }),
+            current => current,
+        }
+    }
+}
+
+/// Decodes the stored record of wallet `address`, upgraded to the current version, along
+/// with the version it was stored as
+pub fn decode_record(address: &str, bytes: &[u8]) -> Result<(Wallet, u32)> {
+    let (version, payload) = match bytes.strip_prefix(RECORD_MAGIC) {
+        Some(rest) => {
+            let version = rest.get(..4).ok_or_else(|| WalletStoreError::Truncated { address: address.to_string() })?;
+            (u32::from_le_bytes([version[0], version[1], version[2], version[3]]), &rest[4..])
+        }
+        None => (0, bytes),
+    };
+    if version > WALLET_RECORD_VERSION {
+        return Err(WalletStoreError::NewerVersion { address: address.to_string(), version }.into());
+    }
+
+    let mut stored = StoredWallet::decode(version, payload)?;
+    loop {
+        match stored {
+            StoredWallet::V2(wallet) => return Ok((wallet, version)),
+            older => stored = older.upgrade(),
+        }
+    }
+}
+
+/// The record of `wallet` at the current version
+pub fn encode_record(wallet: &Wallet) -> Result<Vec<u8>> {
+    let mut bytes = RECORD_MAGIC.to_vec();
+    bytes.extend_from_slice(&WALLET_RECORD_VERSION.to_le_bytes());
+    bytes.extend(bincode::serialize(wallet)?);
+    Ok(bytes)
 }
 
 impl Wallet {
@@ -26,9 +143,17 @@ impl Wallet {
         Wallet {
             secret_key: signing_key.as_bytes().to_vec(),
             public_key: public_key.as_bytes().to_vec(),
+            metadata: WalletMetadata { label: String::new(), created_at: clock::now_millis(), change_of: String::new() },
         }
     }
 
+    /// A new wallet holding change for the wallet at `owner`
+    pub fn new_change(owner: &str) -> Self {
+        let mut wallet = Wallet::new();
+        wallet.metadata.change_of = owner.to_string();
+        wallet
+    }
+
     // Reconstruct a wallet from an existing secret key
     pub fn from_secret_key(secret_key: &[u8; 32]) -> Self {
         // Use the secret key to derive the public key
@@ -38,68 +163,211 @@ impl Wallet {
         Wallet {
             secret_key: signing_key.as_bytes().to_vec(),
             public_key: public_key.as_bytes().to_vec(),
+            metadata: WalletMetadata::default(),
         }
     }
 
     // hashes the public_key and returns the address
     pub fn get_address(&self) -> String {
-        // Hash the public key first with SHA256
+        address::pub_key_to_address(&self.public_key)
+    }
+}
+
+/*
+    Wallet tags
+
+    Addresses look alike, so each one also gets a short tag like "🦊 brave-otter" shown on
+    a colored chip. Everything is taken from SHA256 of the pub key hash: the adjective, the
+    noun and the emoji (6 + 6 + 5 bits) tell tens of wallets apart, the hue (8 bits) makes
+    the chip easy to spot. Tags only depend on the address, they're the same every session.
+*/
+
+const TAG_ADJECTIVES: [&str; 64] = [
+    "amber", "bold", "brave", "brisk", "calm", "clever", "cosmic", "crisp",
+    "dapper", "eager", "early", "fancy", "fierce", "fluffy", "gentle", "giant",
+    "glad", "golden", "grand", "happy", "hidden", "humble", "icy", "jolly",
+    "keen", "kind", "lively", "lucky", "lunar", "mellow", "merry", "mighty",
+    "misty", "noble", "odd", "plucky", "polar", "proud", "quick", "quiet",
+    "rapid", "rosy", "royal", "rusty", "shy", "silent", "silver", "sleepy",
+    "smooth", "snowy", "solar", "spicy", "steady", "stormy", "sunny", "swift",
+    "tidy", "tiny", "vivid", "wandering", "warm", "wild", "witty", "zesty",
+];
+
+const TAG_NOUNS: [&str; 64] = [
+    "anchor", "badger", "beacon", "bear", "bison", "canyon", "cedar", "comet",
+    "coral", "crane", "dolphin", "dune", "eagle", "ember", "falcon", "fern",
+    "fjord", "fox", "gecko", "glacier", "harbor", "hawk", "heron", "island",
+    "jaguar", "kestrel", "koala", "lagoon", "lantern", "lemur", "lynx", "maple",
+    "meadow", "meteor", "moose", "nebula", "newt", "oak", "orca", "otter",
+    "owl", "panda", "pebble", "pine", "planet", "puffin", "quartz", "raven",
+    "reef", "river", "robin", "salmon", "sparrow", "summit", "tiger", "tundra",
+    "valley", "viper", "walrus", "willow", "wolf", "yak", "zebra", "zephyr",
+];
+
+const TAG_EMOJIS: [&str; 32] = [
+    "🐱", "🐶", "🦊", "🐻", "🐼", "🐸", "🐵", "🐔", "🐧", "🐦", "🐢", "🐍", "🐙", "🐳", "🐬", "🐟",
+    "🌵", "🌲", "🌻", "🌙", "⭐", "🔥", "💧", "⚡", "🍎", "🍋", "🍒", "🍇", "🎲", "🎵", "🔔", "⚓",
+];
+
+/// Short human-readable identifier of an address, for telling wallets apart in the UI
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WalletTag {
+    pub emoji: &'static str,
+    pub words: String, // "adjective-noun"
+    pub color: (u8, u8, u8), // rgb of the chip
+}
+
+impl WalletTag {
+    pub fn from_pub_key_hash(pub_key_hash: &[u8]) -> Self {
         let mut sha256 = Sha256::new();
-        sha256.input(&self.public_key);
-        let sha256_result = sha256.result_str(); // Hex string of SHA256 hash
-
-        // Convert the SHA256 hash back to bytes and apply RIPEMD160
-        let mut ripemd160 = Ripemd160::new();
-        ripemd160.input(&hex::decode(sha256_result).unwrap());
-        let ripemd160_bytes = ripemd160.result_str();
-
-        // Convert the RIPEMD160 result into bytes for the address generation
-        let ripemd160_vec = hex::decode(ripemd160_bytes).unwrap();
-
-        let address = Address::new(
-            ripemd160_vec,
-            Scheme::Base58,       // Choose Base58 or CashAddr
-            HashType::Key,        // Public Key Hash
-            Network::Main,     // Use Mainnet or Testnet as appropriate
-        );
-        
-        address.encode().unwrap()
+        sha256.input(pub_key_hash);
+        let mut digest = [0u8; 32];
+        sha256.result(&mut digest);
+
+        WalletTag {
+            emoji: TAG_EMOJIS[digest[2] as usize % TAG_EMOJIS.len()],
+            words: format!(
+                "{}-{}",
+                TAG_ADJECTIVES[digest[0] as usize % TAG_ADJECTIVES.len()],
+                TAG_NOUNS[digest[1] as usize % TAG_NOUNS.len()],
+            ),
+            color: chip_color(digest[3]),
+        }
+    }
 
+    /// None for strings that aren't addresses
+    pub fn from_address(address: &str) -> Option<Self> {
+        address::address_to_hash(address).ok().map(|hash| Self::from_pub_key_hash(&hash))
     }
 }
 
-#[derive(Clone)]
+impl std::fmt::Display for WalletTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.emoji, self.words)
+    }
+}
+
+// A muted color of hue `hue` / 256, dark enough for white text
+fn chip_color(hue: u8) -> (u8, u8, u8) {
+    let h = hue as f32 / 256.0 * 6.0;
+    let (max, min) = (170.0, 60.0);
+    let x = min + (max - min) * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (max, x, min),
+        1 => (x, max, min),
+        2 => (min, max, x),
+        3 => (min, x, max),
+        4 => (x, min, max),
+        _ => (max, min, x),
+    };
+    (r as u8, g as u8, b as u8)
+}
+
+/*
+    Change wallets
+
+    Change going back to the address that paid makes every send easy to link to the next one,
+    and piles the whole balance up on one address. With the change_addresses setting on, each
+    send with change creates a new wallet for it instead (Wallet::new_change), marked with
+    `change_of` as holding change for the wallet that paid. ChangeAddressProvider picks the
+    address: FreshChangeAddress writes the new wallet to the wallets database before its
+    address goes in a transaction, so no change is ever sent to a key we could lose.
+    `Wallets::load_new` picks it up afterwards.
+
+    A change wallet isn't shown on its own. get_all_address lists the wallets of their own,
+    change wallets count, and are spent from, under the wallet they hold change for
+    (spending_wallets). One whose wallet was deleted is listed on its own again.
+*/
+
+/// Where a transaction paid by `wallet` sends its change
+pub trait ChangeAddressProvider: Send + Sync {
+    fn change_address(&self, wallet: &Wallet) -> Result<String>;
+}
+
+/// Change back to the paying wallet's own address
+pub struct SameAddress;
+
+impl ChangeAddressProvider for SameAddress {
+    fn change_address(&self, wallet: &Wallet) -> Result<String> {
+        Ok(wallet.get_address())
+    }
+}
+
+/// Change to a new wallet of the same owner, saved in `store` right away
+pub struct FreshChangeAddress {
+    pub store: sled::Db,
+}
+
+impl ChangeAddressProvider for FreshChangeAddress {
+    fn change_address(&self, wallet: &Wallet) -> Result<String> {
+        let owner = match wallet.metadata.change_of.as_str() {
+            "" => wallet.get_address(),
+            owner => owner.to_string(),
+        };
+        let change = Wallet::new_change(&owner);
+        let address = change.get_address();
+        self.store.insert(&address, encode_record(&change)?)?;
+        self.store.flush()?;
+        println!("Create change wallet: {} for {}", address, owner);
+        Ok(address)
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct Wallets {
     // address, Wallet
     wallets: HashMap<String, Wallet>,
+    store: Option<(String, sled::Db)>, // path and handle of the store they were loaded from, kept open
+    audit: AuditLog, // where deletions are recorded
 }
 
 impl Wallets {
 
     // returns wallets that are stored on the device's db
     pub fn new() -> Result<Wallets> {
+        Wallets::load(WALLETS_PATH)
+    }
+
+    /// Reads the wallets stored at `path`, upgrading records written by older versions. The
+    /// store stays open (and locked) for saving until the wallets are dropped
+    pub fn load(path: &str) -> Result<Wallets> {
+        crate::maintenance::recover(path)?;
+        let db = sled::open(path)?;
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            store: None,
+            audit: AuditLog::default(),
         };
 
-        let db = sled::open("data/wallets")?;
-        for item in db.into_iter() {
-            let i = item?;
-            let address = String::from_utf8(i.0.to_vec())?;
-            let wallet: Wallet = bincode::deserialize(&i.1.to_vec())?;
-            
-            wlt.wallets.insert(address, wallet);
-        }
-
-        drop(db);
+        wlt.store = Some((path.to_string(), db));
+        wlt.load_new()?;
         Ok(wlt)
     }
-    
-    // returns empty Wallets
-    pub fn default() -> Wallets {
-        Wallets {
-            wallets: HashMap::new()
+
+    /// Reads the wallets of the store that aren't loaded yet, like the change wallets a
+    /// FreshChangeAddress wrote
+    pub fn load_new(&mut self) -> Result<()> {
+        let Some((_, db)) = &self.store else {
+            return Ok(());
+        };
+
+        for item in db.iter() {
+            let (key, value) = item?;
+            let address = String::from_utf8(key.to_vec())?;
+            if self.wallets.contains_key(&address) {
+                continue;
+            }
+            let (wallet, version) = decode_record(&address, &value)?;
+            if version < WALLET_RECORD_VERSION {
+                println!("Upgrading wallet {} from record version {}", address, version);
+                db.insert(&key, encode_record(&wallet)?)?;
+            }
+
+            self.wallets.insert(address, wallet);
         }
+
+        db.flush()?;
+        Ok(())
     }
 
     pub fn get_wallets(&self) -> &HashMap<String, Wallet> {
@@ -119,41 +387,70 @@ impl Wallets {
         address
     }
 
+    // The wallets of their own, change wallets are under the wallet they hold change for
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::new();
-        for (address, _) in &self.wallets {
-            addresses.push(address.clone());
+        for (address, wallet) in &self.wallets {
+            if !self.wallets.contains_key(&wallet.metadata.change_of) {
+                addresses.push(address.clone());
+            }
         }
         addresses
     }
 
+    /// Addresses of the change wallets of `address`, sorted
+    pub fn change_addresses(&self, address: &str) -> Vec<String> {
+        let mut addresses: Vec<String> = self.wallets.iter()
+            .filter(|(_, wallet)| wallet.metadata.change_of == address)
+            .map(|(change, _)| change.clone())
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
+    /// Where change of sends from these wallets goes, following the change_addresses setting.
+    /// Wallets only kept in memory get their change back
+    pub fn change_provider(&self) -> Box<dyn ChangeAddressProvider> {
+        match &self.store {
+            Some((_, db)) if crate::settings::SETTINGS.change_addresses => Box::new(FreshChangeAddress { store: db.clone() }),
+            _ => Box::new(SameAddress),
+        }
+    }
+
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
         self.wallets.get(address)
     }
 
-    // saves all wallets | Meant as a function at the end of the application runtime
+    // saves all wallets to the store they were loaded from | Meant as a function at the end of the application runtime
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let Some((_, db)) = &self.store else {
+            return Ok(()); // only kept in memory
+        };
 
         for (address, wallet) in &self.wallets {
-            let data = bincode::serialize(wallet)?;
-            db.insert(address, data)?;
+            db.insert(address, encode_record(wallet)?)?;
         } 
 
         db.flush()?;
-        drop(db);
         Ok(())
     }
 
+    // Recorded in the audit log whether it worked or not
     pub fn delete_wallet(&mut self, address: &str) -> Result<()> {
-        if self.wallets.remove(address).is_some() {
-            let db = sled::open("data/wallets")?;
-            db.remove(address)?;  // Remove from the database
-            db.flush()?;          // Ensure changes are saved to disk
-            Ok(())
+        let result = if self.wallets.remove(address).is_some() {
+            (|| {
+                if let Some((_, db)) = &self.store {
+                    db.remove(address)?;  // Remove from the database
+                    db.flush()?;          // Ensure changes are saved to disk
+                }
+                Ok(())
+            })()
         } else {
             Err(failure::err_msg("Wallet not found"))
-        }
+        };
+        let params = vec![(String::from("address"), audit::short_address(address))];
+        self.audit.record(AuditAction::DeleteWallet, params, AuditOutcome::from(&result));
+        result
     }
 
     pub fn insert(&mut self, address: &str, wlt: Wallet) {
@@ -164,5 +461,205 @@ impl Wallets {
         self.wallets.iter()
     }
 
+    /// Compacts the store the wallets were loaded from, None when they're only in memory
+    pub fn compact(&mut self) -> Result<Option<StoreReport>> {
+        match &mut self.store {
+            Some((path, db)) => Ok(Some(crate::maintenance::compact_db(db, path, "Wallets")?)),
+            None => Ok(None),
+        }
+    }
+
+}
+ 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use crate::testing::{reopen, WalletFixture};
+
+    // file, seed of the WalletFixture it holds, version it was written as
+    const RECORD_FIXTURES: &[(&str, u64, u32)] = &[
+        ("v0.bin", 1, 0),
+        ("v1.bin", 2, 1),
+        ("v2.bin", 4, 2),
+    ];
+
+    fn record_fixture(name: &str) -> Vec<u8> {
+        std::fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/wallet_records").join(name)).unwrap()
+    }
+
+    fn fixture_wallet(seed: u64) -> Wallet {
+        let mut wallet = WalletFixture::new(seed).wallet;
+        if seed == 2 {
+            wallet.metadata = WalletMetadata { label: String::from("savings"), created_at: 1_700_000_000_000, change_of: String::new() };
+        }
+        if seed == 4 {
+            wallet.metadata = WalletMetadata { label: String::new(), created_at: 1_700_000_000_000, change_of: fixture_wallet(2).get_address() };
+        }
+        wallet
+    }
+
+    // A wallets database holding `records` as they are, by address
+    fn store_with(records: &[(String, Vec<u8>)]) -> String {
+        let path = std::env::temp_dir().join(format!("blockjain-wallets-{}", rand::random::<u64>()));
+        let db = sled::open(&path).unwrap();
+        for (address, bytes) in records {
+            db.insert(address, bytes.as_slice()).unwrap();
+        }
+        db.flush().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_record_fixtures_decode() {
+        for &(name, seed, version) in RECORD_FIXTURES {
+            let wallet = fixture_wallet(seed);
+            let decoded = decode_record(&wallet.get_address(), &record_fixture(name)).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(decoded, (wallet, version), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_v0_store_upgrades_on_load() {
+        let wallet = fixture_wallet(1);
+        let address = wallet.get_address();
+        let path = store_with(&[(address.clone(), record_fixture("v0.bin"))]);
+
+        let loaded = reopen(|| Wallets::load(&path)).unwrap();
+        assert_eq!(loaded.get_wallet(&address), Some(&wallet));
+        // written back as the current version, loading again changes nothing
+        let stored = loaded.store.as_ref().unwrap().1.get(&address).unwrap().unwrap().to_vec();
+        assert_eq!(stored, encode_record(&wallet).unwrap());
+        let saved = loaded.get_wallets().clone();
+        drop(loaded);
+        assert_eq!(reopen(|| Wallets::load(&path)).unwrap().get_wallets(), &saved);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_wallets_round_trip() {
+        let path = store_with(&[]);
+        let mut wallets = reopen(|| Wallets::load(&path)).unwrap();
+        for seed in [1, 2] {
+            let wallet = fixture_wallet(seed);
+            wallets.insert(&wallet.get_address(), wallet);
+        }
+        let created = wallets.create_wallet();
+        assert!(wallets.get_wallet(&created).unwrap().metadata.created_at > 0);
+        wallets.save_all().unwrap();
+        let saved = wallets.get_wallets().clone();
+        drop(wallets);
+        assert_eq!(reopen(|| Wallets::load(&path)).unwrap().get_wallets(), &saved);
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn test_change_wallets_are_listed_under_their_wallet() {
+        let mut wallets = Wallets::default();
+        let owner = fixture_wallet(2);
+        wallets.insert(&owner.get_address(), owner.clone());
+        let change = fixture_wallet(4);
+        wallets.insert(&change.get_address(), change.clone());
+        assert_eq!(wallets.get_all_address(), vec![owner.get_address()]);
+        assert_eq!(wallets.change_addresses(&owner.get_address()), vec![change.get_address()]);
+        // in memory only, nowhere to keep a new wallet
+        assert_eq!(wallets.change_provider().change_address(&owner).unwrap(), owner.get_address());
+
+        // without its wallet it's listed on its own
+        wallets.get_wallets_mut().remove(&owner.get_address());
+        assert_eq!(wallets.get_all_address(), vec![change.get_address()]);
+    }
+
+    #[test]
+    fn test_deleting_a_wallet_is_audited() {
+        let wallet = fixture_wallet(1);
+        let address = wallet.get_address();
+        let path = store_with(&[(address.clone(), encode_record(&wallet).unwrap())]);
+        let audit_path = format!("{}-audit", path);
+        let mut wallets = reopen(|| Wallets::load(&path)).unwrap();
+        wallets.audit = AuditLog::with_path(&audit_path);
+
+        wallets.delete_wallet(&address).unwrap();
+        assert!(wallets.delete_wallet(&address).is_err());
+        let entries = wallets.audit.entries().unwrap();
+        drop(wallets);
+        assert!(reopen(|| Wallets::load(&path)).unwrap().get_wallet(&address).is_none());
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::DeleteWallet);
+        assert_eq!(entries[0].params, vec![(String::from("address"), audit::short_address(&address))]);
+        assert_eq!(entries[0].outcome, AuditOutcome::Done);
+        assert!(matches!(entries[1].outcome, AuditOutcome::Failed(_)));
+        assert_eq!(audit::verify(&entries), None);
+        std::fs::remove_dir_all(&path).ok();
+        std::fs::remove_dir_all(&audit_path).ok();
+    }
+
+    #[test]
+    fn test_newer_record_is_left_alone() {
+        let address = fixture_wallet(3).get_address();
+        let future = record_fixture("future_v3.bin");
+        let path = store_with(&[(address.clone(), future.clone())]);
+
+        let err = reopen(|| Wallets::load(&path)).err().unwrap();
+        assert_eq!(err.downcast::<WalletStoreError>().unwrap(), WalletStoreError::NewerVersion { address: address.clone(), version: 3 });
+        assert_eq!(reopen(|| sled::open(&path)).unwrap().get(&address).unwrap().unwrap().to_vec(), future);
+        let err = decode_record(&address, b"BJWR\x01").err().unwrap();
+        assert_eq!(err.downcast::<WalletStoreError>().unwrap(), WalletStoreError::Truncated { address });
+        std::fs::remove_dir_all(&path).ok();
+    }
+
+    /// Writes the records in fixtures/wallet_records that don't exist yet. Existing ones
+    /// are never rewritten: they are what older builds stored. Run with `--ignored` after
+    /// bumping WALLET_RECORD_VERSION, then add the new file to RECORD_FIXTURES.
+    #[test]
+    #[ignore]
+    fn write_record_fixtures() {
+        let write = |name: &str, bytes: Vec<u8>| {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/wallet_records").join(name);
+            if !path.exists() {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, bytes).unwrap();
+            }
+        };
+        let v0 = fixture_wallet(1);
+        write("v0.bin", bincode::serialize(&WalletV0 { secret_key: v0.secret_key, public_key: v0.public_key }).unwrap());
+        let v1 = fixture_wallet(2);
+        let metadata = WalletMetadataV1 { label: v1.metadata.label, created_at: v1.metadata.created_at };
+        let mut record = RECORD_MAGIC.to_vec();
+        record.extend_from_slice(&1u32.to_le_bytes());
+        record.extend(bincode::serialize(&WalletV1 { secret_key: v1.secret_key, public_key: v1.public_key, metadata }).unwrap());
+        write("v1.bin", record);
+        write("v2.bin", encode_record(&fixture_wallet(4)).unwrap());
+
+        let mut future = RECORD_MAGIC.to_vec();
+        future.extend_from_slice(&3u32.to_le_bytes());
+        future.extend_from_slice(b"a layout this build doesn't know");
+        write("future_v3.bin", future);
+    }
+
+    #[test]
+    fn test_wallet_tags_are_stable() {
+        let wallet = Wallet::from_secret_key(&[7; 32]);
+        let tag = WalletTag::from_address(&wallet.get_address()).unwrap();
+        assert_eq!(WalletTag::from_address(&wallet.get_address()), Some(tag.clone()));
+        assert_eq!(Wallet::from_secret_key(&[7; 32]).get_address(), wallet.get_address());
+        assert_eq!(tag.to_string(), format!("{} {}", tag.emoji, tag.words));
+        assert_eq!(WalletTag::from_address("not an address"), None);
+    }
+
+    #[test]
+    fn test_wallet_tags_are_distinct() {
+        let tags: HashSet<WalletTag> = (0..1000u32)
+            .map(|i| {
+                let mut secret = [0u8; 32];
+                secret[..4].copy_from_slice(&i.to_be_bytes());
+                WalletTag::from_address(&Wallet::from_secret_key(&secret).get_address()).unwrap()
+            })
+            .collect();
+        assert_eq!(tags.len(), 1000);
+    }
 }
- 
\ No newline at end of file
@@ -0,0 +1,401 @@
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::{digest::Digest, hmac::Hmac, pbkdf2::pbkdf2, sha2::Sha256};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{Result, WalletImportError};
+use crate::wallet::{Wallet, WalletV0};
+
+/*
+    Wallet files
+
+    Every format a build of BlockJain ever exported has to keep importing. `detect` looks at
+    the bytes and names the format, then the decoder for exactly that format (and version)
+    runs, so a damaged file is reported as such instead of as "invalid secret key".
+
+      - LegacyBincode: bincode `WalletV0` as exported before versioning. The secret key is either
+        the 32 byte dalek seed or the 64 byte rust-crypto key (seed followed by the public key)
+      - Versioned: WALLET_MAGIC, a little endian u16 version, then that version's bincode body
+      - Json: an object with a "version" field
+      - Wif: base58check of 0x80, the 32 byte seed and 0x01
+      - Hex: the secret key as pasted in "Provide Private Key", 32 or 64 bytes
+
+    Versions above the ones known here fail with `NewerVersion` rather than being guessed at.
+    fixtures/wallets holds one file per format, the tests below fail if any stops importing.
+*/
+
+pub const WALLET_MAGIC: &[u8; 8] = b"BJWALLET";
+// Newest version of each format this build can read and writes
+pub const WALLET_FILE_VERSION: u16 = 1;
+pub const WALLET_JSON_VERSION: u32 = 1;
+
+// PBKDF2-HMAC-SHA256 rounds for new encrypted files, stored in the file
+const KDF_ROUNDS: u32 = 20_000;
+const WIF_PREFIX: u8 = 0x80;
+const WIF_COMPRESSED: u8 = 0x01;
+const BASE58_CHARS: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WalletFormat {
+    LegacyBincode,
+    Versioned(u16),
+    Json(u32),
+    Wif,
+    Hex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat<'a> {
+    Binary,
+    Encrypted(&'a str), // password
+    Json,
+    Wif,
+}
+
+// Body of a version 1 file
+#[derive(Serialize, Deserialize)]
+enum WalletFileV1 {
+    Plain { secret_key: Vec<u8> },
+    Encrypted { rounds: u32, salt: Vec<u8>, nonce: Vec<u8>, ciphertext: Vec<u8>, tag: Vec<u8> },
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalletJsonV1 {
+    version: u32,
+    address: String,
+    secret_key: String, // hex
+}
+
+/// Names the format of `bytes` without decoding the key
+pub fn detect(bytes: &[u8]) -> Result<WalletFormat> {
+    if let Some(rest) = bytes.strip_prefix(WALLET_MAGIC) {
+        let version = rest.get(..2).ok_or(WalletImportError::UnknownFormat)?;
+        return Ok(WalletFormat::Versioned(u16::from_le_bytes([version[0], version[1]])));
+    }
+    if let Ok(wallet) = bincode::deserialize::<WalletV0>(bytes) {
+        if bincode::serialized_size(&wallet).is_ok_and(|size| size as usize == bytes.len()) {
+            return Ok(WalletFormat::LegacyBincode);
+        }
+    }
+
+    let text = std::str::from_utf8(bytes).map_err(|_| WalletImportError::UnknownFormat)?.trim();
+    if text.starts_with('{') {
+        let value: serde_json::Value = serde_json::from_str(text).map_err(|_| WalletImportError::UnknownFormat)?;
+        let version = value.get("version").and_then(|v| v.as_u64()).ok_or(WalletImportError::UnknownFormat)?;
+        return Ok(WalletFormat::Json(version as u32));
+    }
+    if (text.len() == 64 || text.len() == 128) && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(WalletFormat::Hex);
+    }
+    if from_base58_check(text).is_some() {
+        return Ok(WalletFormat::Wif);
+    }
+    Err(WalletImportError::UnknownFormat.into())
+}
+
+/// Decodes a wallet in any known format. `password` is only used by encrypted files.
+pub fn import(bytes: &[u8], password: Option<&str>) -> Result<Wallet> {
+    match detect(bytes)? {
+        WalletFormat::LegacyBincode => decode_legacy(bytes),
+        WalletFormat::Versioned(1) => decode_file_v1(&bytes[WALLET_MAGIC.len() + 2..], password),
+        WalletFormat::Versioned(version) => Err(unsupported(version as u32, WALLET_FILE_VERSION as u32)),
+        WalletFormat::Json(1) => decode_json_v1(bytes),
+        WalletFormat::Json(version) => Err(unsupported(version, WALLET_JSON_VERSION)),
+        WalletFormat::Wif => decode_wif(bytes),
+        WalletFormat::Hex => decode_hex(bytes),
+    }
+}
+
+/// Encodes `wallet` in the newest version of `format`
+pub fn export(wallet: &Wallet, format: ExportFormat) -> Result<Vec<u8>> {
+    let secret_key = seed(&wallet.secret_key)?;
+    match format {
+        ExportFormat::Binary => versioned_file(&WalletFileV1::Plain { secret_key: secret_key.to_vec() }),
+        ExportFormat::Encrypted(password) => {
+            let salt = rand::random::<[u8; 16]>().to_vec();
+            let nonce = rand::random::<[u8; 8]>().to_vec();
+            let key = derive_key(password, &salt, KDF_ROUNDS);
+            let mut ciphertext = vec![0u8; secret_key.len()];
+            let mut tag = vec![0u8; 16];
+            ChaCha20Poly1305::new(&key, &nonce, WALLET_MAGIC).encrypt(&secret_key, &mut ciphertext, &mut tag);
+            versioned_file(&WalletFileV1::Encrypted { rounds: KDF_ROUNDS, salt, nonce, ciphertext, tag })
+        }
+        ExportFormat::Json => Ok(serde_json::to_vec_pretty(&WalletJsonV1 {
+            version: WALLET_JSON_VERSION,
+            address: wallet.get_address(),
+            secret_key: hex::encode(secret_key),
+        })?),
+        ExportFormat::Wif => {
+            let mut payload = vec![WIF_PREFIX];
+            payload.extend_from_slice(&secret_key);
+            payload.push(WIF_COMPRESSED);
+            Ok(to_base58_check(&payload).into_bytes())
+        }
+    }
+}
+
+fn unsupported(version: u32, newest: u32) -> failure::Error {
+    if version > newest {
+        WalletImportError::NewerVersion { version }.into()
+    } else {
+        WalletImportError::UnknownFormat.into()
+    }
+}
+
+fn versioned_file(body: &WalletFileV1) -> Result<Vec<u8>> {
+    let mut bytes = WALLET_MAGIC.to_vec();
+    bytes.extend_from_slice(&WALLET_FILE_VERSION.to_le_bytes());
+    bytes.extend(bincode::serialize(body)?);
+    Ok(bytes)
+}
+
+fn decode_legacy(bytes: &[u8]) -> Result<Wallet> {
+    let stored: WalletV0 = bincode::deserialize(bytes)?;
+    let wallet = Wallet::from_secret_key(&seed(&stored.secret_key)?);
+    if wallet.public_key != stored.public_key {
+        return Err(WalletImportError::KeyMismatch.into());
+    }
+    Ok(wallet)
+}
+
+fn decode_file_v1(body: &[u8], password: Option<&str>) -> Result<Wallet> {
+    let file: WalletFileV1 = bincode::deserialize(body).map_err(|_| WalletImportError::UnknownFormat)?;
+    match file {
+        WalletFileV1::Plain { secret_key } => Ok(Wallet::from_secret_key(&seed(&secret_key)?)),
+        WalletFileV1::Encrypted { rounds, salt, nonce, ciphertext, tag } => {
+            let password = password.ok_or(WalletImportError::PasswordRequired)?;
+            if ciphertext.len() != 32 || tag.len() != 16 || nonce.len() != 8 {
+                return Err(WalletImportError::UnknownFormat.into());
+            }
+            let key = derive_key(password, &salt, rounds);
+            let mut secret_key = [0u8; 32];
+            if !ChaCha20Poly1305::new(&key, &nonce, WALLET_MAGIC).decrypt(&ciphertext, &mut secret_key, &tag) {
+                return Err(WalletImportError::WrongPassword.into());
+            }
+            Ok(Wallet::from_secret_key(&secret_key))
+        }
+    }
+}
+
+fn decode_json_v1(bytes: &[u8]) -> Result<Wallet> {
+    let file: WalletJsonV1 = serde_json::from_slice(bytes).map_err(|_| WalletImportError::UnknownFormat)?;
+    let secret_key = hex::decode(&file.secret_key).map_err(|_| WalletImportError::InvalidSecretKeyFormat)?;
+    let wallet = Wallet::from_secret_key(&seed(&secret_key)?);
+    if wallet.get_address() != file.address {
+        return Err(WalletImportError::KeyMismatch.into());
+    }
+    Ok(wallet)
+}
+
+fn decode_wif(bytes: &[u8]) -> Result<Wallet> {
+    let text = std::str::from_utf8(bytes)?.trim();
+    let payload = from_base58_check(text).ok_or(WalletImportError::InvalidSecretKeyFormat)?;
+    match payload.as_slice() {
+        [WIF_PREFIX, secret_key @ .., WIF_COMPRESSED] | [WIF_PREFIX, secret_key @ ..] if secret_key.len() == 32 => {
+            Ok(Wallet::from_secret_key(&seed(secret_key)?))
+        }
+        _ => Err(WalletImportError::InvalidSecretKeyFormat.into()),
+    }
+}
+
+fn decode_hex(bytes: &[u8]) -> Result<Wallet> {
+    let secret_key = hex::decode(std::str::from_utf8(bytes)?.trim()).map_err(|_| WalletImportError::InvalidSecretKeyFormat)?;
+    Ok(Wallet::from_secret_key(&seed(&secret_key)?))
+}
+
+// The 32 byte seed of a dalek or rust-crypto secret key
+fn seed(secret_key: &[u8]) -> Result<[u8; 32]> {
+    let seed: [u8; 32] = secret_key
+        .get(..32)
+        .and_then(|s| s.try_into().ok())
+        .ok_or(WalletImportError::InvalidSecretKeyFormat)?;
+    match secret_key.len() {
+        32 => Ok(seed),
+        64 if Wallet::from_secret_key(&seed).public_key == secret_key[32..] => Ok(seed),
+        64 => Err(WalletImportError::KeyMismatch.into()),
+        _ => Err(WalletImportError::InvalidSecretKeyFormat.into()),
+    }
+}
+
+fn derive_key(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+    let mut key = [0u8; 32];
+    pbkdf2(&mut mac, salt, rounds, &mut key);
+    key
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut first = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result(&mut first);
+
+    let mut second = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(&first);
+    hasher.result(&mut second);
+    [second[0], second[1], second[2], second[3]]
+}
+
+fn to_base58_check(payload: &[u8]) -> String {
+    let mut data = payload.to_vec();
+    data.extend_from_slice(&checksum(payload));
+
+    // base 256 to base 58, little endian digits
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = data.iter().take_while(|&&b| b == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|&d| BASE58_CHARS[d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn from_base58_check(text: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new(); // little endian
+    for c in text.bytes() {
+        let mut carry = BASE58_CHARS.iter().position(|&b| b == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += *byte as u32 * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = text.bytes().take_while(|&b| b == b'1').count();
+    let mut data = vec![0u8; zeros];
+    data.extend(bytes.iter().rev());
+
+    if data.len() < 5 {
+        return None;
+    }
+    let (payload, check) = data.split_at(data.len() - 4);
+    (checksum(payload) == check).then(|| payload.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::testing::{coinbase, TxBuilder, WalletFixture};
+
+    const FIXTURE_PASSWORD: &str = "correct horse battery staple";
+
+    // file, seed of the WalletFixture it was exported from, address it must import to
+    const FIXTURES: &[(&str, u64, &str)] = &[
+        ("legacy_plain.dat", 1, "1AsN3k8N59Wc6GLqArYZRveCfCPmaT4kKN"),
+        ("legacy_rust_crypto.dat", 2, "18mDW6zJPy1Sn4RVt9n7dLRes4ciwepKSX"),
+        ("legacy_rust_crypto.hex", 3, "1J4thPMJQKwtwFA7br3YTfZY1HKbFeGiZt"),
+        ("v1_plain.dat", 4, "148djuhVasM3ANPivKz6jLNJLChuyPM4dG"),
+        ("v1_encrypted.dat", 5, "1Cgz7SBinLw9q81JTS1bpJQRrpKtb3qTFd"),
+        ("v1.json", 6, "17dJ4ycUH65AGmBx9nWLTxvUgzDKcRb4Je"),
+        ("wallet.wif", 7, "17XHMhCuPiiqfN9EY4odhyYuoB5iCdb8ee"),
+    ];
+
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/wallets").join(name)
+    }
+
+    // rust-crypto's expanded secret key: the seed followed by the public key
+    fn rust_crypto_key(wallet: &Wallet) -> Vec<u8> {
+        let (secret_key, _) = crypto::ed25519::keypair(&wallet.secret_key);
+        secret_key.to_vec()
+    }
+
+    #[test]
+    fn test_wallet_fixtures_import() {
+        for &(name, seed, address) in FIXTURES {
+            let bytes = std::fs::read(fixture_path(name)).unwrap();
+            let wallet = import(&bytes, Some(FIXTURE_PASSWORD)).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(wallet.get_address(), address, "{}", name);
+            assert_eq!(wallet, WalletFixture::new(seed).wallet, "{}", name);
+
+            // and it still signs transactions that verify
+            let owner = WalletFixture { wallet };
+            let reward = coinbase(address, 1);
+            let tx = TxBuilder::new(&owner).spend(&reward, 0).pay(&WalletFixture::new(99).address(), 10).build();
+            assert!(tx.verify(HashMap::from([(reward.id.clone(), reward)])).unwrap(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_wallet_format_errors() {
+        let newer = |name: &str| {
+            let err = import(&std::fs::read(fixture_path(name)).unwrap(), None).unwrap_err();
+            matches!(err.downcast_ref(), Some(WalletImportError::NewerVersion { version: 2 }))
+        };
+        assert!(newer("future_v2.dat"));
+        assert!(newer("future_v2.json"));
+
+        let encrypted = std::fs::read(fixture_path("v1_encrypted.dat")).unwrap();
+        let err = import(&encrypted, None).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(WalletImportError::PasswordRequired)));
+        let err = import(&encrypted, Some("wrong")).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(WalletImportError::WrongPassword)));
+
+        let err = import(b"not a wallet", None).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(WalletImportError::UnknownFormat)));
+
+        // A rust-crypto key whose public half belongs to someone else
+        let mut key = rust_crypto_key(&WalletFixture::new(1).wallet);
+        key[32..].copy_from_slice(&WalletFixture::new(2).wallet.public_key);
+        let err = import(hex::encode(key).as_bytes(), None).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(WalletImportError::KeyMismatch)));
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let wallet = WalletFixture::new(42).wallet;
+        for format in [ExportFormat::Binary, ExportFormat::Encrypted("pw"), ExportFormat::Json, ExportFormat::Wif] {
+            let bytes = export(&wallet, format).unwrap();
+            assert_eq!(import(&bytes, Some("pw")).unwrap(), wallet, "{:?}", format);
+        }
+    }
+
+    /// Writes the files in fixtures/wallets that don't exist yet. Existing fixtures are
+    /// never rewritten: they are what older builds produced. Run with `--ignored` after
+    /// adding a format, then add the new file to FIXTURES.
+    #[test]
+    #[ignore]
+    fn write_wallet_fixtures() {
+        let write = |name: &str, bytes: Vec<u8>| {
+            let path = fixture_path(name);
+            if !path.exists() {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, bytes).unwrap();
+            }
+        };
+        let wallet = |seed: u64| WalletFixture::new(seed).wallet;
+
+        write("legacy_plain.dat", bincode::serialize(&WalletV0 { secret_key: wallet(1).secret_key, public_key: wallet(1).public_key }).unwrap());
+        let legacy = WalletV0 { secret_key: rust_crypto_key(&wallet(2)), public_key: wallet(2).public_key };
+        write("legacy_rust_crypto.dat", bincode::serialize(&legacy).unwrap());
+        write("legacy_rust_crypto.hex", hex::encode(rust_crypto_key(&wallet(3))).into_bytes());
+        write("v1_plain.dat", export(&wallet(4), ExportFormat::Binary).unwrap());
+        write("v1_encrypted.dat", export(&wallet(5), ExportFormat::Encrypted(FIXTURE_PASSWORD)).unwrap());
+        write("v1.json", export(&wallet(6), ExportFormat::Json).unwrap());
+        write("wallet.wif", export(&wallet(7), ExportFormat::Wif).unwrap());
+
+        let mut future = WALLET_MAGIC.to_vec();
+        future.extend_from_slice(&2u16.to_le_bytes());
+        future.extend_from_slice(b"a layout this build doesn't know");
+        write("future_v2.dat", future);
+        write("future_v2.json", br#"{ "version": 2, "keys": [] }"#.to_vec());
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::address;
+use crate::block::Block;
+use crate::errors::Result;
+use crate::transaction::{block_subsidy, Transaction};
+use crate::tx::TXInput;
+
+/*
+    Wallet history index
+
+    The "owned_txs" tree of the block database holds ready-made history entries for the
+    addresses it tracks (our wallets), so the history is read without scanning the chain.
+    Keys:
+
+        w:<address>                     tracked address, empty value
+        h:<address>:<height>:<txid>     HistoryEntry, height zero-padded so a prefix scan is
+                                        in chain order
+        TIP                             hash of the block the entries are current with
+        FORMAT                          version of the HistoryEntry encoding
+
+    The chain keeps the tree up to date as blocks connect and disconnect. When TIP doesn't
+    match the chain tip (an older build added blocks, an update failed) the entries are
+    rebuilt by a rescan, see `Blockchain::wallet_history`. So are entries written in an
+    older FORMAT, opening the index drops TIP for them.
+*/
+
+pub const OWNED_TXS_TREE: &str = "owned_txs";
+const TIP_KEY: &str = "TIP";
+const FORMAT_KEY: &str = "FORMAT";
+const FORMAT_VERSION: u8 = 2; // 2 added MiningReward
+// Counterparty of burn outputs, which pay no address
+pub const BURN_COUNTERPARTY: &str = "burned";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Mined,        // block reward
+    Received,
+    Sent,
+    SelfTransfer, // every output came back to the same address
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub txid: String,
+    pub height: i32,
+    pub direction: Direction,
+    pub net_amount: i64, // received minus spent by this address
+    pub counterparties: Vec<String>, // senders of what was received, receivers of what was sent
+    pub reward: Option<MiningReward>, // Mined entries only
+}
+
+/// What a coinbase paid an address, split into the block subsidy and the fees the miner
+/// claimed on top of it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MiningReward {
+    pub subsidy: i64,
+    pub fees: i64,
+    pub timestamp: u128, // of the block, ms since the epoch
+}
+
+/// What the Mined entries of a history add up to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MiningIncome {
+    pub blocks: usize,
+    pub subsidy: i64,
+    pub fees: i64,
+    pub last_reward: Option<u128>, // block timestamp, ms since the epoch
+}
+
+impl MiningIncome {
+    /// Rewards of `history` in blocks stamped at or after `since` (ms since the epoch, 0 for
+    /// every one). None when there isn't any
+    pub fn from_history(history: &[HistoryEntry], since: u128) -> Option<MiningIncome> {
+        let mut income = MiningIncome::default();
+        for reward in history.iter().filter_map(|entry| entry.reward).filter(|reward| reward.timestamp >= since) {
+            income.blocks += 1;
+            income.subsidy += reward.subsidy;
+            income.fees += reward.fees;
+            income.last_reward = income.last_reward.max(Some(reward.timestamp));
+        }
+        (income.blocks > 0).then_some(income)
+    }
+
+    pub fn total(&self) -> i64 {
+        self.subsidy + self.fees
+    }
+}
+
+/// `history` as CSV, one row per entry with the subsidy and fees of mined ones
+pub fn history_csv(history: &[HistoryEntry]) -> String {
+    let mut csv = String::from("height,txid,direction,net_amount,subsidy,fees,counterparties\n");
+    for entry in history {
+        let (subsidy, fees) = entry.reward.map_or((String::new(), String::new()), |r| (r.subsidy.to_string(), r.fees.to_string()));
+        csv.push_str(&format!(
+            "{},{},{:?},{},{},{},{}\n",
+            entry.height, entry.txid, entry.direction, entry.net_amount, subsidy, fees, entry.counterparties.join(";")
+        ));
+    }
+    csv
+}
+
+/// Entries `tx` of `block` makes in the history of the `tracked` addresses it touches.
+/// `spent_value` gives the value of the output an input spends
+pub fn entries_for(
+    tx: &Transaction,
+    block: &Block,
+    tracked: &HashSet<String>,
+    spent_value: &impl Fn(&TXInput) -> Result<i32>,
+) -> Result<Vec<(String, HistoryEntry)>> {
+    let senders: Vec<String> = if tx.is_coinbase() {
+        Vec::new()
+    } else {
+        tx.vin.iter().map(TXInput::get_address).collect()
+    };
+    let receivers: Vec<String> = tx.vout.iter()
+        .map(|out| if out.is_burn() { BURN_COUNTERPARTY.to_string() } else { address::wallet_address(&out.pub_key_hash) })
+        .collect();
+
+    let mut touched: Vec<&String> = Vec::new();
+    for address in senders.iter().chain(&receivers) {
+        if tracked.contains(address) && !touched.contains(&address) {
+            touched.push(address);
+        }
+    }
+
+    let mut entries = Vec::new();
+    for owner in touched {
+        let mut spent = 0i64;
+        for (vin, sender) in tx.vin.iter().zip(&senders) {
+            if sender == owner {
+                spent += spent_value(vin)? as i64;
+            }
+        }
+        let received: i64 = tx.vout.iter().zip(&receivers)
+            .filter(|(_, receiver)| *receiver == owner)
+            .map(|(out, _)| out.value as i64)
+            .sum();
+
+        // The subsidy comes first, anything a coinbase pays beyond it is claimed fees
+        let reward = tx.is_coinbase().then(|| {
+            let subsidy = received.min(block_subsidy(block.get_height()) as i64);
+            MiningReward { subsidy, fees: received - subsidy, timestamp: block.get_timestamp() }
+        });
+        let (direction, others) = if tx.is_coinbase() {
+            (Direction::Mined, &receivers[..0])
+        } else if senders.contains(owner) {
+            let direction = if receivers.iter().all(|r| r == owner) { Direction::SelfTransfer } else { Direction::Sent };
+            (direction, &receivers[..])
+        } else {
+            (Direction::Received, &senders[..])
+        };
+        let mut counterparties: Vec<String> = Vec::new();
+        for other in others.iter().filter(|other| *other != owner) {
+            if !counterparties.contains(other) {
+                counterparties.push(other.clone());
+            }
+        }
+
+        entries.push((owner.clone(), HistoryEntry {
+            txid: tx.id.clone(),
+            height: block.get_height(),
+            direction,
+            net_amount: received - spent,
+            counterparties,
+            reward,
+        }));
+    }
+    Ok(entries)
+}
+
+/// The "owned_txs" tree, see the module comment
+pub struct OwnedTxIndex {
+    tree: sled::Tree,
+}
+
+impl OwnedTxIndex {
+    pub fn open(db: &sled::Db) -> Result<OwnedTxIndex> {
+        let tree = db.open_tree(OWNED_TXS_TREE)?;
+        if tree.get(FORMAT_KEY)?.as_deref() != Some(&[FORMAT_VERSION]) {
+            tree.remove(TIP_KEY)?; // rescanned before the old entries are read
+            tree.insert(FORMAT_KEY, &[FORMAT_VERSION])?;
+        }
+        Ok(OwnedTxIndex { tree })
+    }
+
+    pub fn tracked(&self) -> Result<HashSet<String>> {
+        let mut addresses = HashSet::new();
+        for kv in self.tree.scan_prefix("w:") {
+            let (key, _) = kv?;
+            addresses.insert(String::from_utf8(key[2..].to_vec())?);
+        }
+        Ok(addresses)
+    }
+
+    pub fn track(&self, address: &str) -> Result<()> {
+        self.tree.insert(format!("w:{}", address), &[])?;
+        Ok(())
+    }
+
+    /// Stops tracking `address` and drops its entries
+    pub fn remove_address(&self, address: &str) -> Result<()> {
+        self.tree.remove(format!("w:{}", address))?;
+        self.clear_entries(address)
+    }
+
+    pub fn clear_entries(&self, address: &str) -> Result<()> {
+        for kv in self.tree.scan_prefix(format!("h:{}:", address)) {
+            let (key, _) = kv?;
+            self.tree.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Hash of the block the entries are current with
+    pub fn tip(&self) -> Result<Option<String>> {
+        Ok(self.tree.get(TIP_KEY)?.map(|hash| String::from_utf8_lossy(&hash).into_owned()))
+    }
+
+    pub fn set_tip(&self, hash: &str) -> Result<()> {
+        self.tree.insert(TIP_KEY, hash.as_bytes())?;
+        Ok(())
+    }
+
+    /// Entries of `address`, newest first
+    pub fn history(&self, address: &str) -> Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+        for kv in self.tree.scan_prefix(format!("h:{}:", address)).rev() {
+            let (_, data) = kv?;
+            entries.push(bincode::deserialize(&data)?);
+        }
+        Ok(entries)
+    }
+
+    pub fn insert(&self, address: &str, entry: &HistoryEntry) -> Result<()> {
+        self.tree.insert(entry_key(address, entry.height, &entry.txid), bincode::serialize(entry)?)?;
+        Ok(())
+    }
+
+    /// Adds the entries of `block` and moves the tip to it
+    pub fn connect_block(&self, block: &Block, spent_value: &impl Fn(&TXInput) -> Result<i32>) -> Result<()> {
+        let tracked = self.tracked()?;
+        for tx in block.get_transactions() {
+            for (address, entry) in entries_for(tx, block, &tracked, spent_value)? {
+                self.insert(&address, &entry)?;
+            }
+        }
+        self.set_tip(&block.get_hash())
+    }
+
+    /// Removes the entries of `block` and moves the tip to its parent
+    pub fn disconnect_block(&self, block: &Block) -> Result<()> {
+        for address in self.tracked()? {
+            for tx in block.get_transactions() {
+                self.tree.remove(entry_key(&address, block.get_height(), &tx.id))?;
+            }
+        }
+        self.set_tip(&block.get_prev_hash())
+    }
+}
+
+fn entry_key(address: &str, height: i32, txid: &str) -> String {
+    format!("h:{}:{:010}:{}", address, height, txid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::INITIAL_TARGET;
+    use crate::blockchain::Blockchain;
+    use crate::testing::{coinbase, ChainBuilder, TxBuilder, WalletFixture};
+
+    // The index as kept up to date, and as a rescan rebuilds it
+    fn assert_matches_rescan(bc: &Blockchain, addresses: &[String]) {
+        let index = OwnedTxIndex::open(&bc.db).unwrap();
+        assert_eq!(index.tip().unwrap(), Some(bc.tip.clone()));
+        let kept: Vec<Vec<HistoryEntry>> = addresses.iter().map(|a| bc.wallet_history(a).unwrap()).collect();
+        index.set_tip("stale").unwrap();
+        let rescanned: Vec<Vec<HistoryEntry>> = addresses.iter().map(|a| bc.wallet_history(a).unwrap()).collect();
+        assert_eq!(kept, rescanned);
+    }
+
+    fn summary(history: &[HistoryEntry]) -> Vec<(i32, Direction, i64)> {
+        let mut summary: Vec<_> = history.iter().map(|e| (e.height, e.direction, e.net_amount)).collect();
+        summary.sort_by_key(|(height, direction, _)| (-height, *direction as u8));
+        summary
+    }
+
+    #[test]
+    fn test_history_follows_connects_reorgs_and_deletion() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let carol = WalletFixture::new(3);
+        let wallets = vec![alice.address(), bob.address()];
+        let chain = ChainBuilder::new(&alice);
+        let genesis = chain.tip();
+        let reward = genesis.get_transactions()[0].clone();
+        let bc = &mut chain.build();
+        bc.track_wallets(&wallets).unwrap();
+        assert_eq!(summary(&bc.wallet_history(&alice.address()).unwrap()), vec![(0, Direction::Mined, 10)]);
+
+        // Connect
+        let pay = TxBuilder::new(&alice).spend(&reward, 0).pay(&bob.address(), 6).pay(&alice.address(), 4).build();
+        let block = Block::new_block(vec![coinbase(&alice.address(), 1), pay.clone()], genesis.get_hash(), 1, INITIAL_TARGET).unwrap();
+        bc.add_block(block).unwrap();
+        assert_eq!(
+            summary(&bc.wallet_history(&alice.address()).unwrap()),
+            vec![(1, Direction::Mined, 10), (1, Direction::Sent, -6), (0, Direction::Mined, 10)]
+        );
+        let bob_history = bc.wallet_history(&bob.address()).unwrap();
+        assert_eq!(bob_history, vec![HistoryEntry {
+            txid: pay.id.clone(),
+            height: 1,
+            direction: Direction::Received,
+            net_amount: 6,
+            counterparties: vec![alice.address()],
+            reward: None,
+        }]);
+        assert_matches_rescan(bc, &wallets);
+
+        // Deleting drops the entries, importing again backfills them
+        bc.untrack_wallet(&bob.address()).unwrap();
+        assert!(!OwnedTxIndex::open(&bc.db).unwrap().tracked().unwrap().contains(&bob.address()));
+        assert!(bc.wallet_history(&bob.address()).unwrap().is_empty());
+        bc.track_wallets(&wallets).unwrap();
+        assert_eq!(bc.wallet_history(&bob.address()).unwrap(), bob_history);
+
+        // A longer branch without the payment replaces it
+        let fork = Block::new_block(vec![coinbase(&carol.address(), 1)], genesis.get_hash(), 1, INITIAL_TARGET).unwrap();
+        let longer = Block::new_block_at(vec![coinbase(&carol.address(), 2)], fork.get_hash(), 2, INITIAL_TARGET, fork.get_timestamp() + 1).unwrap();
+        bc.add_block(fork).unwrap();
+        bc.add_block(longer).unwrap();
+        assert_eq!(OwnedTxIndex::open(&bc.db).unwrap().tip().unwrap(), Some(bc.tip.clone()));
+        assert_eq!(summary(&bc.wallet_history(&alice.address()).unwrap()), vec![(0, Direction::Mined, 10)]);
+        assert!(bc.wallet_history(&bob.address()).unwrap().is_empty());
+        assert_matches_rescan(bc, &wallets);
+    }
+
+    #[test]
+    fn test_mining_reward_splits_subsidy_and_fees() {
+        let alice = WalletFixture::new(1);
+        let bob = WalletFixture::new(2);
+        let chain = ChainBuilder::new(&alice);
+        let genesis = chain.tip();
+        let bc = &mut chain.build();
+        bc.track_wallets(&[alice.address()]).unwrap();
+
+        // 6 + 3 out of 10, the miner claims the 1 left
+        let pay = TxBuilder::new(&alice).spend(&genesis.get_transactions()[0], 0).pay(&bob.address(), 6).pay(&alice.address(), 3).build();
+        let reward = Transaction::new_coinbase_with_fees(alice.address(), String::from("reward"), 1, 1).unwrap();
+        let block = Block::new_block(vec![reward.clone(), pay], genesis.get_hash(), 1, INITIAL_TARGET).unwrap();
+        bc.add_block(block.clone()).unwrap();
+
+        let history = bc.wallet_history(&alice.address()).unwrap();
+        let mined = history.iter().find(|entry| entry.txid == reward.id).unwrap();
+        assert_eq!((mined.direction, mined.net_amount), (Direction::Mined, 11));
+        assert_eq!(mined.reward, Some(MiningReward { subsidy: 10, fees: 1, timestamp: block.get_timestamp() }));
+        assert!(history.iter().filter(|entry| entry.direction != Direction::Mined).all(|entry| entry.reward.is_none()));
+        assert_matches_rescan(bc, &[alice.address()]);
+
+        let income = MiningIncome::from_history(&history, 0).unwrap();
+        assert_eq!((income.blocks, income.subsidy, income.fees, income.total()), (2, 20, 1, 21));
+        assert_eq!(income.last_reward, Some(block.get_timestamp()));
+        assert_eq!(MiningIncome::from_history(&history, block.get_timestamp()).unwrap().blocks, 1);
+        assert_eq!(MiningIncome::from_history(&bc.wallet_history(&bob.address()).unwrap(), 0), None);
+
+        let csv = history_csv(&history);
+        assert!(csv.starts_with("height,txid,direction,net_amount,subsidy,fees,counterparties\n"));
+        assert!(csv.contains(&format!("1,{},Mined,11,10,1,\n", reward.id)));
+        assert!(csv.contains(&format!(",Sent,-7,,,{}\n", bob.address())));
+    }
+
+    #[test]
+    fn test_entries_of_an_older_format_are_rescanned() {
+        let alice = WalletFixture::new(1);
+        let bc = ChainBuilder::new(&alice).empty_blocks(1).build();
+        bc.track_wallets(&[alice.address()]).unwrap();
+        let history = bc.wallet_history(&alice.address()).unwrap();
+        let index = OwnedTxIndex::open(&bc.db).unwrap();
+        index.tree.insert(FORMAT_KEY, &[1]).unwrap();
+        for entry in &history {
+            index.tree.insert(entry_key(&alice.address(), entry.height, &entry.txid), &b"old"[..]).unwrap();
+        }
+
+        assert_eq!(OwnedTxIndex::open(&bc.db).unwrap().tip().unwrap(), None);
+        assert_eq!(bc.wallet_history(&alice.address()).unwrap(), history);
+    }
+}